@@ -0,0 +1,19 @@
+//! `evie fmt`, formats evie scripts with [evie_frontend::fmt].
+use std::fs;
+
+use evie_common::errors::*;
+pub use evie_frontend::fmt::format_source;
+
+/// Formats the file at `path` in place. With `check`, the file is left as is.
+/// Returns true if the file was already formatted.
+pub fn format_file(path: &str, check: bool) -> Result<bool> {
+    let source = fs::read_to_string(path).chain_err(|| "Unable to read file")?;
+    let formatted = format_source(&source)?;
+    if formatted == source {
+        return Ok(true);
+    }
+    if !check {
+        fs::write(path, formatted).chain_err(|| "Unable to write file")?;
+    }
+    Ok(false)
+}
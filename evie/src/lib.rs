@@ -24,4 +24,7 @@
 //!    
 //!
 
+pub mod fmt;
 pub mod runner;
+pub mod serve;
+pub mod test;
@@ -1,24 +1,132 @@
 use evie::runner::Runner;
+use evie::serve::{Limits, DEFAULT_ADDRESS};
 use evie_common::{env_logger, errors::*, print_error};
 use std::env;
-use std::io::stderr;
+use std::io::{stderr, stdout};
+use std::net::TcpListener;
 fn main() -> Result<()> {
     env_logger::init();
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("fmt") {
+        return fmt(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("test") {
+        return test(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("serve") {
+        return serve(&args[2..]);
+    }
     let mut runner = Runner::new();
-    let result = match args.len() {
-        1 => runner.repl(),
-        2 => runner.run_script(&args[1]),
+    // Work with any of the modes below
+    loop {
+        match args.get(1).map(String::as_str) {
+            Some("--strict") => {
+                args.remove(1);
+                runner.set_strict(true);
+            }
+            Some("--max-heap") => {
+                let max_heap_bytes = match args.get(2).and_then(|bytes| bytes.parse().ok()) {
+                    Some(max_heap_bytes) => max_heap_bytes,
+                    None => return print_help(),
+                };
+                args.drain(1..3);
+                runner.set_max_heap_bytes(Some(max_heap_bytes));
+            }
+            _ => break,
+        }
+    }
+    let result = match &args[1..] {
+        [] => runner.repl(),
+        [flag] if flag == "--inspect" => {
+            runner.set_inspect(true);
+            runner.repl()
+        }
+        [flag, source] if flag == "-e" => runner.run_source(source.clone()),
+        [script] => runner.run_script(script),
+        [flag, trace, script] if flag == "--record" => runner.record_script(script, trace),
+        [flag, trace, script] if flag == "--replay" => runner.replay_script(script, trace),
+        [flag, profile, script] if flag == "--pgo-profile" => {
+            runner.profile_script(script, profile)
+        }
+        [flag, profile, script] if flag == "--pgo-use" => {
+            runner.run_script_with_profile(script, profile)
+        }
         _ => print_help(),
     };
-    match result {
-        Ok(_) => {}
-        Err(e) => print_error(e, &mut stderr()),
+    if let Err(e) = result {
+        let code = exit_code(&e);
+        print_error(e, &mut stderr());
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// The exit code for a script that failed, as clox: 65 if it does not compile, 70 if it fails at runtime
+/// (e.g. a failed `assert`) and 74 for IO errors
+fn exit_code(e: &Error) -> i32 {
+    match e.kind() {
+        ErrorKind::ScanError(_)
+        | ErrorKind::ParseError(_)
+        | ErrorKind::ResolutionError(_)
+        | ErrorKind::CompileErrors(_) => 65,
+        ErrorKind::RuntimeError(_) => 70,
+        _ => 74,
+    }
+}
+
+/// `evie fmt [--check] <file>`, with --check the file is not changed and the exit code is 1 if it is not formatted
+fn fmt(args: &[String]) -> Result<()> {
+    let (check, path) = match args {
+        [path] => (false, path),
+        [flag, path] if flag == "--check" => (true, path),
+        _ => return print_help(),
+    };
+    match evie::fmt::format_file(path, check) {
+        Ok(true) => {}
+        Ok(false) if check => {
+            eprintln!("{} is not formatted", path);
+            std::process::exit(1);
+        }
+        Ok(false) => {}
+        Err(e) => {
+            print_error(e, &mut stderr());
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// `evie test <dir>`, the exit code is 1 if a test fails
+fn test(args: &[String]) -> Result<()> {
+    let dir = match args {
+        [dir] => dir,
+        _ => return print_help(),
     };
+    match evie::test::run_tests(dir, &mut stdout()) {
+        Ok(summary) if summary.failed == 0 => {}
+        Ok(_) => std::process::exit(1),
+        Err(e) => {
+            print_error(e, &mut stderr());
+            std::process::exit(1);
+        }
+    }
     Ok(())
 }
 
+/// `evie serve [address]`, runs the scripts posted to /run (see [evie::serve])
+fn serve(args: &[String]) -> Result<()> {
+    let address = match args {
+        [] => DEFAULT_ADDRESS,
+        [address] => address,
+        _ => return print_help(),
+    };
+    let listener =
+        TcpListener::bind(address).chain_err(|| format!("Unable to listen on {}", address))?;
+    eprintln!("Listening on http://{}/run", address);
+    evie::serve::serve(listener, Limits::default())
+}
+
 fn print_help() -> Result<()> {
-    eprintln!("Usage: evie [path to evie script]\nNote: If you run without any arguments, you enter REPL mode");
+    eprintln!("Usage: evie [path to evie script, - for stdin]\n       evie -e [evie code]\n       evie --record|--replay [path to trace] [path to evie script]\n       evie --pgo-profile|--pgo-use [path to profile] [path to evie script]\n       evie fmt [--check] [path to evie script]\n       evie test [path to a directory of *_test.evie scripts]\n       evie serve [address, 127.0.0.1:8080 by default]\nWith --strict (before the other arguments) the undefined globals fail the compilation instead of the run and dividing by zero fails the run\nWith --max-heap [bytes] (before the other arguments) a script whose objects take more bytes fails with an out of memory error\nNote: If you run without any arguments (or only with --inspect), you enter REPL mode, with --inspect it prints the value of each expression\nWith --record the clock values, random numbers and input the script reads are written to the trace, with --replay they are read from it\nWith --pgo-profile the calls and constant reads of the run are written to the profile, with --pgo-use the script is compiled for it");
     Ok(())
 }
@@ -1,17 +1,33 @@
-///! The runner for evie. This is invoked from the cmd line
-/// Evie supports both executing a file and repl mode
+//! The runner for evie. This is invoked from the cmd line
+//! Evie supports both executing a file and repl mode
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{self, stderr, Read, Write},
 };
 
-use evie_common::{errors::*, print_error};
-use evie_native::{clock, to_string};
-use evie_vm::vm::VirtualMachine;
+use evie_common::{errors::*, print_error, print_warning};
+use evie_compiler::pgo::Profile;
+use evie_frontend::{
+    ast::{parse_to_ast, Stmt},
+    scanner::{is_incomplete, Scanner},
+};
+use evie_memory::replay::{self, Replay};
+use evie_vm::vm::{Args, VirtualMachine};
+
+/// The path that reads the script from stdin, e.g. `cat script.evie | evie -`
+pub const STDIN_PATH: &str = "-";
+/// The inputs of the REPL whose compilations are kept, see [VirtualMachine::set_compilation_cache]
+const REPL_COMPILATION_CACHE: usize = 64;
 
 /// The runner is responsible for streaming code into the [VirtualMachine] via repl or  reading from a file
 pub struct Runner<'a> {
     vm: VirtualMachine<'a>,
+    /// The REPL prints the value of the expressions, see [Runner::set_inspect]
+    inspect: bool,
+    /// See [Runner::set_strict]
+    strict: bool,
+    /// See [Runner::set_max_heap_bytes]
+    max_heap_bytes: Option<usize>,
 }
 
 impl<'a> Runner<'a> {
@@ -20,42 +36,132 @@ impl<'a> Runner<'a> {
     pub fn new() -> Self {
         let mut vm = VirtualMachine::new();
         // Define native functions
-        evie_vm::vm::define_native_fn("clock", 0, &mut vm, clock);
-        evie_vm::vm::define_native_fn("to_string", 1, &mut vm, to_string);
-        Runner { vm }
+        for (name, arity, native_fn) in evie_native::all_natives() {
+            evie_vm::vm::define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        Runner {
+            vm,
+            inspect: false,
+            strict: false,
+            max_heap_bytes: None,
+        }
     }
 
-    /// Run the given script
+    /// Run the given script, [STDIN_PATH] reads it from stdin
     pub fn run_script(&mut self, path: &str) -> Result<()> {
-        let mut script = File::open(path).chain_err(|| "Unable to create file")?;
+        let mut script: Box<dyn Read> = if path == STDIN_PATH {
+            Box::new(io::stdin())
+        } else {
+            Box::new(File::open(path).chain_err(|| "Unable to create file")?)
+        };
         let mut script_contents = String::new();
         if script
             .read_to_string(&mut script_contents)
             .chain_err(|| "Unable to read file")?
             > 0
         {
-            self.run_vm(script_contents)?;
+            let source_name = if path == STDIN_PATH { "<stdin>" } else { path };
+            self.run_vm(script_contents, source_name)?;
         }
         self.vm.free();
         Ok(())
     }
-    /// REPL mode
+
+    /// Run the given source code, e.g. of `evie -e "print 1 + 2;"`
+    pub fn run_source(&mut self, source: String) -> Result<()> {
+        self.run_vm(source, "<-e>")?;
+        self.vm.free();
+        Ok(())
+    }
+
+    /// Run the given script, recording its nondeterministic inputs (see [evie_memory::replay]) to the trace file.
+    /// The trace is written even if the script fails, to reproduce the failure
+    pub fn record_script(&mut self, path: &str, trace: &str) -> Result<()> {
+        self.vm.runtime().set_replay(Replay::Recording(Vec::new()));
+        let result = self.run_script(path);
+        if let Replay::Recording(inputs) = self.vm.runtime().set_replay(Replay::Off) {
+            fs::write(trace, replay::to_trace(&inputs))
+                .chain_err(|| "Unable to write the trace")?;
+        }
+        result
+    }
+
+    /// Run the given script, replaying the nondeterministic inputs recorded in the trace file by [Runner::record_script].
+    /// Fails if the script diverged from the recorded run, see [evie_memory::replay]
+    pub fn replay_script(&mut self, path: &str, trace: &str) -> Result<()> {
+        let trace = fs::read_to_string(trace).chain_err(|| "Unable to read the trace")?;
+        let inputs = replay::from_trace(&trace)?;
+        self.vm
+            .runtime()
+            .set_replay(Replay::Replaying(inputs.into()));
+        let result = self.run_script(path);
+        let replay = self.vm.runtime().set_replay(Replay::Off);
+        result?;
+        replay.finish()
+    }
+
+    /// Run the given script, writing the [Profile] of the run (see [evie_compiler::pgo]) to the profile file.
+    /// The profile is written even if the script fails at runtime
+    pub fn profile_script(&mut self, path: &str, profile: &str) -> Result<()> {
+        self.vm.set_profiling(true);
+        let result = self.run_script(path);
+        self.vm.set_profiling(false);
+        if let Some(recorded) = self.vm.last_profile() {
+            fs::write(profile, recorded.to_json()).chain_err(|| "Unable to write the profile")?;
+        }
+        result
+    }
+
+    /// Run the given script compiled for the profile written by [Runner::profile_script]
+    pub fn run_script_with_profile(&mut self, path: &str, profile: &str) -> Result<()> {
+        let profile = fs::read_to_string(profile).chain_err(|| "Unable to read the profile")?;
+        self.vm.set_profile(Some(Profile::from_json(&profile)?));
+        let result = self.run_script(path);
+        self.vm.set_profile(None);
+        result
+    }
+
+    /// With inspect, the REPL prints the value of each expression it is given (e.g. `1 + 2` or `point`) with the
+    /// `inspect` native (see [evie_native::object::inspect]), as `print inspect(point);` would
+    pub fn set_inspect(&mut self, inspect: bool) {
+        self.inspect = inspect;
+    }
+
+    /// In strict mode the globals that are not defined (by the script, the natives or the previous inputs of the REPL)
+    /// fail the compilation and dividing by zero fails the run, see [Args::with_strict]
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Caps the heap of every run, a script that needs more fails with an out of memory error, see
+    /// [Args::with_max_heap_bytes]. None (the default) is unbounded
+    pub fn set_max_heap_bytes(&mut self, max_heap_bytes: Option<usize>) {
+        self.max_heap_bytes = max_heap_bytes;
+    }
+
+    /// REPL mode. The lines are read until the input is complete (see [is_incomplete]), so that a block or a
+    /// multi-line snippet is run as a whole
     pub fn repl(&mut self) -> Result<()> {
         println!("####### REPL mode (evie) ########");
+        // The same input again (e.g. a call to see what it returns now) is not compiled again
+        self.vm.set_compilation_cache(Some(REPL_COMPILATION_CACHE));
+        let mut input = String::new();
         loop {
-            print!("evie> ");
+            print!("{}", if input.is_empty() { "evie> " } else { "  ... " });
             io::stdout().flush().chain_err(|| "")?;
-            let mut line = String::new();
             let bytes = io::stdin()
-                .read_line(&mut line)
+                .read_line(&mut input)
                 .chain_err(|| "Unable to read stdin")?;
-            let result = self.run_vm(with_semi_colon(line.trim().to_string()));
-            match result {
-                Ok(_) => continue,
-                Err(e) => {
+            // At the end of stdin, what was read is run as it is
+            if bytes > 0 && is_incomplete(&input) {
+                continue;
+            }
+            let source = std::mem::take(&mut input);
+            if !source.trim().is_empty() {
+                if let Err(e) = self.eval(&source) {
                     print_error(e, &mut stderr());
                 }
-            };
+            }
             if bytes == 0 {
                 break;
             }
@@ -64,9 +170,50 @@ impl<'a> Runner<'a> {
         Ok(())
     }
 
-    fn run_vm(&mut self, source: String) -> Result<()> {
-        self.vm.interpret(source, None)?;
-        Ok(())
+    /// Runs an input of the REPL
+    fn eval(&mut self, input: &str) -> Result<()> {
+        let mut source = with_semi_colon(input.trim().to_string());
+        if self.inspect {
+            source = with_inspect(source);
+        }
+        self.run_vm(source, "<repl>")
+    }
+
+    /// Runs the source, `source_name` is shown in the stack traces of runtime errors
+    fn run_vm(&mut self, source: String, source_name: &str) -> Result<()> {
+        let mut args = Args::default()
+            .with_source_name(source_name)
+            .with_strict(self.strict);
+        if let Some(max_heap_bytes) = self.max_heap_bytes {
+            args = args.with_max_heap_bytes(max_heap_bytes);
+        }
+        let result = self.vm.interpret(source, Some(args));
+        for warning in self.vm.warnings() {
+            print_warning(warning, &mut stderr());
+        }
+        result
+    }
+}
+
+/// `print inspect(expression);` if the source is a single expression statement, else the source as it is (e.g. when
+/// it does not parse, the compiler reports why)
+fn with_inspect(source: String) -> String {
+    let parse = |source: &str| {
+        Scanner::new(source.to_string())
+            .scan_tokens()
+            .and_then(parse_to_ast)
+    };
+    if !matches!(parse(&source).as_deref(), Ok([Stmt::Expression(_)])) {
+        return source;
+    }
+    let inspected = format!(
+        "print inspect({});",
+        source.trim_end().trim_end_matches(';')
+    );
+    // e.g. with a trailing comment the expression is not the end of the source
+    match parse(&inspected) {
+        Ok(_) => inspected,
+        Err(_) => source,
     }
 }
 
@@ -76,3 +223,78 @@ pub fn with_semi_colon(mut line: String) -> String {
     }
     line
 }
+
+#[cfg(test)]
+mod tests {
+    use evie_common::errors::*;
+
+    use super::Runner;
+
+    #[test]
+    fn inspects_deep_and_long_expressions() -> Result<()> {
+        // Parsing up to the nesting limit takes more than the stack of a test thread in a debug build, the REPL runs
+        // on the main thread
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(|| {
+                let mut runner = Runner::new();
+                runner.set_inspect(true);
+                let deep = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+                let error = runner.eval(&deep).unwrap_err();
+                assert!(error.to_string().contains("Too deeply nested"), "{}", error);
+                // Not nested, a long chain is printed
+                runner.eval(&format!(
+                    "(fun(a) {{ return a{}; }})(1)",
+                    " + a - a".repeat(10_000)
+                ))?;
+                // The REPL carries on
+                runner.eval("var a = 1")?;
+                runner.eval("a")
+            })?
+            .join()
+            .expect("Expected no stack overflow")
+    }
+
+    #[test]
+    fn replays_only_the_recorded_run() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("evie_replay_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).chain_err(|| "Unable to create the directory")?;
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let script = |name: &str, source: &str| -> Result<String> {
+            std::fs::write(path(name), source).chain_err(|| "Unable to write the script")?;
+            Ok(path(name))
+        };
+        let trace = path("trace");
+        let recorded = script("recorded.evie", "print random(); print clock() > 0;")?;
+        Runner::new().record_script(&recorded, &trace)?;
+        Runner::new().replay_script(&recorded, &trace)?;
+        // The clock is read first, the recording has a random number
+        let swapped = script("swapped.evie", "print clock(); print random();")?;
+        let error = Runner::new().replay_script(&swapped, &trace).unwrap_err();
+        assert!(
+            error.to_string().contains("expected an input from clock"),
+            "{}",
+            error
+        );
+        // The clock is not read
+        let fewer = script("fewer.evie", "print random();")?;
+        let error = Runner::new().replay_script(&fewer, &trace).unwrap_err();
+        assert!(
+            error.to_string().contains("The replay has 1 inputs left"),
+            "{}",
+            error
+        );
+        std::fs::remove_dir_all(&dir).chain_err(|| "Unable to remove the directory")?;
+        Ok(())
+    }
+
+    #[test]
+    fn caps_the_heap() {
+        let mut runner = Runner::new();
+        runner.set_max_heap_bytes(Some(1 << 20));
+        let source = "var s = \"0123456789abcdef\"; for (i in 0..20) s = s + s;";
+        let error = runner.run_source(source.to_string()).unwrap_err();
+        assert!(error.to_string().contains("out of memory"), "{}", error);
+        assert!(Runner::new().run_source(source.to_string()).is_ok());
+    }
+}
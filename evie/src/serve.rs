@@ -0,0 +1,411 @@
+//! `evie serve`, runs scripts over HTTP (e.g. for the docs and a web playground).
+//!
+//! `POST /run` with the source as the body runs it in a fresh [VirtualMachine] and responds with JSON:
+//! ```text
+//! curl -d 'print 1 + 2;' localhost:8080/run
+//! {"error":null,"stderr":"","stdout":"3\n"}
+//! ```
+//! - `stdout` is what the script printed,
+//! - `stderr` are its warnings and its error as the command line prints them,
+//! - `error` is the error (null if the script ran to the end).
+//!
+//! Every request gets its own VM with the [Limits] applied and no input, and the natives that block or reach
+//! the host (`sleep` and the `process` ones) are not defined. Responses allow any origin, so a page can call it.
+//! At most [MAX_CONNECTIONS] are served at once, the connections over it get a 503.
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use evie_common::{errors::*, print_error, print_warning, utf8_to_string};
+use evie_memory::objects::NativeFn;
+use evie_vm::vm::{define_native_fn, Args, VirtualMachine};
+use serde_json::json;
+
+/// The address `evie serve` listens on by default
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+/// The largest source accepted, in bytes
+pub const MAX_SOURCE_BYTES: usize = 1 << 20;
+/// The most connections served at once
+pub const MAX_CONNECTIONS: usize = 64;
+/// How long a connection can take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// The most bytes of the request line and the headers together
+const MAX_HEAD_BYTES: usize = 16 << 10;
+/// The most headers of a request
+const MAX_HEADERS: usize = 64;
+
+/// The limits of every script run by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// See [VirtualMachine::set_max_instructions]
+    pub max_instructions: u64,
+    /// See [VirtualMachine::set_max_heap_bytes]
+    pub max_heap_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_instructions: 10_000_000,
+            max_heap_bytes: 64 << 20,
+        }
+    }
+}
+
+/// What a script run by the server printed and how it ended
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RunOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub error: Option<String>,
+}
+
+impl RunOutput {
+    /// The body of the response
+    pub fn to_json(&self) -> String {
+        json!({"stdout": self.stdout, "stderr": self.stderr, "error": self.error}).to_string()
+    }
+}
+
+/// Serves the requests of the listener, each connection on its own thread (up to [MAX_CONNECTIONS] at once).
+/// Only returns if the listener fails
+pub fn serve(listener: TcpListener, limits: Limits) -> Result<()> {
+    // Only this thread adds to it, a connection is counted before the next one is accepted
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = stream.chain_err(|| "Unable to accept the connection")?;
+        if connections.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+            let body = json!({ "error": "Too many connections, try again later" }).to_string();
+            if let Err(e) = respond(&stream, "503 Service Unavailable", &body) {
+                evie_common::warn!("Request failed: {}", e);
+            }
+            continue;
+        }
+        connections.fetch_add(1, Ordering::SeqCst);
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, limits) {
+                evie_common::warn!("Request failed: {}", e);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+/// Runs the source in a fresh VM with the limits
+pub fn run(source: String, limits: Limits) -> RunOutput {
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let result = {
+        let input = Box::new(std::io::empty());
+        let mut vm = VirtualMachine::new_with_reader_and_writer(Some(input), Some(&mut stdout));
+        for (name, arity, native_fn) in natives() {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        vm.set_max_instructions(Some(limits.max_instructions));
+        vm.set_max_heap_bytes(Some(limits.max_heap_bytes));
+        let result = vm.interpret(source, Some(Args::default().with_source_name("<serve>")));
+        for warning in vm.warnings() {
+            print_warning(warning, &mut stderr);
+        }
+        vm.free();
+        result
+    };
+    let error = result.err().map(|e| {
+        let message = e.to_string();
+        print_error(e, &mut stderr);
+        message
+    });
+    RunOutput {
+        stdout: utf8_to_string(&stdout),
+        stderr: utf8_to_string(&stderr),
+        error,
+    }
+}
+
+/// [evie_native::all_natives] without the ones that block or reach the host
+fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    let excluded = ["sleep"];
+    #[cfg(feature = "unsafe_natives")]
+    let excluded: Vec<_> = excluded
+        .into_iter()
+        .chain(
+            evie_native::process::natives()
+                .iter()
+                .map(|(name, ..)| *name),
+        )
+        .collect();
+    evie_native::all_natives()
+        .into_iter()
+        .filter(|(name, ..)| !excluded.contains(name))
+        .collect()
+}
+
+fn handle_connection(stream: TcpStream, limits: Limits) -> Result<()> {
+    stream
+        .set_read_timeout(Some(READ_TIMEOUT))
+        .chain_err(|| "Unable to set the timeout")?;
+    let mut reader = BufReader::new(&stream);
+    let (status, body) = match read_request(&mut reader)? {
+        Request::Run(source) => ("200 OK", run(source, limits).to_json()),
+        Request::Options => ("204 No Content", String::new()),
+        Request::Invalid(status, message) => (status, json!({ "error": message }).to_string()),
+    };
+    respond(&stream, status, &body)?;
+    // What is left of a request turned away (e.g. the headers over the limit) is read before closing, closing
+    // with unread bytes resets the connection and the client may lose the response
+    stream
+        .shutdown(Shutdown::Write)
+        .chain_err(|| "Unable to close the connection")?;
+    let _ = std::io::copy(
+        &mut reader.take(MAX_SOURCE_BYTES as u64),
+        &mut std::io::sink(),
+    );
+    Ok(())
+}
+
+fn respond(mut stream: &TcpStream, status: &str, body: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: POST, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .chain_err(|| "Unable to write the response")
+}
+
+enum Request {
+    /// `POST /run` with the source
+    Run(String),
+    /// A CORS preflight
+    Options,
+    /// Anything else, with the status and the reason
+    Invalid(&'static str, String),
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let head_too_large = || {
+        Ok(Request::Invalid(
+            "431 Request Header Fields Too Large",
+            format!(
+                "The headers are larger than {} bytes or more than {}",
+                MAX_HEAD_BYTES, MAX_HEADERS
+            ),
+        ))
+    };
+    let mut head_bytes = 0;
+    let request_line = match read_head_line(reader, &mut head_bytes)? {
+        Some(line) => line,
+        None => return head_too_large(),
+    };
+    let mut content_length = 0;
+    let mut headers = 0;
+    loop {
+        let header = match read_head_line(reader, &mut head_bytes)? {
+            Some(header) => header,
+            None => return head_too_large(),
+        };
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return head_too_large();
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = match value.trim().parse() {
+                    Ok(length) => length,
+                    Err(_) => {
+                        return Ok(Request::Invalid(
+                            "400 Bad Request",
+                            "Invalid Content-Length".into(),
+                        ))
+                    }
+                };
+            }
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("OPTIONS"), _) => return Ok(Request::Options),
+        (Some("POST"), Some("/run")) => {}
+        (_, Some("/run")) => {
+            return Ok(Request::Invalid(
+                "405 Method Not Allowed",
+                "Use POST /run".into(),
+            ))
+        }
+        _ => return Ok(Request::Invalid("404 Not Found", "Use POST /run".into())),
+    }
+    if content_length > MAX_SOURCE_BYTES {
+        return Ok(Request::Invalid(
+            "413 Payload Too Large",
+            format!("The source is larger than {} bytes", MAX_SOURCE_BYTES),
+        ));
+    }
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .chain_err(|| "Unable to read the request")?;
+    match String::from_utf8(body) {
+        Ok(source) => Ok(Request::Run(source)),
+        Err(_) => Ok(Request::Invalid(
+            "400 Bad Request",
+            "The source is not UTF-8".into(),
+        )),
+    }
+}
+
+/// Reads a line of the request line and headers, None once they take more than [MAX_HEAD_BYTES] (`head_bytes` so far)
+fn read_head_line(reader: &mut impl BufRead, head_bytes: &mut usize) -> Result<Option<String>> {
+    let remaining = MAX_HEAD_BYTES - *head_bytes;
+    let mut line = String::new();
+    reader
+        .take(remaining as u64)
+        .read_line(&mut line)
+        .chain_err(|| "Unable to read the request")?;
+    *head_bytes += line.len();
+    if line.len() == remaining && !line.ends_with('\n') {
+        return Ok(None);
+    }
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        thread,
+    };
+
+    use evie_common::errors::*;
+
+    use super::{run, serve, Limits, RunOutput, MAX_CONNECTIONS};
+
+    /// Sends the request to the server and returns the response
+    fn request(address: SocketAddr, request: &str) -> Result<String> {
+        let mut stream = TcpStream::connect(address)?;
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    /// A server on a free port
+    fn start(limits: Limits) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || serve(listener, limits));
+        Ok(address)
+    }
+
+    fn post(source: &str) -> String {
+        format!(
+            "POST /run HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            source.len(),
+            source
+        )
+    }
+
+    #[test]
+    fn runs_with_limits() {
+        let limits = Limits::default();
+        assert_eq!(
+            run("print 1 + 2;".into(), limits),
+            RunOutput {
+                stdout: "3\n".into(),
+                ..RunOutput::default()
+            }
+        );
+        let output = run("print 1; print nil + 1;".into(), limits);
+        assert_eq!(output.stdout, "1\n");
+        assert!(
+            output.stderr.contains("[Runtime Error]"),
+            "{}",
+            output.stderr
+        );
+        assert!(output.error.is_some());
+        let limits = Limits {
+            max_instructions: 1000,
+            ..limits
+        };
+        let output = run("while (true) {}".into(), limits);
+        assert!(output
+            .error
+            .unwrap()
+            .contains("Instruction limit of 1000 exceeded"));
+        assert!(run("sleep(1);".into(), limits).error.is_some());
+    }
+
+    #[test]
+    fn serves_over_http() -> Result<()> {
+        let address = start(Limits::default())?;
+        let response = request(address, &post("print \"hi\";"))?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.ends_with(r#"{"error":null,"stderr":"","stdout":"hi\n"}"#),
+            "{}",
+            response
+        );
+        let response = request(address, "GET /run HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+        let response = request(address, "GET / HTTP/1.1\r\n\r\n")?;
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+        Ok(())
+    }
+
+    #[test]
+    fn limits_the_requests() -> Result<()> {
+        let address = start(Limits {
+            max_heap_bytes: 1 << 20,
+            ..Limits::default()
+        })?;
+        let source =
+            r#"var s = "0123456789abcdef"; for (i in 0..23) { s = s + s; } print s.length();"#;
+        let response = request(address, &post(source))?;
+        assert!(
+            response.contains("out of memory, the heap is limited to 1048576 bytes"),
+            "{}",
+            response
+        );
+        let long_header = format!(
+            "POST /run HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+            "a".repeat(20 << 10)
+        );
+        let response = request(address, &long_header)?;
+        assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+        let many_headers = format!(
+            "POST /run HTTP/1.1\r\n{}\r\n",
+            "X-Header: a\r\n".repeat(100)
+        );
+        let response = request(address, &many_headers)?;
+        assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+        Ok(())
+    }
+
+    #[test]
+    fn limits_the_connections() -> Result<()> {
+        let address = start(Limits::default())?;
+        // Connections that have not sent their request yet
+        let open: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(address))
+            .collect::<std::io::Result<_>>()?;
+        // Turned away before its request is read
+        let response = request(address, "")?;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        drop(open);
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! `evie test <dir>`, runs the evie tests in a directory.
+//!
+//! Every `*_test.evie` file in the directory (and its sub directories) is a test. Each one runs in a fresh
+//! [VirtualMachine] with the default natives (including the [evie_native::assert] natives), its output is captured
+//! and only printed if it fails. A test fails if it does not compile or fails at runtime, e.g. on a failed assertion.
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use evie_common::{errors::*, print_error, utf8_to_string};
+use evie_vm::vm::{define_native_fn, Args, VirtualMachine};
+
+/// The suffix of the files that are tests
+pub const TEST_SUFFIX: &str = "_test.evie";
+
+/// The result of a test run
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TestSummary {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+/// Runs the tests in `dir`, writing the report to `writer`
+pub fn run_tests(dir: &str, writer: &mut dyn Write) -> Result<TestSummary> {
+    let mut tests = vec![];
+    find_tests(Path::new(dir), &mut tests)?;
+    tests.sort();
+    writeln!(writer, "running {} tests", tests.len())?;
+    let mut summary = TestSummary::default();
+    let mut failures = vec![];
+    for test in tests {
+        let name = test
+            .strip_prefix(dir)
+            .unwrap_or(&test)
+            .display()
+            .to_string();
+        let (passed, output) = run_test(&test)?;
+        writeln!(
+            writer,
+            "test {} ... {}",
+            name,
+            if passed { "ok" } else { "FAILED" }
+        )?;
+        if passed {
+            summary.passed += 1;
+        } else {
+            summary.failed += 1;
+            failures.push((name, output));
+        }
+    }
+    if !failures.is_empty() {
+        writeln!(writer, "\nfailures:")?;
+        for (name, output) in failures {
+            writeln!(writer, "\n---- {} ----\n{}", name, output.trim_end())?;
+        }
+    }
+    writeln!(
+        writer,
+        "\ntest result: {}. {} passed; {} failed",
+        if summary.failed == 0 { "ok" } else { "FAILED" },
+        summary.passed,
+        summary.failed
+    )?;
+    Ok(summary)
+}
+
+/// Runs the test in a fresh VM, returns whether it passed and its output (and error)
+fn run_test(path: &Path) -> Result<(bool, String)> {
+    let source = fs::read_to_string(path).chain_err(|| "Unable to read file")?;
+    let mut output = vec![];
+    let result = {
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut output));
+        for (name, arity, native_fn) in evie_native::all_natives() {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        let name = path.display().to_string();
+        vm.interpret(source, Some(Args::default().with_source_name(&name)))
+    };
+    let passed = result.is_ok();
+    if let Err(e) = result {
+        print_error(e, &mut output);
+    }
+    Ok((passed, utf8_to_string(&output)))
+}
+
+fn find_tests(dir: &Path, tests: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = fs::read_dir(dir).chain_err(|| format!("Unable to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry.chain_err(|| "Unable to read directory")?.path();
+        if path.is_dir() {
+            find_tests(&path, tests)?;
+        } else if path.to_string_lossy().ends_with(TEST_SUFFIX) {
+            tests.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_common::{errors::*, utf8_to_string};
+
+    use super::{run_tests, TestSummary};
+
+    #[test]
+    fn run_test_dir() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("evie_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested"))?;
+        std::fs::write(
+            dir.join("math_test.evie"),
+            "assert_eq(1 + 1, 2); assert(true, \"true\");",
+        )?;
+        std::fs::write(
+            dir.join("nested/failing_test.evie"),
+            "print \"before\"; assert_eq(\"a\", \"b\");",
+        )?;
+        std::fs::write(dir.join("helper.evie"), "assert(false, \"not a test\");")?;
+        let mut report = vec![];
+        let summary = run_tests(dir.to_str().unwrap(), &mut report)?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(
+            TestSummary {
+                passed: 1,
+                failed: 1
+            },
+            summary
+        );
+        let report = utf8_to_string(&report);
+        assert!(report.contains("test math_test.evie ... ok"), "{}", report);
+        // The output of the failing test is reported
+        assert!(
+            report.contains("---- nested/failing_test.evie ----\nbefore\n"),
+            "{}",
+            report
+        );
+        // The stack trace names the test file
+        assert!(
+            report.contains("failing_test.evie:1] in <fn script>"),
+            "{}",
+            report
+        );
+        assert!(
+            report.contains("Assertion failed: expected 'b', got 'a'"),
+            "{}",
+            report
+        );
+        assert!(
+            report.ends_with("test result: FAILED. 1 passed; 1 failed\n"),
+            "{}",
+            report
+        );
+        Ok(())
+    }
+}
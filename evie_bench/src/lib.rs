@@ -1,144 +1,440 @@
-/**
- * This test runs the bench mark tests and prints the timing information between clox and vm.
- * It does not assert on anything yet.
- */
-#[cfg(test)]
-mod tests {
-    const TEST_CASE_PATH: &str = "/Users/kprajith/workspace/rust/evie-lang/evie_bench/files";
-    const CLOX_PATH: &str =
-        "/Users/kprajith/workspace/crafting-interpretors/craftinginterpreters/clox";
-    const VM_PATH: &str = "/Users/kprajith/workspace/rust/evie-lang/target/release/evie";
-    const WS_PATH: &str = "/Users/kprajith/workspace/rust/evie-lang/Cargo.toml";
-    use cli_table::{print_stdout, Cell, Color, Style, Table};
-    use evie_common::{bail, errors::*};
-    use std::{ffi::OsStr, fs, path::Path, process::Command, time::Instant};
+//! The benchmark harness for evie.
+//!
+//! Runs every script in a directory with evie (and clox, if configured, to compare), a few warmup runs and then
+//! `iterations` timed runs of each, and reports the mean and standard deviation of the wall clock time.
+//! The means can be saved as a baseline, later runs fail if a script got slower than its baseline by more than
+//! the threshold (in percent).
+//!
+//! The [Config] is read from a TOML file (see `evie_bench/bench.toml`), any of its keys can be overridden by an
+//! environment variable named `EVIE_BENCH_<KEY>` (e.g. `EVIE_BENCH_ITERATIONS=10`).
+//! Run it with `cargo run -p evie_bench --release -- [--save-baseline] [path to config]`.
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Instant,
+};
 
-    #[test]
-    fn perf_timings() -> Result<()> {
-        let test_case_path =
-            std::env::var("TEST_CASE_PATH").unwrap_or_else(|_| TEST_CASE_PATH.into());
-        let clox_path = std::env::var("CLOX_PATH").unwrap_or_else(|_| CLOX_PATH.into());
-        let vm_path = std::env::var("VM_PATH").unwrap_or_else(|_| VM_PATH.into());
-        let ws_path = std::env::var("WS_PATH").unwrap_or_else(|_| WS_PATH.into());
-        println!("This test runs the bench mark tests and compares the timing (performance) between clox and vm.\nIt DOES NOT  assert on anything!\n");
-        println!("Building release...");
-        cargo_build_release(&ws_path)?;
-        if !binary_path_exists(&clox_path, &vm_path) {
-            eprintln!(
-                "Binaries ({} or {}) does not exist, exiting!",
-                clox_path, vm_path
-            );
-            // Exit early if there is nothing to run
-            return Ok(());
+use cli_table::{print_stdout, Cell, Color, Style, Table};
+use evie_common::{bail, errors::*};
+
+/// The configuration of a benchmark run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// The directory with the scripts
+    pub files: PathBuf,
+    /// The evie binary
+    pub evie: PathBuf,
+    /// The clox binary to compare with, if any
+    pub clox: Option<PathBuf>,
+    /// The runs of each script before the timed ones
+    pub warmup: usize,
+    /// The timed runs of each script
+    pub iterations: usize,
+    /// The file with the baseline means, if any
+    pub baseline: Option<PathBuf>,
+    /// How much slower than its baseline (in percent) a script can get before it is a regression
+    pub threshold: f64,
+    /// Build evie (release) before running
+    pub build: bool,
+}
+
+impl Default for Config {
+    /// The scripts in `evie_bench/files` with the release build of this workspace, no clox and no baseline
+    fn default() -> Self {
+        let workspace = workspace();
+        Config {
+            files: workspace.join("evie_bench/files"),
+            evie: workspace.join("target/release/evie"),
+            clox: None,
+            warmup: 1,
+            iterations: 5,
+            baseline: None,
+            threshold: 10.0,
+            build: true,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config from the file (if any) and the environment, relative paths in the file are relative to it
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let mut config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .chain_err(|| format!("Unable to read {}", path.display()))?;
+                Config::from_toml(&text, path.parent().unwrap_or_else(|| Path::new(".")))?
+            }
+            None => Config::default(),
+        };
+        config.apply(|key| std::env::var(format!("EVIE_BENCH_{}", key.to_uppercase())).ok())?;
+        Ok(config)
+    }
+
+    /// The config in the TOML text, on top of the [Config::default], relative paths are resolved against `base`
+    pub fn from_toml(text: &str, base: &Path) -> Result<Config> {
+        let table: toml::value::Table = toml::from_str(text).chain_err(|| "Invalid config")?;
+        let mut config = Config::default();
+        let value = |key: &str| -> Option<String> {
+            table.get(key).map(|v| match v {
+                toml::Value::String(s) => match key {
+                    "files" | "evie" | "clox" | "baseline" => base.join(s).display().to_string(),
+                    _ => s.clone(),
+                },
+                v => v.to_string(),
+            })
+        };
+        config.apply(value)?;
+        if let Some(key) = table.keys().find(|key| !KEYS.contains(&key.as_str())) {
+            bail!(format!("Unknown config key '{}'", key))
         }
-        println!("Built release, starting test...");
-        let dir_path = Path::new(&test_case_path);
-        let mut entries: Vec<_> = fs::read_dir(dir_path)?.collect();
-        entries.sort_by(|a, b| {
-            let a = a.as_ref().unwrap();
-            let b = b.as_ref().unwrap();
-            a.file_name().cmp(&b.file_name())
-        });
-        let mut table = vec![];
-        let allow_listed_entries = entries;
-        // let allow_listed_entries = entries.into_iter().filter(|e| {
-        //     [OsStr::new("zoo_batch.lox").to_os_string()].contains(&e.as_ref().unwrap().file_name())
-        // });
-        for entry in allow_listed_entries {
-            let e = entry?;
-            if e.file_type()?.is_file() {
-                let file_name = String::from(e.file_name().to_string_lossy());
-                let path = e.path();
-                println!("Benchmark for {:?}", path.as_os_str());
-                let timed_taken_by_vm = run_vm(path.as_os_str(), &vm_path)?;
-                let timed_taken_by_clox = run_clox(path.as_os_str(), &clox_path)?;
-                let percentage_difference =
-                    ((timed_taken_by_vm / timed_taken_by_clox) * 100f64) - 100f64;
-                let percentage_difference_styled = if percentage_difference < 0f64 {
-                    percentage_difference
-                        .cell()
-                        .background_color(Some(Color::Green))
-                } else {
-                    percentage_difference.cell().bold(true)
-                };
-                println!("Timing for test = {}, time taken by clox ={}, time taken by vm = {}, difference = {} %", file_name, timed_taken_by_clox, timed_taken_by_vm, percentage_difference);
-                table.push(vec![
-                    file_name.cell(),
-                    timed_taken_by_clox.cell(),
-                    timed_taken_by_vm.cell(),
-                    percentage_difference_styled,
-                ]);
+        Ok(config)
+    }
+
+    /// Sets the values `value` has for the keys
+    fn apply<F: Fn(&str) -> Option<String>>(&mut self, value: F) -> Result<()> {
+        for key in KEYS {
+            let value = match value(key) {
+                Some(value) => value,
+                None => continue,
+            };
+            let invalid = || format!("Invalid value '{}' for {}", value, key);
+            match key {
+                "files" => self.files = value.into(),
+                "evie" => self.evie = value.into(),
+                "clox" => self.clox = Some(value.into()),
+                "baseline" => self.baseline = Some(value.into()),
+                "warmup" => self.warmup = value.parse().chain_err(invalid)?,
+                "iterations" => self.iterations = value.parse().chain_err(invalid)?,
+                "threshold" => self.threshold = value.parse().chain_err(invalid)?,
+                "build" => self.build = value.parse().chain_err(invalid)?,
+                _ => unreachable!("every key is handled"),
             }
         }
-        let table = table
-            .table()
-            .title(vec![
-                "Test".cell().bold(true),
-                "Clox time in seconds".cell().bold(true),
-                "Vm time in seconds".cell().bold(true),
-                "Percentage difference".cell().bold(true),
-            ])
-            .bold(true);
-
-        println!("\nFinal results:");
-        print_stdout(table)?;
+        if self.iterations == 0 {
+            bail!("iterations must be at least 1")
+        }
         Ok(())
     }
+}
+
+const KEYS: [&str; 8] = [
+    "files",
+    "evie",
+    "clox",
+    "warmup",
+    "iterations",
+    "baseline",
+    "threshold",
+    "build",
+];
+
+/// The workspace this crate is in
+fn workspace() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("evie_bench is in the workspace")
+        .to_path_buf()
+}
 
-    fn binary_path_exists(clox_path: &str, vm_path: &str) -> bool {
-        Path::new(clox_path).exists() && Path::new(vm_path).exists()
+/// Statistics of the timings (in seconds) of a script
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub mean: f64,
+    /// The sample standard deviation, 0 for a single sample
+    pub stddev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Stats {
+    pub fn from_samples(samples: &[f64]) -> Stats {
+        assert!(!samples.is_empty(), "Expected at least one sample");
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = if samples.len() > 1 {
+            samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1.0)
+        } else {
+            0.0
+        };
+        Stats {
+            mean,
+            stddev: variance.sqrt(),
+            min: samples.iter().copied().fold(f64::INFINITY, f64::min),
+            max: samples.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        }
     }
+}
+
+/// The result of benchmarking a script
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    /// The file name of the script
+    pub name: String,
+    pub evie: Stats,
+    pub clox: Option<Stats>,
+}
 
-    fn run_clox(path: &OsStr, clox_path: &str) -> Result<f64> {
-        run(OsStr::new(clox_path), path)
+/// Runs every script in the configured directory, in the order of their names
+pub fn run_benchmarks(config: &Config) -> Result<Vec<BenchResult>> {
+    if config.build {
+        build_release()?;
+    }
+    if !config.evie.exists() {
+        bail!(format!("{} does not exist", config.evie.display()))
     }
+    let mut scripts: Vec<PathBuf> = fs::read_dir(&config.files)
+        .chain_err(|| format!("Unable to read {}", config.files.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    scripts.retain(|path| path.is_file());
+    scripts.sort();
+    let mut results = vec![];
+    for script in scripts {
+        let name = script
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        println!("Benchmark for {}", name);
+        let evie = time(&config.evie, &script, config.warmup, config.iterations)?;
+        let clox = match &config.clox {
+            Some(clox) => Some(time(clox, &script, config.warmup, config.iterations)?),
+            None => None,
+        };
+        results.push(BenchResult { name, evie, clox });
+    }
+    Ok(results)
+}
 
-    fn run_vm(path: &OsStr, vm_path: &str) -> Result<f64> {
-        run(OsStr::new(vm_path), path)
+/// Runs the script with the executable `warmup` times and then times `iterations` runs.
+/// Fails if a run fails (exits with a nonzero status).
+pub fn time(executable: &Path, script: &Path, warmup: usize, iterations: usize) -> Result<Stats> {
+    for _ in 0..warmup {
+        run(executable, script)?;
     }
+    let samples = (0..iterations)
+        .map(|_| run(executable, script))
+        .collect::<Result<Vec<f64>>>()?;
+    Ok(Stats::from_samples(&samples))
+}
 
-    fn run(path_to_executable: &OsStr, path_to_file: &OsStr) -> Result<f64> {
-        let command = &mut Command::new(path_to_executable);
-        command.arg(path_to_file);
-        let start_time = Instant::now();
-        let output = command.output()?;
-        let stdout =
-            std::str::from_utf8(&output.stdout).map_err(|e| ErrorKind::Msg(e.to_string()))?;
-        let stderr =
-            std::str::from_utf8(&output.stderr).map_err(|e| ErrorKind::Msg(e.to_string()))?;
-        println!("RAN  {:?}{:?}", path_to_executable, path_to_file);
-        println!("STDOUT:{}", stdout);
-        println!("STDERR:{}", stderr);
-        println!("---------------------------------");
-        if !output.status.success() {
-            bail!("Error")
-        } else {
-            Ok(start_time.elapsed().as_secs_f64())
-        }
+/// Runs the script, returns the time it took in seconds
+fn run(executable: &Path, script: &Path) -> Result<f64> {
+    let start_time = Instant::now();
+    let output = Command::new(executable)
+        .arg(script)
+        .output()
+        .chain_err(|| format!("Unable to run {}", executable.display()))?;
+    let elapsed = start_time.elapsed().as_secs_f64();
+    if !output.status.success() {
+        bail!(format!(
+            "{} {} failed with {}\nSTDOUT:{}\nSTDERR:{}",
+            executable.display(),
+            script.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))
     }
+    Ok(elapsed)
+}
 
-    fn cargo_build_release(ws_path: &str) -> Result<()> {
-        let cargo_run_release = Command::new("cargo")
-            .arg("build")
-            .arg("--release")
-            .arg("--features=nan_boxed")
-            .arg("--manifest-path")
-            .arg(ws_path)
-            .output()?;
-        if !cargo_run_release.status.success() {
-            println!(
-                "{}",
-                std::str::from_utf8(&cargo_run_release.stderr)
-                    .map_err(|e| ErrorKind::Msg(e.to_string()))?
-            );
-            bail!("Error running cargo")
-        } else {
-            println!(
-                "{}",
-                std::str::from_utf8(&cargo_run_release.stdout)
-                    .map_err(|e| ErrorKind::Msg(e.to_string()))?
-            );
-            Ok(())
-        }
+/// Builds the release evie binary of the workspace
+fn build_release() -> Result<()> {
+    println!("Building release...");
+    let output = Command::new(env!("CARGO"))
+        .args(["build", "--release", "-p", "evie", "--features=nan_boxed"])
+        .arg("--manifest-path")
+        .arg(workspace().join("Cargo.toml"))
+        .output()?;
+    if !output.status.success() {
+        bail!(format!(
+            "Error running cargo\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+    Ok(())
+}
+
+/// The mean time of each script (by name) in seconds
+pub type Baseline = BTreeMap<String, f64>;
+
+/// Reads a baseline written by [write_baseline]
+pub fn read_baseline(path: &Path) -> Result<Baseline> {
+    let text =
+        fs::read_to_string(path).chain_err(|| format!("Unable to read {}", path.display()))?;
+    toml::from_str(&text).chain_err(|| format!("Invalid baseline {}", path.display()))
+}
+
+/// Writes the means of the results as the baseline, a TOML table of file name = seconds
+pub fn write_baseline(path: &Path, results: &[BenchResult]) -> Result<()> {
+    let baseline: Baseline = results
+        .iter()
+        .map(|result| (result.name.clone(), result.evie.mean))
+        .collect();
+    let text = toml::to_string(&baseline).chain_err(|| "Unable to write the baseline")?;
+    fs::write(path, text).chain_err(|| format!("Unable to write {}", path.display()))
+}
+
+/// A script that got slower than its baseline
+#[derive(Debug, Clone, PartialEq)]
+pub struct Regression {
+    pub name: String,
+    pub baseline: f64,
+    pub mean: f64,
+    /// How much slower, in percent
+    pub percent: f64,
+}
+
+/// The scripts that got slower than their baseline by more than `threshold` percent.
+/// Scripts that are not in the baseline are not compared.
+pub fn regressions(
+    results: &[BenchResult],
+    baseline: &Baseline,
+    threshold: f64,
+) -> Vec<Regression> {
+    results
+        .iter()
+        .filter_map(|result| {
+            let base = *baseline.get(&result.name)?;
+            let percent = (result.evie.mean / base - 1.0) * 100.0;
+            (percent > threshold).then(|| Regression {
+                name: result.name.clone(),
+                baseline: base,
+                mean: result.evie.mean,
+                percent,
+            })
+        })
+        .collect()
+}
+
+/// Prints the results as a table, with the difference to clox (if it ran) and the regressions
+pub fn print_report(results: &[BenchResult], regressions: &[Regression]) -> Result<()> {
+    let rows: Vec<_> = results
+        .iter()
+        .map(|result| {
+            let difference = match result.clox {
+                Some(clox) => {
+                    let percentage = (result.evie.mean / clox.mean) * 100.0 - 100.0;
+                    let cell = format!("{:.2}", percentage).cell();
+                    if percentage < 0.0 {
+                        cell.background_color(Some(Color::Green))
+                    } else {
+                        cell.bold(true)
+                    }
+                }
+                None => "-".cell(),
+            };
+            let regression = match regressions.iter().find(|r| r.name == result.name) {
+                Some(r) => format!("+{:.2} %", r.percent)
+                    .cell()
+                    .background_color(Some(Color::Red)),
+                None => "".cell(),
+            };
+            vec![
+                result.name.clone().cell(),
+                format!("{:.4} ± {:.4}", result.evie.mean, result.evie.stddev).cell(),
+                result
+                    .clox
+                    .map_or("-".to_string(), |c| {
+                        format!("{:.4} ± {:.4}", c.mean, c.stddev)
+                    })
+                    .cell(),
+                difference,
+                regression,
+            ]
+        })
+        .collect();
+    let table = rows
+        .table()
+        .title(vec![
+            "Test".cell().bold(true),
+            "Evie time in seconds".cell().bold(true),
+            "Clox time in seconds".cell().bold(true),
+            "Percentage difference".cell().bold(true),
+            "Regression".cell().bold(true),
+        ])
+        .bold(true);
+    print_stdout(table)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use evie_common::errors::*;
+
+    use super::*;
+
+    #[test]
+    fn stats() {
+        let stats = Stats::from_samples(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(2.5, stats.mean);
+        assert!((stats.stddev - 1.2909944).abs() < 1e-6);
+        assert_eq!((1.0, 4.0), (stats.min, stats.max));
+        assert_eq!(0.0, Stats::from_samples(&[1.0]).stddev);
+    }
+
+    #[test]
+    fn config() -> Result<()> {
+        let text = r#"
+            files = "scripts"
+            clox = "/usr/bin/clox"
+            iterations = 3
+            threshold = 5.5
+            build = false
+        "#;
+        let mut config = Config::from_toml(text, Path::new("/bench"))?;
+        assert_eq!(Path::new("/bench/scripts"), config.files);
+        assert_eq!(Some(Path::new("/usr/bin/clox").into()), config.clox);
+        assert_eq!(
+            (1, 3, 5.5, false),
+            (
+                config.warmup,
+                config.iterations,
+                config.threshold,
+                config.build
+            )
+        );
+        assert_eq!(Config::default().evie, config.evie);
+        // The environment overrides the file
+        config.apply(|key| (key == "iterations").then(|| "7".to_string()))?;
+        assert_eq!(7, config.iterations);
+        assert!(config
+            .apply(|key| (key == "warmup").then(|| "x".to_string()))
+            .is_err());
+        assert!(Config::from_toml("iterations = 0", Path::new("/")).is_err());
+        assert!(Config::from_toml("unknown = 1", Path::new("/")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn baseline_and_regressions() -> Result<()> {
+        let result = |name: &str, mean: f64| BenchResult {
+            name: name.to_string(),
+            evie: Stats::from_samples(&[mean]),
+            clox: None,
+        };
+        let results = vec![result("fast.lox", 1.0), result("slow.lox", 2.0)];
+        let path =
+            std::env::temp_dir().join(format!("evie_bench_baseline_{}.toml", std::process::id()));
+        write_baseline(&path, &results)?;
+        let mut baseline = read_baseline(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!(Some(&2.0), baseline.get("slow.lox"));
+        baseline.insert("slow.lox".to_string(), 1.5);
+        let later = vec![
+            result("fast.lox", 1.05),
+            result("slow.lox", 2.0),
+            result("new.lox", 9.0),
+        ];
+        let regressions = regressions(&later, &baseline, 10.0);
+        assert_eq!(1, regressions.len());
+        assert_eq!("slow.lox", regressions[0].name);
+        assert!((regressions[0].percent - 33.33).abs() < 0.01);
+        Ok(())
     }
 }
@@ -0,0 +1,70 @@
+use std::{io::stderr, path::Path};
+
+use evie_bench::{
+    print_report, read_baseline, regressions, run_benchmarks, write_baseline, Config,
+};
+use evie_common::{bail, errors::*, print_error};
+
+/// `evie_bench [--save-baseline] [path to config]`, exits with 1 if a script regressed against the baseline
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (save_baseline, config) = match args.as_slice() {
+        [flag, rest @ ..] if flag == "--save-baseline" => (true, rest),
+        rest => (false, rest),
+    };
+    let config = match config {
+        [] => std::env::var("EVIE_BENCH_CONFIG").ok(),
+        [path] => Some(path.clone()),
+        _ => {
+            eprintln!("Usage: evie_bench [--save-baseline] [path to config]");
+            std::process::exit(2);
+        }
+    };
+    match run(config.as_deref().map(Path::new), save_baseline) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            print_error(e, &mut stderr());
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Runs the benchmarks, returns false if there are regressions
+fn run(config: Option<&Path>, save_baseline: bool) -> Result<bool> {
+    let config = Config::load(config)?;
+    let results = run_benchmarks(&config)?;
+    let baseline = match &config.baseline {
+        Some(path) if save_baseline => {
+            write_baseline(path, &results)?;
+            println!("Saved the baseline to {}", path.display());
+            None
+        }
+        Some(path) if path.exists() => Some(read_baseline(path)?),
+        Some(path) => {
+            println!(
+                "No baseline at {}, save one with --save-baseline",
+                path.display()
+            );
+            None
+        }
+        None if save_baseline => bail!("There is no baseline in the config"),
+        None => None,
+    };
+    let regressions = baseline.map_or_else(Vec::new, |baseline| {
+        regressions(&results, &baseline, config.threshold)
+    });
+    println!("\nFinal results:");
+    print_report(&results, &regressions)?;
+    for regression in &regressions {
+        eprintln!(
+            "{} regressed by {:.2} % (baseline {:.4}s, now {:.4}s), the threshold is {} %",
+            regression.name,
+            regression.percent,
+            regression.baseline,
+            regression.mean,
+            config.threshold
+        );
+    }
+    Ok(regressions.is_empty())
+}
@@ -18,15 +18,34 @@ pub mod errors {
                 display("Parse Error: {}", message)
             }
 
+            /// Resolution errors, e.g. a redeclared local variable
+            ResolutionError(message: String) {
+                description("Resolution Error")
+                display("Resolution Error: {}", message)
+            }
+
+            /// Several errors of one compilation (e.g. every parse error), in the order they were found.
+            /// A compilation with a single error fails with that error
+            CompileErrors(errors: Vec<ErrorKind>) {
+                description("Compile Errors")
+                display("{}", errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"))
+            }
+
             /// Runtime errors
             RuntimeError(message: String) {
                 description("Runtime Error")
                 display("Runtime Error: {}", message)
             }
+
+            /// Warnings, these do not fail the compilation but are reported (e.g. unused variables)
+            Warning(line: usize, column: usize, message: String) {
+                description("Warning")
+                display("Warning: [line: {}, column: {}] {}", line, column, message)
+            }
         }
 
         foreign_links {
-            Io(::std::io::Error) #[cfg(unix)];
+            Io(::std::io::Error);
         }
     }
 }
@@ -34,8 +53,11 @@ pub use env_logger;
 pub use error_chain::bail;
 pub use errors::*;
 pub use log::*;
-use std::io::Write;
-pub type Writer<'a> = &'a mut dyn Write;
+use std::io::{BufRead, Write};
+/// A custom output sink. It is [Send] so that whatever owns it (e.g. the VM) can be moved across threads.
+pub type Writer<'a> = &'a mut (dyn Write + Send);
+/// A custom input source, the counterpart of [Writer]. It is owned (boxed) as it lives in the runtime.
+pub type Reader = Box<dyn BufRead + Send>;
 pub type ByteUnit = u8;
 
 pub fn report_error(message: String, error_writer: Writer) {
@@ -58,21 +80,32 @@ pub fn report_error_with_line_and_location(
     );
 }
 pub fn utf8_to_string(bytes: &[u8]) -> String {
-    match String::from_utf8(bytes.to_vec()) {
-        Ok(s) => s,
-        Err(_) => String::new(),
-    }
+    String::from_utf8(bytes.to_vec()).unwrap_or_default()
 }
 
 pub fn print_error(e: Error, error_writer: &mut dyn Write) {
     match e.0 {
         ErrorKind::ScanError(i) => print_error_kind_message("[Scan Error]", &i, error_writer),
         ErrorKind::ParseError(i) => print_error_kind_message("[Parse Error]", &i, error_writer),
+        ErrorKind::ResolutionError(i) => {
+            print_error_kind_message("[Resolution Error]", &i, error_writer)
+        }
+        ErrorKind::CompileErrors(errors) => errors
+            .into_iter()
+            .for_each(|e| print_error(e.into(), error_writer)),
         ErrorKind::RuntimeError(i) => print_error_kind_message("[Runtime Error]", &i, error_writer),
+        ErrorKind::Warning(..) => print_warning(&e.0, error_writer),
         _ => print_error_kind_message("Unknown", &e.to_string(), error_writer),
     };
 }
 
+pub fn print_warning(warning: &ErrorKind, warning_writer: &mut dyn Write) {
+    if let ErrorKind::Warning(line, column, message) = warning {
+        let message = format!("[line: {}, column: {}] {}", line, column, message);
+        print_error_kind_message("[Warning]", &message, warning_writer)
+    }
+}
+
 fn print_error_kind_message(kind: &str, message: &str, error_writer: &mut dyn Write) {
     writeln!(error_writer, "{} {}", kind, message).expect("Write failed");
 }
@@ -0,0 +1,195 @@
+//! The compiled output, for tools (disassemblers, analyzers, the language server) that inspect the byte code without
+//! running it.
+//!
+//! An [Artifact] lists every function of a script: the script itself first, then the functions, methods and
+//! anonymous functions in the order they appear in the source. Each [CompiledFunction] gives read only access to its
+//! [Chunk] (the code and the constants) and to the upvalues its closure captures.
+use evie_common::ByteUnit;
+use evie_memory::{
+    chunk::Chunk,
+    objects::{GCObjectOf, UserDefinedFunction},
+};
+
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+
+/// What a closure captures for one of its upvalues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueCapture {
+    /// A local of the enclosing function, or else an upvalue of the enclosing function
+    pub is_local: bool,
+    /// The slot of the local, or the index of the upvalue in the enclosing function
+    pub index: ByteUnit,
+}
+
+/// A function of an [Artifact]
+#[derive(Debug, Clone)]
+pub struct CompiledFunction {
+    pub(crate) function: GCObjectOf<UserDefinedFunction>,
+    pub(crate) enclosing: Option<usize>,
+    pub(crate) upvalues: Vec<UpvalueCapture>,
+}
+
+impl CompiledFunction {
+    pub(crate) fn new(function: GCObjectOf<UserDefinedFunction>, enclosing: Option<usize>) -> Self {
+        CompiledFunction {
+            function,
+            enclosing,
+            upvalues: Vec::new(),
+        }
+    }
+
+    /// The name, None for the script
+    pub fn name(&self) -> Option<&str> {
+        self.function
+            .as_ref()
+            .name
+            .as_ref()
+            .map(|name| &**name.as_ref())
+    }
+
+    /// The name an anonymous function is assigned to, see [UserDefinedFunction::inferred_name]
+    pub fn inferred_name(&self) -> Option<&str> {
+        self.function
+            .as_ref()
+            .inferred_name
+            .as_ref()
+            .map(|name| &**name.as_ref())
+    }
+
+    pub fn arity(&self) -> usize {
+        self.function.arity
+    }
+
+    /// The number of fields an `init` sets, see [UserDefinedFunction::field_count]
+    pub fn field_count(&self) -> usize {
+        self.function.field_count
+    }
+
+    /// The function (index in [Artifact::functions]) it is declared in, None for the script
+    pub fn enclosing(&self) -> Option<usize> {
+        self.enclosing
+    }
+
+    /// The upvalues captured when the closure of the function is created, in the order of its upvalue indices
+    pub fn upvalues(&self) -> &[UpvalueCapture] {
+        &self.upvalues
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        self.function.as_ref().chunk.as_ref()
+    }
+
+    pub fn code(&self) -> &[ByteUnit] {
+        &self.chunk().code.inner
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.chunk().constants.inner
+    }
+
+    /// The function object, e.g. to run it in a VM
+    pub fn function(&self) -> GCObjectOf<UserDefinedFunction> {
+        self.function
+    }
+}
+
+/// The functions of a compiled script, see the [module docs](self)
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    pub(crate) functions: Vec<CompiledFunction>,
+}
+
+impl Artifact {
+    /// The top level code
+    pub fn script(&self) -> &CompiledFunction {
+        &self.functions[0]
+    }
+
+    pub fn functions(&self) -> &[CompiledFunction] {
+        &self.functions
+    }
+
+    /// The first function with the name
+    pub fn function(&self, name: &str) -> Option<&CompiledFunction> {
+        self.functions.iter().find(|f| f.name() == Some(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_common::errors::*;
+    use evie_frontend::scanner::Scanner;
+    use evie_memory::ObjectAllocator;
+
+    use super::UpvalueCapture;
+    use crate::compiler::Compiler;
+
+    #[test]
+    fn artifact() -> Result<()> {
+        let source = r#"
+        fun counter(start) {
+            var count = start;
+            fun increment() {
+                fun add() { count = count + 1; return count; }
+                return add();
+            }
+            return increment;
+        }
+        class Point { init(x) { this.x = x; } }
+        var f = fun () { return "anonymous"; };
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let artifact = Compiler::new(tokens, &allocator).compile()?;
+        let names: Vec<_> = artifact.functions().iter().map(|f| f.name()).collect();
+        assert_eq!(
+            vec![
+                None,
+                Some("counter"),
+                Some("increment"),
+                Some("add"),
+                Some("init"),
+                Some("anonymous")
+            ],
+            names
+        );
+        let script = artifact.script();
+        assert_eq!(None, script.enclosing());
+        assert!(script.constants().iter().any(|c| c.to_string() == "Point"));
+        let counter = artifact.function("counter").unwrap();
+        assert_eq!((1, Some(0)), (counter.arity(), counter.enclosing()));
+        assert!(counter.upvalues().is_empty());
+        let increment = artifact.function("increment").unwrap();
+        assert_eq!(Some(1), increment.enclosing());
+        // `count` is the local in slot 2 of counter (after the function itself and `start`)
+        assert_eq!(
+            &[UpvalueCapture {
+                is_local: true,
+                index: 2
+            }],
+            increment.upvalues()
+        );
+        let add = artifact.function("add").unwrap();
+        assert_eq!(Some(2), add.enclosing());
+        // Through the upvalue of increment
+        assert_eq!(
+            &[UpvalueCapture {
+                is_local: false,
+                index: 0
+            }],
+            add.upvalues()
+        );
+        assert_eq!(Some(0), artifact.function("init").unwrap().enclosing());
+        let anonymous = artifact.function("anonymous").unwrap();
+        assert!(!anonymous.code().is_empty());
+        assert_eq!(
+            anonymous.function().chunk.code.item_count(),
+            anonymous.chunk().code.item_count()
+        );
+        Ok(())
+    }
+}
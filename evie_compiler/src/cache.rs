@@ -0,0 +1,226 @@
+//! A cache of compilations, to run a source that was compiled before without compiling it again (e.g. the same line
+//! in a REPL or a benchmark loop).
+//!
+//! The compilations are looked up by the hash of the source and of the [CompileOptions] it was compiled with, the
+//! source and the options are compared too so that two sources with the same hash are not mistaken for each other.
+//! The byte code is not changed by a run, the cached function (its constants and nested functions included) is
+//! shared by every run of the source. The functions are allocated by the [evie_memory::ObjectAllocator] of the
+//! compilation: the cache only holds for that allocator and must be traced by its owner (see [Trace]) to keep
+//! them alive.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+};
+
+use evie_common::errors::*;
+use evie_memory::{
+    gc::{Trace, Tracer},
+    objects::{GCObjectOf, UserDefinedFunction},
+};
+
+use crate::{artifact::Artifact, compiler::Compilation};
+
+/// What the byte code of a source depends on, besides the source
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CompileOptions {
+    /// See [crate::compiler::Compiler::set_superinstructions]
+    pub superinstructions: bool,
+    /// See [crate::compiler::Compiler::set_eliminate_dead_functions]
+    pub eliminate_dead_functions: bool,
+    /// See [crate::compiler::Compiler::set_global_constants]
+    pub global_constants: Vec<String>,
+    /// See [crate::compiler::Compiler::set_strict]
+    pub strict: Option<Vec<String>>,
+    /// See [crate::compiler::Compiler::set_source_name]
+    pub source_name: Option<String>,
+}
+
+/// A successful compilation, see [Compilation]
+#[derive(Debug)]
+pub struct CachedCompilation {
+    pub function: GCObjectOf<UserDefinedFunction>,
+    pub artifact: Artifact,
+    warnings: Vec<ErrorKind>,
+}
+
+impl CachedCompilation {
+    /// The warnings of the compilation, see [Compilation::warnings]
+    pub fn warnings(&self) -> Vec<ErrorKind> {
+        copy_warnings(&self.warnings)
+    }
+}
+
+/// The compilations of the last `capacity` sources, see the [module docs](self)
+#[derive(Debug)]
+pub struct CompilationCache {
+    capacity: usize,
+    entries: HashMap<u64, Vec<Entry>>,
+    /// The hashes of the entries, the oldest first
+    order: VecDeque<u64>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    source: String,
+    options: CompileOptions,
+    compilation: CachedCompilation,
+}
+
+impl CompilationCache {
+    /// A cache of up to `capacity` compilations, the oldest one is dropped for a new one once it is full
+    pub fn new(capacity: usize) -> Self {
+        CompilationCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// The compilation of the source with the options, if it is cached
+    pub fn get(&self, source: &str, options: &CompileOptions) -> Option<&CachedCompilation> {
+        self.entries
+            .get(&hash(source, options))?
+            .iter()
+            .find(|entry| entry.source == source && &entry.options == options)
+            .map(|entry| &entry.compilation)
+    }
+
+    /// Caches the compilation of the source with the options
+    pub fn insert(&mut self, source: String, options: CompileOptions, compilation: &Compilation) {
+        if self.capacity == 0 || self.get(&source, &options).is_some() {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            self.evict_oldest();
+        }
+        let hash = hash(&source, &options);
+        let compilation = CachedCompilation {
+            function: compilation.function,
+            artifact: compilation.artifact.clone(),
+            warnings: copy_warnings(&compilation.warnings),
+        };
+        self.entries.entry(hash).or_default().push(Entry {
+            source,
+            options,
+            compilation,
+        });
+        self.order.push_back(hash);
+    }
+
+    /// The number of cached compilations
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Drops every compilation
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(hash) = self.order.pop_front() {
+            if let Some(entries) = self.entries.get_mut(&hash) {
+                // The entries of a hash are in the order they were inserted
+                entries.remove(0);
+                if entries.is_empty() {
+                    self.entries.remove(&hash);
+                }
+            }
+        }
+    }
+}
+
+impl Trace for CompilationCache {
+    fn trace(&self, tracer: &mut Tracer) {
+        for entry in self.entries.values().flatten() {
+            // The artifact's too, e.g. for a profile, even those the script does not reference
+            for function in entry.compilation.artifact.functions() {
+                function.function().trace(tracer);
+            }
+            entry.compilation.function.trace(tracer);
+        }
+    }
+}
+
+/// [ErrorKind] is not [Clone], the compiler only warns with [ErrorKind::Warning]
+fn copy_warnings(warnings: &[ErrorKind]) -> Vec<ErrorKind> {
+    warnings
+        .iter()
+        .map(|warning| match warning {
+            ErrorKind::Warning(line, column, message) => {
+                ErrorKind::Warning(*line, *column, message.clone())
+            }
+            other => ErrorKind::Msg(other.to_string()),
+        })
+        .collect()
+}
+
+fn hash(source: &str, options: &CompileOptions) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    options.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_common::errors::*;
+    use evie_frontend::scanner::Scanner;
+    use evie_memory::ObjectAllocator;
+
+    use super::{CompilationCache, CompileOptions};
+    use crate::compiler::{Compilation, Compiler};
+
+    fn compile(source: &str, allocator: &ObjectAllocator) -> Result<Compilation> {
+        let mut scanner = Scanner::new(source.to_string());
+        Compiler::new(scanner.scan_tokens()?, allocator).compile_with_analysis()
+    }
+
+    #[test]
+    fn caches_by_source_and_options() -> Result<()> {
+        let allocator = ObjectAllocator::new();
+        let mut cache = CompilationCache::new(2);
+        let source = "{ var unused = 1; } fun f() { return 1; }";
+        let compilation = compile(source, &allocator)?;
+        let options = CompileOptions::default();
+        cache.insert(source.to_string(), options.clone(), &compilation);
+        let cached = cache
+            .get(source, &options)
+            .expect("Expected it to be cached");
+        assert_eq!(compilation.function.as_ptr(), cached.function.as_ptr());
+        assert_eq!(2, cached.artifact.functions().len());
+        let warnings: Vec<_> = cached.warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            vec!["Warning: [line: 1, column: 7] Unused local variable 'unused'"],
+            warnings
+        );
+        let strict = CompileOptions {
+            strict: Some(vec![]),
+            ..CompileOptions::default()
+        };
+        assert!(cache.get(source, &strict).is_none());
+        assert!(cache.get("fun f() { return 1; }", &options).is_none());
+        // The oldest is dropped once it is full
+        cache.insert(
+            "print 1;".to_string(),
+            options.clone(),
+            &compile("print 1;", &allocator)?,
+        );
+        cache.insert(
+            "print 2;".to_string(),
+            options.clone(),
+            &compile("print 2;", &allocator)?,
+        );
+        assert_eq!(2, cache.len());
+        assert!(cache.get(source, &options).is_none());
+        assert!(cache.get("print 1;", &options).is_some());
+        cache.clear();
+        assert!(cache.is_empty());
+        Ok(())
+    }
+}
@@ -1,14 +1,19 @@
-use std::{
-    collections::{linked_list::IterMut, LinkedList},
-    iter::Rev,
-};
+use std::collections::{HashSet, LinkedList};
 
 use evie_common::{bail, errors::*, ByteUnit, Writer};
 use evie_frontend::tokens::*;
 use evie_instructions::opcodes::Opcode;
 
+use crate::{
+    artifact::{Artifact, CompiledFunction, UpvalueCapture},
+    cache::CompileOptions,
+    dead_code::{eliminate_dead_functions, GlobalFunction},
+    pgo::{apply_profile, Profile},
+    resolver::{Local, Nesting, Resolution, Resolver, ScopeTable},
+};
+
 use evie_memory::{
-    chunk::Chunk,
+    chunk::{Chunk, SourceSpan},
     objects::{GCObjectOf, Object, ObjectType, UserDefinedFunction},
     ObjectAllocator,
 };
@@ -19,26 +24,55 @@ use evie_memory::objects::nan_boxed::Value;
 use evie_memory::objects::non_nan_boxed::Value;
 use num_enum::{FromPrimitive, IntoPrimitive};
 
+/// The default of [Compiler::set_max_nesting_depth], the limit of the AST parser too
+pub const DEFAULT_MAX_NESTING_DEPTH: usize = evie_frontend::ast::MAX_NESTING_DEPTH;
+
+/// The comparisons fused with a following [Opcode::JumpIfFalse] into [Opcode::CompareJumpIfFalse]
+const COMPARISONS: &[Opcode] = &[
+    Opcode::EqualEqual,
+    Opcode::BangEqual,
+    Opcode::Greater,
+    Opcode::GreaterEqual,
+    Opcode::Less,
+    Opcode::LessEqual,
+];
+
 fn parse_error(token: &Token, message: &str) -> ErrorKind {
     ErrorKind::ParseError(format!(
-        "[line: {}] Error at <{}>: message: {}",
-        token.line, token.lexeme, message
+        "[line: {}, column: {}] Error at <{}>: message: {}",
+        token.line, token.column, token.lexeme, message
     ))
 }
+
+fn source_span(token: &Token) -> SourceSpan {
+    let span = token.span();
+    SourceSpan {
+        line: span.line,
+        column: span.column,
+        length: span.length,
+    }
+}
 #[repr(usize)]
 #[derive(Debug, FromPrimitive, IntoPrimitive, Clone, Copy, PartialEq, PartialOrd)]
 enum Precedence {
     #[num_enum(default)]
     None = 0,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
+    Assignment,  // =
+    Conditional, // ?:
+    Or,          // or
+    And,         // and
+    Equality,    // == !=
+    Comparison,  // < > <= >= is
+    Range,       // .. ..=
+    BitOr,       // |
+    BitXor,      // ^
+    BitAnd,      // &
+    Shift,       // << >>
+    Term,        // + -
+    Factor,      // * /
+    Unary,       // ! -
+    Exponent,    // **
+    Call,        // . ()
     Primary,
 }
 
@@ -81,18 +115,6 @@ impl<'a> ParseRule<'a> {
         }
     }
 }
-#[derive(Debug)]
-struct Upvalue {
-    index: ByteUnit,
-    is_local: bool,
-}
-
-impl Upvalue {
-    pub fn new(index: ByteUnit, is_local: bool) -> Self {
-        Upvalue { index, is_local }
-    }
-}
-
 struct ClassCompiler {}
 
 impl ClassCompiler {
@@ -101,83 +123,102 @@ impl ClassCompiler {
     }
 }
 
-#[derive(Debug)]
-struct Scope<'a> {
-    locals: Vec<Local<'a>>,
-    depth: usize,
-}
-
-impl<'a> Scope<'a> {
-    #[allow(clippy::new_without_default)]
-    fn new() -> Self {
-        Scope {
-            locals: Vec::new(),
-            depth: 0,
-        }
-    }
-}
-
-const GLOBAL_SCOPE_DEPTH: usize = 0;
-
-#[derive(Debug)]
-struct Local<'a> {
-    name: &'a str,
-    depth: Option<usize>,
-    is_captured: bool,
-}
-
-impl<'a> Local<'a> {
-    #[allow(clippy::new_without_default)]
-    fn new(name: &'a str, depth: Option<usize>) -> Self {
-        Local {
-            name,
-            depth,
-            is_captured: false,
-        }
-    }
-}
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum FunctionType {
     Script,
     Function,
     Method,
     Initializer,
+    StaticMethod,
+    Anonymous,
 }
 
 #[derive(Debug)]
-struct State<'a> {
+struct State {
     function: GCObjectOf<UserDefinedFunction>,
-    scope: Scope<'a>,
     function_type: FunctionType,
-    upvalues: Vec<Upvalue>,
+    /// The offsets of the last two instructions, the last one first (see [Compiler::fusable])
+    last_instructions: [Option<usize>; 2],
+    /// The largest offset a jump lands on, the instructions before it are not fused with the ones after it
+    jump_target: usize,
+    /// The index of the function in [Artifact::functions]
+    index: usize,
+    /// The fields set by `this.<field> = ...` in the body of `init`, see [UserDefinedFunction::field_count]
+    fields: Vec<GCObjectOf<Box<str>>>,
 }
 
-impl<'a> State<'a> {
+impl State {
     fn new(
         function: GCObjectOf<UserDefinedFunction>,
-        scope: Scope<'a>,
         function_type: FunctionType,
+        index: usize,
     ) -> Self {
         State {
             function,
-            scope,
             function_type,
-            upvalues: Vec::new(),
+            last_instructions: [None, None],
+            jump_target: 0,
+            index,
+            fields: Vec::new(),
         }
     }
 }
 
+/// Where a declaration starts, the compiler goes back to it after an error (see [Compiler::recover])
+struct Checkpoint {
+    token_index: usize,
+    states: usize,
+    nesting: Nesting,
+    in_class: bool,
+    class_compilers: usize,
+    emit_span: Option<SourceSpan>,
+}
+
+/// The output of [Compiler::compile_with_analysis]
+pub struct Compilation {
+    pub function: GCObjectOf<UserDefinedFunction>,
+    /// See [ErrorKind::Warning]
+    pub warnings: Vec<ErrorKind>,
+    pub scope_table: ScopeTable,
+    pub artifact: Artifact,
+}
+
 pub struct Compiler<'a> {
     tokens: &'a [Token],
     token_index: usize,
     parse_rules: Vec<ParseRule<'a>>,
-    states: LinkedList<State<'a>>,
-    state: State<'a>,
+    states: LinkedList<State>,
+    state: State,
+    resolver: Resolver<'a>,
     #[allow(unused)]
     custom_writer: Option<Writer<'a>>,
     current_class: Option<ClassCompiler>,
     class_compilers: LinkedList<ClassCompiler>,
     allocater: &'a ObjectAllocator,
+    warnings: Vec<ErrorKind>,
+    /// The errors of the declarations compiled so far, the compiler recovers from them to report them all
+    errors: Vec<ErrorKind>,
+    /// Emit superinstructions (e.g. [Opcode::AddConstant]) for common sequences of instructions
+    superinstructions: bool,
+    /// See [Compiler::set_source_name]
+    source_name: Option<GCObjectOf<Box<str>>>,
+    /// The span of the code emitted, instead of the previous token's (see [Compiler::emit_at])
+    emit_span: Option<SourceSpan>,
+    /// Every function compiled so far, see [Artifact]
+    functions: Vec<CompiledFunction>,
+    /// The functions declared at the top level, see [Compiler::set_eliminate_dead_functions]
+    global_functions: Vec<GlobalFunction>,
+    eliminate_dead_functions: bool,
+    /// See [Compiler::set_profile]
+    profile: Option<Profile>,
+    /// See [Compiler::set_strict]
+    strict: Option<Vec<String>>,
+    /// The local functions declared ahead of their declaration: the token index of the name and the slot, see
+    /// [Compiler::hoist_functions]
+    hoisted: Vec<(usize, ByteUnit)>,
+    /// The nesting of the expression, statement or function being parsed (see [Compiler::set_max_nesting_depth])
+    nesting_depth: usize,
+    max_nesting_depth: usize,
 }
 #[allow(dead_code)]
 impl<'a> Compiler<'a> {
@@ -216,14 +257,27 @@ impl<'a> Compiler<'a> {
             tokens,
             token_index: 0,
             parse_rules: Vec::new(),
-            state: State::new(script_fn, Scope::new(), function_type),
+            state: State::new(script_fn, function_type, 0),
+            functions: vec![CompiledFunction::new(script_fn, None)],
+            global_functions: Vec::new(),
+            eliminate_dead_functions: false,
+            profile: None,
+            resolver: Resolver::new(),
             states: LinkedList::new(),
             custom_writer,
             current_class: None,
             class_compilers: LinkedList::new(),
             allocater,
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            superinstructions: true,
+            source_name: None,
+            emit_span: None,
+            strict: None,
+            hoisted: Vec::new(),
+            nesting_depth: 0,
+            max_nesting_depth: DEFAULT_MAX_NESTING_DEPTH,
         };
-        c.current_scope_mut().locals.push(Local::new("", Some(0)));
         c.init_parse_rules();
         c
     }
@@ -241,6 +295,18 @@ impl<'a> Compiler<'a> {
             ParseRule::new(TokenType::RightBrace, None, None, Precedence::None),
             ParseRule::new(TokenType::Comma, None, None, Precedence::None),
             ParseRule::new(TokenType::Dot, None, Some(Compiler::dot), Precedence::Call),
+            ParseRule::new(
+                TokenType::DotDot,
+                None,
+                Some(Compiler::binary),
+                Precedence::Range,
+            ),
+            ParseRule::new(
+                TokenType::DotDotEqual,
+                None,
+                Some(Compiler::binary),
+                Precedence::Range,
+            ),
             ParseRule::new(
                 TokenType::Minus,
                 Some(Compiler::unary),
@@ -266,6 +332,37 @@ impl<'a> Compiler<'a> {
                 Some(Compiler::binary),
                 Precedence::Factor,
             ),
+            ParseRule::new(
+                TokenType::StarStar,
+                None,
+                Some(Compiler::binary),
+                Precedence::Exponent,
+            ),
+            ParseRule::new(
+                TokenType::Question,
+                None,
+                Some(Compiler::conditional),
+                Precedence::Conditional,
+            ),
+            ParseRule::new(TokenType::Colon, None, None, Precedence::None),
+            ParseRule::new(
+                TokenType::Ampersand,
+                None,
+                Some(Compiler::binary),
+                Precedence::BitAnd,
+            ),
+            ParseRule::new(
+                TokenType::Pipe,
+                None,
+                Some(Compiler::binary),
+                Precedence::BitOr,
+            ),
+            ParseRule::new(
+                TokenType::Caret,
+                None,
+                Some(Compiler::binary),
+                Precedence::BitXor,
+            ),
             ParseRule::new(
                 TokenType::Bang,
                 Some(Compiler::unary),
@@ -297,6 +394,12 @@ impl<'a> Compiler<'a> {
                 Some(Compiler::binary),
                 Precedence::Comparison,
             ),
+            ParseRule::new(
+                TokenType::GreaterGreater,
+                None,
+                Some(Compiler::binary),
+                Precedence::Shift,
+            ),
             ParseRule::new(
                 TokenType::Less,
                 None,
@@ -309,6 +412,12 @@ impl<'a> Compiler<'a> {
                 Some(Compiler::binary),
                 Precedence::Comparison,
             ),
+            ParseRule::new(
+                TokenType::LessLess,
+                None,
+                Some(Compiler::binary),
+                Precedence::Shift,
+            ),
             ParseRule::new(
                 TokenType::Identifier,
                 Some(Compiler::variable_usage),
@@ -334,6 +443,7 @@ impl<'a> Compiler<'a> {
                 Precedence::And,
             ),
             ParseRule::new(TokenType::Class, None, None, Precedence::None),
+            ParseRule::new(TokenType::Const, None, None, Precedence::None),
             ParseRule::new(TokenType::Else, None, None, Precedence::None),
             ParseRule::new(
                 TokenType::False,
@@ -341,9 +451,21 @@ impl<'a> Compiler<'a> {
                 None,
                 Precedence::None,
             ),
+            ParseRule::new(
+                TokenType::Fun,
+                Some(Compiler::anonymous_function),
+                None,
+                Precedence::None,
+            ),
             ParseRule::new(TokenType::For, None, None, Precedence::None),
-            ParseRule::new(TokenType::Fun, None, None, Precedence::None),
             ParseRule::new(TokenType::If, None, None, Precedence::None),
+            ParseRule::new(TokenType::In, None, None, Precedence::None),
+            ParseRule::new(
+                TokenType::Is,
+                None,
+                Some(Compiler::binary),
+                Precedence::Comparison,
+            ),
             ParseRule::new(
                 TokenType::Nil,
                 Some(Compiler::literal),
@@ -358,7 +480,13 @@ impl<'a> Compiler<'a> {
             ),
             ParseRule::new(TokenType::Print, None, None, Precedence::None),
             ParseRule::new(TokenType::Return, None, None, Precedence::None),
-            ParseRule::new(TokenType::Super, None, None, Precedence::None),
+            ParseRule::new(TokenType::Static, None, None, Precedence::None),
+            ParseRule::new(
+                TokenType::Super,
+                Some(Compiler::super_),
+                None,
+                Precedence::None,
+            ),
             ParseRule::new(
                 TokenType::This,
                 Some(Compiler::this),
@@ -373,29 +501,183 @@ impl<'a> Compiler<'a> {
             ),
             ParseRule::new(TokenType::Var, None, None, Precedence::None),
             ParseRule::new(TokenType::While, None, None, Precedence::None),
+            ParseRule::new(
+                TokenType::Yield,
+                Some(Compiler::yield_),
+                None,
+                Precedence::None,
+            ),
             ParseRule::new(TokenType::Eof, None, None, Precedence::None),
         ]
     }
 
-    pub fn compile(mut self) -> Result<GCObjectOf<UserDefinedFunction>> {
+    /// Enables (the default) or disables superinstructions, the fused opcodes emitted for common sequences of instructions
+    pub fn set_superinstructions(&mut self, enabled: bool) {
+        self.superinstructions = enabled;
+    }
+
+    /// Drops the functions declared at the top level that are never referenced from reachable code (disabled by
+    /// default, see [crate::dead_code]). Only for whole programs: e.g. in the REPL a later input could call them.
+    pub fn set_eliminate_dead_functions(&mut self, enabled: bool) {
+        self.eliminate_dead_functions = enabled;
+    }
+
+    /// Lays out the byte code for the [Profile] of a previous run of the same script (see [crate::pgo]), it is an
+    /// error if the profile was recorded for another script
+    pub fn set_profile(&mut self, profile: Option<Profile>) {
+        self.profile = profile;
+    }
+
+    /// The `const` globals declared before (e.g. by the previous inputs of a REPL) in the order of their slots,
+    /// see [Opcode::DefineGlobalConstant]. Assigning them or declaring them again is an error.
+    pub fn set_global_constants(&mut self, names: Vec<String>) {
+        self.resolver.set_global_constants(names);
+    }
+
+    /// The parser is recursive, deeper nesting of expressions and statements (e.g. `((((1))))` or `{{{{}}}}`) is a
+    /// [ErrorKind::ParseError] instead of a stack overflow
+    pub fn set_max_nesting_depth(&mut self, depth: usize) {
+        self.max_nesting_depth = depth;
+    }
+
+    /// The name of the source (e.g. the path of the script) recorded in the chunks of the functions, for stack traces
+    pub fn set_source_name(&mut self, name: &str) {
+        let name = self.boxed_string(name);
+        self.source_name = Some(name);
+        self.current_chunk_mut().source = Some(name);
+    }
+
+    /// Strict mode: a global that is used but declared neither in the source nor in `defined_globals` (e.g. the natives
+    /// and the globals of the previous inputs of a REPL) is a [ErrorKind::ResolutionError] at compile time, instead of
+    /// a runtime error when (and if) the code runs. None (the default) turns it off
+    pub fn set_strict(&mut self, defined_globals: Option<Vec<String>>) {
+        self.strict = defined_globals;
+    }
+
+    /// Sets all the [CompileOptions] at once, e.g. those a [crate::cache::CompilationCache] is looked up with
+    pub fn set_options(&mut self, options: CompileOptions) {
+        self.set_superinstructions(options.superinstructions);
+        self.set_eliminate_dead_functions(options.eliminate_dead_functions);
+        self.set_global_constants(options.global_constants);
+        self.set_strict(options.strict);
+        if let Some(name) = options.source_name {
+            self.set_source_name(&name);
+        }
+    }
+
+    /// Compiles the script, the [Artifact] gives access to the byte code of all its functions
+    pub fn compile(self) -> Result<Artifact> {
+        Ok(self.compile_with_analysis()?.artifact)
+    }
+
+    /// Compiles and also returns the warnings ([ErrorKind::Warning]) found, e.g. unused local variables
+    pub fn compile_with_warnings(
+        self,
+    ) -> Result<(GCObjectOf<UserDefinedFunction>, Vec<ErrorKind>)> {
+        let compilation = self.compile_with_analysis()?;
+        Ok((compilation.function, compilation.warnings))
+    }
+
+    /// Compiles and also returns the warnings and the [ScopeTable].
+    /// The compiler recovers from the errors (see [Compiler::recover]), a script with several errors fails with
+    /// [ErrorKind::CompileErrors]
+    pub fn compile_with_analysis(mut self) -> Result<Compilation> {
         #[cfg(all(feature = "nan_boxed", feature = "trace_enabled"))]
         evie_common::trace!("Nan boxing enabled");
         #[cfg(all(not(feature = "nan_boxed"), feature = "trace_enabled"))]
         evie_common::trace!("Nan boxing disabled");
         while !self.is_at_end() {
-            self.declaration()?;
+            self.declaration();
+        }
+        if let Some(defined_globals) = &self.strict {
+            let undefined = self.resolver.undefined_globals(defined_globals);
+            self.errors.extend(undefined);
+        }
+        match self.errors.len() {
+            0 => {}
+            1 => bail!(self.errors.remove(0)),
+            _ => bail!(ErrorKind::CompileErrors(self.errors)),
+        }
+        let scope_table = std::mem::replace(&mut self.resolver, Resolver::new()).into_table();
+        let mut artifact = Artifact {
+            functions: std::mem::take(&mut self.functions),
+        };
+        if self.eliminate_dead_functions {
+            eliminate_dead_functions(&mut artifact, &scope_table, &self.global_functions);
         }
         self.emit_return_and_log();
-        Ok(self.state.function)
+        if let Some(profile) = &self.profile {
+            apply_profile(&artifact, profile)?;
+        }
+        Ok(Compilation {
+            function: self.state.function,
+            warnings: self.warnings,
+            scope_table,
+            artifact,
+        })
+    }
+
+    /// Runs `parse` one level deeper, see [Compiler::set_max_nesting_depth]
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        if self.nesting_depth >= self.max_nesting_depth {
+            bail!(parse_error(
+                self.current(),
+                &format!(
+                    "Too deeply nested, the limit is {} levels",
+                    self.max_nesting_depth
+                )
+            ))
+        }
+        self.nesting_depth += 1;
+        let result = parse(self);
+        self.nesting_depth -= 1;
+        result
+    }
+
+    /// Compiles a declaration, an error is kept and the compiler recovers from it
+    fn declaration(&mut self) {
+        let checkpoint = Checkpoint {
+            token_index: self.token_index,
+            states: self.states.len(),
+            nesting: self.resolver.nesting(),
+            in_class: self.current_class.is_some(),
+            class_compilers: self.class_compilers.len(),
+            emit_span: self.emit_span,
+        };
+        if let Err(e) = self.declaration_unchecked() {
+            self.errors.push(e.0);
+            self.recover(checkpoint);
+        }
+    }
+
+    /// Goes back to the functions, scopes and classes of the checkpoint and skips the rest of the declaration
+    /// (panic mode), so that the errors after it are reported as well. The code compiled after an error is discarded
+    fn recover(&mut self, checkpoint: Checkpoint) {
+        while self.states.len() > checkpoint.states {
+            self.end_new_function();
+        }
+        self.resolver.unwind(checkpoint.nesting);
+        while self.class_compilers.len() > checkpoint.class_compilers {
+            self.class_compilers.pop_back();
+        }
+        self.current_class = checkpoint.in_class.then(ClassCompiler::new);
+        self.emit_span = checkpoint.emit_span;
+        self.synchronize(checkpoint.token_index);
     }
 
-    fn declaration(&mut self) -> Result<()> {
+    fn declaration_unchecked(&mut self) -> Result<()> {
         if self.match_and_advance(&[TokenType::Class]) {
             self.class_declaration()?;
-        } else if self.match_and_advance(&[TokenType::Fun]) {
+        } else if self.current().token_type == TokenType::Fun
+            && self.next().token_type == TokenType::Identifier
+        {
+            // `fun (` is an anonymous function (expression)
+            self.advance();
             self.fun_declaration()?;
         } else if self.match_and_advance(&[TokenType::Var]) {
             self.var_declaration()?;
+        } else if self.match_and_advance(&[TokenType::Const]) {
+            self.const_declaration()?;
         } else {
             self.statement()?;
         }
@@ -407,20 +689,31 @@ impl<'a> Compiler<'a> {
         self.consume_next_token(TokenType::Identifier, "Expect class name")?;
         let class_name = self.previous().clone();
         let name_constant = self.identifier_constant(class_name.clone())?;
-        self.declare_local_variable()?;
+        self.resolver.declare(self.previous())?;
         self.emit_opcode_and_bytes(Opcode::Class, name_constant);
         self.define_variable(name_constant);
         if let Some(current_compiler) = self.current_class.take() {
             self.class_compilers.push_back(current_compiler);
         }
         self.current_class = Some(ClassCompiler::new());
+        self.resolver.begin_class(&class_name);
         // this will bring the variable back on top of the stack
         self.named_variable(class_name, false)?;
         self.consume_next_token(TokenType::LeftBrace, "Expect '{' before class body")?;
+        if let Some(doc) = self.docstring(false) {
+            self.resolver.set_class_doc(doc);
+            let constant = self.name_constant(doc)?;
+            self.emit_opcode_and_bytes(Opcode::Doc, constant);
+        }
         while self.current().token_type != TokenType::RightBrace && !self.is_at_end() {
-            self.method()?;
+            if self.match_and_advance(&[TokenType::Static]) {
+                self.static_method()?;
+            } else {
+                self.method()?;
+            }
         }
         self.consume_next_token(TokenType::RightBrace, "Expect '}' after class body")?;
+        self.resolver.end_class();
         self.emit_op_code(Opcode::Pop); // pop the class
         let prev_class_compiler = self.class_compilers.pop_back();
         self.current_class = prev_class_compiler;
@@ -442,43 +735,95 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
+    fn static_method(&mut self) -> Result<()> {
+        self.consume_next_token(TokenType::Identifier, "Expect static method name")?;
+        let method_name = self.previous().clone();
+        self.function(FunctionType::StaticMethod)?;
+        let constant = self.identifier_constant(method_name)?;
+        self.emit_opcode_and_bytes(Opcode::StaticMethod, constant);
+        Ok(())
+    }
+
     fn start_new_function(&mut self, function_type: FunctionType) -> Result<()> {
         let new_function_name = self.function_name(function_type)?;
+        let declaration = match function_type {
+            FunctionType::Anonymous => None,
+            _ => Some(self.previous()),
+        };
+        self.resolver.begin_function(
+            &new_function_name,
+            declaration,
+            !matches!(
+                function_type,
+                FunctionType::Function | FunctionType::StaticMethod | FunctionType::Anonymous
+            ),
+        );
         let new_function_name = self.boxed_string(&new_function_name);
-        let new_function = self.allocater.alloc(UserDefinedFunction::new(
+        let mut chunk = Chunk::new();
+        chunk.source = self.source_name;
+        let mut new_function = self.allocater.alloc(UserDefinedFunction::new(
             Some(new_function_name),
-            self.allocater.alloc(Chunk::new()),
+            self.allocater.alloc(chunk),
             0,
             0,
         ));
-        let mut new_scope = Scope::new();
-        if function_type != FunctionType::Function {
-            new_scope.locals.push(Local::new("this", Some(0)));
-        } else {
-            new_scope.locals.push(Local::new("", Some(0)));
+        if function_type == FunctionType::Anonymous {
+            new_function.inferred_name = self.assigned_name().map(|name| self.boxed_string(name));
         }
+        let index = self.functions.len();
+        self.functions
+            .push(CompiledFunction::new(new_function, Some(self.state.index)));
         let current_state = std::mem::replace(
             &mut self.state,
-            State::new(new_function, new_scope, function_type),
+            State::new(new_function, function_type, index),
         );
         self.states.push_back(current_state);
         Ok(())
     }
 
-    fn end_new_function(&mut self) -> State<'a> {
+    fn end_new_function(&mut self) -> State {
         let prev_state = self.states.pop_back().expect("State expected");
         std::mem::replace(&mut self.state, prev_state)
     }
 
     fn fun_declaration(&mut self) -> Result<()> {
+        if let Some(position) = self
+            .hoisted
+            .iter()
+            .position(|(index, _)| *index == self.token_index)
+        {
+            let (_, slot) = self.hoisted.swap_remove(position);
+            self.resolver.define_hoisted(slot);
+            self.advance();
+            self.function(FunctionType::Function)?;
+            self.emit_opcode_and_bytes(Opcode::SetLocal, slot);
+            self.emit_op_code(Opcode::Pop);
+            return Ok(());
+        }
+        let start = self.current_chunk().code.item_count();
         let global = self.parse_variable("Expect function name")?;
-        self.mark_initialized();
+        let name = self.previous().lexeme.clone();
+        let is_global = self.states.is_empty() && self.resolver.is_global_scope();
+        self.resolver.mark_initialized();
+        let index = self.functions.len();
         self.function(FunctionType::Function)?;
         self.define_variable(global);
+        if is_global {
+            let end = self.current_chunk().code.item_count();
+            self.global_functions.push(GlobalFunction {
+                index,
+                name,
+                code: start..end,
+            });
+        }
         Ok(())
     }
 
     fn function(&mut self, function_type: FunctionType) -> Result<()> {
+        self.nested(|c| c.function_unchecked(function_type))
+    }
+
+    fn function_unchecked(&mut self, function_type: FunctionType) -> Result<()> {
         self.start_new_function(function_type)?;
         self.begin_scope();
         self.consume_next_token(TokenType::LeftParen, "Expect '(' after function name")?;
@@ -487,6 +832,7 @@ impl<'a> Compiler<'a> {
             f.arity += 1;
             let constant = self.parse_variable("Expect parameter name")?;
             self.define_variable(constant);
+            self.resolver.mark_parameter();
             if self.current().token_type == TokenType::Comma {
                 self.advance();
             } else {
@@ -495,13 +841,32 @@ impl<'a> Compiler<'a> {
         }
         self.consume_next_token(TokenType::RightParen, "Expect ')' after parameters")?;
         self.consume_next_token(TokenType::LeftBrace, "Expect '{' before function body")?;
+        if let Some(doc) = self.docstring(true) {
+            self.resolver.set_function_doc(doc);
+            let mut f = self.state.function;
+            f.doc = Some(self.boxed_string(doc));
+        }
         self.block()?;
         self.emit_return_and_log();
         let state = self.end_new_function();
-        let up_values = &state.upvalues;
-        let function = Object::new_gc_object(ObjectType::Function(state.function), self.allocater);
+        let resolved = self.resolver.end_function();
+        resolved
+            .locals
+            .iter()
+            .for_each(|local| self.warn_if_unused(local));
+        let mut function = state.function;
+        function.upvalue_count = resolved.upvalues.len();
+        let up_values = &resolved.upvalues;
+        self.functions[state.index].upvalues = up_values
+            .iter()
+            .map(|u| UpvalueCapture {
+                is_local: u.is_local,
+                index: u.index,
+            })
+            .collect();
+        let function = Object::new_gc_object(ObjectType::Function(function), self.allocater);
         let function = Value::object(function);
-        let index = self.add_constant(function);
+        let index = self.add_constant(function)?;
         self.emit_opcode_and_bytes(Opcode::Closure, index);
         for u in up_values {
             self.emit_byte(if u.is_local { 1 } else { 0 });
@@ -510,12 +875,47 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
+    /// The docstring at the current token: a string literal followed by a `;` (optional at the start of a class body).
+    /// It is documentation, not code, so it is skipped
+    fn docstring(&mut self, semicolon_required: bool) -> Option<&'a str> {
+        let token = self.current();
+        let (TokenType::String, Some(Literal::String(doc))) = (token.token_type, &token.literal)
+        else {
+            return None;
+        };
+        let semicolon = self.next().token_type == TokenType::Semicolon;
+        if semicolon_required && !semicolon {
+            return None;
+        }
+        self.advance();
+        if semicolon {
+            self.advance();
+        }
+        Some(doc)
+    }
+
+    fn anonymous_function(&mut self, _can_assign: bool) -> Result<()> {
+        self.function(FunctionType::Anonymous)
+    }
+
+    /// The name an anonymous function is assigned to, `f` for `var f = fun`, `f = fun` or `object.f = fun` (the
+    /// previous token is `fun`). See [UserDefinedFunction::inferred_name]
+    fn assigned_name(&self) -> Option<&'a str> {
+        let fun = self.token_index.checked_sub(1)?;
+        let equal = &self.tokens[fun.checked_sub(1)?];
+        let name = &self.tokens[fun.checked_sub(2)?];
+        (equal.token_type == TokenType::Equal && name.token_type == TokenType::Identifier)
+            .then_some(name.lexeme.as_str())
+    }
+
     fn function_name(&self, t: FunctionType) -> Result<String> {
         match t {
             FunctionType::Script => Ok("".to_string()),
             FunctionType::Function => self.function_name_from_token(),
             FunctionType::Method => self.function_name_from_token(),
             FunctionType::Initializer => self.function_name_from_token(),
+            FunctionType::StaticMethod => self.function_name_from_token(),
+            FunctionType::Anonymous => Ok("anonymous".to_string()),
         }
     }
 
@@ -546,26 +946,55 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
+    /// `const NAME = expression;`, only at the top level of the script
+    fn const_declaration(&mut self) -> Result<()> {
+        if !(self.states.is_empty() && self.resolver.is_global_scope()) {
+            bail!(parse_error(
+                self.previous(),
+                "Constants can only be declared at the top level"
+            ))
+        }
+        self.consume_next_token(TokenType::Identifier, "Expect constant name")?;
+        let name = self.previous();
+        self.consume_next_token(TokenType::Equal, "Expect '=' after constant name")?;
+        // The constant is declared after its value, `const a = a;` refers to another `a`
+        self.expression()?;
+        self.consume_next_token(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        )?;
+        let slot = self.resolver.declare_global_constant(name)?;
+        let name_constant = self.identifier_constant(name.clone())?;
+        self.emit_opcode_and_bytes(Opcode::DefineGlobalConstant, name_constant);
+        self.emit_byte(slot);
+        Ok(())
+    }
+
     fn variable_usage(&mut self, can_assign: bool) -> Result<()> {
         self.named_variable(self.previous().clone(), can_assign)
     }
 
     fn named_variable(&mut self, token: Token, can_assign: bool) -> Result<()> {
-        let mut get_op = Opcode::GetGlobal;
-        let mut set_op = Opcode::SetGlobal;
-        let arg;
-        if let Some(index) = self.resolve_local(&token)? {
-            get_op = Opcode::GetLocal;
-            set_op = Opcode::SetLocal;
-            arg = index;
-        } else if let Some(index) = self.resolve_upvalue(&token)? {
-            get_op = Opcode::GetUpvalue;
-            set_op = Opcode::SetUpvalue;
-            arg = index;
-        } else {
-            arg = self.identifier_constant(token)?;
+        let (get_op, set_op, arg) = match self.resolver.resolve(&token)? {
+            Resolution::Local(index) => (Opcode::GetLocal, Opcode::SetLocal, index),
+            Resolution::Upvalue(index) => (Opcode::GetUpvalue, Opcode::SetUpvalue, index),
+            Resolution::Global => (
+                Opcode::GetGlobal,
+                Opcode::SetGlobal,
+                self.identifier_constant(token.clone())?,
+            ),
+            Resolution::GlobalConstant(slot) => {
+                (Opcode::GetGlobalConstant, Opcode::GetGlobalConstant, slot)
+            }
+        };
+        let is_assignment = can_assign && self.match_and_advance(&[TokenType::Equal]);
+        if is_assignment && get_op == Opcode::GetGlobalConstant {
+            bail!(parse_error(&token, "Can't assign to a constant"))
         }
-        if can_assign && self.match_and_advance(&[TokenType::Equal]) {
+        if get_op == Opcode::GetLocal {
+            self.resolver.mark_used(arg, is_assignment);
+        }
+        if is_assignment {
             self.expression()?;
             self.emit_opcode_and_bytes(set_op, arg)
         } else {
@@ -574,86 +1003,27 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
-    fn resolve_upvalue(&mut self, name: &Token) -> Result<Option<ByteUnit>> {
-        let state_iterator = self.states.iter_mut().rev();
-        Compiler::resolve_upvalue_with_state(&mut self.state, state_iterator, name)
-    }
-
-    fn resolve_upvalue_with_state(
-        current: &mut State,
-        mut state_iter: Rev<IterMut<State>>,
-        name: &Token,
-    ) -> Result<Option<ByteUnit>> {
-        if let Some(enclosing) = state_iter.next() {
-            if let Some(index) = Compiler::resolve_local_with_state(name, enclosing)? {
-                let local = &mut enclosing.scope.locals[index as usize];
-                local.is_captured = true;
-                Ok(Some(Compiler::add_upvalue(index, current, true)))
-            } else if let Some(index) =
-                Compiler::resolve_upvalue_with_state(enclosing, state_iter, name)?
-            {
-                Ok(Some(Compiler::add_upvalue(index, current, false)))
-            } else {
-                Ok(None)
-            }
-        } else {
-            Ok(None)
-        }
-    }
-
-    fn add_upvalue(index: ByteUnit, state: &mut State, is_local: bool) -> ByteUnit {
-        let mut u = state.function;
-        if let Some((i, _)) = state
-            .upvalues
-            .iter()
-            .enumerate()
-            .find(|(_, v)| v.is_local == is_local && v.index == index)
-        {
-            return i as ByteUnit;
-        }
-        state.upvalues.push(Upvalue::new(index, is_local));
-        u.upvalue_count += 1;
-        (u.upvalue_count - 1) as ByteUnit
-    }
-
-    fn resolve_local_with_state(name: &Token, state: &State) -> Result<Option<ByteUnit>> {
-        let scope = &state.scope;
-        let mut i = scope.locals.len() as i32 - 1;
-        while i >= 0 {
-            let index = i as usize;
-            if scope.locals[index].name == name.lexeme {
-                if scope.locals[index].depth.is_none() {
-                    bail!(parse_error(
-                        name,
-                        "Can't read local variable in its own initializer"
-                    ))
-                }
-                return Ok(Some(i as ByteUnit));
-            }
-            i -= 1;
-        }
-        Ok(None)
-    }
-
-    fn resolve_local(&self, name: &Token) -> Result<Option<ByteUnit>> {
-        Compiler::resolve_local_with_state(name, &self.state)
-    }
-
     fn define_variable(&mut self, byte_unit: ByteUnit) {
-        if self.current_scope_mut().depth == GLOBAL_SCOPE_DEPTH {
+        if self.resolver.is_global_scope() {
             self.emit_opcode_and_bytes(Opcode::DefineGlobal, byte_unit);
         } else {
-            self.mark_initialized();
+            self.resolver.mark_initialized();
         }
     }
 
     fn statement(&mut self) -> Result<()> {
+        self.nested(Compiler::statement_unchecked)
+    }
+
+    fn statement_unchecked(&mut self) -> Result<()> {
         if self.match_and_advance(&[TokenType::Print]) {
             self.print_statement()?;
         } else if self.match_and_advance(&[TokenType::If]) {
             self.if_statement()?;
         } else if self.match_and_advance(&[TokenType::While]) {
             self.while_statement()?;
+        } else if self.match_and_advance(&[TokenType::For]) {
+            self.for_statement()?;
         } else if self.match_and_advance(&[TokenType::LeftBrace]) {
             self.begin_scope();
             self.block()?;
@@ -690,7 +1060,7 @@ impl<'a> Compiler<'a> {
     }
 
     fn while_statement(&mut self) -> Result<()> {
-        let loop_start = self.current_chunk_mut().code.item_count();
+        let loop_start = self.mark_jump_target();
         self.consume_next_token(TokenType::LeftParen, "Expect '(' after while")?;
         self.expression()?;
         self.consume_next_token(TokenType::RightParen, "Expect ')' after condition")?;
@@ -703,6 +1073,42 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
+    /// `for (x in iterable) body`, the iterator protocol: `iterable.iter()` returns the iterator and each call of
+    /// its `next()` returns the next element, nil at the end. `x` is a new variable for each element.
+    fn for_statement(&mut self) -> Result<()> {
+        self.consume_next_token(TokenType::LeftParen, "Expect '(' after for")?;
+        self.consume_next_token(TokenType::Identifier, "Expect variable name")?;
+        let variable = self.previous();
+        self.consume_next_token(TokenType::In, "Expect 'in' after the variable")?;
+        self.begin_scope();
+        self.expression()?;
+        self.consume_next_token(TokenType::RightParen, "Expect ')' after the iterable")?;
+        let iter = self.name_constant("iter")?;
+        self.emit_opcode_and_bytes(Opcode::Invoke, iter);
+        self.emit_byte(0);
+        // A space can't be in a name, the code can't refer to the iterator
+        let iterator = self.resolver.declare_hidden(" iterator");
+        let loop_start = self.mark_jump_target();
+        let next = self.name_constant("next")?;
+        self.emit_opcode_and_bytes(Opcode::GetLocal, iterator);
+        self.emit_opcode_and_bytes(Opcode::Invoke, next);
+        self.emit_byte(0);
+        let exit_jump = self.emit_jump(Opcode::JumpIfNil);
+        // The element is the local of the body's scope: a new variable for each iteration (the scope ends and closes
+        // its upvalue before the next one), the closures created in the body capture the element of their iteration
+        self.begin_scope();
+        self.resolver.declare(variable)?;
+        self.resolver.mark_initialized();
+        self.statement()?;
+        self.end_scope();
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump)?;
+        // The nil
+        self.emit_op_code(Opcode::Pop);
+        self.end_scope();
+        Ok(())
+    }
+
     fn if_statement(&mut self) -> Result<()> {
         self.consume_next_token(TokenType::LeftParen, "Expect '(' after if")?;
         self.expression()?;
@@ -720,51 +1126,84 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
-    fn current_scope_mut(&mut self) -> &mut Scope<'a> {
-        &mut self.state.scope
-    }
-
-    fn current_scope(&self) -> &Scope<'a> {
-        &self.state.scope
-    }
-
     fn begin_scope(&mut self) {
-        self.current_scope_mut().depth += 1;
+        self.resolver.begin_scope();
     }
 
     fn block(&mut self) -> Result<()> {
+        if !self.resolver.is_global_scope() {
+            self.hoist_functions();
+        }
+        let mut returned = false;
         while self.current().token_type != TokenType::RightBrace
             && self.current().token_type != TokenType::Eof
         {
-            self.declaration()?;
+            if returned {
+                // Reported once, for the first statement after the return
+                self.warn(self.current(), "Unreachable code");
+                returned = false;
+            } else {
+                returned = self.current().token_type == TokenType::Return;
+            }
+            self.declaration();
         }
         self.consume_next_token(TokenType::RightBrace, "Expect '}' after block")?;
         Ok(())
     }
 
-    fn end_scope(&mut self) {
-        self.current_scope_mut().depth -= 1;
-        let mut i: i32 = self.current_scope_mut().locals.len() as i32 - 1;
-        while i >= 0 {
-            if self.current_scope_mut().locals[i as usize]
-                .depth
-                .expect("Expect depth")
-                > self.current_scope_mut().depth
-            {
-                let local = self
-                    .current_scope_mut()
-                    .locals
-                    .pop()
-                    .expect("local expected");
-                if local.is_captured {
-                    self.emit_op_code(Opcode::CloseUpvalue);
-                } else {
-                    self.emit_op_code(Opcode::Pop);
+    /// Declares the functions of the block ahead of its statements, so that the functions of a block can call each
+    /// other (and the ones declared after them) like global functions do. The block itself can't use a function
+    /// before its declaration, it is nil until the declaration runs.
+    /// Only the functions named before their declaration are hoisted, the others are declared in place. Top level
+    /// functions are globals, they are resolved when they are called and are not hoisted
+    fn hoist_functions(&mut self) {
+        let mut depth = 0;
+        let mut named = HashSet::new();
+        for index in self.token_index..self.tokens.len() {
+            let token = &self.tokens[index];
+            let previous = self.tokens[index - 1].token_type;
+            match token.token_type {
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace if depth == 0 => break,
+                TokenType::RightBrace => depth -= 1,
+                TokenType::Eof => break,
+                // A declaration starts a statement, `if (c) fun f() {}` is not one
+                TokenType::Identifier
+                    if previous == TokenType::Fun
+                        && depth == 0
+                        && matches!(
+                            self.tokens[index - 2].token_type,
+                            TokenType::LeftBrace | TokenType::RightBrace | TokenType::Semicolon
+                        ) =>
+                {
+                    if !named.contains(token.lexeme.as_str()) {
+                        continue;
+                    }
+                    // A second function with the same name is not hoisted, its declaration reports the error
+                    if let Some(slot) = self.resolver.hoist(token) {
+                        self.emit_op_code(Opcode::Nil);
+                        self.hoisted.push((index, slot));
+                    }
+                }
+                // Not a property
+                TokenType::Identifier
+                    if previous != TokenType::Dot && previous != TokenType::Fun =>
+                {
+                    named.insert(token.lexeme.as_str());
                 }
+                _ => {}
+            }
+        }
+    }
+
+    fn end_scope(&mut self) {
+        for local in self.resolver.end_scope() {
+            self.warn_if_unused(&local);
+            if local.is_captured {
+                self.emit_op_code(Opcode::CloseUpvalue);
             } else {
-                break;
+                self.emit_op_code(Opcode::Pop);
             }
-            i -= 1;
         }
     }
 
@@ -788,6 +1227,14 @@ impl<'a> Compiler<'a> {
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) -> Result<()> {
+        self.nested(|c| c.parse_precedence_unchecked(precedence))
+    }
+
+    fn parse_precedence_unchecked(&mut self, precedence: Precedence) -> Result<()> {
+        // advance() stays at the end, the previous token would be parsed again
+        if self.is_at_end() {
+            bail!(parse_error(self.current(), "Expect expression"))
+        }
         self.advance();
         let previous = self.previous().token_type;
         let can_assign = precedence <= Precedence::Assignment;
@@ -812,8 +1259,7 @@ impl<'a> Compiler<'a> {
     fn number(&mut self, _can_assign: bool) -> Result<()> {
         if let Some(Literal::Number(n)) = &self.previous().literal {
             let value = Value::number(*n);
-            self.emit_constant(value);
-            Ok(())
+            self.emit_constant(value)
         } else {
             bail!(parse_error(self.previous(), "not a number"))
         }
@@ -822,8 +1268,7 @@ impl<'a> Compiler<'a> {
     fn string(&mut self, _can_assign: bool) -> Result<()> {
         if let Some(Literal::String(s)) = &self.previous().literal {
             let value = Value::object(self.allocater.alloc_interned_object(self.boxed_string(s)));
-            self.emit_constant(value);
-            Ok(())
+            self.emit_constant(value)
         } else {
             bail!(parse_error(self.previous(), "not a string"))
         }
@@ -836,39 +1281,54 @@ impl<'a> Compiler<'a> {
     }
 
     fn unary(&mut self, _can_assign: bool) -> Result<()> {
-        let token_type = self.previous().token_type;
+        let operator = self.previous();
         self.parse_precedence(Precedence::Unary)?;
-        match token_type {
-            TokenType::Minus => self.emit_op_code(Opcode::Negate),
-            TokenType::Bang => self.emit_op_code(Opcode::Not),
-            _ => bail!(parse_error(
-                self.previous(),
-                "Cannot perform unary operation."
-            )),
-        }
-        Ok(())
+        self.emit_at(operator, |c| {
+            match operator.token_type {
+                TokenType::Minus => c.emit_op_code(Opcode::Negate),
+                TokenType::Bang => c.emit_op_code(Opcode::Not),
+                _ => bail!(parse_error(c.previous(), "Cannot perform unary operation.")),
+            }
+            Ok(())
+        })
     }
 
     fn binary(&mut self, _can_assign: bool) -> Result<()> {
-        let prev_token = self.previous().clone();
+        let prev_token = self.previous();
         let operator = prev_token.token_type;
         let rule = self.get_rule(operator);
-        let next_precedence = rule.precedence.higher_precedence();
+        let next_precedence = match operator {
+            // Right associative, with a unary operand: 2 ** 3 ** 2 is 2 ** (3 ** 2) and 2 ** -1 is valid
+            TokenType::StarStar => Precedence::Unary,
+            _ => rule.precedence.higher_precedence(),
+        };
         self.parse_precedence(next_precedence)?;
-        match operator {
-            TokenType::Plus => self.emit_op_code(Opcode::Add),
-            TokenType::Minus => self.emit_op_code(Opcode::Subtract),
-            TokenType::Star => self.emit_op_code(Opcode::Multiply),
-            TokenType::Slash => self.emit_op_code(Opcode::Divide),
-            TokenType::BangEqual => self.emit_op_code(Opcode::BangEqual),
-            TokenType::EqualEqual => self.emit_op_code(Opcode::EqualEqual),
-            TokenType::Greater => self.emit_op_code(Opcode::Greater),
-            TokenType::GreaterEqual => self.emit_op_code(Opcode::GreaterEqual),
-            TokenType::Less => self.emit_op_code(Opcode::Less),
-            TokenType::LessEqual => self.emit_op_code(Opcode::LessEqual),
-            _ => bail!(parse_error(&prev_token, "Invalid operator (to be impl?)")),
-        }
-        Ok(())
+        // The instruction points at the operator, e.g. for a runtime error on the operands
+        self.emit_at(prev_token, |c| {
+            match operator {
+                TokenType::Plus => c.emit_add(),
+                TokenType::Minus => c.emit_op_code(Opcode::Subtract),
+                TokenType::Star => c.emit_op_code(Opcode::Multiply),
+                TokenType::Slash => c.emit_op_code(Opcode::Divide),
+                TokenType::StarStar => c.emit_op_code(Opcode::Power),
+                TokenType::BangEqual => c.emit_op_code(Opcode::BangEqual),
+                TokenType::EqualEqual => c.emit_op_code(Opcode::EqualEqual),
+                TokenType::Greater => c.emit_op_code(Opcode::Greater),
+                TokenType::GreaterEqual => c.emit_op_code(Opcode::GreaterEqual),
+                TokenType::Less => c.emit_op_code(Opcode::Less),
+                TokenType::LessEqual => c.emit_op_code(Opcode::LessEqual),
+                TokenType::Is => c.emit_op_code(Opcode::Is),
+                TokenType::Ampersand => c.emit_op_code(Opcode::BitAnd),
+                TokenType::Pipe => c.emit_op_code(Opcode::BitOr),
+                TokenType::Caret => c.emit_op_code(Opcode::BitXor),
+                TokenType::LessLess => c.emit_op_code(Opcode::ShiftLeft),
+                TokenType::GreaterGreater => c.emit_op_code(Opcode::ShiftRight),
+                TokenType::DotDot => c.emit_op_code(Opcode::Range),
+                TokenType::DotDotEqual => c.emit_op_code(Opcode::RangeInclusive),
+                _ => bail!(parse_error(prev_token, "Invalid operator (to be impl?)")),
+            }
+            Ok(())
+        })
     }
 
     fn literal(&mut self, _can_assign: bool) -> Result<()> {
@@ -896,28 +1356,64 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
+    fn conditional(&mut self, _can_assign: bool) -> Result<()> {
+        let if_condition_is_false = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_op_code(Opcode::Pop);
+        self.parse_precedence(Precedence::Conditional)?;
+        self.consume_next_token(TokenType::Colon, "Expect ':' after then branch of '?'")?;
+        let end = self.emit_jump(Opcode::Jump);
+        self.patch_jump(if_condition_is_false)?;
+        self.emit_op_code(Opcode::Pop);
+        // The else branch is parsed at the same precedence, so that `a ? b : c ? d : e` nests to the right
+        self.parse_precedence(Precedence::Conditional)?;
+        self.patch_jump(end)?;
+        Ok(())
+    }
+
     fn call(&mut self, _can_assign: bool) -> Result<()> {
+        let paren = self.previous();
         let arg_count = self.argument_list()?;
-        self.emit_opcode_and_bytes(Opcode::Call, arg_count);
+        self.emit_at(paren, |c| c.emit_opcode_and_bytes(Opcode::Call, arg_count));
         Ok(())
     }
 
     fn dot(&mut self, can_assign: bool) -> Result<()> {
         self.consume_next_token(TokenType::Identifier, "Expect property name after '.'")?;
-        let name = self.identifier_constant(self.previous().clone())?;
+        let property = self.previous();
+        self.resolver.reference_property(property);
+        let name = self.identifier_constant(property.clone())?;
         if can_assign && self.match_and_advance(&[TokenType::Equal]) {
+            // `this.<field> = ` in `init`, the token before the '.' is `this`
+            if self.state.function_type == FunctionType::Initializer
+                && self.tokens[self.token_index - 4].token_type == TokenType::This
+            {
+                self.field_set_by_init(&property.lexeme);
+            }
             self.expression()?;
-            self.emit_opcode_and_bytes(Opcode::SetProperty, name);
+            self.emit_at(property, |c| {
+                c.emit_opcode_and_bytes(Opcode::SetProperty, name)
+            });
         } else if self.match_and_advance(&[TokenType::LeftParen]) {
             let arg_count = self.argument_list()?;
-            self.emit_opcode_and_bytes(Opcode::Invoke, name);
-            self.emit_byte(arg_count);
+            self.emit_at(property, |c| {
+                c.emit_opcode_and_bytes(Opcode::Invoke, name);
+                c.emit_byte(arg_count);
+            });
         } else {
             self.emit_opcode_and_bytes(Opcode::GetProperty, name);
         }
         Ok(())
     }
 
+    /// Records a field set by `init`, see [State::fields]
+    fn field_set_by_init(&mut self, field: &str) {
+        let field = self.boxed_string(field);
+        if !self.state.fields.contains(&field) {
+            self.state.fields.push(field);
+            self.state.function.field_count = self.state.fields.len();
+        }
+    }
+
     fn this(&mut self, _can_assign: bool) -> Result<()> {
         if self.current_class.is_none() {
             bail!(parse_error(
@@ -925,10 +1421,68 @@ impl<'a> Compiler<'a> {
                 "Can't use 'this' outside a class"
             ));
         }
+        // Functions nested in a static method have no receiver to capture either
+        if self.enclosing_method_type() == Some(FunctionType::StaticMethod) {
+            bail!(parse_error(
+                self.previous(),
+                "Can't use 'this' in a static method"
+            ));
+        }
         self.variable_usage(false)
     }
 
-    fn argument_list(&mut self) -> Result<ByteUnit> {
+    /// evie has no inheritance, `super` is always an error
+    fn super_(&mut self, _can_assign: bool) -> Result<()> {
+        if self.current_class.is_none() {
+            bail!(parse_error(
+                self.previous(),
+                "Can't use 'super' outside a class"
+            ));
+        }
+        bail!(parse_error(
+            self.previous(),
+            "Can't use 'super' in a class with no superclass"
+        ))
+    }
+
+    /// `yield` or `yield value`, the value is nil when the expression ends after `yield` (e.g. `yield;`)
+    fn yield_(&mut self, _can_assign: bool) -> Result<()> {
+        match self.state.function_type {
+            FunctionType::Script => bail!(parse_error(
+                self.previous(),
+                "Can't yield from top level code"
+            )),
+            FunctionType::Initializer => bail!(parse_error(
+                self.previous(),
+                "Can't yield from an initializer"
+            )),
+            _ => {}
+        }
+        match self.current().token_type {
+            TokenType::Semicolon
+            | TokenType::RightParen
+            | TokenType::RightBrace
+            | TokenType::Comma => self.emit_op_code(Opcode::Nil),
+            _ => self.parse_precedence(Precedence::Assignment)?,
+        }
+        self.emit_op_code(Opcode::Yield);
+        Ok(())
+    }
+
+    /// The type of the innermost method (or initializer) the current function is in, None outside classes
+    fn enclosing_method_type(&self) -> Option<FunctionType> {
+        std::iter::once(&self.state)
+            .chain(self.states.iter().rev())
+            .map(|state| state.function_type)
+            .find(|function_type| {
+                matches!(
+                    function_type,
+                    FunctionType::Method | FunctionType::Initializer | FunctionType::StaticMethod
+                )
+            })
+    }
+
+    fn argument_list(&mut self) -> Result<ByteUnit> {
         let mut count = 0;
         while self.current().token_type != TokenType::RightParen {
             self.expression()?;
@@ -952,7 +1506,13 @@ impl<'a> Compiler<'a> {
 
     #[inline]
     fn emit_jump(&mut self, opcode: Opcode) -> usize {
-        self.emit_op_code(opcode);
+        match self.fusable(0, COMPARISONS) {
+            Some((offset, comparison)) if opcode == Opcode::JumpIfFalse => {
+                self.fuse(offset, Opcode::CompareJumpIfFalse);
+                self.emit_byte(comparison.into());
+            }
+            _ => self.emit_op_code(opcode),
+        }
         self.emit_byte(0xff);
         self.emit_byte(0xff);
         self.current_chunk_mut().code.item_count() - 2
@@ -969,31 +1529,82 @@ impl<'a> Compiler<'a> {
 
     #[inline]
     fn patch_jump(&mut self, offset: usize) -> Result<()> {
-        let jump = self.current_chunk_mut().code.item_count() - offset - 2;
+        let jump = self.mark_jump_target() - offset - 2;
         let (first, second) = as_two_bytes(jump);
         self.current_chunk_mut().code.insert_at(offset, first);
         self.current_chunk_mut().code.insert_at(offset + 1, second);
         Ok(())
     }
 
+    /// The current offset, marked as the target of a jump
+    fn mark_jump_target(&mut self) -> usize {
+        self.state.jump_target = self.current_chunk().code.item_count();
+        self.state.jump_target
+    }
+
+    /// Emits [Opcode::Add], or a superinstruction if the operands are two locals or a constant
+    fn emit_add(&mut self) {
+        if let (Some((first, _)), Some((second, _))) = (
+            self.fusable(1, &[Opcode::GetLocal]),
+            self.fusable(0, &[Opcode::GetLocal]),
+        ) {
+            let code = &self.current_chunk().code;
+            let (a, b) = (code.read_item_at(first + 1), code.read_item_at(second + 1));
+            self.fuse(first, Opcode::AddLocals);
+            self.emit_byte(a);
+            self.emit_byte(b);
+        } else if let Some((offset, _)) = self.fusable(0, &[Opcode::Constant]) {
+            let constant = self.current_chunk().code.read_item_at(offset + 1);
+            self.fuse(offset, Opcode::AddConstant);
+            self.emit_byte(constant);
+        } else {
+            self.emit_op_code(Opcode::Add);
+        }
+    }
+
+    /// The offset and opcode of the `nth` last instruction (0 is the last one) if it is one of `opcodes`
+    /// and it can be fused with the instructions after it, i.e. no jump lands after its start
+    fn fusable(&self, nth: usize, opcodes: &[Opcode]) -> Option<(usize, Opcode)> {
+        if !self.superinstructions {
+            return None;
+        }
+        let offset = self.state.last_instructions[nth]?;
+        let opcode = Opcode::try_from(self.current_chunk().code.read_item_at(offset)).ok()?;
+        (offset >= self.state.jump_target && opcodes.contains(&opcode)).then_some((offset, opcode))
+    }
+
+    /// Replaces the instructions from `offset` on with the superinstruction, its operands are emitted by the caller
+    fn fuse(&mut self, offset: usize, superinstruction: Opcode) {
+        self.current_chunk_mut().truncate(offset);
+        self.state.last_instructions = [None, None];
+        self.emit_op_code(superinstruction);
+    }
+
     #[inline]
-    fn emit_constant(&mut self, value: Value) {
-        let offset = self.add_constant(value);
+    fn emit_constant(&mut self, value: Value) -> Result<()> {
+        let offset = self.add_constant(value)?;
         self.emit_opcode_and_bytes(Opcode::Constant, offset);
+        Ok(())
     }
 
     #[inline]
-    fn add_constant(&mut self, value: Value) -> ByteUnit {
-        self.current_chunk_mut().add_constant(value)
+    fn add_constant(&mut self, value: Value) -> Result<ByteUnit> {
+        match self.current_chunk_mut().add_constant(value) {
+            Some(index) => Ok(index),
+            None => bail!(parse_error(
+                self.previous(),
+                "Too many constants in one chunk"
+            )),
+        }
     }
 
     fn emit_return_and_log(&mut self) {
         self.emit_return();
-        #[cfg(any(feature = "trace_enabled"))]
+        #[cfg(feature = "trace_enabled")]
         {
             if self.custom_writer.is_some() {
                 let function = self.state.function.as_ref();
-                let name = function.to_string();
+                let name = function.trace_name();
                 let mut writer_opt = self.custom_writer.take();
                 let writer = writer_opt.as_deref_mut().expect("Writer expected");
                 evie_instructions::opcodes::disassemble_chunk_with_writer(
@@ -1009,7 +1620,7 @@ impl<'a> Compiler<'a> {
         {
             if self.custom_writer.is_some() {
                 let function = self.state.function.as_ref();
-                let name = function.to_string();
+                let name = function.trace_name();
                 let mut writer_opt = self.custom_writer.take();
                 let writer = writer_opt.as_deref_mut().expect("Writer expected");
                 evie_instructions::opcodes::disassemble_chunk_with_writer(
@@ -1035,71 +1646,78 @@ impl<'a> Compiler<'a> {
 
     fn parse_variable(&mut self, message: &str) -> Result<ByteUnit> {
         self.consume_next_token(TokenType::Identifier, message)?;
-        self.declare_local_variable()?;
-        if self.current_scope_mut().depth > GLOBAL_SCOPE_DEPTH {
+        self.resolver.declare(self.previous())?;
+        if !self.resolver.is_global_scope() {
             return Ok(0);
         }
         self.identifier_constant(self.previous().clone())
     }
 
-    fn declare_local_variable(&mut self) -> Result<()> {
-        let current_scope_depth = self.current_scope().depth;
-        if self.current_scope_mut().depth > GLOBAL_SCOPE_DEPTH {
-            let token = self.previous();
-            for local in self.current_scope_mut().locals.iter().rev() {
-                if let Some(depth) = &local.depth {
-                    if *depth < current_scope_depth {
-                        break;
-                    }
-                    if local.name == token.lexeme {
-                        bail!(parse_error(
-                            token,
-                            "Already a variable with this name exists in this scope"
-                        ))
-                    }
-                }
-            }
-            self.add_local(token);
+    /// Warns about a local that goes out of scope without being read.
+    /// Names starting with '_' are never reported.
+    fn warn_if_unused(&mut self, local: &Local<'a>) {
+        if local.is_read || local.is_captured || local.name.starts_with('_') {
+            return;
+        }
+        if let Some(token) = local.token {
+            let message = if local.is_assigned {
+                format!("Variable '{}' is assigned but never read", local.name)
+            } else {
+                format!("Unused local variable '{}'", local.name)
+            };
+            self.warn(token, &message);
         }
-        Ok(())
-    }
-
-    fn add_local(&mut self, token: &'a Token) {
-        let local = Local::new(&token.lexeme, None);
-        self.current_scope_mut().locals.push(local);
     }
 
-    fn mark_initialized(&mut self) {
-        if self.current_scope_mut().depth == 0 {
-            return;
-        }
-        let locals_count = self.current_scope_mut().locals.len();
-        self.current_scope_mut().locals[locals_count - 1].depth =
-            Some(self.current_scope_mut().depth);
+    fn warn(&mut self, token: &Token, message: &str) {
+        self.warnings.push(ErrorKind::Warning(
+            token.line,
+            token.column,
+            message.to_string(),
+        ));
     }
 
     fn identifier_constant(&mut self, mut token: Token) -> Result<ByteUnit> {
         let literal = token.literal.take();
         if let Literal::Identifier(s) = literal.expect("Expect string") {
-            let name = Value::object(self.allocater.alloc_interned_object(self.boxed_string(&s)));
-            Ok(self.add_constant(name))
+            self.name_constant(&s)
         } else {
             bail!(parse_error(&token, "Expect identifier"))
         }
     }
 
+    /// The constant of a name, e.g. of a method the compiled code invokes
+    fn name_constant(&mut self, name: &str) -> Result<ByteUnit> {
+        let name = Value::object(
+            self.allocater
+                .alloc_interned_object(self.boxed_string(name)),
+        );
+        self.add_constant(name)
+    }
+
     #[inline]
     fn emit_op_code(&mut self, opcode: Opcode) {
+        let offset = self.current_chunk().code.item_count();
+        self.state.last_instructions = [Some(offset), self.state.last_instructions[0]];
         self.emit_byte(opcode.into())
     }
 
     #[inline]
     fn emit_byte(&mut self, byte: ByteUnit) {
-        let mut line = 0;
-        if self.token_index != 0 {
-            line = self.previous().line;
-        }
-        self.current_chunk_mut().write_chunk(byte, line);
+        let span = match self.emit_span {
+            Some(span) => span,
+            None if self.token_index != 0 => source_span(self.previous()),
+            None => SourceSpan::default(),
+        };
+        self.current_chunk_mut().write_chunk_with_span(byte, span);
+    }
+
+    /// Emits the code with the span of `token` instead of the previous token's, e.g. the operator of a binary expression
+    fn emit_at<T>(&mut self, token: &Token, emit: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = self.emit_span.replace(source_span(token));
+        let result = emit(self);
+        self.emit_span = outer;
+        result
     }
 
     #[inline]
@@ -1146,15 +1764,42 @@ impl<'a> Compiler<'a> {
         false
     }
 
-    fn synchronize(&mut self) {
-        self.advance();
-
+    /// Skips to the start of the next declaration after an error in the one that starts at `start`: past the blocks
+    /// it opened (e.g. the body of a class whose method failed) and then to a statement boundary.
+    /// A `}` that closes an enclosing block is not skipped
+    fn synchronize(&mut self, start: usize) {
+        if self.token_index == start {
+            // Nothing was parsed, the token that failed can not start a declaration
+            self.advance();
+        }
+        let mut open_braces = 0usize;
+        for token in &self.tokens[start..self.token_index] {
+            match token.token_type {
+                TokenType::LeftBrace => open_braces += 1,
+                TokenType::RightBrace => open_braces = open_braces.saturating_sub(1),
+                _ => {}
+            }
+        }
         while !self.is_at_end() {
-            if self.previous().token_type == TokenType::Semicolon {
+            if open_braces > 0 {
+                match self.current().token_type {
+                    TokenType::LeftBrace => open_braces += 1,
+                    TokenType::RightBrace => open_braces -= 1,
+                    _ => {}
+                }
+                self.advance();
+                continue;
+            }
+            if matches!(
+                self.previous().token_type,
+                TokenType::Semicolon | TokenType::RightBrace
+            ) {
                 return;
             }
             match self.current().token_type {
+                TokenType::RightBrace => return,
                 TokenType::Class
+                | TokenType::Const
                 | TokenType::Fun
                 | TokenType::Var
                 | TokenType::For
@@ -1162,8 +1807,10 @@ impl<'a> Compiler<'a> {
                 | TokenType::While
                 | TokenType::Print
                 | TokenType::Return => return,
-                _ => self.advance(),
+                TokenType::LeftBrace => open_braces += 1,
+                _ => {}
             }
+            self.advance();
         }
     }
 
@@ -1172,6 +1819,12 @@ impl<'a> Compiler<'a> {
         &self.tokens[self.token_index]
     }
 
+    #[inline]
+    fn next(&self) -> &'a Token {
+        // The last token is always Eof
+        &self.tokens[(self.token_index + 1).min(self.tokens.len() - 1)]
+    }
+
     #[inline]
     fn previous(&self) -> &'a Token {
         &self.tokens[self.token_index - 1]
@@ -1201,13 +1854,57 @@ fn as_two_bytes(jump: usize) -> (ByteUnit, ByteUnit) {
 mod tests {
     use crate::compiler::FunctionType;
 
-    use super::Compiler;
+    use super::{Compiler, DEFAULT_MAX_NESTING_DEPTH};
     use evie_common::errors::*;
     use evie_common::utf8_to_string;
     use evie_frontend::scanner::Scanner;
     use evie_memory::objects::*;
     use evie_memory::ObjectAllocator;
 
+    #[test]
+    #[cfg(feature = "sync")]
+    fn compiles_in_parallel_with_one_allocator() -> Result<()> {
+        let allocator = ObjectAllocator::new();
+        let sources: Vec<String> = (0..4)
+            .map(|i| format!("var shared = {}; fun f{}() {{ return shared; }}", i, i))
+            .collect();
+        let globals = std::thread::scope(|scope| {
+            let threads: Vec<_> = sources
+                .iter()
+                .map(|source| {
+                    let allocator = &allocator;
+                    scope.spawn(move || -> Result<usize> {
+                        let mut scanner = Scanner::new(source.clone());
+                        let compilation = Compiler::new(scanner.scan_tokens()?, allocator)
+                            .compile_with_analysis()?;
+                        Ok(compilation.scope_table.global("shared").map_or(0, |_| {
+                            allocator.alloc_interned_str("shared").as_ptr() as usize
+                        }))
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|t| t.join().expect("Thread panicked"))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        // The names are interned once, whichever thread compiled first
+        assert_ne!(0, globals[0]);
+        assert!(globals.iter().all(|g| *g == globals[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_rules_are_indexed_by_token_type() {
+        let allocator = ObjectAllocator::new();
+        let tokens = Scanner::new("".to_string()).scan_tokens().unwrap().to_vec();
+        let compiler = Compiler::new(&tokens, &allocator);
+        for (index, rule) in compiler.parse_rules.iter().enumerate() {
+            let token_index: usize = rule.token_type.into();
+            assert_eq!(index, token_index, "{:?}", rule);
+        }
+    }
+
     #[test]
     fn number() -> Result<()> {
         let source = r#"3.14;"#;
@@ -1370,11 +2067,10 @@ mod tests {
         assert_eq!(
             r#"== <fn script> ==
 0000 0001 OpCode[Constant]                  0 'Hello '
-0002    | OpCode[Constant]                  1 ' world'
-0004    | OpCode[Add]
-0005    | OpCode[Pop]
-0006    | OpCode[Nil]
-0007    | OpCode[Return]
+0002    | OpCode[AddConstant]               1 ' world'
+0004    | OpCode[Pop]
+0005    | OpCode[Nil]
+0006    | OpCode[Return]
 "#,
             utf8_to_string(&buf)
         );
@@ -1390,7 +2086,7 @@ mod tests {
             Some(&mut buf),
             &allocator,
         );
-        let function = compiler.compile()?;
+        let function = compiler.compile()?.script().function();
         let u = &*function;
         let (a, b) = (
             u.chunk.as_ref().read_constant_at(0),
@@ -1424,11 +2120,10 @@ mod tests {
         assert_eq!(
             r#"== <fn script> ==
 0000 0001 OpCode[Constant]                  0 '3'
-0002    | OpCode[Constant]                  1 '3'
-0004    | OpCode[Add]
-0005    | OpCode[Print]
-0006    | OpCode[Nil]
-0007    | OpCode[Return]
+0002    | OpCode[AddConstant]               1 '3'
+0004    | OpCode[Print]
+0005    | OpCode[Nil]
+0006    | OpCode[Return]
 "#,
             utf8_to_string(&buf)
         );
@@ -1461,6 +2156,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn const_declaration() -> Result<()> {
+        let source = "const a = 2; print a;";
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let mut buf = vec![];
+        let mut compiler = Compiler::new_with_type_and_writer(
+            tokens,
+            FunctionType::Script,
+            Some(&mut buf),
+            &allocator,
+        );
+        // Declared by a previous input, in slot 0
+        compiler.set_global_constants(vec!["before".to_string()]);
+        let _function = compiler.compile()?;
+        assert_eq!(
+            r#"== <fn script> ==
+0000 0001 OpCode[Constant]                  0 '2'
+0002    | OpCode[DefineGlobalConstant]      1 'a' in slot 1
+0005    | OpCode[GetGlobalConstant]         1
+0007    | OpCode[Print]
+0008    | OpCode[Nil]
+0009    | OpCode[Return]
+"#,
+            utf8_to_string(&buf)
+        );
+        Ok(())
+    }
+
     #[test]
     fn block() -> Result<()> {
         let source = r#"
@@ -1559,6 +2284,353 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn conditional_expression() -> Result<()> {
+        let source = r#"
+        print true ? 1 : 2;
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let mut buf = vec![];
+        let compiler = Compiler::new_with_type_and_writer(
+            tokens,
+            FunctionType::Script,
+            Some(&mut buf),
+            &allocator,
+        );
+        let _function = compiler.compile()?;
+        assert_eq!(
+            r#"== <fn script> ==
+0000 0002 OpCode[True]
+0001    | OpCode[JumpIfFalse]               1 -> 10
+0004    | OpCode[Pop]
+0005    | OpCode[Constant]                  0 '1'
+0007    | OpCode[Jump]                      7 -> 13
+0010    | OpCode[Pop]
+0011    | OpCode[Constant]                  1 '2'
+0013    | OpCode[Print]
+0014    | OpCode[Nil]
+0015    | OpCode[Return]
+"#,
+            utf8_to_string(&buf)
+        );
+        let source = r#"
+        print true ? 1;
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let compiler = Compiler::new(tokens, &allocator);
+        assert!(compiler.compile().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn misplaced_this_super_and_return() -> Result<()> {
+        for (source, message) in [
+            (
+                "print this;",
+                "Error at <this>: message: Can't use 'this' outside a class",
+            ),
+            (
+                "fun f() { return this; }",
+                "Error at <this>: message: Can't use 'this' outside a class",
+            ),
+            (
+                "class A { static s() { return this; } }",
+                "Error at <this>: message: Can't use 'this' in a static method",
+            ),
+            (
+                "class A { static s() { fun f() { return this; } return f; } }",
+                "Error at <this>: message: Can't use 'this' in a static method",
+            ),
+            (
+                "class A { static s() { return fun () { return this; }; } }",
+                "Error at <this>: message: Can't use 'this' in a static method",
+            ),
+            (
+                "print super.x;",
+                "Error at <super>: message: Can't use 'super' outside a class",
+            ),
+            (
+                "class A { m() { return super.m(); } }",
+                "Error at <super>: message: Can't use 'super' in a class with no superclass",
+            ),
+            ("return 1;", "message: Can't return from top level code"),
+            (
+                "class A { init() { return 1; } }",
+                "message: Can't return a value from an initializer",
+            ),
+            ("var a = yield 1;", "message: Can't yield from top level code"),
+            (
+                "class A { init() { yield; } }",
+                "message: Can't yield from an initializer",
+            ),
+        ] {
+            let mut scanner = Scanner::new(source.to_string());
+            let tokens = scanner.scan_tokens()?;
+            let allocator = ObjectAllocator::new();
+            let error = Compiler::new(tokens, &allocator).compile().unwrap_err();
+            assert!(
+                matches!(error.kind(), ErrorKind::ParseError(_)),
+                "{}: {}",
+                source,
+                error
+            );
+            assert!(
+                error.to_string().ends_with(message),
+                "{}: {}",
+                source,
+                error
+            );
+        }
+        // A method of a class declared in a static method has a receiver, as do functions nested in methods
+        for source in [
+            "class A { static s() { class B { m() { return this; } } return B; } }",
+            "class A { m() { fun f() { return this; } return f; } }",
+            "class A { init() { fun f() { return 1; } f(); } }",
+            "class A { init() { fun f() { yield (yield); } f(); } }",
+        ] {
+            let mut scanner = Scanner::new(source.to_string());
+            let tokens = scanner.scan_tokens()?;
+            let allocator = ObjectAllocator::new();
+            assert!(
+                Compiler::new(tokens, &allocator).compile().is_ok(),
+                "{}",
+                source
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn nesting_depth_limit() -> Result<()> {
+        let depth = DEFAULT_MAX_NESTING_DEPTH;
+        let message = format!("Too deeply nested, the limit is {} levels", depth);
+        for (open, close) in [
+            ("(", ")"),
+            ("{", "}"),
+            ("if (true) ", ""),
+            ("fun f() {", "}"),
+        ] {
+            // Within the limit (the print statement and its expression are two levels)
+            let nested = |n: usize| format!("{}print 1;{}", open.repeat(n), close.repeat(n));
+            let source = match open {
+                "(" => format!(
+                    "print {}1{};",
+                    open.repeat(depth - 2),
+                    close.repeat(depth - 2)
+                ),
+                _ => nested(depth / 2 - 1),
+            };
+            let allocator = ObjectAllocator::new();
+            let mut scanner = Scanner::new(source);
+            let tokens = scanner.scan_tokens()?;
+            Compiler::new(tokens, &allocator).compile()?;
+            // Far beyond it, an error and not a stack overflow
+            let source = match open {
+                "(" => format!("print {}1{};", open.repeat(100_000), close.repeat(100_000)),
+                _ => nested(100_000),
+            };
+            let mut scanner = Scanner::new(source);
+            let tokens = scanner.scan_tokens()?;
+            let error = Compiler::new(tokens, &allocator).compile().unwrap_err();
+            assert!(error.to_string().contains(&message), "{}: {}", open, error);
+        }
+        // The limit is configurable
+        let allocator = ObjectAllocator::new();
+        let mut scanner = Scanner::new("print ((1));".to_string());
+        let tokens = scanner.scan_tokens()?;
+        let mut compiler = Compiler::new(tokens, &allocator);
+        compiler.set_max_nesting_depth(3);
+        assert!(compiler.compile().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn constant_limit() -> Result<()> {
+        let prints = |n: usize| (0..n).map(|i| format!("print {};", i)).collect::<String>();
+        let allocator = ObjectAllocator::new();
+        // 256 constants: the name, the value, 253 numbers and the name again
+        for source in [
+            format!("var x = 1; {} print x;", prints(253)),
+            // A function has its own chunk
+            format!("fun f() {{ {} }} print f;", prints(256)),
+        ] {
+            let mut scanner = Scanner::new(source);
+            let tokens = scanner.scan_tokens()?;
+            Compiler::new(tokens, &allocator).compile()?;
+        }
+        // One more is an error, not an index that wraps around to another constant
+        for (source, token) in [
+            (format!("var x = 1; {} print x;", prints(256)), "254"),
+            (
+                (0..300)
+                    .map(|i| format!("var v{} = nil;", i))
+                    .collect::<String>()
+                    + "v299 = 5; print v299;",
+                "v256",
+            ),
+        ] {
+            let mut scanner = Scanner::new(source);
+            let tokens = scanner.scan_tokens()?;
+            let error = Compiler::new(tokens, &allocator).compile().unwrap_err();
+            let message = format!(
+                "Error at <{}>: message: Too many constants in one chunk",
+                token
+            );
+            assert!(error.to_string().contains(&message), "{}", error);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ast_nesting_depth_limit() -> Result<()> {
+        // The AST parser takes more stack per level than the compiler, a debug build needs more than the stack of a
+        // test thread to reach the limit (`evie fmt` parses on the main thread)
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(ast_nesting_depth_limit_on_a_large_stack)?
+            .join()
+            .expect("Expected no stack overflow")
+    }
+
+    fn ast_nesting_depth_limit_on_a_large_stack() -> Result<()> {
+        let message = format!(
+            "Too deeply nested, the limit is {} levels",
+            DEFAULT_MAX_NESTING_DEPTH
+        );
+        let sources: [fn(usize) -> String; 11] = [
+            |n| format!("print {}1{};", "(".repeat(n), ")".repeat(n)),
+            |n| format!("{}print 1;{}", "{".repeat(n), "}".repeat(n)),
+            |n| format!("{}print 1;", "if (true) ".repeat(n)),
+            |n| format!("{}print 1;{}", "fun f() {".repeat(n), "}".repeat(n)),
+            |n| format!("print {}1;", "- ".repeat(n)),
+            |n| format!("print {}1;", "true ? 1 : ".repeat(n)),
+            |n| format!("print {}1{};", "true ? ".repeat(n), " : 1".repeat(n)),
+            |n| format!("print {}1;", "2 ** ".repeat(n)),
+            |n| format!("var a; {}1;", "a = ".repeat(n)),
+            |n| format!("f({}1{});", "f(".repeat(n), ")".repeat(n)),
+            |n| format!("print {}1{};", "1 + (".repeat(n), ")".repeat(n)),
+        ];
+        let parse = |source: String| -> Result<()> {
+            let mut scanner = Scanner::new(source);
+            evie_frontend::ast::parse_to_ast(scanner.scan_tokens()?)?;
+            Ok(())
+        };
+        for source in sources {
+            // The sources the compiler accepts, at the limit and around it
+            let depth = DEFAULT_MAX_NESTING_DEPTH;
+            for n in [depth / 2 - 1, depth - 3, depth - 2, depth - 1] {
+                let allocator = ObjectAllocator::new();
+                let mut scanner = Scanner::new(source(n));
+                let tokens = scanner.scan_tokens()?;
+                let compiled = Compiler::new(tokens, &allocator).compile();
+                assert_eq!(compiled.is_ok(), parse(source(n)).is_ok(), "{}", source(n));
+            }
+            let error = parse(source(100_000)).unwrap_err();
+            assert!(error.to_string().contains(&message), "{}", error);
+            // `evie fmt` reports it too
+            let error = evie_frontend::fmt::format_source(&source(100_000)).unwrap_err();
+            assert!(error.to_string().contains(&message), "{}", error);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unfinished_source() -> Result<()> {
+        for source in ["(", "f(", "f(1, ", "print -"] {
+            let mut scanner = Scanner::new(source.to_string());
+            let tokens = scanner.scan_tokens()?;
+            let allocator = ObjectAllocator::new();
+            let error = Compiler::new(tokens, &allocator).compile().unwrap_err();
+            assert!(
+                error.to_string().ends_with("Error at <>: message: Expect expression"),
+                "{}: {}",
+                source,
+                error
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn errors_are_all_reported() -> Result<()> {
+        // The compiler recovers at the next declaration, a block (or a class body) opened by the one that failed is skipped
+        let source = r#"
+        var a = ;
+        print a
+        fun f(x) {
+            var y = x +;
+            return y;
+        }
+        class A {
+            m(1) { return 1; }
+            n() { return 2; }
+        }
+        {
+            var b = 1;
+            var b = 2;
+        }
+        print "fine";
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let error = Compiler::new(tokens, &allocator).compile().unwrap_err();
+        let errors = match error.kind() {
+            ErrorKind::CompileErrors(errors) => errors,
+            _ => panic!("Expected several errors, got {}", error),
+        };
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Parse Error: [line: 2, column: 17] Error at <;>: message: Expect expression",
+                "Parse Error: [line: 3, column: 15] Error at <a>: message: Expect ';' after print statement",
+                "Parse Error: [line: 5, column: 24] Error at <;>: message: Expect expression",
+                "Parse Error: [line: 9, column: 14] Error at <(>: message: Expect parameter name",
+                "Resolution Error: [line: 14, column: 17] Error at <b>: message: Already a variable with this name exists in this scope",
+            ],
+            errors
+        );
+        // A single error is not wrapped
+        let mut scanner = Scanner::new("var a = ; print a;".to_string());
+        let tokens = scanner.scan_tokens()?;
+        let error = Compiler::new(tokens, &allocator).compile().unwrap_err();
+        assert!(
+            matches!(error.kind(), ErrorKind::ParseError(_)),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn strict_mode() -> Result<()> {
+        let source = r#"
+        const LIMIT = 1;
+        fun f(a) { return later(a) + LIMIT + clock(); }
+        class Later { m() { return Later; } }
+        var later = fun (x) { return x + undeclared + undeclared; };
+        missing = 1;
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        // Off by default
+        Compiler::new(tokens, &allocator).compile()?;
+        let mut compiler = Compiler::new(tokens, &allocator);
+        compiler.set_strict(Some(vec!["clock".to_string()]));
+        let error = compiler.compile().unwrap_err();
+        assert_eq!(
+            "Resolution Error: [line: 5, column: 42] Error at <undeclared>: message: Undefined variable 'undeclared'\n\
+             Resolution Error: [line: 6, column: 9] Error at <missing>: message: Undefined variable 'missing'",
+            error.to_string()
+        );
+        Ok(())
+    }
+
     #[test]
     fn logical_or_and_and_statements() -> Result<()> {
         let source = r#"
@@ -1643,32 +2715,106 @@ mod tests {
 0002    | OpCode[DefineGlobal]              0 'a'
 0004 0003 OpCode[GetGlobal]                 2 'a'
 0006    | OpCode[Constant]                  3 '5'
-0008    | OpCode[LessEqual]
-0009    | OpCode[JumpIfFalse]               9 -> 27
+0008    | OpCode[CompareJumpIfFalse]     LessEqual    8 -> 26
 0012    | OpCode[Pop]
 0013 0004 OpCode[GetGlobal]                 4 'a'
 0015    | OpCode[Print]
 0016 0005 OpCode[GetGlobal]                 6 'a'
-0018    | OpCode[Constant]                  7 '1'
-0020    | OpCode[Add]
-0021    | OpCode[SetGlobal]                 5 'a'
-0023    | OpCode[Pop]
-0024 0006 OpCode[Loop]                     24 -> 4
-0027    | OpCode[Pop]
-0028    | OpCode[Nil]
-0029    | OpCode[Return]
+0018    | OpCode[AddConstant]               7 '1'
+0020    | OpCode[SetGlobal]                 5 'a'
+0022    | OpCode[Pop]
+0023 0006 OpCode[Loop]                     23 -> 4
+0026    | OpCode[Pop]
+0027    | OpCode[Nil]
+0028    | OpCode[Return]
 "#,
             utf8_to_string(&buf)
         );
         Ok(())
     }
 
+    #[test]
+    fn superinstructions() -> Result<()> {
+        // The last `+` is not fused, the jump of `and` lands between the constant and the add
+        let source = r#"
+        fun f(a, b) {
+            if (a < b) return a + b;
+            return a + (b and 2);
+        }
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        for (superinstructions, expected) in [
+            (
+                true,
+                r#"== <fn f> ==
+0000 0003 OpCode[GetLocal]                  1
+0002    | OpCode[GetLocal]                  2
+0004    | OpCode[CompareJumpIfFalse]     Less    4 -> 16
+0008    | OpCode[Pop]
+0009    | OpCode[AddLocals]                 1    2
+0012    | OpCode[Return]
+0013    | OpCode[Jump]                     13 -> 17
+0016    | OpCode[Pop]
+0017 0004 OpCode[GetLocal]                  1
+0019    | OpCode[GetLocal]                  2
+0021    | OpCode[JumpIfFalse]              21 -> 27
+0024    | OpCode[Pop]
+0025    | OpCode[Constant]                  0 '2'
+0027    | OpCode[Add]
+0028    | OpCode[Return]
+0029 0005 OpCode[Nil]
+0030    | OpCode[Return]
+"#,
+            ),
+            (
+                false,
+                r#"== <fn f> ==
+0000 0003 OpCode[GetLocal]                  1
+0002    | OpCode[GetLocal]                  2
+0004    | OpCode[Less]
+0005    | OpCode[JumpIfFalse]               5 -> 18
+0008    | OpCode[Pop]
+0009    | OpCode[GetLocal]                  1
+0011    | OpCode[GetLocal]                  2
+0013    | OpCode[Add]
+0014    | OpCode[Return]
+0015    | OpCode[Jump]                     15 -> 19
+0018    | OpCode[Pop]
+0019 0004 OpCode[GetLocal]                  1
+0021    | OpCode[GetLocal]                  2
+0023    | OpCode[JumpIfFalse]              23 -> 29
+0026    | OpCode[Pop]
+0027    | OpCode[Constant]                  0 '2'
+0029    | OpCode[Add]
+0030    | OpCode[Return]
+0031 0005 OpCode[Nil]
+0032    | OpCode[Return]
+"#,
+            ),
+        ] {
+            let mut buf = vec![];
+            let allocator = ObjectAllocator::new();
+            let mut compiler = Compiler::new_with_type_and_writer(
+                tokens,
+                FunctionType::Script,
+                Some(&mut buf),
+                &allocator,
+            );
+            compiler.set_superinstructions(superinstructions);
+            let _function = compiler.compile()?;
+            let output = utf8_to_string(&buf);
+            assert_eq!(expected, output.split("== <fn script>").next().unwrap());
+        }
+        Ok(())
+    }
+
     #[test]
     fn functions() -> Result<()> {
         let source = r#"
-        var const = "You answered";
+        var prompt = "You answered";
         fun areWeHavingItYet(answer) {
-            print  const + " " + answer;
+            print  prompt + " " + answer;
         }
           
         areWeHavingItYet("yes!");
@@ -1687,17 +2833,16 @@ mod tests {
 
         assert_eq!(
             r#"== <fn areWeHavingItYet> ==
-0000 0004 OpCode[GetGlobal]                 0 'const'
-0002    | OpCode[Constant]                  1 ' '
-0004    | OpCode[Add]
-0005    | OpCode[GetLocal]                  1
-0007    | OpCode[Add]
-0008    | OpCode[Print]
-0009 0005 OpCode[Nil]
-0010    | OpCode[Return]
+0000 0004 OpCode[GetGlobal]                 0 'prompt'
+0002    | OpCode[AddConstant]               1 ' '
+0004    | OpCode[GetLocal]                  1
+0006    | OpCode[Add]
+0007    | OpCode[Print]
+0008 0005 OpCode[Nil]
+0009    | OpCode[Return]
 == <fn script> ==
 0000 0002 OpCode[Constant]                  1 'You answered'
-0002    | OpCode[DefineGlobal]              0 'const'
+0002    | OpCode[DefineGlobal]              0 'prompt'
 0004 0005 OpCode[Closure]                   3 '<fn areWeHavingItYet>'
 0006    | OpCode[DefineGlobal]              2 'areWeHavingItYet'
 0008 0007 OpCode[GetGlobal]                 4 'areWeHavingItYet'
@@ -1711,9 +2856,9 @@ mod tests {
         );
 
         let source = r#"
-        var const = "You answered";
+        var prompt = "You answered";
         fun areWeHavingItYet(answer) {
-            return  const + " " + answer;
+            return  prompt + " " + answer;
         }
           
         print areWeHavingItYet("yes!");
@@ -1731,17 +2876,16 @@ mod tests {
         let _ = compiler.compile()?;
         assert_eq!(
             r#"== <fn areWeHavingItYet> ==
-0000 0004 OpCode[GetGlobal]                 0 'const'
-0002    | OpCode[Constant]                  1 ' '
-0004    | OpCode[Add]
-0005    | OpCode[GetLocal]                  1
-0007    | OpCode[Add]
-0008    | OpCode[Return]
-0009 0005 OpCode[Nil]
-0010    | OpCode[Return]
+0000 0004 OpCode[GetGlobal]                 0 'prompt'
+0002    | OpCode[AddConstant]               1 ' '
+0004    | OpCode[GetLocal]                  1
+0006    | OpCode[Add]
+0007    | OpCode[Return]
+0008 0005 OpCode[Nil]
+0009    | OpCode[Return]
 == <fn script> ==
 0000 0002 OpCode[Constant]                  1 'You answered'
-0002    | OpCode[DefineGlobal]              0 'const'
+0002    | OpCode[DefineGlobal]              0 'prompt'
 0004 0005 OpCode[Closure]                   3 '<fn areWeHavingItYet>'
 0006    | OpCode[DefineGlobal]              2 'areWeHavingItYet'
 0008 0007 OpCode[GetGlobal]                 4 'areWeHavingItYet'
@@ -1756,6 +2900,72 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn docstrings() -> Result<()> {
+        let source = r#"fun f() {
+            "Does f";
+            return "f";
+        }
+        class A {
+            "An A"
+            m() { "Does m"; }
+        }"#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let mut buf = vec![];
+        let compiler = Compiler::new_with_type_and_writer(
+            tokens,
+            FunctionType::Script,
+            Some(&mut buf),
+            &allocator,
+        );
+        let compilation = compiler.compile_with_analysis()?;
+        assert_eq!(
+            r#"== <fn f> ==
+0000 0003 OpCode[Constant]                  0 'f'
+0002    | OpCode[Return]
+0003 0004 OpCode[Nil]
+0004    | OpCode[Return]
+== <fn m> ==
+0000 0007 OpCode[Nil]
+0001    | OpCode[Return]
+== <fn script> ==
+0000 0004 OpCode[Closure]                   1 '<fn f>'
+0002    | OpCode[DefineGlobal]              0 'f'
+0004 0005 OpCode[Class]                     2 'A'
+0006    | OpCode[DefineGlobal]              2 'A'
+0008    | OpCode[GetGlobal]                 3 'A'
+0010 0006 OpCode[Doc]                       4 'An A'
+0012 0007 OpCode[Closure]                   5 '<fn m>'
+0014    | OpCode[Method]                    6 'm'
+0016 0008 OpCode[Pop]
+0017    | OpCode[Nil]
+0018    | OpCode[Return]
+"#,
+            utf8_to_string(&buf)
+        );
+        let docs: Vec<_> = compilation
+            .scope_table
+            .functions()
+            .iter()
+            .map(|f| (f.name.as_str(), f.doc.as_deref()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("script", None),
+                ("f", Some("Does f")),
+                ("m", Some("Does m"))
+            ],
+            docs
+        );
+        assert_eq!(
+            Some("An A"),
+            compilation.scope_table.classes()[0].doc.as_deref()
+        );
+        Ok(())
+    }
+
     #[test]
     fn closure() -> Result<()> {
         let source = r#"fun outer() {
@@ -1901,13 +3111,12 @@ mod tests {
 0000 0004 OpCode[Constant]                  0 'scone with '
 0002    | OpCode[GetLocal]                  1
 0004    | OpCode[Add]
-0005    | OpCode[Constant]                  1 ' and '
-0007    | OpCode[Add]
-0008    | OpCode[GetLocal]                  2
-0010    | OpCode[Add]
-0011    | OpCode[Print]
-0012 0005 OpCode[Nil]
-0013    | OpCode[Return]
+0005    | OpCode[AddConstant]               1 ' and '
+0007    | OpCode[GetLocal]                  2
+0009    | OpCode[Add]
+0010    | OpCode[Print]
+0011 0005 OpCode[Nil]
+0012    | OpCode[Return]
 == <fn script> ==
 0000 0002 OpCode[Class]                     0 'Scone'
 0002    | OpCode[DefineGlobal]              0 'Scone'
@@ -1931,6 +3140,166 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn warnings() -> Result<()> {
+        let source = r#"
+        fun f(unused_parameter) {
+            var unused = 1;
+            var written = 2;
+            written = 3;
+            var read = 4;
+            var _ignored = 5;
+            var captured = 6;
+            fun g() { return captured; }
+            return read + g();
+            print "unreachable";
+            print "not reported twice";
+        }
+        {
+            var in_block = 1;
+        }
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let compiler = Compiler::new(tokens, &allocator);
+        let (_, warnings) = compiler.compile_with_warnings()?;
+        let warnings: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            vec![
+                "Warning: [line: 11, column: 13] Unreachable code",
+                "Warning: [line: 3, column: 17] Unused local variable 'unused'",
+                "Warning: [line: 4, column: 17] Variable 'written' is assigned but never read",
+                "Warning: [line: 15, column: 17] Unused local variable 'in_block'",
+            ],
+            warnings
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn infers_the_names_of_anonymous_functions() -> Result<()> {
+        let source = r#"
+        var add = fun (a, b) { return a + b; };
+        class Button { init() { this.on_click = fun () {}; } }
+        {
+            var local;
+            local = fun () {};
+        }
+        // Not assigned, or compared
+        fun apply(f) { return f(); }
+        apply(fun () {});
+        var same = add == fun () {};
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let artifact = Compiler::new(tokens, &allocator).compile()?;
+        let names: Vec<_> = artifact
+            .functions()
+            .iter()
+            .filter(|f| f.name() == Some("anonymous"))
+            .map(|f| f.inferred_name())
+            .collect();
+        assert_eq!(
+            vec![Some("add"), Some("on_click"), Some("local"), None, None],
+            names
+        );
+        let mut buf = vec![];
+        let mut scanner = Scanner::new("var f = fun () {};".to_string());
+        let tokens = scanner.scan_tokens()?;
+        Compiler::new_with_writer(tokens, &allocator, Some(&mut buf)).compile()?;
+        assert!(utf8_to_string(&buf).contains("== <fn f (anonymous)> =="));
+        Ok(())
+    }
+
+    #[test]
+    fn fields_set_by_init() -> Result<()> {
+        let source = r#"
+        class A {
+            init(x) {
+                this.x = x;
+                this.y = x;
+                this.x = 2;
+                if (x) this.z = 1;
+                // Not counted: set by another function, a field of a field and a field of another object
+                fun f() { this.w = 1; }
+                this.y.v = 1;
+                x.u = 1;
+            }
+            move() { this.x = 1; }
+        }
+        class B { init() {} }
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let artifact = Compiler::new(tokens, &allocator).compile()?;
+        let fields: Vec<_> = artifact
+            .functions()
+            .iter()
+            .map(|f| (f.name(), f.field_count()))
+            .collect();
+        assert_eq!(
+            vec![
+                (None, 0),
+                (Some("init"), 3),
+                (Some("f"), 0),
+                (Some("move"), 0),
+                (Some("init"), 0)
+            ],
+            fields
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn classes_static_methods() -> Result<()> {
+        let source = r#"
+        class Math {
+            static add(a, b) {
+                return a + b;
+            }
+        }
+        print Math.add(1, 2);
+        "#;
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let mut buf = vec![];
+        let allocator = ObjectAllocator::new();
+        let compiler = Compiler::new_with_type_and_writer(
+            tokens,
+            FunctionType::Script,
+            Some(&mut buf),
+            &allocator,
+        );
+        let _ = compiler.compile()?;
+        assert_eq!(
+            r#"== <fn add> ==
+0000 0004 OpCode[AddLocals]                 1    2
+0003    | OpCode[Return]
+0004 0005 OpCode[Nil]
+0005    | OpCode[Return]
+== <fn script> ==
+0000 0002 OpCode[Class]                     0 'Math'
+0002    | OpCode[DefineGlobal]              0 'Math'
+0004    | OpCode[GetGlobal]                 1 'Math'
+0006 0005 OpCode[Closure]                   2 '<fn add>'
+0008    | OpCode[StaticMethod]              3 'add'
+0010 0006 OpCode[Pop]
+0011 0007 OpCode[GetGlobal]                 4 'Math'
+0013    | OpCode[Constant]                  6 '1'
+0015    | OpCode[Constant]                  7 '2'
+0017    | OpCode[Invoke]                   (2 args)   5 'add'
+0020    | OpCode[Print]
+0021    | OpCode[Nil]
+0022    | OpCode[Return]
+"#,
+            utf8_to_string(&buf)
+        );
+        Ok(())
+    }
+
     #[test]
     fn class_initializer_with_this() -> Result<()> {
         let source = r#"
@@ -1959,13 +3328,12 @@ mod tests {
 0000 0004 OpCode[Constant]                  0 'scone with '
 0002    | OpCode[GetLocal]                  1
 0004    | OpCode[Add]
-0005    | OpCode[Constant]                  1 ' and '
-0007    | OpCode[Add]
-0008    | OpCode[GetLocal]                  2
-0010    | OpCode[Add]
-0011    | OpCode[Print]
-0012 0005 OpCode[Nil]
-0013    | OpCode[Return]
+0005    | OpCode[AddConstant]               1 ' and '
+0007    | OpCode[GetLocal]                  2
+0009    | OpCode[Add]
+0010    | OpCode[Print]
+0011 0005 OpCode[Nil]
+0012    | OpCode[Return]
 == <fn script> ==
 0000 0002 OpCode[Class]                     0 'Scone'
 0002    | OpCode[DefineGlobal]              0 'Scone'
@@ -2057,21 +3425,18 @@ mod tests {
 0032    | OpCode[DefineGlobal]             10 'brunch_with_dessert'
 0034 0018 OpCode[GetGlobal]                14 'brunch_with_dessert'
 0036    | OpCode[GetProperty]              15 'food'
-0038    | OpCode[Constant]                 16 ' and '
-0040    | OpCode[Add]
-0041    | OpCode[GetGlobal]                17 'brunch_with_dessert'
-0043    | OpCode[GetProperty]              18 'drinks'
-0045    | OpCode[Add]
-0046    | OpCode[Constant]                 19 ' with '
-0048    | OpCode[Add]
-0049    | OpCode[GetGlobal]                20 'brunch_with_dessert'
-0051    | OpCode[GetProperty]              21 'dessert'
-0053    | OpCode[Add]
-0054    | OpCode[Constant]                 22 ' as dessert'
-0056    | OpCode[Add]
-0057    | OpCode[Print]
-0058    | OpCode[Nil]
-0059    | OpCode[Return]
+0038    | OpCode[AddConstant]              16 ' and '
+0040    | OpCode[GetGlobal]                17 'brunch_with_dessert'
+0042    | OpCode[GetProperty]              18 'drinks'
+0044    | OpCode[Add]
+0045    | OpCode[AddConstant]              19 ' with '
+0047    | OpCode[GetGlobal]                20 'brunch_with_dessert'
+0049    | OpCode[GetProperty]              21 'dessert'
+0051    | OpCode[Add]
+0052    | OpCode[AddConstant]              22 ' as dessert'
+0054    | OpCode[Print]
+0055    | OpCode[Nil]
+0056    | OpCode[Return]
 "#,
             utf8_to_string(&buf)
         );
@@ -0,0 +1,161 @@
+//! Dead function elimination, see [crate::compiler::Compiler::set_eliminate_dead_functions].
+//!
+//! The functions declared at the top level (`fun f() {}` in the script) are globals. One is live if it is referenced
+//! (by name) from live code, starting from the script. A dead one is dropped: its `Closure` and `DefineGlobal`
+//! instructions are removed from the script, so the function (and the functions nested in it) is never created and
+//! its chunk is garbage. Classes, methods and the functions declared in other scopes are always kept.
+use std::{collections::HashMap, ops::Range};
+
+use evie_instructions::opcodes::Opcode;
+
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+
+use crate::{artifact::Artifact, resolver::ScopeTable};
+
+/// A function declared at the top level
+#[derive(Debug)]
+pub(crate) struct GlobalFunction {
+    /// The index in [Artifact::functions] (and in [ScopeTable::functions], they are in the same order)
+    pub(crate) index: usize,
+    pub(crate) name: String,
+    /// The `Closure` and `DefineGlobal` instructions in the script that define it
+    pub(crate) code: Range<usize>,
+}
+
+/// Removes the dead functions from the script and the artifact, see the [module docs](self)
+pub(crate) fn eliminate_dead_functions(
+    artifact: &mut Artifact,
+    table: &ScopeTable,
+    globals: &[GlobalFunction],
+) {
+    let mut script = artifact.script().function().chunk;
+    let code = &script.code.inner;
+    // Only the definitions that are exactly the two instructions can be removed
+    let removable: HashMap<usize, &GlobalFunction> = globals
+        .iter()
+        .filter(|global| {
+            let (start, end) = (global.code.start, global.code.end);
+            end - start == 4
+                && code[start] == Opcode::Closure as u8
+                && code[end - 2] == Opcode::DefineGlobal as u8
+        })
+        .map(|global| (global.index, global))
+        .collect();
+    let functions = artifact.functions();
+    let mut live = vec![false; functions.len()];
+    live[0] = true;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (index, function) in functions.iter().enumerate().skip(1) {
+            if live[index] {
+                continue;
+            }
+            live[index] = match removable.get(&index) {
+                Some(global) => table.global(&global.name).is_some_and(|symbol| {
+                    symbol
+                        .references
+                        .iter()
+                        .any(|reference| live[reference.function])
+                }),
+                None => function
+                    .enclosing()
+                    .is_some_and(|enclosing| live[enclosing]),
+            };
+            changed |= live[index];
+        }
+    }
+    let mut dead: Vec<&GlobalFunction> = removable
+        .values()
+        .filter(|global| !live[global.index])
+        .copied()
+        .collect();
+    // From the last, so that the offsets of the others do not move
+    dead.sort_by_key(|global| std::cmp::Reverse(global.code.start));
+    for global in dead {
+        let constant = script.code.inner[global.code.start + 1] as usize;
+        script.constants.inner[constant] = Value::nil();
+        script.remove_code(global.code.clone());
+    }
+    // The indices of the functions that are left
+    let mut indices = Vec::with_capacity(live.len());
+    let mut next = 0;
+    for &live in &live {
+        indices.push(next);
+        next += live as usize;
+    }
+    let mut index = 0;
+    artifact.functions.retain_mut(|function| {
+        function.enclosing = function.enclosing.map(|enclosing| indices[enclosing]);
+        index += 1;
+        live[index - 1]
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_common::errors::*;
+    use evie_frontend::scanner::Scanner;
+    use evie_instructions::verifier::verify;
+    use evie_memory::ObjectAllocator;
+
+    use crate::compiler::Compiler;
+
+    #[test]
+    fn dead_functions() -> Result<()> {
+        let source = r#"
+        fun unused() { fun nested() {} return nested; }
+        fun helper(n) { return n + 1; }
+        fun used() { return helper(1); }
+        fun recursive(n) { if (n > 0) return recursive(n - 1); return 0; }
+        fun called_by_dead() { return 1; }
+        fun dead() { return called_by_dead(); }
+        class A { m() { return used(); } }
+        { fun local() {} }
+        var f = fun () { return 1; };
+        print A().m();
+        "#;
+        for (eliminate, expected) in [
+            (
+                false,
+                vec![
+                    "unused",
+                    "nested",
+                    "helper",
+                    "used",
+                    "recursive",
+                    "called_by_dead",
+                    "dead",
+                    "m",
+                    "local",
+                    "anonymous",
+                ],
+            ),
+            (true, vec!["helper", "used", "m", "local", "anonymous"]),
+        ] {
+            let mut scanner = Scanner::new(source.to_string());
+            let tokens = scanner.scan_tokens()?;
+            let allocator = ObjectAllocator::new();
+            let mut compiler = Compiler::new(tokens, &allocator);
+            compiler.set_eliminate_dead_functions(eliminate);
+            let artifact = compiler.compile()?;
+            let names: Vec<_> = artifact.functions()[1..]
+                .iter()
+                .map(|f| f.name().unwrap())
+                .collect();
+            assert_eq!(expected, names);
+            verify(artifact.script().function().chunk, "script")?;
+            for name in ["used", "m", "local", "anonymous"] {
+                assert_eq!(Some(0), artifact.function(name).unwrap().enclosing());
+            }
+            if let Some(nested) = artifact.function("nested") {
+                let unused = nested.enclosing().unwrap();
+                assert_eq!(Some("unused"), artifact.functions()[unused].name());
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,2 +1,7 @@
 //! The compiler crate. This crate consumes [evie_frontend::tokens::Token] produced by [evie_frontend::scanner::Scanner] and outputs the byte code
+pub mod artifact;
+pub mod cache;
 pub mod compiler;
+pub mod dead_code;
+pub mod pgo;
+pub mod resolver;
@@ -0,0 +1,253 @@
+//! Profile guided optimization, see [crate::compiler::Compiler::set_profile].
+//!
+//! A [Profile] is recorded by running a script (`evie --pgo-profile profile.json script.evie`): it counts the calls
+//! of every function of the [Artifact] and the reads of each of their constants. Compiling the same script with the
+//! profile (`evie --pgo-use profile.json script.evie`) lays the byte code out for that run:
+//! - the constants of each function are reordered, the most read first, so that the hot constants share cache lines,
+//! - the code of the called functions is reallocated one after the other, the most called first, so that the hot
+//!   byte code is close in memory instead of in the order of the source.
+//!
+//! The functions of the profile are matched to the functions of the artifact by their position (and checked by
+//! name), a profile is only valid for the script (and the compiler options) it was recorded with.
+use evie_common::{bail, errors::*, ByteUnit};
+use evie_instructions::opcodes::Opcode;
+use evie_memory::objects::ObjectType;
+use serde_json::json;
+
+use crate::artifact::{Artifact, CompiledFunction};
+
+/// The counts of a run, see the [module docs](self)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// In the order of [Artifact::functions]
+    pub functions: Vec<FunctionProfile>,
+}
+
+/// The counts of a function
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionProfile {
+    /// The name, None for the script
+    pub name: Option<String>,
+    pub calls: u64,
+    /// The reads of each constant, by its index in the chunk
+    pub constant_reads: Vec<u64>,
+}
+
+impl Profile {
+    /// Writes the profile as JSON, e.g. `{"functions":[{"name":null,"calls":1,"constant_reads":[1,0]}]}`
+    pub fn to_json(&self) -> String {
+        let functions: Vec<_> = self
+            .functions
+            .iter()
+            .map(|function| {
+                json!({
+                    "name": function.name,
+                    "calls": function.calls,
+                    "constant_reads": function.constant_reads,
+                })
+            })
+            .collect();
+        json!({ "functions": functions }).to_string()
+    }
+
+    /// Reads a profile written by [Profile::to_json]
+    pub fn from_json(text: &str) -> Result<Profile> {
+        let json: serde_json::Value = serde_json::from_str(text)
+            .map_err(|e| ErrorKind::Msg(format!("Invalid profile: {}", e)))?;
+        let functions = match json.get("functions").and_then(|f| f.as_array()) {
+            Some(functions) => functions,
+            None => bail!("Invalid profile: expected the list of functions"),
+        };
+        let functions = functions
+            .iter()
+            .map(|function| {
+                let name = match function.get("name") {
+                    Some(serde_json::Value::String(name)) => Some(name.clone()),
+                    Some(serde_json::Value::Null) => None,
+                    _ => bail!("Invalid profile: expected the name of the function"),
+                };
+                let calls = function.get("calls").and_then(|calls| calls.as_u64());
+                let constant_reads = function
+                    .get("constant_reads")
+                    .and_then(|reads| reads.as_array())
+                    .and_then(|reads| reads.iter().map(|r| r.as_u64()).collect());
+                match (calls, constant_reads) {
+                    (Some(calls), Some(constant_reads)) => Ok(FunctionProfile {
+                        name,
+                        calls,
+                        constant_reads,
+                    }),
+                    _ => bail!("Invalid profile: expected the counts of the function"),
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(Profile { functions })
+    }
+}
+
+/// Lays out the byte code of the artifact for the profile, see the [module docs](self)
+pub(crate) fn apply_profile(artifact: &Artifact, profile: &Profile) -> Result<()> {
+    let functions = artifact.functions();
+    let matches = functions.len() == profile.functions.len()
+        && functions
+            .iter()
+            .zip(&profile.functions)
+            .all(|(function, counts)| function.name() == counts.name.as_deref());
+    if !matches {
+        bail!("The profile does not match the script, profile it again with --pgo-profile")
+    }
+    for (function, counts) in functions.iter().zip(&profile.functions) {
+        reorder_constants(function, &counts.constant_reads);
+    }
+    let mut hot: Vec<_> = functions
+        .iter()
+        .zip(&profile.functions)
+        .filter(|(_, counts)| counts.calls > 0)
+        .collect();
+    hot.sort_by_key(|(_, counts)| std::cmp::Reverse(counts.calls));
+    // All the copies are allocated before the originals are freed, so that they are not reused in between
+    let copies: Vec<_> = hot
+        .iter()
+        .map(|(function, _)| function.code().to_vec())
+        .collect();
+    for ((function, _), code) in hot.into_iter().zip(copies) {
+        function.function().chunk.code.inner = code;
+    }
+    Ok(())
+}
+
+/// Sorts the constants by their reads (the most read first) and updates the operands that refer to them
+fn reorder_constants(function: &CompiledFunction, reads: &[u64]) {
+    let mut chunk = function.function().chunk;
+    let count = chunk.constants.inner.len();
+    let mut order: Vec<usize> = (0..count).collect();
+    // Stable, the constants that are read as often keep their order
+    order.sort_by_key(|&constant| std::cmp::Reverse(reads.get(constant).copied().unwrap_or(0)));
+    let mut new_index: Vec<ByteUnit> = vec![0; count];
+    for (new, &old) in order.iter().enumerate() {
+        new_index[old] = new as ByteUnit;
+    }
+    let code = &mut chunk.code.inner;
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = match Opcode::try_from(code[offset]) {
+            Ok(opcode) => opcode,
+            // The verifier reports it
+            Err(_) => return,
+        };
+        // The length of the instruction and whether its first operand is a constant
+        let (length, constant_operand) = match opcode {
+            Opcode::Constant
+            | Opcode::AddConstant
+            | Opcode::DefineGlobal
+            | Opcode::GetGlobal
+            | Opcode::SetGlobal
+            | Opcode::Class
+            | Opcode::SetProperty
+            | Opcode::GetProperty
+            | Opcode::Method
+            | Opcode::StaticMethod
+            | Opcode::Doc => (2, true),
+            Opcode::Invoke | Opcode::DefineGlobalConstant => (3, true),
+            Opcode::Closure => {
+                let constant = function.constants()[code[offset + 1] as usize];
+                let upvalue_count = match constant.as_object().object_type {
+                    ObjectType::Function(f) => f.upvalue_count,
+                    _ => 0,
+                };
+                (2 + upvalue_count * 2, true)
+            }
+            Opcode::GetLocal
+            | Opcode::SetLocal
+            | Opcode::Call
+            | Opcode::GetUpvalue
+            | Opcode::SetUpvalue
+            | Opcode::GetGlobalConstant => (2, false),
+            Opcode::AddLocals
+            | Opcode::Jump
+            | Opcode::JumpIfFalse
+            | Opcode::JumpIfTrue
+            | Opcode::JumpIfNil
+            | Opcode::Loop => (3, false),
+            Opcode::CompareJumpIfFalse => (4, false),
+            _ => (1, false),
+        };
+        if constant_operand {
+            code[offset + 1] = new_index[code[offset + 1] as usize];
+        }
+        offset += length;
+    }
+    let constants = std::mem::take(&mut chunk.constants.inner);
+    chunk.constants.inner = order.into_iter().map(|old| constants[old]).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_common::errors::*;
+    use evie_frontend::scanner::Scanner;
+    use evie_instructions::verifier::verify;
+    use evie_memory::ObjectAllocator;
+
+    use super::{FunctionProfile, Profile};
+    use crate::compiler::Compiler;
+
+    fn constants(source: &str, profile: Option<Profile>) -> Result<Vec<Vec<String>>> {
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens()?;
+        let allocator = ObjectAllocator::new();
+        let mut compiler = Compiler::new(tokens, &allocator);
+        compiler.set_profile(profile);
+        let artifact = compiler.compile()?;
+        verify(artifact.script().function().chunk, "script")?;
+        Ok(artifact
+            .functions()
+            .iter()
+            .map(|f| f.constants().iter().map(|c| c.to_string()).collect())
+            .collect())
+    }
+
+    #[test]
+    fn profile_layout() -> Result<()> {
+        let source = r#"
+        fun add(a, b) { print "cold"; return a + b + 1; }
+        var total = add(1, 2);
+        print total;
+        "#;
+        let function = |name: Option<&str>, calls, constant_reads: Vec<u64>| FunctionProfile {
+            name: name.map(str::to_string),
+            calls,
+            constant_reads,
+        };
+        let profile = Profile {
+            functions: vec![
+                function(None, 1, vec![0, 0, 3, 0, 0, 0, 5]),
+                function(Some("add"), 1, vec![1, 2]),
+            ],
+        };
+        let profile = Profile::from_json(&profile.to_json())?;
+        assert_eq!(
+            vec![
+                vec!["add", "<fn add>", "total", "add", "1", "2", "total"],
+                vec!["cold", "1"]
+            ],
+            constants(source, None)?
+        );
+        // The most read first, the others keep their order
+        assert_eq!(
+            vec![
+                vec!["total", "total", "add", "<fn add>", "add", "1", "2"],
+                vec!["1", "cold"]
+            ],
+            constants(source, Some(profile.clone()))?
+        );
+
+        let mut other = profile;
+        other.functions[1].name = Some("other".to_string());
+        let error = constants(source, Some(other)).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("The profile does not match the script"));
+        assert!(Profile::from_json(r#"{"functions": [{"name": null}]}"#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,898 @@
+//! Variable resolution (scope analysis).
+//!
+//! The [Resolver] tracks the scopes, locals and upvalues of every function while the compiler walks the tokens,
+//! and decides whether a name refers to a local, an upvalue or a global.
+//! Everything it resolves is recorded in a [ScopeTable], which can be queried afterwards (e.g. by the language server
+//! for hover and go to definition). Use [resolve] to only run the resolution, without keeping the byte code.
+use std::collections::HashMap;
+
+use evie_common::{bail, errors::*, ByteUnit};
+pub use evie_frontend::tokens::Span;
+use evie_frontend::tokens::Token;
+use evie_memory::ObjectAllocator;
+
+use crate::compiler::Compiler;
+
+const GLOBAL_SCOPE_DEPTH: usize = 0;
+
+fn resolution_error(token: &Token, message: &str) -> ErrorKind {
+    ErrorKind::ResolutionError(format!(
+        "[line: {}, column: {}] Error at <{}>: message: {}",
+        token.line, token.column, token.lexeme, message
+    ))
+}
+
+/// Resolves the variables in the given tokens and returns the [ScopeTable]
+pub fn resolve(tokens: &[Token]) -> Result<ScopeTable> {
+    let allocator = ObjectAllocator::new();
+    let compilation = Compiler::new(tokens, &allocator).compile_with_analysis()?;
+    Ok(compilation.scope_table)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Global,
+    Local,
+    Parameter,
+}
+
+/// A declared (or for globals, also an undeclared but used) name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The function (index in [ScopeTable::functions]) that declares it, the script for globals
+    pub function: usize,
+    /// None for globals that are used but not declared in the source (e.g. natives)
+    pub declaration: Option<Span>,
+    pub references: Vec<Reference>,
+}
+
+/// A use of a [Symbol]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference {
+    pub span: Span,
+    /// The function (index in [ScopeTable::functions]) the reference is in.
+    /// For locals, a function other than [Symbol::function] means the local is captured as an upvalue.
+    pub function: usize,
+}
+
+/// The names declared and used by one function, as indices in [ScopeTable::symbols]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionScope {
+    pub name: String,
+    /// The span of the name, None for the script and anonymous functions
+    pub declaration: Option<Span>,
+    pub arity: usize,
+    /// The class (index in [ScopeTable::classes]) for methods
+    pub class: Option<usize>,
+    /// The enclosing function, None for the script
+    pub enclosing: Option<usize>,
+    pub locals: Vec<usize>,
+    /// The locals of enclosing functions captured by this function
+    pub upvalues: Vec<usize>,
+    /// The globals used by this function
+    pub globals: Vec<usize>,
+    /// The docstring, a string literal that is the first statement of the body
+    pub doc: Option<String>,
+}
+
+/// A class and its methods
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassScope {
+    pub name: String,
+    pub declaration: Span,
+    /// The methods (static or not), as indices in [ScopeTable::functions]
+    pub methods: Vec<usize>,
+    /// The docstring, a string literal at the start of the class body
+    pub doc: Option<String>,
+    /// The function that declares the class
+    function: usize,
+}
+
+/// The result of the resolution: the functions (the script is the first), the classes and their symbols
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScopeTable {
+    functions: Vec<FunctionScope>,
+    classes: Vec<ClassScope>,
+    symbols: Vec<Symbol>,
+    globals: HashMap<String, usize>,
+    /// The property names used after a '.', with the function they are used in
+    properties: Vec<(String, Reference)>,
+}
+
+impl ScopeTable {
+    pub fn functions(&self) -> &[FunctionScope] {
+        &self.functions
+    }
+
+    pub fn classes(&self) -> &[ClassScope] {
+        &self.classes
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// The property (or method) name accessed or declared as a method at the position
+    pub fn member_at(&self, line: usize, column: usize) -> Option<&str> {
+        let property = self
+            .properties
+            .iter()
+            .find(|(_, reference)| reference.span.contains(line, column))
+            .map(|(name, _)| name.as_str());
+        property.or_else(|| {
+            self.functions
+                .iter()
+                .find(|f| {
+                    f.class.is_some() && f.declaration.is_some_and(|d| d.contains(line, column))
+                })
+                .map(|f| f.name.as_str())
+        })
+    }
+
+    /// The methods with the given name, in any class
+    pub fn methods_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a FunctionScope> {
+        self.functions
+            .iter()
+            .filter(move |f| f.class.is_some() && f.name == name)
+    }
+
+    /// The uses of the property (or method) with the given name, e.g. `p.x` or `p.move()`
+    pub fn property_references<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Reference> {
+        self.properties
+            .iter()
+            .filter(move |(property, _)| property == name)
+            .map(|(_, reference)| reference)
+    }
+
+    /// The function whose name is declared at the span
+    pub fn function_declared_at(&self, span: Span) -> Option<&FunctionScope> {
+        self.functions
+            .iter()
+            .find(|function| function.declaration == Some(span))
+    }
+
+    /// The class whose name is declared at the span
+    pub fn class_declared_at(&self, span: Span) -> Option<&ClassScope> {
+        self.classes.iter().find(|class| class.declaration == span)
+    }
+
+    /// The parameters of the function, in order
+    pub fn parameters<'a>(
+        &'a self,
+        function: &'a FunctionScope,
+    ) -> impl Iterator<Item = &'a Symbol> {
+        function
+            .locals
+            .iter()
+            .map(|&index| &self.symbols[index])
+            .filter(|symbol| symbol.kind == SymbolKind::Parameter)
+    }
+
+    /// The signature of the function, e.g. `add(a, b)`
+    pub fn signature(&self, function: &FunctionScope) -> String {
+        let parameters: Vec<&str> = self
+            .parameters(function)
+            .map(|parameter| parameter.name.as_str())
+            .collect();
+        format!("{}({})", function.name, parameters.join(", "))
+    }
+
+    /// The global with the given name, if declared or used
+    pub fn global(&self, name: &str) -> Option<&Symbol> {
+        self.globals.get(name).map(|&index| &self.symbols[index])
+    }
+
+    /// The symbol declared or referenced at the position (lines and columns start at 1)
+    pub fn symbol_at(&self, line: usize, column: usize) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| {
+            symbol
+                .declaration
+                .iter()
+                .chain(symbol.references.iter().map(|reference| &reference.span))
+                .any(|span| span.contains(line, column))
+        })
+    }
+
+    fn add_function(
+        &mut self,
+        name: &str,
+        declaration: Option<Span>,
+        enclosing: Option<usize>,
+        class: Option<usize>,
+    ) -> usize {
+        self.functions.push(FunctionScope {
+            name: name.to_string(),
+            declaration,
+            arity: 0,
+            class,
+            enclosing,
+            locals: Vec::new(),
+            upvalues: Vec::new(),
+            globals: Vec::new(),
+            doc: None,
+        });
+        let index = self.functions.len() - 1;
+        if let Some(class) = class {
+            self.classes[class].methods.push(index);
+        }
+        index
+    }
+
+    fn add_symbol(&mut self, token: &Token, kind: SymbolKind, function: usize) -> usize {
+        self.symbols.push(Symbol {
+            name: token.lexeme.clone(),
+            kind,
+            function,
+            declaration: Some(token.span()),
+            references: Vec::new(),
+        });
+        self.symbols.len() - 1
+    }
+
+    fn declare_global(&mut self, token: &Token) {
+        match self.globals.get(&token.lexeme) {
+            Some(&index) => {
+                let symbol = &mut self.symbols[index];
+                // Used before it was declared, redeclarations keep the first declaration
+                if symbol.declaration.is_none() {
+                    symbol.declaration = Some(token.span());
+                } else {
+                    symbol.references.push(Reference {
+                        span: token.span(),
+                        function: 0,
+                    });
+                }
+            }
+            None => {
+                let index = self.add_symbol(token, SymbolKind::Global, 0);
+                self.globals.insert(token.lexeme.clone(), index);
+            }
+        }
+    }
+
+    fn reference_global(&mut self, token: &Token, function: usize) {
+        let index = match self.globals.get(&token.lexeme) {
+            Some(&index) => index,
+            None => {
+                let index = self.add_symbol(token, SymbolKind::Global, 0);
+                self.symbols[index].declaration = None;
+                self.globals.insert(token.lexeme.clone(), index);
+                index
+            }
+        };
+        self.symbols[index].references.push(Reference {
+            span: token.span(),
+            function,
+        });
+        let globals = &mut self.functions[function].globals;
+        if !globals.contains(&index) {
+            globals.push(index);
+        }
+    }
+}
+
+/// Where a name was resolved to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resolution {
+    /// The slot in the current function
+    Local(ByteUnit),
+    /// The index in the upvalues of the current function
+    Upvalue(ByteUnit),
+    Global,
+    /// The slot of a `const` global
+    GlobalConstant(ByteUnit),
+}
+
+#[derive(Debug)]
+pub(crate) struct Local<'a> {
+    pub(crate) name: &'a str,
+    depth: Option<usize>,
+    pub(crate) is_captured: bool,
+    /// The declaring token, None for the implicit slot 0 (`this` or the function itself)
+    pub(crate) token: Option<&'a Token>,
+    pub(crate) is_read: bool,
+    pub(crate) is_assigned: bool,
+    symbol: Option<usize>,
+    /// A function declared ahead of its declaration (see [Resolver::hoist]) which is not compiled yet
+    is_hoisted: bool,
+}
+
+impl<'a> Local<'a> {
+    fn new(name: &'a str, depth: Option<usize>) -> Self {
+        Local {
+            name,
+            depth,
+            is_captured: false,
+            token: None,
+            is_read: false,
+            is_assigned: false,
+            symbol: None,
+            is_hoisted: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Upvalue {
+    pub(crate) index: ByteUnit,
+    pub(crate) is_local: bool,
+}
+
+/// The resolution state of one function
+#[derive(Debug)]
+pub(crate) struct FunctionResolver<'a> {
+    pub(crate) locals: Vec<Local<'a>>,
+    pub(crate) upvalues: Vec<Upvalue>,
+    depth: usize,
+    function: usize,
+}
+
+impl<'a> FunctionResolver<'a> {
+    fn new(slot_zero: &'a str, function: usize) -> Self {
+        FunctionResolver {
+            locals: vec![Local::new(slot_zero, Some(0))],
+            upvalues: Vec::new(),
+            depth: 0,
+            function,
+        }
+    }
+
+    fn resolve_local(&self, name: &Token) -> Result<Option<ByteUnit>> {
+        match self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name.lexeme)
+        {
+            Some((_, local)) if local.depth.is_none() => bail!(resolution_error(
+                name,
+                "Can't read local variable in its own initializer"
+            )),
+            Some((index, _)) => Ok(Some(index as ByteUnit)),
+            None => Ok(None),
+        }
+    }
+
+    fn add_upvalue(
+        &mut self,
+        index: ByteUnit,
+        is_local: bool,
+        symbol: Option<usize>,
+        table: &mut ScopeTable,
+    ) -> ByteUnit {
+        if let Some(i) = self
+            .upvalues
+            .iter()
+            .position(|u| u.is_local == is_local && u.index == index)
+        {
+            return i as ByteUnit;
+        }
+        self.upvalues.push(Upvalue { index, is_local });
+        if let Some(symbol) = symbol {
+            table.functions[self.function].upvalues.push(symbol);
+        }
+        (self.upvalues.len() - 1) as ByteUnit
+    }
+}
+
+/// How deep the resolver is in functions, scopes and classes, see [Resolver::unwind]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Nesting {
+    functions: usize,
+    depth: usize,
+    classes: usize,
+}
+
+/// Resolves the variables of the function being compiled and its enclosing functions
+#[derive(Debug)]
+pub(crate) struct Resolver<'a> {
+    current: FunctionResolver<'a>,
+    enclosing: Vec<FunctionResolver<'a>>,
+    /// The classes being declared (indices in [ScopeTable::classes]), innermost last
+    classes: Vec<usize>,
+    /// The names of the `const` globals, by slot
+    global_constants: Vec<String>,
+    table: ScopeTable,
+}
+
+impl<'a> Resolver<'a> {
+    pub(crate) fn new() -> Self {
+        let mut table = ScopeTable::default();
+        let script = table.add_function("script", None, None, None);
+        Resolver {
+            current: FunctionResolver::new("", script),
+            enclosing: Vec::new(),
+            classes: Vec::new(),
+            global_constants: Vec::new(),
+            table,
+        }
+    }
+
+    /// The constants declared before (e.g. by the previous inputs of a REPL), by slot
+    pub(crate) fn set_global_constants(&mut self, names: Vec<String>) {
+        self.global_constants = names;
+    }
+
+    /// Declares the `const` global named by `token`, returns its slot
+    pub(crate) fn declare_global_constant(&mut self, token: &'a Token) -> Result<ByteUnit> {
+        self.declare(token)?;
+        if self.global_constants.len() > ByteUnit::MAX as usize {
+            bail!(resolution_error(token, "Too many constants"))
+        }
+        self.global_constants.push(token.lexeme.clone());
+        Ok((self.global_constants.len() - 1) as ByteUnit)
+    }
+
+    fn global_constant(&self, name: &str) -> Option<ByteUnit> {
+        self.global_constants
+            .iter()
+            .position(|constant| constant == name)
+            .map(|slot| slot as ByteUnit)
+    }
+
+    /// Starts resolving a new (nested) function, methods have the receiver (`this`) in slot 0.
+    /// `declaration` is the name token, None for anonymous functions
+    pub(crate) fn begin_function(
+        &mut self,
+        name: &str,
+        declaration: Option<&Token>,
+        has_receiver: bool,
+    ) {
+        // Functions declared directly in a class body are its methods
+        let class = self
+            .classes
+            .last()
+            .copied()
+            .filter(|&class| self.table.classes[class].function == self.current.function);
+        let function = self.table.add_function(
+            name,
+            declaration.map(Token::span),
+            Some(self.current.function),
+            class,
+        );
+        let slot_zero = if has_receiver { "this" } else { "" };
+        let enclosing = std::mem::replace(
+            &mut self.current,
+            FunctionResolver::new(slot_zero, function),
+        );
+        self.enclosing.push(enclosing);
+    }
+
+    /// Ends the current function, returns its resolution state (e.g. the upvalues to capture)
+    pub(crate) fn end_function(&mut self) -> FunctionResolver<'a> {
+        let enclosing = self.enclosing.pop().expect("Enclosing function expected");
+        std::mem::replace(&mut self.current, enclosing)
+    }
+
+    /// Starts the body of the class named by `token`, the functions begun until [Resolver::end_class] are its methods
+    pub(crate) fn begin_class(&mut self, token: &Token) {
+        self.table.classes.push(ClassScope {
+            name: token.lexeme.clone(),
+            declaration: token.span(),
+            methods: Vec::new(),
+            doc: None,
+            function: self.current.function,
+        });
+        self.classes.push(self.table.classes.len() - 1);
+    }
+
+    /// Records the docstring of the current function
+    pub(crate) fn set_function_doc(&mut self, doc: &str) {
+        self.table.functions[self.current.function].doc = Some(doc.to_string());
+    }
+
+    /// Records the docstring of the class being declared
+    pub(crate) fn set_class_doc(&mut self, doc: &str) {
+        let class = *self.classes.last().expect("Class expected");
+        self.table.classes[class].doc = Some(doc.to_string());
+    }
+
+    pub(crate) fn end_class(&mut self) {
+        self.classes.pop().expect("Class expected");
+    }
+
+    pub(crate) fn nesting(&self) -> Nesting {
+        Nesting {
+            functions: self.enclosing.len(),
+            depth: self.current.depth,
+            classes: self.classes.len(),
+        }
+    }
+
+    /// Goes back to the `nesting` after an error, the functions, scopes and classes begun since are dropped
+    pub(crate) fn unwind(&mut self, nesting: Nesting) {
+        while self.enclosing.len() > nesting.functions {
+            self.end_function();
+        }
+        // The local of a declaration that failed was not initialized
+        self.current.depth = nesting.depth;
+        while let Some(local) = self.current.locals.last() {
+            if local.depth.is_some_and(|depth| depth <= nesting.depth) {
+                break;
+            }
+            self.current.locals.pop();
+        }
+        self.classes.truncate(nesting.classes);
+    }
+
+    /// The globals used but declared neither in the source nor in `defined` (e.g. the natives), as errors at their
+    /// first use, see [crate::compiler::Compiler::set_strict]
+    pub(crate) fn undefined_globals(&self, defined: &[String]) -> Vec<ErrorKind> {
+        self.table
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.kind == SymbolKind::Global && symbol.declaration.is_none())
+            .filter(|symbol| {
+                !defined.contains(&symbol.name) && self.global_constant(&symbol.name).is_none()
+            })
+            .filter_map(|symbol| {
+                let span = symbol.references.first()?.span;
+                Some(ErrorKind::ResolutionError(format!(
+                    "[line: {}, column: {}] Error at <{}>: message: Undefined variable '{}'",
+                    span.line, span.column, symbol.name, symbol.name
+                )))
+            })
+            .collect()
+    }
+
+    pub(crate) fn is_global_scope(&self) -> bool {
+        self.current.depth == GLOBAL_SCOPE_DEPTH
+    }
+
+    pub(crate) fn begin_scope(&mut self) {
+        self.current.depth += 1;
+    }
+
+    /// Ends the current scope, returns its locals in the order they go out of scope (last declared first)
+    pub(crate) fn end_scope(&mut self) -> Vec<Local<'a>> {
+        self.current.depth -= 1;
+        let depth = self.current.depth;
+        let mut ended = Vec::new();
+        while let Some(local) = self.current.locals.last() {
+            if local.depth.expect("Expect depth") <= depth {
+                break;
+            }
+            ended.push(self.current.locals.pop().expect("local expected"));
+        }
+        ended
+    }
+
+    /// Declares the variable named by `token` in the current scope
+    pub(crate) fn declare(&mut self, token: &'a Token) -> Result<()> {
+        if self.is_global_scope() {
+            if self.global_constant(&token.lexeme).is_some() {
+                bail!(resolution_error(token, "Already a constant with this name"))
+            }
+            self.table.declare_global(token);
+            return Ok(());
+        }
+        let depth = self.current.depth;
+        let exists = self
+            .current
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d >= depth))
+            .any(|local| local.depth.is_some() && local.name == token.lexeme);
+        if exists {
+            bail!(resolution_error(
+                token,
+                "Already a variable with this name exists in this scope"
+            ))
+        }
+        let mut local = Local::new(&token.lexeme, None);
+        local.token = Some(token);
+        local.symbol = Some(
+            self.table
+                .add_symbol(token, SymbolKind::Local, self.current.function),
+        );
+        self.table.functions[self.current.function]
+            .locals
+            .extend(local.symbol);
+        self.current.locals.push(local);
+        Ok(())
+    }
+
+    /// Declares the local function named by `token` before its declaration is compiled, returns its slot. None if
+    /// it can't be declared (e.g. a parameter has the same name), the declaration reports the error.
+    /// The functions declared in the current function can use it, the current function can't until
+    /// [Resolver::define_hoisted]: it is nil until its declaration runs
+    pub(crate) fn hoist(&mut self, token: &'a Token) -> Option<ByteUnit> {
+        self.declare(token).ok()?;
+        self.mark_initialized();
+        let slot = self.current.locals.len() - 1;
+        self.current.locals[slot].is_hoisted = true;
+        Some(slot as ByteUnit)
+    }
+
+    /// The declaration of the function hoisted in `slot` is compiled
+    pub(crate) fn define_hoisted(&mut self, slot: ByteUnit) {
+        self.current.locals[slot as usize].is_hoisted = false;
+    }
+
+    /// Declares a local the code can't name (e.g. the iterator of a `for` loop), returns its slot
+    pub(crate) fn declare_hidden(&mut self, name: &'static str) -> ByteUnit {
+        self.current
+            .locals
+            .push(Local::new(name, Some(self.current.depth)));
+        (self.current.locals.len() - 1) as ByteUnit
+    }
+
+    /// The last declared local can be used from now on
+    pub(crate) fn mark_initialized(&mut self) {
+        if self.is_global_scope() {
+            return;
+        }
+        let depth = self.current.depth;
+        if let Some(local) = self.current.locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    /// The last declared local is a parameter. Unused parameters are not reported,
+    /// e.g. callbacks often ignore some of them
+    pub(crate) fn mark_parameter(&mut self) {
+        if let Some(local) = self.current.locals.last_mut() {
+            local.is_read = true;
+            if let Some(symbol) = local.symbol {
+                self.table.symbols[symbol].kind = SymbolKind::Parameter;
+            }
+            self.table.functions[self.current.function].arity += 1;
+        }
+    }
+
+    /// Resolves `name` and records the reference
+    pub(crate) fn resolve(&mut self, name: &Token) -> Result<Resolution> {
+        if let Some(index) = self.current.resolve_local(name)? {
+            if self.current.locals[index as usize].is_hoisted {
+                bail!(resolution_error(
+                    name,
+                    "Can't use a local function before its declaration"
+                ))
+            }
+            if let Some(symbol) = self.current.locals[index as usize].symbol {
+                self.table.symbols[symbol].references.push(Reference {
+                    span: name.span(),
+                    function: self.current.function,
+                });
+            }
+            return Ok(Resolution::Local(index));
+        }
+        if let Some((index, symbol)) = Resolver::resolve_upvalue(
+            &mut self.current,
+            &mut self.enclosing,
+            name,
+            &mut self.table,
+        )? {
+            if let Some(symbol) = symbol {
+                self.table.symbols[symbol].references.push(Reference {
+                    span: name.span(),
+                    function: self.current.function,
+                });
+            }
+            return Ok(Resolution::Upvalue(index));
+        }
+        self.table.reference_global(name, self.current.function);
+        match self.global_constant(&name.lexeme) {
+            Some(slot) => Ok(Resolution::GlobalConstant(slot)),
+            None => Ok(Resolution::Global),
+        }
+    }
+
+    fn resolve_upvalue(
+        current: &mut FunctionResolver<'a>,
+        enclosing: &mut [FunctionResolver<'a>],
+        name: &Token,
+        table: &mut ScopeTable,
+    ) -> Result<Option<(ByteUnit, Option<usize>)>> {
+        let (innermost, rest) = match enclosing.split_last_mut() {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        if let Some(index) = innermost.resolve_local(name)? {
+            let local = &mut innermost.locals[index as usize];
+            local.is_captured = true;
+            let symbol = local.symbol;
+            Ok(Some((
+                current.add_upvalue(index, true, symbol, table),
+                symbol,
+            )))
+        } else if let Some((index, symbol)) =
+            Resolver::resolve_upvalue(innermost, rest, name, table)?
+        {
+            Ok(Some((
+                current.add_upvalue(index, false, symbol, table),
+                symbol,
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Records the use of the property named by `token` (after a '.')
+    pub(crate) fn reference_property(&mut self, token: &Token) {
+        self.table.properties.push((
+            token.lexeme.clone(),
+            Reference {
+                span: token.span(),
+                function: self.current.function,
+            },
+        ));
+    }
+
+    /// Records that the local in `slot` is read or assigned, for the unused variable warnings
+    pub(crate) fn mark_used(&mut self, slot: ByteUnit, is_assignment: bool) {
+        let local = &mut self.current.locals[slot as usize];
+        if is_assignment {
+            local.is_assigned = true;
+        } else {
+            local.is_read = true;
+        }
+    }
+
+    pub(crate) fn into_table(self) -> ScopeTable {
+        self.table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Span, SymbolKind};
+    use evie_common::errors::*;
+    use evie_frontend::scanner::Scanner;
+
+    #[test]
+    fn scope_table() -> Result<()> {
+        let source = r#"
+var counter = 0;
+fun make(step) {
+    var total = counter;
+    fun add() {
+        total = total + step;
+        return total;
+    }
+    return add;
+}
+print clock;
+"#;
+        let mut scanner = Scanner::new(source.to_string());
+        let table = resolve(scanner.scan_tokens()?)?;
+        let names: Vec<&str> = table.functions().iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(vec!["script", "make", "add"], names);
+
+        let counter = table.global("counter").expect("counter is a global");
+        assert_eq!(
+            Some(Span {
+                line: 2,
+                column: 5,
+                length: 7
+            }),
+            counter.declaration
+        );
+        assert_eq!(1, counter.references.len());
+        assert_eq!(
+            None,
+            table.global("clock").expect("clock is used").declaration
+        );
+
+        let make = &table.functions()[1];
+        let locals: Vec<(&str, SymbolKind)> = make
+            .locals
+            .iter()
+            .map(|&i| (table.symbols()[i].name.as_str(), table.symbols()[i].kind))
+            .collect();
+        assert_eq!(
+            vec![
+                ("step", SymbolKind::Parameter),
+                ("total", SymbolKind::Local),
+                ("add", SymbolKind::Local)
+            ],
+            locals
+        );
+        assert_eq!(1, make.globals.len());
+
+        let add = &table.functions()[2];
+        let upvalues: Vec<&str> = add
+            .upvalues
+            .iter()
+            .map(|&i| table.symbols()[i].name.as_str())
+            .collect();
+        assert_eq!(vec!["total", "step"], upvalues);
+
+        // `total` in `return total;` resolves to the local declared in `make`
+        let total = table.symbol_at(7, 17).expect("total is referenced");
+        assert_eq!("total", total.name);
+        assert_eq!(1, total.function);
+        assert_eq!(4, total.declaration.expect("declared").line);
+        assert_eq!(3, total.references.len());
+        Ok(())
+    }
+
+    #[test]
+    fn functions_and_classes() -> Result<()> {
+        let source = r#"
+class Point {
+    init(x, y) { this.x = x; }
+    static origin() { return Point(0, 0); }
+}
+fun outer(a) {
+    return fun() { return a; };
+}
+"#;
+        let mut scanner = Scanner::new(source.to_string());
+        let table = resolve(scanner.scan_tokens()?)?;
+        let point = table.global("Point").expect("Point is declared");
+        let class = table
+            .class_declared_at(point.declaration.expect("declared"))
+            .expect("Point is a class");
+        let methods: Vec<String> = class
+            .methods
+            .iter()
+            .map(|&m| table.signature(&table.functions()[m]))
+            .collect();
+        assert_eq!(vec!["init(x, y)", "origin()"], methods);
+
+        let outer = table.global("outer").expect("outer is declared");
+        let function = table
+            .function_declared_at(outer.declaration.expect("declared"))
+            .expect("outer is a function");
+        assert_eq!((1, None), (function.arity, function.class));
+        assert_eq!("outer(a)", table.signature(function));
+
+        // `a` is captured by the anonymous function
+        let a = table.symbol_at(7, 27).expect("a is referenced");
+        assert_eq!(SymbolKind::Parameter, a.kind);
+        let anonymous = a.references[0].function;
+        assert_ne!(a.function, anonymous);
+        assert_eq!("anonymous", table.functions()[anonymous].name);
+        assert_eq!(None, table.functions()[anonymous].declaration);
+        Ok(())
+    }
+
+    #[test]
+    fn members() -> Result<()> {
+        let source = r#"
+class A { move() {} }
+class B { move(by) { this.x = by; } }
+fun f(a) { a.move(); return a.x; }
+"#;
+        let mut scanner = Scanner::new(source.to_string());
+        let table = resolve(scanner.scan_tokens()?)?;
+        // `move` in `a.move()`
+        assert_eq!(Some("move"), table.member_at(4, 14));
+        // The declaration in B
+        assert_eq!(Some("move"), table.member_at(3, 11));
+        assert_eq!(None, table.member_at(4, 7));
+        let methods: Vec<usize> = table
+            .methods_named("move")
+            .map(|m| m.declaration.expect("declared").line)
+            .collect();
+        assert_eq!(vec![2, 3], methods);
+        let x: Vec<(usize, &str)> = table
+            .property_references("x")
+            .map(|r| (r.span.line, table.functions()[r.function].name.as_str()))
+            .collect();
+        assert_eq!(vec![(3, "move"), (4, "f")], x);
+        Ok(())
+    }
+
+    #[test]
+    fn resolution_errors() -> Result<()> {
+        let mut scanner = Scanner::new("{ var a = 1; var a = 2; }".to_string());
+        let error = resolve(scanner.scan_tokens()?).unwrap_err();
+        assert_eq!(
+            "Resolution Error: [line: 1, column: 18] Error at <a>: message: Already a variable with this name exists in this scope",
+            error.to_string()
+        );
+        let mut scanner = Scanner::new("{ var a = a; }".to_string());
+        let error = resolve(scanner.scan_tokens()?).unwrap_err();
+        assert_eq!(
+            "Resolution Error: [line: 1, column: 11] Error at <a>: message: Can't read local variable in its own initializer",
+            error.to_string()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+/* libevie: embeds evie in C hosts, see evie_ffi/src/lib.rs. Link with -levie_ffi */
+#ifndef EVIE_H
+#define EVIE_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct EvieVm EvieVm;
+
+typedef enum {
+    EVIE_OK = 0,
+    EVIE_COMPILE_ERROR = 1,
+    EVIE_RUNTIME_ERROR = 2,
+    /* A null pointer, a string that is not UTF-8, too many natives or a call from a native of the running VM */
+    EVIE_INVALID_ARGUMENT = 3,
+    /* The VM or a native panicked, see evie_error. The VM can only be freed */
+    EVIE_PANIC = 4,
+} EvieStatus;
+
+typedef enum {
+    EVIE_NIL,
+    EVIE_BOOL,
+    EVIE_NUMBER,
+    EVIE_STRING,
+    /* Any other value, passed as its string form */
+    EVIE_OBJECT,
+} EvieType;
+
+/* Only the field of the type is set. The strings of the arguments are valid during the call,
+   the string of the result is copied when the native returns */
+typedef struct {
+    EvieType kind;
+    bool boolean;
+    double number;
+    const char *string;
+} EvieValue;
+
+/* Sets the result and returns true, or returns false to fail the script (with the result as the message if it is a string) */
+typedef bool (*EvieNative)(void *userdata, const EvieValue *args, size_t arg_count, EvieValue *result);
+
+/* A VM is not re-entrant: its natives can call evie_output and evie_error, but evie_interpret and
+   evie_register_native fail with EVIE_INVALID_ARGUMENT while it runs and evie_vm_free must not be called */
+EvieVm *evie_vm_new(void);
+void evie_vm_free(EvieVm *vm);
+/* At most 32 natives per VM, a native of the same name is replaced */
+EvieStatus evie_register_native(EvieVm *vm, const char *name, size_t arity, EvieNative callback, void *userdata);
+EvieStatus evie_interpret(EvieVm *vm, const char *source);
+/* Valid until the next evie_interpret or evie_vm_free */
+const char *evie_output(const EvieVm *vm);
+/* NULL if the last evie_interpret did not fail */
+const char *evie_error(const EvieVm *vm);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
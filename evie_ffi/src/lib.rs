@@ -0,0 +1,609 @@
+//! libevie: a C ABI to embed evie in hosts that are not written in Rust (C, Python via ctypes...), see `evie.h`.
+//!
+//! ```text
+//! EvieVm *vm = evie_vm_new();
+//! evie_register_native(vm, "twice", 1, twice, NULL);
+//! if (evie_interpret(vm, "print twice(21);") != EVIE_OK) {
+//!     fputs(evie_error(vm), stderr);
+//! }
+//! fputs(evie_output(vm), stdout); // 42
+//! evie_vm_free(vm);
+//! ```
+//! ```text
+//! evie = ctypes.CDLL("target/release/libevie_ffi.so")
+//! evie.evie_vm_new.restype = ctypes.c_void_p
+//! evie.evie_output.restype = ctypes.c_char_p
+//! vm = ctypes.c_void_p(evie.evie_vm_new())
+//! evie.evie_interpret(vm, b"print 1 + 2;")
+//! print(evie.evie_output(vm).decode()) # 3
+//! evie.evie_vm_free(vm)
+//! ```
+//! A VM has the default natives ([evie_native::all_natives]) and up to [MAX_NATIVES] natives of the host, each one
+//! a C function and a userdata pointer that is passed back to it. The values exchanged with the host are
+//! [EvieValue]s: nil, booleans, numbers and strings (any other value is passed as its string form).
+//!
+//! The strings returned by [evie_output] and [evie_error] belong to the VM, they are valid until the next
+//! [evie_interpret] or [evie_vm_free]. A VM must only be used by one thread at a time, and it is not re-entrant:
+//! while it runs, its natives can read [evie_output] and [evie_error] but [evie_interpret] and
+//! [evie_register_native] fail with [EvieStatus::InvalidArgument] (and [evie_vm_free] must not be called).
+//!
+//! A panic of the VM or of a native does not unwind into the host: the call fails with [EvieStatus::Panic] and
+//! the VM can only be freed after.
+use std::{
+    any::Any,
+    cell::Cell,
+    ffi::{c_char, c_void, CStr, CString},
+    io::Write,
+    mem::ManuallyDrop,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{Arc, Mutex},
+};
+
+use evie_common::{bail, errors::*, print_error};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{
+    objects::{NativeFn, ObjectType},
+    runtime::EvieRuntime,
+};
+use evie_vm::vm::{define_native_fn, VirtualMachine};
+
+/// The natives of the host a VM can have, see [evie_register_native]
+pub const MAX_NATIVES: usize = 32;
+
+/// The type of an [EvieValue]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvieType {
+    Nil,
+    Bool,
+    Number,
+    String,
+    /// Any other value (an instance, a function...), only passed to the host as its string form
+    Object,
+}
+
+/// A value exchanged with the host, only the field of its type is set
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct EvieValue {
+    pub kind: EvieType,
+    pub boolean: bool,
+    pub number: f64,
+    /// NUL terminated, for strings and objects. The strings of the arguments are valid during the call,
+    /// the string of the result is copied when the native returns
+    pub string: *const c_char,
+}
+
+impl EvieValue {
+    fn nil() -> Self {
+        EvieValue {
+            kind: EvieType::Nil,
+            boolean: false,
+            number: 0.0,
+            string: ptr::null(),
+        }
+    }
+}
+
+/// A native of the host: it sets the result and returns true, or returns false to fail the script
+/// (with the result as the message if it is a string)
+pub type EvieNative = extern "C" fn(
+    userdata: *mut c_void,
+    args: *const EvieValue,
+    arg_count: usize,
+    result: *mut EvieValue,
+) -> bool;
+
+/// How a call of the C ABI ended
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvieStatus {
+    Ok = 0,
+    /// The source does not compile, see [evie_error]
+    CompileError = 1,
+    /// The script failed, see [evie_error]
+    RuntimeError = 2,
+    /// A null pointer, a string that is not UTF-8, too many natives or a call from a native of the running VM
+    InvalidArgument = 3,
+    /// The VM or a native panicked, see [evie_error]. The VM can only be freed
+    Panic = 4,
+}
+
+struct Native {
+    name: String,
+    callback: EvieNative,
+    userdata: *mut c_void,
+}
+
+/// What the scripts of a VM print, shared by the VM (as its writer) and [EvieVm]
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .expect("Poisoned output")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A VM of the host, created by [evie_vm_new] and freed by [evie_vm_free]
+pub struct EvieVm {
+    vm: ManuallyDrop<VirtualMachine<'static>>,
+    /// The writer the VM borrows, it is freed after the VM
+    writer: *mut Output,
+    output: Output,
+    last_output: CString,
+    last_error: Option<CString>,
+    natives: Vec<Native>,
+    /// Set while [evie_interpret] runs, to reject the calls of its natives that would change the VM
+    running: bool,
+    /// Set once the VM panicked, it may be left in any state
+    panicked: bool,
+}
+
+impl Drop for EvieVm {
+    fn drop(&mut self) {
+        // Safety: the VM is dropped before the writer it borrows, and neither is used after
+        unsafe {
+            ManuallyDrop::drop(&mut self.vm);
+            drop(Box::from_raw(self.writer));
+        }
+    }
+}
+
+thread_local! {
+    /// The natives of the VM that runs on this thread, set by [evie_interpret] for [trampoline]
+    static RUNNING: Cell<*const Vec<Native>> = const { Cell::new(ptr::null()) };
+}
+
+/// A new VM with the default natives, free it with [evie_vm_free]. Null if it panicked
+#[no_mangle]
+pub extern "C" fn evie_vm_new() -> *mut EvieVm {
+    panic::catch_unwind(new_vm).unwrap_or(ptr::null_mut())
+}
+
+fn new_vm() -> *mut EvieVm {
+    let output = Output::default();
+    let writer = Box::into_raw(Box::new(output.clone()));
+    // Safety: the writer outlives the VM, see the Drop of EvieVm
+    let mut vm = VirtualMachine::new_with_writer(Some(unsafe { &mut *writer }));
+    for (name, arity, native_fn) in evie_native::all_natives() {
+        define_native_fn(name, arity, &mut vm, native_fn);
+    }
+    Box::into_raw(Box::new(EvieVm {
+        vm: ManuallyDrop::new(vm),
+        writer,
+        output,
+        last_output: CString::default(),
+        last_error: None,
+        natives: Vec::new(),
+        running: false,
+        panicked: false,
+    }))
+}
+
+/// Frees the VM, null is ignored
+///
+/// # Safety
+/// `vm` is null or a VM of [evie_vm_new] that is not running (see the [module docs](self)) and is not used after
+#[no_mangle]
+pub unsafe extern "C" fn evie_vm_free(vm: *mut EvieVm) {
+    if !vm.is_null() {
+        // The VM is gone either way, a panic of its drop only leaks what was not freed yet
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(vm))));
+    }
+}
+
+/// Defines the global native `name`, that calls `callback` with `userdata`. A native of the same name is replaced.
+/// Fails with [EvieStatus::InvalidArgument] while the VM runs
+///
+/// # Safety
+/// `vm` is a VM of [evie_vm_new] and `name` is a NUL terminated string. `userdata` is valid for as long as the
+/// native can be called. The natives of `vm` can call this, the call is rejected while `vm` runs: it is the only
+/// way into a running VM besides [evie_interpret], [evie_output] and [evie_error]
+#[no_mangle]
+pub unsafe extern "C" fn evie_register_native(
+    vm: *mut EvieVm,
+    name: *const c_char,
+    arity: usize,
+    callback: Option<EvieNative>,
+    userdata: *mut c_void,
+) -> EvieStatus {
+    if let Some(status) = check_idle(vm) {
+        return status;
+    }
+    let (Some(evie), Some(callback), Some(name)) = (vm.as_mut(), callback, to_str(name)) else {
+        return EvieStatus::InvalidArgument;
+    };
+    match panic::catch_unwind(AssertUnwindSafe(|| {
+        register_native(evie, name, arity, callback, userdata)
+    })) {
+        Ok(status) => status,
+        Err(payload) => panicked(evie, payload),
+    }
+}
+
+fn register_native(
+    evie: &mut EvieVm,
+    name: &str,
+    arity: usize,
+    callback: EvieNative,
+    userdata: *mut c_void,
+) -> EvieStatus {
+    let native = Native {
+        name: name.to_string(),
+        callback,
+        userdata,
+    };
+    let slot = match evie.natives.iter().position(|n| n.name == name) {
+        Some(slot) => {
+            evie.natives[slot] = native;
+            slot
+        }
+        None if evie.natives.len() < MAX_NATIVES => {
+            evie.natives.push(native);
+            evie.natives.len() - 1
+        }
+        None => return EvieStatus::InvalidArgument,
+    };
+    define_native_fn(name, arity, &mut evie.vm, TRAMPOLINES[slot]);
+    EvieStatus::Ok
+}
+
+/// Runs the source, its output is in [evie_output] and its error (if any) in [evie_error].
+/// The globals of a script are visible to the scripts that run after it in the same VM.
+/// Fails with [EvieStatus::InvalidArgument] while the VM runs, i.e. when called by one of its natives
+///
+/// # Safety
+/// `vm` is a VM of [evie_vm_new] and `source` is a NUL terminated string. The natives of `vm` can call this, the
+/// call is rejected while `vm` runs, but must not call [evie_vm_free] on it
+#[no_mangle]
+pub unsafe extern "C" fn evie_interpret(vm: *mut EvieVm, source: *const c_char) -> EvieStatus {
+    if let Some(status) = check_idle(vm) {
+        return status;
+    }
+    let Some(source) = to_str(source) else {
+        return EvieStatus::InvalidArgument;
+    };
+    // Only the fields the run uses are borrowed, the natives may read the others (see evie_output)
+    (*vm).running = true;
+    let previous = RUNNING.with(|running| running.replace(ptr::addr_of!((*vm).natives)));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        (*vm).vm.interpret(source.to_string(), None)
+    }));
+    RUNNING.with(|running| running.set(previous));
+    (*vm).running = false;
+    let evie = &mut *vm;
+    let result = match result {
+        Ok(result) => result,
+        Err(payload) => return panicked(evie, payload),
+    };
+    let output = std::mem::take(&mut *evie.output.0.lock().expect("Poisoned output"));
+    evie.last_output = to_c_string(output);
+    evie.last_error = None;
+    let Err(e) = result else {
+        return EvieStatus::Ok;
+    };
+    let status = match e.kind() {
+        ErrorKind::ScanError(_)
+        | ErrorKind::ParseError(_)
+        | ErrorKind::ResolutionError(_)
+        | ErrorKind::CompileErrors(_) => EvieStatus::CompileError,
+        _ => EvieStatus::RuntimeError,
+    };
+    let mut message = vec![];
+    print_error(e, &mut message);
+    evie.last_error = Some(to_c_string(message));
+    status
+}
+
+/// What the last [evie_interpret] printed, null if `vm` is null
+///
+/// # Safety
+/// `vm` is null or a VM of [evie_vm_new]
+#[no_mangle]
+pub unsafe extern "C" fn evie_output(vm: *const EvieVm) -> *const c_char {
+    // Only the field is borrowed, the VM may be running (this is called by one of its natives)
+    if vm.is_null() {
+        return ptr::null();
+    }
+    (*vm).last_output.as_ptr()
+}
+
+/// The error of the last [evie_interpret] (or the panic of the VM), null if it did not fail (or `vm` is null)
+///
+/// # Safety
+/// `vm` is null or a VM of [evie_vm_new]
+#[no_mangle]
+pub unsafe extern "C" fn evie_error(vm: *const EvieVm) -> *const c_char {
+    if vm.is_null() {
+        return ptr::null();
+    }
+    (*vm)
+        .last_error
+        .as_ref()
+        .map_or(ptr::null(), |error| error.as_ptr())
+}
+
+/// The status to fail with if `vm` is null, running or panicked, without borrowing it: it may be borrowed by the
+/// [evie_interpret] that runs it
+unsafe fn check_idle(vm: *mut EvieVm) -> Option<EvieStatus> {
+    if vm.is_null() || (*vm).running {
+        Some(EvieStatus::InvalidArgument)
+    } else if (*vm).panicked {
+        Some(EvieStatus::Panic)
+    } else {
+        None
+    }
+}
+
+/// Records the panic as the error of the VM
+fn panicked(evie: &mut EvieVm, payload: Box<dyn Any + Send>) -> EvieStatus {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Unknown panic".to_string());
+    evie.panicked = true;
+    evie.last_error = Some(to_c_string(format!("[Panic] {}", message).into_bytes()));
+    EvieStatus::Panic
+}
+
+macro_rules! trampolines {
+    ($($slot:literal)*) => {
+        [$(trampoline::<$slot> as NativeFn),*]
+    };
+}
+
+/// A [NativeFn] per slot of [EvieVm::natives]
+const TRAMPOLINES: [NativeFn; MAX_NATIVES] = trampolines!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+);
+
+/// Calls the native in the slot of the running VM: a [NativeFn] has no state, so the slot is its only context
+fn trampoline<const SLOT: usize>(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    // Safety: set by evie_interpret to the natives of the VM, for as long as it runs
+    match unsafe { RUNNING.with(Cell::get).as_ref() }.and_then(|natives| natives.get(SLOT)) {
+        Some(native) => call(native, inputs, runtime),
+        None => bail!("A native of the host can only be called while its VM runs"),
+    }
+}
+
+fn call(native: &Native, inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let mut strings = Vec::new();
+    let args: Vec<EvieValue> = inputs
+        .iter()
+        .map(|value| to_evie_value(*value, &mut strings))
+        .collect();
+    let mut result = EvieValue::nil();
+    let succeeded = (native.callback)(native.userdata, args.as_ptr(), args.len(), &mut result);
+    // Safety: the host sets a NUL terminated string (or null)
+    let string = unsafe { result.string.as_ref() }.map(|string| {
+        unsafe { CStr::from_ptr(string) }
+            .to_string_lossy()
+            .into_owned()
+    });
+    if !succeeded {
+        match string {
+            Some(message) if result.kind == EvieType::String => bail!(message),
+            _ => bail!(format!("<native {}> failed", native.name)),
+        }
+    }
+    Ok(match (result.kind, string) {
+        (EvieType::Nil, _) => Value::nil(),
+        (EvieType::Bool, _) => Value::bool(result.boolean),
+        (EvieType::Number, _) => Value::number(result.number),
+        (EvieType::String, Some(string)) => runtime.alloc_string(string),
+        _ => bail!(format!(
+            "Expected nil, a boolean, a number or a string from <native {}>",
+            native.name
+        )),
+    })
+}
+
+/// The value for the host, the strings are kept alive in `strings`
+fn to_evie_value(value: Value, strings: &mut Vec<CString>) -> EvieValue {
+    let mut string = |kind, text: String| {
+        strings.push(to_c_string(text.into_bytes()));
+        EvieValue {
+            kind,
+            string: strings.last().expect("Just pushed").as_ptr(),
+            ..EvieValue::nil()
+        }
+    };
+    if value.is_nil() {
+        EvieValue::nil()
+    } else if value.is_bool() {
+        EvieValue {
+            kind: EvieType::Bool,
+            boolean: value.as_bool(),
+            ..EvieValue::nil()
+        }
+    } else if value.is_number() {
+        EvieValue {
+            kind: EvieType::Number,
+            number: value.as_number(),
+            ..EvieValue::nil()
+        }
+    } else {
+        match value.as_object().object_type {
+            ObjectType::String(_) | ObjectType::Rope(_) => {
+                string(EvieType::String, value.to_string())
+            }
+            _ => string(EvieType::Object, value.to_string()),
+        }
+    }
+}
+
+/// None for null and strings that are not UTF-8
+unsafe fn to_str<'a>(string: *const c_char) -> Option<&'a str> {
+    if string.is_null() {
+        return None;
+    }
+    CStr::from_ptr(string).to_str().ok()
+}
+
+/// The bytes as a C string, without the NULs
+fn to_c_string(mut bytes: Vec<u8>) -> CString {
+    bytes.retain(|b| *b != 0);
+    CString::new(bytes).expect("The NULs are removed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{c_void, CStr, CString};
+
+    use super::*;
+
+    extern "C" fn twice(
+        userdata: *mut c_void,
+        args: *const EvieValue,
+        arg_count: usize,
+        result: *mut EvieValue,
+    ) -> bool {
+        // Safety: the counter of the test and the arguments of the call
+        let (calls, args, result) = unsafe {
+            (
+                &mut *(userdata as *mut u32),
+                std::slice::from_raw_parts(args, arg_count),
+                &mut *result,
+            )
+        };
+        *calls += 1;
+        match args[0].kind {
+            EvieType::Number => {
+                result.kind = EvieType::Number;
+                result.number = args[0].number * 2.0;
+                true
+            }
+            _ => {
+                result.kind = EvieType::String;
+                result.string = c"Expected a number".as_ptr();
+                false
+            }
+        }
+    }
+
+    /// Calls the VM (the userdata) back, which it rejects while it runs
+    extern "C" fn reenter(
+        userdata: *mut c_void,
+        _: *const EvieValue,
+        _: usize,
+        result: *mut EvieValue,
+    ) -> bool {
+        let vm = userdata as *mut EvieVm;
+        // Safety: the VM of the test and the result of the call
+        unsafe {
+            let source = c"print 1;";
+            let interpreted = evie_interpret(vm, source.as_ptr());
+            let registered =
+                evie_register_native(vm, c"other".as_ptr(), 0, Some(reenter), userdata);
+            (*result).kind = EvieType::Bool;
+            (*result).boolean = interpreted == EvieStatus::InvalidArgument
+                && registered == EvieStatus::InvalidArgument
+                && !evie_output(vm).is_null();
+        }
+        true
+    }
+
+    fn boom(_: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+        panic!("boom")
+    }
+
+    fn text(string: *const c_char) -> Option<String> {
+        // Safety: the strings of the VM
+        unsafe { string.as_ref() }
+            .map(|s| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn embeds_from_c() {
+        let mut calls = 0u32;
+        unsafe {
+            let vm = evie_vm_new();
+            let name = CString::new("twice").unwrap();
+            let userdata = &mut calls as *mut u32 as *mut c_void;
+            assert_eq!(
+                EvieStatus::Ok,
+                evie_register_native(vm, name.as_ptr(), 1, Some(twice), userdata)
+            );
+            let source = CString::new("var a = twice(21); print a; print type(clock());").unwrap();
+            assert_eq!(EvieStatus::Ok, evie_interpret(vm, source.as_ptr()));
+            assert_eq!(Some("42\nnumber\n".to_string()), text(evie_output(vm)));
+            assert_eq!(None, text(evie_error(vm)));
+            // The globals persist
+            let source = CString::new("print a; print twice(\"a\");").unwrap();
+            assert_eq!(
+                EvieStatus::RuntimeError,
+                evie_interpret(vm, source.as_ptr())
+            );
+            assert_eq!(Some("42\n".to_string()), text(evie_output(vm)));
+            let error = text(evie_error(vm)).unwrap();
+            assert!(error.contains("Expected a number"), "{}", error);
+            let source = CString::new("print ;").unwrap();
+            assert_eq!(
+                EvieStatus::CompileError,
+                evie_interpret(vm, source.as_ptr())
+            );
+            assert_eq!(
+                EvieStatus::InvalidArgument,
+                evie_interpret(vm, std::ptr::null())
+            );
+            assert_eq!(
+                EvieStatus::InvalidArgument,
+                evie_register_native(vm, name.as_ptr(), 1, None, userdata)
+            );
+            evie_vm_free(vm);
+        }
+        assert_eq!(2, calls);
+    }
+
+    #[test]
+    fn rejects_calls_from_its_natives() {
+        unsafe {
+            let vm = evie_vm_new();
+            let name = CString::new("reenter").unwrap();
+            assert_eq!(
+                EvieStatus::Ok,
+                evie_register_native(vm, name.as_ptr(), 0, Some(reenter), vm as *mut c_void)
+            );
+            let source = CString::new("print reenter();").unwrap();
+            assert_eq!(EvieStatus::Ok, evie_interpret(vm, source.as_ptr()));
+            assert_eq!(Some("true\n".to_string()), text(evie_output(vm)));
+            // It is not running anymore
+            assert_eq!(EvieStatus::Ok, evie_interpret(vm, source.as_ptr()));
+            evie_vm_free(vm);
+        }
+    }
+
+    #[test]
+    fn catches_panics() {
+        unsafe {
+            let vm = evie_vm_new();
+            define_native_fn("boom", 0, &mut (*vm).vm, boom);
+            let source = CString::new("print 1; boom();").unwrap();
+            assert_eq!(EvieStatus::Panic, evie_interpret(vm, source.as_ptr()));
+            assert_eq!(Some("[Panic] boom".to_string()), text(evie_error(vm)));
+            // The VM can only be freed
+            let source = CString::new("print 1;").unwrap();
+            assert_eq!(EvieStatus::Panic, evie_interpret(vm, source.as_ptr()));
+            let name = CString::new("twice").unwrap();
+            assert_eq!(
+                EvieStatus::Panic,
+                evie_register_native(vm, name.as_ptr(), 1, Some(twice), ptr::null_mut())
+            );
+            evie_vm_free(vm);
+        }
+    }
+}
@@ -0,0 +1,870 @@
+//! An optional abstract syntax tree (AST) for tooling, e.g. formatters and analyzers.
+//!
+//! The VM does not use it, the compiler goes straight from [Token]s to byte code.
+//! [parse_to_ast] accepts the same grammar as the compiler, with the same nesting limit ([MAX_NESTING_DEPTH]). Every
+//! node has the [Span] of its main token (the name, the operator or the keyword).
+use evie_common::errors::*;
+
+use crate::tokens::{Literal, Span, Token, TokenType};
+
+fn parse_error(token: &Token, message: &str) -> ErrorKind {
+    ErrorKind::ParseError(format!(
+        "[line: {}, column: {}] Error at <{}>: message: {}",
+        token.line, token.column, token.lexeme, message
+    ))
+}
+
+/// How deeply statements, functions and expressions can nest, deeper sources are a parse error rather than a stack
+/// overflow. The compiler has the same limit by default
+pub const MAX_NESTING_DEPTH: usize = 256;
+
+/// Parses the tokens (from [crate::scanner::Scanner]) into statements
+pub fn parse_to_ast(tokens: &[Token]) -> Result<Vec<Stmt>> {
+    Parser::new(tokens).parse()
+}
+
+/// The precedence of a binary (or logical) operator, the lowest is 0
+fn binary_precedence(token_type: TokenType) -> Option<usize> {
+    let precedence = match token_type {
+        TokenType::Or => 0,
+        TokenType::And => 1,
+        TokenType::BangEqual | TokenType::EqualEqual => 2,
+        TokenType::Greater
+        | TokenType::GreaterEqual
+        | TokenType::Less
+        | TokenType::LessEqual
+        | TokenType::Is => 3,
+        TokenType::DotDot | TokenType::DotDotEqual => 4,
+        TokenType::Pipe => 5,
+        TokenType::Caret => 6,
+        TokenType::Ampersand => 7,
+        TokenType::LessLess | TokenType::GreaterGreater => 8,
+        TokenType::Minus | TokenType::Plus => 9,
+        TokenType::Slash | TokenType::Star => 10,
+        _ => return None,
+    };
+    Some(precedence)
+}
+
+/// A name with its span, e.g. a variable or a parameter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identifier {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LiteralValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+/// A named function, a method or an anonymous function (without a name)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: Option<Identifier>,
+    pub parameters: Vec<Identifier>,
+    pub body: Vec<Stmt>,
+    /// The span of the `fun` keyword, or of the name for methods
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Method {
+    pub is_static: bool,
+    pub function: Function,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal {
+        value: LiteralValue,
+        span: Span,
+    },
+    Variable(Identifier),
+    This {
+        span: Span,
+    },
+    Assign {
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    Unary {
+        operator: TokenType,
+        operand: Box<Expr>,
+        span: Span,
+    },
+    /// Arithmetic, comparison (including `is`) and equality
+    Binary {
+        left: Box<Expr>,
+        operator: TokenType,
+        right: Box<Expr>,
+        span: Span,
+    },
+    /// `and` and `or`
+    Logical {
+        left: Box<Expr>,
+        operator: TokenType,
+        right: Box<Expr>,
+        span: Span,
+    },
+    Conditional {
+        condition: Box<Expr>,
+        then_branch: Box<Expr>,
+        else_branch: Box<Expr>,
+        span: Span,
+    },
+    Grouping {
+        expression: Box<Expr>,
+        span: Span,
+    },
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+        span: Span,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Identifier,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    Function(Box<Function>),
+    /// `yield` or `yield value`
+    Yield {
+        value: Option<Box<Expr>>,
+        span: Span,
+    },
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Literal { span, .. }
+            | Expr::This { span }
+            | Expr::Unary { span, .. }
+            | Expr::Binary { span, .. }
+            | Expr::Logical { span, .. }
+            | Expr::Conditional { span, .. }
+            | Expr::Grouping { span, .. }
+            | Expr::Call { span, .. }
+            | Expr::Yield { span, .. } => *span,
+            Expr::Variable(name)
+            | Expr::Assign { name, .. }
+            | Expr::Get { name, .. }
+            | Expr::Set { name, .. } => name.span,
+            Expr::Function(function) => function.span,
+        }
+    }
+
+    /// The expression a chain (e.g. `1 + 2 + 3` or `a.b().c`) continues, the left operand of a binary or logical
+    /// expression, the callee of a call or the object of a property. A chain is not nested (see
+    /// [MAX_NESTING_DEPTH]), it can be walked in a loop with this rather than recursively
+    pub fn chained(&self) -> Option<&Expr> {
+        match self {
+            Expr::Binary { left, .. }
+            | Expr::Logical { left, .. }
+            | Expr::Call { callee: left, .. }
+            | Expr::Get { object: left, .. }
+            | Expr::Set { object: left, .. } => Some(left),
+            _ => None,
+        }
+    }
+
+    /// Takes the expression the chain continues (see [Expr::chained]), leaving `nil` in its place
+    fn take_chained(&mut self) -> Option<Expr> {
+        match self {
+            Expr::Binary { left, .. }
+            | Expr::Logical { left, .. }
+            | Expr::Call { callee: left, .. }
+            | Expr::Get { object: left, .. }
+            | Expr::Set { object: left, .. } => {
+                let span = left.span();
+                Some(std::mem::replace(&mut **left, Expr::nil(span)))
+            }
+            _ => None,
+        }
+    }
+
+    fn nil(span: Span) -> Expr {
+        Expr::Literal {
+            value: LiteralValue::Nil,
+            span,
+        }
+    }
+}
+
+/// A chain is dropped in a loop, a long one would overflow the stack if it was dropped recursively
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut chained = self.take_chained();
+        while let Some(mut expression) = chained {
+            chained = expression.take_chained();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Expression(Expr),
+    Print {
+        expression: Expr,
+        span: Span,
+    },
+    Var {
+        name: Identifier,
+        initializer: Option<Expr>,
+    },
+    /// `const name = initializer;`
+    Const {
+        name: Identifier,
+        initializer: Expr,
+    },
+    Block {
+        statements: Vec<Stmt>,
+        span: Span,
+    },
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+        span: Span,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+        span: Span,
+    },
+    /// `for (variable in iterable) body`
+    For {
+        variable: Identifier,
+        iterable: Expr,
+        body: Box<Stmt>,
+        span: Span,
+    },
+    Return {
+        value: Option<Expr>,
+        span: Span,
+    },
+    Function(Function),
+    Class {
+        name: Identifier,
+        /// The string literal at the start of the class body (if any)
+        doc: Option<String>,
+        methods: Vec<Method>,
+    },
+}
+
+impl Stmt {
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Expression(expression) => expression.span(),
+            Stmt::Print { span, .. }
+            | Stmt::Block { span, .. }
+            | Stmt::If { span, .. }
+            | Stmt::While { span, .. }
+            | Stmt::For { span, .. }
+            | Stmt::Return { span, .. } => *span,
+            Stmt::Var { name, .. } | Stmt::Const { name, .. } | Stmt::Class { name, .. } => {
+                name.span
+            }
+            Stmt::Function(function) => function.span,
+        }
+    }
+}
+
+/// A recursive descent parser producing the AST
+struct Parser<'a> {
+    tokens: &'a [Token],
+    current: usize,
+    /// The nesting of the statement, function or expression being parsed, see [MAX_NESTING_DEPTH]
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(tokens: &'a [Token]) -> Self {
+        Parser {
+            tokens,
+            current: 0,
+            depth: 0,
+        }
+    }
+
+    /// Runs `parse` one level deeper, see [MAX_NESTING_DEPTH]
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            bail!(parse_error(
+                self.peek(),
+                &format!(
+                    "Too deeply nested, the limit is {} levels",
+                    MAX_NESTING_DEPTH
+                )
+            ))
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn parse(mut self) -> Result<Vec<Stmt>> {
+        let mut statements = Vec::new();
+        while !self.is_at_end() {
+            statements.push(self.declaration()?);
+        }
+        Ok(statements)
+    }
+
+    fn declaration(&mut self) -> Result<Stmt> {
+        let statement = if self.match_token(TokenType::Class) {
+            self.class_declaration()?
+        } else if self.check(TokenType::Fun) && self.check_next(TokenType::Identifier) {
+            // `fun (` is an anonymous function (expression)
+            let keyword = self.advance().span();
+            let name = self.identifier("Expect function name")?;
+            Stmt::Function(self.function(Some(name), keyword)?)
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()?
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration()?
+        } else {
+            self.statement()?
+        };
+        // Like the compiler, a stray ';' after a declaration is allowed
+        self.match_token(TokenType::Semicolon);
+        Ok(statement)
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt> {
+        let name = self.identifier("Expect class name")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body")?;
+        let doc = match &self.peek().literal {
+            Some(Literal::String(doc)) if self.check(TokenType::String) => {
+                self.advance();
+                self.match_token(TokenType::Semicolon);
+                Some(doc.clone())
+            }
+            _ => None,
+        };
+        let mut methods = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            let is_static = self.match_token(TokenType::Static);
+            let method_name = self.identifier(if is_static {
+                "Expect static method name"
+            } else {
+                "Expect method name"
+            })?;
+            let span = method_name.span;
+            let function = self.function(Some(method_name), span)?;
+            methods.push(Method {
+                is_static,
+                function,
+            });
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body")?;
+        Ok(Stmt::Class { name, doc, methods })
+    }
+
+    fn function(&mut self, name: Option<Identifier>, span: Span) -> Result<Function> {
+        self.nested(|p| p.function_unchecked(name, span))
+    }
+
+    fn function_unchecked(&mut self, name: Option<Identifier>, span: Span) -> Result<Function> {
+        self.consume(TokenType::LeftParen, "Expect '(' after function name")?;
+        let mut parameters = Vec::new();
+        while !self.check(TokenType::RightParen) {
+            parameters.push(self.identifier("Expect parameter name")?);
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body")?;
+        let body = self.block()?;
+        Ok(Function {
+            name,
+            parameters,
+            body,
+            span,
+        })
+    }
+
+    fn var_declaration(&mut self) -> Result<Stmt> {
+        let name = self.identifier("Expect variable name")?;
+        let initializer = if self.match_token(TokenType::Equal) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+        Ok(Stmt::Var { name, initializer })
+    }
+
+    fn const_declaration(&mut self) -> Result<Stmt> {
+        let name = self.identifier("Expect constant name")?;
+        self.consume(TokenType::Equal, "Expect '=' after constant name")?;
+        let initializer = self.expression()?;
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        )?;
+        Ok(Stmt::Const { name, initializer })
+    }
+
+    fn statement(&mut self) -> Result<Stmt> {
+        self.nested(Parser::statement_unchecked)
+    }
+
+    fn statement_unchecked(&mut self) -> Result<Stmt> {
+        let span = self.peek().span();
+        if self.match_token(TokenType::Print) {
+            let expression = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after print statement")?;
+            Ok(Stmt::Print { expression, span })
+        } else if self.match_token(TokenType::If) {
+            self.consume(TokenType::LeftParen, "Expect '(' after if")?;
+            let condition = self.expression()?;
+            self.consume(TokenType::RightParen, "Expect ')' after condition")?;
+            let then_branch = Box::new(self.statement()?);
+            let else_branch = if self.match_token(TokenType::Else) {
+                Some(Box::new(self.statement()?))
+            } else {
+                None
+            };
+            Ok(Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                span,
+            })
+        } else if self.match_token(TokenType::While) {
+            self.consume(TokenType::LeftParen, "Expect '(' after while")?;
+            let condition = self.expression()?;
+            self.consume(TokenType::RightParen, "Expect ')' after condition")?;
+            let body = Box::new(self.statement()?);
+            Ok(Stmt::While {
+                condition,
+                body,
+                span,
+            })
+        } else if self.match_token(TokenType::For) {
+            self.consume(TokenType::LeftParen, "Expect '(' after for")?;
+            let variable = self.identifier("Expect variable name")?;
+            self.consume(TokenType::In, "Expect 'in' after the variable")?;
+            let iterable = self.expression()?;
+            self.consume(TokenType::RightParen, "Expect ')' after the iterable")?;
+            let body = Box::new(self.statement()?);
+            Ok(Stmt::For {
+                variable,
+                iterable,
+                body,
+                span,
+            })
+        } else if self.match_token(TokenType::LeftBrace) {
+            let statements = self.block()?;
+            Ok(Stmt::Block { statements, span })
+        } else if self.match_token(TokenType::Return) {
+            let value = if self.match_token(TokenType::Semicolon) {
+                None
+            } else {
+                let value = self.expression()?;
+                self.consume(TokenType::Semicolon, "Expect ';' after return")?;
+                Some(value)
+            };
+            Ok(Stmt::Return { value, span })
+        } else {
+            let expression = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after expression")?;
+            Ok(Stmt::Expression(expression))
+        }
+    }
+
+    /// The statements up to the closing '}', the '{' is already consumed
+    fn block(&mut self) -> Result<Vec<Stmt>> {
+        let mut statements = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            statements.push(self.declaration()?);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block")?;
+        Ok(statements)
+    }
+
+    fn expression(&mut self) -> Result<Expr> {
+        self.nested(Parser::assignment)
+    }
+
+    fn assignment(&mut self) -> Result<Expr> {
+        let mut target = self.conditional()?;
+        if self.match_token(TokenType::Equal) {
+            let equal = self.previous();
+            let value = Box::new(self.nested(Parser::assignment)?);
+            return match target {
+                Expr::Variable(ref name) => Ok(Expr::Assign {
+                    name: name.clone(),
+                    value,
+                }),
+                Expr::Get { ref name, .. } => {
+                    let name = name.clone();
+                    let object = target.take_chained().map(Box::new);
+                    Ok(Expr::Set {
+                        object: object.expect("Expected the object of the property"),
+                        name,
+                        value,
+                    })
+                }
+                _ => bail!(parse_error(equal, "Invalid assignment target")),
+            };
+        }
+        Ok(target)
+    }
+
+    fn conditional(&mut self) -> Result<Expr> {
+        let condition = self.or()?;
+        if self.match_token(TokenType::Question) {
+            let span = self.previous().span();
+            let then_branch = Box::new(self.nested(Parser::conditional)?);
+            self.consume(TokenType::Colon, "Expect ':' after then branch of '?'")?;
+            let else_branch = Box::new(self.nested(Parser::conditional)?);
+            return Ok(Expr::Conditional {
+                condition: Box::new(condition),
+                then_branch,
+                else_branch,
+                span,
+            });
+        }
+        Ok(condition)
+    }
+
+    /// A binary or logical expression, by precedence climbing (one call per operand rather than per precedence
+    /// level, to keep the stack of deeply nested expressions small)
+    fn or(&mut self) -> Result<Expr> {
+        self.binary(0)
+    }
+
+    /// A left associative binary expression of the operators of `precedence` or higher, see [binary_precedence]
+    fn binary(&mut self, precedence: usize) -> Result<Expr> {
+        let mut left = self.unary()?;
+        while let Some(operator_precedence) =
+            binary_precedence(self.peek().token_type).filter(|p| *p >= precedence)
+        {
+            let operator = self.advance();
+            let (operator, span) = (operator.token_type, operator.span());
+            let right = Box::new(self.nested(|p| p.binary(operator_precedence + 1))?);
+            let left_operand = Box::new(left);
+            left = match operator {
+                TokenType::Or | TokenType::And => Expr::Logical {
+                    left: left_operand,
+                    operator,
+                    right,
+                    span,
+                },
+                _ => Expr::Binary {
+                    left: left_operand,
+                    operator,
+                    right,
+                    span,
+                },
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.check(TokenType::Bang) || self.check(TokenType::Minus) {
+            let operator = self.advance();
+            let (operator, span) = (operator.token_type, operator.span());
+            let operand = Box::new(self.nested(Parser::unary)?);
+            return Ok(Expr::Unary {
+                operator,
+                operand,
+                span,
+            });
+        }
+        self.power()
+    }
+
+    /// `**` binds tighter than a unary operator on its left and is right associative, `-2 ** 2` is `-(2 ** 2)`
+    fn power(&mut self) -> Result<Expr> {
+        let left = self.call()?;
+        if self.match_token(TokenType::StarStar) {
+            let span = self.previous().span();
+            let right = self.nested(Parser::unary)?;
+            return Ok(Expr::Binary {
+                left: Box::new(left),
+                operator: TokenType::StarStar,
+                right: Box::new(right),
+                span,
+            });
+        }
+        Ok(left)
+    }
+
+    fn call(&mut self) -> Result<Expr> {
+        let mut expression = self.primary()?;
+        loop {
+            if self.match_token(TokenType::LeftParen) {
+                let span = self.previous().span();
+                let mut arguments = Vec::new();
+                while !self.check(TokenType::RightParen) {
+                    arguments.push(self.expression()?);
+                    if !self.match_token(TokenType::Comma) {
+                        break;
+                    }
+                }
+                self.consume(TokenType::RightParen, "Expect ')' after arguments")?;
+                expression = Expr::Call {
+                    callee: Box::new(expression),
+                    arguments,
+                    span,
+                };
+            } else if self.match_token(TokenType::Dot) {
+                let name = self.identifier("Expect property name after '.'")?;
+                expression = Expr::Get {
+                    object: Box::new(expression),
+                    name,
+                };
+            } else {
+                return Ok(expression);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let token = self.advance();
+        let span = token.span();
+        let value = match (token.token_type, &token.literal) {
+            (TokenType::Nil, _) => LiteralValue::Nil,
+            (TokenType::True, _) => LiteralValue::Bool(true),
+            (TokenType::False, _) => LiteralValue::Bool(false),
+            (TokenType::Number, Some(Literal::Number(n))) => LiteralValue::Number(*n),
+            (TokenType::String, Some(Literal::String(s))) => LiteralValue::String(s.clone()),
+            (TokenType::Identifier, _) => {
+                return Ok(Expr::Variable(Identifier {
+                    name: token.lexeme.clone(),
+                    span,
+                }))
+            }
+            (TokenType::This, _) => return Ok(Expr::This { span }),
+            (TokenType::Fun, _) => {
+                return Ok(Expr::Function(Box::new(self.function(None, span)?)));
+            }
+            (TokenType::Yield, _) => {
+                let value = match self.peek().token_type {
+                    TokenType::Semicolon
+                    | TokenType::RightParen
+                    | TokenType::RightBrace
+                    | TokenType::Comma => None,
+                    _ => Some(Box::new(self.nested(Parser::assignment)?)),
+                };
+                return Ok(Expr::Yield { value, span });
+            }
+            (TokenType::LeftParen, _) => {
+                let expression = Box::new(self.expression()?);
+                self.consume(TokenType::RightParen, "Expect ')' after expression")?;
+                return Ok(Expr::Grouping { expression, span });
+            }
+            _ => bail!(parse_error(token, "Expect expression")),
+        };
+        Ok(Expr::Literal { value, span })
+    }
+
+    fn identifier(&mut self, message: &str) -> Result<Identifier> {
+        let token = self.consume(TokenType::Identifier, message)?;
+        Ok(Identifier {
+            name: token.lexeme.clone(),
+            span: token.span(),
+        })
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) -> Result<&'a Token> {
+        if self.check(token_type) {
+            Ok(self.advance())
+        } else {
+            bail!(parse_error(self.peek(), message))
+        }
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if self.check(token_type) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.peek().token_type == token_type
+    }
+
+    fn check_next(&self, token_type: TokenType) -> bool {
+        self.tokens
+            .get(self.current + 1)
+            .is_some_and(|t| t.token_type == token_type)
+    }
+
+    fn advance(&mut self) -> &'a Token {
+        let token = self.peek();
+        if !self.is_at_end() {
+            self.current += 1;
+        }
+        token
+    }
+
+    fn peek(&self) -> &'a Token {
+        &self.tokens[self.current]
+    }
+
+    fn previous(&self) -> &'a Token {
+        &self.tokens[self.current - 1]
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.peek().token_type == TokenType::Eof
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_to_ast, Expr, Identifier, LiteralValue, Stmt};
+    use crate::{
+        scanner::Scanner,
+        tokens::{Span, TokenType},
+    };
+    use evie_common::errors::*;
+
+    fn parse(source: &str) -> Result<Vec<Stmt>> {
+        let mut scanner = Scanner::new(source.to_string());
+        parse_to_ast(scanner.scan_tokens()?)
+    }
+
+    #[test]
+    fn parses_declarations_and_expressions() -> Result<()> {
+        let statements = parse(
+            r#"
+var a = 1 + 2 * 3;
+class Point {
+    init(x) { this.x = x; }
+    static origin() { return Point(0); }
+}
+fun apply(f) { return f(a) ? "yes" : "no"; }
+print apply(fun(n) { return n is Point or !n; });
+"#,
+        )?;
+        assert_eq!(4, statements.len());
+        match &statements[0] {
+            Stmt::Var {
+                name,
+                initializer:
+                    Some(Expr::Binary {
+                        operator, right, ..
+                    }),
+            } => {
+                assert_eq!(
+                    &Identifier {
+                        name: "a".to_string(),
+                        span: Span {
+                            line: 2,
+                            column: 5,
+                            length: 1
+                        }
+                    },
+                    name
+                );
+                assert_eq!(TokenType::Plus, *operator);
+                assert!(matches!(
+                    **right,
+                    Expr::Binary {
+                        operator: TokenType::Star,
+                        ..
+                    }
+                ));
+            }
+            s => panic!("Unexpected {:?}", s),
+        }
+        match &statements[1] {
+            Stmt::Class { name, doc, methods } => {
+                assert_eq!("Point", name.name);
+                assert_eq!(None, *doc);
+                let methods: Vec<(bool, String)> = methods
+                    .iter()
+                    .map(|m| (m.is_static, m.function.name.clone().unwrap().name))
+                    .collect();
+                assert_eq!(
+                    vec![(false, "init".to_string()), (true, "origin".to_string())],
+                    methods
+                );
+            }
+            s => panic!("Unexpected {:?}", s),
+        }
+        match &statements[2] {
+            Stmt::Function(f) => {
+                assert_eq!(1, f.parameters.len());
+                assert!(matches!(
+                    f.body[0],
+                    Stmt::Return {
+                        value: Some(Expr::Conditional { .. }),
+                        ..
+                    }
+                ));
+            }
+            s => panic!("Unexpected {:?}", s),
+        }
+        match &statements[3] {
+            Stmt::Print {
+                expression: Expr::Call { arguments, .. },
+                span,
+            } => {
+                assert_eq!(8, span.line);
+                assert!(matches!(arguments[0], Expr::Function(_)));
+            }
+            s => panic!("Unexpected {:?}", s),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parses_assignments() -> Result<()> {
+        let statements = parse("a = b.c = \"x\";")?;
+        match &statements[0] {
+            Stmt::Expression(Expr::Assign { name, value }) => {
+                assert_eq!("a", name.name);
+                match &**value {
+                    Expr::Set { name, value, .. } => {
+                        assert_eq!("c", name.name);
+                        assert!(matches!(
+                            **value,
+                            Expr::Literal {
+                                value: LiteralValue::String(_),
+                                ..
+                            }
+                        ));
+                    }
+                    e => panic!("Unexpected {:?}", e),
+                }
+            }
+            s => panic!("Unexpected {:?}", s),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert_eq!(
+            "Parse Error: [line: 1, column: 7] Error at <=>: message: Invalid assignment target",
+            parse("a + b = 1;").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "Parse Error: [line: 1, column: 9] Error at <2>: message: Expect ';' after print statement",
+            parse("print 1 2").unwrap_err().to_string()
+        );
+    }
+}
@@ -0,0 +1,673 @@
+//! The formatter for evie, used by `evie fmt` and the language server.
+//!
+//! It parses the source into an [crate::ast] and prints it back with four space indentation,
+//! one statement per line and a single space around binary operators.
+//! Top level functions and classes, as well as methods, are separated by a blank line.
+//! Comments are kept, each comment is printed before the statement that follows it
+//! (or after the statement or the `}`, if it was at the end of the same line).
+use std::{iter::Peekable, vec::IntoIter};
+
+use evie_common::errors::*;
+
+use crate::{
+    ast::{parse_to_ast, Expr, Function, LiteralValue, Stmt},
+    scanner::Scanner,
+    tokens::{Comment, TokenType},
+};
+
+const INDENT: &str = "    ";
+
+/// Formats the given source
+pub fn format_source(source: &str) -> Result<String> {
+    let mut scanner = Scanner::new(source.to_string());
+    let tokens = scanner.scan_tokens()?.to_vec();
+    let statements = parse_to_ast(&tokens)?;
+    let braces: Vec<(usize, usize)> = tokens
+        .iter()
+        .filter(|t| t.token_type == TokenType::RightBrace)
+        .map(|t| (t.line, t.column))
+        .collect();
+    // The index of the `}` each comment directly follows on the same line (if any)
+    let comments: Vec<(&Comment, Option<usize>)> = scanner
+        .comments()
+        .iter()
+        .map(|comment| {
+            let before =
+                tokens.partition_point(|t| (t.line, t.column) < (comment.line, comment.column));
+            let follows_brace = before > 0
+                && tokens[before - 1].token_type == TokenType::RightBrace
+                && tokens[before - 1].line == comment.line;
+            let brace = braces.partition_point(|&b| b < (comment.line, comment.column));
+            (comment, follows_brace.then(|| brace - 1))
+        })
+        .collect();
+    let mut formatter = Formatter {
+        output: String::new(),
+        indent: 0,
+        comments: comments.into_iter().peekable(),
+        trailing: Vec::new(),
+        braces,
+        closed: 0,
+    };
+    formatter.statements(&statements, true);
+    formatter.comments_before(usize::MAX);
+    Ok(formatter.output)
+}
+
+struct Formatter<'a> {
+    output: String,
+    indent: usize,
+    comments: Peekable<IntoIter<(&'a Comment, Option<usize>)>>,
+    /// The comments to print at the end of the current line
+    trailing: Vec<&'a Comment>,
+    /// The line and column of each `}` in the source
+    braces: Vec<(usize, usize)>,
+    /// The number of `}` printed so far
+    closed: usize,
+}
+
+impl<'a> Formatter<'a> {
+    fn statements(&mut self, statements: &[Stmt], top_level: bool) {
+        for (i, statement) in statements.iter().enumerate() {
+            let separated = |s: &Stmt| matches!(s, Stmt::Function(_) | Stmt::Class { .. });
+            if top_level && i > 0 && (separated(statement) || separated(&statements[i - 1])) {
+                self.output.push('\n');
+            }
+            self.comments_before(statement.span().line);
+            self.write_indent();
+            self.statement(statement);
+            self.trailing_comment(statement);
+            self.end_line();
+        }
+    }
+
+    /// Ends the current line, after the comments that were at the end of it
+    fn end_line(&mut self) {
+        for comment in self.trailing.drain(..) {
+            self.output.push(' ');
+            self.output.push_str(&comment.text);
+        }
+        self.output.push('\n');
+    }
+
+    /// Prints the comments that appear before `line`, each on its own line
+    fn comments_before(&mut self, line: usize) {
+        while let Some((comment, _)) = self.comments.next_if(|(c, _)| c.line < line) {
+            self.write_indent();
+            self.output.push_str(&comment.text);
+            self.output.push('\n');
+        }
+    }
+
+    /// Keeps a comment at the end of a single line statement on the same line
+    fn trailing_comment(&mut self, statement: &Stmt) {
+        if let Stmt::Expression(_)
+        | Stmt::Print { .. }
+        | Stmt::Var { .. }
+        | Stmt::Const { .. }
+        | Stmt::Return { .. } = statement
+        {
+            // The statement ends on the line of the last `}` in it (e.g. of a function expression), if any
+            let mut line = statement.span().line;
+            if self.closed > 0 {
+                line = line.max(self.braces[self.closed - 1].0);
+            }
+            while let Some((comment, _)) = self
+                .comments
+                .next_if(|(c, brace)| c.line == line && brace.is_none())
+            {
+                self.trailing.push(comment);
+            }
+        }
+    }
+
+    /// Returns true if there are comments before the next `}`
+    fn comments_before_brace(&mut self) -> bool {
+        let brace = self.braces[self.closed];
+        self.comments
+            .peek()
+            .is_some_and(|(c, _)| (c.line, c.column) < brace)
+    }
+
+    /// Prints the comments before the next `}` (e.g. after the last statement of a block), each on its own line
+    fn comments_in_braces(&mut self) {
+        while self.comments_before_brace() {
+            let (comment, _) = self.comments.next().unwrap();
+            self.write_indent();
+            self.output.push_str(&comment.text);
+            self.output.push('\n');
+        }
+    }
+
+    /// Prints the next `}`, the comments that follow it on the same line are printed at the end of the line
+    fn close_brace(&mut self) {
+        let brace = self.closed;
+        self.closed += 1;
+        self.output.push('}');
+        while let Some((comment, _)) = self.comments.next_if(|(_, b)| *b == Some(brace)) {
+            self.trailing.push(comment);
+        }
+    }
+
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.output.push_str(INDENT);
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Expression(expression) => {
+                self.expression(expression);
+                self.output.push(';');
+            }
+            Stmt::Print { expression, .. } => {
+                self.output.push_str("print ");
+                self.expression(expression);
+                self.output.push(';');
+            }
+            Stmt::Var { name, initializer } => {
+                self.output.push_str("var ");
+                self.output.push_str(&name.name);
+                if let Some(initializer) = initializer {
+                    self.output.push_str(" = ");
+                    self.expression(initializer);
+                }
+                self.output.push(';');
+            }
+            Stmt::Const { name, initializer } => {
+                self.output.push_str("const ");
+                self.output.push_str(&name.name);
+                self.output.push_str(" = ");
+                self.expression(initializer);
+                self.output.push(';');
+            }
+            Stmt::Block { statements, .. } => self.block(statements),
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.output.push_str("if (");
+                self.expression(condition);
+                self.output.push(')');
+                self.branch(then_branch);
+                if let Some(else_branch) = else_branch {
+                    if matches!(**then_branch, Stmt::Block { .. }) {
+                        self.output.push(' ');
+                    } else {
+                        self.end_line();
+                        self.write_indent();
+                    }
+                    self.output.push_str("else");
+                    if let Stmt::If { .. } = **else_branch {
+                        // else if
+                        self.output.push(' ');
+                        self.statement(else_branch);
+                    } else {
+                        self.branch(else_branch);
+                    }
+                }
+            }
+            Stmt::While {
+                condition, body, ..
+            } => {
+                self.output.push_str("while (");
+                self.expression(condition);
+                self.output.push(')');
+                self.branch(body);
+            }
+            Stmt::For {
+                variable,
+                iterable,
+                body,
+                ..
+            } => {
+                self.output.push_str("for (");
+                self.output.push_str(&variable.name);
+                self.output.push_str(" in ");
+                self.expression(iterable);
+                self.output.push(')');
+                self.branch(body);
+            }
+            Stmt::Return { value, .. } => {
+                self.output.push_str("return");
+                if let Some(value) = value {
+                    self.output.push(' ');
+                    self.expression(value);
+                }
+                self.output.push(';');
+            }
+            Stmt::Function(function) => {
+                self.output.push_str("fun ");
+                self.function(function);
+            }
+            Stmt::Class { name, doc, methods } => {
+                self.output.push_str("class ");
+                self.output.push_str(&name.name);
+                if methods.is_empty() && doc.is_none() && !self.comments_before_brace() {
+                    self.output.push_str(" {");
+                    self.close_brace();
+                    return;
+                }
+                self.output.push_str(" {");
+                self.end_line();
+                self.indent += 1;
+                if let Some(doc) = doc {
+                    self.write_indent();
+                    self.output.push('"');
+                    self.output.push_str(&escape(doc));
+                    self.output.push_str("\";\n");
+                }
+                for (i, method) in methods.iter().enumerate() {
+                    if i > 0 {
+                        self.output.push('\n');
+                    }
+                    self.comments_before(method.function.span.line);
+                    self.write_indent();
+                    if method.is_static {
+                        self.output.push_str("static ");
+                    }
+                    self.function(&method.function);
+                    self.end_line();
+                }
+                self.comments_in_braces();
+                self.indent -= 1;
+                self.write_indent();
+                self.close_brace();
+            }
+        }
+    }
+
+    /// The body of an if, else, while or for. A block stays on the same line, anything else goes on the next line.
+    fn branch(&mut self, statement: &Stmt) {
+        if let Stmt::Block { statements, .. } = statement {
+            self.output.push(' ');
+            self.block(statements);
+        } else {
+            self.end_line();
+            self.indent += 1;
+            self.write_indent();
+            self.statement(statement);
+            self.indent -= 1;
+        }
+    }
+
+    fn block(&mut self, statements: &[Stmt]) {
+        if statements.is_empty() && !self.comments_before_brace() {
+            self.output.push('{');
+            self.close_brace();
+            return;
+        }
+        self.output.push('{');
+        self.end_line();
+        self.indent += 1;
+        self.statements(statements, false);
+        self.comments_in_braces();
+        self.indent -= 1;
+        self.write_indent();
+        self.close_brace();
+    }
+
+    /// The name (if any), the parameters and the body
+    fn function(&mut self, function: &Function) {
+        if let Some(name) = &function.name {
+            self.output.push_str(&name.name);
+        }
+        self.output.push('(');
+        let parameters: Vec<&str> = function
+            .parameters
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        self.output.push_str(&parameters.join(", "));
+        self.output.push_str(") ");
+        self.block(&function.body);
+    }
+
+    fn expression(&mut self, expression: &Expr) {
+        // A chain (e.g. `1 + 2 + 3` or `a.b().c`) is printed in a loop from its first operand on, a long one would
+        // overflow the stack if it was printed recursively
+        let mut chain = vec![expression];
+        while let Some(chained) = chain[chain.len() - 1].chained() {
+            chain.push(chained);
+        }
+        for expression in chain.into_iter().rev() {
+            self.expression_after_chained(expression);
+        }
+    }
+
+    /// The expression without the one its chain continues from (see [Expr::chained]), it is already printed
+    fn expression_after_chained(&mut self, expression: &Expr) {
+        match expression {
+            Expr::Literal { value, .. } => match value {
+                LiteralValue::Nil => self.output.push_str("nil"),
+                LiteralValue::Bool(b) => self.output.push_str(&b.to_string()),
+                LiteralValue::Number(n) => self.output.push_str(&number(*n)),
+                LiteralValue::String(s) => {
+                    self.output.push('"');
+                    self.output.push_str(&escape(s));
+                    self.output.push('"');
+                }
+            },
+            Expr::Variable(name) => self.output.push_str(&name.name),
+            Expr::This { .. } => self.output.push_str("this"),
+            Expr::Assign { name, value } => {
+                self.output.push_str(&name.name);
+                self.output.push_str(" = ");
+                self.expression(value);
+            }
+            Expr::Unary {
+                operator, operand, ..
+            } => {
+                self.output.push_str(operator_str(*operator));
+                self.expression(operand);
+            }
+            // Ranges are written without spaces, e.g. `0..10`
+            Expr::Binary {
+                operator: operator @ (TokenType::DotDot | TokenType::DotDotEqual),
+                right,
+                ..
+            } => {
+                self.output.push_str(operator_str(*operator));
+                self.expression(right);
+            }
+            Expr::Binary {
+                operator, right, ..
+            }
+            | Expr::Logical {
+                operator, right, ..
+            } => {
+                self.output.push(' ');
+                self.output.push_str(operator_str(*operator));
+                self.output.push(' ');
+                self.expression(right);
+            }
+            Expr::Conditional {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition);
+                self.output.push_str(" ? ");
+                self.expression(then_branch);
+                self.output.push_str(" : ");
+                self.expression(else_branch);
+            }
+            Expr::Grouping { expression, .. } => {
+                self.output.push('(');
+                self.expression(expression);
+                self.output.push(')');
+            }
+            Expr::Call { arguments, .. } => {
+                self.output.push('(');
+                for (i, argument) in arguments.iter().enumerate() {
+                    if i > 0 {
+                        self.output.push_str(", ");
+                    }
+                    self.expression(argument);
+                }
+                self.output.push(')');
+            }
+            Expr::Get { name, .. } => {
+                self.output.push('.');
+                self.output.push_str(&name.name);
+            }
+            Expr::Set { name, value, .. } => {
+                self.output.push('.');
+                self.output.push_str(&name.name);
+                self.output.push_str(" = ");
+                self.expression(value);
+            }
+            Expr::Function(function) => {
+                self.output.push_str("fun");
+                self.function(function);
+            }
+            Expr::Yield { value, .. } => {
+                self.output.push_str("yield");
+                if let Some(value) = value {
+                    self.output.push(' ');
+                    self.expression(value);
+                }
+            }
+        }
+    }
+}
+
+fn operator_str(operator: TokenType) -> &'static str {
+    match operator {
+        TokenType::Minus => "-",
+        TokenType::Plus => "+",
+        TokenType::Slash => "/",
+        TokenType::Star => "*",
+        TokenType::StarStar => "**",
+        TokenType::Bang => "!",
+        TokenType::BangEqual => "!=",
+        TokenType::EqualEqual => "==",
+        TokenType::Greater => ">",
+        TokenType::GreaterEqual => ">=",
+        TokenType::Less => "<",
+        TokenType::LessEqual => "<=",
+        TokenType::LessLess => "<<",
+        TokenType::GreaterGreater => ">>",
+        TokenType::Ampersand => "&",
+        TokenType::Pipe => "|",
+        TokenType::Caret => "^",
+        TokenType::DotDot => "..",
+        TokenType::DotDotEqual => "..=",
+        TokenType::And => "and",
+        TokenType::Or => "or",
+        TokenType::Is => "is",
+        _ => unreachable!("{} is not an operator", operator),
+    }
+}
+
+/// Very large and very small numbers in scientific notation (like JavaScript), e.g. 1e21 instead of 22 digits
+fn number(n: f64) -> String {
+    if n != 0.0 && !(1e-6..1e21).contains(&n.abs()) {
+        format!("{:e}", n)
+    } else {
+        n.to_string()
+    }
+}
+
+/// The reverse of the unescaping done by the scanner
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_source;
+    use evie_common::errors::*;
+
+    #[test]
+    fn formats_source() -> Result<()> {
+        let source = r#"
+// A counter
+var  count=0 ;   // starts at zero
+fun   increment( by ){count=count+by;return count>10?"big":"small\n";}
+class Point{init(x,y){this.x=x;this.y=y;}
+/* origin */
+static origin(){return Point(0,0);}}
+if(count==0)print "zero";else if (count<0) {print -count;} else {}
+while(!(count>=3 and true)){increment(1);}
+var f=fun(a){return a is Point;};
+"#;
+        let expected = r#"// A counter
+var count = 0; // starts at zero
+
+fun increment(by) {
+    count = count + by;
+    return count > 10 ? "big" : "small\n";
+}
+
+class Point {
+    init(x, y) {
+        this.x = x;
+        this.y = y;
+    }
+
+    /* origin */
+    static origin() {
+        return Point(0, 0);
+    }
+}
+
+if (count == 0)
+    print "zero";
+else if (count < 0) {
+    print -count;
+} else {}
+while (!(count >= 3 and true)) {
+    increment(1);
+}
+var f = fun(a) {
+    return a is Point;
+};
+"#;
+        let formatted = format_source(source)?;
+        assert_eq!(expected, formatted);
+        // Formatting is idempotent
+        assert_eq!(expected, format_source(&formatted)?);
+        Ok(())
+    }
+
+    #[test]
+    fn formats_bitwise_operators() -> Result<()> {
+        let expected = "print (a | b & 1) ^ c << 2 >> 1;\n";
+        assert_eq!(expected, format_source("print (a|b&1)^c<<2>>1;")?);
+        Ok(())
+    }
+
+    #[test]
+    fn formats_exponents() -> Result<()> {
+        assert_eq!(
+            "print -2 ** 3 ** 2 + 2.5e-7 * 1e21 - 1000;\n",
+            format_source("print -2**3**2+2.5e-7*1E21-1e3;")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn formats_long_chains() -> Result<()> {
+        // Chains are not nested, they are longer than the nesting limit
+        let n = 100_000;
+        let formatted = format_source(&format!("print 1{};", "+1".repeat(n)))?;
+        assert!(formatted == format!("print 1{};\n", " + 1".repeat(n)));
+        for source in [
+            format!("print a{};\n", " and b or c".repeat(n)),
+            format!("a{}.c = 1;\n", ".b()(1, 2)".repeat(n)),
+            format!("print 0{};\n", "..1".repeat(n)),
+        ] {
+            assert!(format_source(&source)? == source, "{}", &source[..20]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn formats_for_in_loops() -> Result<()> {
+        assert_eq!(
+            "for (x in items) {\n    print x;\n}\nfor (y in items.iter())\n    print y;\n",
+            format_source("for(x in items){print x;}\nfor (y   in items.iter()) print y;")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn formats_ranges() -> Result<()> {
+        assert_eq!(
+            "for (i in 0..n + 1)\n    print i;\nvar r = 1..=3;\n",
+            format_source("for (i in 0 .. n+1) print i;\nvar r = 1 ..= 3;")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn formats_constants() -> Result<()> {
+        assert_eq!(
+            "const LIMIT = 10 * 2;\n",
+            format_source("const   LIMIT=10*2;")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn formats_yields() -> Result<()> {
+        assert_eq!(
+            "fun f() {\n    yield;\n    var x = yield 1 + 2;\n}\n",
+            format_source("fun f(){yield ;var x=yield 1+2;}")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn formats_docstrings() -> Result<()> {
+        assert_eq!(
+            "class A {\n    \"An \\\"A\\\"\";\n}\n\nfun f() {\n    \"Does f\";\n}\n",
+            format_source("class A { \"An \\\"A\\\"\" } fun f() { \"Does f\"; }")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_comments_in_blocks() -> Result<()> {
+        let source = "fun f() {\n  // first\n  print 1;\n}\n// end\n";
+        assert_eq!(
+            "fun f() {\n    // first\n    print 1;\n}\n// end\n",
+            format_source(source)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_comments_after_braces() -> Result<()> {
+        assert_eq!(
+            "fun f(a, b) {\n    \"doc\";\n    return a + b;\n} // after fun\n",
+            format_source("fun f(a,b){ \"doc\"; return a+b; } // after fun")?
+        );
+        assert_eq!(
+            "fun f() {\n    print 1; // one\n} // end of f\n\nprint f();\n",
+            format_source("fun f() {\n  print 1; // one\n} // end of f\nprint f();")?
+        );
+        assert_eq!(
+            "class A {\n    m() {\n        return 1;\n    } // m\n} /* A */\n",
+            format_source("class A { m() { return 1; } // m\n} /* A */")?
+        );
+        assert_eq!(
+            "if (a) {\n    print 1;\n} else { /* a */\n    print 2;\n}\nvar f = fun() {}; // f\n",
+            format_source("if (a) { print 1; } /* a */ else { print 2; }\nvar f = fun() {}; // f")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_comments_at_the_end_of_blocks() -> Result<()> {
+        let expected = "fun f() {\n    print 1;\n    // done\n}\n\nclass A {\n    // empty\n}\n";
+        assert_eq!(
+            expected,
+            format_source("fun f() { print 1;\n// done\n}\nclass A {\n// empty\n}")?
+        );
+        assert_eq!(expected, format_source(expected)?);
+        Ok(())
+    }
+
+    #[test]
+    fn does_not_format_invalid_source() {
+        assert!(format_source("var a = ;").is_err());
+    }
+}
@@ -1,6 +1,9 @@
 //! The 'frontend' crate which parses the source code and produces [tokens].
+//! Tooling can additionally build an [ast] from the tokens, and [fmt] formats source code.
 #[macro_use(bail)]
 extern crate evie_common;
 
+pub mod ast;
+pub mod fmt;
 pub mod scanner;
 pub mod tokens;
@@ -2,56 +2,218 @@
 use evie_common::{error, errors::*};
 use std::collections::HashMap;
 
-use super::tokens::{Literal, Token, TokenType};
+use super::tokens::{Comment, Literal, Token, TokenType};
 
 /// Scanner for Evie. Outputs the tokens a [Vec].
+///
+/// The source is UTF-8, `start` and `current` are byte offsets into it,
+/// while lines and columns are counted in characters (code points) starting at 1.
 pub struct Scanner {
     source: String,
     source_len: usize,
     tokens: Vec<Token>,
+    comments: Vec<Comment>,
     line: usize,
+    column: usize,
+    start_line: usize,
+    start_column: usize,
     start: usize,
     current: usize,
     reserved_key_words: HashMap<&'static str, TokenType>,
+    /// The source ended in a string or a block comment
+    unterminated: bool,
+}
+
+/// Whether more input is needed to complete the source: it ends in a string or a block comment, or has a `(` or `{`
+/// that is not closed yet. The REPL reads more lines until the input is complete.
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source.to_string());
+    while !scanner.is_at_end() {
+        // The errors are reported when the complete input is compiled
+        let _ = scanner.scan_next_token();
+    }
+    let open = scanner
+        .tokens
+        .iter()
+        .fold(0isize, |open, token| match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace => open + 1,
+            TokenType::RightParen | TokenType::RightBrace => open - 1,
+            _ => open,
+        });
+    scanner.unterminated || open > 0
+}
+
+/// The lines an edit changed, numbered from 1 as the lines of the tokens: the lines `start..=old_end` of the previous
+/// source are the lines `start..=new_end` of the new one (an empty range if the edit removed or inserted whole lines).
+/// The other lines, with the line break that ends them, are unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineEdit {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+impl LineEdit {
+    /// The lines that differ between the sources, between their common first and last lines
+    pub fn between(old: &str, new: &str) -> Self {
+        let old: Vec<_> = old.split_inclusive('\n').collect();
+        let new: Vec<_> = new.split_inclusive('\n').collect();
+        let prefix = old.iter().zip(&new).take_while(|(o, n)| o == n).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(o, n)| o == n)
+            .count();
+        LineEdit {
+            start: prefix + 1,
+            old_end: old.len() - suffix,
+            new_end: new.len() - suffix,
+        }
+    }
+}
+
+/// Scans the source after an edit, with the same result as [Scanner::scan_tokens] but only re-tokenizing the edited
+/// lines: `previous` are the tokens of a successful scan of the source before the edit.
+///
+/// The tokens that end before the edit are kept and the scan starts again at the end of the last one. Once the scan
+/// is past the edit, a token that starts where one of the previous tokens started is the same token (the source from
+/// there on is unchanged), the previous tokens from there on are kept with their lines moved.
+pub fn rescan(previous: &[Token], source: String, edit: LineEdit) -> Result<Vec<Token>> {
+    let kept = previous
+        .iter()
+        .take_while(|token| token.token_type != TokenType::Eof && token_end(token).0 < edit.start)
+        .count();
+    let (line, column) = match kept.checked_sub(1) {
+        Some(last) => token_end(&previous[last]),
+        None => (1, 1),
+    };
+    let offset = match byte_offset(&source, line, column) {
+        Some(offset) if kept > 0 => offset,
+        // Nothing to keep (or the edit is not in the source)
+        _ => return Scanner::new(source).scan_tokens().map(<[_]>::to_vec),
+    };
+    let mut scanner = Scanner::new(source);
+    scanner.tokens = previous[..kept].to_vec();
+    (scanner.current, scanner.line, scanner.column) = (offset, line, column);
+    let moved_by = edit.new_end as isize - edit.old_end as isize;
+    let mut next = kept;
+    let mut error_found = false;
+    while !scanner.is_at_end() {
+        if scanner.line > edit.new_end {
+            let position = ((scanner.line as isize - moved_by) as usize, scanner.column);
+            while next < previous.len() && (previous[next].line, previous[next].column) < position {
+                next += 1;
+            }
+            let same = previous.get(next).filter(|token| {
+                token.token_type != TokenType::Eof && (token.line, token.column) == position
+            });
+            if same.is_some() {
+                scanner
+                    .tokens
+                    .extend(previous[next..].iter().map(|token| Token {
+                        line: (token.line as isize + moved_by) as usize,
+                        ..token.clone()
+                    }));
+                return scanned(scanner.tokens, error_found);
+            }
+        }
+        if let Err(e) = scanner.scan_next_token() {
+            error!("Error: {}", e.to_string());
+            error_found = true;
+        }
+    }
+    scanner.tokens.push(Token::new(
+        TokenType::Eof,
+        "".into(),
+        scanner.line,
+        scanner.column,
+        None,
+    ));
+    scanned(scanner.tokens, error_found)
+}
+
+fn scanned(tokens: Vec<Token>, error_found: bool) -> Result<Vec<Token>> {
+    if error_found {
+        bail!(ErrorKind::ScanError("Scan failed".into()))
+    }
+    Ok(tokens)
+}
+
+/// The line and column right after the token
+fn token_end(token: &Token) -> (usize, usize) {
+    match token.lexeme.rsplit_once('\n') {
+        Some((before, last)) => (
+            token.line + before.matches('\n').count() + 1,
+            last.chars().count() + 1,
+        ),
+        None => (token.line, token.column + token.lexeme.chars().count()),
+    }
+}
+
+/// The offset of the line and column in the source, None if the line is not in it
+fn byte_offset(source: &str, line: usize, column: usize) -> Option<usize> {
+    let line_start = match line {
+        1 => 0,
+        _ => source.match_indices('\n').nth(line - 2)?.0 + 1,
+    };
+    let columns = source[line_start..].chars().take(column - 1);
+    Some(line_start + columns.map(char::len_utf8).sum::<usize>())
 }
 
 impl Scanner {
     pub fn new(source: String) -> Self {
-        let source_len = source.chars().count();
+        let source_len = source.len();
         Scanner {
             source,
             source_len,
             tokens: vec![],
+            comments: vec![],
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
             start: 0,
             current: 0,
+            unterminated: false,
             // reserved keywords
             reserved_key_words: HashMap::from([
                 ("and", TokenType::And),
                 ("class", TokenType::Class),
+                ("const", TokenType::Const),
                 ("else", TokenType::Else),
                 ("false", TokenType::False),
                 ("for", TokenType::For),
                 ("fun", TokenType::Fun),
                 ("if", TokenType::If),
+                ("in", TokenType::In),
+                ("is", TokenType::Is),
                 ("nil", TokenType::Nil),
                 ("or", TokenType::Or),
                 ("print", TokenType::Print),
                 ("return", TokenType::Return),
+                ("static", TokenType::Static),
                 ("super", TokenType::Super),
                 ("this", TokenType::This),
                 ("true", TokenType::True),
                 ("var", TokenType::Var),
                 ("while", TokenType::While),
+                ("yield", TokenType::Yield),
             ]),
         }
     }
 
     pub fn scan_tokens(&mut self) -> Result<&[Token]> {
         let mut error_found = false;
+        // A `#!/usr/bin/env evie` first line makes a script executable, it is kept as a comment (e.g. by evie fmt)
+        if self.source.starts_with("#!") {
+            while self.peek() != '\n' && !self.is_at_end() {
+                self.advance();
+            }
+            self.add_comment();
+        }
         while !self.is_at_end() {
-            self.start = self.current;
-            match self.scan_token() {
+            match self.scan_next_token() {
                 Ok(_) => continue,
                 Err(e) => {
                     error!("Error: {}", e.to_string());
@@ -59,8 +221,13 @@ impl Scanner {
                 }
             }
         }
-        self.tokens
-            .push(Token::new(TokenType::Eof, "".into(), self.line, None));
+        self.tokens.push(Token::new(
+            TokenType::Eof,
+            "".into(),
+            self.line,
+            self.column,
+            None,
+        ));
         if error_found {
             bail!(ErrorKind::ScanError("Scan failed".into()))
         } else {
@@ -68,6 +235,18 @@ impl Scanner {
         }
     }
 
+    fn scan_next_token(&mut self) -> Result<()> {
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
+        self.scan_token()
+    }
+
+    /// The comments found by [Scanner::scan_tokens], in the order of appearance
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source_len
     }
@@ -81,31 +260,52 @@ impl Scanner {
             '{' => self.add_token(TokenType::LeftBrace, None),
             '}' => self.add_token(TokenType::RightBrace, None),
             ',' => self.add_token(TokenType::Comma, None),
+            '.' if self.next_char_is('.') => {
+                self.advance();
+                self.match_char_and_add_token('=', TokenType::DotDotEqual, TokenType::DotDot)
+            }
             '.' => self.add_token(TokenType::Dot, None),
             '-' => self.add_token(TokenType::Minus, None),
             '+' => self.add_token(TokenType::Plus, None),
             ';' => self.add_token(TokenType::Semicolon, None),
-            '*' => self.add_token(TokenType::Star, None),
+            '*' => self.match_char_and_add_token('*', TokenType::StarStar, TokenType::Star),
+            '?' => self.add_token(TokenType::Question, None),
+            ':' => self.add_token(TokenType::Colon, None),
+            '&' => self.add_token(TokenType::Ampersand, None),
+            '|' => self.add_token(TokenType::Pipe, None),
+            '^' => self.add_token(TokenType::Caret, None),
             // Double character tokens
             '!' => self.match_char_and_add_token('=', TokenType::BangEqual, TokenType::Bang),
             '=' => self.match_char_and_add_token('=', TokenType::EqualEqual, TokenType::Equal),
+            '<' if self.next_char_is('<') => {
+                self.advance();
+                self.add_token(TokenType::LessLess, None)
+            }
+            '>' if self.next_char_is('>') => {
+                self.advance();
+                self.add_token(TokenType::GreaterGreater, None)
+            }
             '<' => self.match_char_and_add_token('=', TokenType::LessEqual, TokenType::Less),
             '>' => self.match_char_and_add_token('=', TokenType::GreaterEqual, TokenType::Greater),
             '/' => {
                 // Comment
-                if self.get_char() == '/' {
+                if self.peek() == '/' {
                     // Traverse until the end of this line
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
+                    self.add_comment();
+                } else if self.peek() == '*' {
+                    self.advance();
+                    self.block_comment()?;
+                    self.add_comment();
                 } else {
                     self.add_token(TokenType::Slash, None);
                 }
             }
-            ' ' | '\r' | '\t' => {
-                // do nothing
+            ' ' | '\r' | '\t' | '\n' => {
+                // do nothing, lines are counted in advance()
             }
-            '\n' => self.line += 1,
             // String literals
             '"' => self.add_string()?,
             _ => {
@@ -113,11 +313,12 @@ impl Scanner {
                 if current_char.is_ascii_digit() {
                     self.add_number()?;
                     // identifier
-                } else if current_char.is_ascii_alphabetic() || current_char == '_' {
+                } else if current_char.is_alphabetic() || current_char == '_' {
                     self.add_identifier();
                 } else {
                     bail!(scan_error(
-                        self.line,
+                        self.start_line,
+                        self.start_column,
                         &format!("Unexpected character {}", current_char)
                     ))
                 }
@@ -127,43 +328,114 @@ impl Scanner {
     }
 
     fn add_string(&mut self) -> Result<()> {
+        let mut string = String::new();
+        // The first invalid escape, reported once the whole literal is consumed
+        let mut invalid_escape = None;
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            let (line, column) = (self.line, self.column);
+            let c = self.get_char_and_advance();
+            if c != '\\' {
+                string.push(c);
+                continue;
+            }
+            if self.is_at_end() {
+                break;
+            }
+            match self.get_char_and_advance() {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                'r' => string.push('\r'),
+                '0' => string.push('\0'),
+                '"' => string.push('"'),
+                '\\' => string.push('\\'),
+                other => {
+                    invalid_escape.get_or_insert_with(|| {
+                        scan_error(
+                            line,
+                            column,
+                            &format!("Invalid escape sequence '\\{}' in String literal", other),
+                        )
+                    });
+                }
             }
-            self.advance();
         }
         if self.is_at_end() {
+            self.unterminated = true;
             let l = &self.source[self.start..self.current];
             bail!(scan_error(
-                self.line,
+                self.start_line,
+                self.start_column,
                 &format!("Unterminated String literal {}", l)
             ))
         }
         // advance to convert the closing '"'
         self.advance();
-        // get the value from "[...]", excluding the '"'
-        let string = self.source[self.start + 1..self.current - 1].to_string();
+        if let Some(e) = invalid_escape {
+            bail!(e)
+        }
         self.add_token(TokenType::String, Literal::opt_string(string));
         Ok(())
     }
 
+    /// Skips a (possibly nested) `/* ... */` comment, the opening `/*` is already consumed
+    fn block_comment(&mut self) -> Result<()> {
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                self.unterminated = true;
+                bail!(scan_error(
+                    self.start_line,
+                    self.start_column,
+                    "Unterminated block comment"
+                ))
+            }
+            match self.get_char_and_advance() {
+                '/' if self.peek() == '*' => {
+                    self.advance();
+                    depth += 1;
+                }
+                '*' if self.peek() == '/' => {
+                    self.advance();
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     fn add_number(&mut self) -> Result<()> {
         while self.peek().is_ascii_digit() {
             self.advance();
         }
-        if self.peek() == '.' {
+        // `1..2` is a range, not the number `1.`
+        if self.peek() == '.' && !self.source.as_bytes()[self.current..].starts_with(b"..") {
             self.advance();
             while self.peek().is_ascii_digit() {
                 self.advance();
             }
         }
+        // Scientific notation, e.g. 1e-3 or 2.5E6 (an `e` not followed by the digits of the exponent is not part of it)
+        let exponent = match &self.source.as_bytes()[self.current..] {
+            [b'e' | b'E', digit, ..] if digit.is_ascii_digit() => 1,
+            [b'e' | b'E', b'+' | b'-', digit, ..] if digit.is_ascii_digit() => 2,
+            _ => 0,
+        };
+        if exponent > 0 {
+            for _ in 0..exponent {
+                self.advance();
+            }
+            while self.peek().is_ascii_digit() {
+                self.advance();
+            }
+        }
         let number_string = &self.source[self.start..self.current];
         if let Ok(number) = number_string.parse::<f64>() {
             self.add_token(TokenType::Number, Literal::opt_number(number))
         } else {
             bail!(scan_error(
-                self.line,
+                self.start_line,
+                self.start_column,
                 &format!("{} Not a valid number", number_string)
             ))
         }
@@ -171,7 +443,7 @@ impl Scanner {
     }
 
     fn add_identifier(&mut self) {
-        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
             self.advance();
         }
 
@@ -185,10 +457,23 @@ impl Scanner {
         }
     }
 
+    fn add_comment(&mut self) {
+        self.comments.push(Comment {
+            text: self.source[self.start..self.current].trim_end().into(),
+            line: self.start_line,
+            column: self.start_column,
+        })
+    }
+
     fn add_token(&mut self, token_type: TokenType, literal: Option<Literal>) {
         let lexeme = &self.source[self.start..self.current];
-        self.tokens
-            .push(Token::new(token_type, lexeme.into(), self.line, literal))
+        self.tokens.push(Token::new(
+            token_type,
+            lexeme.into(),
+            self.start_line,
+            self.start_column,
+            literal,
+        ))
     }
 
     fn get_char_and_advance(&mut self) -> char {
@@ -215,8 +500,10 @@ impl Scanner {
     }
 
     fn get_char(&self) -> char {
-        let v = &self.source[self.current..(self.current + 1)];
-        v.chars().next().expect("Character expected")
+        self.source[self.current..]
+            .chars()
+            .next()
+            .expect("Character expected")
     }
 
     fn peek(&self) -> char {
@@ -228,18 +515,28 @@ impl Scanner {
     }
 
     fn advance(&mut self) {
-        self.current += 1
+        let c = self.get_char();
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
     }
 }
 
-fn scan_error(line: usize, message: &str) -> ErrorKind {
-    ErrorKind::ScanError(format!("[line: {}] Error: message: {}", line, message))
+fn scan_error(line: usize, column: usize, message: &str) -> ErrorKind {
+    ErrorKind::ScanError(format!(
+        "[line: {}, column: {}] Error: message: {}",
+        line, column, message
+    ))
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::Scanner;
+    use super::{is_incomplete, rescan, LineEdit, Scanner};
     use crate::tokens::{Literal, Token, TokenType};
     use evie_common::errors::*;
 
@@ -252,21 +549,23 @@ mod tests {
         let mut scanner = Scanner::new(source.into());
         let mut tokens = scanner.scan_tokens()?;
         let expected = &[
-            Token::new(TokenType::Var, "var".into(), 1, None),
+            Token::new(TokenType::Var, "var".into(), 1, 1, None),
             Token::new(
                 TokenType::Identifier,
                 "language".into(),
                 1,
+                5,
                 Some(Literal::Identifier("language".into())),
             ),
-            Token::new(TokenType::Equal, "=".into(), 1, None),
+            Token::new(TokenType::Equal, "=".into(), 1, 14, None),
             Token::new(
                 TokenType::String,
                 "\"lox\"".into(),
                 1,
+                16,
                 Some(Literal::String("lox".into())),
             ),
-            Token::new(TokenType::Eof, "".into(), 1, None),
+            Token::new(TokenType::Eof, "".into(), 1, 21, None),
         ];
         assert_eq!(expected, tokens);
 
@@ -278,40 +577,50 @@ mod tests {
         scanner = Scanner::new(source.into());
         tokens = scanner.scan_tokens()?;
         let expected = &[
-            Token::new(TokenType::Var, "var".into(), 2, None),
+            Token::new(TokenType::Var, "var".into(), 2, 9, None),
             Token::new(
                 TokenType::Identifier,
                 "pi".into(),
                 2,
+                13,
                 Some(Literal::Identifier("pi".into())),
             ),
-            Token::new(TokenType::Equal, "=".into(), 2, None),
+            Token::new(TokenType::Equal, "=".into(), 2, 16, None),
             Token::new(
                 TokenType::Number,
                 "3.14".into(),
                 2,
+                18,
                 #[allow(clippy::approx_constant)]
                 Some(Literal::Number(3.14)),
             ),
-            Token::new(TokenType::Var, "var".into(), 4, None),
+            Token::new(TokenType::Var, "var".into(), 4, 9, None),
             Token::new(
                 TokenType::Identifier,
                 "two_pi".into(),
                 4,
+                13,
                 Some(Literal::Identifier("two_pi".into())),
             ),
-            Token::new(TokenType::Equal, "=".into(), 4, None),
-            Token::new(TokenType::LeftParen, "(".into(), 4, None),
+            Token::new(TokenType::Equal, "=".into(), 4, 20, None),
+            Token::new(TokenType::LeftParen, "(".into(), 4, 22, None),
             Token::new(
                 TokenType::Identifier,
                 "pi".into(),
                 4,
+                23,
                 Some(Literal::Identifier("pi".into())),
             ),
-            Token::new(TokenType::RightParen, ")".into(), 4, None),
-            Token::new(TokenType::Star, "*".into(), 4, None),
-            Token::new(TokenType::Number, "2".into(), 4, Some(Literal::Number(2.0))),
-            Token::new(TokenType::Eof, "".into(), 5, None),
+            Token::new(TokenType::RightParen, ")".into(), 4, 25, None),
+            Token::new(TokenType::Star, "*".into(), 4, 27, None),
+            Token::new(
+                TokenType::Number,
+                "2".into(),
+                4,
+                29,
+                Some(Literal::Number(2.0)),
+            ),
+            Token::new(TokenType::Eof, "".into(), 5, 9, None),
         ];
         assert_eq!(expected, tokens);
 
@@ -320,16 +629,357 @@ mod tests {
         tokens = scanner.scan_tokens()?;
         assert_eq!(
             &[
-                Token::new(TokenType::Number, "5".into(), 1, Literal::opt_number(5.0)),
-                Token::new(TokenType::Slash, "/".into(), 1, None),
-                Token::new(TokenType::Number, "5".into(), 1, Literal::opt_number(5.0)),
-                Token::new(TokenType::EqualEqual, "==".into(), 1, None),
-                Token::new(TokenType::Number, "1".into(), 1, Literal::opt_number(1.0)),
-                Token::new(TokenType::Semicolon, ";".into(), 1, Literal::opt_none()),
-                Token::new(TokenType::Eof, "".into(), 1, None)
+                Token::new(
+                    TokenType::Number,
+                    "5".into(),
+                    1,
+                    1,
+                    Literal::opt_number(5.0)
+                ),
+                Token::new(TokenType::Slash, "/".into(), 1, 2, None),
+                Token::new(
+                    TokenType::Number,
+                    "5".into(),
+                    1,
+                    3,
+                    Literal::opt_number(5.0)
+                ),
+                Token::new(TokenType::EqualEqual, "==".into(), 1, 5, None),
+                Token::new(
+                    TokenType::Number,
+                    "1".into(),
+                    1,
+                    7,
+                    Literal::opt_number(1.0)
+                ),
+                Token::new(TokenType::Semicolon, ";".into(), 1, 8, Literal::opt_none()),
+                Token::new(TokenType::Eof, "".into(), 1, 9, None)
             ],
             tokens
         );
         Ok(())
     }
+
+    #[test]
+    fn scanner_handles_unicode() -> Result<()> {
+        let source = "var café = \"naïve ☕\";\n  print café;";
+        let mut scanner = Scanner::new(source.into());
+        let tokens = scanner.scan_tokens()?;
+        assert_eq!(
+            &[
+                Token::new(TokenType::Var, "var".into(), 1, 1, None),
+                Token::new(
+                    TokenType::Identifier,
+                    "café".into(),
+                    1,
+                    5,
+                    Some(Literal::Identifier("café".into())),
+                ),
+                Token::new(TokenType::Equal, "=".into(), 1, 10, None),
+                Token::new(
+                    TokenType::String,
+                    "\"naïve ☕\"".into(),
+                    1,
+                    12,
+                    Some(Literal::String("naïve ☕".into())),
+                ),
+                Token::new(TokenType::Semicolon, ";".into(), 1, 21, None),
+                Token::new(TokenType::Print, "print".into(), 2, 3, None),
+                Token::new(
+                    TokenType::Identifier,
+                    "café".into(),
+                    2,
+                    9,
+                    Some(Literal::Identifier("café".into())),
+                ),
+                Token::new(TokenType::Semicolon, ";".into(), 2, 13, None),
+                Token::new(TokenType::Eof, "".into(), 2, 14, None),
+            ],
+            tokens
+        );
+
+        let mut scanner = Scanner::new("var ☕ = 1;".into());
+        assert!(scanner.scan_tokens().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scanner_unescapes_strings() -> Result<()> {
+        let source = r#"print "a\tb\n\"quoted\" \\ end";"#;
+        let mut scanner = Scanner::new(source.into());
+        let tokens = scanner.scan_tokens()?;
+        assert_eq!(
+            Some(Literal::String("a\tb\n\"quoted\" \\ end".into())),
+            tokens[1].literal
+        );
+        assert_eq!(TokenType::Semicolon, tokens[2].token_type);
+
+        let mut scanner = Scanner::new(r#""ok\q" + 1"#.into());
+        let error = scanner.scan_token().unwrap_err();
+        assert_eq!(
+            "Scan Error: [line: 1, column: 4] Error: message: Invalid escape sequence '\\q' in String literal",
+            error.to_string()
+        );
+        // The rest of the literal is consumed, scanning continues after it
+        assert_eq!(
+            '"',
+            scanner.source[..scanner.current].chars().last().unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scanner_skips_shebang() -> Result<()> {
+        let mut scanner = Scanner::new("#!/usr/bin/env evie\nprint 1;".into());
+        let tokens = scanner.scan_tokens()?;
+        assert_eq!(TokenType::Print, tokens[0].token_type);
+        assert_eq!((2, 1), (tokens[0].line, tokens[0].column));
+        assert_eq!("#!/usr/bin/env evie", scanner.comments()[0].text);
+        // Only on the first line
+        let mut scanner = Scanner::new("print 1;\n#!/usr/bin/env evie".into());
+        assert!(scanner.scan_tokens().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scanner_bitwise_operators() -> Result<()> {
+        let mut scanner = Scanner::new("a & b | c ^ d << 1 >> 2 <= 3 >= 4 < >".into());
+        let tokens = scanner.scan_tokens()?;
+        let token_types: Vec<TokenType> = tokens
+            .iter()
+            .map(|t| t.token_type)
+            .filter(|t| !matches!(t, TokenType::Identifier | TokenType::Number))
+            .collect();
+        assert_eq!(
+            vec![
+                TokenType::Ampersand,
+                TokenType::Pipe,
+                TokenType::Caret,
+                TokenType::LessLess,
+                TokenType::GreaterGreater,
+                TokenType::LessEqual,
+                TokenType::GreaterEqual,
+                TokenType::Less,
+                TokenType::Greater,
+                TokenType::Eof
+            ],
+            token_types
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scanner_scientific_notation() -> Result<()> {
+        let mut scanner = Scanner::new("1e-3 2.5E6 1e+2 3e 2**10".into());
+        let tokens = scanner.scan_tokens()?;
+        let numbers: Vec<f64> = tokens
+            .iter()
+            .filter_map(|t| match t.literal {
+                Some(Literal::Number(n)) => Some(n),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![0.001, 2.5e6, 100.0, 3.0, 2.0, 10.0], numbers);
+        // `3e` is the number 3 and the identifier e
+        assert_eq!(TokenType::Identifier, tokens[4].token_type);
+        assert_eq!(TokenType::StarStar, tokens[6].token_type);
+        Ok(())
+    }
+
+    #[test]
+    fn scanner_ranges() -> Result<()> {
+        let mut scanner = Scanner::new("1..2 0..=1.5 a.b".into());
+        let tokens = scanner.scan_tokens()?;
+        let token_types: Vec<TokenType> = tokens.iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Number,
+                TokenType::DotDot,
+                TokenType::Number,
+                TokenType::Number,
+                TokenType::DotDotEqual,
+                TokenType::Number,
+                TokenType::Identifier,
+                TokenType::Dot,
+                TokenType::Identifier,
+                TokenType::Eof
+            ],
+            token_types
+        );
+        assert_eq!(Some(Literal::Number(1.5)), tokens[5].literal);
+        Ok(())
+    }
+
+    #[test]
+    fn incomplete_sources() {
+        for source in [
+            "fun f() {",
+            "class A { m() { print 1; }",
+            "print (1 +",
+            "var s = \"a string\nspanning lines",
+            "/* a comment",
+            "while (true) { print \"}\";",
+        ] {
+            assert!(is_incomplete(source), "{}", source);
+        }
+        for source in [
+            "print 1",
+            "fun f() { return (1); }",
+            "print 1; }",
+            "// {",
+            "print \"{\";",
+            "print 1 $ 2;",
+        ] {
+            assert!(!is_incomplete(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn scanner_skips_block_comments() -> Result<()> {
+        let source = "var /* a /* nested */ comment\n spanning lines */ a = 1 /**/ / 2;";
+        let mut scanner = Scanner::new(source.into());
+        let tokens = scanner.scan_tokens()?;
+        let token_types: Vec<TokenType> = tokens.iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            vec![
+                TokenType::Var,
+                TokenType::Identifier,
+                TokenType::Equal,
+                TokenType::Number,
+                TokenType::Slash,
+                TokenType::Number,
+                TokenType::Semicolon,
+                TokenType::Eof
+            ],
+            token_types
+        );
+        assert_eq!((2, 20), (tokens[1].line, tokens[1].column));
+        let comments: Vec<(&str, usize, usize)> = scanner
+            .comments()
+            .iter()
+            .map(|c| (c.text.as_str(), c.line, c.column))
+            .collect();
+        assert_eq!(
+            vec![
+                ("/* a /* nested */ comment\n spanning lines */", 1, 5),
+                ("/**/", 2, 26)
+            ],
+            comments
+        );
+
+        let mut scanner = Scanner::new("/* outer /* inner */ ".into());
+        let error = scanner.scan_token().unwrap_err();
+        assert_eq!(
+            "Scan Error: [line: 1, column: 1] Error: message: Unterminated block comment",
+            error.to_string()
+        );
+        Ok(())
+    }
+
+    fn scan(source: &str) -> Result<Vec<Token>> {
+        Scanner::new(source.to_string())
+            .scan_tokens()
+            .map(<[_]>::to_vec)
+    }
+
+    #[test]
+    fn rescans_the_edited_lines() -> Result<()> {
+        let old = "var a = 1;\nvar b = \"two\nlines\";\nprint a + b; // */\n";
+        let new = "var a = 1;\nvar b = 2; var c = 3;\n\nprint a + b; // */\n";
+        let edit = LineEdit::between(old, new);
+        assert_eq!(
+            LineEdit {
+                start: 2,
+                old_end: 3,
+                new_end: 3
+            },
+            edit
+        );
+        let tokens = rescan(&scan(old)?, new.to_string(), edit)?;
+        assert_eq!(scan(new)?, tokens);
+        assert_eq!((4, 1), (tokens[15].line, tokens[15].column));
+        // An edit that opens a comment (or a string) changes the tokens up to where it ends
+        let new = "var a = 1; /*\nvar b = \"two\nlines\";\nprint a + b; // */\n";
+        let edit = LineEdit::between(old, new);
+        let tokens = rescan(&scan(old)?, new.to_string(), edit)?;
+        assert_eq!(scan(new)?, tokens);
+        assert_eq!(6, tokens.len());
+        let new = "var a = 1;\nvar b = \"two\nlines;\nprint a + b; // */\n";
+        let edit = LineEdit::between(old, new);
+        assert!(rescan(&scan(old)?, new.to_string(), edit).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rescan_is_a_full_scan() -> Result<()> {
+        const PIECES: &[&str] = &[
+            "var x = 1;",
+            "\n",
+            " ",
+            "\"",
+            "/*",
+            "*/",
+            "//",
+            "1.5e3",
+            "..",
+            "é",
+            "(",
+            "}",
+            "fun f() {",
+            "\"a\\\"b\"",
+            "return x;",
+            "=",
+            "==",
+            "#",
+            "1.",
+            "e+",
+        ];
+        let sources = [
+            "var a = 1;\n{\n  print a;\n}\n",
+            "fun f(x) {\n  /* a\n  comment */ return \"a\nstring\";\n}\nprint f(1) + 2.5e-3;\n",
+            "// héllo\nvar é = 0..=10;\nfor (i in 0..3) print i * é;",
+            "#!/usr/bin/env evie\nprint 1;\n\n\nprint 2;",
+        ];
+        // xorshift, a seed reproduces the edits
+        let mut seed = 0x2545F4914F6CDD1Du64;
+        let mut below = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+        for _ in 0..2000 {
+            let mut source = sources[below(sources.len())].to_string();
+            let mut tokens = scan(&source)?;
+            for _ in 0..4 {
+                let mut lines: Vec<String> =
+                    source.split_inclusive('\n').map(str::to_string).collect();
+                let start = below(lines.len() + 1);
+                let end = (start + below(3)).min(lines.len());
+                let inserted: String = (0..below(6)).map(|_| PIECES[below(PIECES.len())]).collect();
+                lines.splice(
+                    start..end,
+                    inserted.split_inclusive('\n').map(str::to_string),
+                );
+                let new = lines.concat();
+                let edit = LineEdit::between(&source, &new);
+                let rescanned = rescan(&tokens, new.clone(), edit);
+                match (scan(&new), rescanned) {
+                    (Ok(expected), Ok(rescanned)) => {
+                        assert_eq!(expected, rescanned, "{:?} -> {:?}", source, new);
+                        (source, tokens) = (new, rescanned);
+                    }
+                    (Err(_), Err(_)) => break,
+                    (expected, rescanned) => panic!(
+                        "{:?} -> {:?}: {:?} != {:?}",
+                        source,
+                        new,
+                        expected.is_ok(),
+                        rescanned.is_ok()
+                    ),
+                }
+            }
+        }
+        Ok(())
+    }
 }
@@ -14,11 +14,19 @@ pub enum TokenType {
     RightBrace,
     Comma,
     Dot,
+    DotDot,
+    DotDotEqual,
     Minus,
     Plus,
     Semicolon,
     Slash,
     Star,
+    StarStar,
+    Question,
+    Colon,
+    Ampersand,
+    Pipe,
+    Caret,
 
     // One or two character tokens.
     Bang,
@@ -27,8 +35,10 @@ pub enum TokenType {
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
 
     // Literals.
     Identifier,
@@ -38,20 +48,25 @@ pub enum TokenType {
     // Keywords.
     And,
     Class,
+    Const,
     Else,
     False,
     Fun,
     For,
     If,
+    In,
+    Is,
     Nil,
     Or,
     Print,
     Return,
+    Static,
     Super,
     This,
     True,
     Var,
     While,
+    Yield,
     // Special end of file keyword
     Eof,
 }
@@ -108,6 +123,8 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: usize,
+    /// The column (in characters, starting at 1) where the token starts
+    pub column: usize,
     pub literal: Option<Literal>,
 }
 
@@ -116,17 +133,55 @@ impl Token {
         token_type: TokenType,
         lexeme: String,
         line: usize,
+        column: usize,
         literal: Option<Literal>,
     ) -> Self {
         Token {
             token_type,
             lexeme,
             line,
+            column,
             literal,
         }
     }
 }
 
+impl Token {
+    /// Where this token is in the source
+    pub fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            length: self.lexeme.chars().count(),
+        }
+    }
+}
+
+/// Where a token (or a name) appears in the source, lines and columns start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    /// The length in characters
+    pub length: usize,
+}
+
+impl Span {
+    /// Returns true if the position is within this span
+    pub fn contains(&self, line: usize, column: usize) -> bool {
+        self.line == line && column >= self.column && column < self.column + self.length
+    }
+}
+
+/// A `//` or `/* */` comment, the scanner does not produce tokens for them but keeps them for tooling
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    /// The text of the comment, including the `//` or `/* */`
+    pub text: String,
+    pub line: usize,
+    pub column: usize,
+}
+
 impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
@@ -0,0 +1,456 @@
+//! A fuzzer for the scanner, the compiler and the VM (`cargo run -p evie_fuzz -- [iterations] [seed]`).
+//!
+//! It has no dependencies: the inputs come from a seeded [Random], so a seed reproduces a whole run.
+//! - [Fuzzer::fuzz_source] mutates a script of the corpus (deletes, duplicates, swaps and inserts tokens) and runs it
+//!   through the [Scanner], the [Compiler] and the [VirtualMachine].
+//! - [Fuzzer::fuzz_bytecode] mutates the bytes of a compiled script, or generates random code, and runs the
+//!   chunks the verifier accepts. Whatever passes [verify] must be safe to run.
+//!
+//! Errors are expected, a panic is a [Crash]. Every run is capped (instructions and heap) so that any input ends, and
+//! the natives that reach the host (files, the time, sleeping) are not defined.
+use std::{
+    fmt::Display,
+    io,
+    panic::{self, AssertUnwindSafe},
+};
+
+use evie_compiler::compiler::Compiler;
+use evie_frontend::scanner::Scanner;
+use evie_instructions::{opcodes::Opcode, verifier::verify};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{
+    chunk::Chunk,
+    objects::{Closure, GCObjectOf, Object, ObjectType, UserDefinedFunction},
+    runtime::{EvieRuntime, Random},
+};
+use evie_vm::vm::{define_native_fn, VirtualMachine};
+
+/// The instructions a fuzzed script may run
+const MAX_INSTRUCTIONS: u64 = 100_000;
+/// The bytes the objects of a fuzzed script may take
+const MAX_HEAP_BYTES: usize = 16 * 1024 * 1024;
+
+/// The scripts the inputs are mutated from
+const CORPUS: [&str; 9] = [
+    "var a = 1; var b = a + 2 * 3 - 4 / 5; print a ** b; print -a << 2 >> 1 & 7 | 8 ^ 3;",
+    "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(10);",
+    "fun counter() { var i = 0; fun inc() { i = i + 1; return i; } return inc; } var c = counter(); c(); print c();",
+    "class A { \"An A\" init(x) { \"Sets x\"; this.x = x; } get() { return this.x + 1; } static make() { return A(1); } }
+     var a = A(2); a.y = a.get(); print a.y; print A.make() is A; print a.get; print doc(A) + doc(a.init);",
+    "var s = 0; for (i in 0..10) { if (i == 5) s = s + i; else s = s - 1; } for (j in 0..=3) print j; print s;",
+    "var t = \"a\" + \"b\"; while (t != \"abbb\") { t = t + \"b\"; } print t.length(); print to_string(1.5) + type(nil);",
+    "const LIMIT = 3; var x = nil; var y = x or true and !false; print y ? LIMIT : -LIMIT;",
+    "fun f(a, b, c) { var l = a; { var inner = c; return inner(l); } } print f(1, 2, to_string); print f(nil, 2, type);",
+    "fun gen(n) { var k = 0; fun add() { k = k + 1; } for (i in 0..n) { add(); var r = yield i * k; } return k; }
+     var g = coroutine(gen); print g.resume(3); for (x in g) print x; print g.is_done();",
+];
+
+/// A script of the corpus with one constant less than a chunk can index, the mutations go over the limit: the name
+/// and the value of `x`, 252 numbers and the name again
+fn near_the_constant_limit() -> String {
+    let prints: String = (0..252).map(|i| format!("print {}; ", i)).collect();
+    format!("var x = 1; {}print x;", prints)
+}
+
+/// The tokens inserted by the mutations, the keywords and operators of evie and some values at the edges
+const VOCABULARY: [&str; 47] = [
+    "(", ")", "{", "}", ",", ".", "..", "..=", "-", "+", ";", "/", "*", "**", "?", ":", "&", "|",
+    "^", "!", "!=", "=", "==", ">", ">=", ">>", "<", "<=", "<<", "and", "class", "const", "else",
+    "fun", "for", "if", "in", "is", "nil", "return", "static", "super", "this", "var", "while",
+    "print", "yield",
+];
+
+/// The values inserted by the mutations
+const VALUES: [&str; 12] = [
+    "0", "-1", "1.5", "1e308", "x", "init", "\"\"", "\"s\"", "\"", "\u{e9}", "true", "f(",
+];
+
+/// The stage that panicked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Scanner,
+    Compiler,
+    Vm,
+    /// The VM running bytecode that passed [verify]
+    Bytecode,
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self))
+    }
+}
+
+/// An input that made the [Target] panic
+#[derive(Debug, Clone)]
+pub struct Crash {
+    pub target: Target,
+    /// The source, or the bytecode (and how it was made), that reproduces the crash
+    pub input: String,
+    /// The message of the panic
+    pub message: String,
+}
+
+impl Display for Crash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} panicked: {}\n--- input ---\n{}\n-------------",
+            self.target, self.message, self.input
+        ))
+    }
+}
+
+/// Generates the inputs, see the [module docs](self)
+pub struct Fuzzer {
+    random: Random,
+    corpus: Vec<String>,
+}
+
+impl Fuzzer {
+    /// A fuzzer with the default corpus
+    pub fn new(seed: u64) -> Self {
+        let mut corpus: Vec<String> = CORPUS.iter().map(|s| s.to_string()).collect();
+        corpus.push(near_the_constant_limit());
+        Fuzzer::with_corpus(seed, corpus)
+    }
+
+    /// A fuzzer that mutates the given scripts (e.g. the benchmarks), it must not be empty
+    pub fn with_corpus(seed: u64, corpus: Vec<String>) -> Self {
+        assert!(!corpus.is_empty(), "The corpus is empty");
+        Fuzzer {
+            random: Random::new(seed),
+            corpus,
+        }
+    }
+
+    /// Runs `iterations` inputs, every other one is bytecode. Returns the crashes
+    pub fn run(&mut self, iterations: usize) -> Vec<Crash> {
+        (0..iterations)
+            .filter_map(|i| {
+                if i % 2 == 0 {
+                    self.fuzz_source()
+                } else {
+                    self.fuzz_bytecode()
+                }
+            })
+            .collect()
+    }
+
+    /// Runs a mutated script of the corpus, see [run_source]
+    pub fn fuzz_source(&mut self) -> Option<Crash> {
+        let source = self.mutate_source();
+        run_source(&source)
+    }
+
+    /// A script of the corpus with a few mutations
+    pub fn mutate_source(&mut self) -> String {
+        let source = self.pick_source();
+        let mut pieces = split(&source);
+        for _ in 0..=self.below(4) {
+            let at = self.below(pieces.len() + 1);
+            match self.below(7) {
+                0 if at < pieces.len() => {
+                    pieces.remove(at);
+                }
+                1 if at < pieces.len() => {
+                    let piece = pieces[at].clone();
+                    let to = self.below(pieces.len());
+                    pieces.insert(to, piece);
+                }
+                2 if at < pieces.len() => {
+                    let other = self.below(pieces.len());
+                    pieces.swap(at, other);
+                }
+                3 => {
+                    let token = VOCABULARY[self.below(VOCABULARY.len())];
+                    pieces.insert(at, format!(" {} ", token));
+                }
+                4 => {
+                    let value = VALUES[self.below(VALUES.len())];
+                    pieces.insert(at, value.to_string());
+                }
+                // Deep nesting, e.g. of parenthesis or blocks
+                5 => {
+                    let token = ["(", "{", "-", "!", "f("][self.below(5)];
+                    let repeat = 1 + self.below(300);
+                    pieces.insert(at, token.repeat(repeat));
+                }
+                _ => {
+                    let c = char::from_u32(self.below(0x250) as u32).unwrap_or('?');
+                    pieces.insert(at, c.to_string());
+                }
+            }
+        }
+        pieces.concat()
+    }
+
+    /// Runs bytecode that passed [verify]: a compiled script of the corpus with a few bytes changed, or random code
+    pub fn fuzz_bytecode(&mut self) -> Option<Crash> {
+        let mut output = io::sink();
+        let mut vm = new_vm(&mut output);
+        let (function, input) = if self.below(2) == 0 {
+            self.mutate_bytecode(vm.runtime())?
+        } else {
+            self.random_bytecode(vm.runtime())
+        };
+        if verify(function.chunk, "fuzz").is_err() {
+            return None;
+        }
+        let allocator = vm.runtime().allocator();
+        let closure = allocator.alloc(Closure::new(function, allocator.alloc(Vec::new())));
+        let closure = Value::object(Object::new_gc_object(
+            ObjectType::Closure(closure),
+            allocator,
+        ));
+        vm.runtime().define_global("fuzz", closure);
+        let crash = catch(Target::Bytecode, &input, || {
+            let _ = vm.call("fuzz", &[]);
+        });
+        vm.free();
+        crash
+    }
+
+    /// Compiles a script of the corpus and changes a few bytes of its code (or of the functions in it)
+    fn mutate_bytecode(
+        &mut self,
+        runtime: &mut EvieRuntime,
+    ) -> Option<(GCObjectOf<UserDefinedFunction>, String)> {
+        let source = self.pick_source();
+        let mut scanner = Scanner::new(source.clone());
+        let tokens = scanner.scan_tokens().ok()?;
+        let compiler = Compiler::new(tokens, runtime.allocator());
+        let (function, _) = compiler.compile_with_warnings().ok()?;
+        let mut chunks = vec![function.chunk];
+        let mut i = 0;
+        while i < chunks.len() {
+            let chunk = chunks[i];
+            for value in chunk.constants.inner.iter() {
+                if value.is_object() {
+                    if let ObjectType::Function(function) = value.as_object().object_type {
+                        chunks.push(function.chunk);
+                    }
+                }
+            }
+            i += 1;
+        }
+        let mut edits = vec![];
+        for _ in 0..=self.below(3) {
+            let index = self.below(chunks.len());
+            let mut chunk = chunks[index];
+            if chunk.code.inner.is_empty() {
+                continue;
+            }
+            let offset = self.below(chunk.code.inner.len());
+            // Small bytes are more likely to be valid operands (and opcodes)
+            let byte = if self.below(2) == 0 {
+                self.below(8)
+            } else {
+                self.below(256)
+            } as u8;
+            chunk.code.inner[offset] = byte;
+            edits.push((index, offset, byte));
+        }
+        let input = format!("{}\n// the bytes changed (chunk, offset, byte), the chunks are numbered breadth first from the script: {:?}", source, edits);
+        Some((function, input))
+    }
+
+    /// A function with random code that returns nil, its constants are a number, a string and nil
+    fn random_bytecode(
+        &mut self,
+        runtime: &mut EvieRuntime,
+    ) -> (GCObjectOf<UserDefinedFunction>, String) {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::number(1.0));
+        chunk.add_constant(runtime.alloc_string("fuzz"));
+        chunk.add_constant(Value::nil());
+        let last_opcode = u8::from(Opcode::Doc) as usize;
+        for _ in 0..self.below(24) {
+            chunk.write_chunk(self.below(last_opcode + 1) as u8, 1);
+            for _ in 0..self.below(3) {
+                chunk.write_chunk(self.below(3) as u8, 1);
+            }
+        }
+        chunk.write_chunk(Opcode::Nil.into(), 1);
+        chunk.write_chunk(Opcode::Return.into(), 1);
+        let input = format!("random code: {:?}", chunk.code.inner);
+        let allocator = runtime.allocator();
+        let name = allocator.alloc_interned_str("fuzz");
+        let function = UserDefinedFunction::new(Some(name), allocator.alloc(chunk), 0, 0);
+        (allocator.alloc(function), input)
+    }
+
+    fn pick_source(&mut self) -> String {
+        let i = self.below(self.corpus.len());
+        self.corpus[i].clone()
+    }
+
+    /// A number in [0, n)
+    fn below(&mut self, n: usize) -> usize {
+        (self.random.next_u64() % n.max(1) as u64) as usize
+    }
+}
+
+/// Scans, compiles and runs the source, returns the stage that panicked (if any)
+pub fn run_source(source: &str) -> Option<Crash> {
+    let tokens = catch_value(Target::Scanner, source, || {
+        Scanner::new(source.to_string())
+            .scan_tokens()
+            .ok()
+            .map(<[_]>::to_vec)
+    });
+    let tokens = match tokens {
+        Ok(Some(tokens)) => tokens,
+        Ok(None) => return None,
+        Err(crash) => return Some(crash),
+    };
+    let compiled = catch_value(Target::Compiler, source, || {
+        let runtime = EvieRuntime::new();
+        let compiler = Compiler::new(&tokens, runtime.allocator());
+        compiler.compile_with_warnings().is_ok()
+    });
+    match compiled {
+        Ok(true) => {}
+        Ok(false) => return None,
+        Err(crash) => return Some(crash),
+    }
+    let mut output = io::sink();
+    let mut vm = new_vm(&mut output);
+    let crash = catch(Target::Vm, source, || {
+        let _ = vm.interpret(source.to_string(), None);
+    });
+    vm.free();
+    crash
+}
+
+/// A VM with the natives that do not reach the host, that reads an empty input and caps the runs
+fn new_vm(output: &mut (dyn io::Write + Send)) -> VirtualMachine<'_> {
+    let mut vm =
+        VirtualMachine::new_with_reader_and_writer(Some(Box::new(io::empty())), Some(output));
+    let natives = evie_native::natives()
+        .into_iter()
+        .chain(evie_native::assert::natives())
+        .chain(evie_native::coroutine::natives())
+        .chain(evie_native::function::natives())
+        .chain(evie_native::gc::natives())
+        .chain(evie_native::io::natives())
+        .chain(evie_native::json::natives())
+        .chain(evie_native::math::natives())
+        .chain(evie_native::object::natives())
+        .chain(evie_native::pattern::natives())
+        .chain(evie_native::random::natives());
+    for (name, arity, native_fn) in natives {
+        define_native_fn(name, arity, &mut vm, native_fn);
+    }
+    vm.set_max_instructions(Some(MAX_INSTRUCTIONS));
+    vm.set_max_heap_bytes(Some(MAX_HEAP_BYTES));
+    vm
+}
+
+/// Splits the source into words, runs of white space and single characters, the pieces the mutations work on
+fn split(source: &str) -> Vec<String> {
+    let mut pieces: Vec<String> = vec![];
+    let mut last_kind = None;
+    for c in source.chars() {
+        let kind = if c.is_alphanumeric() || c == '_' {
+            Some(0)
+        } else if c.is_whitespace() {
+            Some(1)
+        } else {
+            None
+        };
+        match pieces.last_mut() {
+            Some(piece) if kind.is_some() && kind == last_kind => piece.push(c),
+            _ => pieces.push(c.to_string()),
+        }
+        last_kind = kind;
+    }
+    pieces
+}
+
+/// Runs `f`, a panic is a [Crash] of the target for the input
+fn catch<F: FnOnce()>(target: Target, input: &str, f: F) -> Option<Crash> {
+    catch_value(target, input, f).err()
+}
+
+/// Runs `f`, returns its value or the [Crash] if it panicked
+fn catch_value<T, F: FnOnce() -> T>(
+    target: Target,
+    input: &str,
+    f: F,
+) -> std::result::Result<T, Crash> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Crash {
+            target,
+            input: input.to_string(),
+            message,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_common::errors::*;
+    use evie_memory::ObjectAllocator;
+
+    use super::{near_the_constant_limit, run_source, split, Compiler, Fuzzer, Scanner};
+
+    #[test]
+    fn fuzz_finds_no_crashes() {
+        let crashes = Fuzzer::new(42).run(1000);
+        assert!(
+            crashes.is_empty(),
+            "{}",
+            crashes
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+
+    #[test]
+    fn fuzz_is_reproducible() {
+        let mut first = Fuzzer::new(7);
+        let mut second = Fuzzer::new(7);
+        for _ in 0..20 {
+            assert_eq!(first.mutate_source(), second.mutate_source());
+        }
+    }
+
+    #[test]
+    fn fuzz_mutations_keep_the_rest_of_the_source() {
+        assert_eq!(
+            vec!["var", " ", "a_1", " ", "=", " ", "\"", "x", "\"", ";"],
+            split("var a_1 = \"x\";")
+        );
+        assert!(run_source("var a = 1; print a;").is_none());
+    }
+
+    #[test]
+    fn fuzz_corpus_reaches_the_constant_limit() {
+        let source = near_the_constant_limit();
+        let allocator = ObjectAllocator::new();
+        let compile = |source: &str| -> Result<()> {
+            let tokens = Scanner::new(source.to_string()).scan_tokens()?.to_vec();
+            Compiler::new(&tokens, &allocator).compile().map(|_| ())
+        };
+        assert!(compile(&source.replace("print x;", "print 252; print x;")).is_ok());
+        let error =
+            compile(&source.replace("print x;", "print 252; print 253; print x;")).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Too many constants in one chunk"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::panic;
+
+use evie_fuzz::Fuzzer;
+
+/// `evie_fuzz [iterations] [seed]`, prints the crashes and exits with 1 if there are any.
+/// Without a seed one is taken from the time, it is printed so that the run can be reproduced
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let parsed: Option<(usize, Option<u64>)> = match args.as_slice() {
+        [] => Some((10_000, None)),
+        [iterations] => iterations.parse().ok().map(|i| (i, None)),
+        [iterations, seed] => iterations.parse().ok().zip(seed.parse().ok().map(Some)),
+        _ => None,
+    };
+    let (iterations, seed) = match parsed {
+        Some(parsed) => parsed,
+        None => {
+            eprintln!("Usage: evie_fuzz [iterations] [seed]");
+            std::process::exit(2);
+        }
+    };
+    let seed = seed.unwrap_or_else(|| evie_memory::runtime::Random::from_time().next_u64());
+    println!("Fuzzing {} inputs with seed {}", iterations, seed);
+    // The crashes are reported once, with their input
+    panic::set_hook(Box::new(|_| {}));
+    let crashes = Fuzzer::new(seed).run(iterations);
+    let _ = panic::take_hook();
+    for crash in &crashes {
+        println!("{}", crash);
+    }
+    if crashes.is_empty() {
+        println!("No crashes");
+    } else {
+        println!("{} crashes", crashes.len());
+        std::process::exit(1);
+    }
+}
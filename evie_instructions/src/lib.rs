@@ -1,3 +1,4 @@
 //! The instructions for the Evie virtual Machine
 //! See [opcodes] for the full list.
 pub mod opcodes;
+pub mod verifier;
@@ -1,6 +1,6 @@
 use std::{convert::TryFrom, fmt::Display, io::Write};
 
-use evie_common::ByteUnit;
+use evie_common::{bail, errors::*, ByteUnit};
 use evie_memory::{chunk::Chunk, objects::ObjectType};
 
 #[cfg(feature = "nan_boxed")]
@@ -88,11 +88,60 @@ pub enum Opcode {
     Method,
     /// Invokes a Class method
     Invoke,
+    /// Defines a static Class method
+    StaticMethod,
+    /// `value is Class`, true if the value is an instance of the class
+    Is,
+    /// Superinstruction for [Opcode::Constant] followed by [Opcode::Add], adds the constant to the top of the stack
+    AddConstant,
+    /// Superinstruction for two [Opcode::GetLocal]s followed by [Opcode::Add], pushes the sum of the two locals
+    AddLocals,
+    /// Superinstruction for a comparison ([Opcode::Less], [Opcode::EqualEqual] etc) followed by [Opcode::JumpIfFalse].
+    /// Pushes the result of the comparison (the operand) and jumps if it is false
+    CompareJumpIfFalse,
+    /// `&`, the bitwise operators work on the numbers truncated to 64 bit integers (see [Opcode::ShiftLeft])
+    BitAnd,
+    /// `|`
+    BitOr,
+    /// `^`
+    BitXor,
+    /// `<<`, the numbers are truncated (towards zero) to 64 bit integers and the result converted back.
+    /// The shift count is taken modulo 64
+    ShiftLeft,
+    /// `>>`, an arithmetic shift (the sign is kept)
+    ShiftRight,
+    /// `**`, exponentiation
+    Power,
+    /// Jumps if the top of the stack is nil (without popping it), the end of a `for (x in iterable)` loop
+    JumpIfNil,
+    /// `start..end`, a [evie_memory::objects::Range] of the two numbers on the stack
+    Range,
+    /// `start..=end`
+    RangeInclusive,
+    /// `const NAME = value;`, operands: the name (constant) and the slot of the constant.
+    /// Defines the global and stores the value in the slot of the runtime's global constants
+    DefineGlobalConstant,
+    /// Pushes the global constant in the slot (operand), read without looking up the name
+    GetGlobalConstant,
+    /// `yield value`: suspends the running coroutine, the value (popped) is the result of the resume that ran it.
+    /// Pushes the value the coroutine is resumed with
+    Yield,
+    /// Sets the docstring (constant) of the class on top of the stack
+    Doc,
 }
 
-impl From<u8> for Opcode {
-    fn from(byte: u8) -> Self {
-        unsafe { std::mem::transmute::<u8, Opcode>(byte) }
+/// The last opcode, every byte up to it is a valid [Opcode] (keep it up to date when adding one)
+const LAST_OPCODE: Opcode = Opcode::Doc;
+
+impl TryFrom<u8> for Opcode {
+    type Error = Error;
+
+    fn try_from(byte: u8) -> Result<Self> {
+        if byte > LAST_OPCODE as u8 {
+            bail!(format!("Invalid opcode {}", byte))
+        }
+        // SAFETY: Opcode is repr(u8) with consecutive discriminants starting at 0, the byte is in range
+        Ok(unsafe { std::mem::transmute::<u8, Opcode>(byte) })
     }
 }
 
@@ -109,7 +158,7 @@ impl Display for Opcode {
 }
 
 pub fn simple_instruction(instruction: &Opcode, offset: usize, writer: &mut dyn Write) -> usize {
-    writeln!(writer, "{}", instruction.to_string()).expect("Write failed");
+    writeln!(writer, "{}", instruction).expect("Write failed");
     offset + 1
 }
 
@@ -124,7 +173,7 @@ pub fn constant_instruction(
     if pretty {
         write!(writer, "{:<30} {:4} '", instruction.to_string(), constant).expect("Write failed");
     } else {
-        write!(writer, "{} {:4} '", instruction.to_string(), constant).expect("Write failed");
+        write!(writer, "{} {:4} '", instruction, constant).expect("Write failed");
     }
     print_value(chunk.constants.read_item_at(constant as usize), writer);
     writeln!(writer, "'").expect("Write failed");
@@ -142,7 +191,7 @@ pub fn byte_instruction(
     if pretty {
         writeln!(writer, "{:<30} {:4}", instruction.to_string(), slot).expect("Write failed");
     } else {
-        writeln!(writer, "{} {:4}", instruction.to_string(), slot).expect("Write failed");
+        writeln!(writer, "{} {:4}", instruction, slot).expect("Write failed");
     }
     offset + 2
 }
@@ -170,7 +219,7 @@ pub fn jump_instruction(
         writeln!(
             writer,
             "{} {:4} -> {}",
-            instruction.to_string(),
+            instruction,
             offset,
             (offset as i32) + 3 + (jump as i32) * sign
         )
@@ -208,10 +257,10 @@ pub fn disassemble_instruction_with_writer(
     pretty: bool,
 ) -> usize {
     write!(writer, "{:04} ", offset).expect("Write failed");
-    if offset > 0 && chunk.lines[offset - 1] == chunk.lines[offset] {
+    if offset > 0 && chunk.line_at(offset - 1) == chunk.line_at(offset) {
         write!(writer, "   | ").expect("Write failed");
     } else {
-        write!(writer, "{:04} ", chunk.lines[offset]).expect("Write failed");
+        write!(writer, "{:04} ", chunk.line_at(offset)).expect("Write failed");
     }
     let byte = chunk.code.read_item_at(offset);
     disassemble_instruction(byte, chunk, offset, writer, pretty)
@@ -227,6 +276,64 @@ pub fn disassemble_instruction_with_writer_with_out_line_num(
     disassemble_instruction(byte, chunk, offset, writer, pretty)
 }
 
+pub fn two_byte_instruction(
+    instruction: &Opcode,
+    chunk: &Chunk,
+    offset: usize,
+    writer: &mut dyn Write,
+    pretty: bool,
+) -> usize {
+    let first = chunk.code.read_item_at(offset + 1);
+    let second = chunk.code.read_item_at(offset + 2);
+    if pretty {
+        writeln!(
+            writer,
+            "{:<30} {:4} {:4}",
+            instruction.to_string(),
+            first,
+            second
+        )
+        .expect("Write failed");
+    } else {
+        writeln!(writer, "{} {:4} {:4}", instruction, first, second).expect("Write failed");
+    }
+    offset + 3
+}
+
+pub fn compare_jump_instruction(
+    instruction: &Opcode,
+    chunk: &Chunk,
+    offset: usize,
+    writer: &mut dyn Write,
+    pretty: bool,
+) -> usize {
+    let comparison = chunk.code.read_item_at(offset + 1);
+    let comparison = Opcode::try_from(comparison)
+        .map_or(format!("<invalid {}>", comparison), |c| format!("{:?}", c));
+    let mut jump = as_u16(chunk.code.read_item_at(offset + 2)) << 8;
+    jump |= as_u16(chunk.code.read_item_at(offset + 3));
+    let target = offset + 4 + jump as usize;
+    if pretty {
+        writeln!(
+            writer,
+            "{:<30} {} {:4} -> {}",
+            instruction.to_string(),
+            comparison,
+            offset,
+            target
+        )
+        .expect("Write failed");
+    } else {
+        writeln!(
+            writer,
+            "{} {} {:4} -> {}",
+            instruction, comparison, offset, target
+        )
+        .expect("Write failed");
+    }
+    offset + 4
+}
+
 pub fn closure_instruction(
     instruction: &Opcode,
     chunk: &Chunk,
@@ -240,7 +347,7 @@ pub fn closure_instruction(
     if pretty {
         write!(writer, "{:<30} {:4} '", instruction.to_string(), constant).expect("Write failed");
     } else {
-        write!(writer, "{} {:4} '", instruction.to_string(), constant).expect("Write failed");
+        write!(writer, "{} {:4} '", instruction, constant).expect("Write failed");
     }
     print_value(chunk.constants.read_item_at(constant as usize), writer);
     writeln!(writer, "'").expect("write failed");
@@ -292,9 +399,7 @@ pub fn invoke_instruction(
         write!(
             writer,
             "{} ({} args){:4} '",
-            instruction.to_string(),
-            arg_count,
-            constant
+            instruction, arg_count, constant
         )
         .expect("Write failed");
     }
@@ -303,6 +408,25 @@ pub fn invoke_instruction(
     offset + 3
 }
 
+pub fn global_constant_instruction(
+    instruction: &Opcode,
+    chunk: &Chunk,
+    offset: usize,
+    writer: &mut dyn Write,
+    pretty: bool,
+) -> usize {
+    let constant = chunk.code.read_item_at(offset + 1);
+    let slot = chunk.code.read_item_at(offset + 2);
+    if pretty {
+        write!(writer, "{:<30} {:4} '", instruction.to_string(), constant).expect("Write failed");
+    } else {
+        write!(writer, "{} {:4} '", instruction, constant).expect("Write failed");
+    }
+    print_value(chunk.constants.read_item_at(constant as usize), writer);
+    writeln!(writer, "' in slot {}", slot).expect("Write failed");
+    offset + 3
+}
+
 pub fn disassemble_instruction(
     byte: ByteUnit,
     chunk: &Chunk,
@@ -347,6 +471,7 @@ pub fn disassemble_instruction(
             Opcode::SetUpvalue => byte_instruction(&instruction, chunk, offset, writer, pretty),
             Opcode::JumpIfFalse => jump_instruction(&instruction, chunk, 1, offset, writer, pretty),
             Opcode::JumpIfTrue => jump_instruction(&instruction, chunk, 1, offset, writer, pretty),
+            Opcode::JumpIfNil => jump_instruction(&instruction, chunk, 1, offset, writer, pretty),
             Opcode::Class => constant_instruction(&instruction, chunk, offset, writer, pretty),
             Opcode::SetProperty => {
                 constant_instruction(&instruction, chunk, offset, writer, pretty)
@@ -356,6 +481,33 @@ pub fn disassemble_instruction(
             }
             Opcode::Method => constant_instruction(&instruction, chunk, offset, writer, pretty),
             Opcode::Invoke => invoke_instruction(&instruction, chunk, offset, writer, pretty),
+            Opcode::StaticMethod => {
+                constant_instruction(&instruction, chunk, offset, writer, pretty)
+            }
+            Opcode::Doc => constant_instruction(&instruction, chunk, offset, writer, pretty),
+            Opcode::Is => simple_instruction(&instruction, offset, writer),
+            Opcode::BitAnd => simple_instruction(&instruction, offset, writer),
+            Opcode::BitOr => simple_instruction(&instruction, offset, writer),
+            Opcode::BitXor => simple_instruction(&instruction, offset, writer),
+            Opcode::ShiftLeft => simple_instruction(&instruction, offset, writer),
+            Opcode::ShiftRight => simple_instruction(&instruction, offset, writer),
+            Opcode::Power => simple_instruction(&instruction, offset, writer),
+            Opcode::Range => simple_instruction(&instruction, offset, writer),
+            Opcode::RangeInclusive => simple_instruction(&instruction, offset, writer),
+            Opcode::Yield => simple_instruction(&instruction, offset, writer),
+            Opcode::DefineGlobalConstant => {
+                global_constant_instruction(&instruction, chunk, offset, writer, pretty)
+            }
+            Opcode::GetGlobalConstant => {
+                byte_instruction(&instruction, chunk, offset, writer, pretty)
+            }
+            Opcode::AddConstant => {
+                constant_instruction(&instruction, chunk, offset, writer, pretty)
+            }
+            Opcode::AddLocals => two_byte_instruction(&instruction, chunk, offset, writer, pretty),
+            Opcode::CompareJumpIfFalse => {
+                compare_jump_instruction(&instruction, chunk, offset, writer, pretty)
+            }
         },
         Err(e) => {
             eprintln!(
@@ -384,17 +536,17 @@ mod tests {
         let mut chunk = Chunk::new();
 
         // -((1.2 + 3.4)/5.6)
-        let constant = chunk.add_constant(Value::number(1.2));
+        let constant = chunk.add_constant(Value::number(1.2)).unwrap();
         chunk.write_chunk(Opcode::Constant.into(), 123);
         chunk.write_chunk(constant as ByteUnit, 123);
 
-        let constant = chunk.add_constant(Value::number(3.4));
+        let constant = chunk.add_constant(Value::number(3.4)).unwrap();
         chunk.write_chunk(Opcode::Constant.into(), 123);
         chunk.write_chunk(constant as ByteUnit, 123);
 
         chunk.write_chunk(Opcode::Add.into(), 123);
 
-        let constant = chunk.add_constant(Value::number(5.6));
+        let constant = chunk.add_constant(Value::number(5.6)).unwrap();
         chunk.write_chunk(Opcode::Constant.into(), 123);
         chunk.write_chunk(constant as ByteUnit, 123);
 
@@ -425,7 +577,21 @@ mod tests {
         assert_eq!(0u8, Opcode::Constant.into());
         assert_eq!(37u8, Opcode::Invoke.into());
 
-        assert_eq!(Opcode::Constant, 0u8.into());
-        assert_eq!(Opcode::Invoke, 37u8.into());
+        assert_eq!(Opcode::Constant, 0u8.try_into().unwrap());
+        assert_eq!(Opcode::Invoke, 37u8.try_into().unwrap());
+        assert_eq!(
+            Opcode::CompareJumpIfFalse,
+            Opcode::try_from(u8::from(Opcode::CompareJumpIfFalse)).unwrap()
+        );
+        assert_eq!(
+            Opcode::GetGlobalConstant,
+            Opcode::try_from(u8::from(Opcode::GetGlobalConstant)).unwrap()
+        );
+        assert_eq!(
+            Opcode::Doc,
+            Opcode::try_from(u8::from(Opcode::Doc)).unwrap()
+        );
+        assert!(Opcode::try_from(u8::from(Opcode::Doc) + 1).is_err());
+        assert!(Opcode::try_from(u8::MAX).is_err());
     }
 }
@@ -0,0 +1,509 @@
+//! Verifies the bytecode of a [Chunk] before the VM runs it.
+//!
+//! The VM trusts its bytecode: operands index the constants and the stack, and jumps move the instruction pointer
+//! without any checks. [verify] checks once, up front, that every opcode is valid, every instruction has all of its
+//! operands, constant operands are in range (and of the kind the instruction expects) and every jump lands on
+//! the start of an instruction. The chunks of the functions in the constants are verified as well.
+//!
+//! The stack is checked by following every path through the code: an instruction must not pop more values than the
+//! frame has, the locals it reads must be on the stack, the upvalues must be captured by the function, every path
+//! that reaches an instruction must have as many values on the stack and the code can not run past its end. The
+//! most values a frame needs is kept in [Chunk::max_stack], the VM checks that they fit before it calls the function.
+use evie_common::{bail, errors::*};
+use evie_memory::{
+    chunk::Chunk,
+    objects::{GCObjectOf, ObjectType},
+};
+
+use crate::opcodes::Opcode;
+
+/// The comparisons a [Opcode::CompareJumpIfFalse] can fuse
+const COMPARISONS: [Opcode; 6] = [
+    Opcode::EqualEqual,
+    Opcode::BangEqual,
+    Opcode::Greater,
+    Opcode::GreaterEqual,
+    Opcode::Less,
+    Opcode::LessEqual,
+];
+
+/// Where the execution goes after an instruction
+#[derive(Clone, Copy)]
+enum Flow {
+    Next,
+    Jump(usize),
+    /// The next instruction or the target
+    Branch(usize),
+    Return,
+}
+
+/// A decoded instruction and what it does to the stack
+struct Instruction {
+    offset: usize,
+    pops: usize,
+    pushes: usize,
+    /// The values pushed for a while (and popped) when it runs
+    transient: usize,
+    /// The locals it reads or writes, they must be on the stack
+    slots: Vec<usize>,
+    flow: Flow,
+}
+
+/// Verifies the code of the script (and the chunks of the functions in its constants), `name` is used in the errors
+pub fn verify(chunk: GCObjectOf<Chunk>, name: &str) -> Result<()> {
+    // The frame of the script holds the script itself
+    verify_function(chunk, name, 1, 0)
+}
+
+/// Verifies the chunk of a function, its frame starts with `frame` values (the function and its arguments)
+fn verify_function(
+    mut chunk: GCObjectOf<Chunk>,
+    name: &str,
+    frame: usize,
+    upvalue_count: usize,
+) -> Result<()> {
+    let instructions = decode(&chunk, name, upvalue_count)?;
+    chunk.max_stack = max_stack(&instructions, chunk.code.inner.len(), name, frame)?;
+    for value in chunk.constants.inner.iter() {
+        if value.is_object() {
+            if let ObjectType::Function(function) = value.as_object().object_type {
+                verify_function(
+                    function.chunk,
+                    &function.to_string(),
+                    function.arity + 1,
+                    function.upvalue_count,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the instructions and checks their operands and jumps
+fn decode(chunk: &Chunk, name: &str, upvalue_count: usize) -> Result<Vec<Instruction>> {
+    let code = &chunk.code.inner;
+    let mut starts = vec![false; code.len()];
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < code.len() {
+        starts[offset] = true;
+        let opcode =
+            Opcode::try_from(code[offset]).map_err(|e| invalid(name, offset, &e.to_string()))?;
+        let operand = |i: usize| -> Result<u8> {
+            match code.get(offset + i) {
+                Some(byte) => Ok(*byte),
+                None => bail!(invalid(
+                    name,
+                    offset,
+                    &format!("{} is missing operands", opcode)
+                )),
+            }
+        };
+        let short = |i: usize| -> Result<usize> {
+            Ok((operand(i)? as usize) << 8 | operand(i + 1)? as usize)
+        };
+        let upvalue = |index: u8| -> Result<()> {
+            if index as usize >= upvalue_count {
+                bail!(invalid(
+                    name,
+                    offset,
+                    &format!("upvalue {} is not captured", index)
+                ))
+            }
+            Ok(())
+        };
+        let mut instruction = Instruction {
+            offset,
+            pops: 0,
+            pushes: 0,
+            transient: 0,
+            slots: vec![],
+            flow: Flow::Next,
+        };
+        // (pops, pushes)
+        let (length, effect) = match opcode {
+            Opcode::Constant => {
+                constant(chunk, name, offset, operand(1)?, Kind::Any)?;
+                (2, (0, 1))
+            }
+            Opcode::AddConstant => {
+                constant(chunk, name, offset, operand(1)?, Kind::Any)?;
+                instruction.transient = 1;
+                (2, (1, 1))
+            }
+            Opcode::GetGlobal | Opcode::Class => {
+                constant(chunk, name, offset, operand(1)?, Kind::String)?;
+                (2, (0, 1))
+            }
+            Opcode::DefineGlobal => {
+                constant(chunk, name, offset, operand(1)?, Kind::String)?;
+                (2, (1, 0))
+            }
+            Opcode::SetGlobal | Opcode::GetProperty | Opcode::Doc => {
+                constant(chunk, name, offset, operand(1)?, Kind::String)?;
+                (2, (1, 1))
+            }
+            // The class is below the method
+            Opcode::SetProperty | Opcode::Method | Opcode::StaticMethod => {
+                constant(chunk, name, offset, operand(1)?, Kind::String)?;
+                (2, (2, 1))
+            }
+            Opcode::Invoke => {
+                constant(chunk, name, offset, operand(1)?, Kind::String)?;
+                // The receiver and the arguments are replaced by the result
+                (3, (operand(2)? as usize + 1, 1))
+            }
+            Opcode::DefineGlobalConstant => {
+                constant(chunk, name, offset, operand(1)?, Kind::String)?;
+                operand(2)?;
+                (3, (1, 0))
+            }
+            Opcode::GetLocal => {
+                instruction.slots.push(operand(1)? as usize);
+                (2, (0, 1))
+            }
+            Opcode::SetLocal => {
+                instruction.slots.push(operand(1)? as usize);
+                (2, (1, 1))
+            }
+            Opcode::Call => (2, (operand(1)? as usize + 1, 1)),
+            Opcode::GetUpvalue => {
+                upvalue(operand(1)?)?;
+                (2, (0, 1))
+            }
+            Opcode::SetUpvalue => {
+                upvalue(operand(1)?)?;
+                (2, (1, 1))
+            }
+            Opcode::GetGlobalConstant => {
+                operand(1)?;
+                (2, (0, 1))
+            }
+            Opcode::AddLocals => {
+                instruction
+                    .slots
+                    .extend([operand(1)? as usize, operand(2)? as usize]);
+                instruction.transient = 2;
+                (3, (0, 1))
+            }
+            Opcode::Jump => {
+                instruction.flow = Flow::Jump(offset + 3 + short(1)?);
+                (3, (0, 0))
+            }
+            Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::JumpIfNil => {
+                instruction.flow = Flow::Branch(offset + 3 + short(1)?);
+                (3, (1, 1))
+            }
+            Opcode::Loop => {
+                let target = (offset + 3).checked_sub(short(1)?);
+                instruction.flow = Flow::Jump(target.unwrap_or(usize::MAX));
+                (3, (0, 0))
+            }
+            Opcode::CompareJumpIfFalse => {
+                let comparison = Opcode::try_from(operand(1)?)
+                    .map_err(|e| invalid(name, offset, &e.to_string()))?;
+                if !COMPARISONS.contains(&comparison) {
+                    bail!(invalid(
+                        name,
+                        offset,
+                        &format!("{} is not a comparison", comparison)
+                    ))
+                }
+                instruction.flow = Flow::Branch(offset + 4 + short(2)?);
+                (4, (2, 1))
+            }
+            Opcode::Closure => {
+                let upvalue_count =
+                    constant(chunk, name, offset, operand(1)?, Kind::Function)?.unwrap_or_default();
+                for captured in 0..upvalue_count {
+                    let is_local = operand(2 + captured * 2)?;
+                    let index = operand(3 + captured * 2)?;
+                    match is_local {
+                        1 => instruction.slots.push(index as usize),
+                        0 => upvalue(index)?,
+                        _ => bail!(invalid(
+                            name,
+                            offset,
+                            &format!("invalid upvalue kind {}", is_local)
+                        )),
+                    }
+                }
+                (2 + upvalue_count * 2, (0, 1))
+            }
+            Opcode::Return => {
+                instruction.flow = Flow::Return;
+                (1, (1, 0))
+            }
+            Opcode::Nil | Opcode::True | Opcode::False => (1, (0, 1)),
+            Opcode::Negate | Opcode::Not | Opcode::Yield => (1, (1, 1)),
+            Opcode::Print | Opcode::Pop | Opcode::CloseUpvalue => (1, (1, 0)),
+            Opcode::Add
+            | Opcode::Subtract
+            | Opcode::Multiply
+            | Opcode::Divide
+            | Opcode::EqualEqual
+            | Opcode::BangEqual
+            | Opcode::Greater
+            | Opcode::GreaterEqual
+            | Opcode::Less
+            | Opcode::LessEqual
+            | Opcode::Is
+            | Opcode::BitAnd
+            | Opcode::BitOr
+            | Opcode::BitXor
+            | Opcode::ShiftLeft
+            | Opcode::ShiftRight
+            | Opcode::Power
+            | Opcode::Range
+            | Opcode::RangeInclusive => (1, (2, 1)),
+        };
+        (instruction.pops, instruction.pushes) = effect;
+        instructions.push(instruction);
+        offset += length;
+    }
+    for instruction in &instructions {
+        if let Flow::Jump(target) | Flow::Branch(target) = instruction.flow {
+            if target >= code.len() || !starts[target] {
+                bail!(invalid(
+                    name,
+                    instruction.offset,
+                    "the jump does not land on an instruction"
+                ))
+            }
+        }
+    }
+    Ok(instructions)
+}
+
+/// Follows every path from the start of the code (with `frame` values on the stack), returns the most values on the
+/// stack
+fn max_stack(
+    instructions: &[Instruction],
+    code_length: usize,
+    name: &str,
+    frame: usize,
+) -> Result<usize> {
+    // The index of the instruction at each offset, and the values on the stack before each reachable instruction
+    let mut indices = vec![usize::MAX; code_length];
+    instructions
+        .iter()
+        .enumerate()
+        .for_each(|(index, instruction)| indices[instruction.offset] = index);
+    let mut heights: Vec<Option<usize>> = vec![None; instructions.len()];
+    if instructions.is_empty() {
+        bail!(invalid(name, 0, "the code is empty"))
+    }
+    heights[0] = Some(frame);
+    let mut pending = vec![0];
+    let mut max = frame;
+    while let Some(index) = pending.pop() {
+        let instruction = &instructions[index];
+        let height = heights[index].unwrap_or_default();
+        if height < instruction.pops {
+            bail!(invalid(
+                name,
+                instruction.offset,
+                &format!(
+                    "the instruction needs {} values on the stack, there are {}",
+                    instruction.pops, height
+                )
+            ))
+        }
+        if let Some(slot) = instruction.slots.iter().find(|&&slot| slot >= height) {
+            bail!(invalid(
+                name,
+                instruction.offset,
+                &format!("local {} is not on the stack", slot)
+            ))
+        }
+        let after = height - instruction.pops + instruction.pushes;
+        max = max.max(after).max(height + instruction.transient);
+        let successors = match instruction.flow {
+            Flow::Next => [Some(index + 1), None],
+            Flow::Jump(target) => [Some(indices[target]), None],
+            Flow::Branch(target) => [Some(index + 1), Some(indices[target])],
+            Flow::Return => [None, None],
+        };
+        for next in successors.into_iter().flatten() {
+            match heights.get(next) {
+                None => bail!(invalid(
+                    name,
+                    instruction.offset,
+                    "the code runs past its end"
+                )),
+                Some(None) => {
+                    heights[next] = Some(after);
+                    pending.push(next);
+                }
+                Some(Some(other)) if *other != after => bail!(invalid(
+                    name,
+                    instructions[next].offset,
+                    &format!(
+                        "the stack has {} values on one path and {} on another",
+                        other, after
+                    )
+                )),
+                Some(Some(_)) => {}
+            }
+        }
+    }
+    Ok(max)
+}
+
+/// The kind of constant an operand must refer to
+enum Kind {
+    Any,
+    String,
+    Function,
+}
+
+/// Checks the constant operand, returns the upvalue count of functions
+fn constant(
+    chunk: &Chunk,
+    name: &str,
+    offset: usize,
+    index: u8,
+    kind: Kind,
+) -> Result<Option<usize>> {
+    let value = match chunk.constants.inner.get(index as usize) {
+        Some(value) => *value,
+        None => bail!(invalid(
+            name,
+            offset,
+            &format!("constant {} is out of range", index)
+        )),
+    };
+    let object_type = value.is_object().then(|| value.as_object().object_type);
+    match (kind, object_type) {
+        (Kind::Any, _) => Ok(None),
+        (Kind::String, Some(ObjectType::String(_))) => Ok(None),
+        (Kind::Function, Some(ObjectType::Function(function))) => Ok(Some(function.upvalue_count)),
+        (Kind::String, _) => bail!(invalid(
+            name,
+            offset,
+            &format!("constant {} is not a string", index)
+        )),
+        (Kind::Function, _) => bail!(invalid(
+            name,
+            offset,
+            &format!("constant {} is not a function", index)
+        )),
+    }
+}
+
+fn invalid(name: &str, offset: usize, message: &str) -> String {
+    format!(
+        "Invalid bytecode in {} at offset {}: {}",
+        name, offset, message
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_common::{errors::*, ByteUnit};
+    #[cfg(feature = "nan_boxed")]
+    use evie_memory::objects::nan_boxed::Value;
+    #[cfg(not(feature = "nan_boxed"))]
+    use evie_memory::objects::non_nan_boxed::Value;
+    use evie_memory::{chunk::Chunk, objects::GCObjectOf, ObjectAllocator};
+
+    use super::verify;
+    use crate::opcodes::Opcode;
+
+    fn chunk(
+        allocator: &ObjectAllocator,
+        code: &[ByteUnit],
+        constants: &[Value],
+    ) -> GCObjectOf<Chunk> {
+        let mut chunk = Chunk::new();
+        constants.iter().for_each(|c| {
+            chunk.add_constant(*c);
+        });
+        code.iter().for_each(|b| chunk.write_chunk(*b, 1));
+        allocator.alloc(chunk)
+    }
+
+    fn error(chunk: GCObjectOf<Chunk>) -> String {
+        verify(chunk, "test")
+            .expect_err("invalid bytecode")
+            .to_string()
+    }
+
+    #[test]
+    fn verify_chunks() -> Result<()> {
+        let allocator = ObjectAllocator::new();
+        let chunk = |code: &[ByteUnit], constants: &[Value]| chunk(&allocator, code, constants);
+        let number = Value::number(1.0);
+        let name = Value::object(allocator.alloc_string("x"));
+        let (constant, get_global, jump, jump_if_false, pop, nil, ret, loop_) = (
+            Opcode::Constant.into(),
+            Opcode::GetGlobal.into(),
+            Opcode::Jump.into(),
+            Opcode::JumpIfFalse.into(),
+            Opcode::Pop.into(),
+            Opcode::Nil.into(),
+            Opcode::Return.into(),
+            Opcode::Loop.into(),
+        );
+        // constant 0; jump if false -> 9; pop; loop -> 0; pop; nil; return
+        let valid = chunk(
+            &[
+                constant,
+                0,
+                jump_if_false,
+                0,
+                4,
+                pop,
+                loop_,
+                0,
+                9,
+                pop,
+                nil,
+                ret,
+            ],
+            &[number],
+        );
+        verify(valid, "test")?;
+        // The script and the constant
+        assert_eq!(2, valid.max_stack);
+        assert!(error(chunk(&[255], &[])).contains("Invalid opcode 255"));
+        assert!(error(chunk(&[constant], &[number])).contains("missing operands"));
+        assert!(error(chunk(&[constant, 1, ret], &[number])).contains("out of range"));
+        assert!(error(chunk(&[get_global, 0, ret], &[number])).contains("not a string"));
+        verify(chunk(&[get_global, 0, ret], &[name]), "test")?;
+        // Into the operand of the constant, past the end and before the start
+        assert!(error(chunk(&[jump, 0, 1, constant, 0, ret], &[number])).contains("does not land"));
+        assert!(error(chunk(&[jump, 0, 1, ret], &[])).contains("does not land"));
+        assert!(error(chunk(&[loop_, 0, 4, ret], &[])).contains("does not land"));
+        let compare_jump: ByteUnit = Opcode::CompareJumpIfFalse.into();
+        assert!(error(chunk(&[compare_jump, pop, 0, 0, ret], &[])).contains("not a comparison"));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_stack() -> Result<()> {
+        let allocator = ObjectAllocator::new();
+        let chunk = |code: &[ByteUnit], constants: &[Value]| chunk(&allocator, code, constants);
+        let (jump_if_false, pop, nil, ret, get_local, get_upvalue) = (
+            Opcode::JumpIfFalse.into(),
+            Opcode::Pop.into(),
+            Opcode::Nil.into(),
+            Opcode::Return.into(),
+            Opcode::GetLocal.into(),
+            Opcode::GetUpvalue.into(),
+        );
+        verify(chunk(&[get_local, 0, ret], &[]), "test")?;
+        assert!(error(chunk(&[pop, ret], &[])).contains("needs 1 values on the stack, there are 0"));
+        assert!(error(chunk(&[get_local, 1, ret], &[])).contains("local 1 is not on the stack"));
+        assert!(error(chunk(&[get_upvalue, 0, ret], &[])).contains("upvalue 0 is not captured"));
+        assert!(error(chunk(&[nil], &[])).contains("runs past its end"));
+        // nil; jump if false -> 6; nil; nil (with 2 or 3 values); return
+        assert!(
+            error(chunk(&[nil, jump_if_false, 0, 1, nil, nil, ret], &[]))
+                .contains("2 values on one path and 3 on another")
+        );
+        // Unreachable code is not checked
+        verify(chunk(&[nil, ret, pop, pop, ret], &[]), "test")?;
+        Ok(())
+    }
+}
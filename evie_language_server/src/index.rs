@@ -0,0 +1,248 @@
+//! The workspace index: the text and the [ScopeTable] of every evie file in the workspace, keyed by [Url].
+//! Open documents are indexed from the editor's text, the other files from the disk.
+//! A file is re-indexed only when its text changes.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use evie_compiler::resolver::{self, ScopeTable};
+use evie_frontend::scanner::{rescan, LineEdit, Scanner};
+use evie_frontend::tokens::Token;
+use lspower::lsp::{TextDocumentContentChangeEvent, Url};
+
+use crate::text::TextDocument;
+
+/// The file extension of evie scripts
+const EXTENSION: &str = "evie";
+
+pub struct Document {
+    pub text: TextDocument,
+    /// The tokens of the text, None if it does not scan
+    tokens: Option<Vec<Token>>,
+    /// The scope table of the last text that compiled
+    table: Option<ScopeTable>,
+    /// The text compiles, i.e. the table is up to date
+    compiles: bool,
+    /// Open in the editor, the text may differ from the disk
+    pub open: bool,
+}
+
+impl Document {
+    /// Indexes the text, `previous` is the previous version of the document (if any)
+    fn new(text: TextDocument, open: bool, previous: Option<Document>) -> Self {
+        let source = text.as_str().to_string();
+        // Only the lines that changed since the previous text are scanned again
+        let scanned = previous
+            .as_ref()
+            .and_then(|p| Some((p.text.as_str(), p.tokens.as_deref()?)));
+        let tokens = match scanned {
+            Some((previous_text, previous_tokens)) => {
+                let edit = LineEdit::between(previous_text, &source);
+                rescan(previous_tokens, source, edit)
+            }
+            None => Scanner::new(source).scan_tokens().map(<[_]>::to_vec),
+        }
+        .ok();
+        let table = tokens
+            .as_deref()
+            .and_then(|tokens| resolver::resolve(tokens).ok());
+        let compiles = table.is_some();
+        Document {
+            text,
+            tokens,
+            table: table.or(previous.and_then(|p| p.table)),
+            compiles,
+            open,
+        }
+    }
+
+    /// The scope table, None if the text does not compile
+    pub fn table(&self) -> Option<&ScopeTable> {
+        self.table.as_ref().filter(|_| self.compiles)
+    }
+
+    /// The scope table of the last text that compiled, the positions in it may be outdated.
+    /// Useful while the text is being edited, e.g. for signature help in an unfinished call.
+    pub fn last_table(&self) -> Option<&ScopeTable> {
+        self.table.as_ref()
+    }
+}
+
+#[derive(Default)]
+pub struct WorkspaceIndex {
+    documents: HashMap<Url, Document>,
+}
+
+impl WorkspaceIndex {
+    /// Indexes the evie files in the folder and its sub folders, the open documents are left as they are
+    pub fn index_folder(&mut self, folder: &Path) {
+        let entries = match fs::read_dir(folder) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if path.is_dir() {
+                self.index_folder(&path);
+            } else if path.extension().is_some_and(|e| e == EXTENSION) {
+                if let (Ok(uri), Ok(text)) = (Url::from_file_path(&path), fs::read_to_string(&path))
+                {
+                    if !self.documents.get(&uri).is_some_and(|d| d.open) {
+                        self.update(uri, text, false);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The document was opened or changed in the editor
+    pub fn open(&mut self, uri: Url, text: String) {
+        self.update(uri, text, true);
+    }
+
+    /// The document was edited in the editor, the changes are applied in order
+    pub fn edit(&mut self, uri: Url, changes: Vec<TextDocumentContentChangeEvent>) {
+        let mut text = match self.documents.get(&uri) {
+            Some(document) => document.text.clone(),
+            None => TextDocument::new(String::new()),
+        };
+        for change in changes {
+            text.edit(change.range, &change.text);
+        }
+        self.update_text(uri, text, true);
+    }
+
+    /// The document was saved, with the saved text if the editor sent it
+    pub fn save(&mut self, uri: Url, text: Option<String>) {
+        let text = text.or_else(|| {
+            uri.to_file_path()
+                .ok()
+                .and_then(|path| fs::read_to_string(path).ok())
+        });
+        if let Some(text) = text {
+            self.update(uri, text, true);
+        }
+    }
+
+    /// The document was closed in the editor, from now on the file on the disk is indexed (if there is one)
+    pub fn close(&mut self, uri: &Url) {
+        match uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| fs::read_to_string(path).ok())
+        {
+            Some(text) => self.update(uri.clone(), text, false),
+            None => {
+                self.documents.remove(uri);
+            }
+        }
+    }
+
+    pub fn get(&self, uri: &Url) -> Option<&Document> {
+        self.documents.get(uri)
+    }
+
+    pub fn documents(&self) -> impl Iterator<Item = (&Url, &Document)> {
+        self.documents.iter()
+    }
+
+    fn update(&mut self, uri: Url, text: String, open: bool) {
+        self.update_text(uri, TextDocument::new(text), open);
+    }
+
+    fn update_text(&mut self, uri: Url, text: TextDocument, open: bool) {
+        match self.documents.get_mut(&uri) {
+            // Unchanged, no need to re-index
+            Some(document) if document.text == text => document.open = open,
+            _ => {
+                let previous = self.documents.remove(&uri);
+                self.documents
+                    .insert(uri, Document::new(text, open, previous));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use lspower::lsp::Url;
+
+    use super::WorkspaceIndex;
+
+    /// An empty folder in the temp dir
+    fn folder(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("evie_index_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn indexes_the_evie_files_of_the_folder() {
+        let dir = folder("folder");
+        fs::write(dir.join("main.evie"), "var a = 1;").unwrap();
+        fs::write(dir.join("lib").join("util.evie"), "fun f() {}").unwrap();
+        fs::write(dir.join("notes.txt"), "var b = 2;").unwrap();
+        let main = Url::from_file_path(dir.join("main.evie")).unwrap();
+        let mut index = WorkspaceIndex::default();
+        index.open(main.clone(), "var a = 2;".to_string());
+        index.index_folder(&dir);
+        let mut paths: Vec<_> = index
+            .documents()
+            .map(|(uri, _)| uri.path().to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(
+            vec![
+                Url::from_file_path(dir.join("lib").join("util.evie"))
+                    .unwrap()
+                    .path()
+                    .to_string(),
+                main.path().to_string(),
+            ],
+            paths
+        );
+        // The open document is not replaced by the file
+        assert_eq!("var a = 2;", index.get(&main).unwrap().text.as_str());
+        assert!(index
+            .documents()
+            .all(|(_, document)| document.table().is_some()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reindexes_saved_and_closed_documents() {
+        let dir = folder("save");
+        let path = dir.join("main.evie");
+        fs::write(&path, "var a = 1;").unwrap();
+        let uri = Url::from_file_path(&path).unwrap();
+        let mut index = WorkspaceIndex::default();
+        index.open(uri.clone(), "var a = 1;".to_string());
+        // Saved with the text
+        index.save(uri.clone(), Some("var a = ;".to_string()));
+        let document = index.get(&uri).unwrap();
+        assert!(document.table().is_none());
+        assert!(document.last_table().is_some());
+        // Saved without the text, it is read from the disk
+        index.save(uri.clone(), None);
+        assert_eq!("var a = 1;", index.get(&uri).unwrap().text.as_str());
+        assert!(index.get(&uri).unwrap().table().is_some());
+        // Closed with unsaved changes, the file on the disk is indexed again
+        index.open(uri.clone(), "var b = 2;".to_string());
+        index.close(&uri);
+        let document = index.get(&uri).unwrap();
+        assert_eq!("var a = 1;", document.text.as_str());
+        assert!(!document.open);
+        // Closed without a file
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+        index.open(untitled.clone(), "print 1;".to_string());
+        index.close(&untitled);
+        assert!(index.get(&untitled).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,14 +1,41 @@
 
 use std::collections::HashMap;
+use std::sync::Mutex;
 use std::vec;
 
-use lspower::lsp::{CompletionOptions, InitializeParams, InitializeResult, ServerCapabilities, CompletionParams, CompletionResponse, CompletionItem, Diagnostic, DidChangeTextDocumentParams, self, DiagnosticSeverity, HoverProviderCapability, TextDocumentSyncCapability, TextDocumentSyncKind, HoverParams, Hover, Range, HoverContents, MarkupKind, MarkupContent, SignatureHelpOptions, SignatureHelp, SignatureInformation, ParameterInformation, Documentation, ParameterLabel, SignatureHelpParams, OneOf, GotoDefinitionParams, GotoDefinitionResponse, Location, Position, ReferenceParams, DocumentSymbolParams, DocumentSymbolResponse, SymbolInformation, SymbolKind, RenameParams, WorkspaceEdit, TextEdit};
-use lspower::jsonrpc::{Result};
+use lspower::lsp::{CompletionOptions, InitializeParams, InitializeResult, ServerCapabilities, CompletionParams, CompletionResponse, CompletionItem, CompletionItemKind, Documentation, Diagnostic, DidChangeTextDocumentParams, self, DiagnosticSeverity, HoverProviderCapability, TextDocumentSyncCapability, TextDocumentSyncKind, HoverParams, Hover, Range, HoverContents, MarkupKind, MarkupContent, SignatureHelpOptions, SignatureHelp, SignatureHelpParams, OneOf, GotoDefinitionParams, GotoDefinitionResponse, Location, Position, ReferenceParams, DocumentSymbolParams, DocumentSymbolResponse, SymbolInformation, SymbolKind, RenameParams, WorkspaceEdit, TextEdit, DidOpenTextDocumentParams, DidCloseTextDocumentParams, DidSaveTextDocumentParams, WorkspaceSymbolParams, TextDocumentSyncOptions, TextDocumentSyncSaveOptions, SaveOptions, SemanticTokensParams, SemanticTokensResult, SemanticTokens, SemanticTokensOptions, SemanticTokensFullOptions, DocumentFormattingParams, DocumentRangeFormattingParams, ExecuteCommandParams, ExecuteCommandOptions};
+use lspower::jsonrpc::{Error, Result};
+use evie_common::errors::ErrorKind;
+use evie_compiler::compiler::Compiler;
+use evie_compiler::resolver::{ScopeTable, Span, Symbol, SymbolKind as EvieSymbolKind};
+use evie_frontend::fmt;
+use evie_frontend::scanner::Scanner;
+use evie_memory::ObjectAllocator;
+use index::WorkspaceIndex;
+use text::TextDocument;
+
+pub mod index;
+pub mod run;
+pub mod semantic_tokens;
+pub mod signature_help;
+pub mod text;
+
+/// Runs a document, the argument is the document's uri
+pub const RUN_FILE: &str = "evie.runFile";
+/// Runs part of a document, the arguments are the document's uri and the range
+pub const EVAL_SELECTION: &str = "evie.evalSelection";
+
 #[derive(Default)]
-pub struct EvieLanguageServer {}
+pub struct EvieLanguageServer {
+    /// The evie files of the workspace and the open documents
+    index: Mutex<WorkspaceIndex>,
+}
 
 impl EvieLanguageServer {
-    pub fn initialize(&self, _params: InitializeParams) -> InitializeResult {
+    pub fn initialize(&self, params: InitializeParams) -> InitializeResult {
+        if let Some(root) = params.root_uri.and_then(|uri| uri.to_file_path().ok()) {
+            self.index.lock().expect("Lock poisoned").index_folder(&root);
+        }
         let capabilities     = ServerCapabilities {
             completion_provider: 
                 Some(CompletionOptions {
@@ -27,7 +54,24 @@ impl EvieLanguageServer {
             references_provider: Some(OneOf::Left(true)),
             document_symbol_provider: Some(OneOf::Left(true)),
             rename_provider:  Some(OneOf::Left(true)),
-            text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::INCREMENTAL)),
+            workspace_symbol_provider: Some(OneOf::Left(true)),
+            document_formatting_provider: Some(OneOf::Left(true)),
+            document_range_formatting_provider: Some(OneOf::Left(true)),
+            execute_command_provider: Some(ExecuteCommandOptions {
+                commands: vec![RUN_FILE.to_string(), EVAL_SELECTION.to_string()],
+                ..Default::default()
+            }),
+            semantic_tokens_provider: Some(SemanticTokensOptions {
+                legend: semantic_tokens::legend(),
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                ..Default::default()
+            }.into()),
+            text_document_sync: Some(TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+                open_close: Some(true),
+                change: Some(TextDocumentSyncKind::INCREMENTAL),
+                save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions { include_text: Some(true) })),
+                ..Default::default()
+            })),
             ..Default::default()
         };
         InitializeResult {
@@ -36,83 +80,176 @@ impl EvieLanguageServer {
         }
     }
 
-    pub fn completion(&self, _params: CompletionParams) -> Result<Option<CompletionResponse>> {
-        Ok(Some(CompletionResponse::Array(
-            vec![CompletionItem::new_simple("label".to_string(), "item1".to_string())])))
+    /// The classes, functions and methods of the document, with their signature and docstring.
+    /// The text is usually being edited, so the table of the last version that compiled is used
+    pub fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let index = self.index.lock().expect("Lock poisoned");
+        let table = match index.get(&params.text_document_position.text_document.uri).and_then(|document| document.last_table()) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let item = |label: &str, kind: CompletionItemKind, detail: String, doc: &Option<String>| CompletionItem {
+            label: label.to_string(),
+            kind: Some(kind),
+            detail: Some(detail),
+            documentation: doc.as_ref().map(|doc| Documentation::MarkupContent(MarkupContent { kind: MarkupKind::Markdown, value: doc.clone() })),
+            ..Default::default()
+        };
+        let classes = table.classes().iter().map(|class| item(&class.name, CompletionItemKind::CLASS, format!("class {}", class.name), &class.doc));
+        let functions = table.functions().iter().filter(|f| f.declaration.is_some()).map(|f| {
+            let kind = if f.class.is_some() { CompletionItemKind::METHOD } else { CompletionItemKind::FUNCTION };
+            item(&f.name, kind, format!("fun {}", table.signature(f)), &f.doc)
+        });
+        Ok(Some(CompletionResponse::Array(classes.chain(functions).collect())))
     }
 
-    pub fn completion_resolve(&self, _params: CompletionItem) -> Result<CompletionItem> {
-       Ok(CompletionItem::new_simple("label".to_string(), "item1".to_string()))
+    /// The items of [Self::completion] are complete
+    pub fn completion_resolve(&self, params: CompletionItem) -> Result<CompletionItem> {
+       Ok(params)
+    }
+
+    pub fn did_open(&self, params: DidOpenTextDocumentParams) -> (lsp::Url, Vec<lsp::Diagnostic>, Option<i32>) {
+        let uri = params.text_document.uri;
+        let mut index = self.index.lock().expect("Lock poisoned");
+        index.open(uri.clone(), params.text_document.text);
+        let diagnostics = index.get(&uri).map(|document| diagnostics(&document.text)).unwrap_or_default();
+        (uri, diagnostics, Some(params.text_document.version))
     }
 
     pub fn did_change(&self, params: DidChangeTextDocumentParams) -> (lsp::Url, Vec<lsp::Diagnostic>, Option<i32>) {
-        let changes = params.content_changes;
-        let diagnostics: Vec<Diagnostic> = changes.into_iter().map(|t| {
-            {
-                let mut d = Diagnostic::new_simple(t.range.unwrap(), "A simple error".to_string());
-                d.severity = Some(DiagnosticSeverity::WARNING);
-                d.source = Some("evie".to_string());
-                d
-            }
-        }).collect();
-        (params.text_document.uri.clone(), diagnostics, Some(params.text_document.version))
+        // The changes are incremental, each is applied to the text after the previous one
+        let uri = params.text_document.uri;
+        let mut index = self.index.lock().expect("Lock poisoned");
+        index.edit(uri.clone(), params.content_changes);
+        let diagnostics = index.get(&uri).map(|document| diagnostics(&document.text)).unwrap_or_default();
+        (uri, diagnostics, Some(params.text_document.version))
+    }
+
+    pub fn did_save(&self, params: DidSaveTextDocumentParams) -> (lsp::Url, Vec<lsp::Diagnostic>, Option<i32>) {
+        let uri = params.text_document.uri;
+        let mut index = self.index.lock().expect("Lock poisoned");
+        index.save(uri.clone(), params.text);
+        let diagnostics = index.get(&uri).map(|document| diagnostics(&document.text)).unwrap_or_default();
+        (uri, diagnostics, None)
+    }
+
+    /// Returns the document, its diagnostics are cleared
+    pub fn did_close(&self, params: DidCloseTextDocumentParams) -> lsp::Url {
+        self.index.lock().expect("Lock poisoned").close(&params.text_document.uri);
+        params.text_document.uri
+    }
+
+    /// The symbol at the (LSP, 0 based) position in the document, the scope table it belongs to,
+    /// the function (index in [ScopeTable::functions]) the position is in and the range of the name at the position
+    fn with_symbol_at<T>(&self, uri: &lsp::Url, position: Position, f: impl FnOnce(&ScopeTable, &Symbol, usize, Range) -> T) -> Option<T> {
+        self.with_scope_table(uri, |text, table| {
+            let (line, column) = text.evie_position(position);
+            let symbol = table.symbol_at(line, column)?;
+            let reference = symbol.references.iter().find(|r| r.span.contains(line, column));
+            let function = reference.map(|r| r.function).unwrap_or(symbol.function);
+            // Not a reference, so the declaration
+            let span = reference.map(|r| r.span).or(symbol.declaration)?;
+            Some(f(table, symbol, function, text.span_range(span)))
+        }).flatten()
+    }
+
+    /// The text and the scope table of the document, None if it is not indexed or does not compile
+    fn with_scope_table<T>(&self, uri: &lsp::Url, f: impl FnOnce(&TextDocument, &ScopeTable) -> T) -> Option<T> {
+        let index = self.index.lock().expect("Lock poisoned");
+        let document = index.get(uri)?;
+        Some(f(&document.text, document.table()?))
+    }
+
+    /// The declarations and (optionally) the uses of the name at the position, declarations first.
+    /// Variables are resolved to their exact declaration (shadowing and closures included),
+    /// properties and methods are dynamic, so every method with the same name is a candidate.
+    fn ranges_at(&self, uri: &lsp::Url, position: Position, include_references: bool) -> Vec<Range> {
+        self.with_scope_table(uri, |text, table| {
+            let (line, column) = text.evie_position(position);
+            let spans: Vec<Span> = if let Some(symbol) = table.symbol_at(line, column) {
+                let references = symbol.references.iter().filter(|_| include_references).map(|r| r.span);
+                symbol.declaration.into_iter().chain(references).collect()
+            } else if let Some(name) = table.member_at(line, column) {
+                let references = table.property_references(name).filter(|_| include_references).map(|r| r.span);
+                table.methods_named(name).filter_map(|m| m.declaration).chain(references).collect()
+            } else {
+                vec![]
+            };
+            spans.into_iter().map(|span| text.span_range(span)).collect()
+        }).unwrap_or_default()
     }
 
     pub fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let uri = params.text_document_position_params.text_document.uri;
         let position = params.text_document_position_params.position;
-        let markdown = MarkupContent {
-            kind: MarkupKind::Markdown,
-            value: [
-                "### Header",
-                "Some text",
-                "```typescript",
-                "someCode();",
-                "```"
-            ]
-            .join("\n"),
-        };
-        Ok(Some(Hover{
-            contents: HoverContents::Markup(markdown),
-            range: Some(Range::new(position, position))
+        Ok(self.with_symbol_at(&uri, position, |table, symbol, function, range| {
+            let markdown = MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: hover_markdown(table, symbol, function),
+            };
+            Hover {
+                contents: HoverContents::Markup(markdown),
+                range: Some(range),
+            }
         }))
     }
 
-    pub fn signature_help(&self, _params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
-        let signature = MarkupContent {
-            kind: MarkupKind::Markdown,
-            value: "Function to poop".to_string(),
-        };
-        let parameter = MarkupContent {
-            kind: MarkupKind::Markdown,
-            value: "poop".to_string(),
+    pub fn semantic_tokens_full(&self, params: SemanticTokensParams) -> Result<Option<SemanticTokensResult>> {
+        let index = self.index.lock().expect("Lock poisoned");
+        Ok(index.get(&params.text_document.uri).map(|document| {
+            let data = semantic_tokens::semantic_tokens(&document.text, document.table());
+            SemanticTokensResult::Tokens(SemanticTokens { result_id: None, data })
+        }))
+    }
+
+    pub fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let index = self.index.lock().expect("Lock poisoned");
+        let document = match index.get(&uri) {
+            Some(document) => document,
+            None => return Ok(None),
         };
-        let sig =  SignatureInformation{
-            label: "Signature".to_string(),
-            documentation: Some(Documentation::MarkupContent(signature)),
-            parameters: Some(vec![
-                ParameterInformation{ label: ParameterLabel::Simple("param1".to_string()), documentation: Some(Documentation::MarkupContent(parameter.clone()))},
-                ParameterInformation{ label: ParameterLabel::Simple("param2".to_string()), documentation: Some(Documentation::MarkupContent(parameter))}
-                ]),
-            active_parameter: Some(0)
+        // The call is usually unfinished, so the table of the last version that compiled is used
+        let (line, column) = document.text.evie_position(position);
+        let (call, table) = match (signature_help::call_at(document.text.as_str(), line, column), document.last_table()) {
+            (Some(call), Some(table)) => (call, table),
+            _ => return Ok(None),
         };
+        let natives: Vec<(&str, usize)> = evie_native::all_natives().into_iter().map(|(name, arity, _)| (name, arity)).collect();
+        let string_methods: Vec<(&str, usize)> = evie_native::string::methods().into_iter().map(|(name, arity, _)| (name, arity)).collect();
+        let signatures = signature_help::signatures(&call, table, &natives, &string_methods);
+        if signatures.is_empty() {
+            return Ok(None);
+        }
         Ok(Some(SignatureHelp {
-            signatures: vec![sig],
+            signatures,
             active_signature: Some(0),
-            active_parameter: Some(0),
+            active_parameter: Some(call.active_parameter as u32),
         }))
     }
 
     pub fn goto_definition(&self, params: GotoDefinitionParams) -> Result<Option<GotoDefinitionResponse>> {
-        let position = Range::new(Position::new(0, 3), Position::new(0, 5));
-        Ok(Some(GotoDefinitionResponse::Scalar(Location::new(params.text_document_position_params.text_document.uri, position))))
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let mut locations: Vec<Location> = self.ranges_at(&uri, position, false).into_iter().map(|range| Location::new(uri.clone(), range)).collect();
+        Ok(match locations.len() {
+            0 => None,
+            1 => locations.pop().map(GotoDefinitionResponse::Scalar),
+            _ => Some(GotoDefinitionResponse::Array(locations)),
+        })
     }
 
     pub fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
         let uri = params.text_document_position.text_document.uri;
-        Ok(Some(vec![
-            Location::new(uri.clone(), Range::new(Position::new(0, 3), Position::new(0, 5))),
-            Location::new(uri, Range::new(Position::new(2, 3), Position::new(2, 5)))
-        ]))
+        let position = params.text_document_position.position;
+        let mut ranges = self.ranges_at(&uri, position, true);
+        if !params.context.include_declaration {
+            // Only the declared names are excluded, the uses are kept
+            let declarations = self.ranges_at(&uri, position, false);
+            ranges.retain(|range| !declarations.contains(range));
+        }
+        Ok(Some(ranges.into_iter().map(|range| Location::new(uri.clone(), range)).collect()))
     }
 
     pub fn document_symbol(&self, params: DocumentSymbolParams) -> Result<Option<DocumentSymbolResponse>> {
@@ -132,6 +269,91 @@ impl EvieLanguageServer {
         Ok(Some(d))
     }
 
+    /// The classes, functions, methods and global variables of all the indexed files whose name contains the query
+    pub fn symbol(&self, params: WorkspaceSymbolParams) -> Result<Option<Vec<SymbolInformation>>> {
+        let query = params.query.to_lowercase();
+        let index = self.index.lock().expect("Lock poisoned");
+        let mut symbols = Vec::new();
+        for (uri, text, table) in index.documents().filter_map(|(uri, document)| Some((uri, &document.text, document.table()?))) {
+            let mut add = |name: &str, kind: SymbolKind, span: Span, container: Option<&str>| {
+                if name.to_lowercase().contains(&query) {
+                    #[allow(deprecated)]
+                    symbols.push(SymbolInformation {
+                        name: name.to_string(),
+                        kind,
+                        tags: None,
+                        deprecated: None,
+                        location: Location::new(uri.clone(), text.span_range(span)),
+                        container_name: container.map(str::to_string),
+                    });
+                }
+            };
+            for class in table.classes() {
+                add(&class.name, SymbolKind::CLASS, class.declaration, None);
+            }
+            for function in table.functions() {
+                let span = match function.declaration {
+                    Some(span) => span,
+                    None => continue,
+                };
+                match function.class {
+                    Some(class) => add(&function.name, SymbolKind::METHOD, span, Some(&table.classes()[class].name)),
+                    None => add(&function.name, SymbolKind::FUNCTION, span, function.enclosing.map(|f| table.functions()[f].name.as_str()).filter(|&f| f != "script")),
+                }
+            }
+            for symbol in table.symbols().iter().filter(|s| s.kind == EvieSymbolKind::Global) {
+                let span = match symbol.declaration {
+                    Some(span) => span,
+                    None => continue,
+                };
+                // Classes and functions are listed above
+                if table.class_declared_at(span).is_none() && table.function_declared_at(span).is_none() {
+                    add(&symbol.name, SymbolKind::VARIABLE, span, None);
+                }
+            }
+        }
+        Ok(Some(symbols))
+    }
+
+    /// Formats the whole document with the same formatter as `evie fmt`, nothing is changed if it does not compile
+    pub fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let index = self.index.lock().expect("Lock poisoned");
+        Ok(index.get(&params.text_document.uri).and_then(|document| format_lines(&document.text, 0, usize::MAX)))
+    }
+
+    /// Formats the (whole) lines of the range, they have to contain complete declarations or statements
+    pub fn range_formatting(&self, params: DocumentRangeFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let (start, end) = (params.range.start, params.range.end);
+        // A selection ending at the start of a line does not include that line
+        let end_line = if end.character == 0 && end.line > start.line { end.line } else { end.line + 1 };
+        let index = self.index.lock().expect("Lock poisoned");
+        Ok(index.get(&params.text_document.uri).and_then(|document| format_lines(&document.text, start.line as usize, end_line as usize)))
+    }
+
+    /// Runs [RUN_FILE] and [EVAL_SELECTION], returns `{"output": <printed text>, "error": <error or null>}`
+    pub fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<serde_json::Value>> {
+        let mut arguments = params.arguments.into_iter();
+        let uri: lsp::Url = arguments.next().and_then(|uri| serde_json::from_value(uri).ok())
+            .ok_or_else(|| Error::invalid_params("Expect the document uri as the first argument"))?;
+        let text = match self.index.lock().expect("Lock poisoned").get(&uri) {
+            Some(document) => document.text.clone(),
+            None => return Err(Error::invalid_params(format!("Unknown document {}", uri))),
+        };
+        let source = match params.command.as_str() {
+            RUN_FILE => text.as_str().to_string(),
+            EVAL_SELECTION => {
+                let range: Range = arguments.next().and_then(|range| serde_json::from_value(range).ok())
+                    .ok_or_else(|| Error::invalid_params("Expect the range as the second argument"))?;
+                let (start, end) = (text.offset(range.start), text.offset(range.end));
+                text.as_str()[start..end.max(start)].to_string()
+            }
+            _ => return Err(Error::method_not_found()),
+        };
+        // Running can take a while, the lock is not held
+        let result = run::run(source);
+        Ok(Some(serde_json::json!({ "output": result.output, "error": result.error })))
+    }
+
     pub fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
         let uri = params.text_document_position.text_document.uri;
         let new_name = params.new_name;
@@ -148,3 +370,230 @@ impl EvieLanguageServer {
         Ok(Some(edit))
     }
 }
+
+/// Compiles the source, the compiler warnings are reported with Warning severity and a failed compilation as an error
+fn diagnostics(text: &TextDocument) -> Vec<Diagnostic> {
+    let allocator = ObjectAllocator::new();
+    let mut scanner = Scanner::new(text.as_str().to_string());
+    let result = scanner.scan_tokens().and_then(|tokens| Compiler::new(tokens, &allocator).compile_with_warnings());
+    match result {
+        Ok((_, warnings)) => warnings.iter().filter_map(|w| match w {
+            ErrorKind::Warning(line, column, message) => {
+                let position = text.span_range(Span { line: *line, column: *column, length: 0 }).start;
+                Some(diagnostic(Range::new(position, position), DiagnosticSeverity::WARNING, message.clone()))
+            }
+            _ => None,
+        }).collect(),
+        Err(e) => error_diagnostics(text, e.kind()),
+    }
+}
+
+/// The diagnostics of a failed compilation, one per error of a [ErrorKind::CompileErrors]
+fn error_diagnostics(text: &TextDocument, error: &ErrorKind) -> Vec<Diagnostic> {
+    if let ErrorKind::CompileErrors(errors) = error {
+        return errors.iter().flat_map(|e| error_diagnostics(text, e)).collect();
+    }
+    let message = error.to_string();
+    let range = match error_position(&message) {
+        Some((line, column)) => {
+            let position = text.span_range(Span { line, column, length: 0 }).start;
+            Range::new(position, position)
+        }
+        None => Range::default(),
+    };
+    vec![diagnostic(range, DiagnosticSeverity::ERROR, message)]
+}
+
+/// The line and column of a parse (or resolution) error, from its `[line: 1, column: 2]` prefix
+fn error_position(message: &str) -> Option<(usize, usize)> {
+    let rest = &message[message.find("[line: ")? + "[line: ".len()..];
+    let (line, rest) = rest.split_once(", column: ")?;
+    let (column, _) = rest.split_once(']')?;
+    Some((line.parse().ok()?, column.parse().ok()?))
+}
+
+/// Formats the lines `start..end` (0 based) of the text, keeping the indentation of the first line and whether the
+/// last one ends with a new line. Returns None if they do not parse, no edits if they are already formatted.
+fn format_lines(text: &TextDocument, start: usize, end: usize) -> Option<Vec<TextEdit>> {
+    if start >= text.line_count() {
+        return None;
+    }
+    let from = text.offset(Position::new(start as u32, 0));
+    let to = if end < text.line_count() { text.offset(Position::new(end as u32, 0)) } else { text.as_str().len() };
+    let end_position = text.position(to);
+    let region = &text.as_str()[from..to];
+    let indent: String = region.chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+    let mut formatted: String = fmt::format_source(region).ok()?.lines().map(|line| {
+        if line.is_empty() { "\n".to_string() } else { format!("{}{}\n", indent, line) }
+    }).collect();
+    if !region.ends_with('\n') && formatted.ends_with('\n') {
+        formatted.pop();
+    }
+    if formatted == region {
+        return Some(vec![]);
+    }
+    Some(vec![TextEdit::new(Range::new(Position::new(start as u32, 0), end_position), formatted)])
+}
+
+fn diagnostic(range: Range, severity: DiagnosticSeverity, message: String) -> Diagnostic {
+    let mut d = Diagnostic::new_simple(range, message);
+    d.severity = Some(severity);
+    d.source = Some("evie".to_string());
+    d
+}
+
+/// Describes the symbol, as used from `function`: the signature and docstring of functions, the docstring and methods
+/// of classes and for variables where they are declared and whether they are captured (upvalues)
+fn hover_markdown(table: &ScopeTable, symbol: &Symbol, function: usize) -> String {
+    let declaration = match symbol.declaration {
+        Some(span) => span,
+        None => return format!("```evie\n(global) {}\n```\nnot declared in this file", symbol.name),
+    };
+    if let Some(class) = table.class_declared_at(declaration) {
+        let methods: Vec<String> = class.methods.iter().map(|&m| format!("- `{}`", table.signature(&table.functions()[m]))).collect();
+        return format!("```evie\nclass {}\n```\n{}declared on line {}\n\nmethods:\n{}", class.name, doc_markdown(&class.doc), declaration.line, methods.join("\n"));
+    }
+    if let Some(f) = table.function_declared_at(declaration) {
+        return format!("```evie\nfun {}\n```\n{}arity {}, declared on line {}", table.signature(f), doc_markdown(&f.doc), f.arity, declaration.line);
+    }
+    let declared_in = &table.functions()[symbol.function].name;
+    if symbol.kind != EvieSymbolKind::Global && function != symbol.function {
+        format!("```evie\n(upvalue) {}\n```\n{} declared on line {} in `{}`, captured by `{}`",
+            symbol.name, kind_name(symbol.kind), declaration.line, declared_in, table.functions()[function].name)
+    } else {
+        format!("```evie\n({}) {}\n```\ndeclared on line {} in `{}`", kind_name(symbol.kind), symbol.name, declaration.line, declared_in)
+    }
+}
+
+/// The docstring as a paragraph of [hover_markdown], empty if there is none
+fn doc_markdown(doc: &Option<String>) -> String {
+    doc.as_ref().map(|doc| format!("{}\n\n", doc)).unwrap_or_default()
+}
+
+fn kind_name(kind: EvieSymbolKind) -> &'static str {
+    match kind {
+        EvieSymbolKind::Global => "global",
+        EvieSymbolKind::Local => "local",
+        EvieSymbolKind::Parameter => "parameter",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use evie_compiler::resolver;
+    use evie_frontend::scanner::Scanner;
+    use lspower::lsp::{DidCloseTextDocumentParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams, HoverContents, HoverParams, InitializeParams, Position, Range, SymbolKind, TextDocumentIdentifier, TextDocumentItem, TextDocumentPositionParams, TextEdit, Url, WorkspaceSymbolParams};
+
+    use super::{format_lines, hover_markdown, EvieLanguageServer};
+    use crate::text::TextDocument;
+
+    fn format(text: &str, start: usize, end: usize) -> Option<Vec<TextEdit>> {
+        format_lines(&TextDocument::new(text.to_string()), start, end)
+    }
+
+    #[test]
+    fn formats_lines() {
+        let text = "fun f() {\n  var a=1;\n    print a;\n}\nvar  b=2;\n";
+        assert_eq!(
+            Some(vec![TextEdit::new(Range::new(Position::new(1, 0), Position::new(3, 0)), "  var a = 1;\n  print a;\n".to_string())]),
+            format(text, 1, 3)
+        );
+        // Already formatted
+        assert_eq!(Some(vec![]), format("print 1;\n", 0, usize::MAX));
+        // Does not parse
+        assert_eq!(None, format("print (1;\n", 0, usize::MAX));
+        // A chain longer than the nesting limit
+        let long = format!("print 1{};\n", " + 1".repeat(10_000));
+        assert_eq!(Some(vec![]), format(&long, 0, usize::MAX));
+    }
+
+    #[test]
+    fn describes_symbols() {
+        let source = "class A {\n  \"An A\";\n  m(x) { return x; }\n}\nfun f(a) {\n  \"Adds one\";\n  fun g() { return a; }\n  return a + 1;\n}\nprint clock();\n";
+        let tokens = Scanner::new(source.to_string()).scan_tokens().unwrap().to_vec();
+        let table = resolver::resolve(&tokens).unwrap();
+        let hover = |line: usize, column: usize| {
+            let symbol = table.symbol_at(line, column).unwrap();
+            let function = symbol.references.iter().find(|r| r.span.contains(line, column)).map(|r| r.function).unwrap_or(symbol.function);
+            hover_markdown(&table, symbol, function)
+        };
+        assert_eq!("```evie\nclass A\n```\nAn A\n\ndeclared on line 1\n\nmethods:\n- `m(x)`", hover(1, 7));
+        assert_eq!("```evie\nfun f(a)\n```\nAdds one\n\narity 1, declared on line 5", hover(5, 5));
+        assert_eq!("```evie\n(parameter) a\n```\ndeclared on line 5 in `f`", hover(8, 10));
+        assert_eq!("```evie\n(upvalue) a\n```\nparameter declared on line 5 in `f`, captured by `g`", hover(7, 20));
+        assert_eq!("```evie\n(global) clock\n```\nnot declared in this file", hover(10, 7));
+    }
+
+    #[test]
+    fn hovers_over_the_name() {
+        let server = EvieLanguageServer::default();
+        let uri = Url::parse("untitled:Untitled-1").unwrap();
+        server.did_open(DidOpenTextDocumentParams { text_document: TextDocumentItem::new(uri.clone(), "evie".to_string(), 1, "var answer = 42;\nprint answer;\n".to_string()) });
+        let hover = |position: Position| server.hover(HoverParams {
+            text_document_position_params: TextDocumentPositionParams::new(TextDocumentIdentifier::new(uri.clone()), position),
+            work_done_progress_params: Default::default(),
+        }).unwrap();
+        let used = hover(Position::new(1, 9)).unwrap();
+        assert_eq!(Some(Range::new(Position::new(1, 6), Position::new(1, 12))), used.range);
+        assert!(matches!(used.contents, HoverContents::Markup(markup) if markup.value.contains("(global) answer")));
+        // The declaration
+        assert_eq!(Some(Range::new(Position::new(0, 4), Position::new(0, 10))), hover(Position::new(0, 4)).unwrap().range);
+        assert!(hover(Position::new(0, 12)).is_none());
+    }
+
+    #[test]
+    fn finds_workspace_symbols_across_files() {
+        let dir = std::env::temp_dir().join(format!("evie_workspace_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("shapes.evie"), "class Shape {\n  area() { return 0; }\n}\n").unwrap();
+        fs::write(dir.join("main.evie"), "var shape = 1;\nfun show_shape() {}\n").unwrap();
+        let server = EvieLanguageServer::default();
+        let params: InitializeParams = serde_json::from_value(serde_json::json!({
+            "processId": null, "rootUri": Url::from_directory_path(&dir).unwrap(), "capabilities": {}
+        })).unwrap();
+        server.initialize(params);
+        let symbols = |query: &str| {
+            let mut symbols: Vec<_> = server.symbol(WorkspaceSymbolParams { query: query.to_string(), ..Default::default() }).unwrap().unwrap()
+                .into_iter().map(|s| (s.name, s.kind, s.location.uri.path().rsplit('/').next().unwrap().to_string(), s.container_name)).collect();
+            symbols.sort_by(|a, b| a.0.cmp(&b.0));
+            symbols
+        };
+        assert_eq!(vec![
+            ("Shape".to_string(), SymbolKind::CLASS, "shapes.evie".to_string(), None),
+            ("shape".to_string(), SymbolKind::VARIABLE, "main.evie".to_string(), None),
+            ("show_shape".to_string(), SymbolKind::FUNCTION, "main.evie".to_string(), None),
+        ], symbols("shape"));
+        assert_eq!(vec![("area".to_string(), SymbolKind::METHOD, "shapes.evie".to_string(), Some("Shape".to_string()))], symbols("AREA"));
+        // Saved in the editor, then closed after the file changed on the disk
+        let shapes = TextDocumentIdentifier::new(Url::from_file_path(dir.join("shapes.evie")).unwrap());
+        server.did_save(DidSaveTextDocumentParams { text_document: shapes.clone(), text: Some("class Shape {\n  size() { return 0; }\n}\n".to_string()) });
+        assert_eq!("size", symbols("size")[0].0);
+        assert!(symbols("area").is_empty());
+        fs::write(dir.join("shapes.evie"), "class Circle {}\n").unwrap();
+        server.did_close(DidCloseTextDocumentParams { text_document: shapes });
+        assert_eq!(vec!["Circle".to_string()], symbols("c").into_iter().map(|s| s.0).collect::<Vec<_>>());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_last_line_without_a_new_line() {
+        assert_eq!(
+            Some(vec![TextEdit::new(Range::new(Position::new(0, 0), Position::new(1, 9)), "print 1;\nvar b = 2;".to_string())]),
+            format("print 1;\nvar  b=2;", 0, usize::MAX)
+        );
+        assert_eq!(Some(vec![]), format("print 1;", 0, usize::MAX));
+    }
+
+    #[test]
+    fn ends_the_edit_in_utf_16_code_units() {
+        // The emoji is two UTF-16 code units (a surrogate pair), the line is 11 characters long
+        let text = "print 1;\nprint  \"\u{1F600}\";";
+        assert_eq!(
+            Some(vec![TextEdit::new(Range::new(Position::new(1, 0), Position::new(1, 12)), "print \"\u{1F600}\";".to_string())]),
+            format(text, 1, 2)
+        );
+    }
+}
@@ -24,6 +24,11 @@ impl LanguageServer for Backend {
         Ok(())
     }
 
+    async fn did_open(&self, params: DidOpenTextDocumentParams) -> () {
+        let (uri, diags, _version) = self.els.did_open(params);
+        self.client.publish_diagnostics(uri, diags, None).await;
+    }
+
     async fn did_change(&self, params: DidChangeTextDocumentParams) -> () {
         let (uri, diags, _version) = self.els.did_change(params);
         self.client
@@ -32,6 +37,16 @@ impl LanguageServer for Backend {
         self.client.publish_diagnostics(uri, diags, None).await;
     }
 
+    async fn did_save(&self, params: DidSaveTextDocumentParams) -> () {
+        let (uri, diags, _version) = self.els.did_save(params);
+        self.client.publish_diagnostics(uri, diags, None).await;
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) -> () {
+        let uri = self.els.did_close(params);
+        self.client.publish_diagnostics(uri, vec![], None).await;
+    }
+
     async fn completion(&self, d: CompletionParams) -> Result<Option<CompletionResponse>> {
         self.els.completion(d)
     }
@@ -45,6 +60,10 @@ impl LanguageServer for Backend {
         self.els.hover(params)
     }
 
+    async fn semantic_tokens_full(&self, params: SemanticTokensParams) -> Result<Option<SemanticTokensResult>> {
+        self.els.semantic_tokens_full(params)
+    }
+
     async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
         self.els.signature_help(params)
     }
@@ -62,6 +81,22 @@ impl LanguageServer for Backend {
         self.els.document_symbol(params)
     }
       
+    async fn symbol(&self, params: WorkspaceSymbolParams) -> Result<Option<Vec<SymbolInformation>>> {
+        self.els.symbol(params)
+    }
+
+    async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        self.els.formatting(params)
+    }
+
+    async fn range_formatting(&self, params: DocumentRangeFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        self.els.range_formatting(params)
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<serde_json::Value>> {
+        self.els.execute_command(params)
+    }
+
     async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
         self.els.rename(params)
     }
@@ -0,0 +1,115 @@
+//! Runs evie code for the `evie.runFile` and `evie.evalSelection` commands and captures the output.
+//!
+//! Like `evie serve`, every run gets a fresh VM with the [MAX_INSTRUCTIONS] and [MAX_HEAP_BYTES] limits and no
+//! input, and the natives that block or reach the host (`sleep` and the `process` ones) are not defined.
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use evie_common::utf8_to_string;
+use evie_memory::objects::NativeFn;
+use evie_vm::vm::{define_native_fn, VirtualMachine};
+
+/// The most instructions a run executes (see [VirtualMachine::set_max_instructions])
+pub const MAX_INSTRUCTIONS: u64 = 100_000_000;
+/// The most bytes the objects of a run take (see [VirtualMachine::set_max_heap_bytes])
+pub const MAX_HEAP_BYTES: usize = 256 << 20;
+/// Scripts running longer are reported as timed out.
+/// The VM can not be interrupted, a script that timed out keeps its thread until it reaches the limits.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The output of the script
+pub struct Output {
+    /// What the script printed
+    pub output: String,
+    /// The compile or runtime error, if any
+    pub error: Option<String>,
+}
+
+/// Runs the source in a new VM on a separate thread.
+/// The input (e.g. for `read_line()`) is empty, stdin is the connection to the editor.
+pub fn run(source: String) -> Output {
+    run_with_limits(source, MAX_INSTRUCTIONS, MAX_HEAP_BYTES)
+}
+
+fn run_with_limits(source: String, max_instructions: u64, max_heap_bytes: usize) -> Output {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let result = {
+            let mut vm = VirtualMachine::new_with_reader_and_writer(
+                Some(Box::new(io::empty())),
+                Some(&mut output),
+            );
+            for (name, arity, native_fn) in natives() {
+                define_native_fn(name, arity, &mut vm, native_fn);
+            }
+            vm.set_max_instructions(Some(max_instructions));
+            vm.set_max_heap_bytes(Some(max_heap_bytes));
+            let result = vm.interpret(source, None);
+            vm.free();
+            result
+        };
+        // The receiver is gone if the script timed out
+        let _ = sender.send(Output {
+            output: utf8_to_string(&output),
+            error: result.err().map(|e| e.to_string()),
+        });
+    });
+    receiver.recv_timeout(TIMEOUT).unwrap_or_else(|_| Output {
+        output: String::new(),
+        error: Some(format!("Timed out after {} seconds", TIMEOUT.as_secs())),
+    })
+}
+
+/// [evie_native::all_natives] without the ones that block or reach the host
+fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    let excluded = ["sleep"];
+    #[cfg(feature = "unsafe_natives")]
+    let excluded: Vec<_> = excluded
+        .into_iter()
+        .chain(
+            evie_native::process::natives()
+                .iter()
+                .map(|(name, ..)| *name),
+        )
+        .collect();
+    evie_native::all_natives()
+        .into_iter()
+        .filter(|(name, ..)| !excluded.contains(name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, run_with_limits, MAX_HEAP_BYTES, MAX_INSTRUCTIONS};
+
+    #[test]
+    fn runs_with_limits() {
+        let output = run("print 1 + 2;".into());
+        assert_eq!(output.output, "3\n");
+        assert!(output.error.is_none());
+        let output = run("print 1; print nil + 1;".into());
+        assert_eq!(output.output, "1\n");
+        assert!(output.error.is_some());
+        let output = run_with_limits("while (true) {}".into(), 1000, MAX_HEAP_BYTES);
+        assert!(output
+            .error
+            .unwrap()
+            .contains("Instruction limit of 1000 exceeded"));
+        let source = r#"var s = "0123456789abcdef"; while (true) s = s + s;"#;
+        let output = run_with_limits(source.into(), MAX_INSTRUCTIONS, 1 << 20);
+        assert!(output
+            .error
+            .unwrap()
+            .contains("out of memory, the heap is limited to 1048576 bytes"));
+    }
+
+    #[test]
+    fn does_not_define_blocking_natives() {
+        let output = run("sleep(60000);".into());
+        assert!(output.error.unwrap().contains("sleep"));
+        assert!(run("print read_line();".into()).error.is_none());
+    }
+}
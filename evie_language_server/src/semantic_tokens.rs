@@ -0,0 +1,228 @@
+//! Semantic tokens (highlighting), computed from the scanner's tokens and the [ScopeTable].
+use evie_compiler::resolver::{ScopeTable, Symbol, SymbolKind};
+use evie_frontend::scanner::Scanner;
+use evie_frontend::tokens::{Token, TokenType};
+use lspower::lsp::{SemanticToken, SemanticTokenType, SemanticTokensLegend};
+
+use crate::text::TextDocument;
+
+/// The token types, the index in this list is the token type sent to the client
+const TOKEN_TYPES: [SemanticTokenType; 8] = [
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::CLASS,
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::PROPERTY,
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::STRING,
+];
+
+const FUNCTION: u32 = 0;
+const CLASS: u32 = 1;
+const PARAMETER: u32 = 2;
+const VARIABLE: u32 = 3;
+const PROPERTY: u32 = 4;
+const KEYWORD: u32 = 5;
+const NUMBER: u32 = 6;
+const STRING: u32 = 7;
+
+pub fn legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: TOKEN_TYPES.to_vec(),
+        token_modifiers: vec![],
+    }
+}
+
+/// The semantic tokens of the document, identifiers are only classified if the source compiles (`table` is Some)
+pub fn semantic_tokens(document: &TextDocument, table: Option<&ScopeTable>) -> Vec<SemanticToken> {
+    let mut scanner = Scanner::new(document.as_str().to_string());
+    let tokens = match scanner.scan_tokens() {
+        Ok(tokens) => tokens,
+        Err(_) => return vec![],
+    };
+    let mut semantic_tokens = Vec::new();
+    let (mut previous_line, mut previous_character) = (0, 0);
+    for (i, token) in tokens.iter().enumerate() {
+        // LSP does not support tokens spanning lines by default
+        if token.lexeme.contains('\n') {
+            continue;
+        }
+        let token_type = match classify(token, tokens.get(i + 1), table) {
+            Some(token_type) => token_type,
+            None => continue,
+        };
+        let range = document.span_range(token.span());
+        // Positions are relative to the previous token, the column only if it is on the same line
+        let delta_line = range.start.line - previous_line;
+        let delta_start = if delta_line == 0 {
+            range.start.character - previous_character
+        } else {
+            range.start.character
+        };
+        semantic_tokens.push(SemanticToken {
+            delta_line,
+            delta_start,
+            length: range.end.character - range.start.character,
+            token_type,
+            token_modifiers_bitset: 0,
+        });
+        previous_line = range.start.line;
+        previous_character = range.start.character;
+    }
+    semantic_tokens
+}
+
+fn classify(token: &Token, next: Option<&Token>, table: Option<&ScopeTable>) -> Option<u32> {
+    match token.token_type {
+        TokenType::Number => Some(NUMBER),
+        TokenType::String => Some(STRING),
+        TokenType::And
+        | TokenType::Class
+        | TokenType::Const
+        | TokenType::Else
+        | TokenType::False
+        | TokenType::Fun
+        | TokenType::For
+        | TokenType::If
+        | TokenType::In
+        | TokenType::Is
+        | TokenType::Nil
+        | TokenType::Or
+        | TokenType::Print
+        | TokenType::Return
+        | TokenType::Static
+        | TokenType::Super
+        | TokenType::This
+        | TokenType::True
+        | TokenType::Var
+        | TokenType::While
+        | TokenType::Yield => Some(KEYWORD),
+        TokenType::Identifier => {
+            let table = table?;
+            let span = token.span();
+            if let Some(symbol) = table.symbol_at(span.line, span.column) {
+                Some(classify_symbol(symbol, next, table))
+            } else if table.function_declared_at(span).is_some() {
+                // Method declarations
+                Some(FUNCTION)
+            } else if table.member_at(span.line, span.column).is_some() {
+                Some(PROPERTY)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn classify_symbol(symbol: &Symbol, next: Option<&Token>, table: &ScopeTable) -> u32 {
+    match symbol.declaration {
+        Some(declaration) if table.class_declared_at(declaration).is_some() => CLASS,
+        Some(declaration) if table.function_declared_at(declaration).is_some() => FUNCTION,
+        // Not declared in the source, e.g. natives, called like functions
+        None if next.is_some_and(|t| t.token_type == TokenType::LeftParen) => FUNCTION,
+        _ if symbol.kind == SymbolKind::Parameter => PARAMETER,
+        _ => VARIABLE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_compiler::resolver;
+    use evie_frontend::scanner::Scanner;
+    use lspower::lsp::SemanticToken;
+
+    use super::*;
+
+    fn semantic_tokens_of(source: &str) -> Vec<SemanticToken> {
+        let tokens = Scanner::new(source.to_string())
+            .scan_tokens()
+            .unwrap()
+            .to_vec();
+        let table = resolver::resolve(&tokens).unwrap();
+        semantic_tokens(&TextDocument::new(source.to_string()), Some(&table))
+    }
+
+    /// The (line, character, length, token type) of each token
+    fn decoded(tokens: &[SemanticToken]) -> Vec<(u32, u32, u32, u32)> {
+        let (mut line, mut character) = (0, 0);
+        tokens
+            .iter()
+            .map(|token| {
+                if token.delta_line > 0 {
+                    character = 0;
+                }
+                line += token.delta_line;
+                character += token.delta_start;
+                (line, character, token.length, token.token_type)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encodes_positions_relative_to_the_previous_token() {
+        let tokens = semantic_tokens_of("var a = 1;\n  print a;");
+        let deltas: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.delta_line, t.delta_start, t.length, t.token_type))
+            .collect();
+        assert_eq!(
+            vec![
+                (0, 0, 3, KEYWORD),
+                // The same line, relative to the start of the previous token
+                (0, 4, 1, VARIABLE),
+                (0, 4, 1, NUMBER),
+                // The next line, from the start of the line
+                (1, 2, 5, KEYWORD),
+                (0, 6, 1, VARIABLE),
+            ],
+            deltas
+        );
+    }
+
+    #[test]
+    fn skips_tokens_spanning_lines() {
+        let tokens = semantic_tokens_of("var s = \"a\nb\"; print s;\nprint \"c\";");
+        assert_eq!(
+            vec![
+                (0, 0, 3, KEYWORD),
+                (0, 4, 1, VARIABLE),
+                (1, 4, 5, KEYWORD),
+                (1, 10, 1, VARIABLE),
+                (2, 0, 5, KEYWORD),
+                (2, 6, 3, STRING),
+            ],
+            decoded(&tokens)
+        );
+    }
+
+    #[test]
+    fn classifies_identifiers() {
+        let source = "class P { get(p) { return p.x + clock(); } }\nvar v = P().get(1);";
+        assert_eq!(
+            vec![
+                (0, 0, 5, KEYWORD),
+                (0, 6, 1, CLASS),
+                // The method declaration, its parameter, a property and a native
+                (0, 10, 3, FUNCTION),
+                (0, 14, 1, PARAMETER),
+                (0, 19, 6, KEYWORD),
+                (0, 26, 1, PARAMETER),
+                (0, 28, 1, PROPERTY),
+                (0, 32, 5, FUNCTION),
+                (1, 0, 3, KEYWORD),
+                (1, 4, 1, VARIABLE),
+                (1, 8, 1, CLASS),
+                (1, 12, 3, PROPERTY),
+                (1, 16, 1, NUMBER),
+            ],
+            decoded(&semantic_tokens_of(source))
+        );
+        // Without the scope table only the keywords and literals are highlighted
+        let document = TextDocument::new(source.to_string());
+        assert!(semantic_tokens(&document, None)
+            .iter()
+            .all(|t| [KEYWORD, NUMBER, STRING].contains(&t.token_type)));
+    }
+}
@@ -0,0 +1,158 @@
+//! Signature help: the call around the cursor and the signatures of the called function.
+use evie_compiler::resolver::{FunctionScope, ScopeTable};
+use evie_frontend::scanner::Scanner;
+use evie_frontend::tokens::{Token, TokenType};
+use lspower::lsp::{ParameterInformation, ParameterLabel, SignatureInformation};
+
+/// An unfinished call, e.g. `add(1, ` or `point.move(`
+#[derive(Debug, PartialEq)]
+pub struct Call {
+    pub name: String,
+    /// Called as a method, `receiver.name(...)`
+    pub is_method: bool,
+    /// The token of the name
+    pub token: Token,
+    /// The argument the cursor is in, counted from 0
+    pub active_parameter: usize,
+}
+
+/// The innermost call whose arguments contain the position (lines and columns start at 1)
+pub fn call_at(source: &str, line: usize, column: usize) -> Option<Call> {
+    let mut scanner = Scanner::new(source.to_string());
+    let tokens = scanner.scan_tokens().ok()?;
+    let before = tokens
+        .iter()
+        .take_while(|t| t.token_type != TokenType::Eof && (t.line, t.column) < (line, column))
+        .count();
+    let (mut parens, mut braces, mut commas) = (0, 0, 0);
+    for i in (0..before).rev() {
+        match tokens[i].token_type {
+            TokenType::RightParen => parens += 1,
+            TokenType::RightBrace => braces += 1,
+            TokenType::LeftBrace if braces == 0 => return None,
+            TokenType::LeftBrace => braces -= 1,
+            TokenType::LeftParen if parens == 0 && braces == 0 => {
+                return call(tokens, i, commas);
+            }
+            TokenType::LeftParen => parens -= 1,
+            TokenType::Comma if parens == 0 && braces == 0 => commas += 1,
+            TokenType::Semicolon if parens == 0 && braces == 0 => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The call with the '(' at `paren`
+fn call(tokens: &[Token], paren: usize, active_parameter: usize) -> Option<Call> {
+    let name = tokens.get(paren.checked_sub(1)?)?;
+    if name.token_type != TokenType::Identifier {
+        return None;
+    }
+    let previous = paren.checked_sub(2).map(|i| tokens[i].token_type);
+    // `fun name(` declares a function
+    if previous == Some(TokenType::Fun) {
+        return None;
+    }
+    Some(Call {
+        name: name.lexeme.clone(),
+        is_method: previous == Some(TokenType::Dot),
+        token: name.clone(),
+        active_parameter,
+    })
+}
+
+/// The signatures that can be called, e.g. every method with the name, as methods are looked up at runtime.
+/// `natives` and `string_methods` are (name, arity) of the natives and the methods on Strings.
+pub fn signatures(
+    call: &Call,
+    table: &ScopeTable,
+    natives: &[(&str, usize)],
+    string_methods: &[(&str, usize)],
+) -> Vec<SignatureInformation> {
+    if call.is_method {
+        let methods = table
+            .methods_named(&call.name)
+            .map(|method| signature(table, method));
+        let string_methods = string_methods
+            .iter()
+            .filter(|(name, _)| *name == call.name)
+            .map(|&(name, arity)| native_signature(name, arity));
+        return methods.chain(string_methods).collect();
+    }
+    let span = call.token.span();
+    // The position is exact when the text compiles, otherwise the (outdated) table is searched by name
+    let declaration = match table.symbol_at(span.line, span.column) {
+        Some(symbol) if symbol.name == call.name => symbol.declaration,
+        _ => table
+            .functions()
+            .iter()
+            .find(|f| f.class.is_none() && f.name == call.name)
+            .and_then(|f| f.declaration)
+            .or_else(|| {
+                table
+                    .classes()
+                    .iter()
+                    .find(|c| c.name == call.name)
+                    .map(|c| c.declaration)
+            }),
+    };
+    if let Some(declaration) = declaration {
+        if let Some(function) = table.function_declared_at(declaration) {
+            return vec![signature(table, function)];
+        }
+        if let Some(class) = table.class_declared_at(declaration) {
+            // Calling a class calls its initializer
+            let init = class
+                .methods
+                .iter()
+                .map(|&m| &table.functions()[m])
+                .find(|m| m.name == "init");
+            return match init {
+                Some(init) => {
+                    let mut signature = signature(table, init);
+                    signature.label = signature.label.replacen("init", &class.name, 1);
+                    vec![signature]
+                }
+                None => vec![native_signature(&class.name, 0)],
+            };
+        }
+        return vec![];
+    }
+    natives
+        .iter()
+        .filter(|(name, _)| *name == call.name)
+        .map(|&(name, arity)| native_signature(name, arity))
+        .collect()
+}
+
+fn signature(table: &ScopeTable, function: &FunctionScope) -> SignatureInformation {
+    let parameters = table
+        .parameters(function)
+        .map(|p| parameter(p.name.clone()))
+        .collect();
+    SignatureInformation {
+        label: table.signature(function),
+        documentation: None,
+        parameters: Some(parameters),
+        active_parameter: None,
+    }
+}
+
+/// Natives have no parameter names, they are numbered
+fn native_signature(name: &str, arity: usize) -> SignatureInformation {
+    let parameters: Vec<String> = (1..=arity).map(|i| format!("arg{}", i)).collect();
+    SignatureInformation {
+        label: format!("{}({})", name, parameters.join(", ")),
+        documentation: None,
+        parameters: Some(parameters.into_iter().map(parameter).collect()),
+        active_parameter: None,
+    }
+}
+
+fn parameter(name: String) -> ParameterInformation {
+    ParameterInformation {
+        label: ParameterLabel::Simple(name),
+        documentation: None,
+    }
+}
@@ -0,0 +1,191 @@
+//! The text of a document with a line index, to apply (incremental) edits and convert between positions.
+//!
+//! There are three kinds of positions:
+//! - byte offsets into the text,
+//! - LSP [Position]s, 0 based lines and columns in UTF-16 code units,
+//! - evie positions (e.g. in a [Span]), 1 based lines and columns in characters (code points).
+use evie_compiler::resolver::Span;
+use lspower::lsp::{Position, Range};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextDocument {
+    text: String,
+    /// The byte offset of the start of each line
+    line_starts: Vec<usize>,
+}
+
+impl TextDocument {
+    pub fn new(text: String) -> Self {
+        let line_starts = line_starts(&text);
+        TextDocument { text, line_starts }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// Replaces the text in the range with `text`, or the whole text if there is no range
+    pub fn edit(&mut self, range: Option<Range>, text: &str) {
+        match range {
+            Some(range) => {
+                let start = self.offset(range.start);
+                let end = self.offset(range.end).max(start);
+                self.text.replace_range(start..end, text);
+            }
+            None => self.text = text.to_string(),
+        }
+        self.line_starts = line_starts(&self.text);
+    }
+
+    /// The byte offset of the position, positions after the end of a line (or the text) are clamped to its end
+    pub fn offset(&self, position: Position) -> usize {
+        let line = position.line as usize;
+        let start = match self.line_starts.get(line) {
+            Some(&start) => start,
+            None => return self.text.len(),
+        };
+        let end = self.line_end(line);
+        let mut units = 0;
+        for (i, c) in self.text[start..end].char_indices() {
+            if units >= position.character as usize {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        end
+    }
+
+    /// The position of the byte offset
+    pub fn position(&self, offset: usize) -> Position {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let character = self.text[self.line_starts[line]..offset]
+            .encode_utf16()
+            .count();
+        Position::new(line as u32, character as u32)
+    }
+
+    /// The evie line and column of the position
+    pub fn evie_position(&self, position: Position) -> (usize, usize) {
+        let offset = self.offset(position);
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let column = self.text[self.line_starts[line]..offset].chars().count();
+        (line + 1, column + 1)
+    }
+
+    /// The range of the span
+    pub fn span_range(&self, span: Span) -> Range {
+        let start = self.evie_offset(span.line, span.column);
+        let end = self.text[start..]
+            .char_indices()
+            .nth(span.length)
+            .map_or(self.text.len(), |(i, _)| start + i);
+        Range::new(self.position(start), self.position(end))
+    }
+
+    /// The byte offset of the evie line and column
+    fn evie_offset(&self, line: usize, column: usize) -> usize {
+        let start = match self.line_starts.get(line.saturating_sub(1)) {
+            Some(&start) => start,
+            None => return self.text.len(),
+        };
+        let end = self.line_end(line - 1);
+        self.text[start..end]
+            .char_indices()
+            .nth(column.saturating_sub(1))
+            .map_or(end, |(i, _)| start + i)
+    }
+
+    /// The byte offset of the end of the line (before the '\n')
+    fn line_end(&self, line: usize) -> usize {
+        self.line_starts
+            .get(line + 1)
+            .map_or(self.text.len(), |&next| next - 1)
+    }
+}
+
+fn line_starts(text: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use lspower::lsp::{Position, Range};
+
+    use super::TextDocument;
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Option<Range> {
+        Some(Range::new(
+            Position::new(start.0, start.1),
+            Position::new(end.0, end.1),
+        ))
+    }
+
+    #[test]
+    fn replaces_several_lines() {
+        let mut text = TextDocument::new("var a = 1;\nvar b = 2;\nvar c = 3;\n".to_string());
+        text.edit(range((0, 8), (2, 8)), "10;\nprint a + ");
+        assert_eq!("var a = 10;\nprint a + 3;\n", text.as_str());
+        assert_eq!(3, text.line_count());
+        // And the other way, one line into several
+        text.edit(range((1, 6), (1, 11)), "a\n+\n3");
+        assert_eq!("var a = 10;\nprint a\n+\n3;\n", text.as_str());
+        assert_eq!(5, text.line_count());
+        assert_eq!(Position::new(3, 1), text.position(text.as_str().len() - 2));
+    }
+
+    #[test]
+    fn counts_columns_in_utf_16_code_units() {
+        // The emoji is a surrogate pair, two code units but one character
+        let mut text = TextDocument::new("print \"\u{1F600}\";\n".to_string());
+        assert_eq!(
+            Position::new(0, 11),
+            text.position("print \"\u{1F600}\";".len())
+        );
+        assert_eq!("print \"\u{1F600}".len(), text.offset(Position::new(0, 9)));
+        assert_eq!((1, 9), text.evie_position(Position::new(0, 9)));
+        text.edit(range((0, 7), (0, 9)), "\u{e9}");
+        assert_eq!("print \"\u{e9}\";\n", text.as_str());
+    }
+
+    #[test]
+    fn edits_at_and_past_the_end() {
+        let mut text = TextDocument::new("print 1;".to_string());
+        text.edit(range((0, 8), (0, 8)), "\nprint 2;");
+        assert_eq!("print 1;\nprint 2;", text.as_str());
+        // Positions past the end of a line or of the text are clamped to it
+        text.edit(range((1, 100), (1, 100)), "\n");
+        text.edit(range((10, 0), (10, 5)), "print 3;\n");
+        assert_eq!("print 1;\nprint 2;\nprint 3;\n", text.as_str());
+        assert_eq!(4, text.line_count());
+        assert_eq!(text.as_str().len(), text.offset(Position::new(3, 0)));
+        assert_eq!(Position::new(3, 0), text.position(usize::MAX));
+    }
+
+    #[test]
+    fn incremental_edits_match_a_full_resync() {
+        let final_text =
+            "class Point {\n    init(x) { this.x = x; }\n}\nprint Point(\"\u{1F600}\").x;\n";
+        let mut incremental = TextDocument::new("class A {}\n".to_string());
+        for (start, end, edit) in [
+            ((0, 6), (0, 7), "Point"),
+            ((0, 13), (0, 13), "\n    init(x) { this.x = x; }\n"),
+            ((2, 1), (2, 1), "\nprint Point(1).x;"),
+            ((3, 12), (3, 13), "\"\u{1F600}\""),
+            // After the emoji
+            ((3, 17), (3, 19), ".x"),
+        ] {
+            incremental.edit(range(start, end), edit);
+        }
+        let mut resynced = TextDocument::new(String::new());
+        resynced.edit(None, final_text);
+        assert_eq!(resynced, incremental);
+        assert_eq!(TextDocument::new(final_text.to_string()), incremental);
+    }
+}
@@ -1,47 +1,147 @@
 //! Cache module for caching expensive lookup (e.g global variables)
 
+use rustc_hash::FxHashMap;
+
 use crate::objects::GCObjectOf;
 pub type Item<V> = (GCObjectOf<Box<str>>, V);
 
+/// The size above which a [Cache] indexes its items in a hash map.
+/// Up to it a linear scan is about as fast as hashing, above it hashing wins (see the `Cache` benchmark in evie_vm_bench).
+pub const LINEAR_LIMIT: usize = 8;
+
 /// A cache for values.
-/// This is [Vec] based cache instead of a hashmap based one. The logic is to avoid hashing and random memory lookups
+/// Small caches are [Vec] based instead of hashmap based. The logic is to avoid hashing and random memory lookups.
+/// Above a limit (by default [LINEAR_LIMIT]) the positions of the items are indexed in a [FxHashMap], hashing the key's pointer
+/// (keys are interned). The items are kept in insertion order either way.
 /// Mostly used for properties methods, and global variables
 #[derive(Debug)]
 pub struct Cache<V: Copy> {
     cached_values: Vec<Item<V>>,
+    /// The position of each key in `cached_values`, only when there are more than `linear_limit` items
+    index: Option<FxHashMap<GCObjectOf<Box<str>>, usize>>,
+    linear_limit: usize,
 }
 
 impl<V: Copy> Cache<V> {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
+        Cache::with_linear_limit(LINEAR_LIMIT)
+    }
+
+    /// A cache that indexes its items once there are more than `linear_limit`
+    pub fn with_linear_limit(linear_limit: usize) -> Self {
         Cache {
             cached_values: Vec::new(),
+            index: None,
+            linear_limit,
         }
     }
 
     pub fn insert(&mut self, key: GCObjectOf<Box<str>>, value: V) {
-        let v = self.cached_values.iter_mut().find(|(k, _)| *k == key);
-        if let Some((_, v)) = v {
-            *v = value
-        } else {
-            self.cached_values.push((key, value))
+        if let Some(position) = self.position(key) {
+            self.cached_values[position].1 = value;
+            return;
+        }
+        self.cached_values.push((key, value));
+        match &mut self.index {
+            Some(index) => {
+                index.insert(key, self.cached_values.len() - 1);
+            }
+            None if self.cached_values.len() > self.linear_limit => self.reindex(),
+            None => {}
         }
     }
 
     pub fn get(&self, key: GCObjectOf<Box<str>>) -> Option<V> {
-        let r = self.cached_values.iter().find(|(k, _)| *k == key);
-        r.map(|(_, v)| v).copied()
+        self.position(key)
+            .map(|position| self.cached_values[position].1)
+    }
+
+    /// Removes the key, returning its value if it was present. The order of the other items is kept.
+    pub fn remove(&mut self, key: GCObjectOf<Box<str>>) -> Option<V> {
+        let position = self.position(key)?;
+        let (_, value) = self.cached_values.remove(position);
+        if self.index.is_some() {
+            self.reindex();
+        }
+        Some(value)
+    }
+
+    /// The keys in insertion order
+    pub fn keys(&self) -> impl Iterator<Item = GCObjectOf<Box<str>>> + '_ {
+        self.cached_values.iter().map(|(k, _)| *k)
     }
 
     pub fn contains_key(&self, key: GCObjectOf<Box<str>>) -> bool {
-        self.cached_values.iter().any(|(k, _)| *k == key)
+        self.position(key).is_some()
     }
 
     pub fn size(&self) -> usize {
         self.cached_values.len()
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Item<V>> {
+        self.cached_values.iter()
+    }
+
     pub fn drain_first(&mut self, index: usize) -> Vec<Item<V>> {
-        self.cached_values.drain(0..index).collect()
+        let drained = self.cached_values.drain(0..index).collect();
+        if self.index.is_some() {
+            self.reindex();
+        }
+        drained
+    }
+
+    fn position(&self, key: GCObjectOf<Box<str>>) -> Option<usize> {
+        match &self.index {
+            Some(index) => index.get(&key).copied(),
+            None => self.cached_values.iter().position(|(k, _)| *k == key),
+        }
+    }
+
+    /// Rebuilds the index, or drops it if the cache is small enough to be scanned
+    fn reindex(&mut self) {
+        self.index = (self.cached_values.len() > self.linear_limit).then(|| {
+            self.cached_values
+                .iter()
+                .enumerate()
+                .map(|(position, (k, _))| (*k, position))
+                .collect()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cache;
+    use crate::ObjectAllocator;
+
+    #[test]
+    fn linear_and_indexed() {
+        let allocator = ObjectAllocator::new();
+        let keys: Vec<_> = (0..40)
+            .map(|i| allocator.alloc_interned_str(format!("key{}", i)))
+            .collect();
+        let mut cache = Cache::with_linear_limit(8);
+        for (i, key) in keys.iter().enumerate() {
+            cache.insert(*key, i);
+            assert_eq!(Some(i), cache.get(*key));
+        }
+        cache.insert(keys[3], 100);
+        assert_eq!(40, cache.size());
+        assert!(cache.index.is_some());
+        assert_eq!(Some(100), cache.get(keys[3]));
+        // Removing keeps the insertion order, the positions are re-indexed
+        assert_eq!(Some(0), cache.remove(keys[0]));
+        assert_eq!(None, cache.remove(keys[0]));
+        assert!(!cache.contains_key(keys[0]));
+        assert_eq!(Some(39), cache.get(keys[39]));
+        assert_eq!(keys[1..], cache.keys().collect::<Vec<_>>()[..]);
+        // Small enough to be scanned again
+        let drained = cache.drain_first(35);
+        assert_eq!(keys[1], drained[0].0);
+        assert!(cache.index.is_none());
+        assert_eq!(Some(36), cache.get(keys[36]));
+        assert_eq!(None, cache.get(keys[35]));
     }
 }
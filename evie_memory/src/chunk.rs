@@ -1,5 +1,12 @@
+use std::ops::Range;
+
 use evie_common::ByteUnit;
 
+use crate::{
+    objects::{Class, Closure, GCObjectOf},
+    shape::Shape,
+};
+
 #[cfg(feature = "nan_boxed")]
 use crate::objects::nan_boxed::Value;
 #[cfg(not(feature = "nan_boxed"))]
@@ -10,9 +17,32 @@ use crate::objects::non_nan_boxed::Value;
 pub struct Chunk {
     pub code: Memory<ByteUnit>,
     pub constants: Memory<Value>,
-    pub lines: Vec<usize>,
+    /// Where each byte of the code was compiled from
+    pub source_map: SourceMap,
+    /// The name of the source (e.g. the path of the script) the code was compiled from, for stack traces
+    pub source: Option<GCObjectOf<Box<str>>>,
+    /// The inline caches of the invoke instructions, by the offset right after the instruction (see [Chunk::invoke_cache])
+    pub invoke_caches: Vec<Option<InvokeCache>>,
+    /// The inline caches of the property instructions, by the offset right after the instruction (see [Chunk::property_cache])
+    pub property_caches: Vec<Option<PropertyCache>>,
+    /// The most values on the stack while the code runs, counted from the start of its frame (the callee and the
+    /// arguments included). Set when the chunk is verified (see the `evie_instructions` verifier)
+    pub max_stack: usize,
 }
 
+/// The [Shape] of the last instance a field was read or set on (at a property instruction) and the slot of the field.
+/// The next instance with the same shape has the field in the same slot.
+pub type PropertyCache = (GCObjectOf<Shape>, usize);
+
+/// The slot cached at an invoke instruction when the shape has no field with the name, the method of the class is
+/// invoked instead
+pub const NO_FIELD: usize = usize::MAX;
+
+/// The class of the last instance a method was invoked on (at an invoke instruction) and the method it resolved to.
+/// The methods of a class do not change once it is defined, so the next invoke on an instance of the same class
+/// can skip the lookup.
+pub type InvokeCache = (GCObjectOf<Class>, GCObjectOf<Closure>);
+
 impl Default for Chunk {
     fn default() -> Self {
         Self::new()
@@ -24,15 +54,20 @@ impl Chunk {
         Chunk {
             code: Memory::new(),
             constants: Memory::new(),
-            lines: Vec::new(),
+            source_map: SourceMap::default(),
+            source: None,
+            invoke_caches: Vec::new(),
+            property_caches: Vec::new(),
+            max_stack: 0,
         }
     }
 
-    pub fn add_constant(&mut self, value: Value) -> ByteUnit {
+    /// Appends the constant and returns its index, to locate that same constant later.
+    /// None if the chunk already has as many constants as an instruction can index
+    pub fn add_constant(&mut self, value: Value) -> Option<ByteUnit> {
+        let index = ByteUnit::try_from(self.constants.item_count()).ok()?;
         self.constants.write_item(value);
-        // /After we add the constant, we return the index where the constant was appended
-        // so that we can locate that same constant later.
-        (self.constants.item_count() - 1) as ByteUnit
+        Some(index)
     }
 
     #[inline]
@@ -41,10 +76,66 @@ impl Chunk {
         self.constants.read_item_at(offset as usize)
     }
 
+    /// Writes a byte compiled from the given line, see [Chunk::write_chunk_with_span]
     pub fn write_chunk(&mut self, byte: ByteUnit, line: usize) {
+        self.write_chunk_with_span(
+            byte,
+            SourceSpan {
+                line,
+                ..SourceSpan::default()
+            },
+        );
+    }
+
+    pub fn write_chunk_with_span(&mut self, byte: ByteUnit, span: SourceSpan) {
         self.code.write_item(byte);
-        self.lines.push(line);
+        self.source_map.push(span);
+    }
+
+    /// The line the byte at `offset` was compiled from
+    #[inline]
+    pub fn line_at(&self, offset: usize) -> usize {
+        self.source_map.line_at(offset)
+    }
+
+    /// The inline cache of the invoke instruction that ends at `offset`
+    #[inline]
+    pub fn invoke_cache(&self, offset: usize) -> Option<InvokeCache> {
+        self.invoke_caches.get(offset).copied().flatten()
     }
+
+    pub fn set_invoke_cache(&mut self, offset: usize, cache: InvokeCache) {
+        let len = self.code.item_count() + 1;
+        set_inline_cache(&mut self.invoke_caches, len, offset, cache);
+    }
+
+    /// The inline cache of the property instruction that ends at `offset`
+    #[inline]
+    pub fn property_cache(&self, offset: usize) -> Option<PropertyCache> {
+        self.property_caches.get(offset).copied().flatten()
+    }
+
+    pub fn set_property_cache(&mut self, offset: usize, cache: PropertyCache) {
+        let len = self.code.item_count() + 1;
+        set_inline_cache(&mut self.property_caches, len, offset, cache);
+    }
+
+    /// Drops the code (and its spans) from `offset` on
+    pub fn truncate(&mut self, offset: usize) {
+        self.code.inner.truncate(offset);
+        self.source_map.truncate(offset);
+    }
+
+    /// Removes the code (and its spans) in `range`, the code after it moves up.
+    /// Jumps over the range are not adjusted, the caller makes sure there are none.
+    pub fn remove_code(&mut self, range: Range<usize>) {
+        self.code.inner.drain(range.clone());
+        self.source_map.remove(range);
+        // The offsets of the instructions changed
+        self.invoke_caches.clear();
+        self.property_caches.clear();
+    }
+
     pub fn free_code(&mut self) {
         self.code.free_items();
     }
@@ -59,6 +150,125 @@ impl Chunk {
     }
 }
 
+/// The caches are allocated (for every offset of the code) on first use
+fn set_inline_cache<T: Copy>(caches: &mut Vec<Option<T>>, len: usize, offset: usize, cache: T) {
+    if caches.len() <= offset {
+        caches.resize(len, None);
+    }
+    caches[offset] = Some(cache);
+}
+
+/// Where (a byte of) the code comes from in the source, lines and columns start at 1 (a column of 0 is unknown).
+/// The same as a token's span in evie_frontend, e.g. the operator of a binary expression.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub line: usize,
+    pub column: usize,
+    /// The length in characters
+    pub length: usize,
+}
+
+/// The [SourceSpan] of every byte of a [Chunk], run-length encoded: the bytes compiled from the same span
+/// (e.g. an instruction and its operands) share one entry. The lines and the columns are encoded separately, as the
+/// lines change less often (runtime errors only need the line), and the entries are small: a line is a `u32`, a
+/// column and a length are `u16`s (a column that does not fit is unknown, i.e. 0, and the length is capped).
+#[derive(Debug, Default, Clone)]
+pub struct SourceMap {
+    /// The offset of the first byte of each run of bytes on the same line and the line, in order
+    lines: Vec<(u32, u32)>,
+    /// The offset of the first byte of each run of bytes with the same column and length and those, in order
+    columns: Vec<(u32, u16, u16)>,
+    len: usize,
+}
+
+impl SourceMap {
+    /// Adds the span of the next byte
+    pub fn push(&mut self, span: SourceSpan) {
+        let offset = self.len as u32;
+        let line = span.line as u32;
+        if self.lines.last().map(|(_, last)| *last) != Some(line) {
+            self.lines.push((offset, line));
+        }
+        let column = u16::try_from(span.column).unwrap_or(0);
+        let length = u16::try_from(span.length).unwrap_or(u16::MAX);
+        if self.columns.last().map(|(_, c, l)| (*c, *l)) != Some((column, length)) {
+            self.columns.push((offset, column, length));
+        }
+        self.len += 1;
+    }
+
+    /// The span of the byte at `offset`
+    pub fn span_at(&self, offset: usize) -> SourceSpan {
+        let (_, column, length) =
+            self.columns[self.run(&self.columns, offset, |(start, ..)| *start)];
+        SourceSpan {
+            line: self.line_at(offset),
+            column: column as usize,
+            length: length as usize,
+        }
+    }
+
+    /// The line of the byte at `offset`
+    pub fn line_at(&self, offset: usize) -> usize {
+        self.lines[self.run(&self.lines, offset, |(start, _)| *start)].1 as usize
+    }
+
+    /// The index of the run of `offset` in `runs`
+    fn run<T>(&self, runs: &[T], offset: usize, start: impl Fn(&T) -> u32) -> usize {
+        assert!(
+            offset < self.len,
+            "No span at {} (there are {})",
+            offset,
+            self.len
+        );
+        runs.partition_point(|run| start(run) as usize <= offset) - 1
+    }
+
+    /// Drops the spans from `offset` on
+    pub fn truncate(&mut self, offset: usize) {
+        if offset < self.len {
+            let lines = self
+                .lines
+                .partition_point(|(start, _)| (*start as usize) < offset);
+            self.lines.truncate(lines);
+            let columns = self
+                .columns
+                .partition_point(|(start, ..)| (*start as usize) < offset);
+            self.columns.truncate(columns);
+            self.len = offset;
+        }
+    }
+
+    /// Drops the spans in `range`
+    pub fn remove(&mut self, range: Range<usize>) {
+        let mut source_map = SourceMap::default();
+        for offset in (0..self.len).filter(|offset| !range.contains(offset)) {
+            source_map.push(self.span_at(offset));
+        }
+        *self = source_map;
+    }
+
+    /// The number of bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of runs of columns (there are at most as many runs of lines)
+    pub fn runs(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// The bytes the entries take
+    pub fn heap_size(&self) -> usize {
+        self.lines.capacity() * std::mem::size_of::<(u32, u32)>()
+            + self.columns.capacity() * std::mem::size_of::<(u32, u16, u16)>()
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Memory<T: Copy> {
     pub inner: Vec<T>,
@@ -100,3 +310,71 @@ impl<T: Copy> Memory<T> {
         self.inner.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{SourceMap, SourceSpan};
+
+    #[test]
+    fn source_map() {
+        let span = |line, column| SourceSpan {
+            line,
+            column,
+            length: 1,
+        };
+        let mut source_map = SourceMap::default();
+        for s in [
+            span(1, 1),
+            span(1, 1),
+            span(1, 5),
+            span(2, 1),
+            span(2, 1),
+            span(2, 1),
+        ] {
+            source_map.push(s);
+        }
+        assert_eq!((6, 3), (source_map.len(), source_map.runs()));
+        assert_eq!(span(1, 1), source_map.span_at(1));
+        assert_eq!(span(1, 5), source_map.span_at(2));
+        assert_eq!(span(2, 1), source_map.span_at(5));
+        source_map.truncate(3);
+        assert_eq!((3, 2), (source_map.len(), source_map.runs()));
+        source_map.push(span(1, 5));
+        assert_eq!((4, 2), (source_map.len(), source_map.runs()));
+        assert_eq!(span(1, 5), source_map.span_at(3));
+        source_map.remove(1..3);
+        assert_eq!((2, 2), (source_map.len(), source_map.runs()));
+        assert_eq!(span(1, 5), source_map.span_at(1));
+        assert_eq!(1, source_map.line_at(1));
+    }
+
+    #[test]
+    fn source_map_is_smaller_than_a_line_per_byte() {
+        let mut source_map = SourceMap::default();
+        // 10 lines of 100 bytes, an instruction of 2 bytes per column
+        for offset in 0..1000 {
+            source_map.push(SourceSpan {
+                line: offset / 100 + 1,
+                column: offset % 100 / 2 + 1,
+                length: 3,
+            });
+        }
+        assert_eq!((1000, 500), (source_map.len(), source_map.runs()));
+        assert_eq!(10, source_map.line_at(999));
+        assert!(source_map.heap_size() < 1000 * std::mem::size_of::<usize>());
+        // A column that does not fit is unknown
+        source_map.push(SourceSpan {
+            line: 11,
+            column: 1 << 20,
+            length: 1 << 20,
+        });
+        assert_eq!(
+            SourceSpan {
+                line: 11,
+                column: 0,
+                length: u16::MAX as usize
+            },
+            source_map.span_at(1000)
+        );
+    }
+}
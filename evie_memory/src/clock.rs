@@ -0,0 +1,71 @@
+//! The clocks evie reads: the monotonic clock (for the GC pauses, `instant()` & `elapsed()`) and the wall clock
+//! (for `clock()`, `time_millis()` and the seed of [crate::runtime::Random]).
+//!
+//! With the `clock` feature (the default) these are the clocks of the platform. Some targets have none, e.g. reading
+//! the time on `wasm32-unknown-unknown` panics, so without the feature time stands still: every [Instant] is the
+//! same, nothing has elapsed, the wall clock reads the unix epoch and [sleep] returns immediately.
+use std::time::Duration;
+
+/// A point in time of the monotonic clock, see the [module docs](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(#[cfg(feature = "clock")] std::time::Instant);
+
+impl Instant {
+    /// The current instant
+    #[inline(always)]
+    pub fn now() -> Self {
+        #[cfg(feature = "clock")]
+        return Instant(std::time::Instant::now());
+        #[cfg(not(feature = "clock"))]
+        Instant()
+    }
+
+    /// The time elapsed since this instant
+    #[inline(always)]
+    pub fn elapsed(&self) -> Duration {
+        #[cfg(feature = "clock")]
+        return self.0.elapsed();
+        #[cfg(not(feature = "clock"))]
+        Duration::ZERO
+    }
+}
+
+/// The wall clock: the time since the unix epoch
+pub fn since_epoch() -> Duration {
+    #[cfg(feature = "clock")]
+    return std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards");
+    #[cfg(not(feature = "clock"))]
+    Duration::ZERO
+}
+
+/// Blocks the current thread for the given duration
+pub fn sleep(duration: Duration) {
+    #[cfg(feature = "clock")]
+    std::thread::sleep(duration);
+    #[cfg(not(feature = "clock"))]
+    let _ = duration;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_advances() {
+        let start = Instant::now();
+        sleep(Duration::from_millis(2));
+        #[cfg(feature = "clock")]
+        {
+            assert!(start.elapsed() >= Duration::from_millis(2));
+            assert!(Instant::now() > start);
+            assert!(since_epoch() > Duration::ZERO);
+        }
+        #[cfg(not(feature = "clock"))]
+        {
+            assert_eq!(start.elapsed(), Duration::ZERO);
+            assert_eq!(since_epoch(), Duration::ZERO);
+        }
+    }
+}
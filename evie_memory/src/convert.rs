@@ -0,0 +1,217 @@
+//! Conversions between Rust values and evie [Value]s, for embedders exchanging data with scripts.
+//!
+//! Numbers and booleans convert with [From] and [TryFrom]. Everything else has to be allocated in (or is read from)
+//! a runtime, so it goes through [IntoValue] and [FromValue]:
+//! - `String`/`&str` are evie Strings (reading also accepts ropes),
+//! - `Option<T>` is nil or a `T`,
+//! - `HashMap<String, T>` is an instance (of a class named `Map`) with a field per key.
+//!
+//! Evie has no lists yet, so there is no conversion for `Vec`.
+use std::collections::HashMap;
+
+use evie_common::{bail, errors::*};
+
+#[cfg(feature = "nan_boxed")]
+use crate::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use crate::objects::non_nan_boxed::Value;
+use crate::{
+    cache::Cache,
+    objects::{Class, Instance, Object, ObjectType},
+    runtime::EvieRuntime,
+    shape::Fields,
+};
+
+/// A Rust value that can be converted into an evie [Value], allocating in the runtime if needed
+pub trait IntoValue {
+    fn into_value(self, runtime: &EvieRuntime) -> Value;
+}
+
+/// A Rust value that can be read from an evie [Value]
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self>;
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::bool(b)
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        if value.is_number() {
+            return Ok(value.as_number());
+        }
+        bail!(format!("Expected a number, got '{}'", value))
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        if value.is_bool() {
+            return Ok(value.as_bool());
+        }
+        bail!(format!("Expected a boolean, got '{}'", value))
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        if value.is_object() {
+            if let Some(s) = value.as_object().object_type.as_str() {
+                return Ok(s.to_string());
+            }
+        }
+        bail!(format!("Expected a string, got '{}'", value))
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _: &EvieRuntime) -> Value {
+        self
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _: &EvieRuntime) -> Value {
+        Value::number(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _: &EvieRuntime) -> Value {
+        Value::bool(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, runtime: &EvieRuntime) -> Value {
+        runtime.alloc_string(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, runtime: &EvieRuntime) -> Value {
+        runtime.alloc_string(self)
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, runtime: &EvieRuntime) -> Value {
+        self.map_or_else(Value::nil, |v| v.into_value(runtime))
+    }
+}
+
+impl<T: IntoValue> IntoValue for HashMap<String, T> {
+    fn into_value(self, runtime: &EvieRuntime) -> Value {
+        let allocator = runtime.allocator();
+        let class = allocator.alloc(Class::new(
+            allocator.alloc_interned_str("Map"),
+            allocator.alloc(Cache::new()),
+            allocator.alloc(Cache::new()),
+        ));
+        // Sorted, so that the fields are in the same order on every run
+        let mut entries: Vec<_> = self.into_iter().collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut fields = Fields::new(allocator);
+        for (name, value) in entries {
+            let value = value.into_value(runtime);
+            fields.insert(allocator.alloc_interned_str(name), value, allocator);
+        }
+        let instance = allocator.alloc(Instance::new(class, fields));
+        Value::object(Object::new_gc_object(
+            ObjectType::Instance(instance),
+            allocator,
+        ))
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self> {
+        Ok(value)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value) -> Result<Self> {
+        value.try_into()
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self> {
+        value.try_into()
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<Self> {
+        value.try_into()
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> Result<Self> {
+        if value.is_nil() {
+            return Ok(None);
+        }
+        T::from_value(value).map(Some)
+    }
+}
+
+/// The fields of an instance (of any class)
+impl<T: FromValue> FromValue for HashMap<String, T> {
+    fn from_value(value: Value) -> Result<Self> {
+        if value.is_object() {
+            if let ObjectType::Instance(instance) = value.as_object().object_type {
+                return instance
+                    .fields
+                    .iter()
+                    .map(|(name, field)| Ok((name.to_string(), T::from_value(field)?)))
+                    .collect();
+            }
+        }
+        bail!(format!("Expected an instance, got '{}'", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let runtime = EvieRuntime::new();
+        assert_eq!(1.5, f64::try_from(Value::from(1.5))?);
+        assert!(bool::try_from(Value::from(true))?);
+        assert!(f64::try_from(Value::nil()).is_err());
+        let s = "evie".into_value(&runtime);
+        assert_eq!("evie", String::from_value(s)?);
+        assert!(bool::from_value(s).is_err());
+        assert_eq!(
+            None,
+            Option::<f64>::from_value(None::<f64>.into_value(&runtime))?
+        );
+        assert_eq!(Some(2.0), Option::<f64>::from_value(Value::number(2.0))?);
+        let map: HashMap<String, f64> = [("x".to_string(), 1.0), ("y".to_string(), 2.0)].into();
+        let instance = map.clone().into_value(&runtime);
+        assert_eq!("<instance of Map>", instance.to_string());
+        assert_eq!(map, HashMap::<String, f64>::from_value(instance)?);
+        assert!(HashMap::<String, bool>::from_value(instance).is_err());
+        assert!(HashMap::<String, f64>::from_value(Value::number(1.0)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+//! Mark and sweep garbage collection.
+//!
+//! Every object handed out by the [ObjectAllocator] is registered with it. A collection marks everything
+//! reachable from the roots given by the caller (the VM) using [Trace], and then frees (sweeps) the rest.
+//! Interned strings are weak: an interned string that is not reachable is removed from the table.
+use std::{ptr::NonNull, time::Duration};
+
+use rustc_hash::FxHashSet;
+
+use crate::{
+    cache::Cache,
+    chunk::Chunk,
+    objects::{
+        nan_boxed, non_nan_boxed, BoundMethod, Class, Closure, Coroutine, Function, GCObjectOf,
+        Instance, Location, NativeFunction, Object, ObjectType, Range, RangeIterator, Rope,
+        SuspendedFrame, Upvalue, UserDefinedFunction,
+    },
+    shape::{Fields, Shape},
+};
+
+/// Bytes to allocate before the first collection
+pub(crate) const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+/// After a collection, the next one is triggered when the live bytes grow by this factor
+pub(crate) const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Anything that holds references to GC managed objects
+pub trait Trace {
+    /// Marks the objects referenced by `self` (not `self` itself)
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// The bytes `self` owns outside of its allocation (e.g. the text of a string), they count towards the heap, see
+    /// [crate::ObjectAllocator::resize]
+    fn owned_bytes(&self) -> usize {
+        0
+    }
+}
+
+pub(crate) type TraceFn = unsafe fn(NonNull<u8>, &mut Tracer);
+
+pub(crate) unsafe fn trace_erased<T: Trace>(object: NonNull<u8>, tracer: &mut Tracer) {
+    object.cast::<T>().as_ref().trace(tracer)
+}
+
+/// Tracks the marked (reachable) objects during a collection
+pub struct Tracer {
+    marked: FxHashSet<usize>,
+    gray: Vec<(NonNull<u8>, TraceFn)>,
+}
+
+impl Tracer {
+    pub(crate) fn new() -> Self {
+        Tracer {
+            marked: FxHashSet::default(),
+            gray: Vec::new(),
+        }
+    }
+
+    /// Marks the object (if not already marked), the objects it references are traced later
+    pub fn mark<T: Trace>(&mut self, object: GCObjectOf<T>) {
+        if self.marked.insert(object.as_ptr() as usize) {
+            self.gray
+                .push((object.reference.cast(), trace_erased::<T> as TraceFn));
+        }
+    }
+
+    /// Marks the object of an unknown type, traced with `trace`
+    pub(crate) fn mark_erased(&mut self, object: NonNull<u8>, trace: TraceFn) {
+        if self.marked.insert(object.as_ptr() as usize) {
+            self.gray.push((object, trace));
+        }
+    }
+
+    /// Returns true if the object at the given address was marked
+    pub(crate) fn is_marked(&self, address: usize) -> bool {
+        self.marked.contains(&address)
+    }
+
+    /// The addresses of the objects marked but not traced yet, in the order they were marked
+    pub(crate) fn pending(&self) -> impl Iterator<Item = usize> + '_ {
+        self.gray.iter().map(|(object, _)| object.as_ptr() as usize)
+    }
+
+    /// Traces the marked objects until every reachable object is marked
+    pub(crate) fn trace_references(&mut self) {
+        while let Some((object, trace)) = self.gray.pop() {
+            // Safety: only live objects are marked, they are not freed until the sweep
+            unsafe { trace(object, self) }
+        }
+    }
+}
+
+/// Statistics of the garbage collector
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// The number of live objects
+    pub objects: usize,
+    /// The number of live bytes
+    pub bytes_allocated: usize,
+    /// The number of collections run so far
+    pub collections: usize,
+    /// Objects freed by all collections
+    pub objects_freed: usize,
+    /// The objects freed by a collection that were allocated after the previous one (most objects die young)
+    pub young_objects_freed: usize,
+    /// The total time spent in collections
+    pub total_pause: Duration,
+    /// The longest collection
+    pub max_pause: Duration,
+    /// The bytes reserved by the object pools (see [crate::pool]), used or not
+    pub pooled_bytes: usize,
+}
+
+/// The live objects of a type, see [crate::ObjectAllocator::allocation_stats]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeStats {
+    /// The name of the type, without its path (e.g. `Closure`)
+    pub type_name: String,
+    pub objects: usize,
+    pub bytes: usize,
+    /// The objects allocated after the last collection, the others survived at least one
+    pub young_objects: usize,
+}
+
+impl<T: Trace> Trace for GCObjectOf<T> {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(*self)
+    }
+}
+
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(v) = self {
+            v.trace(tracer)
+        }
+    }
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.iter().for_each(|v| v.trace(tracer))
+    }
+
+    fn owned_bytes(&self) -> usize {
+        self.capacity() * std::mem::size_of::<T>()
+    }
+}
+
+impl<V: Copy + Trace> Trace for Cache<V> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.iter().for_each(|(k, v)| {
+            k.trace(tracer);
+            v.trace(tracer);
+        })
+    }
+}
+
+impl Trace for nan_boxed::Value {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        if self.is_object() {
+            tracer.mark(self.as_object())
+        }
+    }
+}
+
+impl Trace for non_nan_boxed::Value {
+    #[inline]
+    fn trace(&self, tracer: &mut Tracer) {
+        if self.is_object() {
+            tracer.mark(self.as_object())
+        }
+    }
+}
+
+impl Trace for Box<str> {
+    fn trace(&self, _: &mut Tracer) {}
+
+    fn owned_bytes(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Trace for String {
+    fn trace(&self, _: &mut Tracer) {}
+
+    fn owned_bytes(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl Trace for Rope {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.buffer)
+    }
+}
+
+impl Trace for Object {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.object_type {
+            ObjectType::String(s) => tracer.mark(s),
+            ObjectType::Rope(r) => tracer.mark(r),
+            ObjectType::Function(f) => tracer.mark(f),
+            ObjectType::NativeFunction(f) => tracer.mark(f),
+            ObjectType::Closure(c) => tracer.mark(c),
+            ObjectType::Class(c) => tracer.mark(c),
+            ObjectType::Instance(i) => tracer.mark(i),
+            ObjectType::BoundMethod(b) => tracer.mark(b),
+            ObjectType::Range(r) => tracer.mark(r),
+            ObjectType::RangeIterator(i) => tracer.mark(i),
+            ObjectType::Coroutine(c) => tracer.mark(c),
+        }
+    }
+}
+
+impl Trace for Chunk {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.constants.inner.trace(tracer);
+        self.source.trace(tracer);
+        self.invoke_caches
+            .iter()
+            .flatten()
+            .for_each(|(class, method)| {
+                class.trace(tracer);
+                method.trace(tracer);
+            });
+        self.property_caches
+            .iter()
+            .flatten()
+            .for_each(|(shape, _)| shape.trace(tracer));
+    }
+}
+
+impl Trace for UserDefinedFunction {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.name.trace(tracer);
+        self.chunk.trace(tracer);
+        self.doc.trace(tracer);
+        self.inferred_name.trace(tracer);
+    }
+}
+
+impl Trace for NativeFunction {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.name.trace(tracer)
+    }
+}
+
+impl Trace for Function {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Function::UserDefined(f) => f.trace(tracer),
+            Function::Native(f) => f.trace(tracer),
+        }
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.function.trace(tracer);
+        self.upvalues.trace(tracer);
+    }
+}
+
+impl Trace for Upvalue {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Location::Heap(value) = self.location {
+            value.trace(tracer)
+        }
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.name.trace(tracer);
+        self.methods.trace(tracer);
+        self.statics.trace(tracer);
+        self.init.trace(tracer);
+        self.doc.trace(tracer);
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.class.trace(tracer);
+        self.fields.trace(tracer);
+    }
+
+    fn owned_bytes(&self) -> usize {
+        self.fields.owned_bytes()
+    }
+}
+
+impl Trace for Fields {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.shape.trace(tracer);
+        self.values.trace(tracer);
+    }
+
+    fn owned_bytes(&self) -> usize {
+        self.values.owned_bytes()
+    }
+}
+
+impl Trace for Shape {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.names().for_each(|name| name.trace(tracer));
+        self.transitions.trace(tracer);
+    }
+}
+
+impl Trace for Range {
+    fn trace(&self, _: &mut Tracer) {}
+}
+
+impl Trace for RangeIterator {
+    fn trace(&self, _: &mut Tracer) {}
+}
+
+impl Trace for Coroutine {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.function.trace(tracer);
+        self.stack.trace(tracer);
+        self.frames
+            .iter()
+            .for_each(|frame| frame.closure.trace(tracer));
+        self.upvalues
+            .iter()
+            .for_each(|(upvalue, _)| upvalue.trace(tracer));
+    }
+
+    fn owned_bytes(&self) -> usize {
+        self.stack.owned_bytes()
+            + self.frames.capacity() * std::mem::size_of::<SuspendedFrame>()
+            + self.upvalues.capacity() * std::mem::size_of::<(GCObjectOf<Upvalue>, usize)>()
+    }
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.trace(tracer);
+        self.1.trace(tracer);
+    }
+}
@@ -0,0 +1,99 @@
+//! Handles: the safe way for embedders to hold on to evie objects.
+//!
+//! A [GCObjectOf] is a raw pointer into the heap of an [ObjectAllocator]: it is [Copy], it can be dereferenced
+//! mutably from a shared copy and nothing keeps the object alive, so holding one across a collection (or using it
+//! with another runtime) is undefined behavior. The VM and the natives use them internally, a host should use a
+//! [Handle] instead:
+//! - the object of a handle is a GC root, it is not freed until the handle is released
+//!   ([EvieRuntime::release]),
+//! - the object is only reached through the runtime that created the handle: [EvieRuntime::get] borrows the runtime
+//!   and [EvieRuntime::get_mut] borrows it mutably, so the borrow checker rules out aliasing with a running script,
+//! - a handle is not [Copy] nor [Clone], each one pins the object once.
+//!
+//! A handle that is dropped without being released keeps its object alive until the runtime is dropped (a leak, not
+//! undefined behavior).
+#[cfg(feature = "nan_boxed")]
+use crate::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use crate::objects::non_nan_boxed::Value;
+use crate::objects::{Class, Closure, GCObjectOf, Instance, Object, ObjectType};
+#[cfg(doc)]
+use crate::{runtime::EvieRuntime, ObjectAllocator};
+
+/// The objects a [Handle] can be created for from a [Value], see [EvieRuntime::pin]
+pub trait Pinnable: Sized {
+    /// What it is called in errors, e.g. "an instance"
+    const KIND: &'static str;
+
+    /// The object of the value, None if it is not of this type
+    fn from_value(value: Value) -> Option<GCObjectOf<Self>>;
+}
+
+impl Pinnable for Object {
+    const KIND: &'static str = "an object";
+
+    fn from_value(value: Value) -> Option<GCObjectOf<Self>> {
+        value.is_object().then(|| value.as_object())
+    }
+}
+
+impl Pinnable for Instance {
+    const KIND: &'static str = "an instance";
+
+    fn from_value(value: Value) -> Option<GCObjectOf<Self>> {
+        match Object::from_value(value)?.object_type {
+            ObjectType::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+}
+
+impl Pinnable for Class {
+    const KIND: &'static str = "a class";
+
+    fn from_value(value: Value) -> Option<GCObjectOf<Self>> {
+        match Object::from_value(value)?.object_type {
+            ObjectType::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+}
+
+impl Pinnable for Closure {
+    const KIND: &'static str = "a closure";
+
+    fn from_value(value: Value) -> Option<GCObjectOf<Self>> {
+        match Object::from_value(value)?.object_type {
+            ObjectType::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+}
+
+/// A pinned object of a runtime, see the [module docs](self)
+#[must_use = "the object stays alive until the handle is released"]
+pub struct Handle<T> {
+    pub(crate) object: GCObjectOf<T>,
+    /// The id of the [ObjectAllocator] that pinned the object
+    pub(crate) allocator: u64,
+}
+
+impl<T> Handle<T> {
+    pub(crate) fn new(object: GCObjectOf<T>, allocator: u64) -> Self {
+        Handle { object, allocator }
+    }
+
+    /// True if both handles are for the same object
+    pub fn same_object(&self, other: &Handle<T>) -> bool {
+        self.object.as_ptr() == other.object.as_ptr() && self.allocator == other.allocator
+    }
+}
+
+impl<T> std::fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Handle")
+            .field("object", &self.object)
+            .field("allocator", &self.allocator)
+            .finish()
+    }
+}
@@ -1,27 +1,130 @@
 //! Defines the data structures that are used across evie.
 //! Also defines the memory management (Garbage Collection) for evie
 use std::{
-    cell::{Cell, RefCell},
+    alloc::Layout,
+    io::Write,
     ptr::NonNull,
-    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
-use objects::{GCObjectOf, Object, ObjectType};
+use clock::Instant;
+use gc::{
+    trace_erased, GcStats, Trace, TraceFn, Tracer, TypeStats, GC_HEAP_GROW_FACTOR,
+    INITIAL_GC_THRESHOLD,
+};
+use handle::Handle;
+use objects::{GCObjectOf, Object, ObjectType, Rope, WeakGCObjectOf};
+use pool::Pools;
 use rustc_hash::FxHashMap;
+use shape::Shape;
+use sync::{Cell, RefCell};
 pub mod cache;
 pub mod chunk;
+pub mod clock;
+pub mod convert;
+pub mod gc;
+pub mod handle;
 pub mod objects;
+pub mod pool;
+pub mod replay;
+pub mod runtime;
+pub mod runtime_memory;
+pub mod shape;
+pub mod snapshot;
+mod sync;
 
-type Mutable<T> = Rc<RefCell<T>>;
+/// Strings created at runtime up to this length (in bytes) are interned by default, see [ObjectAllocator::alloc_string]
+pub const DEFAULT_INTERN_LIMIT: usize = 256;
 
 #[derive(Debug)]
 struct InternedValue(GCObjectOf<Box<str>>, Option<GCObjectOf<Object>>);
 
+/// The id of the next [ObjectAllocator], so that a [Handle] is not resolved by another allocator
+static NEXT_ALLOCATOR_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Called with the object right before it is freed
+type Finalizer = Box<dyn FnOnce(NonNull<u8>) + Send>;
+
+/// A registered allocation, with what is needed to free it without knowing its type
+struct Allocation {
+    /// Unique for the lifetime of the allocator (addresses get reused), see [objects::WeakGCObjectOf]
+    id: u64,
+    size: usize,
+    type_name: &'static str,
+    trace: TraceFn,
+    drop: unsafe fn(NonNull<u8>),
+    layout: Layout,
+    finalizer: Option<Finalizer>,
+}
+
+impl Allocation {
+    /// # Safety
+    /// `address` must be the address of this allocation and it must not be used afterwards.
+    unsafe fn free(self, address: usize, pools: &RefCell<Pools>) {
+        let object = NonNull::new_unchecked(address as *mut u8);
+        if let Some(finalizer) = self.finalizer {
+            finalizer(object);
+        }
+        (self.drop)(object);
+        pools.borrow_mut().dealloc(object, self.layout)
+    }
+}
+
+unsafe fn drop_erased<T>(object: NonNull<u8>) {
+    std::ptr::drop_in_place(object.cast::<T>().as_ptr())
+}
+
 /// A simple [objects::GCObjectOf] allocator.
-/// Internally uses [Box] to create/destroy objects
+/// The objects are allocated in size class pools (see [pool]), the memory of a freed object is reused by the next
+/// object of the same size class.
+/// Every allocation is registered, so that the ones not reachable can be freed by [ObjectAllocator::collect]
 pub struct ObjectAllocator {
     bytes_allocated: Cell<usize>,
-    interned_strings: Mutable<FxHashMap<Box<str>, InternedValue>>,
+    /// Every byte ever allocated, freed or not
+    total_bytes_allocated: Cell<usize>,
+    interned_strings: RefCell<FxHashMap<Box<str>, InternedValue>>,
+    allocations: RefCell<FxHashMap<usize, Allocation>>,
+    next_id: Cell<u64>,
+    /// The id of the first object allocated after the last collection, the young objects are the ones from it on
+    young_from: Cell<u64>,
+    /// The id of the first object not reported yet, see [ObjectAllocator::trace_allocations]
+    #[cfg(feature = "tracing")]
+    traced_from: Cell<u64>,
+    /// A collection is due when bytes_allocated reaches this
+    next_gc: Cell<usize>,
+    /// Collect at every safe point (to flush out GC bugs)
+    stress: Cell<bool>,
+    /// See [ObjectAllocator::set_max_heap_bytes]
+    max_heap_bytes: Cell<Option<usize>>,
+    /// Runtime strings up to this length are interned
+    intern_limit: Cell<usize>,
+    stats: Cell<GcStats>,
+    pools: RefCell<Pools>,
+    /// The root of the shape tree (see [shape]), allocated on first use and never freed
+    empty_shape: Cell<Option<GCObjectOf<Shape>>>,
+    /// Unique for the process, see [Handle]
+    id: u64,
+    /// The number of [Handle]s of each pinned object (by address), they are roots of every collection
+    pinned: RefCell<FxHashMap<usize, usize>>,
+}
+
+// Safety: The allocator is the sole owner of every object it hands out, the [objects::GCObjectOf]s are
+// only handles into that memory. Moving the allocator (together with whatever holds those handles, e.g. the VM)
+// to another thread is therefore sound. It is deliberately not [Sync], the interior mutability is not thread safe.
+unsafe impl Send for ObjectAllocator {}
+
+// Safety: with the `sync` feature every field is behind a lock (see [sync]), the allocations from several threads
+// are serialized. A collection needs the allocator to itself (`&mut`), it can't free the objects another thread has
+// not rooted yet. The objects themselves are not shared, see the [sync] docs.
+#[cfg(feature = "sync")]
+unsafe impl Sync for ObjectAllocator {}
+
+impl Drop for ObjectAllocator {
+    fn drop(&mut self) {
+        let allocations: Vec<_> = self.allocations.get_mut().drain().collect();
+        // Safety: the allocator owns every registered object, nothing can use them after it is dropped
+        unsafe { free_all(allocations, &self.pools) };
+    }
 }
 
 impl ObjectAllocator {
@@ -30,37 +133,158 @@ impl ObjectAllocator {
     pub fn new() -> Self {
         ObjectAllocator {
             bytes_allocated: Cell::new(0),
-            interned_strings: Rc::new(RefCell::new(FxHashMap::default())),
+            total_bytes_allocated: Cell::new(0),
+            interned_strings: RefCell::new(FxHashMap::default()),
+            allocations: RefCell::new(FxHashMap::default()),
+            next_id: Cell::new(0),
+            young_from: Cell::new(0),
+            #[cfg(feature = "tracing")]
+            traced_from: Cell::new(0),
+            next_gc: Cell::new(INITIAL_GC_THRESHOLD),
+            stress: Cell::new(false),
+            max_heap_bytes: Cell::new(None),
+            intern_limit: Cell::new(DEFAULT_INTERN_LIMIT),
+            stats: Cell::new(GcStats::default()),
+            pools: RefCell::new(Pools::default()),
+            empty_shape: Cell::new(None),
+            id: NEXT_ALLOCATOR_ID.fetch_add(1, Ordering::Relaxed),
+            pinned: RefCell::new(FxHashMap::default()),
         }
     }
 
     /// Creates an instance of GCObject
-    pub fn alloc<T>(&self, object: T) -> GCObjectOf<T> {
-        let v = Box::new(object);
-        let bytes_allocated = std::mem::size_of::<T>();
+    pub fn alloc<T: Trace>(&self, object: T) -> GCObjectOf<T> {
+        let layout = Layout::new::<T>();
+        // The bytes the object owns (e.g. the text of a string) are on the heap too
+        let bytes_allocated = std::mem::size_of::<T>() + object.owned_bytes();
+        let ptr = self.pools.borrow_mut().alloc(layout).cast::<T>();
+        // Safety: the block is allocated for T and not used by another object
+        unsafe { ptr.as_ptr().write(object) };
         self.increment_allocated_bytes_by(bytes_allocated);
         #[cfg(feature = "trace_enabled")]
         evie_common::trace!(
             "Allocated {} bytes for {}",
-            std::mem::size_of::<T>(),
+            bytes_allocated,
             std::any::type_name::<T>()
         );
-        let ptr = unsafe { NonNull::new_unchecked(Box::into_raw(v)) };
+        let id = self.next_id.update(|id| id + 1) - 1;
+        self.allocations.borrow_mut().insert(
+            ptr.as_ptr() as usize,
+            Allocation {
+                id,
+                size: bytes_allocated,
+                type_name: std::any::type_name::<T>(),
+                trace: trace_erased::<T>,
+                drop: drop_erased::<T>,
+                layout,
+                finalizer: None,
+            },
+        );
         GCObjectOf::new(ptr)
     }
 
+    /// Charges the bytes the object owns again after it grew or shrank (e.g. text appended to a string buffer),
+    /// see [Trace::owned_bytes]
+    pub fn resize<T: Trace>(&self, object: GCObjectOf<T>) {
+        let size = std::mem::size_of::<T>() + T::owned_bytes(&object);
+        let previous = match self
+            .allocations
+            .borrow_mut()
+            .get_mut(&(object.as_ptr() as usize))
+        {
+            Some(allocation) => std::mem::replace(&mut allocation.size, size),
+            None => return,
+        };
+        if size > previous {
+            self.increment_allocated_bytes_by(size - previous);
+        } else {
+            self.decrement_allocated_bytes_by(previous - size);
+        }
+    }
+
+    /// Sets the finalizer of the object, it is called right before the object is freed (swept).
+    /// Use it to release host resources (files, sockets, ...) held by the object.
+    /// The finalizer must not access other GC objects, they may have been freed already.
+    pub fn set_finalizer<T, F: FnOnce(&mut T) + Send + 'static>(
+        &self,
+        object: GCObjectOf<T>,
+        finalizer: F,
+    ) {
+        let mut allocations = self.allocations.borrow_mut();
+        if let Some(allocation) = allocations.get_mut(&(object.as_ptr() as usize)) {
+            allocation.finalizer = Some(Box::new(move |object: NonNull<u8>| {
+                // Safety: the finalizer is only called with the object it was set on, before it is dropped
+                finalizer(unsafe { object.cast::<T>().as_mut() })
+            }));
+        } else {
+            panic!("BUG: Object {:?} is not allocated", object);
+        }
+    }
+
+    /// Creates a [WeakGCObjectOf] that does not keep the object alive
+    pub fn downgrade<T>(&self, object: GCObjectOf<T>) -> WeakGCObjectOf<T> {
+        let allocations = self.allocations.borrow();
+        if let Some(allocation) = allocations.get(&(object.as_ptr() as usize)) {
+            WeakGCObjectOf::new(object.reference, allocation.id)
+        } else {
+            panic!("BUG: Object {:?} is not allocated", object);
+        }
+    }
+
+    /// Returns the object if it has not been freed
+    pub fn upgrade<T>(&self, weak: &WeakGCObjectOf<T>) -> Option<GCObjectOf<T>> {
+        let allocations = self.allocations.borrow();
+        match allocations.get(&(weak.reference.as_ptr() as usize)) {
+            Some(allocation) if allocation.id == weak.id => Some(GCObjectOf::new(weak.reference)),
+            _ => None,
+        }
+    }
+
+    /// Creates a [Handle] that keeps the object alive until it is released with [ObjectAllocator::unpin]
+    pub fn pin<T>(&self, object: GCObjectOf<T>) -> Handle<T> {
+        let address = object.as_ptr() as usize;
+        if !self.allocations.borrow().contains_key(&address) {
+            panic!("BUG: Object {:?} is not allocated", object);
+        }
+        *self.pinned.borrow_mut().entry(address).or_insert(0) += 1;
+        Handle::new(object, self.id)
+    }
+
+    /// Releases the handle, the object is freed by a collection once it is not reachable
+    pub fn unpin<T>(&self, handle: Handle<T>) {
+        let address = self.resolve(&handle).as_ptr() as usize;
+        let mut pinned = self.pinned.borrow_mut();
+        match pinned.get_mut(&address) {
+            Some(count) if *count > 1 => *count -= 1,
+            _ => {
+                pinned.remove(&address);
+            }
+        }
+    }
+
+    /// The object of the handle
+    ///
+    /// # Panics
+    /// If the handle was created by another allocator
+    pub(crate) fn resolve<T>(&self, handle: &Handle<T>) -> GCObjectOf<T> {
+        if handle.allocator != self.id {
+            panic!("The handle {:?} belongs to another runtime", handle);
+        }
+        handle.object
+    }
+
     /// Creates an interned instance of GCObject<Box<str>>
     pub fn alloc_interned_str<T: AsRef<str>>(&self, object: T) -> GCObjectOf<Box<str>> {
         let object = object.as_ref().to_string().into_boxed_str();
         let v = self.interned_strings.borrow();
         if let Some(v) = v.get(&object) {
-            (*v).0
+            v.0
         } else {
             drop(v);
             let string = self.alloc(object.clone());
-            let mut v = (*self.interned_strings).borrow_mut();
-            v.insert(object, InternedValue(string, None));
-            string
+            let mut v = self.interned_strings.borrow_mut();
+            // Another thread may have interned it in the meantime (see [sync]), this string is then garbage
+            v.entry(object).or_insert(InternedValue(string, None)).0
         }
     }
 
@@ -80,19 +304,71 @@ impl ObjectAllocator {
         }
     }
 
+    /// Allocates a String created at runtime. Strings up to [ObjectAllocator::intern_limit] are interned,
+    /// so that equal strings are the same object and `==` compares pointers.
+    /// Longer ones are not (hashing them costs as much as comparing them), they are allocated as a [Rope].
+    pub fn alloc_string<T: AsRef<str>>(&self, string: T) -> GCObjectOf<Object> {
+        let string = string.as_ref();
+        if string.len() <= self.intern_limit() {
+            return self.alloc_interned_object(self.alloc_interned_str(string));
+        }
+        let rope = Rope::new(self.alloc(string.to_string()), 0, string.len());
+        Object::new_gc_object(ObjectType::Rope(self.alloc(rope)), self)
+    }
+
+    /// Allocates the bytes in `range` of the text of a String (or [Rope]), None if `string` is not one.
+    /// Like [ObjectAllocator::alloc_string] the substrings up to the intern limit are interned, the longer substrings
+    /// of a [Rope] are ropes of the same buffer, so they are not copied.
+    pub fn alloc_substring(
+        &self,
+        string: ObjectType,
+        range: std::ops::Range<usize>,
+    ) -> Option<GCObjectOf<Object>> {
+        let text = string.as_str()?.get(range.clone())?;
+        match string {
+            ObjectType::Rope(rope) if text.len() > self.intern_limit() => {
+                let slice = self.alloc(rope.slice(range));
+                Some(Object::new_gc_object(ObjectType::Rope(slice), self))
+            }
+            _ => Some(self.alloc_string(text)),
+        }
+    }
+
+    /// The shape of the instances without fields, the root of the shape tree
+    pub fn empty_shape(&self) -> GCObjectOf<Shape> {
+        self.empty_shape
+            .update(|shape| shape.or_else(|| Some(self.alloc(Shape::empty()))))
+            .expect("Expect the empty shape")
+    }
+
+    /// The maximum length (in bytes) of the runtime strings that are interned
+    pub fn intern_limit(&self) -> usize {
+        self.intern_limit.get()
+    }
+
+    /// Sets [ObjectAllocator::intern_limit], the strings already allocated are left as they are
+    pub fn set_intern_limit(&self, intern_limit: usize) {
+        self.intern_limit.set(intern_limit);
+    }
+
     /// # Safety
     /// The caller should ensure that the object was note previously de allocated.
     /// This can cause double free.
     pub unsafe fn free<T>(&self, object_of: GCObjectOf<T>) {
-        {
-            // Gets freed when the object is dropped
-            Box::from_raw(object_of.reference.as_ptr());
+        let address = object_of.as_ptr() as usize;
+        let allocation = self.allocations.borrow_mut().remove(&address);
+        let bytes_to_deallocate = allocation
+            .as_ref()
+            .map_or(std::mem::size_of::<T>(), |allocation| allocation.size);
+        match allocation {
+            // Runs the finalizer, if any, and drops the object
+            Some(allocation) => allocation.free(address, &self.pools),
+            None => drop(Box::from_raw(object_of.reference.as_ptr())),
         }
-        let bytes_to_deallocate = std::mem::size_of::<T>();
         #[cfg(feature = "trace_enabled")]
         evie_common::trace!(
             "Deallocated {} bytes for {}",
-            std::mem::size_of::<T>(),
+            bytes_to_deallocate,
             std::any::type_name::<T>()
         );
         assert!(self.bytes_allocated.get() >= bytes_to_deallocate);
@@ -104,20 +380,293 @@ impl ObjectAllocator {
         self.bytes_allocated.get()
     }
 
+    /// The number of objects allocated so far, including the ones that have been freed
+    pub fn allocation_count(&self) -> u64 {
+        self.next_id.get()
+    }
+
+    /// The number of bytes allocated so far, including the ones that have been freed
+    pub fn total_bytes_allocated(&self) -> usize {
+        self.total_bytes_allocated.get()
+    }
+
+    /// Returns true if a collection is due, the caller (at a safe point) should then call [ObjectAllocator::collect]
+    #[inline(always)]
+    pub fn should_collect(&self) -> bool {
+        self.bytes_allocated.get() >= self.next_gc.get()
+    }
+
+    /// Makes [ObjectAllocator::should_collect] return true, i.e. collect at the next safe point
+    pub fn request_collection(&self) {
+        self.next_gc.set(0);
+    }
+
+    /// In stress mode a collection is due at every safe point
+    pub fn set_stress(&self, stress: bool) {
+        self.stress.set(stress);
+        if stress {
+            self.request_collection();
+        }
+    }
+
+    /// Caps the heap: a collection is due when `max` bytes are allocated and if the reachable objects still take
+    /// `max` bytes or more after it, the heap is out of memory (see [ObjectAllocator::is_out_of_memory]).
+    /// None (the default) leaves the heap unbounded
+    pub fn set_max_heap_bytes(&self, max: Option<usize>) {
+        self.max_heap_bytes.set(max);
+        if let Some(max) = max {
+            self.next_gc.set(self.next_gc.get().min(max));
+        }
+    }
+
+    pub fn max_heap_bytes(&self) -> Option<usize> {
+        self.max_heap_bytes.get()
+    }
+
+    /// True if the objects take the bytes the heap is capped to, checked by the caller after a collection
+    /// (see [ObjectAllocator::set_max_heap_bytes])
+    pub fn is_out_of_memory(&self) -> bool {
+        self.max_heap_bytes
+            .get()
+            .is_some_and(|max| self.bytes_allocated() >= max)
+    }
+
+    /// Returns the [GcStats]
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            objects: self.allocations.borrow().len(),
+            bytes_allocated: self.bytes_allocated(),
+            pooled_bytes: self.pools.borrow().reserved_bytes(),
+            ..self.stats.get()
+        }
+    }
+
+    /// The live objects and bytes by type, the types that take the most bytes first
+    pub fn allocation_stats(&self) -> Vec<TypeStats> {
+        let mut by_type: FxHashMap<&'static str, TypeStats> = FxHashMap::default();
+        for allocation in self.allocations.borrow().values() {
+            let stats = by_type
+                .entry(allocation.type_name)
+                .or_insert_with(|| TypeStats {
+                    type_name: short_type_name(allocation.type_name),
+                    objects: 0,
+                    bytes: 0,
+                    young_objects: 0,
+                });
+            stats.objects += 1;
+            stats.bytes += allocation.size;
+            if allocation.id >= self.young_from.get() {
+                stats.young_objects += 1;
+            }
+        }
+        let mut stats: Vec<TypeStats> = by_type.into_values().collect();
+        stats.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.type_name.cmp(&b.type_name))
+        });
+        stats
+    }
+
+    /// Reports the objects allocated since the last report (freed or not) as `tracing` events, one by type with
+    /// the `monotonic_counter.allocations` and `monotonic_counter.allocated_bytes` fields.
+    /// Called by every collection (before it frees the objects), and by the VM at the end of a run.
+    #[cfg(feature = "tracing")]
+    pub fn trace_allocations(&self) {
+        let from = self.traced_from.replace(self.next_id.get());
+        let mut by_type: FxHashMap<&'static str, (u64, u64)> = FxHashMap::default();
+        for allocation in self.allocations.borrow().values() {
+            if allocation.id >= from {
+                let (objects, bytes) = by_type.entry(allocation.type_name).or_default();
+                *objects += 1;
+                *bytes += allocation.size as u64;
+            }
+        }
+        for (type_name, (objects, bytes)) in by_type {
+            tracing::info!(
+                target: "evie_memory::allocations",
+                r#type = %short_type_name(type_name),
+                monotonic_counter.allocations = objects,
+                monotonic_counter.allocated_bytes = bytes,
+            );
+        }
+    }
+
+    /// Frees every object that is not reachable from the roots marked by `mark_roots`.
+    /// It takes the allocator to itself: nothing else (e.g. a compiler, or another thread with the `sync` feature)
+    /// can be allocating objects it has not rooted yet
+    pub fn collect<F: FnOnce(&mut Tracer)>(&mut self, mark_roots: F) {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "gc",
+            objects_freed = tracing::field::Empty,
+            bytes_freed = tracing::field::Empty,
+            young_objects_freed = tracing::field::Empty
+        )
+        .entered();
+        #[cfg(feature = "tracing")]
+        self.trace_allocations();
+        let start = Instant::now();
+        let mut tracer = Tracer::new();
+        mark_roots(&mut tracer);
+        self.mark_pinned(&mut tracer);
+        self.empty_shape.get().trace(&mut tracer);
+        tracer.trace_references();
+        // Interned strings are weak references
+        self.interned_strings.borrow_mut().retain(|_, v| {
+            if v.1.is_some_and(|o| !tracer.is_marked(o.as_ptr() as usize)) {
+                v.1 = None;
+            }
+            tracer.is_marked(v.0.as_ptr() as usize)
+        });
+        let unreachable: Vec<_> = {
+            let mut allocations = self.allocations.borrow_mut();
+            let addresses: Vec<usize> = allocations
+                .keys()
+                .filter(|address| !tracer.is_marked(**address))
+                .copied()
+                .collect();
+            addresses
+                .into_iter()
+                .filter_map(|address| allocations.remove(&address).map(|a| (address, a)))
+                .collect()
+        };
+        let freed = unreachable.len();
+        let bytes_freed: usize = unreachable.iter().map(|(_, a)| a.size).sum();
+        let young_freed = unreachable
+            .iter()
+            .filter(|(_, a)| a.id >= self.young_from.get())
+            .count();
+        self.young_from.set(self.next_id.get());
+        // Safety: the objects are not reachable and are freed exactly once, as they are removed from the registry
+        unsafe { free_all(unreachable, &self.pools) };
+        self.decrement_allocated_bytes_by(bytes_freed);
+        if self.stress.get() {
+            self.request_collection();
+        } else {
+            let next_gc = (self.bytes_allocated() * GC_HEAP_GROW_FACTOR).max(INITIAL_GC_THRESHOLD);
+            self.next_gc
+                .set(next_gc.min(self.max_heap_bytes.get().unwrap_or(usize::MAX)));
+        }
+        let pause = start.elapsed();
+        let stats = self.stats.get();
+        self.stats.set(GcStats {
+            collections: stats.collections + 1,
+            objects_freed: stats.objects_freed + freed,
+            young_objects_freed: stats.young_objects_freed + young_freed,
+            total_pause: stats.total_pause + pause,
+            max_pause: stats.max_pause.max(pause),
+            ..stats
+        });
+        #[cfg(feature = "trace_enabled")]
+        evie_common::trace!(
+            "GC freed {} objects ({} bytes) in {} us",
+            freed,
+            bytes_freed,
+            pause.as_micros()
+        );
+        #[cfg(feature = "tracing")]
+        {
+            span.record("objects_freed", &(freed as u64));
+            span.record("bytes_freed", &(bytes_freed as u64));
+            span.record("young_objects_freed", &(young_freed as u64));
+        }
+    }
+
+    /// Marks the objects of the [Handle]s
+    fn mark_pinned(&self, tracer: &mut Tracer) {
+        let allocations = self.allocations.borrow();
+        for address in self.pinned.borrow().keys() {
+            if let Some(allocation) = allocations.get(address) {
+                // Safety: the object is allocated, pinned objects are not freed
+                tracer.mark_erased(
+                    unsafe { NonNull::new_unchecked(*address as *mut u8) },
+                    allocation.trace,
+                );
+            }
+        }
+    }
+
+    /// Writes every allocated object as a line of JSON, in the order they were allocated, e.g.
+    /// `{"id":12,"type":"Closure","size":16,"reachable":true,"references":[10,11]}`.
+    /// `size` is the size of the object itself (not of what it references), `reachable` is true if the object
+    /// is reachable from the roots marked by `mark_roots` (i.e. it survives a collection) and `references`
+    /// are the ids of the objects it references.
+    pub fn heap_dump<F: FnOnce(&mut Tracer)>(
+        &self,
+        mark_roots: F,
+        writer: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        let mut reachable = Tracer::new();
+        mark_roots(&mut reachable);
+        self.mark_pinned(&mut reachable);
+        self.empty_shape.get().trace(&mut reachable);
+        reachable.trace_references();
+        let allocations = self.allocations.borrow();
+        let mut objects: Vec<_> = allocations.iter().collect();
+        objects.sort_by_key(|(_, allocation)| allocation.id);
+        for (&address, allocation) in objects {
+            let mut references = Tracer::new();
+            // Safety: the object is allocated, tracing only reads it
+            unsafe {
+                (allocation.trace)(NonNull::new_unchecked(address as *mut u8), &mut references)
+            };
+            let references: Vec<String> = references
+                .pending()
+                .filter_map(|reference| allocations.get(&reference))
+                .map(|reference| reference.id.to_string())
+                .collect();
+            writeln!(
+                writer,
+                r#"{{"id":{},"type":"{}","size":{},"reachable":{},"references":[{}]}}"#,
+                allocation.id,
+                short_type_name(allocation.type_name),
+                allocation.size,
+                reachable.is_marked(address),
+                references.join(",")
+            )?;
+        }
+        Ok(())
+    }
+
     fn increment_allocated_bytes_by(&self, bytes_allocated: usize) {
-        self.bytes_allocated
-            .set(self.bytes_allocated() + bytes_allocated);
+        self.bytes_allocated.update(|bytes| bytes + bytes_allocated);
+        self.total_bytes_allocated
+            .update(|bytes| bytes + bytes_allocated);
     }
 
     fn decrement_allocated_bytes_by(&self, bytes: usize) {
-        self.bytes_allocated.set(self.bytes_allocated() - bytes);
+        self.bytes_allocated.update(|allocated| allocated - bytes);
+    }
+}
+
+/// The type name without the module paths, e.g. `Cache<Value>` for `evie_memory::cache::Cache<evie_memory::objects::nan_boxed::Value>`
+fn short_type_name(type_name: &str) -> String {
+    type_name
+        .split_inclusive(|c: char| "<>, ".contains(c))
+        .map(|part| part.rfind("::").map_or(part, |i| &part[i + 2..]))
+        .collect()
+}
+
+/// Frees the allocations, running all the finalizers before dropping any object
+///
+/// # Safety
+/// The allocations must not be reachable.
+unsafe fn free_all(mut allocations: Vec<(usize, Allocation)>, pools: &RefCell<Pools>) {
+    for (address, allocation) in allocations.iter_mut() {
+        if let Some(finalizer) = allocation.finalizer.take() {
+            finalizer(NonNull::new_unchecked(*address as *mut u8));
+        }
+    }
+    for (address, allocation) in allocations {
+        allocation.free(address, pools)
     }
 }
 
 #[allow(unused)]
 #[cfg(test)]
 mod tests {
-    use std::{f64::EPSILON, time::Instant};
+    use std::time::Instant;
 
     use crate::{
         chunk::Chunk,
@@ -125,12 +674,37 @@ mod tests {
         ObjectAllocator,
     };
 
+    #[test]
+    #[cfg(feature = "sync")]
+    fn shared_by_threads() {
+        let mut allocator = ObjectAllocator::new();
+        let strings: Vec<Vec<usize>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..100)
+                            .map(|i| {
+                                allocator.alloc_interned_str(format!("s{}", i)).as_ptr() as usize
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        // Every thread got the same interned strings
+        assert!(strings.iter().all(|s| *s == strings[0]));
+        allocator.collect(|_| {});
+        assert_eq!(0, allocator.stats().objects);
+    }
+
     #[test]
     fn allocation_test() {
         let managed_objects = ObjectAllocator::new();
         let name: GCObjectOf<Box<str>> = managed_objects.alloc("object".into());
+        // The text is charged too
         assert_eq!(
-            std::mem::size_of::<Box<str>>(),
+            std::mem::size_of::<Box<str>>() + 6,
             managed_objects.bytes_allocated()
         );
         let chunk = managed_objects.alloc(Chunk::new());
@@ -142,20 +716,155 @@ mod tests {
         )));
         assert_eq!(
             std::mem::size_of::<Box<str>>()
+                + 6
                 + std::mem::size_of::<Function>()
                 + std::mem::size_of::<Chunk>(),
             managed_objects.bytes_allocated()
         );
         unsafe { managed_objects.free(function) };
         assert_eq!(
-            std::mem::size_of::<Box<str>>() + std::mem::size_of::<Chunk>(),
+            std::mem::size_of::<Box<str>>() + 6 + std::mem::size_of::<Chunk>(),
             managed_objects.bytes_allocated()
         );
+        // Growing a buffer charges the bytes it grew by, they are released when it is freed
+        let mut buffer = managed_objects.alloc(String::with_capacity(16));
+        buffer.push_str(&"a".repeat(100));
+        managed_objects.resize(buffer);
+        assert_eq!(
+            std::mem::size_of::<Box<str>>()
+                + 6
+                + std::mem::size_of::<Chunk>()
+                + std::mem::size_of::<String>()
+                + buffer.capacity(),
+            managed_objects.bytes_allocated()
+        );
+        unsafe { managed_objects.free(buffer) };
         unsafe { managed_objects.free(name) };
         unsafe { managed_objects.free(chunk) };
         assert_eq!(0, managed_objects.bytes_allocated());
     }
 
+    #[test]
+    fn collect_frees_unreachable_objects() {
+        use crate::gc::Trace;
+        let mut allocator = ObjectAllocator::new();
+        let live = allocator.alloc_interned_str("live");
+        let dead = allocator.alloc_interned_str("dead");
+        let weak_live = allocator.downgrade(live);
+        let weak_dead = allocator.downgrade(dead);
+        assert_eq!(2, allocator.stats().objects);
+
+        allocator.collect(|tracer| live.trace(tracer));
+        let stats = allocator.stats();
+        assert_eq!(1, stats.objects);
+        assert_eq!(1, stats.objects_freed);
+        assert_eq!(1, stats.collections);
+        assert_eq!(
+            std::mem::size_of::<Box<str>>() + "live".len(),
+            stats.bytes_allocated
+        );
+        assert!(allocator.upgrade(&weak_live).is_some());
+        assert!(allocator.upgrade(&weak_dead).is_none());
+        // the interned string was removed, so this is a new allocation
+        let dead_again = allocator.alloc_interned_str("dead");
+        assert!(allocator.upgrade(&weak_dead).is_none());
+        assert_eq!("dead", &**dead_again);
+        assert_eq!(live.as_ptr(), allocator.alloc_interned_str("live").as_ptr());
+    }
+
+    #[test]
+    fn allocation_stats_by_type_and_generation() {
+        use crate::gc::{Trace, TypeStats};
+        let mut allocator = ObjectAllocator::new();
+        let old = allocator.alloc_interned_str("old");
+        let _garbage = allocator.alloc_interned_str("garbage");
+        allocator.collect(|tracer| old.trace(tracer));
+        assert_eq!(1, allocator.stats().young_objects_freed);
+        let chunk = allocator.alloc(Chunk::new());
+        let _young = allocator.alloc_interned_str("young");
+        assert_eq!(
+            vec![
+                TypeStats {
+                    type_name: "Chunk".to_string(),
+                    objects: 1,
+                    bytes: std::mem::size_of::<Chunk>(),
+                    young_objects: 1,
+                },
+                TypeStats {
+                    type_name: "Box<str>".to_string(),
+                    objects: 2,
+                    bytes: 2 * std::mem::size_of::<Box<str>>() + "old".len() + "young".len(),
+                    young_objects: 1,
+                },
+            ],
+            allocator.allocation_stats()
+        );
+        // The old string survived a collection before, only the young ones count
+        allocator.collect(|tracer| chunk.trace(tracer));
+        let stats = allocator.stats();
+        assert_eq!((3, 2), (stats.objects_freed, stats.young_objects_freed));
+        assert_eq!(0, allocator.allocation_stats()[0].young_objects);
+    }
+
+    #[test]
+    fn heap_dump() {
+        use crate::{cache::Cache, gc::Trace};
+        let allocator = ObjectAllocator::new();
+        let name = allocator.alloc_interned_str("f");
+        let chunk = allocator.alloc(Chunk::new());
+        let function = allocator.alloc(UserDefinedFunction::new(Some(name), chunk, 0, 0));
+        let _garbage: GCObjectOf<Cache<GCObjectOf<Box<str>>>> = allocator.alloc(Cache::new());
+        let mut buf = vec![];
+        allocator
+            .heap_dump(|tracer| function.trace(tracer), &mut buf)
+            .unwrap();
+        assert_eq!(
+            format!(
+                r#"{{"id":0,"type":"Box<str>","size":{},"reachable":true,"references":[]}}
+{{"id":1,"type":"Chunk","size":{},"reachable":true,"references":[]}}
+{{"id":2,"type":"UserDefinedFunction","size":{},"reachable":true,"references":[0,1]}}
+{{"id":3,"type":"Cache<GCObjectOf<Box<str>>>","size":{},"reachable":false,"references":[]}}
+"#,
+                std::mem::size_of::<Box<str>>() + 1,
+                std::mem::size_of::<Chunk>(),
+                std::mem::size_of::<UserDefinedFunction>(),
+                std::mem::size_of::<Cache<GCObjectOf<Box<str>>>>(),
+            ),
+            evie_common::utf8_to_string(&buf)
+        );
+    }
+
+    #[test]
+    fn finalizers_run_when_swept() {
+        use crate::gc::Trace;
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        let finalized = Arc::new(AtomicUsize::new(0));
+        let mut allocator = ObjectAllocator::new();
+        let live: GCObjectOf<Box<str>> = allocator.alloc("a".into());
+        let dead: GCObjectOf<Box<str>> = allocator.alloc("bb".into());
+        let freed: GCObjectOf<Box<str>> = allocator.alloc("ccc".into());
+        let dropped: GCObjectOf<Box<str>> = allocator.alloc("dddd".into());
+        for object in [live, dead, freed, dropped] {
+            let finalized = finalized.clone();
+            allocator.set_finalizer(object, move |v: &mut Box<str>| {
+                finalized.fetch_add(v.len(), Ordering::SeqCst);
+            });
+        }
+        allocator.collect(|tracer| {
+            live.trace(tracer);
+            freed.trace(tracer);
+            dropped.trace(tracer);
+        });
+        assert_eq!(2, finalized.load(Ordering::SeqCst));
+        unsafe { allocator.free(freed) };
+        assert_eq!(5, finalized.load(Ordering::SeqCst));
+        drop(allocator);
+        assert_eq!(10, finalized.load(Ordering::SeqCst));
+    }
+
     #[test]
     fn timing_non_nan_boxed_value() {
         use crate::objects::non_nan_boxed::Value;
@@ -180,11 +889,11 @@ mod tests {
         }
         #[inline(always)]
         fn num_equals(l: f64, r: f64) -> bool {
-            (l - r).abs() < EPSILON
+            (l - r).abs() < f64::EPSILON
         }
 
         let mut objects = ObjectAllocator::new();
-        let constants = vec![
+        let constants = [
             Value::number(1.0),
             Value::bool(true),
             Value::bool(false),
@@ -232,7 +941,7 @@ mod tests {
         let mut objects = ObjectAllocator::new();
         let str: GCObjectOf<Box<str>> = objects.alloc_interned_str("str");
         let stru: GCObjectOf<Box<str>> = objects.alloc_interned_str("stru");
-        let constants = vec![
+        let constants = [
             Value::number(1.0),
             Value::bool(true),
             Value::bool(false),
@@ -8,9 +8,9 @@ use std::{
 use crate::objects::nan_boxed::Value;
 #[cfg(not(feature = "nan_boxed"))]
 use crate::objects::non_nan_boxed::Value;
-use crate::{cache::Cache, chunk::Chunk, ObjectAllocator};
+use crate::{cache::Cache, chunk::Chunk, runtime::EvieRuntime, shape::Fields, ObjectAllocator};
 use derive_new::new;
-use evie_common::{bail, Writer};
+use evie_common::{bail, errors::Result, Writer};
 pub mod nan_boxed {
     // Bit Flags
     pub(crate) const QNAN_BIT_FLAG: usize = 0x7ffc000000000000;
@@ -28,9 +28,20 @@ pub mod nan_boxed {
     use std::fmt::Display;
 
     /// A word sized value
-    #[derive(Clone, Copy, PartialEq, Eq)]
+    #[derive(Clone, Copy)]
     pub struct Value(pub(crate) usize);
 
+    /// Numbers are compared as IEEE 754 doubles (NaN is not equal to itself, 0 is equal to -0), as
+    /// [super::non_nan_boxed::Value] does, the other values by their bits
+    impl PartialEq for Value {
+        fn eq(&self, other: &Self) -> bool {
+            if self.is_number() && other.is_number() {
+                return self.as_number() == other.as_number();
+            }
+            self.0 == other.0
+        }
+    }
+
     impl std::fmt::Debug for Value {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             let v_type: ValueType = self.into();
@@ -61,7 +72,10 @@ pub mod nan_boxed {
             match v_type {
                 ValueType::Nil => f.write_str("nil"),
                 ValueType::Boolean => f.write_str(&self.as_bool().to_string()),
-                ValueType::Number => f.write_str(&self.as_number().to_string()),
+                ValueType::Number => f.write_str(&super::format_number(
+                    self.as_number(),
+                    super::PRINTED_DIGITS,
+                )),
                 ValueType::Object => f.write_str(&self.as_object().to_string()),
             }
         }
@@ -86,8 +100,11 @@ pub mod nan_boxed {
                 FALSE
             }
         }
+        /// Every NaN is stored as [f64::NAN]: the payload of another NaN could have the bits of nil, a boolean or an
+        /// object
         #[inline(always)]
         pub fn number(n: f64) -> Self {
+            let n = if n.is_nan() { f64::NAN } else { n };
             Value(usize::from_be_bytes(n.to_be_bytes()))
         }
         #[inline(always)]
@@ -169,6 +186,46 @@ pub enum ValueType {
     Object,
 }
 
+/// The significant digits of the numbers printed by Evie, as clox (`printf("%g")`)
+pub const PRINTED_DIGITS: usize = 6;
+
+/// Formats the number as C's `%.{digits}g`, as clox prints numbers: rounded to `digits` significant digits, without
+/// trailing zeros, in scientific notation if the exponent is less than -4 or at least `digits`.
+/// E.g. with 6 digits, `0.1 + 0.2` is `0.3`, `1234567` is `1.23457e+06` and `0.00001` is `1e-05`.
+pub fn format_number(n: f64, digits: usize) -> String {
+    if n.is_nan() {
+        return "nan".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    let digits = digits.max(1);
+    // The exponent after the rounding, e.g. 999999.5 is 1e+06
+    let scientific = format!("{:.*e}", digits - 1, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    if exponent < -4 || exponent >= digits as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{}{:02}",
+            without_trailing_zeros(mantissa),
+            sign,
+            exponent.abs()
+        )
+    } else {
+        let decimals = (digits as i32 - 1 - exponent) as usize;
+        without_trailing_zeros(&format!("{:.*}", decimals, n)).to_string()
+    }
+}
+
+fn without_trailing_zeros(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
 pub mod non_nan_boxed {
     use super::{GCObjectOf, Object, ValueType};
     use std::fmt::Display;
@@ -176,9 +233,10 @@ pub mod non_nan_boxed {
     /// A runtime 'Value' in Evie. This is the only data structure exposed to the runtime.
     /// It is a combination of primitives such as 'Boolean' and complex data structures like 'Object'
     /// See [Object] for more about objects.
-    #[derive(Debug, Clone, Copy)]
+    #[derive(Debug, Clone, Copy, Default)]
     pub enum Value {
         /// Nil value (nothing, null in other languages)
+        #[default]
         Nil,
         /// Boolean as name suggests
         Boolean(bool),
@@ -204,18 +262,12 @@ pub mod non_nan_boxed {
             match self {
                 Value::Nil => f.write_str("nil"),
                 Value::Boolean(b) => f.write_str(&b.to_string()),
-                Value::Number(n) => f.write_str(&n.to_string()),
+                Value::Number(n) => f.write_str(&super::format_number(*n, super::PRINTED_DIGITS)),
                 Value::Object(o) => f.write_str(&o.to_string()),
             }
         }
     }
 
-    impl Default for Value {
-        fn default() -> Self {
-            Value::Nil
-        }
-    }
-
     impl Value {
         #[inline(always)]
         pub fn nil() -> Self {
@@ -347,6 +399,8 @@ impl Display for Object {
 pub enum ObjectType {
     /// Strings
     String(GCObjectOf<Box<str>>),
+    /// Strings that are not interned, see [Rope]
+    Rope(GCObjectOf<Rope>),
     /// Functions
     Function(GCObjectOf<UserDefinedFunction>),
     /// Native Functions (File access socket access etc.)
@@ -359,12 +413,19 @@ pub enum ObjectType {
     Instance(GCObjectOf<Instance>),
     /// A Bound Method with an instance as a receiver
     BoundMethod(GCObjectOf<BoundMethod>),
+    /// `start..end` or `start..=end`
+    Range(GCObjectOf<Range>),
+    /// The iterator of a [Range]
+    RangeIterator(GCObjectOf<RangeIterator>),
+    /// A function that can suspend itself with `yield`, see [Coroutine]
+    Coroutine(GCObjectOf<Coroutine>),
 }
 
 impl Display for ObjectType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ObjectType::String(s) => f.write_str(&s.to_string()),
+            ObjectType::Rope(r) => f.write_str(r.as_str()),
             ObjectType::Function(fun) => f.write_str(&fun.to_string()),
             ObjectType::Closure(c) => f.write_str(&c.to_string()),
             ObjectType::Class(c) => f.write_str(&c.to_string()),
@@ -372,12 +433,58 @@ impl Display for ObjectType {
             ObjectType::BoundMethod(b) => f.write_str(&format!(
                 "[{} bound to instance of {}]",
                 *b.1.function.name.unwrap(),
-                *b.0.class.name
+                *b.instance().class.name
             )),
             ObjectType::NativeFunction(u) => f.write_str(&u.to_string()),
+            ObjectType::Range(r) => f.write_str(&r.to_string()),
+            ObjectType::RangeIterator(i) => f.write_str(&format!("<iterator of {}>", i.range)),
+            ObjectType::Coroutine(c) => f.write_str(&format!("<coroutine of {}>", c.function)),
         }
     }
 }
+impl ObjectType {
+    /// The text of Strings (and [Rope]s), None for other objects
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ObjectType::String(s) => Some(s),
+            ObjectType::Rope(r) => Some(r.as_str()),
+            _ => None,
+        }
+    }
+}
+
+/// A String that is not interned: built by concatenation (`Opcode::Add` in the VM), so that building a string in a loop
+/// is linear, a substring (see [crate::ObjectAllocator::alloc_substring]) or longer than the intern limit (see
+/// [crate::ObjectAllocator::alloc_string]).
+/// Ropes share their buffer, which is only ever appended to: appending to the rope that ends at the end of the buffer
+/// (the usual `s = s + x`) only copies the appended text, other ropes still see their part of it, and a substring of a
+/// rope is a rope of the same buffer (the buffer is alive as long as one of its ropes is).
+/// For evie a rope is a String, it prints, compares and is passed to natives as its text.
+#[derive(Debug, Clone, Copy, new)]
+pub struct Rope {
+    pub buffer: GCObjectOf<String>,
+    /// The text of this rope is the `length` bytes of `buffer` from `start`
+    pub start: usize,
+    pub length: usize,
+}
+
+impl Rope {
+    pub fn as_str(&self) -> &str {
+        &self.buffer[self.start..self.start + self.length]
+    }
+
+    /// Nothing has been appended to the buffer after this rope's text
+    pub fn is_at_end(&self) -> bool {
+        self.start + self.length == self.buffer.len()
+    }
+
+    /// The rope of the bytes in `range` of this rope's text, in the same buffer
+    pub fn slice(&self, range: std::ops::Range<usize>) -> Rope {
+        debug_assert!(self.as_str().get(range.clone()).is_some());
+        Rope::new(self.buffer, self.start + range.start, range.len())
+    }
+}
+
 impl std::hash::Hash for GCObjectOf<Box<str>> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.reference.hash(state)
@@ -433,6 +540,28 @@ pub struct UserDefinedFunction {
     pub arity: usize,
     /// The number of upvalues to be captured for this function
     pub upvalue_count: usize,
+    /// The docstring, a string literal that is the first statement of the body
+    #[new(default)]
+    pub doc: Option<GCObjectOf<Box<str>>>,
+    /// For `init`, the number of fields its body sets with `this.<field> = ...`: the instances of the class are
+    /// created with room for them
+    #[new(default)]
+    pub field_count: usize,
+    /// For an anonymous function, the name of the variable or property it is assigned to where it is created, e.g.
+    /// `f` for `var f = fun () {...}`
+    #[new(default)]
+    pub inferred_name: Option<GCObjectOf<Box<str>>>,
+}
+
+impl UserDefinedFunction {
+    /// The function in the stack traces and the disassembly: as it is printed, with the inferred name of an anonymous
+    /// function, e.g. `<fn f (anonymous)>`
+    pub fn trace_name(&self) -> String {
+        match self.inferred_name {
+            Some(name) => format!("<fn {} (anonymous)>", name.as_ref()),
+            None => self.to_string(),
+        }
+    }
 }
 
 impl Display for UserDefinedFunction {
@@ -445,8 +574,11 @@ impl Display for UserDefinedFunction {
     }
 }
 
-/// Native function is  basically a function pointer
-pub type NativeFn = fn(Vec<Value>, allocator: &ObjectAllocator) -> Value;
+/// Native function is  basically a function pointer.
+/// It gets the arguments (borrowed from the stack of the VM, so that a call does not allocate) and the [EvieRuntime]
+/// of the VM that calls it, natives must not hold on to it (or its objects) across calls.
+/// An error is reported as a runtime error by the VM.
+pub type NativeFn = fn(&[Value], runtime: &mut EvieRuntime) -> Result<Value>;
 
 /// Native functions are functions implemented in Rust
 #[derive(Clone, new, Copy)]
@@ -471,9 +603,9 @@ impl Display for NativeFunction {
 }
 
 impl NativeFunction {
-    pub fn call(&self, arguments: Vec<Value>, allocator: &ObjectAllocator) -> Value {
+    pub fn call(&self, arguments: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
         let function = self.function;
-        function(arguments, allocator)
+        function(arguments, runtime)
     }
 }
 
@@ -484,14 +616,27 @@ pub struct Class {
     pub name: GCObjectOf<Box<str>>,
     /// Methods defined by this class
     pub methods: GCObjectOf<Cache<GCObjectOf<Closure>>>,
+    /// Static methods and class level fields, accessed as `ClassName.name`
+    pub statics: GCObjectOf<Cache<Value>>,
+    /// The `init` method, resolved when it is defined so that instantiating the class does not look it up
+    pub init: Option<GCObjectOf<Closure>>,
+    /// The docstring, a string literal at the start of the class body (see `Opcode::Doc`)
+    pub doc: Option<GCObjectOf<Box<str>>>,
 }
 
 impl Class {
     pub fn new(
         name: GCObjectOf<Box<str>>,
         methods: GCObjectOf<Cache<GCObjectOf<Closure>>>,
+        statics: GCObjectOf<Cache<Value>>,
     ) -> Self {
-        Class { name, methods }
+        Class {
+            name,
+            methods,
+            statics,
+            init: None,
+            doc: None,
+        }
     }
 }
 
@@ -507,11 +652,11 @@ pub struct Instance {
     /// Refers the class
     pub class: GCObjectOf<Class>,
     /// The fields held by this instance
-    pub fields: GCObjectOf<Cache<Value>>,
+    pub fields: Fields,
 }
 
 impl Instance {
-    pub fn new(class: GCObjectOf<Class>, fields: GCObjectOf<Cache<Value>>) -> Self {
+    pub fn new(class: GCObjectOf<Class>, fields: Fields) -> Self {
         Instance { class, fields }
     }
 }
@@ -523,8 +668,122 @@ impl Display for Instance {
 }
 
 #[derive(Debug)]
-/// Struct for BoundMethod
-pub struct BoundMethod(pub GCObjectOf<Instance>, pub GCObjectOf<Closure>);
+/// Struct for BoundMethod, the receiver is the object of the instance the method was read from (`this` in its body)
+pub struct BoundMethod(pub GCObjectOf<Object>, pub GCObjectOf<Closure>);
+
+impl BoundMethod {
+    /// The instance the method is bound to
+    pub fn instance(&self) -> GCObjectOf<Instance> {
+        match self.0.object_type {
+            ObjectType::Instance(instance) => instance,
+            _ => unreachable!("Methods are only bound to instances"),
+        }
+    }
+}
+
+/// `start..end`: the numbers from start up to end (excluded) by steps of 1, `start..=end` includes the end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: f64,
+    pub end: f64,
+    pub inclusive: bool,
+}
+
+impl Range {
+    pub fn iter(&self) -> RangeIterator {
+        RangeIterator {
+            next: self.start,
+            range: *self,
+        }
+    }
+}
+
+impl Display for Range {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operator = if self.inclusive { "..=" } else { ".." };
+        f.write_fmt(format_args!(
+            "{}{}{}",
+            format_number(self.start, PRINTED_DIGITS),
+            operator,
+            format_number(self.end, PRINTED_DIGITS)
+        ))
+    }
+}
+
+/// Iterates a [Range] in place, the numbers are not allocated
+#[derive(Debug, Clone, Copy)]
+pub struct RangeIterator {
+    pub(crate) next: f64,
+    pub range: Range,
+}
+
+impl Iterator for RangeIterator {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        let next = self.next;
+        let in_range = if self.range.inclusive {
+            next <= self.range.end
+        } else {
+            next < self.range.end
+        };
+        in_range.then(|| {
+            self.next += 1.0;
+            next
+        })
+    }
+}
+
+/// A function that runs until it yields (`yield value`) and continues from there when it is resumed, created by the
+/// `coroutine` native. The VM runs a coroutine on its own stack: while it is suspended the values and the call frames
+/// from its first frame on are moved here, resuming moves them back on top of the stack of the resumer.
+#[derive(Debug, Clone)]
+pub struct Coroutine {
+    /// The function (or closure, bound method) called on the first resume
+    pub function: Value,
+    pub state: CoroutineState,
+    /// The stack of the suspended coroutine, from the slot of its function
+    pub stack: Vec<Value>,
+    /// The suspended call frames, the first one is the call of `function`
+    pub frames: Vec<SuspendedFrame>,
+    /// The upvalues that captured a slot of `stack`, they are open again when the coroutine resumes
+    pub upvalues: Vec<(GCObjectOf<Upvalue>, usize)>,
+}
+
+impl Coroutine {
+    pub fn new(function: Value) -> Self {
+        Coroutine {
+            function,
+            state: CoroutineState::Created,
+            stack: vec![],
+            frames: vec![],
+            upvalues: vec![],
+        }
+    }
+}
+
+/// Where a [Coroutine] is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineState {
+    /// Not resumed yet
+    Created,
+    /// Stopped at a `yield`
+    Suspended,
+    /// Resumed and not yielded yet (it may be resuming another coroutine)
+    Running,
+    /// Returned (or failed)
+    Done,
+}
+
+/// A call frame of a suspended [Coroutine]
+#[derive(Debug, Clone, Copy)]
+pub struct SuspendedFrame {
+    pub closure: GCObjectOf<Closure>,
+    /// The offset of the next instruction
+    pub ip: usize,
+    /// The first slot of the frame in [Coroutine::stack]
+    pub slot: usize,
+}
 
 /// Captured value for a Closure (the magic that makes a Closure work)
 #[derive(Debug, Clone, Copy)]
@@ -554,11 +813,13 @@ pub struct Tag {
     /// Used in GC for mark and sweep
     pub is_marked: bool,
     /// Pointer to the next object
-    pub next: Option<NonNull<Tag>>,
+    pub next: Option<GCObjectOf<Tag>>,
 }
 
 /// A Managed Object (garbage collected) in Evie. It contains the metadata and a pointer to the actual object.
 /// This is created and destroyed using [super::ObjectAllocator]
+/// It is a raw handle for the VM and the natives (it does not keep the object alive), hosts should hold on to objects
+/// with a [crate::handle::Handle]
 pub struct GCObjectOf<T> {
     /// Pointer to the heap allocated object `T`
     pub reference: NonNull<T>,
@@ -599,10 +860,7 @@ impl<T> GCObjectOf<T> {
 
 impl<T> Clone for GCObjectOf<T> {
     fn clone(&self) -> Self {
-        Self {
-            // metadata: self.metadata,
-            reference: self.reference,
-        }
+        *self
     }
 }
 
@@ -638,15 +896,71 @@ impl<T> DerefMut for GCObjectOf<T> {
 
 impl<T> Copy for GCObjectOf<T> {}
 
+// Safety: the object is owned by its [super::ObjectAllocator], the handle is only a pointer into that memory. The
+// allocator is [Send] and the handles move along with it (e.g. in the VM), no thread is left with a handle into the
+// heap of another. It is not [Sync], the objects are not shared between threads.
+unsafe impl<T: Send> Send for GCObjectOf<T> {}
+
+/// A weak reference to a [GCObjectOf], it does not keep the object alive.
+/// Created with [ObjectAllocator::downgrade] and resolved with [ObjectAllocator::upgrade],
+/// which returns [None] once the object has been freed by the GC.
+pub struct WeakGCObjectOf<T> {
+    pub(crate) reference: NonNull<T>,
+    /// The id of the allocation, so that a new object at the same address is not mistaken for this one
+    pub(crate) id: u64,
+}
+
+impl<T> WeakGCObjectOf<T> {
+    pub(crate) fn new(reference: NonNull<T>, id: u64) -> Self {
+        WeakGCObjectOf { reference, id }
+    }
+}
+
+impl<T> std::fmt::Debug for WeakGCObjectOf<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeakGCObjectOf")
+            .field("reference", &self.reference)
+            .field("id", &self.id)
+            .field("type", &std::any::type_name::<T>())
+            .finish()
+    }
+}
+
+impl<T> Clone for WeakGCObjectOf<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for WeakGCObjectOf<T> {}
+
 #[cfg(test)]
 mod tests {
-    use std::f64::EPSILON;
-
     use crate::{
         objects::{GCObjectOf, Object, ObjectType},
         ObjectAllocator,
     };
 
+    #[test]
+    fn format_number() {
+        use crate::objects::{format_number, PRINTED_DIGITS};
+        let printed = |n: f64| format_number(n, PRINTED_DIGITS);
+        assert_eq!("0.3", printed(0.1 + 0.2));
+        assert_eq!("10", printed(10.0));
+        assert_eq!("-2.5", printed(-2.5));
+        assert_eq!("0", printed(0.0));
+        assert_eq!("100000", printed(100000.0));
+        assert_eq!("1.23457e+06", printed(1234567.0));
+        assert_eq!("1e+06", printed(999999.5));
+        assert_eq!("0.0001", printed(0.0001));
+        assert_eq!("1e-05", printed(0.00001));
+        assert_eq!("1e+100", printed(1e100));
+        assert_eq!("nan", printed(f64::NAN));
+        assert_eq!("-inf", printed(f64::NEG_INFINITY));
+        assert_eq!("3.14", format_number(std::f64::consts::PI, 3));
+        assert_eq!("0.30000000000000004", format_number(0.1 + 0.2, 17));
+    }
+
     #[test]
     fn value_size() {
         assert_eq!(
@@ -673,7 +987,10 @@ mod tests {
         assert_eq!(Value::nil(), Value::nil().as_nil());
         assert_eq!(true, Value::nil().is_nil());
 
-        assert_eq!(true, Value::number(1f64).as_number() - 1f64 < EPSILON);
+        assert_eq!(
+            true,
+            (Value::number(1f64).as_number() - 1f64).abs() < f64::EPSILON
+        );
         assert_eq!(true, Value::number(1f64).is_number());
 
         let allocator = ObjectAllocator::new();
@@ -686,6 +1003,44 @@ mod tests {
         assert_eq!(true, Value::object(object).is_object());
     }
 
+    #[test]
+    fn number_equality() {
+        use crate::objects::{nan_boxed, non_nan_boxed};
+        let pairs = [
+            (0.0, -0.0, true),
+            (f64::NAN, f64::NAN, false),
+            (0.1 + 0.2, 0.3, false),
+            (1e-17, 0.0, false),
+            (9007199254740992.0, 9007199254740993.0, true),
+            (1.5, 1.5, true),
+        ];
+        for (l, r, equal) in pairs {
+            let boxed = nan_boxed::Value::number(l) == nan_boxed::Value::number(r);
+            let unboxed = non_nan_boxed::Value::number(l) == non_nan_boxed::Value::number(r);
+            assert_eq!((equal, equal), (boxed, unboxed), "{} == {}", l, r);
+        }
+        assert!(nan_boxed::Value::number(0.0) != nan_boxed::Value::bool(false));
+    }
+
+    #[test]
+    fn nan_with_a_payload_is_a_number() {
+        use crate::objects::{nan_boxed, non_nan_boxed};
+        // The bits of nil, true and an object as NaNs (with and without the sign)
+        for bits in [
+            nan_boxed::NIL.0,
+            nan_boxed::TRUE.0,
+            nan_boxed::SIGN_BIT_FLAG | nan_boxed::QNAN_BIT_FLAG | 8,
+        ] {
+            let n = f64::from_bits(bits as u64);
+            assert!(n.is_nan());
+            let boxed = nan_boxed::Value::number(n);
+            assert!(boxed.is_number() && !boxed.is_nil() && !boxed.is_object());
+            assert!(boxed.as_number().is_nan());
+            assert!(non_nan_boxed::Value::number(n).as_number().is_nan());
+            assert_eq!("nan", boxed.to_string());
+        }
+    }
+
     #[test]
     #[allow(clippy::bool_assert_comparison)]
     fn nan_boxed_value_types() {
@@ -700,7 +1055,7 @@ mod tests {
         assert_eq!(true, Value::number(124f64).is_number());
         assert_eq!(
             true,
-            (Value::number(1.24f64).as_number() - 1.24f64).abs() < EPSILON
+            (Value::number(1.24f64).as_number() - 1.24f64).abs() < f64::EPSILON
         );
 
         let allocator = ObjectAllocator::new();
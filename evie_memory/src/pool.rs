@@ -0,0 +1,139 @@
+//! Size class pools for the objects of an [crate::ObjectAllocator].
+//!
+//! Instead of one `malloc` per object, objects are allocated in blocks of their size class (the size rounded up to
+//! [ALIGN] bytes), carved out of chunks of [BLOCKS_PER_CHUNK] blocks. A freed block goes to the free list of its class
+//! and is reused by the next allocation of that class. The chunks are returned to the system when the pools are dropped.
+use std::{alloc::Layout, ptr::NonNull};
+
+/// The alignment of every block, the size classes are multiples of it
+pub const ALIGN: usize = 16;
+/// The number of blocks allocated at once for a size class
+pub const BLOCKS_PER_CHUNK: usize = 64;
+/// Objects larger than this (or aligned to more than [ALIGN]) are allocated one by one
+pub const MAX_POOLED_SIZE: usize = 256;
+
+#[derive(Default)]
+struct SizeClass {
+    free: Vec<NonNull<u8>>,
+    chunks: Vec<NonNull<u8>>,
+}
+
+/// The pools of every size class, see the [module docs](self)
+#[derive(Default)]
+pub(crate) struct Pools {
+    /// The size class of `n * ALIGN` bytes is at `n - 1`
+    classes: Vec<SizeClass>,
+}
+
+impl Pools {
+    /// Allocates a block for `layout`, it is not initialized
+    pub(crate) fn alloc(&mut self, layout: Layout) -> NonNull<u8> {
+        let class = match size_class(layout) {
+            Some(class) => class,
+            // Safety: the size is not zero, see size_class
+            None => return non_null(unsafe { std::alloc::alloc(layout) }, layout),
+        };
+        if self.classes.len() <= class {
+            self.classes.resize_with(class + 1, SizeClass::default);
+        }
+        let size_class = &mut self.classes[class];
+        if let Some(block) = size_class.free.pop() {
+            return block;
+        }
+        let block_size = (class + 1) * ALIGN;
+        let chunk_layout = chunk_layout(block_size);
+        // Safety: the chunk layout is not zero sized
+        let chunk = non_null(unsafe { std::alloc::alloc(chunk_layout) }, chunk_layout);
+        size_class.chunks.push(chunk);
+        // The first block is handed out, the others are free (the last one is used next)
+        size_class
+            .free
+            .extend((1..BLOCKS_PER_CHUNK).rev().map(|block| {
+                // Safety: the block is within the chunk
+                unsafe { NonNull::new_unchecked(chunk.as_ptr().add(block * block_size)) }
+            }));
+        chunk
+    }
+
+    /// Returns the block to its size class (or to the system if it is not pooled)
+    ///
+    /// # Safety
+    /// The block must have been allocated by [Pools::alloc] with the same layout and must not be used afterwards.
+    pub(crate) unsafe fn dealloc(&mut self, block: NonNull<u8>, layout: Layout) {
+        match size_class(layout) {
+            Some(class) => self.classes[class].free.push(block),
+            None => std::alloc::dealloc(block.as_ptr(), layout),
+        }
+    }
+
+    /// The number of bytes reserved by the pools, used or not
+    pub(crate) fn reserved_bytes(&self) -> usize {
+        self.classes
+            .iter()
+            .enumerate()
+            .map(|(class, size_class)| {
+                size_class.chunks.len() * chunk_layout((class + 1) * ALIGN).size()
+            })
+            .sum()
+    }
+}
+
+impl Drop for Pools {
+    fn drop(&mut self) {
+        for (class, size_class) in self.classes.iter().enumerate() {
+            let chunk_layout = chunk_layout((class + 1) * ALIGN);
+            for chunk in &size_class.chunks {
+                // Safety: the chunks were allocated with this layout, the objects in them have been dropped
+                unsafe { std::alloc::dealloc(chunk.as_ptr(), chunk_layout) };
+            }
+        }
+    }
+}
+
+/// The index of the size class of `layout`, None if it is not pooled.
+/// Zero sized objects get a block too, so that every object has an address of its own.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(1);
+    (size <= MAX_POOLED_SIZE && layout.align() <= ALIGN).then(|| size.div_ceil(ALIGN) - 1)
+}
+
+fn chunk_layout(block_size: usize) -> Layout {
+    Layout::from_size_align(block_size * BLOCKS_PER_CHUNK, ALIGN).expect("Invalid chunk layout")
+}
+
+fn non_null(ptr: *mut u8, layout: Layout) -> NonNull<u8> {
+    NonNull::new(ptr).unwrap_or_else(|| std::alloc::handle_alloc_error(layout))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+
+    use super::{Pools, ALIGN, BLOCKS_PER_CHUNK};
+
+    #[test]
+    fn blocks_are_reused() {
+        let mut pools = Pools::default();
+        let layout = Layout::new::<[u64; 3]>();
+        let blocks: Vec<_> = (0..BLOCKS_PER_CHUNK + 1)
+            .map(|_| pools.alloc(layout))
+            .collect();
+        assert!(blocks
+            .iter()
+            .all(|b| (b.as_ptr() as usize).is_multiple_of(ALIGN)));
+        assert_eq!(2 * BLOCKS_PER_CHUNK * 32, pools.reserved_bytes());
+        unsafe { pools.dealloc(blocks[3], layout) };
+        assert_eq!(blocks[3], pools.alloc(layout));
+        // Another size class
+        let small = pools.alloc(Layout::new::<u8>());
+        assert!(!blocks.contains(&small));
+        // Not pooled
+        let large = Layout::new::<[u8; 1024]>();
+        let block = pools.alloc(large);
+        unsafe { pools.dealloc(block, large) };
+        assert_eq!(
+            2 * BLOCKS_PER_CHUNK * 32 + BLOCKS_PER_CHUNK * ALIGN,
+            pools.reserved_bytes()
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Deterministic replay of the nondeterministic inputs of a script: clock values, random numbers and input reads.
+//!
+//! While [Replay::Recording], every such input a native reads (through [crate::runtime::EvieRuntime::number_input]
+//! and [crate::runtime::EvieRuntime::text_input]) is recorded with its [Source]. [Replay::Replaying] a recording
+//! feeds the inputs back in the same order instead of reading them, so a flaky run can be reproduced exactly. A script
+//! that reads an input from another source than the recording (e.g. a random number where it read the clock), or that
+//! does not read all of them (see [Replay::finish]), diverged from the recorded run and fails.
+//!
+//! A recording is written as a trace with one input per line, its source (`clock`, `random`, `read` or `env`) and:
+//! - `number <n>` for numbers (printed exactly, so they read back to the same bits),
+//! - `text <text>` for text, with `\`, new lines and carriage returns escaped,
+//! - `eof` for a read at the end of the input (or a variable that is not set).
+use std::{collections::VecDeque, fmt::Display};
+
+use evie_common::{bail, errors::*};
+
+/// Where a nondeterministic input comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The time, e.g. `clock()`
+    Clock,
+    /// A random number, e.g. `random()`
+    Random,
+    /// A read of the input, e.g. `read_line()`
+    Read,
+    /// A variable of the environment, `env(name)`
+    Env,
+}
+
+impl Source {
+    const ALL: [Source; 4] = [Source::Clock, Source::Random, Source::Read, Source::Env];
+
+    /// The name of the source in a trace
+    fn name(&self) -> &'static str {
+        match self {
+            Source::Clock => "clock",
+            Source::Random => "random",
+            Source::Read => "read",
+            Source::Env => "env",
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A nondeterministic input of a script
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    /// A clock value or a random number
+    Number(Source, f64),
+    /// A read or a variable, None at the end of the input (or if the variable is not set)
+    Text(Source, Option<String>),
+}
+
+impl Input {
+    /// Where the input comes from
+    pub fn source(&self) -> Source {
+        match self {
+            Input::Number(source, _) | Input::Text(source, _) => *source,
+        }
+    }
+}
+
+/// Whether the inputs of a script are read, recorded or replayed
+#[derive(Debug, Default)]
+pub enum Replay {
+    /// Inputs are read as usual (the default)
+    #[default]
+    Off,
+    /// Inputs are read and recorded
+    Recording(Vec<Input>),
+    /// Inputs are taken from the recording, in order
+    Replaying(VecDeque<Input>),
+}
+
+impl Replay {
+    /// The next recorded input, fails if the recording has ended or the input is not from `source`
+    pub(crate) fn next(inputs: &mut VecDeque<Input>, source: Source) -> Result<Input> {
+        match inputs.pop_front() {
+            Some(input) if input.source() == source => Ok(input),
+            Some(input) => bail!(format!(
+                "The replay expected an input from {}, the recording has {:?}",
+                source, input
+            )),
+            None => bail!(
+                "The replay has no more inputs, the script read more than it did when recorded"
+            ),
+        }
+    }
+
+    /// Ends a replay when the run ends, fails if recorded inputs are left: the script read less than it did when
+    /// recorded
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Replay::Replaying(inputs) if !inputs.is_empty() => bail!(format!(
+                "The replay has {} inputs left, the script read less than it did when recorded (the next one is {:?})",
+                inputs.len(),
+                inputs[0]
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the inputs as a trace, see the [module](self) docs for the format
+pub fn to_trace(inputs: &[Input]) -> String {
+    let mut trace = String::new();
+    for input in inputs {
+        trace.push_str(input.source().name());
+        match input {
+            Input::Number(_, n) => trace.push_str(&format!(" number {}\n", n)),
+            Input::Text(_, Some(text)) => {
+                let escaped = text
+                    .replace('\\', "\\\\")
+                    .replace('\n', "\\n")
+                    .replace('\r', "\\r");
+                trace.push_str(&format!(" text {}\n", escaped))
+            }
+            Input::Text(_, None) => trace.push_str(" eof\n"),
+        }
+    }
+    trace
+}
+
+/// Reads the inputs from a trace written by [to_trace]
+pub fn from_trace(trace: &str) -> Result<Vec<Input>> {
+    trace
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let invalid = || format!("Invalid replay trace at line {}: '{}'", i + 1, line);
+            let Some((source, line)) = Source::ALL.iter().find_map(|source| {
+                let line = line.strip_prefix(source.name())?.strip_prefix(' ')?;
+                Some((*source, line))
+            }) else {
+                bail!(invalid())
+            };
+            if line == "eof" {
+                return Ok(Input::Text(source, None));
+            }
+            if let Some(n) = line.strip_prefix("number ") {
+                return n
+                    .parse()
+                    .map(|n| Input::Number(source, n))
+                    .map_err(|_| invalid().into());
+            }
+            if let Some(escaped) = line.strip_prefix("text ") {
+                let mut text = String::new();
+                let mut chars = escaped.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => match chars.next() {
+                            Some('\\') => text.push('\\'),
+                            Some('n') => text.push('\n'),
+                            Some('r') => text.push('\r'),
+                            _ => bail!(invalid()),
+                        },
+                        c => text.push(c),
+                    }
+                }
+                return Ok(Input::Text(source, Some(text)));
+            }
+            bail!(invalid())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_common::errors::*;
+
+    use super::{from_trace, to_trace, Input, Replay, Source};
+    use crate::runtime::EvieRuntime;
+
+    #[test]
+    fn trace_round_trip() -> Result<()> {
+        let inputs = vec![
+            Input::Number(Source::Random, 0.1 + 0.2),
+            Input::Number(Source::Clock, f64::INFINITY),
+            Input::Text(Source::Read, Some("a \\n\nb\r".to_string())),
+            Input::Text(Source::Env, Some(String::new())),
+            Input::Text(Source::Read, None),
+        ];
+        let trace = to_trace(&inputs);
+        assert_eq!(
+            "random number 0.30000000000000004\nclock number inf\nread text a \\\\n\\nb\\r\nenv text \nread eof\n",
+            trace
+        );
+        assert_eq!(inputs, from_trace(&trace)?);
+        assert!(from_trace("clock number x").is_err());
+        assert!(from_trace("read text \\t").is_err());
+        assert!(from_trace("number 1").is_err());
+        assert!(from_trace("something").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn record_and_replay() -> Result<()> {
+        let mut recording = EvieRuntime::new();
+        recording.set_replay(Replay::Recording(vec![]));
+        let random = recording.number_input(Source::Random, |r| r.random().next_f64())?;
+        recording.number_input(Source::Clock, |_| 1.79216e9)?;
+        let line = recording.text_input(Source::Read, |_| Ok(Some("line".to_string())))?;
+        assert_eq!(Some("line".to_string()), line);
+        let inputs = match recording.set_replay(Replay::Off) {
+            Replay::Recording(inputs) => inputs,
+            replay => panic!("Expected a recording, got {:?}", replay),
+        };
+
+        let mut replaying = EvieRuntime::new();
+        replaying.set_replay(Replay::Replaying(inputs.clone().into()));
+        // The live inputs are not read
+        assert_eq!(
+            random,
+            replaying.number_input(Source::Random, |_| unreachable!())?
+        );
+        // The script reads a random number where it read the clock when recorded
+        let error = replaying
+            .number_input(Source::Random, |_| unreachable!())
+            .unwrap_err();
+        assert_eq!(
+            "The replay expected an input from random, the recording has Number(Clock, 1792160000.0)",
+            error.to_string()
+        );
+        // Text where it read a number
+        assert!(replaying
+            .number_input(Source::Read, |_| unreachable!())
+            .is_err());
+        // And then more than it did
+        assert!(replaying
+            .text_input(Source::Read, |_| unreachable!())
+            .is_err());
+        assert!(replaying.set_replay(Replay::Off).finish().is_ok());
+
+        // The script reads less than it did
+        replaying.set_replay(Replay::Replaying(inputs.into()));
+        replaying.number_input(Source::Random, |_| unreachable!())?;
+        let error = replaying.set_replay(Replay::Off).finish().unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("The replay has 2 inputs left, the script read less than it did"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+}
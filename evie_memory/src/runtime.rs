@@ -0,0 +1,508 @@
+//! The runtime context for a single VM instance.
+//!
+//! [EvieRuntime] owns everything that is specific to one VM: the [ObjectAllocator] (and hence the interned strings)
+//! and the global variables (the `const` ones are also in a table by slot, read without looking up their name). Native functions receive it as their context instead of a bare allocator,
+//! so that several VMs can live in one process without ever sharing objects.
+//! It also holds per VM state used by natives, like the [Random] number generator, the monotonic start time,
+//! the input source and the [Replay] of nondeterministic inputs.
+
+#[cfg(feature = "nan_boxed")]
+use crate::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use crate::objects::non_nan_boxed::Value;
+use evie_common::Reader;
+use std::io::BufRead;
+
+use crate::{
+    clock::{self, Instant},
+    gc::{Trace, Tracer},
+    handle::{Handle, Pinnable},
+    objects::{GCObjectOf, Instance, Object, UserDefinedFunction},
+    replay::{Input, Replay, Source},
+    runtime_memory::Values,
+    snapshot, ObjectAllocator,
+};
+use evie_common::{bail, errors::*};
+
+/// Owns the allocator, interned strings and globals for one VM.
+pub struct EvieRuntime {
+    allocator: ObjectAllocator,
+    globals: Values,
+    /// The `const` globals by slot, they are in [EvieRuntime::globals] too
+    global_constants: Vec<(GCObjectOf<Box<str>>, Value)>,
+    /// See [EvieRuntime::functions]
+    functions: Vec<GCObjectOf<UserDefinedFunction>>,
+    random: Random,
+    started: Instant,
+    input: Reader,
+    replay: Replay,
+}
+
+impl std::fmt::Debug for EvieRuntime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EvieRuntime")
+            .field("globals", &self.globals)
+            .field("bytes_allocated", &self.allocator.bytes_allocated())
+            .field("started", &self.started)
+            .finish()
+    }
+}
+
+impl EvieRuntime {
+    /// A new, empty runtime
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        EvieRuntime {
+            allocator: ObjectAllocator::new(),
+            globals: Values::new(),
+            global_constants: Vec::new(),
+            functions: Vec::new(),
+            random: Random::from_time(),
+            started: Instant::now(),
+            input: Box::new(std::io::BufReader::new(std::io::stdin())),
+            replay: Replay::Off,
+        }
+    }
+
+    /// The [ObjectAllocator] for this runtime
+    #[inline(always)]
+    pub fn allocator(&self) -> &ObjectAllocator {
+        &self.allocator
+    }
+
+    /// Frees every object that is not reachable from the runtime (its globals and functions) or from the roots marked
+    /// by `mark_roots`, see [ObjectAllocator::collect]
+    pub fn collect(&mut self, mark_roots: impl FnOnce(&mut Tracer)) {
+        let EvieRuntime {
+            allocator,
+            globals,
+            functions,
+            ..
+        } = self;
+        allocator.collect(|tracer| {
+            mark_roots(tracer);
+            trace_roots(globals, functions, tracer);
+        });
+    }
+
+    /// The global variables
+    #[inline(always)]
+    pub fn globals(&mut self) -> &mut Values {
+        &mut self.globals
+    }
+
+    /// Defines the `const` global `name` in `slot`, the slots are defined in order
+    pub fn define_global_constant(
+        &mut self,
+        slot: usize,
+        name: GCObjectOf<Box<str>>,
+        value: Value,
+    ) {
+        self.globals.insert(name, value);
+        match self.global_constants.get_mut(slot) {
+            Some(constant) => *constant = (name, value),
+            None => self.global_constants.push((name, value)),
+        }
+    }
+
+    /// The value of the `const` global in `slot`, if defined
+    #[inline(always)]
+    pub fn global_constant(&self, slot: usize) -> Option<Value> {
+        self.global_constants.get(slot).map(|(_, value)| *value)
+    }
+
+    /// True if the global `name` is a constant
+    #[inline(always)]
+    pub fn is_global_constant(&self, name: GCObjectOf<Box<str>>) -> bool {
+        !self.global_constants.is_empty()
+            && self
+                .global_constants
+                .iter()
+                .any(|(constant, _)| constant.as_ptr() == name.as_ptr())
+    }
+
+    /// The names of the `const` globals, by slot
+    pub fn global_constant_names(&self) -> Vec<String> {
+        self.global_constants
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    /// The names of the global variables (the natives included)
+    pub fn global_names(&self) -> Vec<String> {
+        self.globals
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    /// The functions compiled for this runtime, in order, a [snapshot] refers to the functions by their index
+    pub fn functions(&self) -> &[GCObjectOf<UserDefinedFunction>] {
+        &self.functions
+    }
+
+    /// Adds the functions of a compiled program, they stay alive as long as the runtime. See [EvieRuntime::functions]
+    pub fn add_functions(
+        &mut self,
+        functions: impl IntoIterator<Item = GCObjectOf<UserDefinedFunction>>,
+    ) {
+        self.functions.extend(functions);
+    }
+
+    /// Saves the globals and the objects reachable from them, see [snapshot]
+    pub fn snapshot(&self) -> Result<Vec<u8>> {
+        snapshot::take(self.globals.iter(), &self.functions)
+    }
+
+    /// Restores a [EvieRuntime::snapshot] of a runtime that compiled the same program, see [snapshot]
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        snapshot::restore(self, snapshot)
+    }
+
+    /// The random number generator of this runtime
+    #[inline(always)]
+    pub fn random(&mut self) -> &mut Random {
+        &mut self.random
+    }
+
+    /// When this runtime was created, the origin for monotonic time
+    #[inline(always)]
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// The input source (stdin unless replaced by [EvieRuntime::set_input])
+    #[inline(always)]
+    pub fn input(&mut self) -> &mut dyn BufRead {
+        &mut self.input
+    }
+
+    /// Replaces the input source, e.g. to inject input in tests
+    pub fn set_input(&mut self, input: Reader) {
+        self.input = input;
+    }
+
+    /// Starts recording or replaying the nondeterministic inputs (or stops with [Replay::Off]),
+    /// returns the previous replay, e.g. to take the inputs recorded so far
+    pub fn set_replay(&mut self, replay: Replay) -> Replay {
+        std::mem::replace(&mut self.replay, replay)
+    }
+
+    /// A nondeterministic number (a clock value or a random number) from `source`: `live` reads it, unless it is
+    /// being replayed
+    pub fn number_input<F: FnOnce(&mut Self) -> f64>(
+        &mut self,
+        source: Source,
+        live: F,
+    ) -> Result<f64> {
+        if let Replay::Replaying(inputs) = &mut self.replay {
+            return match Replay::next(inputs, source)? {
+                Input::Number(_, n) => Ok(n),
+                input => bail!(format!(
+                    "The replay expected a number, the recording has {:?}",
+                    input
+                )),
+            };
+        }
+        let n = live(self);
+        if let Replay::Recording(inputs) = &mut self.replay {
+            inputs.push(Input::Number(source, n));
+        }
+        Ok(n)
+    }
+
+    /// Nondeterministic text (a read, None at the end of the input) from `source`: `live` reads it, unless it is
+    /// being replayed
+    pub fn text_input<F: FnOnce(&mut Self) -> Result<Option<String>>>(
+        &mut self,
+        source: Source,
+        live: F,
+    ) -> Result<Option<String>> {
+        if let Replay::Replaying(inputs) = &mut self.replay {
+            return match Replay::next(inputs, source)? {
+                Input::Text(_, text) => Ok(text),
+                input => bail!(format!(
+                    "The replay expected text, the recording has {:?}",
+                    input
+                )),
+            };
+        }
+        let text = live(self)?;
+        if let Replay::Recording(inputs) = &mut self.replay {
+            inputs.push(Input::Text(source, text.clone()));
+        }
+        Ok(text)
+    }
+
+    /// Defines (or redefines) the global variable `name`, the host can also redefine a constant
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        let name = self.allocator.alloc_interned_str(name);
+        self.globals.insert(name, value);
+        for constant in self.global_constants.iter_mut() {
+            if constant.0.as_ptr() == name.as_ptr() {
+                constant.1 = value;
+            }
+        }
+    }
+
+    /// Returns the value of the global variable `name`, if defined
+    pub fn global(&mut self, name: &str) -> Option<Value> {
+        let name = self.allocator.alloc_interned_str(name);
+        self.globals.get(name)
+    }
+
+    /// Allocates a new String [Value] in this runtime, see [ObjectAllocator::alloc_string]
+    pub fn alloc_string<T: AsRef<str>>(&self, string: T) -> Value {
+        Value::object(self.allocator.alloc_string(string))
+    }
+
+    /// Allocates the bytes in `range` of the String `string`, see [ObjectAllocator::alloc_substring]. None if it is not a
+    /// String or the range is not in it
+    pub fn alloc_substring(&self, string: Value, range: std::ops::Range<usize>) -> Option<Value> {
+        if !string.is_object() {
+            return None;
+        }
+        let object_type = string.as_object().object_type;
+        self.allocator
+            .alloc_substring(object_type, range)
+            .map(Value::object)
+    }
+
+    /// A [Handle] to the object of the value (None if it is not a `T`, e.g. an [Instance]), it is alive until released
+    pub fn pin<T: Pinnable>(&self, value: Value) -> Option<Handle<T>> {
+        T::from_value(value).map(|object| self.allocator.pin(object))
+    }
+
+    /// Releases the handle, see [ObjectAllocator::unpin]
+    pub fn release<T>(&self, handle: Handle<T>) {
+        self.allocator.unpin(handle)
+    }
+
+    /// The object of the handle, the runtime (and so the VM) can not run while it is borrowed
+    ///
+    /// # Panics
+    /// If the handle belongs to another runtime
+    pub fn get<'a, T>(&'a self, handle: &Handle<T>) -> &'a T {
+        let object = self.allocator.resolve(handle);
+        // Safety: the object is pinned by the handle, it is not freed while the runtime is borrowed
+        unsafe { &*object.as_ptr() }
+    }
+
+    /// The object of the handle, to modify it. The runtime is borrowed mutably, nothing else can access the object.
+    ///
+    /// # Panics
+    /// If the handle belongs to another runtime
+    pub fn get_mut<'a, T>(&'a mut self, handle: &Handle<T>) -> &'a mut T {
+        let object = self.allocator.resolve(handle);
+        // Safety: as for get, and the exclusive borrow of the runtime keeps the VM and the natives from using it
+        unsafe { &mut *(object.as_ptr() as *mut T) }
+    }
+
+    /// The value of the field `name` of the instance, if it is set
+    pub fn field(&self, instance: &Handle<Instance>, name: &str) -> Option<Value> {
+        let name = self.allocator.alloc_interned_str(name);
+        self.get(instance).fields.get(name)
+    }
+
+    /// Sets the field `name` of the instance
+    pub fn set_field(&mut self, instance: &Handle<Instance>, name: &str, value: Value) {
+        let name = self.allocator.alloc_interned_str(name);
+        let mut object = self.allocator.resolve(instance);
+        object.fields.insert(name, value, &self.allocator);
+    }
+
+    /// The object of the handle as a [Value], e.g. to pass it to a function of the script
+    pub fn value(&self, handle: &Handle<Object>) -> Value {
+        Value::object(self.allocator.resolve(handle))
+    }
+}
+
+impl Trace for EvieRuntime {
+    fn trace(&self, tracer: &mut Tracer) {
+        trace_roots(&self.globals, &self.functions, tracer);
+    }
+}
+
+fn trace_roots(
+    globals: &Values,
+    functions: &Vec<GCObjectOf<UserDefinedFunction>>,
+    tracer: &mut Tracer,
+) {
+    globals.iter().for_each(|(name, value)| {
+        name.trace(tracer);
+        value.trace(tracer);
+    });
+    functions.trace(tracer);
+}
+
+/// A small deterministic pseudo random number generator (SplitMix64).
+/// The same seed always produces the same sequence, which keeps seeded scripts reproducible.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    /// A generator that produces the sequence for `seed`
+    pub fn new(seed: u64) -> Self {
+        Random { state: seed }
+    }
+
+    /// A generator seeded from the current time
+    pub fn from_time() -> Self {
+        Random::new(clock::since_epoch().as_nanos() as u64)
+    }
+
+    /// Restarts the sequence for `seed`
+    pub fn seed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+
+    /// The next 64 random bits
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in the range [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        // The top 53 bits fill the mantissa exactly
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EvieRuntime, Random};
+    #[cfg(feature = "nan_boxed")]
+    use crate::objects::nan_boxed::Value;
+    #[cfg(not(feature = "nan_boxed"))]
+    use crate::objects::non_nan_boxed::Value;
+    use crate::objects::ObjectType;
+
+    #[test]
+    fn runtimes_are_isolated() {
+        let mut first = EvieRuntime::new();
+        let mut second = EvieRuntime::new();
+        first.define_global("answer", Value::number(42.0));
+        second.define_global("answer", Value::number(7.0));
+        assert_eq!(Some(Value::number(42.0)), first.global("answer"));
+        assert_eq!(Some(Value::number(7.0)), second.global("answer"));
+        assert_eq!(None, first.global("question"));
+
+        let first_name = first.allocator().alloc_interned_str("name");
+        let second_name = second.allocator().alloc_interned_str("name");
+        assert_ne!(first_name.as_ptr(), second_name.as_ptr());
+        assert_eq!(
+            first_name.as_ptr(),
+            first.allocator().alloc_interned_str("name").as_ptr()
+        );
+    }
+
+    #[test]
+    fn handles_pin_objects() {
+        use crate::{
+            convert::IntoValue,
+            objects::{Class, Instance},
+        };
+        use std::collections::HashMap;
+        let mut runtime = EvieRuntime::new();
+        let map: HashMap<String, f64> = [("x".to_string(), 1.0)].into();
+        let value = map.into_value(&runtime);
+        let weak = runtime.allocator().downgrade(value.as_object());
+        assert!(runtime.pin::<Class>(value).is_none());
+        assert!(runtime.pin::<Instance>(Value::number(1.0)).is_none());
+        let instance = runtime.pin::<Instance>(value).unwrap();
+        let object = runtime.pin(value).unwrap();
+        // Nothing else refers to the instance
+        runtime.collect(|_| {});
+        assert_eq!(Some(Value::number(1.0)), runtime.field(&instance, "x"));
+        runtime.set_field(&instance, "y", Value::bool(true));
+        assert_eq!(2, runtime.get(&instance).fields.len());
+        assert_eq!("<instance of Map>", runtime.value(&object).to_string());
+        runtime.release(instance);
+        runtime.collect(|_| {});
+        assert_eq!(Some(Value::bool(true)), {
+            let instance = runtime.pin::<Instance>(runtime.value(&object)).unwrap();
+            let y = runtime.field(&instance, "y");
+            runtime.release(instance);
+            y
+        });
+        assert!(runtime.allocator().upgrade(&weak).is_some());
+        runtime.release(object);
+        runtime.collect(|_| {});
+        assert!(runtime.allocator().upgrade(&weak).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "belongs to another runtime")]
+    fn handles_are_bound_to_their_runtime() {
+        let runtime = EvieRuntime::new();
+        let handle = runtime.pin(runtime.alloc_string("hello")).unwrap();
+        let other = EvieRuntime::new();
+        other.value(&handle);
+    }
+
+    #[test]
+    fn alloc_string() {
+        let runtime = EvieRuntime::new();
+        let value = runtime.alloc_string("hello");
+        if let ObjectType::String(s) = value.as_object().object_type {
+            assert_eq!("hello", &**s);
+        } else {
+            panic!("Expected a string");
+        }
+    }
+
+    #[test]
+    fn alloc_long_string() {
+        let runtime = EvieRuntime::new();
+        runtime.allocator().set_intern_limit(4);
+        let short = runtime.alloc_string("four");
+        assert_eq!(
+            short.as_object().as_ptr(),
+            runtime.alloc_string("four").as_object().as_ptr()
+        );
+        let long = runtime.alloc_string("longer");
+        assert!(matches!(long.as_object().object_type, ObjectType::Rope(_)));
+        assert_eq!(Some("longer"), long.as_object().object_type.as_str());
+        assert_ne!(
+            long.as_object().as_ptr(),
+            runtime.alloc_string("longer").as_object().as_ptr()
+        );
+        // The long substrings of a rope are in its buffer, the short ones are interned
+        let rope = |value: Value| match value.as_object().object_type {
+            ObjectType::Rope(rope) => Some(rope),
+            _ => None,
+        };
+        let slice = runtime.alloc_substring(long, 1..6).unwrap();
+        assert_eq!(Some("onger"), slice.as_object().object_type.as_str());
+        assert_eq!(
+            rope(long).unwrap().buffer.as_ptr(),
+            rope(slice).unwrap().buffer.as_ptr()
+        );
+        let short = runtime.alloc_substring(slice, 1..4).unwrap();
+        assert_eq!(
+            short.as_object().as_ptr(),
+            runtime.alloc_string("nge").as_object().as_ptr()
+        );
+        assert!(runtime.alloc_substring(long, 2..7).is_none());
+        assert!(runtime.alloc_substring(Value::nil(), 0..0).is_none());
+    }
+
+    #[test]
+    fn random_is_deterministic_when_seeded() {
+        let mut first = Random::new(42);
+        let mut second = Random::new(7);
+        second.seed(42);
+        for _ in 0..100 {
+            let n = first.next_f64();
+            assert!((0.0..1.0).contains(&n));
+            assert_eq!(n, second.next_f64());
+        }
+    }
+}
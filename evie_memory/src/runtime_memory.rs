@@ -0,0 +1,49 @@
+//! Stores the runtime values of objects (used for storing global variables)
+
+#[cfg(feature = "nan_boxed")]
+use crate::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use crate::objects::non_nan_boxed::Value;
+use crate::{cache::Cache, objects::GCObjectOf};
+pub type Values = Objects<Value>;
+
+/// The values keyed by name. [Cache] switches from scanning to hashing as it grows,
+/// so every value stays in one place in insertion order.
+#[derive(Debug)]
+pub struct Objects<V>
+where
+    V: Copy,
+{
+    cached_values: Cache<V>,
+}
+
+impl<V: Copy> Objects<V> {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Objects {
+            cached_values: Cache::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: GCObjectOf<Box<str>>, value: V) {
+        self.cached_values.insert(key, value);
+    }
+
+    pub fn get(&mut self, key: GCObjectOf<Box<str>>) -> Option<V> {
+        self.cached_values.get(key)
+    }
+
+    /// All the (key, value) pairs
+    pub fn iter(&self) -> impl Iterator<Item = (GCObjectOf<Box<str>>, V)> + '_ {
+        self.cached_values.iter().copied()
+    }
+
+    /// Removes the key, returning its value if it was present
+    pub fn remove(&mut self, key: GCObjectOf<Box<str>>) -> Option<V> {
+        self.cached_values.remove(key)
+    }
+
+    pub fn contains_key(&self, key: GCObjectOf<Box<str>>) -> bool {
+        self.cached_values.contains_key(key)
+    }
+}
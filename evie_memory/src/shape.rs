@@ -0,0 +1,203 @@
+//! Shapes (hidden classes) for the fields of instances.
+//!
+//! A [Shape] maps the names of the fields to their slots, the values are stored in a flat [Vec] in the [Fields]
+//! of each instance. Shapes form a tree: adding a field transitions to the child shape for that name, so the
+//! instances that add the same fields in the same order (e.g. the instances of a class set up by its `init`)
+//! share a shape. A lookup can then be cached by the shape, see [crate::chunk::Chunk::property_cache].
+#[cfg(feature = "nan_boxed")]
+use crate::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use crate::objects::non_nan_boxed::Value;
+use crate::{cache::Cache, objects::GCObjectOf, ObjectAllocator};
+
+/// The names of the fields and their slots, see the [module docs](self)
+#[derive(Debug)]
+pub struct Shape {
+    /// The slot of each field, in the order they were added (the slot of a field is its position)
+    pub(crate) slots: Cache<usize>,
+    /// The shapes with one more field, by its name
+    pub(crate) transitions: Cache<GCObjectOf<Shape>>,
+}
+
+impl Shape {
+    /// The shape without fields
+    pub fn empty() -> Self {
+        Shape {
+            slots: Cache::new(),
+            transitions: Cache::new(),
+        }
+    }
+
+    /// The slot of the field
+    #[inline]
+    pub fn slot(&self, name: GCObjectOf<Box<str>>) -> Option<usize> {
+        self.slots.get(name)
+    }
+
+    /// The number of fields
+    pub fn len(&self) -> usize {
+        self.slots.size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The names of the fields, in the order of their slots
+    pub fn names(&self) -> impl Iterator<Item = GCObjectOf<Box<str>>> + '_ {
+        self.slots.keys()
+    }
+}
+
+/// Returns the shape with the field `name` added to `shape`, creating it the first time
+fn transition(
+    mut shape: GCObjectOf<Shape>,
+    name: GCObjectOf<Box<str>>,
+    allocator: &ObjectAllocator,
+) -> GCObjectOf<Shape> {
+    if let Some(next) = shape.transitions.get(name) {
+        return next;
+    }
+    let mut next = Shape::empty();
+    for (field, slot) in shape.slots.iter() {
+        next.slots.insert(*field, *slot);
+    }
+    next.slots.insert(name, shape.len());
+    let next = allocator.alloc(next);
+    shape.transitions.insert(name, next);
+    next
+}
+
+/// The fields of an instance: its [Shape] and the values in the slots
+#[derive(Debug, Clone)]
+pub struct Fields {
+    pub shape: GCObjectOf<Shape>,
+    pub values: Vec<Value>,
+}
+
+impl Fields {
+    /// No fields, the shape is the root of the allocator's shape tree
+    pub fn new(allocator: &ObjectAllocator) -> Self {
+        Fields {
+            shape: allocator.empty_shape(),
+            values: Vec::new(),
+        }
+    }
+
+    /// No fields, with room for `capacity` of them (e.g. the fields set by `init`)
+    pub fn with_capacity(capacity: usize, allocator: &ObjectAllocator) -> Self {
+        Fields {
+            shape: allocator.empty_shape(),
+            values: Vec::with_capacity(capacity),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, name: GCObjectOf<Box<str>>) -> Option<Value> {
+        self.shape.slot(name).map(|slot| self.values[slot])
+    }
+
+    /// Sets the field, a new field transitions to the next shape
+    pub fn insert(
+        &mut self,
+        name: GCObjectOf<Box<str>>,
+        value: Value,
+        allocator: &ObjectAllocator,
+    ) {
+        match self.shape.slot(name) {
+            Some(slot) => self.values[slot] = value,
+            None => {
+                self.shape = transition(self.shape, name, allocator);
+                self.values.push(value);
+            }
+        }
+    }
+
+    /// Removes the field, returning its value if it was present.
+    /// The remaining fields keep their order, they move to the shape without the field.
+    pub fn remove(
+        &mut self,
+        name: GCObjectOf<Box<str>>,
+        allocator: &ObjectAllocator,
+    ) -> Option<Value> {
+        let removed = self.shape.slot(name)?;
+        let mut fields = Fields::new(allocator);
+        for (field, value) in self.iter().filter(|(field, _)| *field != name) {
+            fields.insert(field, value, allocator);
+        }
+        let value = self.values[removed];
+        *self = fields;
+        Some(value)
+    }
+
+    /// The names of the fields, in the order they were added
+    pub fn keys(&self) -> impl Iterator<Item = GCObjectOf<Box<str>>> + '_ {
+        self.shape.names()
+    }
+
+    /// The (name, value) pairs, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = (GCObjectOf<Box<str>>, Value)> + '_ {
+        self.keys().zip(self.values.iter().copied())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fields;
+    #[cfg(feature = "nan_boxed")]
+    use crate::objects::nan_boxed::Value;
+    #[cfg(not(feature = "nan_boxed"))]
+    use crate::objects::non_nan_boxed::Value;
+    use crate::ObjectAllocator;
+
+    #[test]
+    fn shared_shapes() {
+        let allocator = ObjectAllocator::new();
+        let (x, y) = (
+            allocator.alloc_interned_str("x"),
+            allocator.alloc_interned_str("y"),
+        );
+        let point = |a: f64, b: f64| {
+            let mut fields = Fields::new(&allocator);
+            fields.insert(x, Value::number(a), &allocator);
+            fields.insert(y, Value::number(b), &allocator);
+            fields
+        };
+        let (mut first, second) = (point(1.0, 2.0), point(3.0, 4.0));
+        assert_eq!(first.shape.as_ptr(), second.shape.as_ptr());
+        assert_eq!(Some(1), second.shape.slot(y));
+        assert_eq!(4.0, second.get(y).unwrap().as_number());
+        // Setting a field keeps the shape
+        first.insert(x, Value::number(5.0), &allocator);
+        assert_eq!(first.shape.as_ptr(), second.shape.as_ptr());
+        // In another order it is another shape
+        let mut other = Fields::new(&allocator);
+        other.insert(y, Value::number(1.0), &allocator);
+        other.insert(x, Value::number(2.0), &allocator);
+        assert_ne!(first.shape.as_ptr(), other.shape.as_ptr());
+        assert_eq!(Some(0), other.shape.slot(y));
+
+        assert_eq!(5.0, first.remove(x, &allocator).unwrap().as_number());
+        assert!(first.remove(x, &allocator).is_none());
+        assert_eq!(vec![y], first.keys().collect::<Vec<_>>());
+        assert_eq!(2.0, first.get(y).unwrap().as_number());
+        // The same shape as the instances that only set y
+        let mut only_y = Fields::new(&allocator);
+        only_y.insert(y, Value::number(3.0), &allocator);
+        assert_eq!(first.shape.as_ptr(), only_y.shape.as_ptr());
+
+        let mut sized = Fields::with_capacity(2, &allocator);
+        sized.insert(x, Value::number(1.0), &allocator);
+        sized.insert(y, Value::number(2.0), &allocator);
+        assert_eq!(second.shape.as_ptr(), sized.shape.as_ptr());
+        assert_eq!(2, sized.values.capacity());
+    }
+}
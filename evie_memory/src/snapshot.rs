@@ -0,0 +1,835 @@
+//! Snapshots of the global state of a runtime, e.g. to save a game or to resume a long running script after a
+//! restart.
+//!
+//! A snapshot holds the globals and every object reachable from them: strings, ranges, classes (with their methods
+//! and class level fields), instances (with their fields), closures (with the values they captured) and bound methods.
+//! The code is not in the snapshot: a function is saved as its index in [EvieRuntime::functions], the functions
+//! compiled for the runtime in order. A snapshot is restored into a runtime that compiled the same program (e.g. a new
+//! VM that ran the same scripts), a native function is restored as the global native of the same name.
+//! Restoring (re)defines the globals of the snapshot, the others are left as they are.
+//!
+//! Snapshots are taken between runs, a closure that captures a local of a running function can't be saved.
+//!
+//! The format is binary, the integers are little endian u32s:
+//! - the magic `EVIESNAP` and the version (a byte),
+//! - the functions: their count, then the index, the name and the arity of each (to check the program is the same),
+//! - the captured values (closed upvalues): their count, then each value,
+//! - the objects: their count, then each object (a tag and what it holds),
+//! - the globals: their count, then the name and the value of each.
+//!
+//! A value is a tag (nil, false, true, number or object) followed by the number (f64) or the index of the object.
+//! A string is its length followed by its UTF-8 bytes, an optional one is a tag (0 or 1) followed by the string.
+use evie_common::{bail, errors::*};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+#[cfg(feature = "nan_boxed")]
+use crate::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use crate::objects::non_nan_boxed::Value;
+use crate::{
+    cache::Cache,
+    objects::{
+        BoundMethod, Class, Closure, GCObjectOf, Instance, Location, Object, ObjectType, Range,
+        RangeIterator, Upvalue, UserDefinedFunction,
+    },
+    runtime::EvieRuntime,
+    shape::Fields,
+};
+
+const MAGIC: &[u8] = b"EVIESNAP";
+const VERSION: u8 = 2;
+
+/// A value, an object is its index in [Snapshot::objects]
+#[derive(Debug, Clone, Copy)]
+enum Saved {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Object(u32),
+}
+
+/// An object, the objects it references are indices in [Snapshot::objects], the upvalues in [Snapshot::upvalues]
+/// and the functions in [EvieRuntime::functions]
+#[derive(Debug)]
+enum Record {
+    /// An interned string or a rope
+    String(String),
+    /// By its name
+    Native(String),
+    Function(u32),
+    Closure {
+        function: u32,
+        upvalues: Vec<u32>,
+    },
+    Class {
+        name: String,
+        doc: Option<String>,
+        methods: Vec<(String, u32)>,
+        statics: Vec<(String, Saved)>,
+        init: Option<u32>,
+    },
+    Instance {
+        class: u32,
+        fields: Vec<(String, Saved)>,
+    },
+    BoundMethod {
+        receiver: u32,
+        method: u32,
+    },
+    Range(Range),
+    RangeIterator(RangeIterator),
+}
+
+/// A function of [EvieRuntime::functions] the snapshot uses, its name and arity are checked when it is restored
+#[derive(Debug)]
+struct SavedFunction {
+    index: u32,
+    name: Option<String>,
+    arity: u32,
+}
+
+#[derive(Debug, Default)]
+struct Snapshot {
+    functions: Vec<SavedFunction>,
+    upvalues: Vec<Saved>,
+    objects: Vec<Record>,
+    globals: Vec<(String, Saved)>,
+}
+
+/// Saves the globals and the objects reachable from them, see the [module](self) docs
+pub(crate) fn take(
+    globals: impl Iterator<Item = (GCObjectOf<Box<str>>, Value)>,
+    functions: &[GCObjectOf<UserDefinedFunction>],
+) -> Result<Vec<u8>> {
+    let mut taker = Taker {
+        function_indices: functions
+            .iter()
+            .enumerate()
+            .map(|(index, function)| (function.as_ptr() as usize, index as u32))
+            .collect(),
+        ..Taker::default()
+    };
+    for (name, value) in globals {
+        let value = taker.value(value);
+        taker.snapshot.globals.push((name.to_string(), value));
+    }
+    // The objects found while saving one are saved after it
+    while taker.snapshot.objects.len() < taker.pending.len() {
+        let object = taker.pending[taker.snapshot.objects.len()];
+        let record = taker.record(object)?;
+        taker.snapshot.objects.push(record);
+    }
+    let mut used: Vec<u32> = taker.used_functions.into_iter().collect();
+    used.sort_unstable();
+    taker.snapshot.functions = used
+        .into_iter()
+        .map(|index| {
+            let function = functions[index as usize];
+            SavedFunction {
+                index,
+                name: function.name.map(|name| name.to_string()),
+                arity: function.arity as u32,
+            }
+        })
+        .collect();
+    Ok(taker.snapshot.write())
+}
+
+/// Restores a snapshot taken by [take] into the runtime, see the [module](self) docs
+pub(crate) fn restore(runtime: &mut EvieRuntime, bytes: &[u8]) -> Result<()> {
+    let snapshot = Snapshot::read(bytes)?;
+    for saved in &snapshot.functions {
+        let same = runtime
+            .functions()
+            .get(saved.index as usize)
+            .is_some_and(|function| {
+                function.name.map(|name| name.to_string()) == saved.name
+                    && function.arity == saved.arity as usize
+            });
+        if !same {
+            bail!(format!(
+                "The snapshot is of another program, the function {} ('{}') was not compiled",
+                saved.index,
+                saved.name.as_deref().unwrap_or("script")
+            ))
+        }
+    }
+    let mut natives = FxHashMap::default();
+    for record in &snapshot.objects {
+        if let Record::Native(name) = record {
+            match runtime.global(name) {
+                Some(value) if is_native(value) => natives.insert(name.as_str(), value),
+                _ => bail!(format!("The native function '{}' is not defined", name)),
+            };
+        }
+    }
+    let mut restorer = Restorer {
+        snapshot: &snapshot,
+        runtime,
+        natives,
+        objects: Vec::new(),
+        upvalues: Vec::new(),
+    };
+    let values = restorer.restore()?;
+    for ((name, _), value) in snapshot.globals.iter().zip(values) {
+        runtime.define_global(name, value);
+    }
+    Ok(())
+}
+
+fn is_native(value: Value) -> bool {
+    value.is_object() && matches!(value.as_object().object_type, ObjectType::NativeFunction(_))
+}
+
+/// The address of the object an [Object] wraps, several [Object]s can wrap the same one
+fn address(object: ObjectType) -> usize {
+    match object {
+        ObjectType::String(s) => s.as_ptr() as usize,
+        ObjectType::Rope(r) => r.as_ptr() as usize,
+        ObjectType::Function(f) => f.as_ptr() as usize,
+        ObjectType::NativeFunction(n) => n.as_ptr() as usize,
+        ObjectType::Closure(c) => c.as_ptr() as usize,
+        ObjectType::Class(c) => c.as_ptr() as usize,
+        ObjectType::Instance(i) => i.as_ptr() as usize,
+        ObjectType::BoundMethod(b) => b.as_ptr() as usize,
+        ObjectType::Range(r) => r.as_ptr() as usize,
+        ObjectType::RangeIterator(i) => i.as_ptr() as usize,
+        ObjectType::Coroutine(c) => c.as_ptr() as usize,
+    }
+}
+
+/// Finds the objects reachable from the globals, see [take]
+#[derive(Default)]
+struct Taker {
+    /// The index of each function in [EvieRuntime::functions], by address
+    function_indices: FxHashMap<usize, u32>,
+    /// The functions used by the snapshot
+    used_functions: FxHashSet<u32>,
+    /// The index of each object found so far, by address
+    objects: FxHashMap<usize, u32>,
+    /// The objects found so far, by index, they are saved in this order
+    pending: Vec<ObjectType>,
+    /// The index of each upvalue saved so far, by address
+    upvalues: FxHashMap<usize, u32>,
+    snapshot: Snapshot,
+}
+
+impl Taker {
+    fn value(&mut self, value: Value) -> Saved {
+        if value.is_nil() {
+            Saved::Nil
+        } else if value.is_bool() {
+            Saved::Bool(value.as_bool())
+        } else if value.is_number() {
+            Saved::Number(value.as_number())
+        } else {
+            Saved::Object(self.object(value.as_object().object_type))
+        }
+    }
+
+    /// The index of the object, it is saved later if it is new
+    fn object(&mut self, object: ObjectType) -> u32 {
+        let next = self.pending.len() as u32;
+        let index = *self.objects.entry(address(object)).or_insert(next);
+        if index == next {
+            self.pending.push(object);
+        }
+        index
+    }
+
+    fn function(&mut self, function: GCObjectOf<UserDefinedFunction>) -> Result<u32> {
+        match self.function_indices.get(&(function.as_ptr() as usize)) {
+            Some(&index) => {
+                self.used_functions.insert(index);
+                Ok(index)
+            }
+            None => bail!(format!(
+                "The function {} can't be saved, it was not added to the runtime when it was compiled",
+                function.as_ref()
+            )),
+        }
+    }
+
+    fn upvalue(&mut self, upvalue: GCObjectOf<Upvalue>) -> Result<u32> {
+        if let Some(&index) = self.upvalues.get(&(upvalue.as_ptr() as usize)) {
+            return Ok(index);
+        }
+        let value = match upvalue.location {
+            Location::Heap(value) => self.value(*value),
+            Location::Stack(_) => bail!(
+                "A snapshot can't be taken while a script runs, a closure captures a local of the stack"
+            ),
+        };
+        let index = self.snapshot.upvalues.len() as u32;
+        self.upvalues.insert(upvalue.as_ptr() as usize, index);
+        self.snapshot.upvalues.push(value);
+        Ok(index)
+    }
+
+    fn record(&mut self, object: ObjectType) -> Result<Record> {
+        Ok(match object {
+            ObjectType::String(s) => Record::String(s.to_string()),
+            ObjectType::Rope(r) => Record::String(r.as_str().to_string()),
+            ObjectType::Function(f) => Record::Function(self.function(f)?),
+            ObjectType::NativeFunction(n) => Record::Native(n.name.to_string()),
+            ObjectType::Closure(c) => Record::Closure {
+                function: self.function(c.function)?,
+                upvalues: c
+                    .upvalues
+                    .iter()
+                    .map(|upvalue| self.upvalue(*upvalue))
+                    .collect::<Result<_>>()?,
+            },
+            ObjectType::Class(c) => Record::Class {
+                name: c.name.to_string(),
+                doc: c.doc.map(|doc| doc.to_string()),
+                methods: c
+                    .methods
+                    .iter()
+                    .map(|(name, method)| {
+                        (name.to_string(), self.object(ObjectType::Closure(*method)))
+                    })
+                    .collect(),
+                statics: c
+                    .statics
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), self.value(*value)))
+                    .collect(),
+                init: c.init.map(|init| self.object(ObjectType::Closure(init))),
+            },
+            ObjectType::Instance(i) => Record::Instance {
+                class: self.object(ObjectType::Class(i.class)),
+                fields: i
+                    .fields
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), self.value(value)))
+                    .collect(),
+            },
+            ObjectType::BoundMethod(b) => Record::BoundMethod {
+                receiver: self.object(b.0.object_type),
+                method: self.object(ObjectType::Closure(b.1)),
+            },
+            ObjectType::Range(r) => Record::Range(*r),
+            ObjectType::RangeIterator(i) => Record::RangeIterator(*i),
+            ObjectType::Coroutine(_) => bail!("Coroutines can't be saved in a snapshot"),
+        })
+    }
+}
+
+/// Creates the objects of a snapshot, see [restore]
+struct Restorer<'a> {
+    snapshot: &'a Snapshot,
+    runtime: &'a EvieRuntime,
+    natives: FxHashMap<&'a str, Value>,
+    /// The objects created so far, by index
+    objects: Vec<Option<GCObjectOf<Object>>>,
+    upvalues: Vec<GCObjectOf<Upvalue>>,
+}
+
+impl<'a> Restorer<'a> {
+    /// Creates the objects and returns the values of the globals, nothing references the objects until the globals
+    /// are defined (the allocator does not collect on its own)
+    fn restore(&mut self) -> Result<Vec<Value>> {
+        let allocator = self.runtime.allocator();
+        self.objects = vec![None; self.snapshot.objects.len()];
+        self.upvalues = self
+            .snapshot
+            .upvalues
+            .iter()
+            .map(|_| {
+                let value = allocator.alloc(Value::nil());
+                allocator.alloc(Upvalue::new_with_location(Location::Heap(value)))
+            })
+            .collect();
+        // The instances reference classes and the bound methods instances and closures, they are created last
+        for pass in 0..3 {
+            for (index, record) in self.snapshot.objects.iter().enumerate() {
+                let object = match (pass, record) {
+                    (0, Record::String(s)) => allocator.alloc_string(s),
+                    (0, Record::Native(name)) => self.natives[name.as_str()].as_object(),
+                    (0, Record::Function(function)) => {
+                        self.new_object(ObjectType::Function(self.function(*function)?))
+                    }
+                    (0, Record::Closure { function, upvalues }) => {
+                        let upvalues = upvalues
+                            .iter()
+                            .map(|&upvalue| self.upvalue(upvalue))
+                            .collect::<Result<Vec<_>>>()?;
+                        let closure =
+                            Closure::new(self.function(*function)?, allocator.alloc(upvalues));
+                        self.new_object(ObjectType::Closure(allocator.alloc(closure)))
+                    }
+                    (0, Record::Class { name, doc, .. }) => {
+                        // Like the classes declared by scripts, see `Opcode::Class` and `Opcode::Doc`
+                        let mut class = Class::new(
+                            allocator.alloc_interned_str(name),
+                            allocator.alloc(Cache::with_linear_limit(0)),
+                            allocator.alloc(Cache::new()),
+                        );
+                        class.doc = doc.as_deref().map(|doc| allocator.alloc_interned_str(doc));
+                        self.new_object(ObjectType::Class(allocator.alloc(class)))
+                    }
+                    (0, Record::Range(range)) => {
+                        self.new_object(ObjectType::Range(allocator.alloc(*range)))
+                    }
+                    (0, Record::RangeIterator(iterator)) => {
+                        self.new_object(ObjectType::RangeIterator(allocator.alloc(*iterator)))
+                    }
+                    (1, Record::Instance { class, .. }) => {
+                        let instance = Instance::new(self.class(*class)?, Fields::new(allocator));
+                        self.new_object(ObjectType::Instance(allocator.alloc(instance)))
+                    }
+                    (2, Record::BoundMethod { receiver, method }) => {
+                        self.instance(*receiver)?;
+                        let receiver = self.objects[*receiver as usize].expect("Expect object");
+                        let bound_method = BoundMethod(receiver, self.closure(*method)?);
+                        self.new_object(ObjectType::BoundMethod(allocator.alloc(bound_method)))
+                    }
+                    _ => continue,
+                };
+                self.objects[index] = Some(object);
+            }
+        }
+        for (index, &saved) in self.snapshot.upvalues.iter().enumerate() {
+            let value = self.value(saved)?;
+            if let Location::Heap(mut heap_value) = self.upvalues[index].location {
+                *heap_value.as_mut() = value;
+            }
+        }
+        for (index, record) in self.snapshot.objects.iter().enumerate() {
+            match record {
+                Record::Class {
+                    methods,
+                    statics,
+                    init,
+                    ..
+                } => {
+                    let mut class = self.class(index as u32)?;
+                    for (name, method) in methods {
+                        let method = self.closure(*method)?;
+                        class
+                            .methods
+                            .insert(allocator.alloc_interned_str(name), method);
+                    }
+                    for (name, value) in statics {
+                        let value = self.value(*value)?;
+                        class
+                            .statics
+                            .insert(allocator.alloc_interned_str(name), value);
+                    }
+                    class.init = init.map(|init| self.closure(init)).transpose()?;
+                }
+                Record::Instance { fields, .. } => {
+                    let mut instance = self.instance(index as u32)?;
+                    for (name, value) in fields {
+                        let value = self.value(*value)?;
+                        instance.fields.insert(
+                            allocator.alloc_interned_str(name),
+                            value,
+                            allocator,
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.snapshot
+            .globals
+            .iter()
+            .map(|(_, value)| self.value(*value))
+            .collect()
+    }
+
+    fn new_object(&self, object: ObjectType) -> GCObjectOf<Object> {
+        Object::new_gc_object(object, self.runtime.allocator())
+    }
+
+    fn function(&self, index: u32) -> Result<GCObjectOf<UserDefinedFunction>> {
+        match self.runtime.functions().get(index as usize) {
+            Some(&function) => Ok(function),
+            None => bail!(invalid()),
+        }
+    }
+
+    fn upvalue(&self, index: u32) -> Result<GCObjectOf<Upvalue>> {
+        match self.upvalues.get(index as usize) {
+            Some(&upvalue) => Ok(upvalue),
+            None => bail!(invalid()),
+        }
+    }
+
+    fn object(&self, index: u32) -> Result<ObjectType> {
+        match self.objects.get(index as usize) {
+            Some(Some(object)) => Ok(object.object_type),
+            _ => bail!(invalid()),
+        }
+    }
+
+    fn value(&self, saved: Saved) -> Result<Value> {
+        Ok(match saved {
+            Saved::Nil => Value::nil(),
+            Saved::Bool(b) => Value::bool(b),
+            Saved::Number(n) => Value::number(n),
+            Saved::Object(index) => {
+                self.object(index)?;
+                Value::object(self.objects[index as usize].expect("Expect object"))
+            }
+        })
+    }
+
+    fn class(&self, index: u32) -> Result<GCObjectOf<Class>> {
+        match self.object(index)? {
+            ObjectType::Class(class) => Ok(class),
+            _ => bail!(invalid()),
+        }
+    }
+
+    fn instance(&self, index: u32) -> Result<GCObjectOf<Instance>> {
+        match self.object(index)? {
+            ObjectType::Instance(instance) => Ok(instance),
+            _ => bail!(invalid()),
+        }
+    }
+
+    fn closure(&self, index: u32) -> Result<GCObjectOf<Closure>> {
+        match self.object(index)? {
+            ObjectType::Closure(closure) => Ok(closure),
+            _ => bail!(invalid()),
+        }
+    }
+}
+
+fn invalid() -> String {
+    "Invalid snapshot".to_string()
+}
+
+mod tag {
+    pub(super) const NIL: u8 = 0;
+    pub(super) const FALSE: u8 = 1;
+    pub(super) const TRUE: u8 = 2;
+    pub(super) const NUMBER: u8 = 3;
+    pub(super) const OBJECT: u8 = 4;
+
+    pub(super) const STRING: u8 = 0;
+    pub(super) const NATIVE: u8 = 1;
+    pub(super) const FUNCTION: u8 = 2;
+    pub(super) const CLOSURE: u8 = 3;
+    pub(super) const CLASS: u8 = 4;
+    pub(super) const INSTANCE: u8 = 5;
+    pub(super) const BOUND_METHOD: u8 = 6;
+    pub(super) const RANGE: u8 = 7;
+    pub(super) const RANGE_ITERATOR: u8 = 8;
+}
+
+impl Snapshot {
+    fn write(&self) -> Vec<u8> {
+        let mut writer = Writer(MAGIC.to_vec());
+        writer.u8(VERSION);
+        writer.u32(self.functions.len() as u32);
+        for function in &self.functions {
+            writer.u32(function.index);
+            writer.optional_str(function.name.as_deref());
+            writer.u32(function.arity);
+        }
+        writer.u32(self.upvalues.len() as u32);
+        self.upvalues.iter().for_each(|value| writer.value(*value));
+        writer.u32(self.objects.len() as u32);
+        for object in &self.objects {
+            writer.record(object);
+        }
+        writer.u32(self.globals.len() as u32);
+        writer.named_values(&self.globals);
+        writer.0
+    }
+
+    fn read(bytes: &[u8]) -> Result<Snapshot> {
+        if !bytes.starts_with(MAGIC) {
+            bail!("Not a snapshot")
+        }
+        let mut reader = Reader {
+            bytes,
+            position: MAGIC.len(),
+        };
+        let version = reader.u8()?;
+        if version != VERSION {
+            bail!(format!(
+                "Unsupported snapshot version {}, expected {}",
+                version, VERSION
+            ))
+        }
+        let mut snapshot = Snapshot::default();
+        for _ in 0..reader.u32()? {
+            snapshot.functions.push(SavedFunction {
+                index: reader.u32()?,
+                name: reader.optional_string()?,
+                arity: reader.u32()?,
+            });
+        }
+        for _ in 0..reader.u32()? {
+            snapshot.upvalues.push(reader.value()?);
+        }
+        for _ in 0..reader.u32()? {
+            snapshot.objects.push(reader.record()?);
+        }
+        snapshot.globals = reader.named_values()?;
+        if reader.position != bytes.len() {
+            bail!(invalid())
+        }
+        Ok(snapshot)
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, byte: u8) {
+        self.0.push(byte);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn f64(&mut self, n: f64) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.u8(b as u8);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn optional_str(&mut self, s: Option<&str>) {
+        self.bool(s.is_some());
+        if let Some(s) = s {
+            self.str(s);
+        }
+    }
+
+    fn value(&mut self, value: Saved) {
+        match value {
+            Saved::Nil => self.u8(tag::NIL),
+            Saved::Bool(false) => self.u8(tag::FALSE),
+            Saved::Bool(true) => self.u8(tag::TRUE),
+            Saved::Number(n) => {
+                self.u8(tag::NUMBER);
+                self.f64(n);
+            }
+            Saved::Object(index) => {
+                self.u8(tag::OBJECT);
+                self.u32(index);
+            }
+        }
+    }
+
+    fn named_values(&mut self, values: &[(String, Saved)]) {
+        for (name, value) in values {
+            self.str(name);
+            self.value(*value);
+        }
+    }
+
+    fn range(&mut self, range: Range) {
+        self.f64(range.start);
+        self.f64(range.end);
+        self.bool(range.inclusive);
+    }
+
+    fn record(&mut self, record: &Record) {
+        match record {
+            Record::String(s) => {
+                self.u8(tag::STRING);
+                self.str(s);
+            }
+            Record::Native(name) => {
+                self.u8(tag::NATIVE);
+                self.str(name);
+            }
+            Record::Function(function) => {
+                self.u8(tag::FUNCTION);
+                self.u32(*function);
+            }
+            Record::Closure { function, upvalues } => {
+                self.u8(tag::CLOSURE);
+                self.u32(*function);
+                self.u32(upvalues.len() as u32);
+                upvalues.iter().for_each(|upvalue| self.u32(*upvalue));
+            }
+            Record::Class {
+                name,
+                doc,
+                methods,
+                statics,
+                init,
+            } => {
+                self.u8(tag::CLASS);
+                self.str(name);
+                self.optional_str(doc.as_deref());
+                self.u32(methods.len() as u32);
+                for (name, method) in methods {
+                    self.str(name);
+                    self.u32(*method);
+                }
+                self.u32(statics.len() as u32);
+                self.named_values(statics);
+                self.bool(init.is_some());
+                if let Some(init) = init {
+                    self.u32(*init);
+                }
+            }
+            Record::Instance { class, fields } => {
+                self.u8(tag::INSTANCE);
+                self.u32(*class);
+                self.u32(fields.len() as u32);
+                self.named_values(fields);
+            }
+            Record::BoundMethod { receiver, method } => {
+                self.u8(tag::BOUND_METHOD);
+                self.u32(*receiver);
+                self.u32(*method);
+            }
+            Record::Range(range) => {
+                self.u8(tag::RANGE);
+                self.range(*range);
+            }
+            Record::RangeIterator(iterator) => {
+                self.u8(tag::RANGE_ITERATOR);
+                self.f64(iterator.next);
+                self.range(iterator.range);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        match self
+            .bytes
+            .get(self.position..self.position.saturating_add(count))
+        {
+            Some(bytes) => {
+                self.position += count;
+                Ok(bytes)
+            }
+            None => bail!(invalid()),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(
+            bytes.try_into().expect("Expect 4 bytes"),
+        ))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        let bytes = self.bytes(8)?;
+        Ok(f64::from_le_bytes(
+            bytes.try_into().expect("Expect 8 bytes"),
+        ))
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => bail!(invalid()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let length = self.u32()? as usize;
+        match std::str::from_utf8(self.bytes(length)?) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => bail!(invalid()),
+        }
+    }
+
+    fn optional_string(&mut self) -> Result<Option<String>> {
+        Ok(match self.bool()? {
+            true => Some(self.string()?),
+            false => None,
+        })
+    }
+
+    fn value(&mut self) -> Result<Saved> {
+        Ok(match self.u8()? {
+            tag::NIL => Saved::Nil,
+            tag::FALSE => Saved::Bool(false),
+            tag::TRUE => Saved::Bool(true),
+            tag::NUMBER => Saved::Number(self.f64()?),
+            tag::OBJECT => Saved::Object(self.u32()?),
+            _ => bail!(invalid()),
+        })
+    }
+
+    fn named_values(&mut self) -> Result<Vec<(String, Saved)>> {
+        (0..self.u32()?)
+            .map(|_| Ok((self.string()?, self.value()?)))
+            .collect()
+    }
+
+    fn range(&mut self) -> Result<Range> {
+        Ok(Range {
+            start: self.f64()?,
+            end: self.f64()?,
+            inclusive: self.bool()?,
+        })
+    }
+
+    fn record(&mut self) -> Result<Record> {
+        Ok(match self.u8()? {
+            tag::STRING => Record::String(self.string()?),
+            tag::NATIVE => Record::Native(self.string()?),
+            tag::FUNCTION => Record::Function(self.u32()?),
+            tag::CLOSURE => Record::Closure {
+                function: self.u32()?,
+                upvalues: (0..self.u32()?)
+                    .map(|_| self.u32())
+                    .collect::<Result<_>>()?,
+            },
+            tag::CLASS => Record::Class {
+                name: self.string()?,
+                doc: self.optional_string()?,
+                methods: (0..self.u32()?)
+                    .map(|_| Ok((self.string()?, self.u32()?)))
+                    .collect::<Result<_>>()?,
+                statics: self.named_values()?,
+                init: match self.bool()? {
+                    true => Some(self.u32()?),
+                    false => None,
+                },
+            },
+            tag::INSTANCE => Record::Instance {
+                class: self.u32()?,
+                fields: self.named_values()?,
+            },
+            tag::BOUND_METHOD => Record::BoundMethod {
+                receiver: self.u32()?,
+                method: self.u32()?,
+            },
+            tag::RANGE => Record::Range(self.range()?),
+            tag::RANGE_ITERATOR => {
+                let next = self.f64()?;
+                Record::RangeIterator(RangeIterator {
+                    next,
+                    range: self.range()?,
+                })
+            }
+            _ => bail!(invalid()),
+        })
+    }
+}
@@ -0,0 +1,124 @@
+//! The interior mutability of the [crate::ObjectAllocator].
+//!
+//! By default these are [std::cell::Cell]s and [std::cell::RefCell]s: an allocator is used by one thread at a time
+//! (it is [Send], e.g. with the VM that owns it). With the `sync` feature they are [Mutex]es and the allocator is
+//! also [Sync], so that threads can share one, e.g. to compile scripts in parallel with the same interned strings.
+//! Sharing is for allocating: a collection takes the allocator to itself ([crate::ObjectAllocator::collect] takes
+//! `&mut self`, i.e. once the threads are done with it), and an object is only used by the thread that allocated it
+//! (or after the threads are joined).
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "sync")]
+use std::sync::Mutex;
+
+/// A [std::cell::Cell], a [Mutex] with the `sync` feature
+#[derive(Debug, Default)]
+pub(crate) struct Cell<T: Copy>(
+    #[cfg(not(feature = "sync"))] std::cell::Cell<T>,
+    #[cfg(feature = "sync")] Mutex<T>,
+);
+
+impl<T: Copy> Cell<T> {
+    pub(crate) fn new(value: T) -> Self {
+        #[cfg(not(feature = "sync"))]
+        return Cell(std::cell::Cell::new(value));
+        #[cfg(feature = "sync")]
+        Cell(Mutex::new(value))
+    }
+
+    #[inline(always)]
+    pub(crate) fn get(&self) -> T {
+        #[cfg(not(feature = "sync"))]
+        return self.0.get();
+        #[cfg(feature = "sync")]
+        *self.0.lock().expect("Lock poisoned")
+    }
+
+    #[inline(always)]
+    pub(crate) fn set(&self, value: T) {
+        self.replace(value);
+    }
+
+    /// Sets the value and returns the previous one
+    #[inline(always)]
+    pub(crate) fn replace(&self, value: T) -> T {
+        #[cfg(not(feature = "sync"))]
+        return self.0.replace(value);
+        #[cfg(feature = "sync")]
+        std::mem::replace(&mut *self.0.lock().expect("Lock poisoned"), value)
+    }
+
+    /// Sets the value to `f` of the value (at once, other threads wait) and returns the new value
+    #[inline(always)]
+    pub(crate) fn update(&self, f: impl FnOnce(T) -> T) -> T {
+        #[cfg(not(feature = "sync"))]
+        {
+            let value = f(self.0.get());
+            self.0.set(value);
+            value
+        }
+        #[cfg(feature = "sync")]
+        {
+            let mut value = self.0.lock().expect("Lock poisoned");
+            *value = f(*value);
+            *value
+        }
+    }
+}
+
+/// A [std::cell::RefCell], a [Mutex] with the `sync` feature: only one borrow at a time
+#[derive(Debug, Default)]
+pub(crate) struct RefCell<T>(
+    #[cfg(not(feature = "sync"))] std::cell::RefCell<T>,
+    #[cfg(feature = "sync")] Mutex<T>,
+);
+
+impl<T> RefCell<T> {
+    pub(crate) fn new(value: T) -> Self {
+        #[cfg(not(feature = "sync"))]
+        return RefCell(std::cell::RefCell::new(value));
+        #[cfg(feature = "sync")]
+        RefCell(Mutex::new(value))
+    }
+
+    #[inline(always)]
+    pub(crate) fn borrow(&self) -> impl Deref<Target = T> + '_ {
+        #[cfg(not(feature = "sync"))]
+        return self.0.borrow();
+        #[cfg(feature = "sync")]
+        self.0.lock().expect("Lock poisoned")
+    }
+
+    #[inline(always)]
+    pub(crate) fn borrow_mut(&self) -> impl DerefMut<Target = T> + '_ {
+        #[cfg(not(feature = "sync"))]
+        return self.0.borrow_mut();
+        #[cfg(feature = "sync")]
+        self.0.lock().expect("Lock poisoned")
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        #[cfg(not(feature = "sync"))]
+        return self.0.get_mut();
+        #[cfg(feature = "sync")]
+        self.0.get_mut().expect("Lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cell, RefCell};
+
+    #[test]
+    fn cells() {
+        let cell = Cell::new(1);
+        assert_eq!(1, cell.replace(2));
+        assert_eq!(3, cell.update(|value| value + 1));
+        cell.set(4);
+        assert_eq!(4, cell.get());
+        let mut ref_cell = RefCell::new(vec![1]);
+        ref_cell.borrow_mut().push(2);
+        assert_eq!(2, ref_cell.borrow().len());
+        ref_cell.get_mut().clear();
+        assert!(ref_cell.borrow().is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+//! Assertion natives for tests: `assert(condition, message)` and `assert_eq(actual, expected)`.
+//!
+//! A failed assertion is a runtime error, which fails the test (`evie test`) or makes `evie` exit with a nonzero
+//! status, so that shell based harnesses can detect it.
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{objects::NativeFn, runtime::EvieRuntime};
+
+use crate::as_str;
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![("assert", 2, assert), ("assert_eq", 2, assert_eq)]
+}
+
+/// Fails with the message if the condition is falsey (nil or false)
+pub fn assert(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    let condition = &inputs[0];
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn assert({}) ", condition);
+    if condition.is_nil() || (condition.is_bool() && !condition.as_bool()) {
+        bail!(format!("Assertion failed: {}", inputs[1]))
+    }
+    Ok(Value::nil())
+}
+
+/// Fails if the values are not equal, as `==` compares them
+pub fn assert_eq(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    let (actual, expected) = (&inputs[0], &inputs[1]);
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn assert_eq({}, {}) ", actual, expected);
+    // Strings are equal by their text, everything else by value (objects by identity)
+    let equal = match (as_str(actual), as_str(expected)) {
+        (Ok(actual), Ok(expected)) => actual == expected,
+        _ => actual == expected,
+    };
+    if !equal {
+        bail!(format!(
+            "Assertion failed: expected '{}', got '{}'",
+            expected, actual
+        ))
+    }
+    Ok(Value::nil())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assertions() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let message = runtime.alloc_string("message");
+        assert(&[Value::number(0.0), message], runtime)?;
+        let error = assert(&[Value::bool(false), message], runtime).expect_err("falsey");
+        assert_eq!("Assertion failed: message", error.to_string());
+        assert!(assert(&[Value::nil(), message], runtime).is_err());
+
+        let a = runtime.alloc_string("a");
+        assert_eq(&[a, runtime.alloc_string("a")], runtime)?;
+        assert_eq(&[Value::number(1.0), Value::number(1.0)], runtime)?;
+        let error = assert_eq(&[Value::number(1.0), a], runtime).expect_err("not equal");
+        assert_eq!("Assertion failed: expected 'a', got '1'", error.to_string());
+        assert!(assert_eq(&[Value::nil(), Value::bool(false)], runtime).is_err());
+        Ok(())
+    }
+}
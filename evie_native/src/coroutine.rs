@@ -0,0 +1,67 @@
+//! Natives for coroutines: `coroutine(function)` creates one.
+//!
+//! A coroutine runs the function when it is resumed (`c.resume(...)`, the arguments of the first resume are the
+//! arguments of the function) until it yields (`yield value`): `resume` returns the value and the next resume
+//! continues after the `yield`, its argument (nil if there is none) is the value of the `yield` expression.
+//! The methods are built in the VM: `resume`, `is_done` and the iterator protocol (`iter` and `next`, which returns
+//! nil once the coroutine is done), so that a coroutine can be the iterable of a for-in loop.
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{
+    objects::{Coroutine, NativeFn, Object, ObjectType},
+    runtime::EvieRuntime,
+};
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![("coroutine", 1, coroutine)]
+}
+
+/// Creates a coroutine that calls the function (a function, closure or bound method) when it is first resumed
+pub fn coroutine(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let function = inputs[0];
+    if !is_function(function) {
+        bail!(format!(
+            "Expected a function for coroutine, got '{}'",
+            function
+        ))
+    }
+    let allocator = runtime.allocator();
+    let coroutine = allocator.alloc(Coroutine::new(function));
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn coroutine() -> <coroutine of {}> ", function);
+    Ok(Value::object(Object::new_gc_object(
+        ObjectType::Coroutine(coroutine),
+        allocator,
+    )))
+}
+
+fn is_function(value: Value) -> bool {
+    value.is_object()
+        && matches!(
+            value.as_object().object_type,
+            ObjectType::Closure(_) | ObjectType::BoundMethod(_)
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coroutine_of_a_function_only() {
+        let runtime = &mut EvieRuntime::new();
+        let error = coroutine(&[Value::number(1.0)], runtime).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Expected a function for coroutine, got '1'"
+        );
+        let name = runtime.alloc_string("clock");
+        assert!(coroutine(&[name], runtime).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Natives to inspect functions and classes: `doc(value)`, `fn_name(f)`, `fn_arity(f)` and `method_receiver(m)`.
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{
+    objects::{NativeFn, ObjectType},
+    runtime::EvieRuntime,
+};
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![
+        ("doc", 1, doc),
+        ("fn_name", 1, fn_name),
+        ("fn_arity", 1, fn_arity),
+        ("method_receiver", 1, method_receiver),
+    ]
+}
+
+/// Returns the docstring of the function (or bound method) or class, nil if it has none. The docstring is a string
+/// literal at the start of the body:
+/// ```text
+/// fun area(r) {
+///     "The area of a circle of radius r";
+///     return 3.14 * r * r;
+/// }
+/// ```
+/// Native functions have no docstring.
+pub fn doc(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let value = inputs[0];
+    let object_type = value.is_object().then(|| value.as_object().object_type);
+    let doc = match object_type {
+        Some(ObjectType::Function(f)) => f.doc,
+        Some(ObjectType::Closure(c)) => c.function.doc,
+        Some(ObjectType::BoundMethod(b)) => b.1.function.doc,
+        Some(ObjectType::Class(c)) => c.doc,
+        Some(ObjectType::NativeFunction(_)) => None,
+        _ => bail!(format!(
+            "Expected a function or a class for doc, got '{}'",
+            value
+        )),
+    };
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn doc() -> {:?} ", doc.map(|d| (*d).clone()));
+    Ok(match doc {
+        Some(doc) => runtime.alloc_string(&**doc),
+        None => Value::nil(),
+    })
+}
+
+/// Returns the name of the function (closure, bound method or native): "anonymous" for anonymous functions and
+/// "script" for the script
+pub fn fn_name(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let result = as_function(&inputs[0], "fn_name")?.name;
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn fn_name() -> {} ", result);
+    Ok(runtime.alloc_string(result))
+}
+
+/// Returns the number of arguments the function (closure, bound method or native) expects
+pub fn fn_arity(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    let result = as_function(&inputs[0], "fn_arity")?.arity;
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn fn_arity() -> {} ", result);
+    Ok(Value::number(result as f64))
+}
+
+/// Returns the instance a bound method (e.g. `point.move`) is bound to, `this` in its body. Nil for the other
+/// functions
+pub fn method_receiver(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    as_function(&inputs[0], "method_receiver")?;
+    let value = inputs[0];
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn method_receiver({}) ", value);
+    Ok(match value.as_object().object_type {
+        ObjectType::BoundMethod(b) => Value::object(b.0),
+        _ => Value::nil(),
+    })
+}
+
+/// The name and arity of a function
+struct Function {
+    name: String,
+    arity: usize,
+}
+
+/// Returns the function in `value` or fails if it is not a function, `native` is the name of the native for the error
+fn as_function(value: &Value, native: &str) -> Result<Function> {
+    let object_type = value.is_object().then(|| value.as_object().object_type);
+    let (name, arity) = match object_type {
+        Some(ObjectType::Function(f)) => (f.name, f.arity),
+        Some(ObjectType::Closure(c)) => (c.function.name, c.function.arity),
+        Some(ObjectType::BoundMethod(b)) => (b.1.function.name, b.1.function.arity),
+        Some(ObjectType::NativeFunction(n)) => (Some(n.name), n.arity),
+        _ => bail!(format!(
+            "Expected a function for {}, got '{}'",
+            native, value
+        )),
+    };
+    Ok(Function {
+        name: name.map_or_else(|| "script".to_string(), |name| name.to_string()),
+        arity,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doc_of_a_function_or_a_class_only() {
+        let runtime = &mut EvieRuntime::new();
+        let error = doc(&[Value::number(1.0)], runtime).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Expected a function or a class for doc, got '1'"
+        );
+        let name = runtime.alloc_string("clock");
+        assert!(doc(&[name], runtime).is_err());
+    }
+
+    #[test]
+    fn inspects_functions_only() {
+        let runtime = &mut EvieRuntime::new();
+        let error = fn_arity(&[Value::nil()], runtime).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Expected a function for fn_arity, got 'nil'"
+        );
+        let name = runtime.alloc_string("clock");
+        assert!(fn_name(&[name], runtime).is_err());
+        assert!(method_receiver(&[Value::bool(true)], runtime).is_err());
+    }
+}
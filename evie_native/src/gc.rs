@@ -0,0 +1,65 @@
+//! Garbage collector natives: `gc_collect()` and `gc_stats()`.
+//!
+//! Natives cannot see the roots of the VM, so `gc_collect()` only requests a collection,
+//! which the VM runs at the next safe point (before the next instruction).
+use evie_common::errors::*;
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{
+    cache::Cache,
+    objects::{Class, Instance, NativeFn, Object, ObjectType},
+    runtime::EvieRuntime,
+    shape::Fields,
+};
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![("gc_collect", 0, gc_collect), ("gc_stats", 0, gc_stats)]
+}
+
+/// Requests a garbage collection
+pub fn gc_collect(_: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    runtime.allocator().request_collection();
+    Ok(Value::nil())
+}
+
+/// Returns an instance of `GcStats` with the fields
+/// `objects`, `bytes`, `collections`, `objects_freed`, `young_objects_freed`, `total_pause_ms`, `max_pause_ms` & `pooled_bytes`
+pub fn gc_stats(_: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let allocator = runtime.allocator();
+    let stats = allocator.stats();
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn gc_stats() -> {:?} ", stats);
+    let fields = [
+        ("objects", stats.objects as f64),
+        ("bytes", stats.bytes_allocated as f64),
+        ("collections", stats.collections as f64),
+        ("objects_freed", stats.objects_freed as f64),
+        ("young_objects_freed", stats.young_objects_freed as f64),
+        ("total_pause_ms", stats.total_pause.as_secs_f64() * 1000.0),
+        ("max_pause_ms", stats.max_pause.as_secs_f64() * 1000.0),
+        ("pooled_bytes", stats.pooled_bytes as f64),
+    ];
+    let mut values = Fields::new(allocator);
+    for (name, value) in fields {
+        values.insert(
+            allocator.alloc_interned_str(name),
+            Value::number(value),
+            allocator,
+        );
+    }
+    let class = allocator.alloc(Class::new(
+        allocator.alloc_interned_str("GcStats"),
+        allocator.alloc(Cache::new()),
+        allocator.alloc(Cache::new()),
+    ));
+    let instance = allocator.alloc(Instance::new(class, values));
+    Ok(Value::object(Object::new_gc_object(
+        ObjectType::Instance(instance),
+        allocator,
+    )))
+}
@@ -0,0 +1,55 @@
+//! Input natives `read_line()` and `read_all()`.
+//!
+//! They read from the input source of the calling VM's runtime (stdin by default),
+//! which can be replaced via `VirtualMachine::new_with_reader_and_writer`.
+use evie_common::errors::*;
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{objects::NativeFn, replay::Source, runtime::EvieRuntime};
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![("read_line", 0, read_line), ("read_all", 0, read_all)]
+}
+
+/// Reads the next line (without the line ending), nil at the end of the input
+pub fn read_line(_: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let line = runtime.text_input(Source::Read, |runtime| {
+        let mut line = String::new();
+        let bytes = runtime
+            .input()
+            .read_line(&mut line)
+            .chain_err(|| "Unable to read input")?;
+        Ok((bytes > 0).then_some(line))
+    })?;
+    let line = match line {
+        Some(line) => line,
+        None => return Ok(Value::nil()),
+    };
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn read_line() -> {} ", line);
+    Ok(runtime.alloc_string(line))
+}
+
+/// Reads the rest of the input
+pub fn read_all(_: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let all = runtime
+        .text_input(Source::Read, |runtime| {
+            let mut all = String::new();
+            runtime
+                .input()
+                .read_to_string(&mut all)
+                .chain_err(|| "Unable to read input")?;
+            Ok(Some(all))
+        })?
+        .unwrap_or_default();
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn read_all() -> {} ", all);
+    Ok(runtime.alloc_string(all))
+}
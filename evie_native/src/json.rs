@@ -0,0 +1,210 @@
+//! JSON natives: `json_parse(string)` and `json_stringify(value)`.
+//!
+//! null, booleans, numbers and strings map to the Evie primitives. Evie has no maps or lists yet, JSON objects
+//! are parsed into instances (of a class named `Json`) and instances are written as objects of their fields,
+//! sorted by name.
+//! Arrays are not supported until Evie has lists.
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{
+    cache::Cache,
+    objects::{Class, GCObjectOf, Instance, NativeFn, Object, ObjectType},
+    runtime::EvieRuntime,
+    shape::Fields,
+};
+use serde_json::{Map, Number};
+
+use crate::as_str;
+
+/// How many objects deep the JSON text can be: the most [json_parse] reads (the recursion limit of serde_json) and
+/// [json_stringify] writes
+pub const MAX_JSON_DEPTH: usize = 127;
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![
+        ("json_parse", 1, json_parse),
+        ("json_stringify", 1, json_stringify),
+    ]
+}
+
+/// Parses the JSON text into a value, objects become instances of the class `Json`
+pub fn json_parse(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let text = as_str(&inputs[0])?;
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn json_parse({}) ", text);
+    let json: serde_json::Value =
+        serde_json::from_str(text).map_err(|e| ErrorKind::Msg(format!("Invalid JSON: {}", e)))?;
+    let allocator = runtime.allocator();
+    let class = allocator.alloc(Class::new(
+        allocator.alloc_interned_str("Json"),
+        allocator.alloc(Cache::new()),
+        allocator.alloc(Cache::new()),
+    ));
+    to_value(&json, class, runtime)
+}
+
+/// Writes the value as JSON text, instances are written as objects of their fields (sorted by name).
+/// Fails for instances nested deeper than [MAX_JSON_DEPTH].
+pub fn json_stringify(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let json = to_json(&inputs[0], &mut Vec::new())?;
+    let result = json.to_string();
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn json_stringify() -> {} ", result);
+    Ok(runtime.alloc_string(result))
+}
+
+fn to_value(
+    json: &serde_json::Value,
+    class: GCObjectOf<Class>,
+    runtime: &mut EvieRuntime,
+) -> Result<Value> {
+    Ok(match json {
+        serde_json::Value::Null => Value::nil(),
+        serde_json::Value::Bool(b) => Value::bool(*b),
+        serde_json::Value::Number(n) => Value::number(n.as_f64().unwrap_or(f64::NAN)),
+        serde_json::Value::String(s) => runtime.alloc_string(s),
+        serde_json::Value::Array(_) => bail!("JSON arrays are not supported, evie has no lists"),
+        serde_json::Value::Object(members) => {
+            let mut fields = Fields::new(runtime.allocator());
+            for (name, member) in members {
+                let value = to_value(member, class, runtime)?;
+                let allocator = runtime.allocator();
+                fields.insert(allocator.alloc_interned_str(name), value, allocator);
+            }
+            let allocator = runtime.allocator();
+            let instance = allocator.alloc(Instance::new(class, fields));
+            Value::object(Object::new_gc_object(
+                ObjectType::Instance(instance),
+                allocator,
+            ))
+        }
+    })
+}
+
+/// `instances` are the instances being written, to fail on cycles instead of recursing forever
+fn to_json(value: &Value, instances: &mut Vec<usize>) -> Result<serde_json::Value> {
+    if value.is_nil() {
+        return Ok(serde_json::Value::Null);
+    }
+    if value.is_bool() {
+        return Ok(serde_json::Value::Bool(value.as_bool()));
+    }
+    if value.is_number() {
+        let n = value.as_number();
+        // Integers are written without a fraction, as Evie prints them
+        let number = if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+            Number::from(n as i64)
+        } else {
+            Number::from_f64(n).ok_or_else(|| format!("Can not write '{}' as JSON", n))?
+        };
+        return Ok(serde_json::Value::Number(number));
+    }
+    match value.as_object().object_type {
+        ObjectType::String(_) | ObjectType::Rope(_) => {
+            Ok(serde_json::Value::String(as_str(value)?.to_string()))
+        }
+        ObjectType::Instance(instance) => {
+            let address = instance.as_ptr() as usize;
+            if instances.contains(&address) {
+                bail!(format!(
+                    "Can not write '{}' as JSON, it contains itself",
+                    value
+                ))
+            }
+            if instances.len() == MAX_JSON_DEPTH {
+                bail!(format!(
+                    "Can not write '{}' as JSON, it is nested deeper than {} levels",
+                    value, MAX_JSON_DEPTH
+                ))
+            }
+            instances.push(address);
+            let mut members = Map::new();
+            for (name, field) in instance.fields.iter() {
+                members.insert(name.to_string(), to_json(&field, instances)?);
+            }
+            instances.pop();
+            Ok(serde_json::Value::Object(members))
+        }
+        _ => bail!(format!("Can not write '{}' as JSON", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_stringify() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let text = runtime.alloc_string(
+            r#"{"name": "evie", "version": 1, "tags": {"fast": true}, "license": null}"#,
+        );
+        let value = json_parse(&[text], runtime)?;
+        assert_eq!("<instance of Json>", value.to_string());
+        // Members are sorted by name
+        assert_eq!(
+            r#"{"license":null,"name":"evie","tags":{"fast":true},"version":1}"#,
+            json_stringify(&[value], runtime)?.to_string()
+        );
+        let number = runtime.alloc_string("1.5");
+        assert_eq!(1.5, json_parse(&[number], runtime)?.as_number());
+        assert_eq!(
+            r#""a \"quote\"""#,
+            json_stringify(&[runtime.alloc_string(r#"a "quote""#)], runtime)?.to_string()
+        );
+        let array = runtime.alloc_string("[1, 2]");
+        assert!(json_parse(&[array], runtime).is_err());
+        let invalid = runtime.alloc_string("{");
+        assert!(json_parse(&[invalid], runtime).is_err());
+        assert!(json_stringify(&[Value::number(f64::NAN)], runtime).is_err());
+        // An instance that contains itself
+        if let ObjectType::Instance(mut instance) = value.as_object().object_type {
+            let name = runtime.allocator().alloc_interned_str("self");
+            instance.fields.insert(name, value, runtime.allocator());
+        }
+        assert!(json_stringify(&[value], runtime).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stringify_as_deep_as_parse() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let nested =
+            |depth: usize| format!("{}1{}", r#"{"next":"#.repeat(depth), "}".repeat(depth));
+        let text = runtime.alloc_string(nested(MAX_JSON_DEPTH));
+        let value = json_parse(&[text], runtime)?;
+        assert_eq!(
+            nested(MAX_JSON_DEPTH),
+            json_stringify(&[value], runtime)?.to_string()
+        );
+        let text = runtime.alloc_string(nested(MAX_JSON_DEPTH + 1));
+        assert!(json_parse(&[text], runtime).is_err());
+        // A long chain of instances
+        let allocator = runtime.allocator();
+        let class = allocator.alloc(Class::new(
+            allocator.alloc_interned_str("Node"),
+            allocator.alloc(Cache::new()),
+            allocator.alloc(Cache::new()),
+        ));
+        let next = allocator.alloc_interned_str("next");
+        let mut value = Value::nil();
+        for _ in 0..100_000 {
+            let mut fields = Fields::new(allocator);
+            fields.insert(next, value, allocator);
+            let node = allocator.alloc(Instance::new(class, fields));
+            value = Value::object(Object::new_gc_object(ObjectType::Instance(node), allocator));
+        }
+        let error = json_stringify(&[value], runtime).expect_err("too deep");
+        assert_eq!(
+            "Can not write '<instance of Node>' as JSON, it is nested deeper than 127 levels",
+            error.to_string()
+        );
+        Ok(())
+    }
+}
@@ -1,36 +1,145 @@
 //! All Native functions supported by Evie.
 //!
-//! Currently only supports two [clock] & [to_string]
+//! Supports [clock], [to_string] & [type_of] (`type`), the [assert], [coroutine], [function], [gc], [io], [json], [math], [object], [pattern], [random] and [time] natives and the methods on String values (see [string]).
+//! The host environment natives in `process` are only available with the `unsafe_natives` feature.
+
+pub mod assert;
+pub mod coroutine;
+pub mod function;
+pub mod gc;
+pub mod io;
+pub mod json;
+pub mod math;
+pub mod object;
+pub mod pattern;
+#[cfg(feature = "unsafe_natives")]
+pub mod process;
+pub mod random;
+pub mod string;
+pub mod time;
 
 #[cfg(feature = "trace_enabled")]
 use evie_common::trace;
+use evie_common::{bail, errors::*};
 #[cfg(feature = "nan_boxed")]
 use evie_memory::objects::nan_boxed::Value;
 #[cfg(not(feature = "nan_boxed"))]
 use evie_memory::objects::non_nan_boxed::Value;
 use evie_memory::{
-    objects::{Object, ObjectType},
-    ObjectAllocator,
+    objects::{NativeFn, ObjectType},
+    replay::Source,
+    runtime::EvieRuntime,
 };
-use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Every native function defined by default (e.g. by the evie runner) as (name, arity, function):
+/// the ones in this module and in [assert], [coroutine], [function], [gc], [io], [json], [math], [object], [pattern], [random] and [time], and the `process` ones with `unsafe_natives`
+pub fn all_natives() -> Vec<(&'static str, usize, NativeFn)> {
+    let natives = natives()
+        .into_iter()
+        .chain(assert::natives())
+        .chain(coroutine::natives())
+        .chain(function::natives())
+        .chain(gc::natives())
+        .chain(io::natives())
+        .chain(json::natives())
+        .chain(math::natives())
+        .chain(object::natives())
+        .chain(pattern::natives())
+        .chain(random::natives())
+        .chain(time::natives());
+    #[cfg(feature = "unsafe_natives")]
+    let natives = natives.chain(process::natives());
+    natives.collect()
+}
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![
+        ("clock", 0, clock),
+        ("to_string", 1, to_string),
+        ("type", 1, type_of),
+    ]
+}
 
 /// Prints the current time as a [evie_memory::objects::Value::Number] (float)
-pub fn clock(_: Vec<Value>, _: &ObjectAllocator) -> Value {
-    let start = SystemTime::now();
-    let since_the_epoch = start
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs_f64();
+pub fn clock(_: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let since_the_epoch = runtime.number_input(Source::Clock, |_| {
+        evie_memory::clock::since_epoch().as_secs_f64()
+    })?;
     #[cfg(feature = "trace_enabled")]
     trace!("native fn clock() -> {} ", since_the_epoch);
-    Value::number(since_the_epoch)
+    Ok(Value::number(since_the_epoch))
 }
 
 /// Converts the given [evie_memory::objects::Value]  into a [evie_memory::objects::ObjectType::String]
-pub fn to_string(inputs: Vec<Value>, allocator: &ObjectAllocator) -> Value {
+pub fn to_string(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
     let result = inputs[0].to_string();
     #[cfg(feature = "trace_enabled")]
     trace!("native fn to_string() -> {} ", result);
-    let string = ObjectType::String(allocator.alloc(result.into_boxed_str()));
-    Value::object(Object::new_gc_object(string, allocator))
+    Ok(runtime.alloc_string(result))
+}
+
+/// Returns the type of the given value as a string:
+/// "nil", "boolean", "number", "string", "function", "class", "instance", "range", "iterator" or "coroutine"
+pub fn type_of(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let value = &inputs[0];
+    let result = if value.is_nil() {
+        "nil"
+    } else if value.is_bool() {
+        "boolean"
+    } else if value.is_number() {
+        "number"
+    } else {
+        match value.as_object().object_type {
+            ObjectType::String(_) | ObjectType::Rope(_) => "string",
+            ObjectType::Function(_)
+            | ObjectType::NativeFunction(_)
+            | ObjectType::Closure(_)
+            | ObjectType::BoundMethod(_) => "function",
+            ObjectType::Class(_) => "class",
+            ObjectType::Instance(_) => "instance",
+            ObjectType::Range(_) => "range",
+            ObjectType::RangeIterator(_) => "iterator",
+            ObjectType::Coroutine(_) => "coroutine",
+        }
+    };
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn type() -> {} ", result);
+    Ok(runtime.alloc_string(result))
+}
+
+/// Returns the string in `value` or fails if it is not a String
+pub(crate) fn as_str(value: &Value) -> Result<&str> {
+    if value.is_object() {
+        match value.as_object().object_type {
+            // Safety: the string is owned by the runtime and outlives this native call
+            ObjectType::String(s) => return Ok(unsafe { &*s.as_ptr() }),
+            // Safety: as above, and the VM does not append to the rope's buffer during a native call
+            ObjectType::Rope(r) => return Ok(unsafe { (*r.as_ptr()).as_str() }),
+            _ => {}
+        }
+    }
+    bail!(format!("Expected a string, got '{}'", value))
+}
+
+/// Returns the number in `value` or fails if it is not a Number
+pub(crate) fn as_number(value: &Value, name: &str) -> Result<f64> {
+    if value.is_number() {
+        return Ok(value.as_number());
+    }
+    bail!(format!("Expected a number for {}, got '{}'", name, value))
+}
+
+/// Returns `value` as an index or fails if it is not a non negative integer
+pub(crate) fn as_index(value: &Value, name: &str) -> Result<usize> {
+    if value.is_number() {
+        let n = value.as_number();
+        if n >= 0.0 && n.fract() == 0.0 {
+            return Ok(n as usize);
+        }
+    }
+    bail!(format!(
+        "Expected a non negative integer for {}, got '{}'",
+        name, value
+    ))
 }
@@ -0,0 +1,179 @@
+//! Numeric natives: parsing (`num`), formatting (`format`), rounding and the common math functions.
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{objects::NativeFn, runtime::EvieRuntime};
+
+use crate::{as_index, as_number, as_str};
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![
+        ("num", 1, num),
+        ("format", 2, format),
+        ("floor", 1, floor),
+        ("ceil", 1, ceil),
+        ("round", 1, round),
+        ("abs", 1, abs),
+        ("sqrt", 1, sqrt),
+        ("pow", 2, pow),
+        ("min", 2, min),
+        ("max", 2, max),
+    ]
+}
+
+/// Parses the given string into a Number, returns nil if it is not a valid number
+pub fn num(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    let string = as_str(&inputs[0])?;
+    let result = match string.trim().parse::<f64>() {
+        Ok(n) => Value::number(n),
+        Err(_) => Value::nil(),
+    };
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn num() -> {} ", result);
+    Ok(result)
+}
+
+/// The largest precision [format] accepts
+pub const MAX_PRECISION: usize = 100;
+
+/// Formats the number with `precision` digits after the decimal point, e.g. `format(2 / 3, 2)` is "0.67".
+/// `print` shows 6 significant digits (see [evie_memory::objects::format_number]).
+/// The precision is at most [MAX_PRECISION].
+pub fn format(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let n = as_number(&inputs[0], "format")?;
+    let precision = as_index(&inputs[1], "precision")?;
+    if precision > MAX_PRECISION {
+        bail!(format!(
+            "Expected a precision of at most {}, got '{}'",
+            MAX_PRECISION, precision
+        ))
+    }
+    let result = format!("{:.*}", precision, n);
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn format() -> {} ", result);
+    Ok(runtime.alloc_string(result))
+}
+
+/// Largest integer less than or equal to the input
+pub fn floor(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    unary(inputs, f64::floor)
+}
+
+/// Smallest integer greater than or equal to the input
+pub fn ceil(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    unary(inputs, f64::ceil)
+}
+
+/// Nearest integer to the input, rounding half-way cases away from 0
+pub fn round(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    unary(inputs, f64::round)
+}
+
+/// Absolute value of the input
+pub fn abs(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    unary(inputs, f64::abs)
+}
+
+/// Square root of the input, fails for negative numbers
+pub fn sqrt(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    let n = as_number(&inputs[0], "sqrt")?;
+    if n < 0.0 {
+        bail!(format!("Cannot take the square root of {}", n))
+    }
+    Ok(Value::number(n.sqrt()))
+}
+
+/// The first input raised to the power of the second
+pub fn pow(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    binary(inputs, f64::powf)
+}
+
+/// The smaller of the two inputs
+pub fn min(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    binary(inputs, f64::min)
+}
+
+/// The larger of the two inputs
+pub fn max(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    binary(inputs, f64::max)
+}
+
+fn unary(inputs: &[Value], f: fn(f64) -> f64) -> Result<Value> {
+    let n = as_number(&inputs[0], "argument")?;
+    Ok(Value::number(f(n)))
+}
+
+fn binary(inputs: &[Value], f: fn(f64, f64) -> f64) -> Result<Value> {
+    let a = as_number(&inputs[0], "first argument")?;
+    let b = as_number(&inputs[1], "second argument")?;
+    Ok(Value::number(f(a, b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(f: NativeFn, inputs: &[Value]) -> Result<Value> {
+        f(inputs, &mut EvieRuntime::new())
+    }
+
+    #[test]
+    fn math_natives() -> Result<()> {
+        let n = Value::number;
+        assert_eq!(n(2.0), call(floor, &[n(2.7)])?);
+        assert_eq!(n(-3.0), call(floor, &[n(-2.2)])?);
+        assert_eq!(n(3.0), call(ceil, &[n(2.1)])?);
+        assert_eq!(n(3.0), call(round, &[n(2.5)])?);
+        assert_eq!(n(2.5), call(abs, &[n(-2.5)])?);
+        assert_eq!(n(3.0), call(sqrt, &[n(9.0)])?);
+        assert_eq!(n(8.0), call(pow, &[n(2.0), n(3.0)])?);
+        assert_eq!(n(1.0), call(min, &[n(1.0), n(3.0)])?);
+        assert_eq!(n(3.0), call(max, &[n(1.0), n(3.0)])?);
+        assert!(call(sqrt, &[n(-1.0)]).is_err());
+        assert!(call(floor, &[Value::nil()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn num_parses_strings() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let input = runtime.alloc_string(" 42.5 ");
+        assert_eq!(Value::number(42.5), num(&[input], runtime)?);
+        let input = runtime.alloc_string("forty two");
+        assert_eq!(Value::nil(), num(&[input], runtime)?);
+        assert!(num(&[Value::number(1.0)], runtime).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn format_numbers() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let n = Value::number;
+        assert_eq!(
+            "0.67",
+            format(&[n(2.0 / 3.0), n(2.0)], runtime)?.to_string()
+        );
+        assert_eq!(
+            "0.30000000000000004",
+            format(&[n(0.1 + 0.2), n(17.0)], runtime)?.to_string()
+        );
+        assert_eq!(
+            "1234568",
+            format(&[n(1234567.5), n(0.0)], runtime)?.to_string()
+        );
+        assert!(format(&[n(1.0), n(-1.0)], runtime).is_err());
+        assert_eq!(102, format(&[n(1.0), n(100.0)], runtime)?.to_string().len());
+        let error = format(&[n(1.0), n(65536.0)], runtime).expect_err("too precise");
+        assert_eq!(
+            "Expected a precision of at most 100, got '65536'",
+            error.to_string()
+        );
+        assert!(format(&[Value::nil(), n(1.0)], runtime).is_err());
+        Ok(())
+    }
+}
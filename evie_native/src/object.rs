@@ -0,0 +1,213 @@
+//! Natives to inspect and change the fields of instances: `fields(instance)`, `remove_field(instance, name)` and
+//! `inspect(value)`.
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use std::collections::HashSet;
+
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{
+    objects::{GCObjectOf, Instance, NativeFn, ObjectType},
+    runtime::EvieRuntime,
+};
+
+use crate::as_str;
+
+/// How many instances deep [inspect] renders, the fields of deeper instances are shown as `...`
+pub const MAX_INSPECT_DEPTH: usize = 256;
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![
+        ("fields", 1, fields),
+        ("remove_field", 2, remove_field),
+        ("inspect", 1, inspect),
+    ]
+}
+
+/// Returns the names of the fields of the instance, in the order they were first set, as a String separated by ", "
+/// (evie has no lists). Methods are not included.
+pub fn fields(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let instance = as_instance(&inputs[0])?;
+    let names: Vec<String> = instance
+        .fields
+        .keys()
+        .map(|name| name.to_string())
+        .collect();
+    let result = names.join(", ");
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn fields() -> {} ", result);
+    Ok(runtime.alloc_string(result))
+}
+
+/// Removes the field `name` from the instance, returns its value or nil if there was no such field
+pub fn remove_field(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let mut instance = as_instance(&inputs[0])?;
+    let name = as_str(&inputs[1])?;
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn remove_field({}) ", name);
+    // Field names are interned, the key is the same as the one the VM used to set the field
+    let name = runtime.allocator().alloc_interned_str(name);
+    Ok(instance
+        .fields
+        .remove(name, runtime.allocator())
+        .unwrap_or_else(Value::nil))
+}
+
+/// Renders the value for debugging, where `print` shows `<instance of Point>`: instances are shown with their fields
+/// (recursively, e.g. `Point { x: 1, next: nil }`), strings are quoted and closures show the name of their function.
+/// An instance that contains itself is shown as `<cycle Point>` the second time, an instance nested deeper than
+/// [MAX_INSPECT_DEPTH] as `Point { ... }`.
+pub fn inspect(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let result = inspect_value(&inputs[0], &mut HashSet::new());
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn inspect() -> {} ", result);
+    Ok(runtime.alloc_string(result))
+}
+
+/// `instances` are the instances being rendered, see [inspect]
+fn inspect_value(value: &Value, instances: &mut HashSet<usize>) -> String {
+    if !value.is_object() {
+        return value.to_string();
+    }
+    match value.as_object().object_type {
+        ObjectType::String(_) | ObjectType::Rope(_) => format!("{:?}", value.to_string()),
+        ObjectType::Closure(closure) => match closure.function.name {
+            Some(name) => format!("<closure {}>", *name),
+            None => "<closure script>".to_string(),
+        },
+        ObjectType::Instance(instance) => {
+            let class = &*instance.class.name;
+            let address = instance.as_ptr() as usize;
+            if instances.contains(&address) {
+                return format!("<cycle {}>", class);
+            }
+            if instance.fields.is_empty() {
+                return format!("{} {{}}", class);
+            }
+            if instances.len() == MAX_INSPECT_DEPTH {
+                return format!("{} {{ ... }}", class);
+            }
+            instances.insert(address);
+            let fields: Vec<String> = instance
+                .fields
+                .iter()
+                .map(|(name, field)| format!("{}: {}", *name, inspect_value(&field, instances)))
+                .collect();
+            instances.remove(&address);
+            format!("{} {{ {} }}", class, fields.join(", "))
+        }
+        _ => value.to_string(),
+    }
+}
+
+fn as_instance(value: &Value) -> Result<GCObjectOf<Instance>> {
+    if value.is_object() {
+        if let ObjectType::Instance(instance) = value.as_object().object_type {
+            return Ok(instance);
+        }
+    }
+    bail!(format!("Expected an instance, got '{}'", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use evie_memory::{
+        cache::Cache,
+        objects::{Class, Object},
+        shape::Fields,
+    };
+
+    use super::*;
+
+    #[test]
+    fn fields_and_remove_field() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let allocator = runtime.allocator();
+        let class = allocator.alloc(Class::new(
+            allocator.alloc_interned_str("Point"),
+            allocator.alloc(Cache::new()),
+            allocator.alloc(Cache::new()),
+        ));
+        let mut values = Fields::new(allocator);
+        values.insert(
+            allocator.alloc_interned_str("x"),
+            Value::number(1.0),
+            allocator,
+        );
+        values.insert(
+            allocator.alloc_interned_str("y"),
+            Value::number(2.0),
+            allocator,
+        );
+        let instance = allocator.alloc(Instance::new(class, values));
+        let instance = Value::object(Object::new_gc_object(
+            ObjectType::Instance(instance),
+            allocator,
+        ));
+        assert_eq!("x, y", fields(&[instance], runtime)?.to_string());
+        let x = runtime.alloc_string("x");
+        assert_eq!(1.0, remove_field(&[instance, x], runtime)?.as_number());
+        assert!(remove_field(&[instance, x], runtime)?.is_nil());
+        assert_eq!("y", fields(&[instance], runtime)?.to_string());
+        assert!(fields(&[Value::number(1.0)], runtime).is_err());
+        assert!(remove_field(&[instance, Value::nil()], runtime).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn inspect_values() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let allocator = runtime.allocator();
+        let class = allocator.alloc(Class::new(
+            allocator.alloc_interned_str("Node"),
+            allocator.alloc(Cache::new()),
+            allocator.alloc(Cache::new()),
+        ));
+        let mut node = allocator.alloc(Instance::new(class, Fields::new(allocator)));
+        let value = Value::object(Object::new_gc_object(ObjectType::Instance(node), allocator));
+        assert_eq!("Node {}", inspect(&[value], runtime)?.to_string());
+        let allocator = runtime.allocator();
+        let name = runtime.alloc_string("a \"node\"");
+        node.fields
+            .insert(allocator.alloc_interned_str("name"), name, allocator);
+        node.fields
+            .insert(allocator.alloc_interned_str("next"), value, allocator);
+        assert_eq!(
+            r#"Node { name: "a \"node\"", next: <cycle Node> }"#,
+            inspect(&[value], runtime)?.to_string()
+        );
+        assert_eq!("1.5", inspect(&[Value::number(1.5)], runtime)?.to_string());
+        assert_eq!("nil", inspect(&[Value::nil()], runtime)?.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn inspect_long_chains() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let allocator = runtime.allocator();
+        let class = allocator.alloc(Class::new(
+            allocator.alloc_interned_str("Node"),
+            allocator.alloc(Cache::new()),
+            allocator.alloc(Cache::new()),
+        ));
+        let next = allocator.alloc_interned_str("next");
+        let mut value = Value::nil();
+        for _ in 0..100_000 {
+            let mut fields = Fields::new(allocator);
+            fields.insert(next, value, allocator);
+            let node = allocator.alloc(Instance::new(class, fields));
+            value = Value::object(Object::new_gc_object(ObjectType::Instance(node), allocator));
+        }
+        let expected = format!(
+            "{}Node {{ ... }}{}",
+            "Node { next: ".repeat(MAX_INSPECT_DEPTH),
+            " }".repeat(MAX_INSPECT_DEPTH)
+        );
+        assert_eq!(expected, inspect(&[value], runtime)?.to_string());
+        Ok(())
+    }
+}
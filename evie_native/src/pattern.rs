@@ -0,0 +1,101 @@
+//! Pattern matching natives: `match(string, pattern)`, `replace(string, pattern, replacement)` and
+//! `find_all(string, pattern)`.
+//!
+//! Patterns are regular expressions in the syntax of [regex_lite] (e.g. `"[a-z]+"`, `"(\\d+)-(\\d+)"`), a small
+//! engine without Unicode classes. A replacement can refer to the groups of the match with `$1` or `${name}`.
+use evie_common::errors::*;
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{objects::NativeFn, runtime::EvieRuntime};
+use regex_lite::Regex;
+
+use crate::as_str;
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![
+        ("match", 2, match_pattern),
+        ("replace", 3, replace),
+        ("find_all", 2, find_all),
+    ]
+}
+
+/// Returns the first match of the pattern in the string, nil if there is none
+pub fn match_pattern(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let string = as_str(&inputs[0])?;
+    let pattern = as_pattern(&inputs[1])?;
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn match({}, {}) ", string, pattern);
+    match pattern.find(string) {
+        Some(found) => Ok(runtime.alloc_string(found.as_str())),
+        None => Ok(Value::nil()),
+    }
+}
+
+/// Replaces every match of the pattern in the string
+pub fn replace(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let string = as_str(&inputs[0])?;
+    let pattern = as_pattern(&inputs[1])?;
+    let replacement = as_str(&inputs[2])?;
+    let result = pattern.replace_all(string, replacement).into_owned();
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn replace() -> {} ", result);
+    Ok(runtime.alloc_string(result))
+}
+
+/// Returns the matches of the pattern in the string, as a String separated by ", " (evie has no lists)
+pub fn find_all(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let string = as_str(&inputs[0])?;
+    let pattern = as_pattern(&inputs[1])?;
+    let matches: Vec<&str> = pattern.find_iter(string).map(|m| m.as_str()).collect();
+    let result = matches.join(", ");
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn find_all() -> {} ", result);
+    Ok(runtime.alloc_string(result))
+}
+
+fn as_pattern(value: &Value) -> Result<Regex> {
+    let pattern = as_str(value)?;
+    Regex::new(pattern)
+        .map_err(|e| ErrorKind::Msg(format!("Invalid pattern '{}': {}", pattern, e)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_natives() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let text = runtime.alloc_string("born 1815-12-10, died 1852-11-27");
+        let date = runtime.alloc_string(r"(\d+)-(\d+)-(\d+)");
+        assert_eq!(
+            "1815-12-10",
+            match_pattern(&[text, date], runtime)?.to_string()
+        );
+        let year = runtime.alloc_string(r"\d{4}");
+        assert_eq!("1815, 1852", find_all(&[text, year], runtime)?.to_string());
+        let replacement = runtime.alloc_string("$3/$2/$1");
+        assert_eq!(
+            "born 10/12/1815, died 27/11/1852",
+            replace(&[text, date, replacement], runtime)?.to_string()
+        );
+
+        let missing = runtime.alloc_string("[A-Z]");
+        assert_eq!(Value::nil(), match_pattern(&[text, missing], runtime)?);
+        assert_eq!("", find_all(&[text, missing], runtime)?.to_string());
+        let invalid = runtime.alloc_string("(");
+        let error = match_pattern(&[text, invalid], runtime).unwrap_err();
+        assert!(
+            error.to_string().starts_with("Invalid pattern '('"),
+            "{}",
+            error
+        );
+        assert!(match_pattern(&[text, Value::nil()], runtime).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Natives that interact with the host environment: `env(name)`, `exit(code)` and `exec(cmd)`.
+//!
+//! They give scripts full access to the host, so they are only compiled with the opt-in `unsafe_natives` feature.
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{objects::NativeFn, replay::Source, runtime::EvieRuntime};
+use std::process::Command;
+
+use crate::{as_number, as_str};
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![("env", 1, env), ("exit", 1, exit), ("exec", 1, exec)]
+}
+
+/// The value of the environment variable `name`, nil if it is not set
+pub fn env(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let name = as_str(&inputs[0])?;
+    let result = match runtime.text_input(Source::Env, |_| Ok(std::env::var(name).ok()))? {
+        Some(v) => runtime.alloc_string(v),
+        None => Value::nil(),
+    };
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn env() -> {} ", result);
+    Ok(result)
+}
+
+/// Exits the process with the given code
+pub fn exit(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    let code = as_number(&inputs[0], "code")?;
+    std::process::exit(code as i32)
+}
+
+/// Runs the command with the system shell and returns its stdout as a string.
+/// Fails if the command cannot be started or exits unsuccessfully.
+pub fn exec(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let command = as_str(&inputs[0])?;
+    let output = shell(command)
+        .output()
+        .chain_err(|| format!("Unable to run '{}'", command))?;
+    if !output.status.success() {
+        bail!(format!("'{}' failed with {}", command, output.status))
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn exec() -> {} ", stdout);
+    Ok(runtime.alloc_string(stdout))
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evie_memory::objects::ObjectType;
+
+    fn string(value: Value) -> String {
+        match value.as_object().object_type {
+            ObjectType::String(s) => s.to_string(),
+            _ => panic!("Expected a string"),
+        }
+    }
+
+    #[test]
+    fn env_and_exec() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        std::env::set_var("EVIE_PROCESS_TEST", "evie");
+        let name = runtime.alloc_string("EVIE_PROCESS_TEST");
+        assert_eq!("evie", string(env(&[name], runtime)?));
+        let name = runtime.alloc_string("EVIE_PROCESS_TEST_UNSET");
+        assert_eq!(Value::nil(), env(&[name], runtime)?);
+
+        let command = runtime.alloc_string("echo hello");
+        assert_eq!("hello\n", string(exec(&[command], runtime)?));
+        let command = runtime.alloc_string("exit 3");
+        assert!(exec(&[command], runtime).is_err());
+        Ok(())
+    }
+}
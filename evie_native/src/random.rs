@@ -0,0 +1,75 @@
+//! Random number natives, backed by the [evie_memory::runtime::Random] generator of the calling VM.
+//!
+//! Every VM has its own generator, so seeding one VM (`random_seed(n)`) never affects another.
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{objects::NativeFn, replay::Source, runtime::EvieRuntime};
+
+use crate::as_number;
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![
+        ("random", 0, random),
+        ("random_range", 2, random_range),
+        ("random_seed", 1, random_seed),
+    ]
+}
+
+/// A random number in the range [0, 1)
+pub fn random(_: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let result = runtime.number_input(Source::Random, |runtime| runtime.random().next_f64())?;
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn random() -> {} ", result);
+    Ok(Value::number(result))
+}
+
+/// A random number in the range [lo, hi)
+pub fn random_range(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let lo = as_number(&inputs[0], "lo")?;
+    let hi = as_number(&inputs[1], "hi")?;
+    if lo > hi {
+        bail!(format!(
+            "random_range lo ({}) must not be greater than hi ({})",
+            lo, hi
+        ))
+    }
+    let result = lo
+        + runtime.number_input(Source::Random, |runtime| runtime.random().next_f64())? * (hi - lo);
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn random_range() -> {} ", result);
+    Ok(Value::number(result))
+}
+
+/// Seeds the generator, after which the sequence of random numbers is reproducible
+pub fn random_seed(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let seed = as_number(&inputs[0], "seed")?;
+    runtime.random().seed(seed.to_bits());
+    Ok(Value::nil())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_random_is_reproducible() -> Result<()> {
+        let mut first = EvieRuntime::new();
+        let mut second = EvieRuntime::new();
+        random_seed(&[Value::number(42.0)], &mut first)?;
+        random_seed(&[Value::number(42.0)], &mut second)?;
+        for _ in 0..10 {
+            let range = &[Value::number(5.0), Value::number(10.0)];
+            let n = random_range(range, &mut first)?;
+            assert_eq!(n, random_range(range, &mut second)?);
+            assert!((5.0..10.0).contains(&n.as_number()));
+        }
+        assert!(random_range(&[Value::number(2.0), Value::number(1.0)], &mut first).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+//! Built-in methods for String values, e.g. `"hello".length()`.
+//!
+//! These are natives whose first argument is the receiver (the string itself).
+//! Lengths and indices are in characters (Unicode code points), not bytes.
+//! The VM resolves them in `Opcode::Invoke` when the receiver is a String.
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{objects::NativeFn, runtime::EvieRuntime};
+
+use crate::{as_index, as_str};
+
+/// The methods available on String values as (name, arity (excluding the receiver), function)
+pub fn methods() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![
+        ("length", 0, length),
+        ("substring", 2, substring),
+        ("char_at", 1, char_at),
+    ]
+}
+
+/// Returns the number of characters in the receiver
+pub fn length(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    let string = as_str(&inputs[0])?;
+    let length = string.chars().count();
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn length() -> {} ", length);
+    Ok(Value::number(length as f64))
+}
+
+/// Returns the characters of the receiver in the range [start, end).
+/// The range is clamped to the length of the string. The long substrings of the strings built at runtime share their
+/// text instead of copying it (see [evie_memory::ObjectAllocator::alloc_substring]).
+pub fn substring(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let string = as_str(&inputs[0])?;
+    let start = as_index(&inputs[1], "start")?;
+    let end = as_index(&inputs[2], "end")?;
+    if start > end {
+        bail!(format!(
+            "substring start ({}) must not be greater than end ({})",
+            start, end
+        ))
+    }
+    let (start, end) = (byte_offset(string, start), byte_offset(string, end));
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn substring() -> {} ", &string[start..end]);
+    Ok(runtime
+        .alloc_substring(inputs[0], start..end)
+        .expect("Expect a substring"))
+}
+
+/// The offset in bytes of the character at `index`, the length of the string if it is out of range
+fn byte_offset(string: &str, index: usize) -> usize {
+    string
+        .char_indices()
+        .nth(index)
+        .map_or(string.len(), |(offset, _)| offset)
+}
+
+/// Returns the character at `index` in the receiver as a String, nil if `index` is out of range
+pub fn char_at(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let string = as_str(&inputs[0])?;
+    let index = as_index(&inputs[1], "index")?;
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn char_at({}) ", index);
+    match string.chars().nth(index) {
+        Some(c) => Ok(runtime.alloc_string(c.to_string())),
+        None => Ok(Value::nil()),
+    }
+}
@@ -0,0 +1,86 @@
+//! Time natives beyond [crate::clock].
+//!
+//! `instant()` and `elapsed(since)` use a monotonic clock (the start of the calling VM's runtime is the origin),
+//! so they are safe for benchmarking inside scripts:
+//! ```text
+//! var start = instant();
+//! work();
+//! print elapsed(start); // milliseconds
+//! ```
+#[cfg(feature = "trace_enabled")]
+use evie_common::trace;
+use evie_common::{bail, errors::*};
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+use evie_memory::{clock, objects::NativeFn, replay::Source, runtime::EvieRuntime};
+use std::time::Duration;
+
+use crate::as_number;
+
+/// The natives defined in this module as (name, arity, function)
+pub fn natives() -> Vec<(&'static str, usize, NativeFn)> {
+    vec![
+        ("time_millis", 0, time_millis),
+        ("sleep", 1, sleep),
+        ("instant", 0, instant),
+        ("elapsed", 1, elapsed),
+    ]
+}
+
+/// Milliseconds since the unix epoch (wall clock)
+pub fn time_millis(_: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let millis =
+        runtime.number_input(Source::Clock, |_| clock::since_epoch().as_millis() as f64)?;
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn time_millis() -> {} ", millis);
+    Ok(Value::number(millis))
+}
+
+/// Blocks the VM for the given milliseconds
+pub fn sleep(inputs: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+    let millis = as_number(&inputs[0], "milliseconds")?;
+    if !(millis >= 0.0 && millis.is_finite()) {
+        bail!(format!("Cannot sleep for {} milliseconds", millis))
+    }
+    clock::sleep(Duration::from_secs_f64(millis / 1000.0));
+    Ok(Value::nil())
+}
+
+/// Monotonic milliseconds since the runtime started, pass it to [elapsed] later
+pub fn instant(_: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    Ok(Value::number(millis_since_start(runtime)?))
+}
+
+/// Monotonic milliseconds elapsed since the given [instant]
+pub fn elapsed(inputs: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+    let since = as_number(&inputs[0], "instant")?;
+    let result = millis_since_start(runtime)? - since;
+    #[cfg(feature = "trace_enabled")]
+    trace!("native fn elapsed() -> {} ", result);
+    Ok(Value::number(result))
+}
+
+fn millis_since_start(runtime: &mut EvieRuntime) -> Result<f64> {
+    runtime.number_input(Source::Clock, |runtime| {
+        runtime.started().elapsed().as_secs_f64() * 1000.0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elapsed_is_monotonic() -> Result<()> {
+        let runtime = &mut EvieRuntime::new();
+        let start = instant(&[], runtime)?;
+        sleep(&[Value::number(5.0)], runtime)?;
+        let elapsed = elapsed(&[start], runtime)?.as_number();
+        assert!(elapsed >= 5.0, "elapsed {}", elapsed);
+        assert!(time_millis(&[], runtime)?.as_number() > 0.0);
+        assert!(sleep(&[Value::number(-1.0)], runtime).is_err());
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 //! THe virtual machine crate.
 //! Implements the logic for all the instructions defined in [evie_instructions::opcodes]
-mod runtime_memory;
+//!
+//! With the `tracing` feature the VM emits spans for `interpret` (and host `call`s), `compile`, `run` and `gc`,
+//! and counters of the allocations by type, for a `tracing` subscriber (e.g. a flamegraph layer).
+pub mod stack;
 pub mod vm;
 
 #[cfg(test)]
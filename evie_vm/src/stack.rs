@@ -0,0 +1,180 @@
+//! The value stack of the VM.
+//!
+//! Every access goes through [Stack], which checks the bounds unless the `unchecked_stack` feature is enabled
+//! in a release build (debug builds are always checked). Indices come from the compiler (locals, arguments),
+//! an out of bounds access is a VM bug, not a user error. Pushing is always checked, a stack overflow can be
+//! caused by the program (e.g. deep recursion).
+use std::ops::Range;
+
+#[cfg(feature = "nan_boxed")]
+use evie_memory::objects::nan_boxed::Value;
+#[cfg(not(feature = "nan_boxed"))]
+use evie_memory::objects::non_nan_boxed::Value;
+
+/// The number of values the stack can hold
+pub const STACK_SIZE: usize = 1024;
+
+/// The access mode the stack was built with, "checked" or "unchecked"
+pub const MODE: &str = if UNCHECKED { "unchecked" } else { "checked" };
+
+const UNCHECKED: bool = cfg!(all(feature = "unchecked_stack", not(debug_assertions)));
+
+/// A fixed size stack of values
+pub struct Stack {
+    values: [Value; STACK_SIZE],
+    /// The index of the next free slot
+    top: usize,
+}
+
+impl Stack {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Stack {
+            values: [Value::default(); STACK_SIZE],
+            top: 0,
+        }
+    }
+
+    /// The number of values on the stack (the index of the top)
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.top
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.top == 0
+    }
+
+    #[inline(always)]
+    pub fn is_full(&self) -> bool {
+        self.top == STACK_SIZE
+    }
+
+    /// Drops the values above `top`
+    #[inline(always)]
+    pub fn truncate(&mut self, top: usize) {
+        assert!(
+            top <= self.top,
+            "VM BUG: truncating the stack from {} to {}",
+            self.top,
+            top
+        );
+        self.top = top;
+    }
+
+    /// Pushes the value, panics on stack overflow (callers check [Stack::is_full] to report it)
+    #[inline(always)]
+    pub fn push(&mut self, value: Value) {
+        assert!(
+            !self.is_full(),
+            "Stack overflow, stack size = {}",
+            STACK_SIZE
+        );
+        self.values[self.top] = value;
+        self.top += 1;
+    }
+
+    #[inline(always)]
+    pub fn pop(&mut self) -> Value {
+        self.check(self.top.wrapping_sub(1));
+        self.top -= 1;
+        // SAFETY: `top` was at least 1 (checked, or guaranteed by the compiler in unchecked mode) and at most STACK_SIZE
+        unsafe { *self.values.get_unchecked(self.top) }
+    }
+
+    /// The value `distance` slots below the top, 0 is the top
+    #[inline(always)]
+    pub fn peek(&self, distance: usize) -> Value {
+        self.get(self.top.wrapping_sub(1 + distance))
+    }
+
+    #[inline(always)]
+    pub fn get(&self, index: usize) -> Value {
+        self.check(index);
+        // SAFETY: the index is below the top (checked, or guaranteed by the compiler in unchecked mode)
+        unsafe { *self.values.get_unchecked(index) }
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, index: usize, value: Value) {
+        self.check(index);
+        // SAFETY: the index is below the top (checked, or guaranteed by the compiler in unchecked mode)
+        unsafe { *self.values.get_unchecked_mut(index) = value }
+    }
+
+    /// The values in the range, which must be on the stack
+    pub fn slice(&self, range: Range<usize>) -> &[Value] {
+        assert!(
+            range.end <= self.top,
+            "VM BUG: Access out of bounds, stack top = {}, range = {:?}",
+            self.top,
+            range
+        );
+        &self.values[range]
+    }
+
+    /// The values on the stack, from the bottom
+    pub fn values(&self) -> &[Value] {
+        &self.values[..self.top]
+    }
+
+    #[inline(always)]
+    fn check(&self, index: usize) {
+        if !UNCHECKED {
+            assert!(
+                index < self.top,
+                "VM BUG: Access out of bounds, stack top = {}, index = {}",
+                self.top,
+                index
+            );
+        }
+    }
+}
+
+impl std::fmt::Debug for Stack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.values()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop_peek() {
+        let mut stack = Stack::new();
+        assert!(stack.is_empty());
+        stack.push(Value::number(1.0));
+        stack.push(Value::number(2.0));
+        assert_eq!(2, stack.len());
+        assert_eq!(2.0, stack.peek(0).as_number());
+        assert_eq!(1.0, stack.peek(1).as_number());
+        stack.set(0, Value::number(3.0));
+        assert_eq!(3.0, stack.get(0).as_number());
+        assert_eq!(2, stack.slice(0..2).len());
+        assert_eq!(2.0, stack.pop().as_number());
+        stack.truncate(0);
+        assert!(stack.values().is_empty());
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "unchecked_stack", not(debug_assertions))))]
+    #[should_panic(expected = "Access out of bounds")]
+    fn checked_access() {
+        // Values above the top are stale, reading them is a VM bug
+        let mut stack = Stack::new();
+        stack.push(Value::nil());
+        stack.get(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stack overflow")]
+    fn overflow() {
+        let mut stack = Stack::new();
+        for _ in 0..=STACK_SIZE {
+            stack.push(Value::nil());
+        }
+    }
+}
@@ -1,29 +1,35 @@
 use std::io::{stdout, Write};
-use std::mem::{self, MaybeUninit};
 use std::ops::Range;
 use std::panic;
-use std::ptr::NonNull;
-use std::time::{Instant};
 use evie_common::{errors::*, info, ByteUnit, bail,  utf8_to_string, error, trace};
 #[cfg(feature="trace_enabled")]
 use evie_common::{log_enabled, Level};
-use evie_common::Writer;
-use evie_compiler::compiler::Compiler;
+use evie_common::{Reader, Writer};
+use evie_compiler::artifact::Artifact;
+use evie_compiler::cache::{CompilationCache, CompileOptions};
+use evie_compiler::compiler::{Compilation, Compiler};
+use evie_compiler::pgo::{FunctionProfile, Profile};
 use evie_frontend::scanner::Scanner;
 use evie_instructions::opcodes::{self, Opcode};
-use evie_memory::{ObjectAllocator};
-use evie_memory::chunk::Chunk;
+use evie_instructions::verifier::verify;
+use evie_memory::clock::Instant;
+use evie_memory::runtime::EvieRuntime;
+use evie_memory::convert::{FromValue, IntoValue};
+use evie_memory::gc::{GcStats, Trace, Tracer, TypeStats};
+use evie_memory::handle::{Handle, Pinnable};
+use evie_memory::chunk::{Chunk, SourceSpan, NO_FIELD};
 use evie_memory::objects::{Closure, Location, NativeFunction, NativeFn, Class, Instance, UserDefinedFunction, BoundMethod, Object};
-use evie_memory::objects::{ObjectType, GCObjectOf, Upvalue};
+use evie_memory::objects::{ObjectType, GCObjectOf, Upvalue, Rope, Coroutine, CoroutineState, SuspendedFrame};
 #[cfg(feature = "nan_boxed")]
 use evie_memory::objects::nan_boxed::Value;
 #[cfg(not(feature = "nan_boxed"))]
 use evie_memory::objects::non_nan_boxed::Value;
 use evie_memory::cache::Cache;
-use crate::runtime_memory::Values;
+use evie_memory::shape::Fields;
+use evie_memory::ObjectAllocator;
+use rustc_hash::FxHashMap;
 
-
-const STACK_SIZE: usize = 1024;
+use crate::stack::{Stack, STACK_SIZE};
 
 #[derive(Debug)]
 struct CallFrame {
@@ -41,87 +47,194 @@ impl CallFrame {
         }
     }
 
-    fn ip_ptr(&self) -> *mut usize {
-        &self.ip as *const usize as *mut usize
-    }
-
-    fn non_null_ptr(&self) -> NonNull<usize> {
-        NonNull::new(self.ip_ptr()).unwrap()
-    }
+}
 
+/// A [Coroutine] that is running: its call frames and its values are on top of the ones of the code that resumed it
+struct RunningCoroutine {
+    coroutine: GCObjectOf<Coroutine>,
+    /// The index of its first call frame
+    frame: usize,
+    /// The stack slot of its function, the result of the resume when it yields or returns
+    base: usize,
 }
 
 /// Defines the given [evie_memory::objects::NativeFn] in the given [VirtualMachine]
 pub fn define_native_fn(name: &str, arity: usize, vm: &mut VirtualMachine, native_fn: NativeFn) {
-    let box_str =name.to_string().into_boxed_str();
-    let name = vm.allocator.alloc_interned_str(box_str);
-    let native_function = vm.allocator.alloc(NativeFunction::new(name, arity, native_fn));
-    vm.runtime_values.insert(name, Value::object(Object::new_gc_object(ObjectType::NativeFunction(native_function), &vm.allocator)));
+    let (name, value) = alloc_native_fn(name, arity, native_fn, vm.runtime.allocator());
+    vm.runtime.globals().insert(name, value);
+}
+
+fn alloc_native_fn(name: &str, arity: usize, native_fn: NativeFn, allocator: &ObjectAllocator) -> (GCObjectOf<Box<str>>, Value) {
+    let name = allocator.alloc_interned_str(name);
+    let native_function = allocator.alloc(NativeFunction::new(name, arity, native_fn));
+    (name, Value::object(Object::new_gc_object(ObjectType::NativeFunction(native_function), allocator)))
+}
+
+/// Natives that are only defined while one script runs, see [Args::with_natives].
+/// Unlike [define_native_fn] they do not change the VM for the scripts that run after it:
+/// a native shadows the global of the same name (e.g. one defined by [define_native_fn]) and the global is restored when the run ends.
+#[derive(Default)]
+pub struct NativeRegistry {
+    natives: Vec<(String, usize, NativeFn)>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> Self {
+        NativeRegistry::default()
+    }
+
+    /// Adds the native, replacing the one with the same name if any
+    pub fn define(&mut self, name: &str, arity: usize, native_fn: NativeFn) -> &mut Self {
+        self.natives.retain(|(n, _, _)| n != name);
+        self.natives.push((name.to_string(), arity, native_fn));
+        self
+    }
 }
 
-/// Optional args for the [VirtualMachine]. 
-/// Currently unused
+/// What the last [VirtualMachine::interpret] (or [VirtualMachine::call]) did, see [VirtualMachine::last_run_stats].
+/// Unlike its duration these are the same on every run of the same script, which makes them a stable metric
+/// for benchmarks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RunStats {
+    /// The number of instructions executed (a superinstruction counts as one)
+    pub instructions: u64,
+    /// The number of objects allocated, by the compiler and at runtime
+    pub allocations: u64,
+    /// The number of bytes allocated, including the ones freed since
+    pub bytes_allocated: u64,
+}
+
+/// Optional args for a [VirtualMachine::interpret] run
 #[derive(Default)]
 pub struct Args {
     _timing_per_instruction: bool,
+    natives: NativeRegistry,
+    source_name: Option<String>,
+    strict: bool,
+    max_heap_bytes: Option<usize>,
+}
+
+impl Args {
+    /// Defines the natives only for the run these [Args] are passed to
+    pub fn with_natives(mut self, natives: NativeRegistry) -> Self {
+        self.natives = natives;
+        self
+    }
+
+    /// The name of the source (e.g. the path of the script), the stack traces of runtime errors show it
+    pub fn with_source_name(mut self, source_name: &str) -> Self {
+        self.source_name = Some(source_name.to_string());
+        self
+    }
+
+    /// In strict mode a global that is neither declared by the script nor defined in the VM (e.g. a native or a global
+    /// of a previous run) fails the compilation, see [Compiler::set_strict], and dividing by zero is a runtime error
+    /// (instead of infinity or NaN)
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
 
+    /// Caps the heap only for the run these [Args] are passed to, see [VirtualMachine::set_max_heap_bytes]. The cap of
+    /// the VM is back once the run ends
+    pub fn with_max_heap_bytes(mut self, max_heap_bytes: usize) -> Self {
+        self.max_heap_bytes = Some(max_heap_bytes);
+        self
+    }
 }
 
 /// The Virtual machine.
 pub struct VirtualMachine<'a> {
-    /// The call stack
-    stack: [Value; STACK_SIZE],
-    /// Pointer to the top of the stack
-    stack_top: usize,
+    /// The value stack
+    stack: Stack,
     /// Call frames (stores functions)
     call_frames: Vec<CallFrame>,
-    /// Global variables
-    runtime_values: Values,
-    /// Up values used for [evie_memory::objects::Closure]
+    /// The open up values (still on the stack) used for [evie_memory::objects::Closure], sorted by stack slot
     up_values: Vec<GCObjectOf<Upvalue>>,
     /// Custom [evie_common::Writer] for non stdout output
     custom_writer: Option<Writer<'a>>,
-    /// The `Object` allocator
-    allocator: ObjectAllocator,
-    /// unused for now
+    /// The runtime (allocator, interned strings & global variables) owned by this VM
+    runtime: EvieRuntime,
+    /// The [Args] of the current run
     optional_args: Option<Args>,
-    /// Instruction pointer
-    ip: NonNull<usize>
+    /// The globals shadowed by the natives of the current run (see [NativeRegistry]), with their values if they were defined
+    shadowed_globals: Vec<(GCObjectOf<Box<str>>, Option<Value>)>,
+    /// The heap cap of the VM while the current run has its own (see [Args::with_max_heap_bytes])
+    shadowed_max_heap_bytes: Option<Option<usize>>,
+    /// Instruction pointer of the current (last) call frame, the other frames keep theirs in [CallFrame::ip]
+    ip: usize,
+    /// Built-in methods on String values (see [evie_native::string])
+    string_methods: Cache<GCObjectOf<NativeFunction>>,
+    /// The compiler warnings of the last [VirtualMachine::interpret]
+    warnings: Vec<ErrorKind>,
+    /// Compile with superinstructions (see [Compiler::set_superinstructions])
+    superinstructions: bool,
+    /// Compile with dead function elimination (see [Compiler::set_eliminate_dead_functions])
+    eliminate_dead_functions: bool,
+    /// The number of instructions executed so far
+    instructions: u64,
+    /// See [VirtualMachine::last_run_stats]
+    last_run_stats: RunStats,
+    /// See [VirtualMachine::last_error_span]
+    last_error_span: Option<SourceSpan>,
+    /// Counts the calls and the constant reads of the run, see [VirtualMachine::set_profiling]
+    profiler: Option<Profiler>,
+    /// See [VirtualMachine::last_profile]
+    last_profile: Option<Profile>,
+    /// See [VirtualMachine::set_profile]
+    profile: Option<Profile>,
+    /// See [VirtualMachine::set_max_instructions]
+    max_instructions: Option<u64>,
+    /// The run fails once the instructions executed so far are over it
+    instruction_limit: u64,
+    /// See [VirtualMachine::set_snapshots]
+    snapshots: bool,
+    /// See [VirtualMachine::set_compilation_cache]
+    compilation_cache: Option<CompilationCache>,
+    /// The coroutines resumed and not yielded yet, the innermost last
+    coroutines: Vec<RunningCoroutine>,
+}
+
+/// The counts of the functions called in a profiled run, by the address of the function
+#[derive(Debug, Default)]
+struct Profiler {
+    counts: FxHashMap<usize, FunctionProfile>,
+}
+
+impl Profiler {
+    /// The counts of the function, created on its first call or read
+    fn counts(&mut self, function: GCObjectOf<UserDefinedFunction>) -> &mut FunctionProfile {
+        self.counts.entry(function.as_ptr() as usize).or_insert_with(|| FunctionProfile {
+            name: function.name.map(|name| name.to_string()),
+            calls: 0,
+            constant_reads: vec![0; function.chunk.constants.inner.len()],
+        })
+    }
+
+    /// The [Profile] of the functions of the artifact, those that were not called have no counts
+    fn profile(&self, artifact: &Artifact) -> Profile {
+        let functions = artifact.functions().iter().map(|function| {
+            let counts = self.counts.get(&(function.function().as_ptr() as usize)).cloned();
+            counts.unwrap_or_else(|| FunctionProfile {
+                name: function.name().map(str::to_string),
+                calls: 0,
+                constant_reads: vec![0; function.constants().len()],
+            })
+        });
+        Profile { functions: functions.collect() }
+    }
 }
 
+
 impl<'a> std::fmt::Debug for VirtualMachine<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("VirtualMachine")
             .field("stack", &self.stack)
-            .field("runtime_values", &self.runtime_values)
+            .field("runtime", &self.runtime)
             .finish()
     }
 }
 
-fn init_stack() -> [Value; STACK_SIZE] {
-    let data = {
-        // Create an uninitialized array of `MaybeUninit`. The `assume_init` is
-        // safe because the type we are claiming to have initialized here is a
-        // bunch of `MaybeUninit`s, which do not require initialization.
-        let mut data: [MaybeUninit<Value>; STACK_SIZE] =
-            unsafe { MaybeUninit::uninit().assume_init() };
-
-        // Dropping a `MaybeUninit` does nothing. Thus using raw pointer
-        // assignment instead of `ptr::write` does not cause the old
-        // uninitialized value to be dropped. Also if there is a panic during
-        // this loop, we have a memory leak, but there is no memory safety
-        // issue.
-        for elem in &mut data[..] {
-            elem.write(Value::default());
-        }
-
-        // Everything is initialized. Transmute the array to the
-        // initialized type.
-        unsafe { mem::transmute::<_, [Value; STACK_SIZE]>(data) }
-    };
-    data
-}
-
 impl<'a> VirtualMachine<'a> {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
@@ -129,70 +242,317 @@ impl<'a> VirtualMachine<'a> {
     }
 
     pub fn new_with_writer(custom_writer: Option<Writer<'a>>) -> Self {
+        VirtualMachine::new_with_reader_and_writer(None, custom_writer)
+    }
+
+    /// A VM that reads input (e.g. for `read_line()`) from `custom_reader` instead of stdin
+    pub fn new_with_reader_and_writer(custom_reader: Option<Reader>, custom_writer: Option<Writer<'a>>) -> Self {
+        let mut runtime = EvieRuntime::new();
+        if let Some(reader) = custom_reader {
+            runtime.set_input(reader);
+        }
+        let mut string_methods = Cache::new();
+        for (name, arity, native_fn) in evie_native::string::methods() {
+            let name = runtime.allocator().alloc_interned_str(name);
+            string_methods.insert(name, runtime.allocator().alloc(NativeFunction::new(name, arity, native_fn)));
+        }
         VirtualMachine {
-            stack: init_stack(),
-            stack_top: 0,
+            stack: Stack::new(),
             call_frames: Vec::new(),
-            runtime_values: Values::new(),
             up_values: Vec::new(),
             custom_writer,
-            allocator: ObjectAllocator::new(),
+            runtime,
             optional_args: None,
-            ip: NonNull::new(&mut 0usize as *mut usize).expect("Null pointer"),
+            shadowed_globals: Vec::new(),
+            shadowed_max_heap_bytes: None,
+            ip: 0,
+            string_methods,
+            warnings: Vec::new(),
+            superinstructions: true,
+            eliminate_dead_functions: false,
+            instructions: 0,
+            last_run_stats: RunStats::default(),
+            last_error_span: None,
+            profiler: None,
+            last_profile: None,
+            profile: None,
+            max_instructions: None,
+            instruction_limit: u64::MAX,
+            snapshots: false,
+            compilation_cache: None,
+            coroutines: Vec::new(),
+        }
+    }
+
+    /// The compiler warnings ([ErrorKind::Warning]) of the last [VirtualMachine::interpret]
+    pub fn warnings(&self) -> &[ErrorKind] {
+        &self.warnings
+    }
+
+    /// The [EvieRuntime] of this VM, e.g. to read or define globals from the host, or to access the objects of the
+    /// [Handle]s (see [VirtualMachine::pin_global])
+    pub fn runtime(&mut self) -> &mut EvieRuntime {
+        &mut self.runtime
+    }
+
+    /// Defines (or redefines) the global variable `name`, converting the value (see [evie_memory::convert])
+    pub fn set_global<T: IntoValue>(&mut self, name: &str, value: T) {
+        let value = value.into_value(&self.runtime);
+        self.runtime.define_global(name, value);
+    }
+
+    /// Returns the value of the global variable `name` converted to `T`,
+    /// fails if it is not defined or can not be converted
+    pub fn get_global<T: FromValue>(&mut self, name: &str) -> Result<T> {
+        match self.runtime.global(name) {
+            Some(value) => T::from_value(value),
+            None => bail!(format!("Undefined variable '{}'", name)),
+        }
+    }
+
+    /// A [Handle] to the object of the global variable `name` (e.g. an [Instance]), it stays alive (even if the global
+    /// is redefined) until the handle is released with [EvieRuntime::release]. Fails if it is not defined or not a `T`
+    pub fn pin_global<T: Pinnable>(&mut self, name: &str) -> Result<Handle<T>> {
+        let value: Value = self.get_global(name)?;
+        match self.runtime.pin(value) {
+            Some(handle) => Ok(handle),
+            None => bail!(format!("Expected '{}' to be {}, got '{}'", name, T::KIND, value)),
+        }
+    }
+
+    /// Calls the global function `name` (defined by a previously interpreted script) with the arguments,
+    /// returns its result converted to `T`, see [VirtualMachine::call]
+    pub fn call_function<T: FromValue>(&mut self, name: &str, args: &[Value]) -> Result<T> {
+        T::from_value(self.call(name, args)?)
+    }
+
+    /// Calls the global `name` with the arguments and runs until it returns, e.g. for callbacks driven by the host.
+    /// Anything callable from evie can be called: functions, native functions, classes (constructors) and bound methods.
+    /// The globals persist between calls, the stack does not.
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Value> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("call", function = name, instructions = tracing::field::Empty,
+            allocations = tracing::field::Empty, bytes_allocated = tracing::field::Empty).entered();
+        let result = self.measured(|vm| vm.call_global(name, args));
+        self.end_run(result.is_err());
+        result
+    }
+
+    fn call_global(&mut self, name: &str, args: &[Value]) -> Result<Value> {
+        let callee: Value = self.get_global(name)?;
+        self.reset_vm();
+        // The callee and its arguments are pushed by the host, not by checked byte code
+        if self.stack.len() + args.len() + 1 > STACK_SIZE {
+            bail!(self.runtime_error(&format!("Stack overflow, stack size = {}", STACK_SIZE)))
         }
+        self.push_to_stack(callee);
+        args.iter().for_each(|arg| self.push_to_stack(*arg));
+        self.call_value(args.len(), callee)?;
+        // Native functions and classes without an initializer have already returned
+        if !self.call_frames.is_empty() {
+            self.run()?;
+        }
+        Ok(self.pop_from_stack())
     }
 
     /// Interprets the given source code.
+    /// Only the globals outlive the call: the stack, call frames and open upvalues are cleared even if it fails,
+    /// so that a session (e.g. the REPL) can go on after an error and what is not reachable from a global can be collected.
     pub fn interpret(&mut self, source: String, optional_args: Option<Args>) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("interpret", instructions = tracing::field::Empty,
+            allocations = tracing::field::Empty, bytes_allocated = tracing::field::Empty).entered();
+        let result = self.measured(|vm| vm.compile_and_run(source, optional_args));
+        self.end_run(result.is_err());
+        result
+    }
+
+    /// Where the instruction that failed the last [VirtualMachine::interpret] (or [VirtualMachine::call]) is in the source,
+    /// e.g. to highlight the expression. None if it succeeded or did not fail at runtime.
+    pub fn last_error_span(&self) -> Option<SourceSpan> {
+        self.last_error_span
+    }
+
+    fn end_run(&mut self, failed: bool) {
+        self.last_error_span = match self.call_frames.last() {
+            // The instruction that failed has been read (at least its opcode)
+            Some(frame) if failed => Some(frame.closure.function.chunk.source_map.span_at(self.ip.saturating_sub(1))),
+            _ => None,
+        };
+        self.restore_shadowed_globals();
+        if let Some(max) = self.shadowed_max_heap_bytes.take() {
+            self.set_max_heap_bytes(max);
+        }
+        self.reset_vm();
+    }
+
+    /// The [RunStats] of the last [VirtualMachine::interpret] or [VirtualMachine::call], whether it succeeded or not
+    pub fn last_run_stats(&self) -> RunStats {
+        self.last_run_stats
+    }
+
+    /// Runs `f`, keeping what it did as the [RunStats] of the last run
+    fn measured<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, f: F) -> Result<T> {
+        let instructions = self.instructions;
+        self.instruction_limit = self.max_instructions.map_or(u64::MAX, |max| instructions.saturating_add(max));
+        let allocations = self.runtime.allocator().allocation_count();
+        let bytes_allocated = self.runtime.allocator().total_bytes_allocated();
+        let result = f(self);
+        self.last_run_stats = RunStats {
+            instructions: self.instructions - instructions,
+            allocations: self.runtime.allocator().allocation_count() - allocations,
+            bytes_allocated: (self.runtime.allocator().total_bytes_allocated() - bytes_allocated) as u64,
+        };
+        #[cfg(feature = "tracing")]
+        {
+            // The fields of the span of the interpret (or call)
+            let span = tracing::Span::current();
+            span.record("instructions", &self.last_run_stats.instructions);
+            span.record("allocations", &self.last_run_stats.allocations);
+            span.record("bytes_allocated", &self.last_run_stats.bytes_allocated);
+            self.runtime.allocator().trace_allocations();
+        }
+        result
+    }
+
+    /// What the compilation of the current run depends on
+    fn compile_options(&self) -> CompileOptions {
+        let args = self.optional_args.as_ref();
+        CompileOptions {
+            superinstructions: self.superinstructions,
+            eliminate_dead_functions: self.eliminate_dead_functions,
+            global_constants: self.runtime.global_constant_names(),
+            strict: args.is_some_and(|args| args.strict).then(|| self.runtime.global_names()),
+            source_name: args.and_then(|args| args.source_name.clone()),
+        }
+    }
+
+    fn compile_and_run(&mut self, source: String, optional_args: Option<Args>) -> Result<()> {
         #[cfg(feature = "trace_enabled")]
-        let native_functions = self.allocator.bytes_allocated();
+        let native_functions = self.runtime.allocator().bytes_allocated();
         self.reset_vm();
+        if let Some(args) = &optional_args {
+            self.define_scoped_natives(&args.natives);
+            if let Some(max) = args.max_heap_bytes {
+                self.shadowed_max_heap_bytes = Some(self.runtime.allocator().max_heap_bytes());
+                self.set_max_heap_bytes(Some(max));
+            }
+        }
         self.optional_args = optional_args;
-        let mut scanner = Scanner::new(source);
-        let start_time = Instant::now();
-        let tokens = scanner.scan_tokens()?;
-        trace!("Tokens created in {} us", start_time.elapsed().as_micros());
-        let start_time = Instant::now();
-        let mut compiler_buf = Vec::new();
-        let compiler = Compiler::new_with_writer(tokens, &self.allocator, Some(&mut compiler_buf));
-        let main_function = compiler.compile()?;
-        #[cfg(feature = "trace_enabled")]
-        let after_compiler_allocation = self.allocator.bytes_allocated();
-        #[cfg(feature = "trace_enabled")]
-        {
-            if evie_common::log_enabled!(Level::Trace) {
-                println!("{}", &utf8_to_string(&compiler_buf));
+        self.warnings.clear();
+        #[cfg(feature = "tracing")]
+        let compile_span = tracing::info_span!("compile").entered();
+        let options = self.compile_options();
+        // A run compiled for a profile is not cached, see VirtualMachine::set_profile
+        let cache = self.compilation_cache.as_ref().filter(|_| self.profile.is_none());
+        let cached = cache.and_then(|cache| cache.get(&source, &options));
+        let (main_function, artifact) = match cached {
+            Some(cached) => {
+                self.warnings = cached.warnings();
+                (cached.function, cached.artifact.clone())
+            }
+            None => {
+                let source_to_cache = cache.is_some().then(|| source.clone());
+                let mut scanner = Scanner::new(source);
+                let start_time = Instant::now();
+                let tokens = scanner.scan_tokens()?;
+                trace!("Tokens created in {} us", start_time.elapsed().as_micros());
+                let start_time = Instant::now();
+                let mut compiler_buf = Vec::new();
+                let mut compiler = Compiler::new_with_writer(tokens, self.runtime.allocator(), Some(&mut compiler_buf));
+                compiler.set_options(options.clone());
+                compiler.set_profile(self.profile.clone());
+                let compilation = compiler.compile_with_analysis()?;
+                verify(compilation.function.chunk, "script")?;
+                #[cfg(feature = "trace_enabled")]
+                {
+                    if evie_common::log_enabled!(Level::Trace) {
+                        println!("{}", &utf8_to_string(&compiler_buf));
+                    }
+                }
+                trace!("Compiled in {} us", start_time.elapsed().as_micros());
+                if let (Some(cache), Some(source)) = (self.compilation_cache.as_mut(), source_to_cache) {
+                    cache.insert(source, options, &compilation);
+                }
+                let Compilation { function, warnings, artifact, .. } = compilation;
+                self.warnings = warnings;
+                (function, artifact)
             }
+        };
+        #[cfg(feature = "tracing")]
+        drop(compile_span);
+        if self.snapshots {
+            self.runtime.add_functions(artifact.functions().iter().map(|function| function.function()));
         }
-        let upvalues = self.allocator.alloc(Vec::<GCObjectOf<Upvalue>>::new());
-        trace!("Compiled in {} us", start_time.elapsed().as_micros());
+        if self.profiler.is_some() {
+            self.profiler = Some(Profiler::default());
+        }
+        #[cfg(feature = "trace_enabled")]
+        let after_compiler_allocation = self.runtime.allocator().bytes_allocated();
+        let upvalues = self.runtime.allocator().alloc(Vec::<GCObjectOf<Upvalue>>::new());
         self.check_arguments("", 0, 0)?;
-        let closure = self.allocator.alloc(Closure::new(main_function, upvalues));
+        let closure = self.runtime.allocator().alloc(Closure::new(main_function, upvalues));
         let script = ObjectType::Closure(closure);
+        self.check_frame(closure, 0)?;
         self.push_to_call_frame(CallFrame::new(0, closure));
-        self.push_to_stack(Value::object(Object::new_gc_object(script, &self.allocator)));
+        self.push_to_stack(Value::object(Object::new_gc_object(script, self.runtime.allocator())));
         #[cfg(feature = "trace_enabled")]
         let start_time = Instant::now();
         #[allow(clippy::let_and_return)]
         let result = self.run();
+        if let Some(profiler) = &self.profiler {
+            self.last_profile = Some(profiler.profile(&artifact));
+        }
         #[cfg(feature = "trace_enabled")]
         trace!("Ran in {} us, Total Allocation: {} bytes, Native Functions: {} bytes, Compiler: {} bytes, VM: {} bytes", 
             start_time.elapsed().as_micros(), 
-            self.allocator.bytes_allocated(),
+            self.runtime.allocator().bytes_allocated(),
             native_functions,
             after_compiler_allocation- native_functions, 
-            self.allocator.bytes_allocated() - after_compiler_allocation);
+            self.runtime.allocator().bytes_allocated() - after_compiler_allocation);
         result
     }
 
     fn push_to_call_frame(&mut self, c: CallFrame) {
+        // The caller continues from here when the callee returns
+        if let Some(caller) = self.call_frames.last_mut() {
+            caller.ip = self.ip;
+        }
+        self.ip = c.ip;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.counts(c.closure.function).calls += 1;
+        }
         self.call_frames.push(c);
-        self.ip = self.call_frame().non_null_ptr();
+    }
+
+    fn define_scoped_natives(&mut self, natives: &NativeRegistry) {
+        for &(ref name, arity, native_fn) in &natives.natives {
+            let (name, value) = alloc_native_fn(name, arity, native_fn, self.runtime.allocator());
+            let previous = self.runtime.globals().get(name);
+            self.shadowed_globals.push((name, previous));
+            self.runtime.globals().insert(name, value);
+        }
+    }
+
+    fn restore_shadowed_globals(&mut self) {
+        while let Some((name, previous)) = self.shadowed_globals.pop() {
+            match previous {
+                Some(value) => self.runtime.globals().insert(name, value),
+                None => {
+                    self.runtime.globals().remove(name);
+                }
+            }
+        }
     }
 
     fn reset_vm(&mut self) {
+        // A coroutine that was running when the run failed can't continue
+        for running in self.coroutines.drain(..) {
+            finish(running.coroutine);
+        }
         self.call_frames.clear();
-        self.stack_top = 0;
+        self.stack.truncate(0);
+        self.up_values.clear();
     }
 
     #[inline(always)]
@@ -201,25 +561,31 @@ impl<'a> VirtualMachine<'a> {
     }
 
     #[inline(always)]
-    fn ip(&self) -> usize {
-        // Safety ip is set in the run method
-        unsafe  {*self.ip.as_ref()}
-    }
-
-    #[inline(always)]
-    fn read_byte(&mut self, chunk: &Chunk, ip: &mut usize) -> ByteUnit{
-        let v =  chunk.code.read_item_at(*ip);
-        *ip += 1;
+    fn read_byte(&mut self, chunk: &Chunk) -> ByteUnit{
+        let v =  chunk.code.read_item_at(self.ip);
+        self.ip += 1;
         v
     }
 
     #[inline(always)]
-    fn read_constant(&mut self, chunk: &Chunk, ip: &mut usize) -> Result<Value> {
-        let v = chunk.read_constant_at(*ip);
-        *ip += 1;
+    fn read_constant(&mut self, chunk: &Chunk) -> Result<Value> {
+        if self.profiler.is_some() {
+            self.count_constant_read(chunk);
+        }
+        let v = chunk.read_constant_at(self.ip);
+        self.ip += 1;
         Ok(v)
     }
 
+    #[inline(never)]
+    fn count_constant_read(&mut self, chunk: &Chunk) {
+        let function = self.current_function();
+        let constant = chunk.code.read_item_at(self.ip) as usize;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.counts(function).constant_reads[constant] += 1;
+        }
+    }
+
     #[inline(always)]
     fn current_chunk(&self) -> GCObjectOf<Chunk> {
         self.current_function().chunk
@@ -238,51 +604,53 @@ impl<'a> VirtualMachine<'a> {
 
     #[inline(always)]
     fn get_value_from_stack(&self, index: usize) -> Value {
-        assert!(index < STACK_SIZE, "{}", self.runtime_error(&format!("VM BUG Access out of bounds, stack size = {}, index = {}", STACK_SIZE, index)));
-        self.stack[index]
+        self.stack.get(index)
     }
 
     #[inline(always)]
     fn set_stack_mut(&mut self, index: usize, v: Value) {
-        assert!(index< STACK_SIZE, "{}", self.runtime_error(&format!("VM BUG: Stack overflow, stack size = {}, index = {}", STACK_SIZE, index)));
-        self.stack[index] = v;
+        self.stack.set(index, v);
     }
 
     #[inline(always)]
-    fn read_short(&mut self, chunk: &Chunk, ip: &mut usize) -> u16 {
-        let first = self.read_byte(chunk, ip) as u16;
-        let second = self.read_byte(chunk, ip) as u16;
+    fn read_short(&mut self, chunk: &Chunk) -> u16 {
+        let first = self.read_byte(chunk) as u16;
+        let second = self.read_byte(chunk) as u16;
         first << 8 | second
     }
 
-    #[inline(always)]
-    fn set_ip_for_run_method(&mut self, ip_ref: &mut &mut usize) {
-        // Safety the call sites ensure that both unsafe access are safe
-        unsafe {
-            *ip_ref = self.ip.as_mut();
-        }
-    }
-
     fn run(&mut self) -> Result<()> {
         // Starting with 
         let mut function_cache_stack= vec![Cache::new()];
         let mut function_cache_stack_index = 0;
         let mut chunk_obj  = self.current_chunk();
         let mut chunk = &chunk_obj;
-        let mut current_ip = &mut 0;
-        self.set_ip_for_run_method(&mut current_ip);
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("run").entered();
         info!("VM starting");
         loop {
-            let byte = self.read_byte(chunk, current_ip);
-            let instruction = Opcode::from(byte);
+            // Safe point: every live value is reachable from the roots
+            if self.runtime.allocator().should_collect() {
+                self.collect_garbage(&function_cache_stack);
+                if self.runtime.allocator().is_out_of_memory() {
+                    bail!(self.runtime_error(&format!("out of memory, the heap is limited to {} bytes",
+                        self.runtime.allocator().max_heap_bytes().unwrap_or_default())));
+                }
+            }
+            self.instructions += 1;
+            let byte = self.read_byte(chunk);
+            let instruction = match Opcode::try_from(byte) {
+                Ok(instruction) => instruction,
+                Err(e) => bail!(self.runtime_error(&e.to_string())),
+            };
             #[cfg(feature ="trace_enabled")]
             if log_enabled!(Level::Trace) {
                 let mut buf = Vec::new();
                 let fun_name = self.current_function().as_ref().to_string();
-                opcodes::disassemble_instruction_with_writer_with_out_line_num(chunk, *current_ip -1, &mut buf, false);
+                opcodes::disassemble_instruction_with_writer_with_out_line_num(chunk, self.ip -1, &mut buf, false);
                 trace!(
                     "ip: {},function {}, stack: {:?}, next instruction: [{}]",
-                    *current_ip,
+                    self.ip,
                     fun_name,
                     self.sanitized_full_stack(),
                     &utf8_to_string(&buf).trim()
@@ -290,25 +658,33 @@ impl<'a> VirtualMachine<'a> {
             }
             match instruction {
                 Opcode::Constant => {
-                    let constant = self.read_constant(chunk, current_ip)?;
+                    let constant = self.read_constant(chunk)?;
                     self.push_to_stack(constant);
                 }
                 Opcode::Return => {
                     let fn_starting_pointer = self.call_frame().fn_start_stack_index;
-                    let result = self.pop_from_stack();
+                    // Closed while the result is on the stack: if it is a captured slot it is still there to be closed
                     self.close_upvalues(fn_starting_pointer);
+                    let result = self.pop_from_stack();
                     if self.call_frames.len() == 1 {
+                        // Leave the result on the stack, for a function called by the host
+                        self.stack.truncate(fn_starting_pointer);
+                        self.push_to_stack(result);
                         return Ok(());
                     }
                     function_cache_stack.pop();
                     function_cache_stack_index -=1;
                     self.call_frames.pop();
-                    self.ip = self.call_frame().non_null_ptr();
-                    self.set_ip_for_run_method(&mut current_ip);
+                    if self.coroutines.last().is_some_and(|running| running.frame == self.call_frames.len()) {
+                        // The coroutine returned, the result goes to its resumer
+                        let running = self.coroutines.pop().expect("VM BUG: Expected a running coroutine");
+                        finish(running.coroutine);
+                    }
+                    self.ip = self.call_frame().ip;
                     chunk_obj = self.current_chunk();
                     chunk = &chunk_obj;
                     // drop all the local values for the last function
-                    self.stack_top = fn_starting_pointer;
+                    self.stack.truncate(fn_starting_pointer);
                     // push the return result
                     self.push_to_stack(result);
                 }
@@ -325,7 +701,15 @@ impl<'a> VirtualMachine<'a> {
                 Opcode::Add => self.add()?,
                 Opcode::Subtract => self.binary_op(|a, b| Value::number(a - b))?,
                 Opcode::Multiply => self.binary_op(|a, b| Value::number(a * b))?,
-                Opcode::Divide => self.binary_op(|a, b| Value::number(a / b))?,
+                Opcode::Divide => self.divide()?,
+                Opcode::Power => self.binary_op(|a, b| Value::number(a.powf(b)))?,
+                Opcode::BitAnd => self.binary_op(|a, b| Value::number((to_integer(a) & to_integer(b)) as f64))?,
+                Opcode::BitOr => self.binary_op(|a, b| Value::number((to_integer(a) | to_integer(b)) as f64))?,
+                Opcode::BitXor => self.binary_op(|a, b| Value::number((to_integer(a) ^ to_integer(b)) as f64))?,
+                Opcode::ShiftLeft => self.binary_op(|a, b| Value::number(to_integer(a).wrapping_shl(to_integer(b) as u32) as f64))?,
+                Opcode::ShiftRight => self.binary_op(|a, b| Value::number(to_integer(a).wrapping_shr(to_integer(b) as u32) as f64))?,
+                Opcode::Range => self.range(false)?,
+                Opcode::RangeInclusive => self.range(true)?,
                 Opcode::Nil => self.push_to_stack(Value::nil()),
                 Opcode::True => self.push_to_stack(Value::bool(true)),
                 Opcode::False => self.push_to_stack(Value::bool(false)),
@@ -355,16 +739,18 @@ impl<'a> VirtualMachine<'a> {
                 }
                 Opcode::DefineGlobal => {
                     let value = self.pop_from_stack();
-                    let name = self.read_string(chunk, current_ip)?;
-                    self.runtime_values.insert(name, value);
+                    let name = self.read_string(chunk)?;
+                    // A global declared again must not be read from the cache, e.g. a class declared again would get the methods of the new one
+                    function_cache_stack[function_cache_stack_index].remove(name);
+                    self.runtime.globals().insert(name, value);
                 }
                 Opcode::GetGlobal => {
-                    let name = self.read_string(chunk, current_ip)?;
+                    let name = self.read_string(chunk)?;
                     let function_cache = &mut function_cache_stack[function_cache_stack_index];
                     if let Some(v) = function_cache.get(name) {
                         self.push_to_stack(v)
                     } else {
-                        let value = self.runtime_values.get(name);
+                        let value = self.runtime.globals().get(name);
                         if let Some(v) = value {
                             function_cache.insert(name, v);
                             self.push_to_stack(v)
@@ -374,63 +760,92 @@ impl<'a> VirtualMachine<'a> {
                     }
                 }
                 Opcode::SetGlobal => {
-                    let name = self.read_string(chunk, current_ip)?;
+                    let name = self.read_string(chunk)?;
                     let value = self.peek_at(0);
+                    // Only the code compiled before the constant was declared gets here, the compiler rejects the others
+                    if self.runtime.is_global_constant(name) {
+                        bail!(self.runtime_error(&format!("Can't assign to the constant '{}'", name.as_ref())))
+                    }
                     function_cache_stack[function_cache_stack_index].insert(name, value);
-                    if self.runtime_values.contains_key(name) {
-                        self.runtime_values.insert(name, value);
+                    if self.runtime.globals().contains_key(name) {
+                        self.runtime.globals().insert(name, value);
                     } else {
                         bail!(self.runtime_error(&format!("Undefined variable '{}'", name.as_ref())))
                     }
                 }
+                Opcode::DefineGlobalConstant => {
+                    let value = self.pop_from_stack();
+                    let name = self.read_string(chunk)?;
+                    let slot = self.read_byte(chunk) as usize;
+                    function_cache_stack[function_cache_stack_index].remove(name);
+                    self.runtime.define_global_constant(slot, name, value);
+                }
+                Opcode::GetGlobalConstant => {
+                    let slot = self.read_byte(chunk) as usize;
+                    match self.runtime.global_constant(slot) {
+                        Some(value) => self.push_to_stack(value),
+                        None => bail!(self.runtime_error("Undefined constant")),
+                    }
+                }
                 Opcode::GetLocal => {
-                    let index = self.read_byte(chunk, current_ip) as usize;
+                    let index = self.read_byte(chunk) as usize;
                     let fn_start_pointer = self.call_frame().fn_start_stack_index;
                     let v = self.get_value_from_stack(fn_start_pointer + index);
                     self.push_to_stack(v);
                 }
                 Opcode::SetLocal => {
-                    let index = self.read_byte(chunk, current_ip);
+                    let index = self.read_byte(chunk);
                     let fn_start_pointer = self.call_frame().fn_start_stack_index;
-                    self.stack[fn_start_pointer + index as usize] = self.peek_at(0);
+                    let v = self.peek_at(0);
+                    self.set_stack_mut(fn_start_pointer + index as usize, v);
                 }
                 Opcode::JumpIfFalse => {
-                    let offset = self.read_short(chunk, current_ip);
+                    let offset = self.read_short(chunk);
                     if is_falsey(&self.peek_at(0)) {
-                        *current_ip += offset as usize;
+                        self.ip += offset as usize;
                     }
                 }
                 Opcode::Jump => {
-                    let offset = self.read_short(chunk, current_ip);
-                    *current_ip += offset as usize;
+                    let offset = self.read_short(chunk);
+                    self.ip += offset as usize;
+                }
+                Opcode::JumpIfNil => {
+                    let offset = self.read_short(chunk);
+                    if self.peek_at(0).is_nil() {
+                        self.ip += offset as usize;
+                    }
                 }
                 Opcode::JumpIfTrue => {
-                    let offset = self.read_short(chunk, current_ip);
+                    let offset = self.read_short(chunk);
                     if !is_falsey(&self.peek_at(0)) {
-                        *current_ip +=  offset as usize;
+                        self.ip +=  offset as usize;
                     }
                 }
                 Opcode::Loop => {
-                    let offset = self.read_short(chunk, current_ip);
-                    *current_ip -= offset as usize;
+                    let offset = self.read_short(chunk);
+                    self.ip -= offset as usize;
+                    self.check_instruction_limit()?;
                 }
                 Opcode::Call => {
-                    let arg_count = self.read_byte(chunk,current_ip) as usize;
+                    let arg_count = self.read_byte(chunk) as usize;
+                    let frame_count = self.call_frames.len();
                     self.call_value(arg_count, self.peek_at(arg_count))?;
-                    function_cache_stack.push(Cache::new());
-                    function_cache_stack_index +=1;
-                    chunk_obj = self.current_chunk();
-                    chunk = &chunk_obj;
-                    self.set_ip_for_run_method(&mut current_ip);
+                    // Natives and init-less constructors complete in place without a new frame
+                    if self.call_frames.len() > frame_count {
+                        function_cache_stack.push(Cache::new());
+                        function_cache_stack_index +=1;
+                        chunk_obj = self.current_chunk();
+                        chunk = &chunk_obj;
+                    }
                 }
                 Opcode::Closure => {
-                    let function = self.read_function(chunk, current_ip)?;
+                    let function = self.read_function(chunk)?;
                     let current_fn_stack_ptr = self.call_frame().fn_start_stack_index;
-                    let upvalues = self.allocator.alloc(Vec::<GCObjectOf<Upvalue>>::new());
+                    let upvalues = self.runtime.allocator().alloc(Vec::<GCObjectOf<Upvalue>>::new());
                     let mut closure = Closure::new(function, upvalues);
                     for _ in 0..function.upvalue_count {
-                        let is_local = self.read_byte(chunk, current_ip) > 0;
-                        let index = self.read_byte(chunk, current_ip);
+                        let is_local = self.read_byte(chunk) > 0;
+                        let index = self.read_byte(chunk);
                         if is_local {
                             let upvalue_index_on_stack =
                                 current_fn_stack_ptr + index as usize;
@@ -444,12 +859,12 @@ impl<'a> VirtualMachine<'a> {
                             closure.upvalues.as_mut().push(upvalue);
                         }
                     }
-                    let object = self.allocator.alloc(closure);
-                    let stack_value = Value::object(Object::new_gc_object(ObjectType::Closure(object), &self.allocator));
+                    let object = self.runtime.allocator().alloc(closure);
+                    let stack_value = Value::object(Object::new_gc_object(ObjectType::Closure(object), self.runtime.allocator()));
                     self.push_to_stack(stack_value);
                 }
                 Opcode::GetUpvalue => {
-                    let slot = self.read_byte(chunk, current_ip) as usize;
+                    let slot = self.read_byte(chunk) as usize;
                     let closure = self.current_closure();
                     let value = {
                         let upvalues = closure.upvalues;
@@ -463,8 +878,8 @@ impl<'a> VirtualMachine<'a> {
                     self.push_to_stack(value);
                 }
                 Opcode::SetUpvalue => {
-                    let slot = self.read_byte(chunk, current_ip) as usize;
-                    let value = self.peek_at(slot as usize);
+                    let slot = self.read_byte(chunk) as usize;
+                    let value = self.peek_at(0);
                     let closure = self.current_closure();
                     let upvalues = closure.upvalues;
                     assert!(slot < upvalues.len(), "{}", self.runtime_error("VM BUG: Invalid up value index"));
@@ -481,103 +896,374 @@ impl<'a> VirtualMachine<'a> {
                     }
                 }
                 Opcode::CloseUpvalue => {
-                    self.close_upvalues(self.stack_top - 1);
+                    self.close_upvalues(self.stack.len() - 1);
                     self.pop_from_stack();
                 }
                 Opcode::Class => {
-                    let class = self.read_string(chunk, current_ip)?;
-                    let methods= self.allocator.alloc(Cache::new());
-                    let class_obj = self.allocator.alloc(Class::new(class, methods));
-                    let value = Value::object(Object::new_gc_object(ObjectType::Class(class_obj), &self.allocator));
+                    let class = self.read_string(chunk)?;
+                    // Hashed whatever the number of methods, invokes that miss their inline cache look them up
+                    let methods= self.runtime.allocator().alloc(Cache::with_linear_limit(0));
+                    let statics = self.runtime.allocator().alloc(Cache::new());
+                    let class_obj = self.runtime.allocator().alloc(Class::new(class, methods, statics));
+                    let value = Value::object(Object::new_gc_object(ObjectType::Class(class_obj), self.runtime.allocator()));
                     self.push_to_stack(value);
                 }
                 Opcode::SetProperty => {
-                    let property = self.read_string(chunk, current_ip)?;
+                    let property = self.read_string(chunk)?;
                     let value = self.peek_at(0);
                     let instance = self.peek_at(1);
                     if instance.is_object() {
-                        if let ObjectType::Instance(mut i) = &instance.as_object().object_type  {
-                            self.set_property(&mut i, property, value)?;
-                            let value = self.pop_from_stack();
-                            self.pop_from_stack();
-                            // a.b = '2' evaluates to '2'
-                            self.push_to_stack(value);
+                        match instance.as_object().object_type {
+                            ObjectType::Instance(i) => self.set_property(i, property, value, self.ip),
+                            // Class level field
+                            ObjectType::Class(mut c) => c.statics.insert(property, value),
+                            _ => bail!(self.runtime_error(&format!("Only instances and classes can have properties got {} instead", instance)))
                         }
+                        let value = self.pop_from_stack();
+                        self.pop_from_stack();
+                        // a.b = '2' evaluates to '2'
+                        self.push_to_stack(value);
                     } else {
-                        bail!(self.runtime_error(&format!("Only instances can have properties got {} instead", instance)))
+                        bail!(self.runtime_error(&format!("Only instances and classes can have properties got {} instead", instance)))
                     }
                 }
                 Opcode::GetProperty => {
-                    let property = self.read_string(chunk, current_ip)?;
+                    let property = self.read_string(chunk)?;
                     let instance = self.peek_at(0);
                     if instance.is_object() {
-                        if let ObjectType::Instance(i) = instance.as_object().object_type {
-                            let v = self.get_property(i, property)?;
-                            self.pop_from_stack();
-                            self.push_to_stack(v);
-                        }
+                        let v = match instance.as_object().object_type {
+                            ObjectType::Instance(i) => self.get_property(instance.as_object(), i, property, self.ip)?,
+                            ObjectType::Class(c) => self.get_static_property(c, property)?,
+                            _ => bail!(self.runtime_error(&format!("Only instances and classes can have properties got {} instead", instance)))
+                        };
+                        self.pop_from_stack();
+                        self.push_to_stack(v);
                     } else {
-                        bail!(self.runtime_error(&format!("Only instances can have properties got {} instead", instance)))
+                        bail!(self.runtime_error(&format!("Only instances and classes can have properties got {} instead", instance)))
                     }
-                    
                 }
                 Opcode::Method => {
-                    let method_name = self.read_string(chunk, current_ip)?;
+                    let method_name = self.read_string(chunk)?;
                     self.define_method(method_name)?;
                 }
+                Opcode::Is => {
+                    let class = self.pop_from_stack();
+                    let value = self.pop_from_stack();
+                    let result = self.is_instance_of(value, class)?;
+                    self.push_to_stack(Value::bool(result));
+                }
+                Opcode::AddConstant => {
+                    let constant = self.read_constant(chunk)?;
+                    let left = self.peek_at(0);
+                    if left.is_number() && constant.is_number() {
+                        self.set_stack_mut(self.stack.len() - 1, Value::number(left.as_number() + constant.as_number()));
+                    } else {
+                        self.push_to_stack(constant);
+                        self.add()?;
+                    }
+                }
+                Opcode::AddLocals => {
+                    let (a, b) = (self.read_byte(chunk) as usize, self.read_byte(chunk) as usize);
+                    let fn_start_pointer = self.call_frame().fn_start_stack_index;
+                    let left = self.get_value_from_stack(fn_start_pointer + a);
+                    let right = self.get_value_from_stack(fn_start_pointer + b);
+                    if left.is_number() && right.is_number() {
+                        self.push_to_stack(Value::number(left.as_number() + right.as_number()));
+                    } else {
+                        self.push_to_stack(left);
+                        self.push_to_stack(right);
+                        self.add()?;
+                    }
+                }
+                Opcode::CompareJumpIfFalse => {
+                    let comparison = self.read_byte(chunk);
+                    let comparison = match Opcode::try_from(comparison) {
+                        Ok(comparison) => comparison,
+                        Err(e) => bail!(self.runtime_error(&e.to_string())),
+                    };
+                    let offset = self.read_short(chunk);
+                    if !self.compare(comparison)? {
+                        self.ip += offset as usize;
+                    }
+                }
+                Opcode::StaticMethod => {
+                    let method_name = self.read_string(chunk)?;
+                    self.define_static_method(method_name)?;
+                }
+                Opcode::Doc => {
+                    let doc = self.read_string(chunk)?;
+                    let v = self.peek_at(0);
+                    match v.is_object().then(|| v.as_object().object_type) {
+                        Some(ObjectType::Class(mut c)) => c.doc = Some(doc),
+                        _ => bail!(self.runtime_error("Only classes can have docstrings")),
+                    }
+                }
                 Opcode::Invoke => {
-                    let method = self.read_string(chunk, current_ip)?;
-                    let arg_count = self.read_byte(chunk, current_ip) as usize;
+                    let method = self.read_string(chunk)?;
+                    let arg_count = self.read_byte(chunk) as usize;
                     let receiver = self.peek_at(arg_count);
-                    let fn_start_stack_index = self.stack_top - arg_count - 1;
-                    self.invoke(receiver, method, fn_start_stack_index)?;
-                    function_cache_stack.push(Cache::new());
-                    function_cache_stack_index +=1;
+                    let fn_start_stack_index = self.stack.len() - arg_count - 1;
+                    let frame_count = self.call_frames.len();
+                    self.invoke(receiver, method, fn_start_stack_index, self.ip)?;
+                    // Resuming a coroutine pushes all its frames
+                    if self.call_frames.len() > frame_count {
+                        function_cache_stack.resize_with(self.call_frames.len(), Cache::new);
+                        function_cache_stack_index = self.call_frames.len() - 1;
+                        chunk_obj = self.current_chunk();
+                        chunk = &chunk_obj;
+                    }
+                }
+                Opcode::Yield => {
+                    let value = self.pop_from_stack();
+                    let Some(running) = self.coroutines.pop() else {
+                        bail!(self.runtime_error("Can only yield inside a coroutine"))
+                    };
+                    self.suspend(running);
+                    self.push_to_stack(value);
+                    function_cache_stack.truncate(self.call_frames.len());
+                    function_cache_stack_index = self.call_frames.len() - 1;
                     chunk_obj = self.current_chunk();
                     chunk = &chunk_obj;
-                    self.set_ip_for_run_method(&mut current_ip);
                 }
             };
         }
     }
 
-    fn invoke(&mut self, receiver: Value, method: GCObjectOf<Box<str>>, fn_start_stack_index: usize) -> Result<()> {
+    /// Invokes the method on the receiver, `site` is the offset after the invoke instruction (see [Chunk::invoke_cache])
+    fn invoke(&mut self, receiver: Value, method: GCObjectOf<Box<str>>, fn_start_stack_index: usize, site: usize) -> Result<()> {
         if receiver.is_object() {
-            if let ObjectType::Instance(i) = receiver.as_object().object_type {
-                if let Some(closure) = i.class.methods.get(method) {
-                    self.set_stack_mut(fn_start_stack_index, receiver);
-                    self.push_closure_to_call_frame(closure, fn_start_stack_index)?;
+            match receiver.as_object().object_type {
+                ObjectType::Instance(i) => {
+                    // A field shadows the method, the function it holds is called without a receiver
+                    if let Some(slot) = self.invoked_field_slot(i, method, site) {
+                        let value = i.fields.values[slot];
+                        self.set_stack_mut(fn_start_stack_index, value);
+                        return self.call_value(self.stack.len() - fn_start_stack_index - 1, value);
+                    }
+                    let mut chunk = self.current_chunk();
+                    let closure = match chunk.invoke_cache(site) {
+                        Some((class, closure)) if class.as_ptr() == i.class.as_ptr() => Some(closure),
+                        _ => i.class.methods.get(method).inspect(|&closure| chunk.set_invoke_cache(site, (i.class, closure))),
+                    };
+                    if let Some(closure) = closure {
+                        let arg_count = self.stack.len() - fn_start_stack_index - 1;
+                        self.check_arguments(&closure.function.name.unwrap(), closure.function.arity, arg_count)?;
+                        self.set_stack_mut(fn_start_stack_index, receiver);
+                        self.push_closure_to_call_frame(closure, fn_start_stack_index)?;
+                        return Ok(())
+                    }
+                }
+                ObjectType::Class(c) => {
+                    if let Some(value) = c.statics.get(method) {
+                        // The callee takes the place of the receiver, as for any other call
+                        self.set_stack_mut(fn_start_stack_index, value);
+                        return self.call_value(self.stack.len() - fn_start_stack_index - 1, value);
+                    }
+                    bail!(self.runtime_error(&format!("Undefined static method '{}' on {}", *method, c.as_ref())))
+                }
+                ObjectType::String(_) | ObjectType::Rope(_) => {
+                    if let Some(native_function) = self.string_methods.get(method) {
+                        let arg_count = self.stack.len() - fn_start_stack_index - 1;
+                        self.check_arguments(&native_function.name, native_function.arity, arg_count)?;
+                        // The receiver is passed as the first argument
+                        let arguments = fn_start_stack_index..self.stack.len();
+                        return self.call_native_function(&native_function, arguments, fn_start_stack_index);
+                    }
+                    bail!(self.runtime_error(&format!("Undefined method '{}' on String", *method)))
+                }
+                // The iterator protocol of the for-in loops, the numbers are not allocated
+                ObjectType::Range(r) if &**method == "iter" => {
+                    self.check_arguments("iter", 0, self.stack.len() - fn_start_stack_index - 1)?;
+                    let iterator = self.runtime.allocator().alloc(r.iter());
+                    let iterator = Value::object(Object::new_gc_object(ObjectType::RangeIterator(iterator), self.runtime.allocator()));
+                    self.set_stack_mut(fn_start_stack_index, iterator);
                     return Ok(())
                 }
+                ObjectType::RangeIterator(mut i) if &**method == "next" => {
+                    self.check_arguments("next", 0, self.stack.len() - fn_start_stack_index - 1)?;
+                    let next = i.next().map_or(Value::nil(), Value::number);
+                    self.set_stack_mut(fn_start_stack_index, next);
+                    return Ok(())
+                }
+                ObjectType::Range(_) | ObjectType::RangeIterator(_) => {
+                    bail!(self.runtime_error(&format!("Undefined method '{}' on {}", *method, receiver)))
+                }
+                ObjectType::Coroutine(c) => return self.invoke_coroutine(c, receiver, method, fn_start_stack_index),
+                _ => {}
             }
         }
         bail!(self.runtime_error(&format!("Undefined method '{}'", *method)))
     }
 
-    fn set_property(&mut self, instance: &mut Instance, property: GCObjectOf<Box<str>>, value: Value) -> Result<()> {
-        let fields = instance.fields.as_mut();
-        fields.insert(property, value);
+    /// The methods of coroutines: `resume(...)`, `is_done()` and the iterator protocol, `iter()` and `next()`
+    /// (a resume without a value that returns nil once the coroutine is done)
+    fn invoke_coroutine(&mut self, coroutine: GCObjectOf<Coroutine>, receiver: Value, method: GCObjectOf<Box<str>>, fn_start_stack_index: usize) -> Result<()> {
+        let arg_count = self.stack.len() - fn_start_stack_index - 1;
+        match &**method {
+            "resume" => self.resume(coroutine, fn_start_stack_index),
+            "next" => {
+                self.check_arguments("next", 0, arg_count)?;
+                if coroutine.state == CoroutineState::Done {
+                    self.set_stack_mut(fn_start_stack_index, Value::nil());
+                    return Ok(())
+                }
+                self.resume(coroutine, fn_start_stack_index)
+            }
+            // The receiver is the result
+            "iter" => self.check_arguments("iter", 0, arg_count),
+            "is_done" => {
+                self.check_arguments("is_done", 0, arg_count)?;
+                self.set_stack_mut(fn_start_stack_index, Value::bool(coroutine.state == CoroutineState::Done));
+                Ok(())
+            }
+            _ => bail!(self.runtime_error(&format!("Undefined method '{}' on {}", *method, receiver))),
+        }
+    }
+
+    /// Runs the coroutine from where it is (its start or its last yield) on top of the stack, its arguments are after
+    /// it from `base`. The value it yields (or returns) replaces them.
+    fn resume(&mut self, mut coroutine: GCObjectOf<Coroutine>, base: usize) -> Result<()> {
+        let arg_count = self.stack.len() - base - 1;
+        let frame = self.call_frames.len();
+        match coroutine.state {
+            CoroutineState::Created => {
+                // Called as any other function, the arguments of the first resume are its arguments
+                let function = coroutine.function;
+                self.set_stack_mut(base, function);
+                self.call_value(arg_count, function)?;
+                if self.call_frames.len() == frame {
+                    // e.g. a class without an initializer, it returned already
+                    finish(coroutine);
+                    return Ok(())
+                }
+            }
+            CoroutineState::Suspended => {
+                if arg_count > 1 {
+                    bail!(self.runtime_error(&format!("Expected 0 or 1 arguments but got {} for <fn resume>", arg_count)))
+                }
+                // The value of the yield expression
+                let value = if arg_count == 1 { self.peek_at(0) } else { Value::nil() };
+                let top = *coroutine.frames.last().expect("VM BUG: Expected a suspended frame");
+                self.check_frame(top.closure, base + top.slot)?;
+                self.stack.truncate(base);
+                coroutine.stack.iter().for_each(|&v| self.stack.push(v));
+                coroutine.stack.clear();
+                // The upvalues are open again, a closure may have changed the value while the coroutine was suspended
+                for (mut upvalue, slot) in coroutine.upvalues.drain(..) {
+                    if let Location::Heap(value) = upvalue.location {
+                        self.set_stack_mut(base + slot, *value);
+                    }
+                    upvalue.location = Location::Stack(base + slot);
+                    self.up_values.push(upvalue);
+                }
+                // The resumer continues from here when the coroutine yields or returns
+                self.call_frames.last_mut().expect("VM BUG: Expected call frame").ip = self.ip;
+                self.call_frames.extend(coroutine.frames.drain(..).map(|f| CallFrame {
+                    fn_start_stack_index: base + f.slot,
+                    closure: f.closure,
+                    ip: f.ip,
+                }));
+                self.ip = top.ip;
+                self.push_to_stack(value);
+            }
+            CoroutineState::Running => bail!(self.runtime_error("Can't resume a running coroutine")),
+            CoroutineState::Done => bail!(self.runtime_error("Can't resume a finished coroutine")),
+        }
+        coroutine.state = CoroutineState::Running;
+        self.coroutines.push(RunningCoroutine { coroutine, frame, base });
         Ok(())
     }
 
+    /// Moves the call frames and the values of the running coroutine into it (the opposite of
+    /// [VirtualMachine::resume]), its resumer continues
+    fn suspend(&mut self, running: RunningCoroutine) {
+        let RunningCoroutine { mut coroutine, frame, base } = running;
+        self.call_frames.last_mut().expect("VM BUG: Expected call frame").ip = self.ip;
+        coroutine.frames.extend(self.call_frames.drain(frame..).map(|f| SuspendedFrame {
+            closure: f.closure,
+            ip: f.ip,
+            slot: f.fn_start_stack_index - base,
+        }));
+        // The upvalues of its slots are closed while it is suspended, the closures that captured them still work
+        let first = self.up_values.partition_point(|&u| open_upvalue_slot(u) < base);
+        for mut upvalue in self.up_values.split_off(first) {
+            let slot = open_upvalue_slot(upvalue);
+            let value = self.runtime.allocator().alloc(self.get_value_from_stack(slot));
+            upvalue.location = Location::Heap(value);
+            coroutine.upvalues.push((upvalue, slot - base));
+        }
+        coroutine.stack.extend_from_slice(self.stack.slice(base..self.stack.len()));
+        self.runtime.allocator().resize(coroutine);
+        self.stack.truncate(base);
+        coroutine.state = CoroutineState::Suspended;
+        self.ip = self.call_frame().ip;
+    }
+
+    /// Sets the field, `site` is the offset after the property instruction (see [Chunk::property_cache])
+    fn set_property(&mut self, mut instance: GCObjectOf<Instance>, property: GCObjectOf<Box<str>>, value: Value, site: usize) {
+        match self.field_slot(instance, property, site) {
+            Some(slot) => instance.fields.values[slot] = value,
+            // A new field, the instance moves to the next shape
+            None => {
+                instance.fields.insert(property, value, self.runtime.allocator());
+                self.runtime.allocator().resize(instance);
+            }
+        }
+    }
+
+    /// The slot of an existing field, looked up in the inline cache of the `site` first
+    #[inline(always)]
+    fn field_slot(&mut self, instance: GCObjectOf<Instance>, property: GCObjectOf<Box<str>>, site: usize) -> Option<usize> {
+        let shape = instance.fields.shape;
+        let mut chunk = self.current_chunk();
+        match chunk.property_cache(site) {
+            Some((cached, slot)) if cached.as_ptr() == shape.as_ptr() => Some(slot),
+            _ => shape.slot(property).inspect(|&slot| chunk.set_property_cache(site, (shape, slot))),
+        }
+    }
+
+    /// Like [VirtualMachine::field_slot] for an invoke site, where the field is usually missing (a method is invoked),
+    /// so the absence of the field is cached as well
+    fn invoked_field_slot(&mut self, instance: GCObjectOf<Instance>, method: GCObjectOf<Box<str>>, site: usize) -> Option<usize> {
+        let shape = instance.fields.shape;
+        let mut chunk = self.current_chunk();
+        match chunk.property_cache(site) {
+            Some((cached, slot)) if cached.as_ptr() == shape.as_ptr() => (slot != NO_FIELD).then_some(slot),
+            _ => {
+                let slot = shape.slot(method);
+                chunk.set_property_cache(site, (shape, slot.unwrap_or(NO_FIELD)));
+                slot
+            }
+        }
+    }
     fn get_property(
         &mut self,
+        receiver: GCObjectOf<Object>,
         instance: GCObjectOf<Instance>,
         property: GCObjectOf<Box<str>>,
+        site: usize,
     )  -> Result<Value>{
-        if let Some(v) =instance.fields.get(property) {
-            Ok(v)
+        if let Some(slot) = self.field_slot(instance, property, site) {
+            Ok(instance.fields.values[slot])
         } else if let Some(method) = instance.class.methods.get(property){
-                Ok(self.bind_method(instance, method))
+                Ok(self.bind_method(receiver, method))
         } else {
             bail!(self.runtime_error(&format!("No property or method with the name {}", *property)))
         }
     }
 
-    fn bind_method(&mut self, instance: GCObjectOf<Instance>, method: GCObjectOf<Closure>) -> Value{
-        self.pop_from_stack();
-        let bound_method = self.allocator.alloc(BoundMethod(instance, method));
-        Value::object(Object::new_gc_object(ObjectType::BoundMethod(bound_method), &self.allocator))
+    fn get_static_property(&mut self, class: GCObjectOf<Class>, property: GCObjectOf<Box<str>>) -> Result<Value> {
+        if let Some(v) = class.statics.get(property) {
+            Ok(v)
+        } else {
+            bail!(self.runtime_error(&format!("No static property or method with the name {} on {}", *property, class.as_ref())))
+        }
+    }
+
+    /// Binds the method to the receiver, the object of an instance
+    fn bind_method(&mut self, receiver: GCObjectOf<Object>, method: GCObjectOf<Closure>) -> Value{
+        let bound_method = self.runtime.allocator().alloc(BoundMethod(receiver, method));
+        Value::object(Object::new_gc_object(ObjectType::BoundMethod(bound_method), self.runtime.allocator()))
     }
 
     fn define_method(&mut self, method_name: GCObjectOf<Box<str>>) -> Result<()> {
@@ -586,16 +1272,19 @@ impl<'a> VirtualMachine<'a> {
             if let ObjectType::Closure(c) = value.as_object().object_type {
                 c
             } else {
-                panic!("{}", self.runtime_error(&format!("VM BUG: expected a closure but got {}", value)));
+                bail!(self.runtime_error(&format!("Expected a closure as the method but got {}", value)));
             }
         } else {
-            panic!("{}", self.runtime_error(&format!("VM BUG: expected a closure but got {}", value)));
+            bail!(self.runtime_error(&format!("Expected a closure as the method but got {}", value)));
         };
         let v = self.peek_at(1);
         if v.is_object() {
-            if let ObjectType::Class(c) = v.as_object().object_type {
+            if let ObjectType::Class(mut c) = v.as_object().object_type {
                 let mut methods = c.methods;
                 methods.insert(method_name, method);
+                if &**method_name == "init" {
+                    c.init = Some(method);
+                }
             }
         } else {
             bail!(self.runtime_error("Only classes can have methods"))
@@ -604,12 +1293,39 @@ impl<'a> VirtualMachine<'a> {
         Ok(())
     }
 
+    fn is_instance_of(&self, value: Value, class: Value) -> Result<bool> {
+        let class = match class.is_object().then(|| class.as_object().object_type) {
+            Some(ObjectType::Class(c)) => c,
+            _ => bail!(self.runtime_error(&format!("Right operand of 'is' must be a class, got '{}'", class))),
+        };
+        if value.is_object() {
+            if let ObjectType::Instance(i) = value.as_object().object_type {
+                return Ok(std::ptr::eq(i.class.as_ptr(), class.as_ptr()))
+            }
+        }
+        Ok(false)
+    }
+
+    fn define_static_method(&mut self, method_name: GCObjectOf<Box<str>>) -> Result<()> {
+        let method = self.peek_at(0);
+        let v = self.peek_at(1);
+        if v.is_object() {
+            if let ObjectType::Class(mut c) = v.as_object().object_type {
+                c.statics.insert(method_name, method);
+            }
+        } else {
+            bail!(self.runtime_error("Only classes can have static methods"))
+        }
+        self.pop_from_stack(); //method closure
+        Ok(())
+    }
+
     fn sanitized_full_stack(&self) -> Vec<String> {
-        self.sanitized_stack_with_range_and_address(0..self.stack_top, false)
+        self.sanitized_stack_with_range_and_address(0..self.stack.len(), false)
     }
 
     fn sanitized_stack_with_range_and_address(&self, range: Range<usize>, with_address: bool) -> Vec<String> {
-        let s: Vec<String> = self.stack[range]
+        let s: Vec<String> = self.stack.slice(range)
             .iter()
             .enumerate()
             .map(|(i, v)| {
@@ -623,73 +1339,57 @@ impl<'a> VirtualMachine<'a> {
         s
     }
 
+    /// Closes the open upvalues of the stack slots from `last_index` on, their values move to the heap.
+    /// The open upvalues are sorted by stack slot, so these are the last ones.
     fn close_upvalues(&mut self, last_index: usize){
-        let upvalue_iter = self.up_values.iter().rev();
-        let mut count = 0;
-        upvalue_iter
-            .take_while(|u| match u.location {
-                Location::Stack(index) => index >= last_index,
-                _ => false,
-            })
-            .for_each(|u| {
-                count += 1;
-                let mut u = *u;
-                let location = u.location;
-                if let Location::Stack(index) = location {
-                    let stack_value = self.get_value_from_stack(index);
-                    // Moving from stack to heap
-                    let heap_value = self.allocator.alloc(stack_value);
-                    u.as_mut().location = Location::Heap(heap_value);
-                }
-            });
-        
-        // drop the ones we don't need.
-        let _captured_values = self.up_values.split_off(self.up_values.len() - count);
+        while let Some(&upvalue) = self.up_values.last() {
+            let index = open_upvalue_slot(upvalue);
+            if index < last_index {
+                break
+            }
+            self.up_values.pop();
+            let stack_value = self.get_value_from_stack(index);
+            // Moving from stack to heap
+            let heap_value = self.runtime.allocator().alloc(stack_value);
+            let mut upvalue = upvalue;
+            upvalue.as_mut().location = Location::Heap(heap_value);
+        }
     }
 
+    /// The open upvalue of the stack slot, a new one if the slot is not captured yet.
+    /// Closures mostly capture the slots of the current function, at the top of the stack, so they are checked first.
     fn capture_upvalue(&mut self, stack_index: usize) -> GCObjectOf<Upvalue> {
-        let upvalue_iter = self.up_values.iter().rev();
-        let upvalue = upvalue_iter
-            .take_while(|&&u| {
-                match u.location {
-                    Location::Stack(index) => index >= stack_index,
-                    _ => false,
-                }
-            })
-            .find(|&&u| {
-                match u.location {
-                    Location::Stack(index) => index == stack_index,
-                    _ => false,
-                }
-            });
-        if let Some(u) = upvalue {
-            *u
-        } else {
-            let created_value = self.allocator.alloc(Upvalue::new_with_location(Location::Stack(stack_index)));
-            self.up_values.push(created_value);
-            created_value
+        let position = match self.up_values.last() {
+            Some(&last) if open_upvalue_slot(last) < stack_index => self.up_values.len(),
+            _ => self.up_values.partition_point(|&u| open_upvalue_slot(u) < stack_index),
+        };
+        match self.up_values.get(position) {
+            Some(&u) if open_upvalue_slot(u) == stack_index => u,
+            _ => {
+                let created_value = self.runtime.allocator().alloc(Upvalue::new_with_location(Location::Stack(stack_index)));
+                self.up_values.insert(position, created_value);
+                created_value
+            }
         }
     }
 
     #[inline(always)]
     fn call_value(&mut self, arg_count: usize, value: Value) -> Result<()> {
-        let arg_count = arg_count as usize;
-        let start_index = self.stack_top - 1 - arg_count;
+        let start_index = self.stack.len() - 1 - arg_count;
         if value.is_object() {
             let object = value.as_object();
             match object.object_type {
                 ObjectType::Closure(c) => {
-                        self.check_arguments(&c.function.name.unwrap(), c.function.arity,arg_count)?;
+                        // Only the script has no name, it can only be called by hand written bytecode
+                        self.check_arguments(c.function.name.as_ref().map_or("script", |name| name), c.function.arity,arg_count)?;
                         self.push_closure_to_call_frame(c, start_index)
                     }
                    ObjectType::Class(class) => {
-                        let methods = class.methods;
-                        let fields = self.allocator.alloc(Cache::new());
-                        let instance = self.allocator.alloc(Instance::new(class, fields));
-                        let receiver = Value::object(Object::new_gc_object(ObjectType::Instance(instance), &self.allocator));
-                        // TODO preallocate this;
-                        let init = self.allocator.alloc_interned_str("init");
-                        if let Some(init) = methods.get(init) {
+                        // Room for the fields set by init, see UserDefinedFunction::field_count
+                        let fields = Fields::with_capacity(class.init.map_or(0, |init| init.function.field_count), self.runtime.allocator());
+                        let instance = self.runtime.allocator().alloc(Instance::new(class, fields));
+                        let receiver = Value::object(Object::new_gc_object(ObjectType::Instance(instance), self.runtime.allocator()));
+                        if let Some(init) = class.init {
                             self.check_arguments(&init.function.name.unwrap(), init.function.arity, arg_count)?;
                             // set the receiver at start index for the constructor;
                             self.set_stack_mut(
@@ -709,17 +1409,17 @@ impl<'a> VirtualMachine<'a> {
                     ObjectType::BoundMethod(b) => {
                         let closure = b.1;
                         self.check_arguments(&closure.function.name.unwrap(), closure.function.arity, arg_count)?;
-                        // set the receiver at start index for the constructor;
+                        // the bound instance is the receiver ('this') of the method
                         self.set_stack_mut(
                             start_index,
-                            value
+                            Value::object(b.0)
                         );
                         self.push_closure_to_call_frame(closure, start_index)?;
                         Ok(())
                     }
                     ObjectType::NativeFunction(f) => {
                         self.check_arguments(&f.name, f.arity, arg_count)?;
-                        self.call_native_function(&f, arg_count, start_index)?;
+                        self.call_native_function(&f, start_index + 1..start_index + 1 + arg_count, start_index)?;
                         Ok(())
                     }
                     _ => bail!(self.runtime_error(&format!(
@@ -742,24 +1442,41 @@ impl<'a> VirtualMachine<'a> {
         closure: GCObjectOf<Closure>,
         fn_start_stack_index: usize,
     ) -> Result<()> {
+        self.check_frame(closure, fn_start_stack_index)?;
         self.push_to_call_frame(CallFrame::new(fn_start_stack_index, closure));
         Ok(())
     }
 
+    /// Fails if the frame of the closure (see [Chunk::max_stack]) does not fit on the stack or if the run is over its
+    /// instruction limit (see [VirtualMachine::set_max_instructions]), checked on every call (and loop iteration)
+    #[inline(always)]
+    fn check_frame(&self, closure: GCObjectOf<Closure>, fn_start_stack_index: usize) -> Result<()> {
+        if fn_start_stack_index + closure.function.chunk.max_stack > STACK_SIZE {
+            bail!(self.runtime_error(&format!("Stack overflow, stack size = {}", STACK_SIZE)))
+        }
+        self.check_instruction_limit()
+    }
+
+    #[inline(always)]
+    fn check_instruction_limit(&self) -> Result<()> {
+        if self.instructions > self.instruction_limit {
+            bail!(self.runtime_error(&format!("Instruction limit of {} exceeded", self.max_instructions.unwrap_or_default())))
+        }
+        Ok(())
+    }
+
     fn call_native_function(
         &mut self,
         native_function: &NativeFunction,
-        arg_count: usize,
+        arguments: Range<usize>,
         fn_start_stack_index: usize,
     ) -> Result<()> {
-        let mut arguments = Vec::new();
-        let arg_start_index = fn_start_stack_index + 1;
-        let arg_end_index = arg_start_index + arg_count;
-        for v in &self.stack[arg_start_index..arg_end_index] {
-            arguments.push(*v);
-        }
-        let result = native_function.call(arguments, &self.allocator);
-        self.stack_top = fn_start_stack_index + 1;
+        // The arguments are borrowed from the stack, the natives only get the runtime (not the stack) mutably
+        let result = match native_function.call(self.stack.slice(arguments), &mut self.runtime) {
+            Ok(v) => v,
+            Err(e) => bail!(self.runtime_error(&e.to_string())),
+        };
+        self.stack.truncate(fn_start_stack_index + 1);
         self.set_stack_mut(fn_start_stack_index, result);
         Ok(())
     }
@@ -776,8 +1493,8 @@ impl<'a> VirtualMachine<'a> {
     }
 
     #[inline(always)]
-    fn read_string(&mut self, chunk:  &Chunk, ip: &mut usize) -> Result<GCObjectOf<Box<str>>> {
-        let constant = self.read_constant(chunk, ip)?;
+    fn read_string(&mut self, chunk:  &Chunk) -> Result<GCObjectOf<Box<str>>> {
+        let constant = self.read_constant(chunk)?;
         let o = constant.as_object();
         if let ObjectType::String(s) = o.object_type {
             return Ok(s)
@@ -786,8 +1503,8 @@ impl<'a> VirtualMachine<'a> {
     }
 
     #[inline(always)]
-    fn read_function(&mut self, chunk:  &Chunk, ip: &mut usize) -> Result<GCObjectOf<UserDefinedFunction>> {
-        let constant = self.read_constant(chunk, ip)?;
+    fn read_function(&mut self, chunk:  &Chunk) -> Result<GCObjectOf<UserDefinedFunction>> {
+        let constant = self.read_constant(chunk)?;
         let o = constant.as_object();
         if let ObjectType::Function(s) = o.object_type {
             return Ok(s)
@@ -796,38 +1513,45 @@ impl<'a> VirtualMachine<'a> {
     }
 
     fn runtime_error(&self, message: &str) -> ErrorKind {
+        if self.call_frames.is_empty() {
+            // A call from the host (see VirtualMachine::call) failed before it started running
+            return ErrorKind::RuntimeError(message.to_string());
+        }
         let mut error_buf = vec![];
         writeln!(error_buf, "{}", message).expect("Write failed");
-        let all_call_frames = (&self.call_frames).iter().rev();
-        for frame in all_call_frames {
+        let all_call_frames = self.call_frames.iter().rev();
+        for (i, frame) in all_call_frames.enumerate() {
             let function = *frame.closure.function;
-            let fun_name = &function.to_string();
-            let ip = frame.ip;
-            let line_num = function.chunk.lines[ip];
-            writeln!(error_buf, "[line {}] in {}", line_num, fun_name)
-                .expect("Write failed")
+            let fun_name = &function.trace_name();
+            // The ip of the current frame is not stored in it, it is past the instruction (at least its opcode) that failed or called
+            let ip = if i == 0 { self.ip } else { frame.ip };
+            let line_num = function.chunk.line_at(ip.saturating_sub(1));
+            match function.chunk.source {
+                Some(source) => writeln!(error_buf, "[{}:{}] in {}", source.as_ref(), line_num, fun_name),
+                None => writeln!(error_buf, "[line {}] in {}", line_num, fun_name),
+            }
+            .expect("Write failed")
         }
-        if self.stack_top < STACK_SIZE {
+        if !self.stack.is_full() {
             // We print stack only if it is not stack overflow
             error!(
                 "Error at function= {}, ip ={}, stack ={:?}",
                 &self
                     .current_function()
                     
-                    .to_string(),
-                self.ip(),
+                    .trace_name(),
+                self.ip,
                 self.sanitized_full_stack()
             );
         }
         let chunk = self.current_chunk();
-        let line = chunk.lines[self.ip()];
+        let line = chunk.line_at(self.ip.saturating_sub(1));
         runtime_vm_error(line, &utf8_to_string(&error_buf))
     }
 
     #[inline(always)]
     fn peek_at(&self, distance: usize) -> Value {
-        let top = self.stack_top;
-        self.get_value_from_stack(top - 1 - distance)
+        self.stack.peek(distance)
     }
 
     #[inline(always)]
@@ -850,31 +1574,105 @@ impl<'a> VirtualMachine<'a> {
         Ok(())
     }
 
+    /// Divides the two numbers on top of the stack as IEEE 754 doubles: `1 / 0` is inf, `-1 / 0` is -inf and `0 / 0` is NaN
+    /// (which is not equal, less or greater than any number, itself included). In strict mode dividing by zero fails
+    fn divide(&mut self) -> Result<()> {
+        let (left, right) = (self.peek_at(1), self.peek_at(0));
+        if left.is_number() && right.is_number() && right.as_number() == 0.0 && self.optional_args.as_ref().is_some_and(|args| args.strict) {
+            bail!(self.runtime_error("Division by zero."))
+        }
+        self.binary_op(|a, b| Value::number(a / b))
+    }
+
+    /// Replaces the two numbers on top of the stack with the range between them
+    fn range(&mut self, inclusive: bool) -> Result<()> {
+        let (start, end) = (self.peek_at(1), self.peek_at(0));
+        if !(start.is_number() && end.is_number()) {
+            bail!(self.runtime_error("Range bounds must be numbers."))
+        }
+        let range = evie_memory::objects::Range { start: start.as_number(), end: end.as_number(), inclusive };
+        let range = self.runtime.allocator().alloc(range);
+        let range = Value::object(Object::new_gc_object(ObjectType::Range(range), self.runtime.allocator()));
+        self.pop_from_stack();
+        self.pop_from_stack();
+        self.push_to_stack(range);
+        Ok(())
+    }
+
+    /// Replaces the two values on top of the stack with the result of the comparison, for [Opcode::CompareJumpIfFalse]
+    #[inline(always)]
+    fn compare(&mut self, comparison: Opcode) -> Result<bool> {
+        let result = match comparison {
+            Opcode::EqualEqual => self.equals(),
+            Opcode::BangEqual => !self.equals(),
+            _ => {
+                let (left, right) = (self.peek_at(1), self.peek_at(0));
+                if !(left.is_number() && right.is_number()) {
+                    bail!(self.runtime_error("Can perform binary operations only on numbers."))
+                }
+                let (left, right) = (left.as_number(), right.as_number());
+                self.pop_from_stack();
+                self.pop_from_stack();
+                match comparison {
+                    Opcode::Greater => left > right,
+                    Opcode::GreaterEqual => left >= right,
+                    Opcode::Less => left < right,
+                    Opcode::LessEqual => left <= right,
+                    _ => unreachable!("VM BUG: {} is not a comparison", comparison),
+                }
+            }
+        };
+        self.push_to_stack(Value::bool(result));
+        Ok(result)
+    }
+
     fn add(&mut self) -> Result<()> {
         let (left, right) = (self.peek_at(1), self.peek_at(0));
         if left.is_number() && right.is_number() {
             self.binary_op(|a, b| Value::number(a + b))?;
-            Ok(())
-        } else if left.is_object() && right.is_object() {
-            if let (ObjectType::String(l), ObjectType::String(r)) = (left.as_object().object_type, right.as_object().object_type) {
-                let mut concatenated_string = String::new();
-                concatenated_string.push_str(&l);
-                concatenated_string.push_str(&r);
+            return Ok(());
+        }
+        if left.is_object() && right.is_object() {
+            if let Some(sv) = self.concatenate(left.as_object().object_type, right.as_object().object_type) {
                 self.pop_from_stack();
                 self.pop_from_stack();
-                let sv = Value::object(self.allocator.alloc_interned_object(self.allocator.alloc_interned_str(concatenated_string)));
                 self.push_to_stack(sv);
-                Ok(())
-            } else {
-                Ok(())
+                return Ok(());
             }
-        } else {
-            bail!(self.runtime_error(&format!(
-                "Add can be perfomed only on numbers or strings, got '{}' and '{}'",
-                left,
-                right,
-            )))
         }
+        bail!(self.runtime_error(&format!(
+            "Add can be perfomed only on numbers or strings, got '{}' and '{}'",
+            left,
+            right,
+        )))
+    }
+
+    /// Concatenates two strings, None if either is not a string. Results up to the allocator's intern limit are interned Strings,
+    /// longer ones [Rope]s, so that appending to a string in a loop only copies the appended text (and not the whole string every time).
+    fn concatenate(&mut self, left: ObjectType, right: ObjectType) -> Option<Value> {
+        let r = right.as_str()?;
+        let allocator = self.runtime.allocator();
+        let rope = match left {
+            // Appending to the rope's own buffer would move the text being appended
+            ObjectType::Rope(mut l) if l.is_at_end() && !matches!(right, ObjectType::Rope(r) if r.buffer.as_ptr() == l.buffer.as_ptr()) => {
+                l.buffer.push_str(r);
+                allocator.resize(l.buffer);
+                Rope::new(l.buffer, l.start, l.buffer.len() - l.start)
+            }
+            _ => {
+                let l = left.as_str()?;
+                let length = l.len() + r.len();
+                if length <= allocator.intern_limit() {
+                    return Some(Value::object(allocator.alloc_string(format!("{}{}", l, r))));
+                }
+                // Room to append as much again before the buffer grows
+                let mut buffer = String::with_capacity(2 * length);
+                buffer.push_str(l);
+                buffer.push_str(r);
+                Rope::new(allocator.alloc(buffer), 0, length)
+            }
+        };
+        Some(Value::object(Object::new_gc_object(ObjectType::Rope(allocator.alloc(rope)), allocator)))
     }
 
     #[inline(always)]
@@ -892,15 +1690,138 @@ impl<'a> VirtualMachine<'a> {
 
     #[inline(always)]
     fn push_to_stack(&mut self, value: Value) {
-        assert!(self.stack_top < STACK_SIZE, "{}", self.runtime_error(&format!("Stack overflow, stack size = {}, index = {}", STACK_SIZE, self.stack_top)));
-        self.stack[self.stack_top] = value;
-        self.stack_top += 1;
+        assert!(!self.stack.is_full(), "{}", self.runtime_error(&format!("Stack overflow, stack size = {}", STACK_SIZE)));
+        self.stack.push(value);
     }
     #[inline(always)]
     fn pop_from_stack(&mut self) -> Value {
-        self.stack_top -= 1;
-        assert!(self.stack_top < STACK_SIZE);
-        self.stack[self.stack_top]
+        self.stack.pop()
+    }
+
+    /// Runs a garbage collection, freeing every object that is not reachable
+    pub fn gc_collect(&mut self) {
+        self.collect_garbage(&[]);
+    }
+
+    /// Writes every object allocated by this VM as a line of JSON (see [evie_memory::ObjectAllocator::heap_dump]),
+    /// `reachable` objects are the ones a collection would keep
+    pub fn heap_dump(&mut self, writer: &mut dyn Write) -> Result<()> {
+        self.with_roots(&[], |runtime, mark_roots| runtime.allocator().heap_dump(|tracer| {
+            mark_roots(tracer);
+            runtime.trace(tracer);
+        }, writer))?;
+        Ok(())
+    }
+
+    /// Saves the globals and the objects reachable from them (e.g. to save a game), see [evie_memory::snapshot]
+    pub fn snapshot(&self) -> Result<Vec<u8>> {
+        self.runtime.snapshot()
+    }
+
+    /// Restores a [VirtualMachine::snapshot] taken by a VM that ran the same scripts, in the same order and with
+    /// snapshots enabled, as this one. The globals of the snapshot are (re)defined, see [evie_memory::snapshot]
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        self.runtime.restore(snapshot)
+    }
+
+    /// Returns the [GcStats] of this VM
+    pub fn gc_stats(&self) -> GcStats {
+        self.runtime.allocator().stats()
+    }
+
+    /// The live objects of this VM by type, see [ObjectAllocator::allocation_stats]
+    pub fn allocation_stats(&self) -> Vec<TypeStats> {
+        self.runtime.allocator().allocation_stats()
+    }
+
+    /// Strings created at runtime (e.g. by concatenation) up to `intern_limit` bytes are interned,
+    /// making `==` on them a pointer comparison. Longer ones are compared by their text.
+    pub fn set_intern_limit(&mut self, intern_limit: usize) {
+        self.runtime.allocator().set_intern_limit(intern_limit);
+    }
+
+    /// Caps the bytes the objects of the VM take, the text of the strings included (see
+    /// [ObjectAllocator::set_max_heap_bytes]), a script that needs more fails with an "out of memory" [ErrorKind::RuntimeError] instead of growing the process. None (the default) is
+    /// unbounded
+    pub fn set_max_heap_bytes(&mut self, max: Option<usize>) {
+        self.runtime.allocator().set_max_heap_bytes(max);
+    }
+
+    /// Enables (the default) or disables superinstructions for the code interpreted from now on
+    pub fn set_superinstructions(&mut self, enabled: bool) {
+        self.superinstructions = enabled;
+    }
+
+    /// Enables or disables (the default) dead function elimination for the code interpreted from now on.
+    /// Every interpreted source is a whole program then, a function it does not use is not defined for the next one.
+    pub fn set_eliminate_dead_functions(&mut self, enabled: bool) {
+        self.eliminate_dead_functions = enabled;
+    }
+
+    /// Enables or disables (the default) profiling of the code interpreted from now on: the calls of the functions and
+    /// the reads of their constants are counted, see [VirtualMachine::last_profile]
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiler = enabled.then(Profiler::default);
+    }
+
+    /// The [Profile] of the last [VirtualMachine::interpret] (whether it succeeded or not) if profiling is enabled
+    pub fn last_profile(&self) -> Option<&Profile> {
+        self.last_profile.as_ref()
+    }
+
+    /// The code interpreted from now on is compiled for the profile (see [Compiler::set_profile]), it must have been
+    /// recorded for the same source. The compilation cache is not used then
+    pub fn set_profile(&mut self, profile: Option<Profile>) {
+        self.profile = profile;
+    }
+
+    /// Keeps the functions of the scripts interpreted from now on alive, so that the globals that refer to them can
+    /// be saved with [VirtualMachine::snapshot]. Off by default, the functions a session redefines (e.g. in the REPL)
+    /// would never be freed
+    pub fn set_snapshots(&mut self, enabled: bool) {
+        self.snapshots = enabled;
+    }
+
+    /// Caches the compilations of the last `capacity` sources interpreted from now on (None, the default, disables and
+    /// drops the cache): interpreting one of them again with the same options, e.g. the same line in the REPL or a
+    /// benchmark loop, runs its byte code without scanning and compiling it again. See [CompilationCache]
+    pub fn set_compilation_cache(&mut self, capacity: Option<usize>) {
+        self.compilation_cache = capacity.map(CompilationCache::new);
+    }
+
+    /// Limits the instructions of every [VirtualMachine::interpret] (or [VirtualMachine::call]), a run that goes over
+    /// fails with a runtime error instead of running forever (e.g. for untrusted scripts). None (the default) is
+    /// unbounded. The limit is checked on calls and loop iterations, a run can go slightly over it
+    pub fn set_max_instructions(&mut self, max: Option<u64>) {
+        self.max_instructions = max;
+    }
+
+    /// In stress mode the VM collects garbage before every instruction (slow, used to find GC bugs)
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.runtime.allocator().set_stress(stress);
+    }
+
+    fn collect_garbage(&mut self, function_caches: &[Cache<Value>]) {
+        self.with_roots(function_caches, |runtime, mark_roots| runtime.collect(mark_roots));
+    }
+
+    /// Calls `f` with the runtime and a function that marks the roots of the VM besides the runtime: they are borrowed
+    /// field by field, so that the runtime can collect with its allocator to itself (see [EvieRuntime::collect])
+    fn with_roots<R>(&mut self, function_caches: &[Cache<Value>], f: impl FnOnce(&mut EvieRuntime, &dyn Fn(&mut Tracer)) -> R) -> R {
+        let mark_roots = |tracer: &mut Tracer| {
+            self.stack.values().iter().for_each(|v| v.trace(tracer));
+            self.call_frames.iter().for_each(|f| f.closure.trace(tracer));
+            self.up_values.trace(tracer);
+            self.coroutines.iter().for_each(|running| running.coroutine.trace(tracer));
+            self.shadowed_globals.iter().for_each(|(name, previous)| {
+                name.trace(tracer);
+                previous.trace(tracer);
+            });
+            self.string_methods.trace(tracer);
+            function_caches.iter().for_each(|c| c.trace(tracer));
+            self.compilation_cache.trace(tracer);
+        };
+        f(&mut self.runtime, &mark_roots)
     }
 
     pub fn free(&mut self) {
@@ -928,17 +1849,14 @@ fn runtime_vm_error(line: usize, message: &str) -> ErrorKind {
     ErrorKind::RuntimeError(format!("Line: {}, message: {}", line, message))
 }
 
+// Numbers are equal as IEEE 754 doubles in both representations of values: NaN is not equal to itself and 0 is equal to -0
 #[cfg(feature="nan_boxed")]
 #[inline(always)]
 fn value_equals(l: Value, r: Value) -> bool {
-    l == r
+    // Value's PartialEq compares the numbers as doubles
+    l == r || (l.is_object() && r.is_object() && object_equals(l.as_object(), r.as_object()))
 }
 
-#[cfg(not(feature="nan_boxed"))]
-#[inline(always)]
-fn num_equals(l: f64, r: f64) -> bool {
-    (l - r).abs() < std::f64::EPSILON
-}
 #[cfg(not(feature="nan_boxed"))]
 #[inline(always)]
 fn value_equals(l: Value, r: Value) -> bool {
@@ -947,18 +1865,54 @@ fn value_equals(l: Value, r: Value) -> bool {
     } else if l.is_nil() && r.is_nil() {
         return true
     } else if l.is_number() && r.is_number() {
-        return num_equals(l.as_number(), r.as_number())
+        return l.as_number() == r.as_number()
     } else if l.is_object() && r.is_object() {
-        match (l.as_object().object_type,r.as_object().object_type) {
-            (ObjectType::String(l), ObjectType::String(r)) => {
-               return std::ptr::eq(l.as_ptr(), r.as_ptr()) || l == r
-            }
-            _ => return false
-        }
+        return object_equals(l.as_object(), r.as_object())
     }
     false
 }
 
+/// A coroutine that returned (or failed), nothing is kept of its run
+fn finish(mut coroutine: GCObjectOf<Coroutine>) {
+    coroutine.state = CoroutineState::Done;
+    coroutine.stack = Vec::new();
+    coroutine.frames = Vec::new();
+    coroutine.upvalues = Vec::new();
+}
+
+/// The stack slot of an open upvalue
+fn open_upvalue_slot(upvalue: GCObjectOf<Upvalue>) -> usize {
+    match upvalue.location {
+        Location::Stack(index) => index,
+        Location::Heap(_) => panic!("VM BUG: closed upvalue {:?} is still open", upvalue),
+    }
+}
+
+/// Strings and ranges are equal by value, bound methods are equal if they bind the same method to the same instance,
+/// all other objects (functions, closures, classes and instances) are equal only to themselves.
+fn object_equals(l: GCObjectOf<Object>, r: GCObjectOf<Object>) -> bool {
+    if std::ptr::eq(l.as_ptr(), r.as_ptr()) {
+        return true
+    }
+    match (l.object_type, r.object_type) {
+        // Strings are interned, equal Strings are the same object
+        (ObjectType::String(l), ObjectType::String(r)) => std::ptr::eq(l.as_ptr(), r.as_ptr()),
+        // Ropes (long strings) are not
+        (l, r) if l.as_str().is_some() && r.as_str().is_some() => l.as_str() == r.as_str(),
+        (ObjectType::Function(l), ObjectType::Function(r)) => std::ptr::eq(l.as_ptr(), r.as_ptr()),
+        (ObjectType::NativeFunction(l), ObjectType::NativeFunction(r)) => std::ptr::eq(l.as_ptr(), r.as_ptr()),
+        (ObjectType::Closure(l), ObjectType::Closure(r)) => std::ptr::eq(l.as_ptr(), r.as_ptr()),
+        (ObjectType::Class(l), ObjectType::Class(r)) => std::ptr::eq(l.as_ptr(), r.as_ptr()),
+        (ObjectType::Instance(l), ObjectType::Instance(r)) => std::ptr::eq(l.as_ptr(), r.as_ptr()),
+        (ObjectType::Coroutine(l), ObjectType::Coroutine(r)) => std::ptr::eq(l.as_ptr(), r.as_ptr()),
+        (ObjectType::Range(l), ObjectType::Range(r)) => *l == *r,
+        (ObjectType::BoundMethod(l), ObjectType::BoundMethod(r)) => {
+            std::ptr::eq(l.instance().as_ptr(), r.instance().as_ptr()) && std::ptr::eq(l.1.as_ptr(), r.1.as_ptr())
+        }
+        _ => false
+    }
+}
+
 #[inline(always)]
 fn is_falsey(value: &Value) -> bool {
     if value.is_bool() {
@@ -966,6 +1920,13 @@ fn is_falsey(value: &Value) -> bool {
     } else { value.is_nil() }
 }
 
+/// The integer the bitwise operators work on: truncated towards zero (and saturated), NaN is 0.
+/// The wrapping shifts take the count modulo 64.
+#[inline(always)]
+fn to_integer(n: f64) -> i64 {
+    n as i64
+}
+
 fn print_stack_value(value: Value, writer: &mut dyn Write) {
    opcodes::print_value(value, writer)
 }
@@ -973,12 +1934,15 @@ fn print_stack_value(value: Value, writer: &mut dyn Write) {
 #[cfg(test)]
 mod tests {
 
+    use std::collections::HashMap;
+
     use evie_common::{errors::*, utf8_to_string, print_error};
-    use evie_native::{clock, to_string};
+    use evie_native::{clock, to_string, type_of};
 
     use crate::vm::VirtualMachine;
+    use evie_memory::runtime::EvieRuntime;
 
-    use super::{define_native_fn};
+    use super::{define_native_fn, Args, Instance, NativeRegistry, Object, SourceSpan, Value, STACK_SIZE};
     
     #[test]
     fn vm_numeric_expressions() -> Result<()> {
@@ -1006,6 +1970,62 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn vm_bitwise_operators() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        print 6 & 3; // 2
+        print 6 | 3; // 7
+        print 6 ^ 3; // 5
+        print 1 << 4; // 16
+        print -16 >> 2; // -4
+        print 7.9 & 3; // 3, truncated
+        print -7.9 | 0; // -7
+        print 1 << 65; // 2, the count is modulo 64
+        print 1 + 2 << 1; // 6, shifts bind looser than +
+        print 1 | 2 == 3; // true, comparisons bind looser than |
+        print 1 | 6 & 3; // 3
+        print 5 ^ 1 | 8; // 12
+        var hash = 7;
+        var i = 0;
+        while (i < 3) {
+            hash = ((hash << 5) ^ (hash >> 2) ^ i) & 65535;
+            i = i + 1;
+        }
+        print hash; // 33828
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let error = vm.interpret("print \"a\" & 1;".to_string(), None).unwrap_err();
+        assert!(error.to_string().contains("only on numbers"), "{}", error);
+        drop(vm);
+        assert_eq!(
+            "2\n7\n5\n16\n-4\n3\n-7\n2\n6\ntrue\n3\n12\n33828\n",
+            utf8_to_string(&buf)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn vm_exponents() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        print 2 ** 10; // 1024
+        print 2 ** 3 ** 2; // 512, right associative
+        print -2 ** 2; // -4, ** binds tighter than -
+        print 2 ** -1; // 0.5
+        print 3 * 2 ** 2; // 12
+        print 1e3 + 2.5E-1; // 1000.25
+        print 4 ** 0.5 == 2; // true
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert!(vm.interpret("print nil ** 2;".to_string(), None).is_err());
+        drop(vm);
+        assert_eq!("1024\n512\n-4\n0.5\n12\n1000.25\ntrue\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
     #[test]
     fn vm_string_expressions() -> Result<()> {
         let mut buf = vec![];
@@ -1025,6 +2045,69 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn vm_string_concatenation_in_a_loop() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.set_gc_stress(true);
+        vm.set_intern_limit(16);
+        // Long strings are ropes sharing a buffer, `before` must not see what is appended to `s` afterwards
+        let source = r#"
+        var s = "";
+        var i = 0;
+        while (i < 100) {
+            s = s + "ab";
+            i = i + 1;
+        }
+        var before = s;
+        s = s + "c";
+        var other = before + "d";
+        print s.length();
+        print before.length();
+        print other.substring(198, 201);
+        print s.substring(199, 201);
+        print (s + s).length();
+        print before + "c" == s;
+        print before == s;
+        print s.char_at(200) == "c";
+        var short = "ab";
+        print short + short == "abab";
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("201\n200\nabd\nbc\n402\ntrue\nfalse\ntrue\ntrue\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_substrings() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.set_gc_stress(true);
+        vm.set_intern_limit(16);
+        // The long substrings of a rope are ropes of its buffer, appending to one must not change the others
+        let source = r#"
+        var s = "";
+        for (i in 0..50) s = s + "abcd";
+        var tail = s.substring(100, 200);
+        var middle = s.substring(50, 150);
+        tail = tail + "x";
+        var t = s + "y";
+        print s.length();
+        print tail.length();
+        print tail.substring(98, 101);
+        print t.substring(198, 201);
+        print middle == s.substring(50, 150);
+        print middle.substring(0, 4) == "cdab";
+        print middle + "!" == s.substring(50, 150) + "!";
+        var rest = s;
+        while (rest.length() > 20) rest = rest.substring(1, rest.length());
+        print rest;
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("200\n101\ncdx\ncdy\ntrue\ntrue\ntrue\nabcdabcdabcdabcdabcd\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
     #[test]
     fn vm_block() -> Result<()> {
         let mut buf = vec![];
@@ -1135,6 +2218,78 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn vm_deep_recursion() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        // Every caller continues where it left off, however often the call frames grow
+        let source = r#"
+        fun count(n) {
+            if (n == 0) return 0;
+            var r = count(n - 1);
+            return r + 1;
+        }
+        print count(300);
+        print count(3) + count(2);
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("300\n5\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_superinstructions() -> Result<()> {
+        let source = r#"
+        fun add(a, b) { return a + b; }
+        fun greet(name) { return "hello " + name + "!"; }
+        var i = 0;
+        var total = 0;
+        while (i < 5) {
+            total = add(total, i) + 1;
+            i = i + 1;
+        }
+        print total;
+        print add("a", "b");
+        print greet("evie");
+        print i == 5 and i != 4;
+        print 1 + (true and 2);
+        print 3 >= 3 or false;
+        "#;
+        for superinstructions in [true, false] {
+            let mut buf = vec![];
+            let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+            vm.set_superinstructions(superinstructions);
+            vm.interpret(source.to_string(), None)?;
+            assert!(vm.interpret("var s = \"s\"; if (s < 1) print s;".to_string(), None).is_err());
+            assert!(vm.interpret("fun f(a, b) { return a + b; } f(1, nil);".to_string(), None).is_err());
+            drop(vm);
+            assert_eq!("15\nab\nhello evie!\ntrue\n3\ntrue\n", utf8_to_string(&buf));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vm_eliminate_dead_functions() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.set_eliminate_dead_functions(true);
+        let source = r#"
+        fun unused() { print "unused"; }
+        fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        fun main() { print fib(10); }
+        var i = 0;
+        while (i < 2) { i = i + 1; }
+        main();
+        print i;
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let error = vm.interpret("unused();".to_string(), None).unwrap_err();
+        assert!(error.to_string().contains("Undefined variable 'unused'"), "{}", error);
+        drop(vm);
+        assert_eq!("55\n2\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
     #[test]
     fn vm_call_error_stack_trace() -> Result<()> {
         let mut buf = vec![];
@@ -1271,6 +2426,66 @@ mod tests {
         "#;
         vm.interpret(source.to_string(), None)?;
         assert_eq!("updated\n", utf8_to_string(&buf));
+
+        // Slots captured out of order (b, then a, then b again) still share one upvalue per slot
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        var globalSet;
+        var globalGet;
+        var globalA;
+
+        fun main() {
+          var a = 1;
+          var b = 2;
+
+          fun get() { print b; }
+          fun getA() { print a; }
+          fun set(value) { b = value; }
+
+          globalSet = set;
+          globalGet = get;
+          globalA = getA;
+        }
+
+        main();
+        globalSet(5);
+        globalGet();
+        globalA();
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("5\n1\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_local_function_hoisting() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        // The functions of a block can call the ones declared after them
+        let source = r#"
+        fun parity(n) {
+            fun isEven(n) { if (n == 0) return true; return isOdd(n - 1); }
+            fun isOdd(n) { if (n == 0) return false; return isEven(n - 1); }
+            return isEven(n);
+        }
+        print parity(10);
+        print parity(7);
+        {
+            var suffix = "!";
+            fun first() { return second() + suffix; }
+            fun second() { return "second"; }
+            print first();
+        }
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let error = vm.interpret("fun f() { g(); fun g() {} }".to_string(), None).unwrap_err();
+        assert!(error.to_string().ends_with("Error at <g>: message: Can't use a local function before its declaration"), "{}", error);
+        // A function called before the declaration of a function it uses has run finds nil
+        let error = vm.interpret("fun f() { fun a() { return b(); } a(); fun b() {} } f();".to_string(), None).unwrap_err();
+        assert!(error.to_string().contains("can only call a function/closure, constructor or a class method, got 'nil'"), "{}", error);
+        drop(vm);
+        assert_eq!("true\nfalse\nsecond!\n", utf8_to_string(&buf));
         Ok(())
     }
 
@@ -1293,27 +2508,34 @@ mod tests {
     }
 
     #[test]
-    fn vm_class_methods() -> Result<()> {
+    fn vm_remove_and_list_fields() -> Result<()> {
         let mut buf = vec![];
         let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        for (name, arity, native_fn) in evie_native::object::natives() {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
         let source = r#"
-        class Scone {
-            topping(first, second) {
-              print "scone with " + first + " and " + second;
-            }
-          }
-          
-          var scone = Scone();
-          scone.topping("berries", "cream");
+        class Pair {}
 
+        var pair = Pair();
+        pair.first = 1;
+        pair.second = 2;
+        print fields(pair);
+        print remove_field(pair, "first");
+        print fields(pair);
+        print remove_field(pair, "first");
+        pair.first = 3;
+        print fields(pair);
         "#;
         vm.interpret(source.to_string(), None)?;
-        assert_eq!("scone with berries and cream\n", utf8_to_string(&buf));
+        // Fields that were never set are not found
+        assert!(vm.interpret("print Pair().first;".to_string(), None).is_err());
+        assert_eq!("first, second\n1\nsecond\nnil\nsecond, first\n", utf8_to_string(&buf));
         Ok(())
     }
 
     #[test]
-    fn vm_bound_methods() -> Result<()> {
+    fn vm_class_methods() -> Result<()> {
         let mut buf = vec![];
         let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
         let source = r#"
@@ -1324,8 +2546,7 @@ mod tests {
           }
           
           var scone = Scone();
-          var topping = scone.topping;
-          topping("berries", "cream");
+          scone.topping("berries", "cream");
 
         "#;
         vm.interpret(source.to_string(), None)?;
@@ -1334,58 +2555,784 @@ mod tests {
     }
 
     #[test]
-    fn vm_class_initializer_and_this() -> Result<()> {
+    fn vm_invoke_inline_cache() -> Result<()> {
         let mut buf = vec![];
         let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
         let source = r#"
-        class Brunch {
-            init(food, drinks) {
-                this.food = food;
-                this.drinks = drinks;
-            }
+        class Cat { speak() { return "meow"; } name() { return "cat"; } }
+        class Dog { speak() { return "woof"; } }
+        fun speak(animal) { return animal.speak(); }
+        var animals = "";
+        var i = 0;
+        while (i < 4) {
+            // The call site in speak sees both classes, the cached method is only used for the same class
+            if (i < 2) animals = animals + speak(Cat()); else animals = animals + speak(Dog());
+            animals = animals + speak(Cat());
+            i = i + 1;
         }
-                  
-        var brunch = Brunch("eggs", "coffee");
-        
-        print brunch.food + " and " + brunch.drinks;
-
+        print animals;
+        // A class declared again is another class
+        class Cat { speak() { return "purr"; } }
+        print speak(Cat());
         "#;
         vm.interpret(source.to_string(), None)?;
-        assert_eq!("eggs and coffee\n", utf8_to_string(&buf));
+        drop(vm);
+        assert_eq!("meowmeowmeowmeowwoofmeowwoofmeow\npurr\n", utf8_to_string(&buf));
+        Ok(())
+    }
 
+    #[test]
+    fn vm_property_inline_cache() -> Result<()> {
         let mut buf = vec![];
         let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        for (name, arity, native_fn) in evie_native::object::natives().into_iter().chain(evie_native::gc::natives()) {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
         let source = r#"
-        class Brunch {
-            init(food, drinks) {
-                this.food = food;
-                this.drinks = drinks;
-            }
-
-            set_dessert(item) {
-                this.dessert = item;
-                return this;
-            }
+        class Point { init(x, y) { this.x = x; this.y = y; } }
+        class Other { init(y) { this.y = y; this.x = 0; } }
+        fun sum(p) { return p.x + p.y; }
+        var total = 0;
+        var i = 0;
+        while (i < 4) {
+            // The get sites in sum see both shapes, the cached slot is only used for the same shape
+            if (i < 2) total = total + sum(Point(i, 10)); else total = total + sum(Other(100));
+            i = i + 1;
+            gc_collect();
         }
-                  
-        var brunch = Brunch("eggs", "coffee");
-
-        var brunch_with_dessert = brunch.set_dessert("cake");
-        
-        print brunch_with_dessert.food + " and " + brunch_with_dessert.drinks + " with " + brunch_with_dessert.dessert + " as dessert";
-
+        print total;
+        var p = Point(1, 2);
+        p.y = 5;
+        p.z = 3;
+        print sum(p) + p.z;
+        remove_field(p, "x");
+        p.x = 7;
+        print fields(p);
+        print sum(p);
         "#;
         vm.interpret(source.to_string(), None)?;
-        assert_eq!(
-            "eggs and coffee with cake as dessert\n",
-            utf8_to_string(&buf)
-        );
+        drop(vm);
+        assert_eq!("221\n9\ny, z, x\n12\n", utf8_to_string(&buf));
+        Ok(())
+    }
 
+    #[test]
+    fn vm_invoke_fields_and_chained_calls() -> Result<()> {
         let mut buf = vec![];
         let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
         let source = r#"
-        class Cake {
-    
+        class Builder {
+            init() { this.parts = ""; }
+            add(part) { this.parts = this.parts + part; return this; }
+            greet() { return "method"; }
+        }
+        print Builder().add("a").add("b").add("c").parts;
+        fun shout(s) { return s + "!"; }
+        var b = Builder();
+        b.format = shout;
+        b.curried = fun (x) { return fun (y) { return x + y; }; };
+        print b.format("hi");
+        print b.curried("x")("y");
+        var i = 0;
+        while (i < 3) {
+            // The same site sees an instance without the field, then with it, then without it again
+            var other = Builder();
+            if (i == 1) other.greet = fun () { return "field"; };
+            print other.greet();
+            i = i + 1;
+        }
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        drop(vm);
+        assert_eq!("abc\nhi!\nxy\nmethod\nfield\nmethod\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_profile_guided_layout() -> Result<()> {
+        let source = r#"
+        class Counter { init() { this.count = 0; } add(n) { this.count = this.count + n; return this; } }
+        fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        fun unused() { return "never called"; }
+        var counter = Counter();
+        var i = 0;
+        while (i < 10) { counter.add(i).add(1); i = i + 1; }
+        print counter.count;
+        print fib(10);
+        "#;
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.set_profiling(true);
+        vm.interpret(source.to_string(), None)?;
+        let profile = vm.last_profile().cloned().unwrap();
+        drop(vm);
+        assert_eq!("55\n55\n", utf8_to_string(&buf));
+        let calls: Vec<_> = profile.functions.iter().map(|f| (f.name.as_deref(), f.calls)).collect();
+        assert_eq!(vec![(None, 1), (Some("init"), 1), (Some("add"), 20), (Some("fib"), 177), (Some("unused"), 0)], calls);
+        // The names of the property read and set by add (a constant each)
+        assert_eq!(vec![20, 20], profile.functions[2].constant_reads);
+
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.set_profile(Some(profile));
+        vm.interpret(source.to_string(), None)?;
+        // Another script
+        let error = vm.interpret("fun other() {}".to_string(), None).unwrap_err();
+        drop(vm);
+        assert_eq!("55\n55\n", utf8_to_string(&buf));
+        assert!(error.to_string().starts_with("The profile does not match the script"));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_for_in_loops() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Countdown {
+            init(from) { this.from = from; }
+            iter() { return CountdownIterator(this.from); }
+        }
+        class CountdownIterator {
+            init(current) { this.current = current; }
+            next() {
+                if (this.current == 0) return nil;
+                this.current = this.current - 1;
+                return this.current + 1;
+            }
+        }
+        var total = 0;
+        for (n in Countdown(3)) {
+            for (m in Countdown(n)) total = total + m;
+            print n;
+        }
+        print total;
+        // A variable for each element, the closures capture their own
+        var first; var last;
+        for (n in Countdown(3)) {
+            if (n == 3) first = fun () { return n; };
+            last = fun () { return n; };
+        }
+        print first() + last();
+        fun find(limit) { for (n in Countdown(10)) if (n < limit) return n; }
+        print find(4);
+        for (n in Countdown(0)) print "never";
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let error = vm.interpret("for (n in 10) print n;".to_string(), None).unwrap_err();
+        drop(vm);
+        assert_eq!("3\n2\n1\n10\n4\n3\n", utf8_to_string(&buf));
+        assert!(error.to_string().contains("Undefined method 'iter'"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_global_constants() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        fun early() { return LIMIT; }
+        fun reset() { LIMIT = 0; }
+        const LIMIT = 10;
+        const DOUBLE = LIMIT * 2;
+        fun late() { var LIMIT = 1; return LIMIT + DOUBLE; }
+        print early() + late();
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        // The next inputs (e.g. of a REPL) read it from its slot too
+        vm.interpret("print LIMIT + DOUBLE;".to_string(), None)?;
+        let compile_errors: Vec<_> = [
+            "LIMIT = 1;",
+            "fun f() { LIMIT = 1; }",
+            "var LIMIT = 1;",
+            "const DOUBLE = 1;",
+            "{ const LOCAL = 1; }",
+            "fun f() { const LOCAL = 1; }",
+            "const MISSING;",
+        ]
+        .into_iter()
+        .map(|source| vm.interpret(source.to_string(), None).unwrap_err().to_string())
+        .collect();
+        let runtime_error = vm.interpret("reset();".to_string(), None).unwrap_err();
+        vm.interpret("print LIMIT;".to_string(), None)?;
+        drop(vm);
+        assert_eq!("31\n30\n10\n", utf8_to_string(&buf));
+        for (error, expected) in compile_errors.iter().zip([
+            "Can't assign to a constant",
+            "Can't assign to a constant",
+            "Already a constant with this name",
+            "Already a constant with this name",
+            "Constants can only be declared at the top level",
+            "Constants can only be declared at the top level",
+            "Expect '=' after constant name",
+        ]) {
+            assert!(error.contains(expected), "{}", error);
+        }
+        assert!(runtime_error.to_string().contains("Can't assign to the constant 'LIMIT'"), "{}", runtime_error);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_number_equality() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        print 0.1 + 0.2 == 0.3;
+        print 1e-17 == 0;
+        print 0 == -0;
+        var nan = 0 / 0;
+        print nan == nan;
+        print nan != nan;
+        print 9007199254740992 == 9007199254740993;
+        print 1e20 == 1e20 + 16384;
+        if (nan == nan) print "never";
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        drop(vm);
+        // TODO: numbers as the keys of maps, once evie has maps
+        assert_eq!("false\nfalse\ntrue\nfalse\ntrue\ntrue\nfalse\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_loop_closures() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Closures {
+            init() { this.count = 0; }
+            add(f) {
+                if (this.count == 0) this.f0 = f;
+                if (this.count == 1) this.f1 = f;
+                if (this.count == 2) this.f2 = f;
+                this.count = this.count + 1;
+            }
+            print_all() { print this.f0() + " " + this.f1() + " " + this.f2(); }
+        }
+        // The loop variable is scoped to its iteration
+        var closures = Closures();
+        for (i in 0..3) closures.add(fun () { return to_string(i); });
+        closures.print_all();
+        // Assigning it in the body only changes the variable of that iteration
+        closures = Closures();
+        for (i in 0..3) {
+            closures.add(fun () { return to_string(i); });
+            i = i * 10;
+        }
+        closures.print_all();
+        // So are the variables declared in the body of a while loop, a variable declared outside is shared
+        closures = Closures();
+        var n = 0;
+        while (n < 3) {
+            var copy = n;
+            closures.add(fun () { return to_string(copy) + ":" + to_string(n); });
+            n = n + 1;
+        }
+        closures.print_all();
+        "#;
+        define_native_fn("to_string", 1, &mut vm, to_string);
+        vm.interpret(source.to_string(), None)?;
+        drop(vm);
+        assert_eq!("0 1 2\n0 10 20\n0:3 1:3 2:3\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_ranges() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        define_native_fn("type", 1, &mut vm, type_of);
+        let source = r#"
+        var total = 0;
+        for (i in 0..5) total = total + i;
+        print total;
+        for (i in 1..=3) print i;
+        var n = 2;
+        for (i in n + 1..n) print "never";
+        var range = 1..n * 2;
+        print range;
+        print 1..=3;
+        print range == (1..4);
+        print range == (1..=4);
+        print type(range) + " " + type(range.iter());
+        var iterator = range.iter();
+        print iterator.next() + iterator.next();
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let bounds = vm.interpret("print 1..nil;".to_string(), None).unwrap_err();
+        let method = vm.interpret("(0..1).reverse();".to_string(), None).unwrap_err();
+        drop(vm);
+        assert_eq!("10\n1\n2\n3\n1..4\n1..=3\ntrue\nfalse\nrange iterator\n3\n", utf8_to_string(&buf));
+        assert!(bounds.to_string().contains("Range bounds must be numbers."), "{}", bounds);
+        assert!(method.to_string().contains("Undefined method 'reverse' on 0..1"), "{}", method);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_coroutines() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        for (name, arity, native_fn) in evie_native::coroutine::natives().into_iter().chain(evie_native::gc::natives()) {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        let source = r#"
+        fun count(n) {
+            for (i in 0..n) yield i;
+        }
+        // The arguments of the first resume are the arguments of the function, a coroutine is an iterable
+        var counter = coroutine(count);
+        print counter.resume(3);
+        for (i in counter) print i;
+        print counter.is_done();
+        // The value a coroutine is resumed with is the value of the yield
+        fun sum() {
+            var total = 0;
+            while (true) {
+                var n = yield total;
+                if (n == nil) return total;
+                total = total + n;
+            }
+        }
+        var adder = coroutine(sum);
+        adder.resume();
+        adder.resume(5);
+        gc_collect();
+        print adder.resume(10);
+        print adder.resume();
+        // A closure sees the local it captured while the coroutine is suspended
+        fun shared() {
+            var count = 0;
+            fun increment() { count = count + 1; return count; }
+            yield increment;
+            yield count;
+        }
+        var task = coroutine(shared);
+        var increment = task.resume();
+        increment();
+        print task.resume() + increment();
+        // It yields from the functions it calls, it resumes other coroutines
+        fun deep(n) { if (n == 0) { yield "bottom"; return "up"; } return deep(n - 1); }
+        var outer = coroutine(fun () {
+            var inner = coroutine(deep);
+            yield inner.resume(3);
+            yield inner.resume();
+        });
+        for (step in outer) print step;
+        class Walker {
+            init(name) { this.name = name; }
+            walk(steps) { for (i in 1..=steps) yield this.name + "."; }
+        }
+        var walk = coroutine(Walker("w").walk);
+        print walk.resume(2) + walk.resume();
+        print coroutine(count);
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let errors: Vec<String> = [
+            "fun f() { yield 1; } f();",
+            "var c = coroutine(fun () { yield 1; }); c.resume(); c.resume(); c.resume();",
+            "var c = coroutine(fun () { c.resume(); }); c.resume();",
+            "var c = coroutine(fun () { yield 1; }); c.resume(); c.resume(1, 2);",
+            "coroutine(1);",
+        ]
+        .iter()
+        .map(|source| vm.interpret(source.to_string(), None).unwrap_err().to_string())
+        .collect();
+        drop(vm);
+        assert_eq!("0\n1\n2\ntrue\n15\n15\n3\nbottom\nup\nw.w.\n<coroutine of <fn count>>\n", utf8_to_string(&buf));
+        assert!(errors[0].contains("Can only yield inside a coroutine"), "{}", errors[0]);
+        assert!(errors[1].contains("Can't resume a finished coroutine"), "{}", errors[1]);
+        assert!(errors[2].contains("Can't resume a running coroutine"), "{}", errors[2]);
+        assert!(errors[3].contains("Expected 0 or 1 arguments but got 2 for <fn resume>"), "{}", errors[3]);
+        assert!(errors[4].contains("Expected a function for coroutine, got '1'"), "{}", errors[4]);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_function_introspection() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        for (name, arity, native_fn) in evie_native::function::natives().into_iter().chain(evie_native::natives()) {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        let source = r#"
+        fun add(a, b) { return a + b; }
+        class Point {
+            init(x, y) { this.x = x; this.y = y; }
+            move(dx, dy) { return Point(this.x + dx, this.y + dy); }
+        }
+        var p = Point(1, 2);
+        var move = p.move;
+        print fn_name(add) + " " + to_string(fn_arity(add));
+        print fn_name(move) + " " + to_string(fn_arity(move));
+        print fn_name(fun (x) { return x; }) + " " + to_string(fn_arity(fun (x) { return x; }));
+        print fn_name(type) + " " + to_string(fn_arity(type));
+        print method_receiver(move) == p;
+        print method_receiver(move).x;
+        print method_receiver(add);
+        // A helper that checks the arguments before calling
+        fun call2(f, a, b) {
+            if (fn_arity(f) != 2) return fn_name(f) + " does not take 2 arguments";
+            return f(a, b);
+        }
+        print call2(add, 1, 2);
+        print call2(type, 1, 2);
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let error = vm.interpret("fn_name(Point);".to_string(), None).unwrap_err().to_string();
+        drop(vm);
+        assert_eq!(
+            "add 2\nmove 2\nanonymous 1\ntype 1\ntrue\n1\nnil\n3\ntype does not take 2 arguments\n",
+            utf8_to_string(&buf)
+        );
+        assert!(error.contains("Expected a function for fn_name, got '<class Point>'"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_docstrings() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        for (name, arity, native_fn) in evie_native::function::natives().into_iter().chain(evie_native::gc::natives()).chain(evie_native::natives()) {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        let source = r#"
+        fun area(r) {
+            "The area of a circle of radius r";
+            return 3 * r * r;
+        }
+        class Circle {
+            "A circle"
+            init(r) { "Creates a circle"; this.r = r; }
+            area() { return area(this.r); }
+        }
+        class Unit {}
+        gc_collect();
+        print doc(area);
+        print area(2);
+        print doc(Circle);
+        print doc(Circle(1).init);
+        print doc(Circle(1).area);
+        print doc(Unit);
+        print doc(fun () { "Anonymous"; });
+        print doc(clock);
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let error = vm.interpret("doc(Circle(1));".to_string(), None).unwrap_err().to_string();
+        drop(vm);
+        assert_eq!("The area of a circle of radius r\n12\nA circle\nCreates a circle\nnil\nnil\nAnonymous\nnil\n", utf8_to_string(&buf));
+        assert!(error.contains("Expected a function or a class for doc, got '<instance of Circle>'"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_bound_methods() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Scone {
+            topping(first, second) {
+              print "scone with " + first + " and " + second;
+            }
+          }
+          
+          var scone = Scone();
+          var topping = scone.topping;
+          topping("berries", "cream");
+
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("scone with berries and cream\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_static_methods_and_class_fields() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Math {
+            static add(a, b) {
+                return a + b;
+            }
+            static twice(a) {
+                return Math.add(a, a);
+            }
+            value() {
+                return Math.base;
+            }
+        }
+        Math.base = 10;
+        print Math.add(1, 2);
+        print Math.twice(Math.base);
+        print Math().value();
+        var add = Math.add;
+        print add(3, 4);
+        Math.base = Math.base + 1;
+        print Math.base;
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("3\n20\n10\n7\n11\n", utf8_to_string(&buf));
+
+        let mut vm = VirtualMachine::new();
+        match vm.interpret("class Math {} Math.sub(1, 2);".to_string(), None) {
+            Ok(_) => panic!("Expected to fail"),
+            Err(e) => assert_eq!(
+                "Runtime Error: Line: 1, message: Undefined static method 'sub' on <class Math>\n[line 1] in <fn script>\n",
+                e.to_string()
+            ),
+        }
+        match vm.interpret("class A { static a() { return this; } }".to_string(), None) {
+            Ok(_) => panic!("Expected to fail"),
+            Err(e) => assert!(e.to_string().contains("Can't use 'this' in a static method"), "{}", e),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vm_is_operator_and_type() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        for (name, arity, native_fn) in evie_native::natives() {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        let source = r#"
+        class Cake {}
+        class Pie {}
+        var cake = Cake();
+        print cake is Cake;
+        print cake is Pie;
+        print 1 is Cake;
+        print !(cake is Pie) == true;
+        print type(1);
+        print type("cake");
+        print type(nil);
+        print type(true);
+        print type(cake);
+        print type(Cake);
+        print type(type);
+        fun f() {}
+        print type(f);
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!(
+            "true\nfalse\nfalse\ntrue\nnumber\nstring\nnil\nboolean\ninstance\nclass\nfunction\nfunction\n",
+            utf8_to_string(&buf)
+        );
+
+        let mut vm = VirtualMachine::new();
+        match vm.interpret("print 1 is 2;".to_string(), None) {
+            Ok(_) => panic!("Expected to fail"),
+            Err(e) => assert_eq!(
+                "Runtime Error: Line: 1, message: Right operand of 'is' must be a class, got '2'\n[line 1] in <fn script>\n",
+                e.to_string()
+            ),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vm_anonymous_functions() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        fun apply(f, a, b) {
+            return f(a, b);
+        }
+        var add = fun(a, b) { return a + b; };
+        print add(1, 2);
+        print apply(fun(a, b) { return a * b; }, 3, 4);
+        fun adder(n) {
+            return fun(a) { return a + n; };
+        }
+        print adder(10)(5);
+        print fun() { return "called"; }();
+        fun(a) { print a; }("statement");
+        print add;
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!(
+            "3\n12\n15\ncalled\nstatement\n<fn anonymous>\n",
+            utf8_to_string(&buf)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn vm_anonymous_functions_in_stack_traces() {
+        let mut vm = VirtualMachine::new();
+        let source = r#"
+        var fail = fun() {
+            return nil + 1;
+        };
+        fun apply(f) { return f(); }
+        apply(fail);
+        "#;
+        match vm.interpret(source.to_string(), None) {
+            Ok(_) => panic!("Expected to fail"),
+            Err(e) => assert!(
+                e.to_string().ends_with("[line 3] in <fn fail (anonymous)>\n[line 5] in <fn apply>\n[line 6] in <fn script>\n"),
+                "{}",
+                e
+            ),
+        }
+    }
+
+    #[test]
+    fn vm_conditional_expressions() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        fun sign(n) {
+            return n < 0 ? "negative" : n == 0 ? "zero" : "positive";
+        }
+        print sign(-2);
+        print sign(0);
+        print sign(3);
+        var a = true ? 1 : 2;
+        print a;
+        print nil or false ? "yes" : "no";
+        print 1 + 2 > 2 ? 10 * 2 : 0;
+        print "left" and nil;
+        print nil or "right";
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!(
+            "negative\nzero\npositive\n1\nno\n20\nnil\nright\n",
+            utf8_to_string(&buf)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn vm_object_equality() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Point {
+            init(x) { this.x = x; }
+            get() { return this.x; }
+        }
+        class Other {}
+        var a = Point(1);
+        var b = Point(1);
+        var c = a;
+        print a == c;
+        print a == b;
+        print a != b;
+        print Point == Point;
+        print Point == Other;
+        fun f() {}
+        fun g() {}
+        var h = f;
+        print f == h;
+        print f == g;
+        print a.get == a.get;
+        print a.get == b.get;
+        var get = a.get;
+        print get();
+        print "ab" == "a" + "b";
+        print a == "a";
+        print nil == false;
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!(
+            "true\nfalse\ntrue\ntrue\nfalse\ntrue\nfalse\ntrue\nfalse\n1\ntrue\nfalse\nfalse\n",
+            utf8_to_string(&buf)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn vm_reports_compiler_warnings() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.interpret("{ var unused = 1; }".to_string(), None)?;
+        let warnings: Vec<String> = vm.warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(vec!["Warning: [line: 1, column: 7] Unused local variable 'unused'"], warnings);
+        vm.interpret("{ var used = 1; print used; }".to_string(), None)?;
+        assert!(vm.warnings().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn vm_bound_method_receiver() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Counter {
+            init() { this.count = 0; }
+            add(n) { this.count = this.count + n; return this; }
+        }
+        var counter = Counter();
+        var add = counter.add;
+        fun twice(f, n) { f(n); return f(n); }
+        // 'this' is the instance the method was read from, the value under the call is left as it was
+        print 1 + twice(add, 2).count;
+        print add(1) == counter;
+        print counter.count;
+        var adds = 0;
+        for (i in 0..1000) { adds = adds + add(1).count - counter.count; }
+        print adds;
+        print counter.count;
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("5\ntrue\n5\n0\n1005\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_class_initializer_and_this() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Brunch {
+            init(food, drinks) {
+                this.food = food;
+                this.drinks = drinks;
+            }
+        }
+                  
+        var brunch = Brunch("eggs", "coffee");
+        
+        print brunch.food + " and " + brunch.drinks;
+
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("eggs and coffee\n", utf8_to_string(&buf));
+
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Brunch {
+            init(food, drinks) {
+                this.food = food;
+                this.drinks = drinks;
+            }
+
+            set_dessert(item) {
+                this.dessert = item;
+                return this;
+            }
+        }
+                  
+        var brunch = Brunch("eggs", "coffee");
+
+        var brunch_with_dessert = brunch.set_dessert("cake");
+        
+        print brunch_with_dessert.food + " and " + brunch_with_dessert.drinks + " with " + brunch_with_dessert.dessert + " as dessert";
+
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!(
+            "eggs and coffee with cake as dessert\n",
+            utf8_to_string(&buf)
+        );
+
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Cake {
+    
             init(type) {
                 this.type = type;
             }
@@ -1455,7 +3402,7 @@ mod tests {
         match vm.interpret(source.to_string(), None) {
             Err(e) => {
                 print_error(e, &mut buf);
-                assert_eq!("[Parse Error] [line: 6] Error at <2>: message: Can't return a value from an initializer\n", utf8_to_string(&buf))
+                assert_eq!("[Parse Error] [line: 6, column: 24] Error at <2>: message: Can't return a value from an initializer\n", utf8_to_string(&buf))
             }
             Ok(_) => panic!("This test is expected to fail"),
         }
@@ -1464,8 +3411,39 @@ mod tests {
 
 
     #[test]
-    #[should_panic] 
-    fn vm_stack_overflow()  {
+    fn vm_class_constructors() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        class Empty {}
+        class Point {
+            init(x) { this.x = x; }
+            init_again(x) { this.init(x + 1); }
+        }
+        print Empty();
+        var p = Point(1);
+        print p.x;
+        p.init_again(2);
+        print p.x;
+        class Point {
+            init(x, y) { this.x = x + y; }
+        }
+        print Point(1, 2).x;
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("<instance of Empty>\n1\n3\n3\n", utf8_to_string(&buf));
+
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        match vm.interpret("class Empty {} Empty(1);".to_string(), None) {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => assert!(e.to_string().contains("Expected 0 arguments but got 1 for Empty constructor"), "{}", e),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vm_stack_overflow() -> Result<()> {
         let mut buf = vec![];
         let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
         let source = r#"
@@ -1476,9 +3454,760 @@ mod tests {
         infinite_recursion();
         "#;
         match vm.interpret(source.to_string(), None) {
-            Ok(_) => panic!("This should not happen"),
-            Err(_) => panic!("This should not happen"),
+            Ok(_) => panic!("Expected a stack overflow"),
+            Err(e) => assert!(e.to_string().contains("Stack overflow, stack size = 1024"), "{}", e),
+        }
+        // The VM can go on
+        vm.interpret("print 1;".to_string(), None)?;
+        assert_eq!("1\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_max_instructions() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        vm.set_max_instructions(Some(1000));
+        for source in ["while (true) {}", "fun f() { return f(); } f();"] {
+            match vm.interpret(source.to_string(), None) {
+                Ok(_) => panic!("Expected the limit to be exceeded by {}", source),
+                Err(e) => assert!(e.to_string().contains("Instruction limit of 1000 exceeded"), "{}", e),
+            }
+        }
+        // Per run
+        for _ in 0..3 {
+            vm.interpret("for (i in 0..50) {}".to_string(), None)?;
+        }
+        vm.set_max_instructions(None);
+        vm.interpret("for (i in 0..1000) {}".to_string(), None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn vm_set_upvalues() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        fun f() {
+            var a = 1;
+            var b = 2;
+            fun g() { a = 10; b = 20; print a; print b; }
+            g();
+            print a;
+            print b;
+        }
+        f();
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("10\n20\n10\n20\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_invalid_calls_and_operands() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        define_native_fn("clock", 0, &mut vm, evie_native::clock);
+        for (source, error) in [
+            ("class A { m(x) { return x; } } A().m();", "Expected 1 arguments but got 0 for <fn m>"),
+            ("class A { m() {} } A().m(1, 2);", "Expected 0 arguments but got 2 for <fn m>"),
+            ("var a = clock + \"b\";", "Add can be perfomed only on numbers or strings"),
+            ("class A {} var a = A() + A();", "Add can be perfomed only on numbers or strings"),
+        ] {
+            match vm.interpret(source.to_string(), None) {
+                Ok(_) => panic!("Expected {} to fail", source),
+                Err(e) => assert!(e.to_string().contains(error), "{}", e),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vm_can_be_sent_to_another_thread() -> Result<()> {
+        fn assert_send<T: Send>(_: &T) {}
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        define_native_fn("to_string", 1, &mut vm, to_string);
+        vm.interpret("var greeting = \"hello\";".to_string(), None)?;
+        assert_send(&vm);
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                vm.interpret("print to_string(greeting + \" world\");".to_string(), None)
+            })
+            .join()
+            .expect("Thread panicked")
+        })?;
+        assert_eq!("hello world\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_isolated_runtimes() -> Result<()> {
+        fn greeting(_: &[Value], runtime: &mut EvieRuntime) -> Result<Value> {
+            Ok(runtime.global("name").unwrap_or_default())
+        }
+        let mut first_buf = vec![];
+        let mut second_buf = vec![];
+        let mut first = VirtualMachine::new_with_writer(Some(&mut first_buf));
+        let mut second = VirtualMachine::new_with_writer(Some(&mut second_buf));
+        define_native_fn("greeting", 0, &mut first, greeting);
+        define_native_fn("greeting", 0, &mut second, greeting);
+        first.interpret("var name = \"first\";".to_string(), None)?;
+        let second_name = second.runtime().alloc_string("second");
+        second.runtime().define_global("name", second_name);
+        first.interpret("print greeting();".to_string(), None)?;
+        second.interpret("print greeting();".to_string(), None)?;
+        second.interpret("name = \"changed\"; print greeting();".to_string(), None)?;
+        first.interpret("print greeting();".to_string(), None)?;
+        assert!(first.runtime().global("changed").is_none());
+        assert_eq!("first\nfirst\n", utf8_to_string(&first_buf));
+        assert_eq!("second\nchanged\n", utf8_to_string(&second_buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_string_methods() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        let source = r#"
+        var greeting = "hello world";
+        print greeting.length();
+        print "hello".substring(1, 3);
+        print greeting.substring(6, greeting.length()) + "!";
+        print "".length();
+        print "naïve ☕".length();
+        print "naïve ☕".substring(2, 7);
+        print "naïve ☕".char_at(2);
+        print "naïve".char_at(5);
+        var café = "olé";
+        print café.char_at(2);
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("11\nel\nworld!\n0\n7\nïve ☕\nï\nnil\né\n", utf8_to_string(&buf));
+
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        match vm.interpret("\"hello\".reverse();".to_string(), None) {
+            Ok(_) => panic!("Expected to fail"),
+            Err(e) => assert_eq!(
+                "Runtime Error: Line: 1, message: Undefined method 'reverse' on String\n[line 1] in <fn script>\n",
+                e.to_string()
+            ),
+        }
+        match vm.interpret("\"hello\".substring(3, 1);".to_string(), None) {
+            Ok(_) => panic!("Expected to fail"),
+            Err(e) => assert_eq!(
+                "Runtime Error: Line: 1, message: substring start (3) must not be greater than end (1)\n[line 1] in <fn script>\n",
+                e.to_string()
+            ),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vm_reads_from_custom_reader() -> Result<()> {
+        let mut buf = vec![];
+        let input = std::io::Cursor::new("evie\r\nsecond line\nthe rest\n".as_bytes());
+        let mut vm = VirtualMachine::new_with_reader_and_writer(Some(Box::new(input)), Some(&mut buf));
+        for (name, arity, native_fn) in evie_native::io::natives() {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        let source = r#"
+        print "hello " + read_line();
+        print read_line();
+        print read_all();
+        print read_line();
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("hello evie\nsecond line\nthe rest\n\nnil\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    const GC_SOURCE: &str = r#"
+        fun make_counter() {
+            var count = 0;
+            fun counter() {
+                count = count + 1;
+                return count;
+            }
+            return counter;
+        }
+        class Node {
+            init(value, next) {
+                this.value = value;
+                this.next = next;
+            }
+            sum() {
+                if (this.next == nil) return this.value;
+                return this.value + this.next.sum();
+            }
+        }
+        var counter = make_counter();
+        var list = nil;
+        var name = "";
+        var i = 0;
+        while (i < 20) {
+            list = Node(counter(), list);
+            name = name + "a";
+            i = i + 1;
+        }
+        print list.sum();
+        print name.length();
+        print list.next.value;
+        "#;
+
+    #[test]
+    fn vm_gc_stress() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.set_gc_stress(true);
+        vm.interpret(GC_SOURCE.to_string(), None)?;
+        let stats = vm.gc_stats();
+        assert!(stats.collections > 100, "{:?}", stats);
+        assert!(stats.objects_freed > 0, "{:?}", stats);
+        assert!(vm.allocation_stats().iter().any(|stats| stats.type_name == "Instance"), "{:?}", vm.allocation_stats());
+        assert_eq!("210\n20\n19\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_gc_frees_unreachable_objects() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        for (name, arity, native_fn) in evie_native::gc::natives() {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        vm.interpret(GC_SOURCE.to_string(), None)?;
+        vm.gc_collect();
+        let before = vm.gc_stats();
+        let source = r#"
+        class Garbage {}
+        var i = 0;
+        while (i < 1000) {
+            var garbage = Garbage();
+            garbage.name = "garbage" + to_string(i);
+            i = i + 1;
+        }
+        gc_collect();
+        var stats = gc_stats();
+        print stats.collections;
+        print stats.objects_freed > 4000;
+        // The garbage is freed by the first collection after it was allocated
+        print stats.young_objects_freed > 4000;
+        "#;
+        define_native_fn("to_string", 1, &mut vm, to_string);
+        vm.interpret(source.to_string(), None)?;
+        vm.gc_collect();
+        let after = vm.gc_stats();
+        // Only the new globals (Garbage, i, stats & to_string) and the compiled script are live
+        assert!(after.objects < before.objects + 100, "before {:?}, after {:?}", before, after);
+        assert_eq!(before.collections + 2, after.collections);
+        vm.interpret("print list.sum();".to_string(), None)?;
+        assert_eq!("210\n20\n19\n2\ntrue\ntrue\n210\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_max_heap_bytes_counts_the_text_of_strings() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.set_max_heap_bytes(Some(1 << 20));
+        // A few objects, each with megabytes of text
+        let source = r#"
+        fun double() { var s = "0123456789abcdef"; for (i in 0..23) { s = s + s; } return s; }
+        print double().length();
+        "#;
+        let error = vm.interpret(source.to_string(), None).unwrap_err();
+        assert!(error.to_string().contains("out of memory, the heap is limited to 1048576 bytes"), "{}", error);
+        // Appending to the buffer of a rope is charged too
+        let source = r#"
+        fun append(n) { var s = ""; for (i in 0..n) { s = s + "0123456789abcdef"; } return s; }
+        print append(100000).length();
+        "#;
+        let error = vm.interpret(source.to_string(), None).unwrap_err();
+        assert!(error.to_string().contains("out of memory, the heap is limited to 1048576 bytes"), "{}", error);
+        // The text is released once the run failed
+        vm.interpret("print append(1000).length();".to_string(), None)?;
+        drop(vm);
+        assert_eq!("16000\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_max_heap_bytes() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.set_max_heap_bytes(Some(1 << 20));
+        let source = r#"
+        class Node { init(next) { this.next = next; } }
+        fun garbage(n) { var i = 0; while (i < n) { Node(nil); i = i + 1; } return i; }
+        fun chain(n) { var head = nil; var i = 0; while (i < n) { head = Node(head); i = i + 1; } return i; }
+        print garbage(50000);
+        "#;
+        // The garbage is collected, the heap stays under the cap
+        vm.interpret(source.to_string(), None)?;
+        let error = vm.interpret("print chain(1000000);".to_string(), None).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::RuntimeError(_)), "{}", error);
+        assert!(error.to_string().contains("out of memory, the heap is limited to 1048576 bytes"), "{}", error);
+        assert!(vm.runtime().allocator().bytes_allocated() >= 1 << 20);
+        // The chain is garbage once the run failed
+        vm.interpret("print chain(100);".to_string(), None)?;
+        assert!(vm.runtime().allocator().bytes_allocated() < 1 << 20);
+        vm.set_max_heap_bytes(None);
+        vm.interpret("print chain(50000);".to_string(), None)?;
+        // Only for one run
+        let args = || Some(Args::default().with_max_heap_bytes(1 << 20));
+        let error = vm.interpret("print chain(1000000);".to_string(), args()).unwrap_err();
+        assert!(error.to_string().contains("out of memory, the heap is limited to 1048576 bytes"), "{}", error);
+        vm.interpret("print chain(100);".to_string(), args())?;
+        assert_eq!(None, vm.runtime().allocator().max_heap_bytes());
+        vm.interpret("print chain(50000);".to_string(), None)?;
+        drop(vm);
+        assert_eq!("50000\n100\n50000\n100\n50000\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_heap_dump() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        let source = r#"
+        class Point {}
+        var point = Point();
+        var garbage = Point();
+        garbage = nil;
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let mut buf = vec![];
+        vm.heap_dump(&mut buf)?;
+        let dump = utf8_to_string(&buf);
+        let id = |line: &str| line[6..line.find(',').unwrap()].to_string();
+        let class = dump.lines().find(|l| l.contains(r#""type":"Class""#)).map(id).expect("a class");
+        let instances: Vec<&str> = dump.lines().filter(|l| l.contains(r#""type":"Instance""#)).collect();
+        assert_eq!(2, instances.len(), "{}", dump);
+        // Both reference the class (and their fields), only the one in the global is reachable
+        for (instance, reachable) in instances.iter().zip(["true", "false"]) {
+            assert!(instance.contains(&format!(r#""reachable":{},"references":[{},"#, reachable, class)), "{}", instance);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vm_host_data_exchange() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        vm.set_global("scale", 2.0);
+        vm.set_global("config", HashMap::from([("name".to_string(), "evie")]));
+        let source = r#"
+        var greeting = "hello " + config.name;
+        fun scaled(n) {
+            return n * scale;
+        }
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        assert_eq!("hello evie", vm.get_global::<String>("greeting")?);
+        assert!(vm.get_global::<f64>("greeting").is_err());
+        assert!(vm.get_global::<f64>("missing").is_err());
+        assert_eq!(6.0, vm.call_function::<f64>("scaled", &[Value::number(3.0)])?);
+        // The globals of the script persist between calls
+        vm.set_global("scale", 10.0);
+        assert_eq!(30.0, vm.call_function::<f64>("scaled", &[Value::number(3.0)])?);
+        assert!(vm.call_function::<f64>("scaled", &[]).is_err());
+        assert!(vm.call_function::<f64>("greeting", &[]).is_err());
+        assert!(vm.call_function::<f64>("scaled", &[Value::nil()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn vm_run_stats() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        // Constant, AddConstant, DefineGlobal, Nil, Return
+        vm.interpret("var a = 1 + 2;".to_string(), None)?;
+        let stats = vm.last_run_stats();
+        assert_eq!(5, stats.instructions);
+        assert!(stats.allocations > 0 && stats.bytes_allocated > 0, "{:?}", stats);
+        let source = r#"
+        class Point {}
+        var i = 0;
+        while (i < 100) {
+            var p = Point();
+            i = i + 1;
+        }
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let stats = vm.last_run_stats();
+        assert!(stats.allocations > 100, "{:?}", stats);
+        // The same on every run, unlike the time it takes
+        let mut other = VirtualMachine::new();
+        other.interpret(source.to_string(), None)?;
+        assert_eq!(stats, other.last_run_stats());
+        // Failed runs are counted too: Constant, DefineGlobal, GetGlobal and the failing Call
+        assert!(vm.interpret("var b = 1; b();".to_string(), None).is_err());
+        assert_eq!(4, vm.last_run_stats().instructions);
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn vm_tracing_spans() -> Result<()> {
+        use std::sync::{Arc, Mutex};
+        use tracing::{span, Event, Metadata, Subscriber};
+
+        /// Records the names of the spans as they are entered and the targets of the events
+        #[derive(Clone, Default)]
+        struct Recorder {
+            spans: Arc<Mutex<Vec<&'static str>>>,
+            entered: Arc<Mutex<Vec<&'static str>>>,
+            events: Arc<Mutex<Vec<&'static str>>>,
+        }
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool { true }
+            fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(span.metadata().name());
+                span::Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                self.events.lock().unwrap().push(event.metadata().target());
+            }
+            fn enter(&self, id: &span::Id) {
+                let name = self.spans.lock().unwrap()[id.into_u64() as usize - 1];
+                self.entered.lock().unwrap().push(name);
+            }
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || -> Result<()> {
+            let mut vm = VirtualMachine::new();
+            define_native_fn("gc_collect", 0, &mut vm, evie_native::gc::gc_collect);
+            vm.interpret("fun f() { gc_collect(); return 1; } f();".to_string(), None)?;
+            vm.call("f", &[])?;
+            Ok(())
+        })?;
+        assert_eq!(vec!["interpret", "compile", "run", "gc", "call", "run", "gc"], *recorder.entered.lock().unwrap());
+        assert!(recorder.events.lock().unwrap().contains(&"evie_memory::allocations"));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_repl_session() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        let live_objects = |vm: &mut VirtualMachine, redefinitions: usize| -> Result<usize> {
+            for _ in 0..redefinitions {
+                vm.interpret("fun f() { return 1; } class A { m() { return f(); } }".to_string(), None)?;
+            }
+            vm.gc_collect();
+            Ok(vm.gc_stats().objects)
+        };
+        // The old definitions are not kept alive by the session
+        let objects = live_objects(&mut vm, 2)?;
+        assert_eq!(objects, live_objects(&mut vm, 10)?);
+        // A runtime error with an open upvalue leaves nothing behind
+        let source = r#"
+        var total = 1;
+        fun outer() {
+            var captured = 2;
+            fun inner() { return captured; }
+            return captured + nil;
+        }
+        outer();
+        "#;
+        assert!(vm.interpret(source.to_string(), None).is_err());
+        assert!(vm.stack.is_empty() && vm.call_frames.is_empty() && vm.up_values.is_empty());
+        // The session goes on with its globals
+        vm.interpret("total = total + A().m();".to_string(), None)?;
+        assert_eq!(2.0, vm.get_global::<f64>("total")?);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_scoped_natives() -> Result<()> {
+        fn one(_: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+            Ok(Value::number(1.0))
+        }
+        fn two(_: &[Value], _: &mut EvieRuntime) -> Result<Value> {
+            Ok(Value::number(2.0))
+        }
+        let mut vm = VirtualMachine::new();
+        define_native_fn("value", 0, &mut vm, one);
+        let mut natives = NativeRegistry::new();
+        natives.define("value", 0, two).define("extra", 0, one).define("extra", 0, two);
+        vm.interpret("var scoped = value() + extra();".to_string(), Some(Args::default().with_natives(natives)))?;
+        assert_eq!(4.0, vm.get_global::<f64>("scoped")?);
+        // The natives only live for that run, the global they shadowed is back
+        vm.interpret("var global = value();".to_string(), None)?;
+        assert_eq!(1.0, vm.get_global::<f64>("global")?);
+        assert!(vm.interpret("extra();".to_string(), None).is_err());
+        // Also when the run fails
+        let mut natives = NativeRegistry::new();
+        natives.define("value", 0, two);
+        assert!(vm.interpret("value(); nil();".to_string(), Some(Args::default().with_natives(natives))).is_err());
+        vm.gc_collect();
+        vm.interpret("global = value();".to_string(), None)?;
+        assert_eq!(1.0, vm.get_global::<f64>("global")?);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_strict_mode() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        define_native_fn("clock", 0, &mut vm, evie_native::clock);
+        let strict = || Some(Args::default().with_strict(true));
+        // The natives and the globals of the previous runs are defined, a global can be used before it is declared
+        vm.interpret("var start = clock();".to_string(), strict())?;
+        vm.interpret("fun f() { return g() + start; } fun g() { return 1; } f();".to_string(), strict())?;
+        match vm.interpret("fun h() { return strat; }".to_string(), strict()) {
+            Ok(_) => panic!("Expected strict mode to fail"),
+            Err(e) => assert!(e.to_string().ends_with("Error at <strat>: message: Undefined variable 'strat'"), "{}", e),
+        }
+        // Without it the error is at runtime, if the code runs
+        vm.interpret("fun h() { return strat; }".to_string(), None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn vm_compilation_cache() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        vm.set_compilation_cache(Some(8));
+        let source = "fun add(a, b) { return a + b; } print add(1, 2); { var unused = 1; }";
+        vm.interpret(source.to_string(), None)?;
+        let compiled = vm.last_run_stats().allocations;
+        vm.gc_collect();
+        vm.interpret(source.to_string(), None)?;
+        // Only the closures of the run are allocated, the functions are not compiled again
+        assert!(vm.last_run_stats().allocations < compiled, "{} < {}", vm.last_run_stats().allocations, compiled);
+        assert_eq!(1, vm.warnings().len());
+        // The options are part of the key: in strict mode it fails to compile
+        vm.interpret("fun h() { return strat; }".to_string(), None)?;
+        match vm.interpret("fun h() { return strat; }".to_string(), Some(Args::default().with_strict(true))) {
+            Ok(_) => panic!("Expected strict mode to fail"),
+            Err(e) => assert!(e.to_string().ends_with("Error at <strat>: message: Undefined variable 'strat'"), "{}", e),
+        }
+        // The slots of the constants declared since are not those of the cached compilation
+        vm.interpret("print SECOND;".to_string(), None).unwrap_err();
+        vm.interpret("const FIRST = 1; const SECOND = 2;".to_string(), None)?;
+        vm.interpret("print SECOND;".to_string(), None)?;
+        drop(vm);
+        assert_eq!("3\n3\n2\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_division_by_zero() -> Result<()> {
+        let mut buf = vec![];
+        let mut vm = VirtualMachine::new_with_writer(Some(&mut buf));
+        // IEEE 754, the same in both representations of values
+        let source = r#"
+        print 1 / 0;
+        print -1 / 0;
+        print 1 / -0;
+        var nan = 0 / 0;
+        print nan;
+        print nan < 1 or nan > 1 or nan <= nan or nan >= nan;
+        print 1 / 0 > 1e308;
+        print 1 / 0 - 1 / 0;
+        print 1 / (1 / 0);
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let strict = || Some(Args::default().with_strict(true));
+        vm.interpret("print 0 / 5; print 1 / 0.5;".to_string(), strict())?;
+        match vm.interpret("fun half(n) { return n / 2; } print half(1); print 1 / (half(1) - 0.5);".to_string(), strict()) {
+            Ok(_) => panic!("Expected strict mode to fail"),
+            Err(e) => assert!(e.to_string().contains("Division by zero."), "{}", e),
+        }
+        drop(vm);
+        assert_eq!("inf\n-inf\n-inf\nnan\nfalse\ntrue\nnan\n0\n0\n2\n0.5\n", utf8_to_string(&buf));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_snapshot_and_restore() -> Result<()> {
+        let program = r#"
+        class Player {
+            "A player"
+            init(name) { this.name = name; this.score = 0; }
+            win(points) { this.score = this.score + points; return this.score; }
+        }
+        Player.count = 0;
+        class Counter {
+            init(increment, get) { this.increment = increment; this.get = get; }
+        }
+        fun counter() {
+            var count = 0;
+            fun increment() { count = count + 1; return count; }
+            fun get() { return count; }
+            return Counter(increment, get);
+        }
+        var now = clock;
+        var levels = 1..=3;
+        var player = Player("evie");
+        "#;
+        let play = r#"
+        player.win(10);
+        player.friend = Player("lox");
+        player.friend.friend = player;
+        player.counter = counter();
+        player.counter.increment();
+        player.counter.increment();
+        Player.count = 2;
+        var win = player.win;
+        var long = "a long string that is not interned";
+        "#;
+        let new_vm = |buf| -> Result<VirtualMachine> {
+            let mut vm = VirtualMachine::new_with_writer(Some(buf));
+            vm.set_snapshots(true);
+            define_native_fn("clock", 0, &mut vm, evie_native::clock);
+            define_native_fn("doc", 1, &mut vm, evie_native::function::doc);
+            vm.set_intern_limit(8);
+            vm.interpret(program.to_string(), None)?;
+            Ok(vm)
+        };
+        let mut saved = vec![];
+        let mut vm = new_vm(&mut saved)?;
+        vm.interpret(play.to_string(), None)?;
+        let snapshot = vm.snapshot()?;
+
+        let mut buf = vec![];
+        let mut restored = new_vm(&mut buf)?;
+        restored.restore(&snapshot)?;
+        restored.gc_collect();
+        let check = r#"
+        print player.name + " " + player.friend.name + " " + player.friend.friend.name;
+        print win(5);
+        print player.score;
+        print player.counter.increment() + player.counter.get();
+        print Player.count;
+        print player is Player;
+        print long;
+        for (level in levels) print level;
+        print now() >= 0;
+        print doc(Player);
+        "#;
+        restored.interpret(check.to_string(), None)?;
+        drop(restored);
+        assert_eq!("evie lox evie\n15\n15\n6\n2\ntrue\na long string that is not interned\n1\n2\n3\ntrue\nA player\n", utf8_to_string(&buf));
+
+        // The program must be the same
+        let mut other = VirtualMachine::new();
+        other.set_snapshots(true);
+        other.interpret("fun counter() {}".to_string(), None)?;
+        let error = other.restore(&snapshot).unwrap_err();
+        assert!(error.to_string().contains("The snapshot is of another program"), "{}", error);
+        let error = VirtualMachine::new().restore(&snapshot[..snapshot.len() - 1]).unwrap_err();
+        assert_eq!("Invalid snapshot", error.to_string());
+        assert_eq!("Not a snapshot", VirtualMachine::new().restore(b"save").unwrap_err().to_string());
+        // The functions are only kept when snapshots are enabled
+        let mut vm = VirtualMachine::new();
+        vm.interpret("fun f() {}".to_string(), None)?;
+        let error = vm.snapshot().unwrap_err();
+        assert!(error.to_string().contains("<fn f> can't be saved"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_stack_trace_source_names() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        vm.interpret("fun fail() {\n return nil + 1;\n}".to_string(), Some(Args::default().with_source_name("lib.evie")))?;
+        let error = vm.interpret("\nfail();".to_string(), Some(Args::default().with_source_name("main.evie"))).unwrap_err();
+        assert!(
+            error.to_string().ends_with("[lib.evie:2] in <fn fail>\n[main.evie:2] in <fn script>\n"),
+            "{}",
+            error
+        );
+        let error = vm.interpret("fail();".to_string(), None).unwrap_err();
+        assert!(error.to_string().ends_with("[lib.evie:2] in <fn fail>\n[line 1] in <fn script>\n"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn vm_error_spans() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        let span = |line, column, length| Some(SourceSpan { line, column, length });
+        assert!(vm.interpret("var a = 1;\nvar b = a +\n  nil;".to_string(), None).is_err());
+        // The operator, not the operand on the line after it
+        assert_eq!(span(2, 11, 1), vm.last_error_span());
+        let error = vm.interpret("class A {}\nA().missing();".to_string(), None).unwrap_err();
+        assert_eq!(span(2, 5, 7), vm.last_error_span());
+        assert!(error.to_string().ends_with("[line 2] in <fn script>\n"), "{}", error);
+        assert!(vm.interpret("var c = -\"a\";".to_string(), None).is_err());
+        assert_eq!(span(1, 9, 1), vm.last_error_span());
+        vm.interpret("var d = 1;".to_string(), None)?;
+        assert_eq!(None, vm.last_error_span());
+        // Compile errors are reported with their own line and column
+        assert!(vm.interpret("var e = ;".to_string(), None).is_err());
+        assert_eq!(None, vm.last_error_span());
+        Ok(())
+    }
+
+    #[test]
+    fn vm_call() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        define_native_fn("to_string", 1, &mut vm, to_string);
+        let source = r#"
+        var calls = 0;
+        fun on_tick(dt) {
+            calls = calls + 1;
+            return calls * dt;
         }
+        class Point {
+            init(x) {
+                this.x = x;
+            }
+            x_plus(y) {
+                return this.x + y;
+            }
+        }
+        var bound = Point(1).x_plus;
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        for tick in 1..=3 {
+            assert_eq!(tick as f64 * 0.5, vm.call("on_tick", &[Value::number(0.5)])?.as_number());
+        }
+        assert_eq!(3.0, vm.get_global::<f64>("calls")?);
+        let point = vm.call("Point", &[Value::number(2.0)])?;
+        assert_eq!("<instance of Point>", point.to_string());
+        assert_eq!(3.0, vm.call("bound", &[Value::number(2.0)])?.as_number());
+        assert_eq!("1", vm.call("to_string", &[Value::number(1.0)])?.to_string());
+        let error = vm.call("on_tick", &[]).expect_err("wrong arity");
+        assert!(error.to_string().contains("Expected 1 arguments but got 0 for <fn on_tick>"), "{}", error);
+        assert!(vm.call("calls", &[]).is_err());
+        assert!(vm.call("missing", &[]).is_err());
+        // A runtime error in the callee does not break later calls
+        assert!(vm.call("on_tick", &[Value::nil()]).is_err());
+        assert_eq!(2.5, vm.call("on_tick", &[Value::number(0.5)])?.as_number());
+        // More arguments than the stack holds
+        let error = vm.call("on_tick", &[Value::nil(); STACK_SIZE]).expect_err("stack overflow");
+        assert!(error.to_string().contains("Stack overflow, stack size = 1024"), "{}", error);
+        assert_eq!(3.0, vm.call("on_tick", &[Value::number(0.5)])?.as_number());
+        Ok(())
+    }
+
+    #[test]
+    fn vm_pinned_globals() -> Result<()> {
+        let mut vm = VirtualMachine::new();
+        let source = r#"
+        class Point {
+            init(x) { this.x = x; }
+            x_plus(y) { return this.x + y; }
+        }
+        var origin = Point(0);
+        fun x_of(point) { return point.x; }
+        "#;
+        vm.interpret(source.to_string(), None)?;
+        let point = vm.pin_global::<Instance>("origin")?;
+        let object = vm.pin_global::<Object>("origin")?;
+        assert!(vm.pin_global::<Instance>("Point").is_err());
+        assert!(vm.pin_global::<Instance>("missing").is_err());
+        vm.interpret("origin = nil;".to_string(), None)?;
+        vm.gc_collect();
+        vm.runtime().set_field(&point, "x", Value::number(2.0));
+        assert_eq!(Some(Value::number(2.0)), vm.runtime().field(&point, "x"));
+        assert_eq!("Point", &**vm.runtime().get(&point).class.name);
+        let value = vm.runtime().value(&object);
+        assert_eq!(2.0, vm.call("x_of", &[value])?.as_number());
+        vm.runtime().release(point);
+        vm.runtime().release(object);
+        Ok(())
     }
 
     #[test]
@@ -1489,7 +4218,7 @@ mod tests {
         print clock();
         "#;
         define_native_fn("clock", 0, &mut vm, clock);
-        let _ = vm.interpret(source.to_string(), None)?;
+        vm.interpret(source.to_string(), None)?;
         let output = utf8_to_string(&buf);
         // This will fail if it is not f64
         let _ = output.trim().parse::<f64>().unwrap();
@@ -1,6 +1,10 @@
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
-use evie_native::clock;
-use evie_vm::vm::VirtualMachine;
+use evie_memory::{cache::Cache, ObjectAllocator};
+use evie_native::{clock, to_string};
+use evie_vm::{
+    stack::{self, Stack},
+    vm::VirtualMachine,
+};
 
 struct Iteration(usize, fn(usize) -> String);
 
@@ -13,6 +17,7 @@ impl Iteration {
 fn vm() -> VirtualMachine<'static> {
     let mut vm = VirtualMachine::new();
     evie_vm::vm::define_native_fn("clock", 0, &mut vm, clock);
+    evie_vm::vm::define_native_fn("to_string", 1, &mut vm, to_string);
     vm
 }
 
@@ -66,6 +71,38 @@ pub fn string_equality(c: &mut Criterion) {
     }
 }
 
+pub fn string_concatenation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("String_Concatenation");
+    let mut vm = vm();
+    for i in [
+        Iteration(100, evie_vm_bench::string_concatenation::src).build(),
+        Iteration(1000, evie_vm_bench::string_concatenation::src).build(),
+        Iteration(10000, evie_vm_bench::string_concatenation::src).build(),
+    ]
+    .into_iter()
+    {
+        group.bench_with_input(BenchmarkId::new("Iteration_count", i.0), &i, |b, i| {
+            b.iter(|| vm.interpret(i.1.clone(), None));
+        });
+    }
+}
+
+pub fn closures(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Closures");
+    let mut vm = vm();
+    for i in [
+        Iteration(100, evie_vm_bench::closures::src).build(),
+        Iteration(1000, evie_vm_bench::closures::src).build(),
+        Iteration(10000, evie_vm_bench::closures::src).build(),
+    ]
+    .into_iter()
+    {
+        group.bench_with_input(BenchmarkId::new("Iteration_count", i.0), &i, |b, i| {
+            b.iter(|| vm.interpret(i.1.clone(), None));
+        });
+    }
+}
+
 pub fn binary_tree(c: &mut Criterion) {
     let mut group = c.benchmark_group("Binary_Tree");
     let mut vm = vm();
@@ -166,12 +203,89 @@ pub fn zoo(c: &mut Criterion) {
     }
 }
 
+/// Looks up every key of caches of increasing sizes, scanned (linear) and indexed (hashed),
+/// to find the size where hashing becomes faster ([evie_memory::cache::LINEAR_LIMIT])
+pub fn cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Cache");
+    let allocator = ObjectAllocator::new();
+    for size in [1, 2, 4, 8, 16, 32, 64] {
+        let keys: Vec<_> = (0..size)
+            .map(|i| allocator.alloc_interned_str(format!("key{}", i)))
+            .collect();
+        for (name, linear_limit) in [("Linear", usize::MAX), ("Hashed", 0)] {
+            let mut cache = Cache::with_linear_limit(linear_limit);
+            keys.iter()
+                .enumerate()
+                .for_each(|(i, key)| cache.insert(*key, i));
+            group.bench_with_input(BenchmarkId::new(name, size), &keys, |b, keys| {
+                b.iter(|| keys.iter().filter_map(|key| cache.get(*key)).sum::<usize>());
+            });
+        }
+    }
+}
+
+/// The ids are named after the mode the stack was built with, compare the modes by running
+/// `cargo bench --bench vm_bench Stack` with and without `--features unchecked_stack`
+pub fn stack(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Stack");
+    let mut stack = Stack::new();
+    for size in [16, 256, 1024] {
+        group.bench_with_input(BenchmarkId::new(stack::MODE, size), &size, |b, &size| {
+            b.iter(|| {
+                // The value type depends on the features evie_vm was built with
+                (0..size).for_each(|_| stack.push(Default::default()));
+                (0..size).for_each(|i| stack.set(i, stack.peek(size - 1 - i)));
+                let values = (0..size).filter(|&i| stack.get(i).is_nil()).count();
+                (0..size).for_each(|_| {
+                    stack.pop();
+                });
+                values
+            });
+        });
+    }
+}
+
+pub fn superinstructions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Superinstructions");
+    for (name, superinstructions) in [("Fused", true), ("Unfused", false)] {
+        let mut vm = vm();
+        vm.set_superinstructions(superinstructions);
+        for i in [
+            Iteration(10000, evie_vm_bench::arithmetic::src).build(),
+            Iteration(100000, evie_vm_bench::arithmetic::src).build(),
+        ]
+        .into_iter()
+        {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}_arithmetic", name), i.0),
+                &i,
+                |b, i| {
+                    b.iter(|| vm.interpret(i.1.clone(), None));
+                },
+            );
+        }
+        let i = Iteration(20, evie_vm_bench::fib::src).build();
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}_fib", name), i.0),
+            &i,
+            |b, i| {
+                b.iter(|| vm.interpret(i.1.clone(), None));
+            },
+        );
+    }
+}
+
 criterion_group!(
     benches,
+    cache,
+    stack,
     equality,
     recursion,
     string_equality,
+    string_concatenation,
+    superinstructions,
     binary_tree,
+    closures,
     instantiation,
     invocation,
     properties,
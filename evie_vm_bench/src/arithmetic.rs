@@ -0,0 +1,20 @@
+static SOURCE: &str = r#"
+fun sum(n) {
+  var total = 0;
+  var i = 0;
+  while (i < n) {
+    var square = i * i;
+    total = total + square + i;
+    i = i + 1;
+  }
+  return total;
+}
+
+var start = clock();
+var result = sum(_COUNT_);
+var elapsed = clock() - start;
+"#;
+
+pub fn src(count: usize) -> String {
+    SOURCE.replace("_COUNT_", &count.to_string())
+}
@@ -0,0 +1,27 @@
+static SOURCE: &str = r#"
+fun counter(a, b, c, d) {
+  var e = a + b;
+  fun sum() { return a + b + c + d + e; }
+  fun first() { return d + c; }
+  fun second() { return b + a; }
+  fun increment() { e = e + 1; return sum() + first() + second(); }
+  return increment;
+}
+
+var i = 0;
+var total = 0;
+
+var start = clock();
+
+while (i < _COUNT_) {
+  i = i + 1;
+  var increment = counter(i, 1, 2, 3);
+  total = total + increment() + increment();
+}
+
+var elapsed = clock() - start;
+"#;
+
+pub fn src(count: usize) -> String {
+    SOURCE.replace("_COUNT_", &count.to_string())
+}
@@ -1,12 +1,30 @@
+pub mod arithmetic;
 pub mod binary_tree;
+pub mod closures;
 pub mod equality;
 pub mod fib;
 pub mod instantiation;
 pub mod invocation;
 pub mod properties;
+pub mod string_concatenation;
 pub mod string_equality;
 pub mod trees;
 pub mod zoo;
+
+use evie_common::errors::*;
+use evie_native::{clock, to_string};
+use evie_vm::vm::{RunStats, VirtualMachine};
+
+/// Interprets the source in a fresh VM (with the natives the benchmarks use) and returns its [RunStats].
+/// Unlike timings they are the same on every run, to compare changes without the noise of the machine.
+pub fn run_stats(source: String, superinstructions: bool) -> Result<RunStats> {
+    let mut vm = VirtualMachine::new();
+    evie_vm::vm::define_native_fn("clock", 0, &mut vm, clock);
+    evie_vm::vm::define_native_fn("to_string", 1, &mut vm, to_string);
+    vm.set_superinstructions(superinstructions);
+    vm.interpret(source, None)?;
+    Ok(vm.last_run_stats())
+}
 #[cfg(test)]
 #[ctor::ctor]
 fn init() {
@@ -18,7 +36,7 @@ mod tests {
     use std::time::Instant;
 
     use evie_common::errors::*;
-    use evie_native::clock;
+    use evie_native::{clock, to_string};
     use evie_vm::vm::VirtualMachine;
 
     #[test]
@@ -26,15 +44,33 @@ mod tests {
         let mut vm = VirtualMachine::new();
         let start = Instant::now();
         evie_vm::vm::define_native_fn("clock", 0, &mut vm, clock);
+        evie_vm::vm::define_native_fn("to_string", 1, &mut vm, to_string);
+        vm.interpret(crate::arithmetic::src(10), None)?;
         vm.interpret(crate::binary_tree::src(10), None)?;
+        vm.interpret(crate::closures::src(10), None)?;
         vm.interpret(crate::equality::src(10), None)?;
         vm.interpret(crate::invocation::src(10), None)?;
         vm.interpret(crate::instantiation::src(10), None)?;
         vm.interpret(crate::properties::src(10), None)?;
+        vm.interpret(crate::string_concatenation::src(10), None)?;
         vm.interpret(crate::string_equality::src(10), None)?;
         vm.interpret(crate::trees::src(10), None)?;
         vm.interpret(crate::zoo::src(10), None)?;
         println!("Elapsed: {} ms", start.elapsed().as_millis());
         Ok(())
     }
+
+    #[test]
+    fn deterministic_run_stats() -> Result<()> {
+        let fused = crate::run_stats(crate::arithmetic::src(100), true)?;
+        assert_eq!(fused, crate::run_stats(crate::arithmetic::src(100), true)?);
+        let unfused = crate::run_stats(crate::arithmetic::src(100), false)?;
+        assert!(
+            fused.instructions < unfused.instructions,
+            "{:?} {:?}",
+            fused,
+            unfused
+        );
+        Ok(())
+    }
 }
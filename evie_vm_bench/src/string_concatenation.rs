@@ -0,0 +1,17 @@
+static SOURCE: &str = r#"
+var s = "";
+var i = 0;
+
+var start = clock();
+
+while (i < _COUNT_) {
+  i = i + 1;
+  s = s + "line " + to_string(i) + "\n";
+}
+
+var elapsed = clock() - start;
+"#;
+
+pub fn src(count: usize) -> String {
+    SOURCE.replace("_COUNT_", &count.to_string())
+}
@@ -0,0 +1,62 @@
+//! Evie for the browser, e.g. for a web playground.
+//!
+//! Built with [wasm-bindgen](https://rustwasm.github.io/docs/wasm-bindgen/) for `wasm32-unknown-unknown`:
+//! ```text
+//! wasm-pack build evie_wasm --target web
+//! ```
+//! ```text
+//! import init, { interpret } from "./pkg/evie_wasm.js";
+//! await init();
+//! console.log(interpret("print 1 + 2;")); // 3
+//! ```
+//! The VM is built without the `clock` feature as the target has no clock: time stands still for the scripts
+//! (see evie_memory), and there is no stdin, `read_line()` reads the end of the input.
+use evie_common::{print_error, print_warning, utf8_to_string};
+use evie_vm::vm::{define_native_fn, Args, VirtualMachine};
+use wasm_bindgen::prelude::wasm_bindgen;
+
+/// Runs the source in a fresh VM and returns what it printed, followed by its warnings and its error (if any)
+/// as the command line prints them
+#[wasm_bindgen]
+pub fn interpret(source: &str) -> String {
+    let mut output = vec![];
+    let mut diagnostics = vec![];
+    {
+        let input = Box::new(std::io::empty());
+        let mut vm = VirtualMachine::new_with_reader_and_writer(Some(input), Some(&mut output));
+        for (name, arity, native_fn) in evie_native::all_natives() {
+            define_native_fn(name, arity, &mut vm, native_fn);
+        }
+        let result = vm.interpret(
+            source.to_string(),
+            Some(Args::default().with_source_name("<playground>")),
+        );
+        for warning in vm.warnings() {
+            print_warning(warning, &mut diagnostics);
+        }
+        if let Err(e) = result {
+            print_error(e, &mut diagnostics);
+        }
+        vm.free();
+    }
+    output.extend(diagnostics);
+    utf8_to_string(&output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::interpret;
+
+    #[test]
+    fn interprets_to_a_string() {
+        assert_eq!(
+            interpret("fun add(a, b) { return a + b; } print add(1, 2); print \"evie\";"),
+            "3\nevie\n"
+        );
+        assert_eq!(interpret("print read_line();"), "nil\n");
+        let output = interpret("print 1; print nil + 1;");
+        assert!(output.starts_with("1\n"), "{}", output);
+        assert!(output.contains("[Runtime Error]"), "{}", output);
+        assert!(interpret("print ;").contains("[Parse Error]"));
+    }
+}